@@ -2,4 +2,7 @@
 fn main() {
     // trigger recompilation when a new migration is added
     println!("cargo:rerun-if-changed=migrations");
+
+    #[cfg(feature = "grpc")]
+    tonic_build::compile_protos("proto/admin.proto").expect("Failed to compile admin.proto");
 }
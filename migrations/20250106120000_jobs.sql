@@ -0,0 +1,14 @@
+create table jobs
+(
+    id         integer unsigned not null auto_increment primary key,
+    kind       varchar(64)      not null,
+    payload    text             not null,
+    status     enum ('queued', 'running', 'complete', 'failed') not null default 'queued',
+    attempts   integer unsigned not null default 0,
+    last_error text,
+    run_after  timestamp        not null default current_timestamp,
+    created    timestamp        not null default current_timestamp,
+    updated    timestamp        not null default current_timestamp on update current_timestamp
+);
+create index ix_jobs_kind_status_run_after on jobs (kind, status, run_after);
+create index ix_jobs_status on jobs (status);
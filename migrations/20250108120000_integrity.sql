@@ -0,0 +1,16 @@
+alter table uploads
+    add column verified timestamp null;
+create index ix_uploads_verified on uploads (verified);
+
+create table corrupt_files
+(
+    file        binary(32) not null primary key,
+    actual_hash binary(32),
+    detected    timestamp  not null default current_timestamp,
+    restored    timestamp  null,
+
+    constraint fk_corrupt_files_file_id
+        foreign key (file) references uploads (id)
+            on delete cascade
+            on update restrict
+);
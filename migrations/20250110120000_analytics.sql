@@ -0,0 +1,17 @@
+create table analytics_hourly
+(
+    hour     timestamp       not null,
+    event    varchar(32)     not null,
+    requests bigint unsigned not null default 0,
+    bytes    bigint unsigned not null default 0,
+
+    primary key (hour, event)
+);
+
+create table analytics_visitors
+(
+    day     date       not null,
+    visitor binary(32) not null,
+
+    primary key (day, visitor)
+);
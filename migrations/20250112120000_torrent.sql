@@ -0,0 +1,2 @@
+alter table uploads
+    add column torrent_info_hash binary(32) null;
@@ -0,0 +1,2 @@
+alter table uploads
+    add column blake3 binary(32) null;
@@ -0,0 +1,2 @@
+alter table users
+    add column allow_blocked_types bit(1) not null default 0;
@@ -0,0 +1 @@
+create index ix_upload_labels_label on upload_labels (label);
@@ -0,0 +1,17 @@
+create table api_keys
+(
+    id          integer unsigned not null auto_increment primary key,
+    user_id     integer unsigned not null,
+    key_hash    binary(32)       not null,
+    description varchar(255),
+    scopes      varchar(64)      not null,
+    created     timestamp        not null default current_timestamp,
+    last_used   timestamp        null,
+    revoked     timestamp        null,
+
+    constraint fk_api_keys_user
+        foreign key (user_id) references users (id)
+            on delete cascade
+            on update restrict
+);
+create unique index ix_api_keys_key_hash on api_keys (key_hash);
@@ -0,0 +1,5 @@
+alter table users
+    add column tier varchar(32);
+alter table uploads
+    add column expires timestamp null;
+create index ix_uploads_expires on uploads (expires);
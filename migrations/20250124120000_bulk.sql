@@ -0,0 +1,4 @@
+alter table uploads
+    add column quarantined bit(1) not null default 0;
+alter table jobs
+    add column progress varchar(255);
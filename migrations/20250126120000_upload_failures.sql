@@ -0,0 +1,13 @@
+create table upload_failures
+(
+    id        bigint unsigned not null auto_increment primary key,
+    pubkey    binary(32)      not null,
+    created   timestamp       not null default current_timestamp,
+    route     varchar(64)     not null,
+    size      bigint unsigned,
+    mime_type varchar(255),
+    stage     varchar(16)     not null,
+    code      varchar(32)     not null,
+    detail    varchar(1024)
+);
+create index ix_upload_failures_pubkey on upload_failures (pubkey, id);
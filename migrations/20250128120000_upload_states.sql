@@ -0,0 +1,6 @@
+create table upload_states
+(
+    id      binary(32)                    not null primary key,
+    state   enum ('pending','processing') not null,
+    updated timestamp                     not null default current_timestamp on update current_timestamp
+);
@@ -0,0 +1,8 @@
+alter table users
+    add column display_name    varchar(255),
+    add column nip05           varchar(255),
+    add column nip05_verified  bit(1) not null default 0,
+    add column lud16           varchar(255),
+    add column lud16_verified  bit(1) not null default 0,
+    add column profile_updated timestamp null;
+create index ix_users_profile_updated on users (profile_updated);
@@ -0,0 +1,9 @@
+create table upload_derivations
+(
+    source  binary(32)   not null,
+    params  varchar(255) not null,
+    derived binary(32)   not null,
+    created timestamp    not null default current_timestamp,
+    primary key (source, params)
+);
+create index ix_upload_derivations_derived on upload_derivations (derived);
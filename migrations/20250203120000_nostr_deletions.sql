@@ -0,0 +1,9 @@
+create table nostr_deletions
+(
+    event_id      binary(32) not null,
+    file          binary(32) not null,
+    pubkey        binary(32) not null,
+    event_created timestamp  not null,
+    processed     timestamp  not null default current_timestamp,
+    primary key (event_id, file)
+);
@@ -0,0 +1,2 @@
+alter table uploads
+    add column storage varchar(32) null;
@@ -0,0 +1,10 @@
+create table processing_reports
+(
+    file             binary(32)       not null primary key,
+    source           binary(32)       not null,
+    source_size      bigint unsigned  not null,
+    source_mime_type varchar(255)     not null,
+    params           varchar(255)     not null,
+    duration_ms      integer unsigned not null,
+    created          timestamp        not null default current_timestamp
+);
@@ -0,0 +1,25 @@
+create table mirror_batches
+(
+    id      integer unsigned not null auto_increment primary key,
+    user_id integer unsigned not null,
+    created timestamp        not null default current_timestamp,
+
+    constraint fk_mirror_batches_user
+        foreign key (user_id) references users (id)
+            on delete cascade
+            on update restrict
+);
+create table mirror_batch_items
+(
+    batch integer unsigned not null,
+    idx   integer unsigned not null,
+    url   varchar(2048)    not null,
+    job   integer unsigned not null,
+    file  binary(32),
+    primary key (batch, idx),
+
+    constraint fk_mirror_batch_items_batch
+        foreign key (batch) references mirror_batches (id)
+            on delete cascade
+            on update restrict
+);
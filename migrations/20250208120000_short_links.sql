@@ -0,0 +1,7 @@
+create table short_links
+(
+    code    varchar(16) not null primary key,
+    file    binary(32)  not null,
+    created timestamp   not null default current_timestamp
+);
+create unique index ix_short_links_file on short_links (file);
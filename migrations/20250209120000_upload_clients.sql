@@ -0,0 +1,3 @@
+alter table user_uploads
+    add column user_agent varchar(256) null,
+    add column client     varchar(64)  null;
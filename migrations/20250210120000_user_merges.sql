@@ -0,0 +1,9 @@
+create table user_merges
+(
+    id      bigint unsigned  not null auto_increment primary key,
+    from_id integer unsigned not null,
+    into_id integer unsigned not null,
+    pubkey  binary(32)       not null,
+    files   integer unsigned not null,
+    created timestamp        not null default current_timestamp
+);
@@ -0,0 +1 @@
+create index ix_users_nip05 on users (nip05);
@@ -0,0 +1,4 @@
+alter table uploads
+    add column original_hash binary(32);
+update uploads u join processing_reports r on r.file = u.id
+set u.original_hash = r.source;
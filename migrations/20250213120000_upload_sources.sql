@@ -0,0 +1,19 @@
+create table upload_sources
+(
+    file       binary(32)        not null,
+    url_hash   binary(32)        not null,
+    url        varchar(2048)     not null,
+    created    timestamp         not null default current_timestamp,
+    checked    timestamp         null,
+    alive      bit(1)            null,
+    dead_since timestamp         null,
+    last_error varchar(255),
+    primary key (file, url_hash),
+
+    constraint fk_upload_sources_file
+        foreign key (file) references uploads (id)
+            on delete cascade
+            on update restrict
+);
+create index ix_upload_sources_checked on upload_sources (checked);
+create index ix_upload_sources_dead_since on upload_sources (dead_since);
@@ -0,0 +1,9 @@
+create table deny_rules
+(
+    id      integer unsigned                     not null auto_increment primary key,
+    kind    enum ('domain', 'client', 'pubkey') not null,
+    value   varchar(255)                         not null,
+    reason  varchar(255),
+    created timestamp                            not null default current_timestamp
+);
+create unique index ix_deny_rules_kind_value on deny_rules (kind, value);
@@ -0,0 +1,32 @@
+create table collections
+(
+    id          integer unsigned not null auto_increment primary key,
+    user_id     integer unsigned not null,
+    name        varchar(255)     not null,
+    share_token varchar(32)      not null,
+    created     timestamp        not null default current_timestamp,
+
+    constraint fk_collections_user
+        foreign key (user_id) references users (id)
+            on delete cascade
+            on update restrict
+);
+create unique index ix_collections_user_name on collections (user_id, name);
+create unique index ix_collections_share_token on collections (share_token);
+create table collection_files
+(
+    collection integer unsigned not null,
+    file       binary(32)       not null,
+    added      timestamp        not null default current_timestamp,
+    primary key (collection, file),
+
+    constraint fk_collection_files_collection
+        foreign key (collection) references collections (id)
+            on delete cascade
+            on update restrict,
+    constraint fk_collection_files_file
+        foreign key (file) references uploads (id)
+            on delete cascade
+            on update restrict
+);
+create index ix_collection_files_file on collection_files (file);
@@ -0,0 +1,2 @@
+alter table processing_reports
+    add column quality tinyint unsigned;
@@ -0,0 +1,9 @@
+alter table uploads
+    add column blur_hash_source enum ('server', 'client'),
+    add column dim_source       enum ('server', 'client');
+update uploads
+set dim_source = 'server'
+where width is not null;
+update uploads
+set blur_hash_source = 'server'
+where blur_hash is not null;
@@ -0,0 +1,13 @@
+create table tos_acceptances
+(
+    user_id  integer unsigned not null,
+    version  varchar(64)      not null,
+    event    text             not null,
+    created  timestamp        not null default current_timestamp,
+    primary key (user_id, version),
+
+    constraint fk_tos_acceptances_user
+        foreign key (user_id) references users (id)
+            on delete cascade
+            on update restrict
+);
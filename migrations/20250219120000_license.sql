@@ -0,0 +1,3 @@
+alter table uploads
+    add column license varchar(128);
+create index ix_uploads_license on uploads (license);
@@ -0,0 +1,2 @@
+alter table jobs
+    modify status enum ('queued', 'running', 'complete', 'failed', 'cancelled') not null default 'queued';
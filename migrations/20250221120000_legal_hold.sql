@@ -0,0 +1,23 @@
+alter table uploads
+    add column legal_hold bit(1) not null default 0;
+create table legal_hold_releases
+(
+    file         binary(32) not null primary key,
+    requested_by binary(32) not null,
+    created      timestamp  not null default current_timestamp,
+
+    constraint fk_legal_hold_releases_file
+        foreign key (file) references uploads (id)
+            on delete cascade
+            on update restrict
+);
+create table legal_hold_log
+(
+    id      integer unsigned                                                    not null auto_increment primary key,
+    file    binary(32)                                                          not null,
+    action  enum ('hold', 'release_requested', 'released', 'access', 'blocked') not null,
+    pubkey  binary(32),
+    detail  varchar(255),
+    created timestamp                                                           not null default current_timestamp
+);
+create index ix_legal_hold_log_file on legal_hold_log (file);
@@ -0,0 +1,9 @@
+create table operator_reports
+(
+    id           integer unsigned not null auto_increment primary key,
+    period_start timestamp        not null,
+    period_end   timestamp        not null,
+    report       text             not null,
+    created      timestamp        not null default current_timestamp
+);
+create index ix_operator_reports_period_end on operator_reports (period_end);
@@ -0,0 +1,5 @@
+-- admin file list: newest first, optionally by mime type prefix or license
+create index ix_uploads_created on uploads (created);
+create index ix_uploads_mime_type_created on uploads (mime_type, created);
+create index ix_uploads_license_created on uploads (license, created);
+drop index ix_uploads_license on uploads;
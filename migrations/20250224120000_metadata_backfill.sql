@@ -0,0 +1,3 @@
+alter table uploads
+    add column metadata_queued timestamp null;
+create index ix_uploads_width_metadata_queued on uploads (width, metadata_queued);
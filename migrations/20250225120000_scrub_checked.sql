@@ -0,0 +1,3 @@
+alter table uploads
+    add column scrubbed timestamp null;
+create index ix_uploads_scrubbed on uploads (scrubbed);
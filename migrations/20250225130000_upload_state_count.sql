@@ -0,0 +1,2 @@
+alter table upload_states
+    add column active int unsigned not null default 1;
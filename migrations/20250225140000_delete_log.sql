@@ -0,0 +1,10 @@
+create table delete_log
+(
+    id      integer unsigned                                     not null auto_increment primary key,
+    file    binary(32)                                           not null,
+    outcome enum ('owner_removed', 'deleted')                    not null,
+    reason  enum ('owner', 'admin', 'peer', 'retention', 'nostr') not null,
+    pubkey  binary(32),
+    created timestamp                                            not null default current_timestamp
+);
+create index ix_delete_log_file on delete_log (file);
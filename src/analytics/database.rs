@@ -0,0 +1,253 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use anyhow::Error;
+use chrono::{DateTime, DurationRound, NaiveDate, TimeDelta, Utc};
+use log::error;
+use rocket::Request;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use sqlx::FromRow;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use uuid::Uuid;
+
+use crate::analytics::{Analytics, AnalyticsEvent};
+use crate::db::Database;
+
+/// How often accumulated counters are written to the database
+const FLUSH_INTERVAL: Duration = Duration::from_secs(60);
+
+struct Hit {
+    event: String,
+    bytes: u64,
+    visitor: Option<Vec<u8>>,
+}
+
+/// Random salt of the visitor ids, replaced when the day changes and only kept in memory,
+/// so ids of past days can't be recomputed from an IP and user agent
+struct DailySalt {
+    day: NaiveDate,
+    salt: [u8; 32],
+}
+
+impl DailySalt {
+    fn new(day: NaiveDate) -> Self {
+        let mut salt = [0u8; 32];
+        salt[..16].copy_from_slice(Uuid::new_v4().as_bytes());
+        salt[16..].copy_from_slice(Uuid::new_v4().as_bytes());
+        Self { day, salt }
+    }
+
+    /// Salt of the day, a new one is generated when the day changed
+    fn get(&mut self, day: NaiveDate) -> [u8; 32] {
+        if self.day != day {
+            *self = Self::new(day);
+        }
+        self.salt
+    }
+}
+
+/// Stores hourly request counters and daily unique visitors in the local database
+pub struct DatabaseAnalytics {
+    tx: UnboundedSender<Hit>,
+    salt: Mutex<DailySalt>,
+}
+
+impl DatabaseAnalytics {
+    pub fn new(db: Database) -> Self {
+        let (tx, rx) = unbounded_channel::<Hit>();
+        tokio::spawn(Self::run(db, rx));
+        Self {
+            tx,
+            salt: Mutex::new(DailySalt::new(Utc::now().date_naive())),
+        }
+    }
+
+    async fn run(db: Database, mut rx: UnboundedReceiver<Hit>) {
+        let mut counters: HashMap<(DateTime<Utc>, String), (u64, u64)> = HashMap::new();
+        let mut visitors: HashSet<(NaiveDate, Vec<u8>)> = HashSet::new();
+        let mut flush = tokio::time::interval(FLUSH_INTERVAL);
+        loop {
+            tokio::select! {
+                msg = rx.recv() => {
+                    let hit = match msg {
+                        Some(h) => h,
+                        None => break,
+                    };
+                    let now = Utc::now();
+                    let hour = now.duration_trunc(TimeDelta::hours(1)).unwrap_or(now);
+                    let c = counters.entry((hour, hit.event)).or_default();
+                    c.0 += 1;
+                    c.1 += hit.bytes;
+                    if let Some(v) = hit.visitor {
+                        visitors.insert((now.date_naive(), v));
+                    }
+                }
+                _ = flush.tick() => {
+                    if let Err(e) = db.write_analytics(&counters, &visitors).await {
+                        error!("Failed to write analytics: {}", e);
+                        continue;
+                    }
+                    counters.clear();
+                    visitors.clear();
+                }
+            }
+        }
+    }
+
+    /// Anonymous visitor id, only stable for a single day
+    fn visitor_id(&self, req: &Request) -> Option<Vec<u8>> {
+        let ip = req
+            .headers()
+            .get_one("X-Forwarded-For")
+            .and_then(|x| x.split(',').next())
+            .map(|x| x.trim().to_string())
+            .or_else(|| req.client_ip().map(|i| i.to_string()))?;
+        let ua = req.headers().get_one("User-Agent").unwrap_or("");
+        let salt = self.salt.lock().unwrap().get(Utc::now().date_naive());
+        let mut hasher = Sha256::new();
+        hasher.update(salt);
+        hasher.update(ip);
+        hasher.update(ua);
+        Some(hasher.finalize().to_vec())
+    }
+}
+
+impl Analytics for DatabaseAnalytics {
+    fn track(&self, req: &Request) -> Result<(), Error> {
+        Ok(self.tx.send(Hit {
+            event: "pageview".to_string(),
+            bytes: 0,
+            visitor: self.visitor_id(req),
+        })?)
+    }
+
+    fn track_event(&self, _req: &Request, event: &AnalyticsEvent) -> Result<(), Error> {
+        Ok(self.tx.send(Hit {
+            event: event.kind.as_str().to_string(),
+            bytes: event.size.unwrap_or(0),
+            visitor: None,
+        })?)
+    }
+}
+
+#[derive(Clone, FromRow, Serialize)]
+pub struct AnalyticsHour {
+    pub hour: DateTime<Utc>,
+    pub event: String,
+    pub requests: u64,
+    pub bytes: u64,
+}
+
+#[derive(Clone, FromRow, Serialize)]
+pub struct AnalyticsDay {
+    pub day: NaiveDate,
+    pub uniques: i64,
+}
+
+/// Uploads per day by client app, the client tag or the user-agent product name
+#[derive(Clone, FromRow, Serialize)]
+pub struct AnalyticsClient {
+    pub day: NaiveDate,
+    pub client: String,
+    pub uploads: i64,
+    pub bytes: i64,
+    /// Uploads which were quarantined since
+    pub quarantined: i64,
+}
+
+impl Database {
+    async fn write_analytics(
+        &self,
+        counters: &HashMap<(DateTime<Utc>, String), (u64, u64)>,
+        visitors: &HashSet<(NaiveDate, Vec<u8>)>,
+    ) -> Result<(), sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        for ((hour, event), (requests, bytes)) in counters {
+            sqlx::query(
+                "insert into analytics_hourly(hour,event,requests,bytes) values(?,?,?,?) \
+                on duplicate key update requests = requests + values(requests), \
+                bytes = bytes + values(bytes)",
+            )
+            .bind(hour)
+            .bind(event)
+            .bind(requests)
+            .bind(bytes)
+            .execute(&mut *tx)
+            .await?;
+        }
+        for (day, visitor) in visitors {
+            sqlx::query("insert ignore into analytics_visitors(day,visitor) values(?,?)")
+                .bind(day)
+                .bind(visitor)
+                .execute(&mut *tx)
+                .await?;
+        }
+        tx.commit().await
+    }
+
+    pub async fn list_analytics_hourly(
+        &self,
+        since: DateTime<Utc>,
+    ) -> Result<Vec<AnalyticsHour>, sqlx::Error> {
+        sqlx::query_as("select * from analytics_hourly where hour >= ? order by hour asc")
+            .bind(since)
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    pub async fn list_analytics_clients(
+        &self,
+        since: DateTime<Utc>,
+    ) -> Result<Vec<AnalyticsClient>, sqlx::Error> {
+        sqlx::query_as(
+            "select date(uu.created) as day, \
+            coalesce(uu.client, substring_index(uu.user_agent, '/', 1)) as client, \
+            count(*) as uploads, \
+            cast(sum(u.size) as signed) as bytes, \
+            cast(sum(u.quarantined = 1) as signed) as quarantined \
+            from user_uploads uu join uploads u on u.id = uu.file \
+            where uu.created >= ? and (uu.client is not null or uu.user_agent is not null) \
+            group by day, client order by day asc, uploads desc",
+        )
+        .bind(since)
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    pub async fn list_analytics_daily_uniques(
+        &self,
+        since: DateTime<Utc>,
+    ) -> Result<Vec<AnalyticsDay>, sqlx::Error> {
+        sqlx::query_as(
+            "select day, count(visitor) as uniques from analytics_visitors \
+            where day >= ? group by day order by day asc",
+        )
+        .bind(since.date_naive())
+        .fetch_all(&self.pool)
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn salt_is_kept_for_the_day() {
+        let day = NaiveDate::from_ymd_opt(2025, 2, 25).unwrap();
+        let mut salt = DailySalt::new(day);
+        assert_eq!(salt.get(day), salt.get(day));
+    }
+
+    #[test]
+    fn salt_rotates_with_the_day() {
+        let day = NaiveDate::from_ymd_opt(2025, 2, 25).unwrap();
+        let mut salt = DailySalt::new(day);
+        let first = salt.get(day);
+        let second = salt.get(day.succ_opt().unwrap());
+        assert_ne!(first, second);
+        assert_ne!(DailySalt::new(day).salt, DailySalt::new(day).salt);
+    }
+}
@@ -1,12 +1,82 @@
+use std::io::Cursor;
+
 use anyhow::Error;
 use log::warn;
+use nostr::serde_json::{self, Value};
 use rocket::fairing::{Fairing, Info, Kind};
-use rocket::{Data, Request};
+use rocket::http::Method;
+use rocket::{Data, Request, Response};
+
+use crate::public_url::is_onion;
+use crate::routes::zip::ZipEntries;
 
+pub mod database;
 pub mod plausible;
 
+/// Typed events which are tracked in addition to pageviews
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventKind {
+    UploadComplete,
+    /// Upload of a file which was already stored, tracked along with UploadComplete
+    UploadDedup,
+    Download,
+    Mirror,
+    Delete,
+}
+
+impl EventKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EventKind::UploadComplete => "upload_complete",
+            EventKind::UploadDedup => "upload_dedup",
+            EventKind::Download => "download",
+            EventKind::Mirror => "mirror",
+            EventKind::Delete => "delete",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AnalyticsEvent {
+    pub kind: EventKind,
+    /// Top level mime type (image, video, audio...)
+    pub mime_class: Option<String>,
+    pub size: Option<u64>,
+    pub status: u16,
+}
+
+impl AnalyticsEvent {
+    /// Bucket the size so events don't leak exact file sizes
+    pub fn size_bucket(&self) -> &'static str {
+        const KB: u64 = 1024;
+        const MB: u64 = 1024 * KB;
+        match self.size {
+            None => "unknown",
+            Some(s) if s < 100 * KB => "<100KB",
+            Some(s) if s < MB => "100KB-1MB",
+            Some(s) if s < 10 * MB => "1MB-10MB",
+            Some(s) if s < 100 * MB => "10MB-100MB",
+            Some(_) => ">100MB",
+        }
+    }
+}
+
 pub trait Analytics {
     fn track(&self, req: &Request) -> Result<(), Error>;
+
+    /// Track a typed event, ignored by default
+    fn track_event(&self, _req: &Request, _event: &AnalyticsEvent) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// Map a mime type to its class, eg. image/png => image
+pub fn mime_class(mime: &str) -> String {
+    let class = mime.split('/').next().unwrap_or("").trim().to_lowercase();
+    match class.as_str() {
+        "image" | "video" | "audio" | "text" | "application" => class,
+        _ => "other".to_string(),
+    }
 }
 
 pub struct AnalyticsFairing {
@@ -24,18 +94,137 @@ impl AnalyticsFairing {
     }
 }
 
+/// Pick the event kind for a request by the route which handled it
+fn event_kind(req: &Request) -> Option<EventKind> {
+    let name = req.route()?.name.as_deref()?;
+    match (req.method(), name) {
+        (Method::Put, "upload") | (Method::Put, "upload_media") | (Method::Post, "upload") => {
+            Some(EventKind::UploadComplete)
+        }
+        (Method::Put, "mirror") => Some(EventKind::Mirror),
+        (Method::Get, "get_blob") | (Method::Post, "zip_blobs") => Some(EventKind::Download),
+        (Method::Delete, "delete_blob") | (Method::Delete, "delete") => Some(EventKind::Delete),
+        _ => None,
+    }
+}
+
+/// Whether a blossom blob descriptor or nip96 upload result reused a stored file
+fn upload_dedup(body: &Value) -> bool {
+    body.get("dedup").and_then(|d| d.as_bool()).unwrap_or(false)
+}
+
+/// Read mime type and size from a blossom blob descriptor or nip96 upload result
+fn upload_details(body: &Value) -> (Option<String>, Option<u64>) {
+    if let Some(size) = body.get("size").and_then(|s| s.as_u64()) {
+        let mime = body.get("type").and_then(|t| t.as_str()).map(mime_class);
+        return (mime, Some(size));
+    }
+    let tags = match body
+        .get("nip94_event")
+        .and_then(|e| e.get("tags"))
+        .and_then(|t| t.as_array())
+    {
+        Some(t) => t,
+        None => return (None, None),
+    };
+    let tag = |name: &str| {
+        tags.iter()
+            .find_map(|t| match (t.get(0)?.as_str()?, t.get(1)?.as_str()) {
+                (n, v) if n == name => v,
+                _ => None,
+            })
+    };
+    (
+        tag("m").map(mime_class),
+        tag("size").and_then(|s| s.parse().ok()),
+    )
+}
+
 #[rocket::async_trait]
 impl Fairing for AnalyticsFairing {
     fn info(&self) -> Info {
         Info {
             name: "Analytics",
-            kind: Kind::Request,
+            kind: Kind::Request | Kind::Response,
         }
     }
 
     async fn on_request(&self, req: &mut Request<'_>, _data: &mut Data<'_>) {
+        // onion users expect not to be tracked
+        if is_onion(req) {
+            return;
+        }
         if let Err(e) = self.inner.track(req) {
             warn!("Failed to track! {}", e);
         }
     }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, rsp: &mut Response<'r>) {
+        if is_onion(req) {
+            return;
+        }
+        let kind = match event_kind(req) {
+            Some(k) => k,
+            None => return,
+        };
+        let status = rsp.status();
+        // each file of a zip archive counts as a download
+        if req.route().and_then(|r| r.name.as_deref()) == Some("zip_blobs") {
+            for (mime, size) in &req.local_cache(ZipEntries::default).0 {
+                let event = AnalyticsEvent {
+                    kind,
+                    mime_class: Some(mime_class(mime)),
+                    size: Some(*size),
+                    status: status.code,
+                };
+                if let Err(e) = self.inner.track_event(req, &event) {
+                    warn!("Failed to track event! {}", e);
+                }
+            }
+            return;
+        }
+        let mut dedup = false;
+        let (mime_class, size) = match kind {
+            EventKind::Download => (
+                rsp.content_type().map(|c| mime_class(&c.to_string())),
+                rsp.body().preset_size().map(|s| s as u64),
+            ),
+            EventKind::UploadComplete | EventKind::Mirror if status.class().is_success() => {
+                // upload results are small json documents, read them and put them back
+                match rsp.body_mut().to_bytes().await {
+                    Ok(bytes) => {
+                        let details = match serde_json::from_slice::<Value>(&bytes) {
+                            Ok(v) => {
+                                dedup = upload_dedup(&v);
+                                upload_details(&v)
+                            }
+                            Err(_) => (None, None),
+                        };
+                        rsp.set_sized_body(bytes.len(), Cursor::new(bytes));
+                        details
+                    }
+                    Err(e) => {
+                        warn!("Failed to read upload response: {}", e);
+                        (None, None)
+                    }
+                }
+            }
+            _ => (None, None),
+        };
+        let mut event = AnalyticsEvent {
+            kind,
+            mime_class,
+            size,
+            status: status.code,
+        };
+        if let Err(e) = self.inner.track_event(req, &event) {
+            warn!("Failed to track event! {}", e);
+        }
+        if dedup {
+            event.kind = EventKind::UploadDedup;
+            if let Err(e) = self.inner.track_event(req, &event) {
+                warn!("Failed to track event! {}", e);
+            }
+        }
+    }
 }
@@ -1,4 +1,4 @@
-use crate::analytics::Analytics;
+use crate::analytics::{Analytics, AnalyticsEvent};
 use crate::settings::Settings;
 use anyhow::Error;
 use log::{info, warn};
@@ -6,6 +6,7 @@ use nostr::serde_json;
 use reqwest::ClientBuilder;
 use rocket::Request;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::time::Duration;
 use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
 
@@ -15,6 +16,8 @@ struct Event {
     pub domain: String,
     pub url: String,
     pub referrer: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub props: Option<HashMap<String, String>>,
     #[serde(skip_serializing)]
     pub user_agent: Option<String>,
     #[serde(skip_serializing)]
@@ -71,21 +74,47 @@ impl PlausibleAnalytics {
     }
 }
 
-impl Analytics for PlausibleAnalytics {
-    fn track(&self, req: &Request) -> Result<(), Error> {
-        Ok(self.tx.send(Event {
-            name: "pageview".to_string(),
-            domain: match req.host() {
-                Some(s) => s.to_string(),
-                None => return Ok(()), // ignore request
-            },
+impl PlausibleAnalytics {
+    fn make_event(req: &Request, name: &str) -> Option<Event> {
+        Some(Event {
+            name: name.to_string(),
+            domain: req.host()?.to_string(),
             url: req.uri().to_string(),
             referrer: req.headers().get_one("Referer").map(|s| s.to_string()),
+            props: None,
             user_agent: req.headers().get_one("User-Agent").map(|s| s.to_string()),
             xff: req
                 .headers()
                 .get_one("X-Forwarded-For")
                 .map(|s| s.to_string()),
-        })?)
+        })
+    }
+}
+
+impl Analytics for PlausibleAnalytics {
+    fn track(&self, req: &Request) -> Result<(), Error> {
+        match Self::make_event(req, "pageview") {
+            Some(e) => Ok(self.tx.send(e)?),
+            None => Ok(()), // ignore request
+        }
+    }
+
+    fn track_event(&self, req: &Request, event: &AnalyticsEvent) -> Result<(), Error> {
+        let mut msg = match Self::make_event(req, event.kind.as_str()) {
+            Some(e) => e,
+            None => return Ok(()),
+        };
+        let mut props = HashMap::new();
+        props.insert(
+            "mime_class".to_string(),
+            event
+                .mime_class
+                .clone()
+                .unwrap_or_else(|| "unknown".to_string()),
+        );
+        props.insert("size".to_string(), event.size_bucket().to_string());
+        props.insert("status".to_string(), event.status.to_string());
+        msg.props = Some(props);
+        Ok(self.tx.send(msg)?)
     }
 }
@@ -0,0 +1,148 @@
+use rocket::shield::Shield;
+use rocket::{routes, Build, Rocket};
+
+#[cfg(feature = "analytics")]
+use crate::analytics::database::DatabaseAnalytics;
+#[cfg(feature = "analytics")]
+use crate::analytics::plausible::PlausibleAnalytics;
+#[cfg(feature = "analytics")]
+use crate::analytics::AnalyticsFairing;
+use crate::auth::replay::{ReplayCache, ReplayFairing};
+use crate::background::backup::BackupState;
+use crate::background::disk::DiskState;
+use crate::background::scrub::ScrubState;
+use crate::background::JobCancels;
+use crate::compression::CompressionFairing;
+use crate::cors::CORS;
+use crate::db::Database;
+use crate::filesystem::FileStore;
+use crate::public_url::PublicUrlFairing;
+use crate::queue::ProcessingQueue;
+use crate::routes;
+use crate::routes::{get_blob, head_blob, root, ProgressTracker};
+#[cfg(feature = "analytics")]
+use crate::settings::AnalyticsSink;
+use crate::settings::Settings;
+use crate::shed::LoadShedder;
+use crate::upload_limit::UploadLimiter;
+use crate::vanity::VanityHosts;
+use crate::webhook::Webhook;
+
+/// Build the rocket instance with all state and routes, without starting background tasks.
+///
+/// Used by the server binary and by tests, which can wrap the result in
+/// `rocket::local::asynchronous::Client` to run requests in-memory.
+pub fn build_rocket(
+    config: rocket::Config,
+    settings: Settings,
+    db: Database,
+    scrub_state: ScrubState,
+    disk_state: DiskState,
+    backup_state: BackupState,
+    job_cancels: JobCancels,
+) -> Rocket<Build> {
+    let mut rocket = rocket::Rocket::custom(config)
+        .manage(FileStore::new(settings.clone()))
+        .manage(settings.clone())
+        .manage(db.clone())
+        .manage(scrub_state)
+        .manage(disk_state)
+        .manage(backup_state)
+        .manage(job_cancels)
+        .manage(ReplayCache::new(settings.replay_cache_size))
+        .manage(ProgressTracker::default())
+        .manage(ProcessingQueue::new(&settings))
+        .manage(LoadShedder::new(&settings))
+        .manage(UploadLimiter::new(&settings))
+        .manage(VanityHosts::new(&settings, db.clone()))
+        .manage(
+            settings
+                .webhook_url
+                .as_ref()
+                .map(|w| Webhook::new(w.clone())),
+        )
+        .attach(CORS)
+        .attach(Shield::new()) // disable
+        .attach(PublicUrlFairing::new(&settings))
+        .attach(ReplayFairing)
+        .mount(
+            "/",
+            routes![
+                root,
+                get_blob,
+                head_blob,
+                routes::pin_blob,
+                routes::update_blob,
+                routes::list_variants,
+                routes::void_cat_redirect
+            ],
+        )
+        .mount("/", routes::preview_routes())
+        .mount("/", routes::progress_routes())
+        .mount("/", routes::health_routes())
+        .mount("/", routes::zip_routes())
+        .mount("/", routes::collection_routes())
+        .mount("/", routes::tos_routes())
+        .mount("/admin", routes::admin_routes())
+        .register("/", routes::error::error_catchers());
+
+    if settings.short_links.is_some() {
+        rocket = rocket.mount("/", routes::short_routes());
+    }
+    #[cfg(feature = "analytics")]
+    {
+        let sink = settings.analytics.or(if settings.plausible_url.is_some() {
+            Some(AnalyticsSink::Plausible)
+        } else {
+            None
+        });
+        match sink {
+            Some(AnalyticsSink::Plausible) => {
+                rocket = rocket.attach(AnalyticsFairing::new(PlausibleAnalytics::new(&settings)))
+            }
+            Some(AnalyticsSink::Database) => {
+                rocket = rocket.attach(AnalyticsFairing::new(DatabaseAnalytics::new(db.clone())))
+            }
+            None => {}
+        }
+    }
+    #[cfg(any(feature = "blossom", feature = "nip96"))]
+    {
+        rocket = rocket
+            .attach(routes::UploadFailureFairing)
+            .mount("/", routes::failure_routes());
+    }
+    #[cfg(feature = "blossom")]
+    {
+        rocket = rocket.mount("/", routes::blossom_routes());
+    }
+    #[cfg(feature = "nip96")]
+    {
+        rocket = rocket
+            .manage(routes::DeferredUploads::default())
+            .mount("/", routes::nip96_routes());
+    }
+    #[cfg(feature = "labels")]
+    {
+        rocket = rocket.mount("/", routes::label_routes());
+    }
+    #[cfg(feature = "torrent-v2")]
+    {
+        rocket = rocket.mount("/", routes![routes::get_torrent]);
+    }
+    #[cfg(feature = "media-compression")]
+    {
+        rocket = rocket.mount("/", routes![routes::thumb_redirect]);
+    }
+    #[cfg(feature = "react-ui")]
+    {
+        rocket = rocket
+            .mount("/", routes::ui_routes())
+            .register("/", routes::ui_catchers());
+    }
+    // attached last so the other fairings see the uncompressed body
+    if settings.compression.is_some() {
+        rocket = rocket.attach(CompressionFairing::new(&settings));
+    }
+    rocket
+}
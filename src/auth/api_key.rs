@@ -0,0 +1,157 @@
+use chrono::{DateTime, Utc};
+use nostr::PublicKey;
+use rocket::Request;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::{Error, FromRow};
+
+use crate::db::Database;
+use crate::settings::Settings;
+
+/// Prefix of generated keys, makes them easy to spot in logs and secret scanners
+const KEY_PREFIX: &str = "r96_";
+
+/// Actions an API key is allowed to perform
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ApiKeyScope {
+    Upload,
+    List,
+    Delete,
+}
+
+impl ApiKeyScope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ApiKeyScope::Upload => "upload",
+            ApiKeyScope::List => "list",
+            ApiKeyScope::Delete => "delete",
+        }
+    }
+}
+
+#[derive(Clone, FromRow, Serialize)]
+pub struct ApiKey {
+    pub id: u64,
+    pub user_id: u64,
+    /// Owner of files uploaded with this key
+    #[serde(with = "hex")]
+    pub pubkey: Vec<u8>,
+    pub description: Option<String>,
+    /// Comma separated list of scopes
+    pub scopes: String,
+    pub created: DateTime<Utc>,
+    pub last_used: Option<DateTime<Utc>>,
+    pub revoked: Option<DateTime<Utc>>,
+}
+
+impl ApiKey {
+    pub fn has_scope(&self, scope: ApiKeyScope) -> bool {
+        self.scopes
+            .split(',')
+            .any(|s| s.trim().eq_ignore_ascii_case(scope.as_str()))
+    }
+}
+
+/// Generate a new random key, returns the key and its hash for storage
+pub fn generate_key() -> (String, Vec<u8>) {
+    let key = format!(
+        "{}{}{}",
+        KEY_PREFIX,
+        uuid::Uuid::new_v4().simple(),
+        uuid::Uuid::new_v4().simple()
+    );
+    let hash = Sha256::digest(key.as_bytes()).to_vec();
+    (key, hash)
+}
+
+/// Validate a bearer token from the authorization header
+pub async fn authenticate(
+    request: &Request<'_>,
+    token: &str,
+) -> Result<(PublicKey, ApiKey), &'static str> {
+    let enabled = request
+        .rocket()
+        .state::<Settings>()
+        .and_then(|s| s.api_keys)
+        .unwrap_or(false);
+    if !enabled {
+        return Err("API keys are not enabled");
+    }
+    let db = match request.rocket().state::<Database>() {
+        Some(db) => db,
+        None => return Err("API keys are not available"),
+    };
+    let hash = Sha256::digest(token.trim().as_bytes()).to_vec();
+    let key = match db.get_api_key(&hash).await {
+        Ok(Some(k)) => k,
+        _ => return Err("Invalid API key"),
+    };
+    let pubkey = match PublicKey::from_slice(&key.pubkey) {
+        Ok(p) => p,
+        Err(_) => return Err("Invalid API key"),
+    };
+    let _ = db.touch_api_key(key.id).await;
+    Ok((pubkey, key))
+}
+
+impl Database {
+    pub async fn add_api_key(
+        &self,
+        user_id: u64,
+        key_hash: &[u8],
+        description: Option<&str>,
+        scopes: &[ApiKeyScope],
+    ) -> Result<u64, Error> {
+        let scopes: Vec<&str> = scopes.iter().map(|s| s.as_str()).collect();
+        let res = sqlx::query(
+            "insert into api_keys(user_id,key_hash,description,scopes) values(?,?,?,?)",
+        )
+        .bind(user_id)
+        .bind(key_hash)
+        .bind(description)
+        .bind(scopes.join(","))
+        .execute(&self.pool)
+        .await?;
+        Ok(res.last_insert_id())
+    }
+
+    /// Load an active (not revoked) key by its hash
+    pub async fn get_api_key(&self, key_hash: &[u8]) -> Result<Option<ApiKey>, Error> {
+        sqlx::query_as(
+            "select k.*, u.pubkey from api_keys k, users u \
+            where k.key_hash = ? and k.revoked is null and k.user_id = u.id",
+        )
+        .bind(key_hash)
+        .fetch_optional(&self.pool)
+        .await
+    }
+
+    pub async fn list_api_keys(&self) -> Result<Vec<ApiKey>, Error> {
+        sqlx::query_as(
+            "select k.*, u.pubkey from api_keys k, users u \
+            where k.user_id = u.id \
+            order by k.created desc",
+        )
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    pub async fn revoke_api_key(&self, id: u64) -> Result<bool, Error> {
+        let res = sqlx::query(
+            "update api_keys set revoked = current_timestamp where id = ? and revoked is null",
+        )
+        .bind(id)
+        .execute(&self.pool)
+        .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    async fn touch_api_key(&self, id: u64) -> Result<(), Error> {
+        sqlx::query("update api_keys set last_used = current_timestamp where id = ?")
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+}
@@ -1,16 +1,50 @@
 use base64::prelude::*;
 use log::info;
-use nostr::{Event, JsonUtil, Kind, TagKind, Timestamp};
+use nostr::{Alphabet, Event, JsonUtil, Kind, PublicKey, SingleLetterTag, TagKind, Timestamp};
 use rocket::http::Status;
 use rocket::request::{FromRequest, Outcome};
 use rocket::{async_trait, Request};
 
+use crate::auth::api_key::{self, ApiKey, ApiKeyScope};
+use crate::auth::replay::{replay_cache_full, ReplayCache, ReplayError};
+use crate::auth::{client_tag, AuthClient, AuthPubkey};
+use crate::routes::error::{ApiError, ErrorCode};
+
 pub struct BlossomAuth {
     pub content_type: Option<String>,
+    pub content_length: Option<u64>,
     pub x_content_type: Option<String>,
     pub x_sha_256: Option<String>,
     pub x_content_length: Option<u64>,
-    pub event: Event,
+    pub pubkey: PublicKey,
+    /// Signed auth event, None when authenticated with an API key
+    pub event: Option<Event>,
+    pub api_key: Option<ApiKey>,
+}
+
+impl BlossomAuth {
+    /// Check the auth is valid for a blossom action (`t` tag or API key scope)
+    pub fn allows(&self, action: &str) -> bool {
+        match (&self.event, &self.api_key) {
+            (Some(event), _) => event.tags.iter().any(|t| {
+                t.kind() == TagKind::SingleLetter(SingleLetterTag::lowercase(Alphabet::T))
+                    && t.content().is_some_and(|c| c.eq_ignore_ascii_case(action))
+            }),
+            (None, Some(key)) => match action {
+                "upload" | "media" | "mirror" => key.has_scope(ApiKeyScope::Upload),
+                "delete" => key.has_scope(ApiKeyScope::Delete),
+                "list" => key.has_scope(ApiKeyScope::List),
+                _ => false,
+            },
+            (None, None) => false,
+        }
+    }
+}
+
+/// Reject the request with 401, the reason is sent as X-Reason by the error catcher
+fn reject<T>(request: &Request<'_>, reason: &'static str) -> Outcome<T, &'static str> {
+    request.local_cache(|| Some(ApiError::with_detail(ErrorCode::InvalidAuth, reason)));
+    Outcome::Error((Status::Unauthorized, reason))
 }
 
 #[async_trait]
@@ -18,85 +52,109 @@ impl<'r> FromRequest<'r> for BlossomAuth {
     type Error = &'static str;
 
     async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
-        if let Some(auth) = request.headers().get_one("authorization") {
-            if auth.starts_with("Nostr ") {
-                let event = if let Ok(j) = BASE64_STANDARD.decode(&auth[6..]) {
-                    if let Ok(ev) = Event::from_json(j) {
-                        ev
-                    } else {
-                        return Outcome::Error((Status::new(400), "Invalid nostr event"));
-                    }
+        let auth = match request.headers().get_one("authorization") {
+            Some(a) => a,
+            None => return reject(request, "Auth header not found"),
+        };
+        let (pubkey, event, api_key) = if let Some(token) = auth.strip_prefix("Bearer ") {
+            match api_key::authenticate(request, token).await {
+                Ok((pubkey, key)) => (pubkey, None, Some(key)),
+                Err(e) => return reject(request, e),
+            }
+        } else if auth.starts_with("Nostr ") {
+            let event = if let Ok(j) = BASE64_STANDARD.decode(&auth[6..]) {
+                if let Ok(ev) = Event::from_json(j) {
+                    ev
                 } else {
-                    return Outcome::Error((Status::new(400), "Invalid auth string"));
-                };
+                    return reject(request, "Invalid nostr event");
+                }
+            } else {
+                return reject(request, "Invalid auth string");
+            };
+
+            if event.kind != Kind::Custom(24242) {
+                return reject(request, "Wrong event kind");
+            }
+            if event.created_at > Timestamp::now() {
+                return reject(request, "Created timestamp is in the future");
+            }
 
-                if event.kind != Kind::Custom(24242) {
-                    return Outcome::Error((Status::new(400), "Wrong event kind"));
+            // check expiration tag
+            let u_exp: Timestamp = if let Some(expiration) = event.tags.iter().find_map(|t| {
+                if t.kind() == TagKind::Expiration {
+                    t.content()
+                } else {
+                    None
                 }
-                if event.created_at > Timestamp::now() {
-                    return Outcome::Error((
-                        Status::new(400),
-                        "Created timestamp is in the future",
-                    ));
+            }) {
+                match expiration.parse() {
+                    Ok(e) if e > Timestamp::now() => e,
+                    _ => return reject(request, "Expiration invalid"),
                 }
+            } else {
+                return reject(request, "Missing expiration tag");
+            };
+
+            if event.verify().is_err() {
+                return reject(request, "Event signature invalid");
+            }
 
-                // check expiration tag
-                if let Some(expiration) = event.tags.iter().find_map(|t| {
-                    if t.kind() == TagKind::Expiration {
-                        t.content()
-                    } else {
-                        None
+            if let Some(cache) = request.rocket().state::<ReplayCache>() {
+                match cache.reserve(event.id, request.method(), u_exp) {
+                    Ok(r) => {
+                        request.local_cache(|| Some(r));
                     }
-                }) {
-                    let u_exp: Timestamp = expiration.parse().unwrap();
-                    if u_exp <= Timestamp::now() {
-                        return Outcome::Error((Status::new(400), "Expiration invalid"));
+                    Err(ReplayError::Replayed) => {
+                        return reject(request, "Auth event already used")
                     }
-                } else {
-                    return Outcome::Error((Status::new(400), "Missing expiration tag"));
+                    Err(ReplayError::Full) => return replay_cache_full(request),
                 }
-
-                if event.verify().is_err() {
-                    return Outcome::Error((Status::new(400), "Event signature invalid"));
-                }
-
-                info!("{}", event.as_json());
-                Outcome::Success(BlossomAuth {
-                    event,
-                    content_type: request.headers().iter().find_map(|h| {
-                        if h.name == "content-type" {
-                            Some(h.value.to_string())
-                        } else {
-                            None
-                        }
-                    }),
-                    x_sha_256: request.headers().iter().find_map(|h| {
-                        if h.name == "x-sha-256" {
-                            Some(h.value.to_string())
-                        } else {
-                            None
-                        }
-                    }),
-                    x_content_length: request.headers().iter().find_map(|h| {
-                        if h.name == "x-content-length" {
-                            Some(h.value.parse().unwrap())
-                        } else {
-                            None
-                        }
-                    }),
-                    x_content_type: request.headers().iter().find_map(|h| {
-                        if h.name == "x-content-type" {
-                            Some(h.value.to_string())
-                        } else {
-                            None
-                        }
-                    }),
-                })
-            } else {
-                Outcome::Error((Status::new(400), "Auth scheme must be Nostr"))
             }
+
+            info!("{}", event.as_json());
+            (event.pubkey, Some(event), None)
         } else {
-            Outcome::Error((Status::new(401), "Auth header not found"))
-        }
+            return reject(request, "Auth scheme must be Nostr or Bearer");
+        };
+
+        request.local_cache(|| AuthPubkey(Some(pubkey)));
+        request.local_cache(|| AuthClient(event.as_ref().and_then(client_tag)));
+        Outcome::Success(BlossomAuth {
+            pubkey,
+            event,
+            api_key,
+            content_type: request.headers().iter().find_map(|h| {
+                if h.name == "content-type" {
+                    Some(h.value.to_string())
+                } else {
+                    None
+                }
+            }),
+            content_length: request
+                .headers()
+                .get_one("content-length")
+                .and_then(|v| v.parse().ok()),
+            x_sha_256: request.headers().iter().find_map(|h| {
+                if h.name == "x-sha-256" {
+                    Some(h.value.to_string())
+                } else {
+                    None
+                }
+            }),
+            x_content_length: request.headers().iter().find_map(|h| {
+                if h.name == "x-content-length" {
+                    h.value.parse().ok()
+                } else {
+                    None
+                }
+            }),
+            x_content_type: request.headers().iter().find_map(|h| {
+                if h.name == "x-content-type" {
+                    Some(h.value.to_string())
+                } else {
+                    None
+                }
+            }),
+        })
     }
 }
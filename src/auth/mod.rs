@@ -1,2 +1,25 @@
+use nostr::{Event, PublicKey};
+
+pub mod api_key;
 pub mod blossom;
 pub mod nip98;
+pub mod replay;
+
+/// Pubkey of the authenticated user, cached on the request by the auth guards
+pub struct AuthPubkey(pub Option<PublicKey>);
+
+/// Client named in the auth event (NIP-89 `client` tag), cached on the request by the auth guards
+pub struct AuthClient(pub Option<String>);
+
+/// Maximum length of a stored client name
+const MAX_CLIENT_LEN: usize = 64;
+
+/// Name of the client which signed the auth event
+pub fn client_tag(event: &Event) -> Option<String> {
+    event.tags.iter().find_map(|t| match t.as_slice() {
+        [n, v, ..] if n == "client" && !v.is_empty() => {
+            Some(v.chars().take(MAX_CLIENT_LEN).collect())
+        }
+        _ => None,
+    })
+}
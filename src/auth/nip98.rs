@@ -1,16 +1,139 @@
 use base64::prelude::BASE64_STANDARD;
 use base64::Engine;
 use log::info;
-use nostr::{Event, JsonUtil, Kind, Timestamp};
-use rocket::http::uri::{Absolute, Uri};
-use rocket::http::Status;
+use nostr::{serde_json, Event, JsonUtil, Kind, PublicKey, Timestamp};
+use rocket::http::uri::{Absolute, Origin};
+use rocket::http::{Method, Status};
 use rocket::request::{FromRequest, Outcome};
 use rocket::{async_trait, Request};
+use serde::de::DeserializeOwned;
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::auth::api_key::{self, ApiKey, ApiKeyScope};
+use crate::auth::replay::{replay_cache_full, ReplayCache, ReplayError};
+use crate::auth::{client_tag, AuthClient, AuthPubkey};
+use crate::routes::error::{ApiError, ErrorCode};
+use crate::settings::{Nip98Config, Settings};
+
+/// Default maximum age of an auth event in seconds
+const DEFAULT_MAX_AGE: u64 = 60;
 
 pub struct Nip98Auth {
     pub content_type: Option<String>,
     pub content_length: Option<u64>,
-    pub event: Event,
+    /// SHA-256 of the request body from the payload tag
+    pub payload: Option<Vec<u8>>,
+    pub pubkey: PublicKey,
+    /// Signed auth event, None when authenticated with an API key
+    pub event: Option<Event>,
+    pub api_key: Option<ApiKey>,
+}
+
+impl Nip98Auth {
+    /// Check the request body against the payload tag, if one was provided
+    pub fn check_payload(&self, body: &[u8]) -> bool {
+        match &self.payload {
+            Some(p) => Sha256::digest(body).as_slice() == p.as_slice(),
+            None => true,
+        }
+    }
+
+    /// Parse a json body, rejecting it when it does not match the payload tag
+    pub fn parse_json<T: DeserializeOwned>(&self, body: &[u8]) -> Result<T, ApiError> {
+        if !self.check_payload(body) {
+            return Err(ApiError::with_detail(
+                ErrorCode::InvalidAuth,
+                "Payload hash does not match",
+            ));
+        }
+        serde_json::from_slice(body)
+            .map_err(|e| ApiError::with_detail(ErrorCode::BadRequest, e.to_string()))
+    }
+
+    /// Check a streamed body against the payload tag, if one was provided
+    pub async fn check_payload_reader<R>(&self, mut reader: R) -> std::io::Result<bool>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let payload = match &self.payload {
+            Some(p) => p,
+            None => return Ok(true),
+        };
+        let mut hasher = Sha256::new();
+        let mut buf = vec![0u8; 64 * 1024];
+        loop {
+            let n = reader.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buf[..n]);
+        }
+        Ok(hasher.finalize().as_slice() == payload.as_slice())
+    }
+
+    /// Nostr auth can do anything, API keys are limited to their scopes
+    pub fn has_scope(&self, scope: ApiKeyScope) -> bool {
+        match &self.api_key {
+            Some(k) => k.has_scope(scope),
+            None => true,
+        }
+    }
+}
+
+fn find_tag<'a>(event: &'a Event, name: &str) -> Option<&'a String> {
+    event.tags.iter().find_map(|t| match t.as_slice() {
+        [n, v, ..] if n == name => Some(v),
+        _ => None,
+    })
+}
+
+/// Check an auth event is valid for a request, returns the body hash of its payload tag
+fn check_event(
+    event: &Event,
+    method: Method,
+    uri: &Origin<'_>,
+    config: &Nip98Config,
+    now: Timestamp,
+) -> Result<Option<Vec<u8>>, &'static str> {
+    if event.kind != Kind::HttpAuth {
+        return Err("Wrong event kind");
+    }
+    if event.created_at > now {
+        return Err("Created timestamp is in the future");
+    }
+    let max_age = config.max_age.unwrap_or(DEFAULT_MAX_AGE);
+    if event.created_at.as_u64() + max_age < now.as_u64() {
+        return Err("Auth event is too old");
+    }
+
+    // check url tag
+    let url = find_tag(event, "u").ok_or("Missing url tag")?;
+    let u_req = Absolute::parse(url).map_err(|_| "Invalid U tag")?;
+    if uri.path() != u_req.path() {
+        return Err("U tag does not match");
+    }
+    if config.strict_url && uri.query().map(|q| q.as_str()) != u_req.query().map(|q| q.as_str()) {
+        return Err("U tag query does not match");
+    }
+
+    // check method tag
+    let tag_method = find_tag(event, "method").ok_or("Missing method tag")?;
+    if !method.as_str().eq_ignore_ascii_case(tag_method) {
+        return Err("Method tag incorrect");
+    }
+
+    // payload tag is optional, it is checked against the body by routes which read it
+    let payload = match find_tag(event, "payload").map(hex::decode) {
+        Some(Ok(p)) if p.len() == 32 => Some(p),
+        Some(_) => return Err("Invalid payload tag"),
+        None => None,
+    };
+
+    if event.verify().is_err() {
+        return Err("Event signature invalid");
+    }
+    Ok(payload)
 }
 
 #[async_trait]
@@ -18,91 +141,318 @@ impl<'r> FromRequest<'r> for Nip98Auth {
     type Error = &'static str;
 
     async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
-        if let Some(auth) = request.headers().get_one("authorization") {
-            if auth.starts_with("Nostr ") {
-                let event = if let Ok(j) = BASE64_STANDARD.decode(&auth[6..]) {
-                    if let Ok(ev) = Event::from_json(j) {
-                        ev
-                    } else {
-                        return Outcome::Error((Status::new(403), "Invalid nostr event"));
-                    }
+        let auth = match request.headers().get_one("authorization") {
+            Some(a) => a,
+            None => return Outcome::Error((Status::new(403), "Auth header not found")),
+        };
+        let (pubkey, event, api_key, payload) = if let Some(token) = auth.strip_prefix("Bearer ") {
+            match api_key::authenticate(request, token).await {
+                Ok((pubkey, key)) => (pubkey, None, Some(key), None),
+                Err(e) => return Outcome::Error((Status::new(401), e)),
+            }
+        } else if auth.starts_with("Nostr ") {
+            let event = if let Ok(j) = BASE64_STANDARD.decode(&auth[6..]) {
+                if let Ok(ev) = Event::from_json(j) {
+                    ev
                 } else {
-                    return Outcome::Error((Status::new(403), "Invalid auth string"));
-                };
-
-                if event.kind != Kind::HttpAuth {
-                    return Outcome::Error((Status::new(401), "Wrong event kind"));
-                }
-                if event.created_at > Timestamp::now() {
-                    return Outcome::Error((
-                        Status::new(401),
-                        "Created timestamp is in the future",
-                    ));
+                    return Outcome::Error((Status::new(403), "Invalid nostr event"));
                 }
+            } else {
+                return Outcome::Error((Status::new(403), "Invalid auth string"));
+            };
 
-                // check url tag
-                if let Some(url) = event.tags.iter().find_map(|t| {
-                    let vec = t.as_slice();
-                    if vec[0] == "u" {
-                        Some(vec[1].clone())
-                    } else {
-                        None
+            let config = request
+                .rocket()
+                .state::<Settings>()
+                .and_then(|s| s.nip98.clone())
+                .unwrap_or_default();
+            let payload = match check_event(
+                &event,
+                request.method(),
+                request.uri(),
+                &config,
+                Timestamp::now(),
+            ) {
+                Ok(p) => p,
+                Err(e) => return Outcome::Error((Status::new(401), e)),
+            };
+            let max_age = config.max_age.unwrap_or(DEFAULT_MAX_AGE);
+
+            if let Some(cache) = request.rocket().state::<ReplayCache>() {
+                match cache.reserve(event.id, request.method(), event.created_at + max_age) {
+                    Ok(r) => {
+                        request.local_cache(|| Some(r));
                     }
-                }) {
-                    if let Ok(u_req) = Uri::parse::<Absolute>(&url) {
-                        if request.uri().path() != u_req.absolute().unwrap().path() {
-                            return Outcome::Error((Status::new(401), "U tag does not match"));
-                        }
-                    } else {
-                        return Outcome::Error((Status::new(401), "Invalid U tag"));
+                    Err(ReplayError::Replayed) => {
+                        return Outcome::Error((Status::new(401), "Auth event already used"))
                     }
-                } else {
-                    return Outcome::Error((Status::new(401), "Missing url tag"));
+                    Err(ReplayError::Full) => return replay_cache_full(request),
                 }
+            }
 
-                // check method tag
-                if let Some(method) = event.tags.iter().find_map(|t| {
-                    let vec = t.as_slice();
-                    if vec[0] == "method" {
-                        Some(vec[1].clone())
-                    } else {
-                        None
-                    }
-                }) {
-                    if request.method().to_string() != *method {
-                        return Outcome::Error((Status::new(401), "Method tag incorrect"));
-                    }
+            info!("{}", event.as_json());
+            (event.pubkey, Some(event), None, payload)
+        } else {
+            return Outcome::Error((Status::new(403), "Auth scheme must be Nostr or Bearer"));
+        };
+
+        request.local_cache(|| AuthPubkey(Some(pubkey)));
+        request.local_cache(|| AuthClient(event.as_ref().and_then(client_tag)));
+        Outcome::Success(Nip98Auth {
+            pubkey,
+            event,
+            api_key,
+            payload,
+            content_type: request.headers().iter().find_map(|h| {
+                if h.name == "content-type" {
+                    Some(h.value.to_string())
                 } else {
-                    return Outcome::Error((Status::new(401), "Missing method tag"));
+                    None
                 }
-
-                if let Err(_err) = event.verify() {
-                    return Outcome::Error((Status::new(401), "Event signature invalid"));
+            }),
+            content_length: request.headers().iter().find_map(|h| {
+                if h.name == "content-length" {
+                    h.value.parse().ok()
+                } else {
+                    None
                 }
+            }),
+        })
+    }
+}
 
-                info!("{}", event.as_json());
-                Outcome::Success(Nip98Auth {
-                    event,
-                    content_type: request.headers().iter().find_map(|h| {
-                        if h.name == "content-type" {
-                            Some(h.value.to_string())
-                        } else {
-                            None
-                        }
-                    }),
-                    content_length: request.headers().iter().find_map(|h| {
-                        if h.name == "content-length" {
-                            Some(h.value.parse().unwrap())
-                        } else {
-                            None
-                        }
-                    }),
-                })
-            } else {
-                Outcome::Error((Status::new(403), "Auth scheme must be Nostr"))
-            }
-        } else {
-            Outcome::Error((Status::new(403), "Auth header not found"))
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+
+    use nostr::{Alphabet, EventBuilder, Keys, SingleLetterTag, Tag, TagKind};
+
+    use super::*;
+
+    const URL: &str = "http://localhost:8000/n96?page=0";
+    const CREATED: u64 = 1_700_000_000;
+
+    fn event(kind: Kind, created_at: u64, url: &str, method: &str, payload: Option<&str>) -> Event {
+        let mut tags = vec![
+            Tag::custom(
+                TagKind::SingleLetter(SingleLetterTag::lowercase(Alphabet::U)),
+                [url],
+            ),
+            Tag::custom(TagKind::Custom(Cow::Borrowed("method")), [method]),
+        ];
+        if let Some(p) = payload {
+            tags.push(Tag::custom(TagKind::Custom(Cow::Borrowed("payload")), [p]));
+        }
+        EventBuilder::new(kind, "")
+            .tags(tags)
+            .custom_created_at(Timestamp::from(created_at))
+            .sign_with_keys(&Keys::generate())
+            .unwrap()
+    }
+
+    fn http_auth(url: &str, method: &str, payload: Option<&str>) -> Event {
+        event(Kind::HttpAuth, CREATED, url, method, payload)
+    }
+
+    fn check(
+        event: &Event,
+        method: Method,
+        uri: &str,
+        config: &Nip98Config,
+        now: u64,
+    ) -> Result<Option<Vec<u8>>, &'static str> {
+        check_event(
+            event,
+            method,
+            &Origin::parse(uri).unwrap(),
+            config,
+            Timestamp::from(now),
+        )
+    }
+
+    fn auth(payload: Option<&[u8]>) -> Nip98Auth {
+        Nip98Auth {
+            content_type: None,
+            content_length: None,
+            payload: payload.map(|p| Sha256::digest(p).to_vec()),
+            pubkey: Keys::generate().public_key(),
+            event: None,
+            api_key: None,
+        }
+    }
+
+    #[test]
+    fn accepts_valid_event() {
+        let ev = http_auth(URL, "GET", None);
+        let res = check(
+            &ev,
+            Method::Get,
+            "/n96?page=0",
+            &Default::default(),
+            CREATED + 5,
+        );
+        assert_eq!(res, Ok(None));
+    }
+
+    #[test]
+    fn returns_payload_hash() {
+        let hash = hex::encode(Sha256::digest(b"{}"));
+        let ev = http_auth(URL, "POST", Some(&hash));
+        let res = check(
+            &ev,
+            Method::Post,
+            "/n96?page=0",
+            &Default::default(),
+            CREATED,
+        );
+        assert_eq!(res, Ok(Some(hex::decode(hash).unwrap())));
+    }
+
+    #[test]
+    fn rejects_wrong_kind() {
+        let ev = event(Kind::TextNote, CREATED, URL, "GET", None);
+        let res = check(
+            &ev,
+            Method::Get,
+            "/n96?page=0",
+            &Default::default(),
+            CREATED,
+        );
+        assert_eq!(res, Err("Wrong event kind"));
+    }
+
+    #[test]
+    fn rejects_too_old() {
+        let ev = http_auth(URL, "GET", None);
+        let now = CREATED + DEFAULT_MAX_AGE + 1;
+        let res = check(&ev, Method::Get, "/n96?page=0", &Default::default(), now);
+        assert_eq!(res, Err("Auth event is too old"));
+
+        let config = Nip98Config {
+            max_age: Some(DEFAULT_MAX_AGE * 2),
+            ..Default::default()
+        };
+        let res = check(&ev, Method::Get, "/n96?page=0", &config, now);
+        assert_eq!(res, Ok(None));
+    }
+
+    #[test]
+    fn rejects_future_timestamp() {
+        let ev = http_auth(URL, "GET", None);
+        let res = check(
+            &ev,
+            Method::Get,
+            "/n96?page=0",
+            &Default::default(),
+            CREATED - 1,
+        );
+        assert_eq!(res, Err("Created timestamp is in the future"));
+    }
+
+    #[test]
+    fn rejects_invalid_payload() {
+        for payload in ["not hex", "abcd"] {
+            let ev = http_auth(URL, "POST", Some(payload));
+            let res = check(
+                &ev,
+                Method::Post,
+                "/n96?page=0",
+                &Default::default(),
+                CREATED,
+            );
+            assert_eq!(res, Err("Invalid payload tag"));
         }
     }
+
+    #[test]
+    fn rejects_invalid_url() {
+        let ev = http_auth("/n96", "GET", None);
+        let res = check(&ev, Method::Get, "/n96", &Default::default(), CREATED);
+        assert_eq!(res, Err("Invalid U tag"));
+    }
+
+    #[test]
+    fn rejects_path_mismatch() {
+        let ev = http_auth(URL, "GET", None);
+        let res = check(
+            &ev,
+            Method::Get,
+            "/n96/limits",
+            &Default::default(),
+            CREATED,
+        );
+        assert_eq!(res, Err("U tag does not match"));
+    }
+
+    #[test]
+    fn rejects_query_mismatch_when_strict() {
+        let ev = http_auth(URL, "GET", None);
+        let res = check(
+            &ev,
+            Method::Get,
+            "/n96?page=1",
+            &Default::default(),
+            CREATED,
+        );
+        assert_eq!(res, Ok(None));
+
+        let config = Nip98Config {
+            strict_url: true,
+            ..Default::default()
+        };
+        let res = check(&ev, Method::Get, "/n96?page=1", &config, CREATED);
+        assert_eq!(res, Err("U tag query does not match"));
+        let res = check(&ev, Method::Get, "/n96?page=0", &config, CREATED);
+        assert_eq!(res, Ok(None));
+    }
+
+    #[test]
+    fn rejects_wrong_method() {
+        let ev = http_auth(URL, "GET", None);
+        let res = check(
+            &ev,
+            Method::Delete,
+            "/n96?page=0",
+            &Default::default(),
+            CREATED,
+        );
+        assert_eq!(res, Err("Method tag incorrect"));
+
+        let ev = http_auth(URL, "get", None);
+        let res = check(
+            &ev,
+            Method::Get,
+            "/n96?page=0",
+            &Default::default(),
+            CREATED,
+        );
+        assert_eq!(res, Ok(None));
+    }
+
+    #[test]
+    fn checks_body_against_payload() {
+        assert!(auth(None).check_payload(b"anything"));
+        assert!(auth(Some(b"body")).check_payload(b"body"));
+        assert!(!auth(Some(b"body")).check_payload(b"other"));
+    }
+
+    #[tokio::test]
+    async fn checks_stream_against_payload() {
+        let a = auth(Some(b"streamed body"));
+        assert!(a.check_payload_reader(&b"streamed body"[..]).await.unwrap());
+        assert!(!a.check_payload_reader(&b"other body"[..]).await.unwrap());
+    }
+
+    #[test]
+    fn parse_json_rejects_mismatched_payload() {
+        let body = br#"{"name":"x"}"#;
+        let res: Result<nostr::serde_json::Value, _> = auth(Some(b"{}")).parse_json(body);
+        assert_eq!(res.unwrap_err().code, ErrorCode::InvalidAuth);
+
+        let res: Result<nostr::serde_json::Value, _> = auth(Some(body)).parse_json(body);
+        assert_eq!(res.unwrap()["name"], "x");
+
+        let res: Result<nostr::serde_json::Value, _> = auth(None).parse_json(b"not json");
+        assert_eq!(res.unwrap_err().code, ErrorCode::BadRequest);
+    }
 }
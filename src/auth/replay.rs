@@ -0,0 +1,301 @@
+use std::collections::{BTreeSet, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use log::warn;
+use nostr::{EventId, Timestamp};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::{Method, Status};
+use rocket::request::Outcome;
+use rocket::{Request, Response};
+use serde::Serialize;
+
+use crate::routes::error::{ApiError, ErrorCode};
+
+/// Default number of auth events remembered
+const DEFAULT_CAPACITY: usize = 100_000;
+
+type ReplayKey = (EventId, &'static str);
+
+/// Remembers used auth events so they cannot be replayed within their validity window.
+///
+/// An event is reserved while its request runs and only recorded as used when the request
+/// succeeds, so a client can retry with the same event after a 5xx, 429 or aborted upload.
+/// Events are only forgotten once expired, new events are refused while the cache is full
+pub struct ReplayCache {
+    capacity: usize,
+    inner: Arc<Mutex<ReplayCacheInner>>,
+    rejected: AtomicU64,
+    full: AtomicU64,
+}
+
+struct ReplayEntry {
+    expires: u64,
+    used: bool,
+}
+
+#[derive(Default)]
+struct ReplayCacheInner {
+    seen: HashMap<ReplayKey, ReplayEntry>,
+    /// Keys of `seen` ordered by expiry
+    expiry: BTreeSet<(u64, ReplayKey)>,
+}
+
+impl ReplayCacheInner {
+    fn remove(&mut self, key: &ReplayKey) {
+        if let Some(e) = self.seen.remove(key) {
+            self.expiry.remove(&(e.expires, *key));
+        }
+    }
+}
+
+/// Why an auth event can't be used
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReplayError {
+    /// Already used for this method, or a request with it is still running
+    Replayed,
+    /// Every remembered event is still valid
+    Full,
+}
+
+#[derive(Serialize)]
+pub struct ReplayCacheStats {
+    pub size: usize,
+    pub capacity: usize,
+    pub rejected: u64,
+    /// Events refused because the cache was full
+    pub full: u64,
+}
+
+impl ReplayCache {
+    pub fn new(capacity: Option<usize>) -> Self {
+        Self {
+            capacity: capacity.unwrap_or(DEFAULT_CAPACITY).max(1),
+            inner: Arc::new(Mutex::new(ReplayCacheInner::default())),
+            rejected: AtomicU64::new(0),
+            full: AtomicU64::new(0),
+        }
+    }
+
+    /// Reserve an auth event for a request method until the request ends.
+    ///
+    /// Fails if the event was already used for this method and has not expired, or a request
+    /// with it is still running. HEAD preflight and the following upload use the same event,
+    /// so the method is part of the key.
+    pub fn reserve(
+        &self,
+        id: EventId,
+        method: Method,
+        expires: Timestamp,
+    ) -> Result<ReplayReservation, ReplayError> {
+        let now = Timestamp::now().as_u64();
+        let mut inner = self.inner.lock().unwrap();
+        let key = (id, method.as_str());
+        if inner.seen.get(&key).is_some_and(|e| e.expires >= now) {
+            drop(inner);
+            let n = self.rejected.fetch_add(1, Ordering::Relaxed) + 1;
+            warn!("Rejected replayed auth event {} ({} total)", id, n);
+            return Err(ReplayError::Replayed);
+        }
+
+        // forgetting a valid event would allow replaying it, only expired ones are dropped
+        while let Some(first) = inner.expiry.first().copied() {
+            if first.0 >= now {
+                break;
+            }
+            inner.remove(&first.1);
+        }
+        inner.remove(&key);
+        if inner.seen.len() >= self.capacity {
+            drop(inner);
+            let n = self.full.fetch_add(1, Ordering::Relaxed) + 1;
+            warn!(
+                "Auth replay cache is full, refused event {} ({} total)",
+                id, n
+            );
+            return Err(ReplayError::Full);
+        }
+        let expires = expires.as_u64();
+        inner.seen.insert(
+            key,
+            ReplayEntry {
+                expires,
+                used: false,
+            },
+        );
+        inner.expiry.insert((expires, key));
+        Ok(ReplayReservation {
+            inner: self.inner.clone(),
+            key,
+        })
+    }
+
+    pub fn stats(&self) -> ReplayCacheStats {
+        ReplayCacheStats {
+            size: self.inner.lock().unwrap().seen.len(),
+            capacity: self.capacity,
+            rejected: self.rejected.load(Ordering::Relaxed),
+            full: self.full.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Refuse a request whose auth event could not be remembered, the error catcher renders it
+/// with Retry-After
+pub fn replay_cache_full<T>(request: &Request<'_>) -> Outcome<T, &'static str> {
+    request.local_cache(|| {
+        Some(ApiError::with_detail(
+            ErrorCode::Overloaded,
+            "Too many auth events in use",
+        ))
+    });
+    Outcome::Error((Status::ServiceUnavailable, "Too many auth events in use"))
+}
+
+/// Auth event of a running request, released when dropped unless the request succeeded.
+///
+/// Kept in the request local cache by the auth guards, the request is dropped when the
+/// client disconnects, so aborted requests release their event
+pub struct ReplayReservation {
+    inner: Arc<Mutex<ReplayCacheInner>>,
+    key: ReplayKey,
+}
+
+impl ReplayReservation {
+    /// Record the event as used, it is rejected until it expires
+    pub fn commit(&self) {
+        if let Some(e) = self.inner.lock().unwrap().seen.get_mut(&self.key) {
+            e.used = true;
+        }
+    }
+}
+
+impl Drop for ReplayReservation {
+    fn drop(&mut self) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.seen.get(&self.key).is_some_and(|e| !e.used) {
+            inner.remove(&self.key);
+        }
+    }
+}
+
+/// Commits the auth event of requests which succeeded, see [ReplayCache]
+pub struct ReplayFairing;
+
+#[rocket::async_trait]
+impl Fairing for ReplayFairing {
+    fn info(&self) -> Info {
+        Info {
+            name: "Auth replay protection",
+            kind: Kind::Response,
+        }
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, rsp: &mut Response<'r>) {
+        if let Some(r) = req.local_cache(|| None::<ReplayReservation>) {
+            // failed requests changed nothing, the client may retry with the same event
+            if rsp.status().code < 400 {
+                r.commit();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(n: u8) -> EventId {
+        EventId::from_byte_array([n; 32])
+    }
+
+    fn expires() -> Timestamp {
+        Timestamp::now() + 60
+    }
+
+    #[test]
+    fn committed_event_is_rejected() {
+        let cache = ReplayCache::new(None);
+        let r = cache.reserve(id(1), Method::Put, expires()).unwrap();
+        r.commit();
+        drop(r);
+        assert_eq!(
+            cache.reserve(id(1), Method::Put, expires()).err(),
+            Some(ReplayError::Replayed)
+        );
+        assert_eq!(cache.stats().rejected, 1);
+    }
+
+    #[test]
+    fn running_event_is_rejected() {
+        let cache = ReplayCache::new(None);
+        let _r = cache.reserve(id(1), Method::Put, expires()).unwrap();
+        assert!(cache.reserve(id(1), Method::Put, expires()).is_err());
+    }
+
+    #[test]
+    fn failed_request_can_be_retried() {
+        let cache = ReplayCache::new(None);
+        drop(cache.reserve(id(1), Method::Put, expires()).unwrap());
+        assert_eq!(cache.stats().size, 0);
+        let r = cache.reserve(id(1), Method::Put, expires()).unwrap();
+        r.commit();
+        assert_eq!(cache.stats().size, 1);
+    }
+
+    #[test]
+    fn methods_are_separate() {
+        let cache = ReplayCache::new(None);
+        cache
+            .reserve(id(1), Method::Head, expires())
+            .unwrap()
+            .commit();
+        assert!(cache.reserve(id(1), Method::Put, expires()).is_ok());
+    }
+
+    #[test]
+    fn expired_event_can_be_reused() {
+        let cache = ReplayCache::new(None);
+        let past = Timestamp::now() - 10;
+        cache.reserve(id(1), Method::Put, past).unwrap().commit();
+        assert!(cache.reserve(id(1), Method::Put, expires()).is_ok());
+    }
+
+    #[test]
+    fn full_cache_keeps_valid_events() {
+        let cache = ReplayCache::new(Some(2));
+        for n in 0..2 {
+            cache
+                .reserve(id(n), Method::Put, expires())
+                .unwrap()
+                .commit();
+        }
+        // flooding with new events does not push out a used one
+        assert_eq!(
+            cache.reserve(id(2), Method::Put, expires()).err(),
+            Some(ReplayError::Full)
+        );
+        assert_eq!(
+            cache.reserve(id(0), Method::Put, expires()).err(),
+            Some(ReplayError::Replayed)
+        );
+        assert_eq!(cache.stats().full, 1);
+    }
+
+    #[test]
+    fn expired_events_make_room() {
+        let cache = ReplayCache::new(Some(2));
+        let past = Timestamp::now() - 10;
+        cache.reserve(id(0), Method::Put, past).unwrap().commit();
+        cache
+            .reserve(id(1), Method::Put, expires())
+            .unwrap()
+            .commit();
+        cache
+            .reserve(id(2), Method::Put, expires())
+            .unwrap()
+            .commit();
+        assert_eq!(cache.stats().size, 2);
+        assert!(cache.reserve(id(1), Method::Put, expires()).is_err());
+    }
+}
@@ -0,0 +1,135 @@
+use std::borrow::Cow;
+use std::time::Duration;
+
+use anyhow::{bail, Error};
+use log::{error, info};
+use nostr::{serde_json, Alphabet, Event, EventBuilder, Keys, Kind, SingleLetterTag, Tag, TagKind};
+use nostr_sdk::Client;
+use tokio::task::JoinHandle;
+
+use crate::routes::ServerInfo;
+use crate::settings::{AnnounceKind, Settings};
+
+/// Default time between announcements, 12 hours
+const DEFAULT_INTERVAL: u64 = 43200;
+
+/// NIP-89 handler information
+const KIND_HANDLER: u16 = 31990;
+
+/// Blossom server listing, addressed by the public url
+const KIND_BLOSSOM_SERVER: u16 = 36363;
+
+/// Periodically publishes a replaceable event describing this server to relays
+pub struct Announcer {
+    keys: Keys,
+    settings: Settings,
+}
+
+impl Announcer {
+    pub fn new(settings: Settings) -> Result<Option<Self>, Error> {
+        match &settings.announce {
+            Some(cfg) => Ok(Some(Self {
+                keys: Keys::parse(&cfg.server_key)?,
+                settings,
+            })),
+            None => Ok(None),
+        }
+    }
+
+    pub fn start(self) -> JoinHandle<()> {
+        let interval = self
+            .settings
+            .announce
+            .as_ref()
+            .and_then(|a| a.interval)
+            .unwrap_or(DEFAULT_INTERVAL);
+        tokio::spawn(async move {
+            loop {
+                if let Err(e) = self.publish().await {
+                    error!("Failed to announce server: {}", e);
+                }
+                tokio::time::sleep(Duration::from_secs(interval)).await;
+            }
+        })
+    }
+
+    /// Build the announcement event from the current settings
+    pub fn build_event(&self) -> Result<Event, Error> {
+        let cfg = match &self.settings.announce {
+            Some(c) => c,
+            None => bail!("Announce is not configured"),
+        };
+        let info = ServerInfo::from_settings(&self.settings);
+        let url = info.public_url.clone();
+
+        let mut tags = vec![Tag::identifier(url.clone())];
+        tags.extend(info.features.iter().map(|f| Tag::hashtag(*f)));
+        for p in cfg.pricing.as_deref().unwrap_or_default() {
+            tags.push(Tag::custom(
+                TagKind::Custom(Cow::Borrowed("price")),
+                [p.amount.clone(), p.currency.clone(), p.unit.clone()],
+            ));
+        }
+        let (kind, content) = match cfg.kind.unwrap_or_default() {
+            AnnounceKind::Handler => {
+                // event kinds this server handles, nip94 file metadata and blossom auth
+                for k in ["1063", "24242"] {
+                    tags.push(Tag::custom(
+                        TagKind::SingleLetter(SingleLetterTag::lowercase(Alphabet::K)),
+                        [k],
+                    ));
+                }
+                tags.push(Tag::custom(
+                    TagKind::Custom(Cow::Borrowed("web")),
+                    [url.clone()],
+                ));
+                let metadata = serde_json::json!({
+                    "name": info.name,
+                    "about": info.description,
+                    "website": url,
+                });
+                (KIND_HANDLER, metadata.to_string())
+            }
+            AnnounceKind::Blossom => {
+                tags.push(Tag::custom(
+                    TagKind::SingleLetter(SingleLetterTag::lowercase(Alphabet::R)),
+                    [url.clone()],
+                ));
+                (KIND_BLOSSOM_SERVER, info.description.unwrap_or_default())
+            }
+        };
+        if let Some(tos) = info.tos_url {
+            tags.push(Tag::custom(TagKind::Custom(Cow::Borrowed("tos")), [tos]));
+        }
+        tags.push(Tag::custom(
+            TagKind::Custom(Cow::Borrowed("max_upload_bytes")),
+            [info.max_upload_bytes.to_string()],
+        ));
+
+        Ok(EventBuilder::new(Kind::Custom(kind), content)
+            .tags(tags)
+            .sign_with_keys(&self.keys)?)
+    }
+
+    pub async fn publish(&self) -> Result<(), Error> {
+        let cfg = match &self.settings.announce {
+            Some(c) => c,
+            None => return Ok(()),
+        };
+        let ev = self.build_event()?;
+        let client = Client::new(self.keys.clone());
+        for r in &cfg.relays {
+            client.add_relay(r).await?;
+        }
+        client.connect().await;
+        let out = client.send_event(ev).await?;
+        info!(
+            "Announced server as {} to {} relays ({} failed)",
+            out.val,
+            out.success.len(),
+            out.failed.len()
+        );
+        client.disconnect().await?;
+        Ok(())
+    }
+}
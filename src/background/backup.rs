@@ -0,0 +1,307 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{bail, Error};
+use chrono::{DateTime, Utc};
+use log::{error, info, warn};
+use nostr::serde_json;
+use rocket::futures::TryStreamExt;
+use serde::Serialize;
+use sqlx::Row;
+use tokio::fs::File;
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::process::Command;
+use tokio::sync::RwLock;
+use tokio::task::JoinHandle;
+
+use crate::db::Database;
+use crate::settings::{BackupConfig, Settings};
+
+/// Default time between backups
+const DEFAULT_INTERVAL: u64 = 86400;
+
+/// Default number of backups kept
+const DEFAULT_KEEP: usize = 7;
+
+/// Default time the upload command may run before it is killed
+const DEFAULT_COMMAND_TIMEOUT: u64 = 3600;
+
+/// Tables exported when none are configured
+const DEFAULT_TABLES: [&str; 3] = ["users", "uploads", "user_uploads"];
+
+/// Summary of the most recent backup
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct BackupStatus {
+    pub running: bool,
+    pub last_started: Option<DateTime<Utc>>,
+    pub last_finished: Option<DateTime<Utc>>,
+    /// Directory of the last complete backup
+    pub last_path: Option<PathBuf>,
+    pub last_error: Option<String>,
+    /// Rows exported per table
+    pub tables: HashMap<String, u64>,
+    /// Files listed in the storage manifest
+    pub files: u64,
+    /// Backups kept in the destination, oldest first
+    pub kept: Vec<String>,
+}
+
+/// Shared backup status, readable from the admin routes
+pub type BackupState = Arc<RwLock<BackupStatus>>;
+
+/// File listed in the storage manifest
+#[derive(Serialize)]
+struct ManifestEntry {
+    pub path: String,
+    pub size: u64,
+    pub modified: Option<DateTime<Utc>>,
+}
+
+impl Database {
+    /// Write every row of a table as a JSON object per line, binary columns are hex encoded.
+    ///
+    /// Returns the number of rows written
+    pub async fn export_table<W>(&self, table: &str, out: &mut W) -> Result<u64, Error>
+    where
+        W: AsyncWriteExt + Unpin,
+    {
+        let columns = sqlx::query(
+            "select column_name, data_type from information_schema.columns \
+            where table_schema = database() and table_name = ? \
+            order by ordinal_position",
+        )
+        .bind(table)
+        .fetch_all(&self.pool)
+        .await?;
+        if columns.is_empty() {
+            bail!("Table {} does not exist", table);
+        }
+        let mut fields = Vec::with_capacity(columns.len());
+        for c in columns {
+            let name: String = c.try_get(0)?;
+            let data_type: String = c.try_get(1)?;
+            let value = match data_type.as_str() {
+                "binary" | "varbinary" | "blob" | "mediumblob" | "longblob" => {
+                    format!("hex(`{}`)", name)
+                }
+                _ => format!("`{}`", name),
+            };
+            fields.push(format!("'{}', {}", name, value));
+        }
+        let sql = format!(
+            "select cast(json_object({}) as char) from `{}`",
+            fields.join(", "),
+            table
+        );
+        let mut rows = sqlx::query(&sql).fetch(&self.pool);
+        let mut n = 0;
+        while let Some(r) = rows.try_next().await? {
+            let line: String = r.try_get(0)?;
+            out.write_all(line.as_bytes()).await?;
+            out.write_all(b"\n").await?;
+            n += 1;
+        }
+        Ok(n)
+    }
+}
+
+/// Periodically exports the database tables and a manifest of the stored files
+pub struct BackupTask {
+    db: Database,
+    settings: Settings,
+    state: BackupState,
+}
+
+impl BackupTask {
+    pub fn new(db: Database, settings: Settings, state: BackupState) -> Self {
+        Self {
+            db,
+            settings,
+            state,
+        }
+    }
+
+    pub fn start(self) -> JoinHandle<()> {
+        let interval = self
+            .settings
+            .backup
+            .as_ref()
+            .and_then(|b| b.interval)
+            .unwrap_or(DEFAULT_INTERVAL);
+        tokio::spawn(async move {
+            loop {
+                if let Err(e) = self.run_once().await {
+                    error!("Backup failed: {}", e);
+                }
+                tokio::time::sleep(Duration::from_secs(interval)).await;
+            }
+        })
+    }
+
+    pub async fn run_once(&self) -> Result<(), Error> {
+        let cfg = match &self.settings.backup {
+            Some(c) => c,
+            None => return Ok(()),
+        };
+        {
+            let mut state = self.state.write().await;
+            state.running = true;
+            state.last_started = Some(Utc::now());
+        }
+        let res = self.backup(cfg).await;
+        let kept = prune(&cfg.dir, cfg.keep.unwrap_or(DEFAULT_KEEP)).await;
+
+        let mut state = self.state.write().await;
+        state.running = false;
+        state.last_finished = Some(Utc::now());
+        match kept {
+            Ok(k) => state.kept = k,
+            Err(e) => warn!("Failed to remove old backups: {}", e),
+        }
+        match res {
+            Ok((path, tables, files)) => {
+                info!("Backup written to {}", path.display());
+                state.last_path = Some(path);
+                state.last_error = None;
+                state.tables = tables;
+                state.files = files;
+                Ok(())
+            }
+            Err(e) => {
+                state.last_error = Some(e.to_string());
+                Err(e)
+            }
+        }
+    }
+
+    /// Write a backup into a new directory, returns the directory with the rows per table
+    /// and the number of files in the manifest
+    async fn backup(
+        &self,
+        cfg: &BackupConfig,
+    ) -> Result<(PathBuf, HashMap<String, u64>, u64), Error> {
+        let name = Utc::now().format("%Y%m%d-%H%M%S").to_string();
+        // written to a temp name so incomplete backups are never listed or kept
+        let tmp = cfg.dir.join(format!(".{}", name));
+        tokio::fs::create_dir_all(&tmp).await?;
+        let res = self.write_backup(cfg, &tmp).await;
+        let res = match res {
+            Ok(r) => {
+                let path = cfg.dir.join(&name);
+                tokio::fs::rename(&tmp, &path).await?;
+                Ok((path, r.0, r.1))
+            }
+            Err(e) => {
+                let _ = tokio::fs::remove_dir_all(&tmp).await;
+                Err(e)
+            }
+        }?;
+        if let Some(cmd) = &cfg.command {
+            run_command(cmd, &res.0, cfg.command_timeout).await?;
+        }
+        Ok(res)
+    }
+
+    async fn write_backup(
+        &self,
+        cfg: &BackupConfig,
+        dir: &Path,
+    ) -> Result<(HashMap<String, u64>, u64), Error> {
+        let tables: Vec<String> = match &cfg.tables {
+            Some(t) => t.clone(),
+            None => DEFAULT_TABLES.iter().map(|t| t.to_string()).collect(),
+        };
+        let mut rows = HashMap::new();
+        for t in tables {
+            let mut out = BufWriter::new(File::create(dir.join(format!("{}.jsonl", t))).await?);
+            let n = self.db.export_table(&t, &mut out).await?;
+            out.flush().await?;
+            rows.insert(t, n);
+        }
+
+        let mut roots = vec![PathBuf::from(&self.settings.storage_dir)];
+        roots.extend(
+            self.settings
+                .storage_roots
+                .iter()
+                .flatten()
+                .map(|r| r.path.clone()),
+        );
+        let entries = tokio::task::spawn_blocking(move || {
+            let mut entries = Vec::new();
+            for root in roots {
+                list_files(&root, &mut entries)?;
+            }
+            Ok::<_, std::io::Error>(entries)
+        })
+        .await??;
+        let mut out = BufWriter::new(File::create(dir.join("manifest.jsonl")).await?);
+        for e in &entries {
+            out.write_all(&serde_json::to_vec(e)?).await?;
+            out.write_all(b"\n").await?;
+        }
+        out.flush().await?;
+        Ok((rows, entries.len() as u64))
+    }
+}
+
+/// Recursively list the files below `dir`
+fn list_files(dir: &Path, out: &mut Vec<ManifestEntry>) -> Result<(), std::io::Error> {
+    let rd = match std::fs::read_dir(dir) {
+        Ok(r) => r,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    for entry in rd {
+        let entry = entry?;
+        let meta = entry.metadata()?;
+        let path = entry.path();
+        if meta.is_dir() {
+            list_files(&path, out)?;
+        } else if meta.is_file() {
+            out.push(ManifestEntry {
+                path: path.display().to_string(),
+                size: meta.len(),
+                modified: meta.modified().ok().map(DateTime::<Utc>::from),
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Remove the oldest backups, the directory names sort by time. Returns the kept backups
+async fn prune(dir: &Path, keep: usize) -> Result<Vec<String>, Error> {
+    let mut names = Vec::new();
+    let mut rd = tokio::fs::read_dir(dir).await?;
+    while let Some(e) = rd.next_entry().await? {
+        let name = e.file_name().to_string_lossy().to_string();
+        if !name.starts_with('.') && e.file_type().await?.is_dir() {
+            names.push(name);
+        }
+    }
+    names.sort();
+    let remove = names.len().saturating_sub(keep);
+    for n in names.drain(..remove) {
+        info!("Removing old backup {}", n);
+        tokio::fs::remove_dir_all(dir.join(n)).await?;
+    }
+    Ok(names)
+}
+
+/// Run the upload command with the backup directory as its argument
+async fn run_command(cmd: &Path, backup: &Path, timeout: Option<u64>) -> Result<(), Error> {
+    let mut child = Command::new(cmd).arg(backup).kill_on_drop(true).spawn()?;
+    let timeout = Duration::from_secs(timeout.unwrap_or(DEFAULT_COMMAND_TIMEOUT));
+    match tokio::time::timeout(timeout, child.wait()).await {
+        Ok(status) => {
+            let status = status?;
+            if !status.success() {
+                bail!("Backup command exited with {}", status);
+            }
+            Ok(())
+        }
+        Err(_) => bail!("Backup command timed out"),
+    }
+}
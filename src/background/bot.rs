@@ -0,0 +1,255 @@
+use std::collections::{HashSet, VecDeque};
+use std::io::Cursor;
+use std::time::Duration;
+
+use anyhow::{bail, Error};
+use base64::prelude::*;
+use log::{error, info, warn};
+use nostr::nips::nip04;
+use nostr::{Event, EventBuilder, EventId, Filter, Keys, Kind, PublicKey, Timestamp, ToBech32};
+use nostr_sdk::{Client, RelayPoolNotification};
+use tokio::task::JoinHandle;
+
+use crate::background::disk::DiskState;
+use crate::background::retention::upload_expiry;
+use crate::db::Database;
+use crate::filesystem::FileStore;
+use crate::hooks;
+use crate::mirror;
+use crate::routes::{check_blocked_upload, check_disk_space, check_quota};
+use crate::settings::Settings;
+
+/// Default maximum size of a base64 payload sent in a message
+const DEFAULT_MAX_INLINE_BYTES: usize = 64 * 1024;
+
+/// Gift wraps are backdated up to 2 days (NIP-59)
+const GIFT_WRAP_LOOKBACK: u64 = 2 * 86400;
+
+/// Number of handled event ids remembered to skip duplicates from multiple relays
+const SEEN_CAPACITY: usize = 10_000;
+
+/// Delay before reconnecting after the relay connection ended
+const RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+/// Stores urls or small base64 payloads sent as direct messages (NIP-04 / NIP-17)
+/// to the server key, replying with the url of the stored file
+pub struct UploadBot {
+    keys: Keys,
+    db: Database,
+    fs: FileStore,
+    settings: Settings,
+    disk: DiskState,
+}
+
+impl UploadBot {
+    pub fn new(db: Database, settings: Settings, disk: DiskState) -> Result<Option<Self>, Error> {
+        let keys = match &settings.bot {
+            Some(b) => Keys::parse(&b.server_key)?,
+            None => return Ok(None),
+        };
+        Ok(Some(Self {
+            keys,
+            db,
+            fs: FileStore::new(settings.clone()),
+            settings,
+            disk,
+        }))
+    }
+
+    pub fn start(self) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            loop {
+                if let Err(e) = self.listen().await {
+                    error!("Upload bot failed: {}", e);
+                }
+                tokio::time::sleep(RECONNECT_DELAY).await;
+            }
+        })
+    }
+
+    async fn listen(&self) -> Result<(), Error> {
+        let cfg = match &self.settings.bot {
+            Some(c) => c,
+            None => return Ok(()),
+        };
+        let client = Client::new(self.keys.clone());
+        for r in &cfg.relays {
+            client.add_relay(r).await?;
+        }
+        client.connect().await;
+        let me = self.keys.public_key();
+        client
+            .subscribe(
+                vec![
+                    Filter::new()
+                        .kind(Kind::EncryptedDirectMessage)
+                        .pubkey(me)
+                        .since(Timestamp::now()),
+                    Filter::new()
+                        .kind(Kind::GiftWrap)
+                        .pubkey(me)
+                        .since(Timestamp::now() - GIFT_WRAP_LOOKBACK),
+                ],
+                None,
+            )
+            .await?;
+        info!("Upload bot listening as {}", me.to_bech32()?);
+
+        let started = Timestamp::now();
+        let mut seen: HashSet<EventId> = HashSet::new();
+        let mut seen_order: VecDeque<EventId> = VecDeque::new();
+        let mut notifications = client.notifications();
+        while let Ok(n) = notifications.recv().await {
+            let event = match n {
+                RelayPoolNotification::Event { event, .. } => event,
+                RelayPoolNotification::Shutdown => break,
+                _ => continue,
+            };
+            if !seen.insert(event.id) {
+                continue;
+            }
+            seen_order.push_back(event.id);
+            if seen_order.len() > SEEN_CAPACITY {
+                if let Some(old) = seen_order.pop_front() {
+                    seen.remove(&old);
+                }
+            }
+            let (sender, message, nip17) = match self.open(&client, &event, started).await {
+                Ok(Some(m)) => m,
+                Ok(None) => continue,
+                Err(e) => {
+                    warn!("Failed to read message {}: {}", event.id, e);
+                    continue;
+                }
+            };
+            let reply = match self.handle(&sender, message.trim()).await {
+                Ok(url) => url,
+                Err(e) => format!("Upload failed: {}", e),
+            };
+            let sent = if nip17 {
+                client
+                    .send_private_msg(sender, reply, [])
+                    .await
+                    .map(|_| ())
+                    .map_err(Error::from)
+            } else {
+                match EventBuilder::encrypted_direct_msg(&self.keys, sender, reply, None) {
+                    Ok(b) => client
+                        .send_event_builder(b)
+                        .await
+                        .map(|_| ())
+                        .map_err(Error::from),
+                    Err(e) => Err(Error::from(e)),
+                }
+            };
+            if let Err(e) = sent {
+                warn!("Failed to reply to {}: {}", sender, e);
+            }
+        }
+        client.disconnect().await?;
+        Ok(())
+    }
+
+    /// Decrypt a direct message, returns (sender, message, is NIP-17)
+    async fn open(
+        &self,
+        client: &Client,
+        event: &Event,
+        started: Timestamp,
+    ) -> Result<Option<(PublicKey, String, bool)>, Error> {
+        match event.kind {
+            Kind::EncryptedDirectMessage => {
+                let msg = nip04::decrypt(self.keys.secret_key(), &event.pubkey, &event.content)?;
+                Ok(Some((event.pubkey, msg, false)))
+            }
+            Kind::GiftWrap => {
+                let gift = client.unwrap_gift_wrap(event).await?;
+                // backdated wraps are fetched again on every reconnect
+                if gift.rumor.kind != Kind::PrivateDirectMessage || gift.rumor.created_at < started
+                {
+                    return Ok(None);
+                }
+                Ok(Some((gift.sender, gift.rumor.content, true)))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Store the url or base64 payload of a message, returning the public url
+    async fn handle(&self, sender: &PublicKey, message: &str) -> Result<String, Error> {
+        if let Some(wl) = &self.settings.whitelist {
+            if !wl.contains(&sender.to_hex()) {
+                bail!("Not on whitelist");
+            }
+        }
+        check_disk_space(&self.disk, &self.settings, None)?;
+        let pubkey = sender.to_bytes().to_vec();
+        let blob = if message.starts_with("https://") || message.starts_with("http://") {
+            let rsp = mirror::fetch(&self.settings, message).await?;
+            check_disk_space(&self.disk, &self.settings, rsp.content_length())?;
+            check_quota(&self.db, &self.settings, &pubkey, rsp.content_length()).await?;
+            let mime_type = rsp
+                .headers()
+                .get("content-type")
+                .and_then(|h| h.to_str().ok())
+                .unwrap_or("application/octet-stream")
+                .to_string();
+            let stream = mirror::response_reader(&self.settings, rsp);
+            self.fs.put(stream, &mime_type, false).await?
+        } else {
+            let max = self
+                .settings
+                .bot
+                .as_ref()
+                .and_then(|b| b.max_inline_bytes)
+                .unwrap_or(DEFAULT_MAX_INLINE_BYTES);
+            if message.len() > max * 4 / 3 + 4 {
+                bail!("Message too large, send a url instead");
+            }
+            let data = match BASE64_STANDARD.decode(message) {
+                Ok(d) => d,
+                Err(_) => bail!("Send a url or base64 encoded file"),
+            };
+            check_quota(&self.db, &self.settings, &pubkey, Some(data.len() as u64)).await?;
+            self.fs
+                .put(Cursor::new(data), "application/octet-stream", false)
+                .await?
+        };
+        let mut blob = blob;
+        let mime_type = blob.upload.mime_type.clone();
+        check_blocked_upload(&pubkey, None, &mime_type, &blob, &self.db, &self.settings).await?;
+
+        blob.upload.expires = upload_expiry(&self.db, &self.settings, &pubkey).await;
+        let user_id = self.db.upsert_user(&pubkey).await?;
+        let id = hex::encode(&blob.upload.id);
+        match self.db.add_file(&blob.upload, user_id).await {
+            Ok(()) => {
+                info!("Bot stored {} for {}", id, sender.to_hex());
+                hooks::post_store(&self.settings, &blob.upload, &pubkey);
+            }
+            // already uploaded by this user, reply with the existing url
+            Err(e) if is_duplicate(&e) => {}
+            Err(e) => {
+                if let Ok(None) = self.db.get_file(&blob.upload.id).await {
+                    let _ = std::fs::remove_file(&blob.path);
+                }
+                return Err(e.into());
+            }
+        }
+        Ok(format!(
+            "{}/{}{}",
+            self.settings.public_url,
+            id,
+            mime2ext::mime2ext(&mime_type)
+                .map(|m| format!(".{m}"))
+                .unwrap_or_default()
+        ))
+    }
+}
+
+fn is_duplicate(e: &sqlx::Error) -> bool {
+    e.as_database_error()
+        .and_then(|d| d.code())
+        .map(|c| c == "23000")
+        .unwrap_or(false)
+}
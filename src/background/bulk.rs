@@ -0,0 +1,256 @@
+use anyhow::{bail, Error};
+use chrono::{DateTime, Utc};
+use log::info;
+use serde::{Deserialize, Serialize};
+use sqlx::Error as SqlxError;
+
+#[cfg(feature = "media-compression")]
+use crate::background::enqueue;
+#[cfg(feature = "media-compression")]
+use crate::background::reprocess::{ReprocessJob, REPROCESS_JOB};
+use crate::background::JobHandler;
+use crate::db::{Database, Job};
+use crate::deletion::{DeleteReason, DeletionService};
+use crate::filesystem::FileStore;
+use crate::legal_hold::OnLegalHold;
+use crate::settings::Settings;
+
+pub const BULK_JOB: &str = "bulk";
+
+/// Number of files loaded per query
+const PAGE_SIZE: u32 = 500;
+
+/// Selects the files a bulk action applies to, all set fields must match
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BulkFilter {
+    pub mime_prefix: Option<String>,
+    /// Uploader pubkey (hex)
+    pub uploader: Option<String>,
+    /// Uploaded at or after this unix timestamp
+    pub since: Option<i64>,
+    /// Uploaded before this unix timestamp
+    pub until: Option<i64>,
+    pub label: Option<String>,
+}
+
+impl BulkFilter {
+    /// A filter without any criteria would match every file
+    pub fn is_empty(&self) -> bool {
+        self.mime_prefix.is_none()
+            && self.uploader.is_none()
+            && self.since.is_none()
+            && self.until.is_none()
+            && self.label.is_none()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum BulkAction {
+    Delete,
+    Quarantine,
+    Reprocess {
+        #[serde(default)]
+        transcode: bool,
+    },
+    AddLabel {
+        label: String,
+    },
+}
+
+/// Payload of a bulk job
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BulkJob {
+    pub filter: BulkFilter,
+    pub action: BulkAction,
+}
+
+/// Applies an action to every file matching a filter, recording progress on the job
+pub struct BulkHandler {
+    db: Database,
+    fs: FileStore,
+    settings: Settings,
+}
+
+impl BulkHandler {
+    pub fn new(db: Database, settings: Settings) -> Self {
+        Self {
+            db,
+            fs: FileStore::new(settings.clone()),
+            settings,
+        }
+    }
+
+    async fn apply(&self, id: &Vec<u8>, action: &BulkAction) -> Result<(), Error> {
+        match action {
+            BulkAction::Delete => {
+                match DeletionService::new(&self.db, &self.fs, &self.settings)
+                    .purge(id, None, DeleteReason::Admin)
+                    .await
+                {
+                    // held files stay, the rest of the job continues
+                    Err(e) if e.is::<OnLegalHold>() => {}
+                    r => {
+                        r?;
+                    }
+                }
+            }
+            BulkAction::Quarantine => self.db.set_file_quarantined(id, true).await?,
+            #[cfg(feature = "media-compression")]
+            BulkAction::Reprocess { transcode } => {
+                enqueue(
+                    &self.db,
+                    REPROCESS_JOB,
+                    &ReprocessJob {
+                        file: id.clone(),
+                        transcode: *transcode,
+                    },
+                )
+                .await?;
+            }
+            #[cfg(not(feature = "media-compression"))]
+            BulkAction::Reprocess { .. } => bail!("Reprocessing requires media-compression"),
+            BulkAction::AddLabel { label } => self.db.add_file_label(id, label, "admin").await?,
+        }
+        Ok(())
+    }
+}
+
+#[rocket::async_trait]
+impl JobHandler for BulkHandler {
+    fn kind(&self) -> &'static str {
+        BULK_JOB
+    }
+
+    fn max_attempts(&self) -> u32 {
+        3
+    }
+
+    async fn run(&self, job: &Job) -> Result<(), Error> {
+        let req: BulkJob = job.payload()?;
+        if req.filter.is_empty() {
+            bail!("Bulk filter is empty");
+        }
+        let uploader = match &req.filter.uploader {
+            Some(p) => Some(nostr::PublicKey::parse(p)?.to_bytes().to_vec()),
+            None => None,
+        };
+
+        // page by id so deleted rows do not shift the offset
+        let mut after = vec![];
+        let mut done = 0u64;
+        while !job.cancel.is_cancelled() {
+            let files = self
+                .db
+                .list_files_by_filter(&req.filter, uploader.as_ref(), &after, PAGE_SIZE)
+                .await?;
+            let last = match files.last() {
+                Some(l) => l.clone(),
+                None => break,
+            };
+            for id in &files {
+                if job.cancel.is_cancelled() {
+                    break;
+                }
+                self.apply(id, &req.action).await?;
+                done += 1;
+            }
+            self.db
+                .set_job_progress(job.id, &format!("{} files", done))
+                .await?;
+            after = last;
+        }
+        info!(
+            "Bulk job {} applied {:?} to {} files",
+            job.id, req.action, done
+        );
+        Ok(())
+    }
+}
+
+impl Database {
+    pub async fn list_files_by_filter(
+        &self,
+        filter: &BulkFilter,
+        uploader: Option<&Vec<u8>>,
+        after: &Vec<u8>,
+        limit: u32,
+    ) -> Result<Vec<Vec<u8>>, SqlxError> {
+        let since = filter
+            .since
+            .and_then(|t| DateTime::<Utc>::from_timestamp(t, 0));
+        let until = filter
+            .until
+            .and_then(|t| DateTime::<Utc>::from_timestamp(t, 0));
+        sqlx::query_scalar(
+            "select u.id from uploads u \
+            where (? is null or u.mime_type like concat(?, '%')) \
+            and (? is null or u.id in (\
+                select uu.file from user_uploads uu, users us \
+                where us.pubkey = ? and us.id = uu.user_id)) \
+            and (? is null or u.created >= ?) \
+            and (? is null or u.created < ?) \
+            and (? is null or u.id in (select l.file from upload_labels l where l.label = ?)) \
+            and u.id > ? \
+            order by u.id \
+            limit ?",
+        )
+        .bind(&filter.mime_prefix)
+        .bind(&filter.mime_prefix)
+        .bind(uploader)
+        .bind(uploader)
+        .bind(since)
+        .bind(since)
+        .bind(until)
+        .bind(until)
+        .bind(&filter.label)
+        .bind(&filter.label)
+        .bind(after)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    /// Hide or show a file together with the renditions generated from it, which can
+    /// otherwise still be fetched by their own hash
+    pub async fn set_file_quarantined(
+        &self,
+        file: &Vec<u8>,
+        quarantined: bool,
+    ) -> Result<(), SqlxError> {
+        sqlx::query(
+            "update uploads set quarantined = ? where id = ? \
+            or id in (select derived from upload_derivations where source = ?)",
+        )
+        .bind(quarantined)
+        .bind(file)
+        .bind(file)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn add_file_label(
+        &self,
+        file: &Vec<u8>,
+        label: &str,
+        model: &str,
+    ) -> Result<(), SqlxError> {
+        sqlx::query("insert ignore into upload_labels(file,label,model) values(?,?,?)")
+            .bind(file)
+            .bind(label)
+            .bind(model)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn set_job_progress(&self, id: u64, progress: &str) -> Result<(), SqlxError> {
+        sqlx::query("update jobs set progress = ? where id = ?")
+            .bind(progress)
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,342 @@
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, SystemTime};
+
+use anyhow::{bail, Error};
+use log::{error, info, warn};
+use nostr::serde_json;
+use serde::{Deserialize, Serialize};
+use sqlx::Error as SqlError;
+use tokio::task::JoinHandle;
+
+use crate::background::scrub::{ScrubState, Scrubber, VerifyResult};
+use crate::background::JobHandler;
+use crate::db::{Database, Job};
+use crate::filesystem::FileStore;
+use crate::settings::Settings;
+
+pub const REPAIR_JOB: &str = "repair";
+
+/// Delay after startup before the scan begins
+const START_DELAY: Duration = Duration::from_secs(30);
+
+/// Default number of rows / files checked per second
+const DEFAULT_RATE: u32 = 50;
+
+/// Rows fetched per query in a full scan
+const PAGE_SIZE: u32 = 1000;
+
+/// Files newer than this may belong to an upload which is still being saved
+const ORPHAN_MIN_AGE: Duration = Duration::from_secs(3600);
+
+/// Directory in storage_dir which orphaned files are moved to
+const ORPHAN_DIR: &str = "orphaned";
+
+/// Counters of the most recent startup scan, exported in the metrics
+pub struct ScanStats {
+    pub running: AtomicBool,
+    pub checked_rows: AtomicU64,
+    pub missing_blobs: AtomicU64,
+    pub checked_files: AtomicU64,
+    pub orphan_files: AtomicU64,
+    pub fixes_queued: AtomicU64,
+}
+
+static STATS: ScanStats = ScanStats {
+    running: AtomicBool::new(false),
+    checked_rows: AtomicU64::new(0),
+    missing_blobs: AtomicU64::new(0),
+    checked_files: AtomicU64::new(0),
+    orphan_files: AtomicU64::new(0),
+    fixes_queued: AtomicU64::new(0),
+};
+
+pub fn stats() -> &'static ScanStats {
+    &STATS
+}
+
+/// Problem found by the startup scan
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "problem", rename_all = "snake_case")]
+pub enum RepairJob {
+    /// Database row without a stored file
+    MissingBlob {
+        #[serde(with = "hex")]
+        file: Vec<u8>,
+    },
+    /// Stored file without a database row
+    OrphanFile { path: PathBuf },
+}
+
+impl Database {
+    /// Ids of uploads after `after` in id order
+    pub async fn list_file_ids(&self, after: &[u8], limit: u32) -> Result<Vec<Vec<u8>>, SqlError> {
+        sqlx::query_scalar("select id from uploads where id > ? order by id limit ?")
+            .bind(after)
+            .bind(limit)
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    /// Random sample of upload ids
+    pub async fn sample_file_ids(&self, limit: u32) -> Result<Vec<Vec<u8>>, SqlError> {
+        sqlx::query_scalar("select id from uploads order by rand() limit ?")
+            .bind(limit)
+            .fetch_all(&self.pool)
+            .await
+    }
+}
+
+/// Checks database rows against stored files once after startup, without blocking
+/// the server from accepting requests
+pub struct ConsistencyScan {
+    db: Database,
+    fs: FileStore,
+    settings: Settings,
+}
+
+impl ConsistencyScan {
+    pub fn new(db: Database, settings: Settings) -> Self {
+        Self {
+            db,
+            fs: FileStore::new(settings.clone()),
+            settings,
+        }
+    }
+
+    pub fn start(self) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            tokio::time::sleep(START_DELAY).await;
+            STATS.running.store(true, Ordering::Relaxed);
+            if let Err(e) = self.run_once().await {
+                error!("Startup scan failed: {}", e);
+            }
+            STATS.running.store(false, Ordering::Relaxed);
+            info!(
+                "Startup scan finished: rows={}, missing_blobs={}, files={}, orphan_files={}, fixes_queued={}",
+                STATS.checked_rows.load(Ordering::Relaxed),
+                STATS.missing_blobs.load(Ordering::Relaxed),
+                STATS.checked_files.load(Ordering::Relaxed),
+                STATS.orphan_files.load(Ordering::Relaxed),
+                STATS.fixes_queued.load(Ordering::Relaxed)
+            );
+        })
+    }
+
+    pub async fn run_once(&self) -> Result<(), Error> {
+        let cfg = match &self.settings.startup_scan {
+            Some(c) => c,
+            None => return Ok(()),
+        };
+        let delay = Duration::from_secs(1) / cfg.rate.unwrap_or(DEFAULT_RATE).max(1);
+
+        // rows -> files
+        match cfg.sample {
+            Some(n) => {
+                for id in self.db.sample_file_ids(n).await? {
+                    self.check_row(id).await?;
+                    tokio::time::sleep(delay).await;
+                }
+            }
+            None => {
+                let mut after = vec![];
+                loop {
+                    let ids = self.db.list_file_ids(&after, PAGE_SIZE).await?;
+                    let last = match ids.last() {
+                        Some(l) => l.clone(),
+                        None => break,
+                    };
+                    for id in ids {
+                        self.check_row(id).await?;
+                        tokio::time::sleep(delay).await;
+                    }
+                    after = last;
+                }
+            }
+        }
+
+        // files -> rows
+        let mut roots = vec![PathBuf::from(&self.settings.storage_dir)];
+        roots.extend(
+            self.settings
+                .storage_roots
+                .iter()
+                .flatten()
+                .map(|r| r.path.clone()),
+        );
+        let mut remaining = cfg.sample.map(|n| n as u64);
+        for root in roots {
+            for dir in shard_dirs(&root, cfg.sample.is_some()).await? {
+                for dir in shard_dirs(&dir, cfg.sample.is_some()).await? {
+                    let mut entries = tokio::fs::read_dir(&dir).await?;
+                    while let Some(e) = entries.next_entry().await? {
+                        if remaining == Some(0) {
+                            return Ok(());
+                        }
+                        self.check_file(&e.path()).await?;
+                        remaining = remaining.map(|r| r - 1);
+                        tokio::time::sleep(delay).await;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn check_row(&self, id: Vec<u8>) -> Result<(), Error> {
+        STATS.checked_rows.fetch_add(1, Ordering::Relaxed);
+        if self.fs.locate(&id).is_some() {
+            return Ok(());
+        }
+        STATS.missing_blobs.fetch_add(1, Ordering::Relaxed);
+        warn!("Startup scan: {} has no stored file", hex::encode(&id));
+        self.queue_fix(&RepairJob::MissingBlob { file: id }).await
+    }
+
+    async fn check_file(&self, path: &Path) -> Result<(), Error> {
+        let id = match path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .and_then(|n| hex::decode(n).ok())
+        {
+            Some(i) if i.len() == 32 => i,
+            // temp files and other leftovers
+            _ => return Ok(()),
+        };
+        STATS.checked_files.fetch_add(1, Ordering::Relaxed);
+        if self.db.get_file(&id).await?.is_some() || !is_old(path).await {
+            return Ok(());
+        }
+        STATS.orphan_files.fetch_add(1, Ordering::Relaxed);
+        warn!("Startup scan: {} has no database row", path.display());
+        self.queue_fix(&RepairJob::OrphanFile {
+            path: path.to_path_buf(),
+        })
+        .await
+    }
+
+    async fn queue_fix(&self, job: &RepairJob) -> Result<(), Error> {
+        if !self
+            .settings
+            .startup_scan
+            .as_ref()
+            .map(|s| s.fix)
+            .unwrap_or(false)
+        {
+            return Ok(());
+        }
+        let payload = serde_json::to_string(job)?;
+        if !self.db.job_exists(REPAIR_JOB, &payload).await? {
+            self.db.enqueue_job(REPAIR_JOB, &payload).await?;
+            STATS.fixes_queued.fetch_add(1, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+}
+
+/// Two character hex sub-directories of a storage directory, starting at a random one when sampling
+async fn shard_dirs(dir: &Path, shuffle: bool) -> Result<Vec<PathBuf>, Error> {
+    let mut ret = vec![];
+    let mut entries = match tokio::fs::read_dir(dir).await {
+        Ok(e) => e,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(ret),
+        Err(e) => return Err(e.into()),
+    };
+    while let Some(e) = entries.next_entry().await? {
+        let name = e.file_name();
+        let name = name.to_string_lossy();
+        if name.len() == 2 && hex::decode(name.as_ref()).is_ok() && e.file_type().await?.is_dir() {
+            ret.push(e.path());
+        }
+    }
+    if shuffle && !ret.is_empty() {
+        let seed = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.subsec_nanos() as usize)
+            .unwrap_or(0);
+        let n = seed % ret.len();
+        ret.rotate_left(n);
+    }
+    Ok(ret)
+}
+
+async fn is_old(path: &Path) -> bool {
+    match tokio::fs::metadata(path).await.and_then(|m| m.modified()) {
+        Ok(t) => SystemTime::now()
+            .duration_since(t)
+            .map(|d| d >= ORPHAN_MIN_AGE)
+            .unwrap_or(false),
+        Err(_) => false,
+    }
+}
+
+/// Repairs problems found by the startup scan.
+///
+/// Missing blobs are recorded as corrupt and restored from the scrub peers when possible,
+/// orphaned files are moved out of the storage tree for review.
+pub struct RepairHandler {
+    db: Database,
+    settings: Settings,
+    scrubber: Scrubber,
+}
+
+impl RepairHandler {
+    pub fn new(db: Database, settings: Settings) -> Self {
+        Self {
+            scrubber: Scrubber::new(db.clone(), settings.clone(), ScrubState::default()),
+            db,
+            settings,
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl JobHandler for RepairHandler {
+    fn kind(&self) -> &'static str {
+        REPAIR_JOB
+    }
+
+    fn max_attempts(&self) -> u32 {
+        1
+    }
+
+    async fn run(&self, job: &Job) -> Result<(), Error> {
+        match job.payload()? {
+            RepairJob::MissingBlob { file } => {
+                if self.db.get_file(&file).await?.is_none() {
+                    return Ok(());
+                }
+                match self.scrubber.verify_file(&file, false).await? {
+                    VerifyResult::Ok | VerifyResult::Restored => Ok(()),
+                    VerifyResult::Missing | VerifyResult::Corrupt => {
+                        bail!("No good copy of {} found", hex::encode(&file))
+                    }
+                }
+            }
+            RepairJob::OrphanFile { path } => {
+                let name = match path.file_name() {
+                    Some(n) => n.to_owned(),
+                    None => bail!("Invalid path {}", path.display()),
+                };
+                let id = hex::decode(name.to_string_lossy().as_ref())?;
+                if !path.exists() || self.db.get_file(&id).await?.is_some() {
+                    return Ok(());
+                }
+                let dst_dir = PathBuf::from(&self.settings.storage_dir).join(ORPHAN_DIR);
+                tokio::fs::create_dir_all(&dst_dir).await?;
+                let dst = dst_dir.join(name);
+                if tokio::fs::rename(&path, &dst).await.is_err() {
+                    // different filesystem
+                    tokio::fs::copy(&path, &dst).await?;
+                    tokio::fs::remove_file(&path).await?;
+                }
+                info!(
+                    "Moved orphaned file {} to {}",
+                    path.display(),
+                    dst.display()
+                );
+                Ok(())
+            }
+        }
+    }
+}
@@ -0,0 +1,200 @@
+use std::collections::HashSet;
+use std::time::Duration;
+
+use anyhow::Error;
+use chrono::DateTime;
+use log::{error, info, warn};
+use nostr::{Alphabet, Event, EventId, Filter, Kind, SingleLetterTag, TagKind, Timestamp};
+use nostr_sdk::Client;
+use sqlx::Error as SqlError;
+use tokio::task::JoinHandle;
+
+use crate::db::Database;
+use crate::deletion::{DeleteReason, DeletionService};
+use crate::filesystem::FileStore;
+use crate::settings::Settings;
+
+/// Default time between relay checks
+const DEFAULT_INTERVAL: u64 = 60;
+
+/// How far back deletions are fetched when none were processed yet
+const INITIAL_LOOKBACK: u64 = 3600;
+
+impl Database {
+    /// Record a file removed by a nostr deletion event
+    pub async fn add_nostr_deletion(&self, event: &Event, file: &Vec<u8>) -> Result<(), SqlError> {
+        sqlx::query(
+            "insert ignore into nostr_deletions(event_id,file,pubkey,event_created) values(?,?,?,?)",
+        )
+        .bind(event.id.as_bytes().to_vec())
+        .bind(file)
+        .bind(event.pubkey.to_bytes().to_vec())
+        .bind(DateTime::from_timestamp(event.created_at.as_u64() as i64, 0))
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Creation time of the newest processed deletion event
+    pub async fn last_nostr_deletion(&self) -> Result<Option<u64>, SqlError> {
+        let ts: Option<i64> = sqlx::query_scalar(
+            "select cast(unix_timestamp(max(event_created)) as signed) from nostr_deletions",
+        )
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(ts.map(|t| t as u64))
+    }
+}
+
+/// Watches relays for NIP-09 deletions of file metadata (kind 1063) events and
+/// removes the referenced files from the author's uploads
+pub struct DeletionWatcher {
+    db: Database,
+    fs: FileStore,
+    settings: Settings,
+}
+
+impl DeletionWatcher {
+    pub fn new(db: Database, settings: Settings) -> Self {
+        Self {
+            db,
+            fs: FileStore::new(settings.clone()),
+            settings,
+        }
+    }
+
+    pub fn start(self) -> JoinHandle<()> {
+        let interval = self
+            .settings
+            .deletions
+            .as_ref()
+            .and_then(|d| d.interval)
+            .unwrap_or(DEFAULT_INTERVAL);
+        tokio::spawn(async move {
+            let mut since = match self.db.last_nostr_deletion().await {
+                Ok(Some(t)) => Timestamp::from(t),
+                _ => Timestamp::now() - INITIAL_LOOKBACK,
+            };
+            loop {
+                let started = Timestamp::now();
+                match self.run_once(since).await {
+                    Ok(n) => {
+                        if n > 0 {
+                            info!("Processed {} nostr deletions", n);
+                        }
+                        // overlap runs a little, removals are idempotent
+                        since = started - interval;
+                    }
+                    Err(e) => error!("Nostr deletion check failed: {}", e),
+                }
+                tokio::time::sleep(Duration::from_secs(interval)).await;
+            }
+        })
+    }
+
+    /// Fetch deletion events since `since` and process them, returns the number of files removed
+    pub async fn run_once(&self, since: Timestamp) -> Result<usize, Error> {
+        let cfg = match &self.settings.deletions {
+            Some(c) => c,
+            None => return Ok(0),
+        };
+        let client = Client::default();
+        for r in &cfg.relays {
+            client.add_relay(r).await?;
+        }
+        client.connect().await;
+        let res = self.process(&client, since).await;
+        client.disconnect().await?;
+        res
+    }
+
+    async fn process(&self, client: &Client, since: Timestamp) -> Result<usize, Error> {
+        let deletions = client
+            .fetch_events(
+                vec![Filter::new()
+                    .kind(Kind::EventDeletion)
+                    .custom_tag(SingleLetterTag::lowercase(Alphabet::K), ["1063"])
+                    .since(since)],
+                Duration::from_secs(10),
+            )
+            .await?;
+        let mut removed = 0;
+        for ev in deletions {
+            for file in self.referenced_files(client, &ev).await? {
+                match self.remove_upload(&ev, &file).await {
+                    Ok(true) => removed += 1,
+                    Ok(false) => {}
+                    Err(e) => warn!(
+                        "Failed to process deletion {} of {}: {}",
+                        ev.id,
+                        hex::encode(&file),
+                        e
+                    ),
+                }
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Hashes of files deleted by the event, from x tags and the x tags of deleted kind 1063 events
+    async fn referenced_files(&self, client: &Client, ev: &Event) -> Result<Vec<Vec<u8>>, Error> {
+        let x_tag = TagKind::SingleLetter(SingleLetterTag::lowercase(Alphabet::X));
+        let e_tag = TagKind::SingleLetter(SingleLetterTag::lowercase(Alphabet::E));
+        let mut hashes: HashSet<String> = ev
+            .tags
+            .iter()
+            .filter(|t| t.kind() == x_tag)
+            .filter_map(|t| t.content().map(|c| c.to_lowercase()))
+            .collect();
+        let ids: Vec<EventId> = ev
+            .tags
+            .iter()
+            .filter(|t| t.kind() == e_tag)
+            .filter_map(|t| t.content().and_then(|c| EventId::from_hex(c).ok()))
+            .collect();
+        if !ids.is_empty() {
+            // only the author of the file metadata event can delete it
+            let metadata = client
+                .fetch_events(
+                    vec![Filter::new()
+                        .ids(ids)
+                        .kind(Kind::FileMetadata)
+                        .author(ev.pubkey)],
+                    Duration::from_secs(10),
+                )
+                .await?;
+            for m in metadata {
+                hashes.extend(
+                    m.tags
+                        .iter()
+                        .filter(|t| t.kind() == x_tag)
+                        .filter_map(|t| t.content().map(|c| c.to_lowercase())),
+                );
+            }
+        }
+        Ok(hashes
+            .iter()
+            .filter_map(|h| hex::decode(h).ok())
+            .filter(|h| h.len() == 32)
+            .collect())
+    }
+
+    /// Remove the event author's ownership of a file, deleting it when no owners are left
+    async fn remove_upload(&self, ev: &Event, file: &Vec<u8>) -> Result<bool, Error> {
+        let pubkey = ev.pubkey.to_bytes().to_vec();
+        let removed = DeletionService::new(&self.db, &self.fs, &self.settings)
+            .remove_owner(file, &pubkey, DeleteReason::Nostr)
+            .await?;
+        if removed.is_none() {
+            return Ok(false);
+        }
+        self.db.add_nostr_deletion(ev, file).await?;
+        info!(
+            "Removed {} for {} (nostr deletion {})",
+            hex::encode(file),
+            ev.pubkey.to_hex(),
+            ev.id
+        );
+        Ok(true)
+    }
+}
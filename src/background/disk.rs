@@ -0,0 +1,139 @@
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::{error, info, warn};
+use serde::Serialize;
+use tokio::task::JoinHandle;
+
+use crate::settings::Settings;
+
+/// Default time between free space checks
+const DEFAULT_INTERVAL: u64 = 30;
+
+/// Free space of the storage directory, updated by the watchdog
+#[derive(Default)]
+pub struct DiskStatus {
+    read_only: AtomicBool,
+    free: AtomicU64,
+    total: AtomicU64,
+    /// Set once the first check completed
+    checked: AtomicBool,
+}
+
+pub type DiskState = Arc<DiskStatus>;
+
+#[derive(Serialize)]
+pub struct DiskReport {
+    pub read_only: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub free_bytes: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total_bytes: Option<u64>,
+}
+
+impl DiskStatus {
+    pub fn read_only(&self) -> bool {
+        self.read_only.load(Ordering::Relaxed)
+    }
+
+    /// Free bytes, None when the watchdog is not running
+    pub fn free(&self) -> Option<u64> {
+        if self.checked.load(Ordering::Relaxed) {
+            Some(self.free.load(Ordering::Relaxed))
+        } else {
+            None
+        }
+    }
+
+    pub fn report(&self) -> DiskReport {
+        DiskReport {
+            read_only: self.read_only(),
+            free_bytes: self.free(),
+            total_bytes: self.free().map(|_| self.total.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+/// Free and total bytes of the filesystem containing `path`
+#[cfg(unix)]
+pub fn disk_space(path: &Path) -> std::io::Result<(u64, u64)> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    let block = stat.f_frsize as u64;
+    Ok((stat.f_bavail as u64 * block, stat.f_blocks as u64 * block))
+}
+
+#[cfg(not(unix))]
+pub fn disk_space(_path: &Path) -> std::io::Result<(u64, u64)> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "Free space check is not supported on this platform",
+    ))
+}
+
+/// Switches the server to read-only mode when free space drops below the threshold
+pub struct DiskWatchdog {
+    settings: Settings,
+    state: DiskState,
+}
+
+impl DiskWatchdog {
+    pub fn new(settings: Settings, state: DiskState) -> Self {
+        Self { settings, state }
+    }
+
+    pub fn start(self) -> JoinHandle<()> {
+        let interval = self
+            .settings
+            .disk
+            .as_ref()
+            .and_then(|d| d.interval)
+            .unwrap_or(DEFAULT_INTERVAL);
+        tokio::spawn(async move {
+            loop {
+                self.check();
+                tokio::time::sleep(Duration::from_secs(interval)).await;
+            }
+        })
+    }
+
+    pub fn check(&self) {
+        let min_free = match &self.settings.disk {
+            Some(d) => d.min_free_bytes,
+            None => return,
+        };
+        let (free, total) = match disk_space(Path::new(&self.settings.storage_dir)) {
+            Ok(s) => s,
+            Err(e) => {
+                error!("Failed to check free disk space: {}", e);
+                return;
+            }
+        };
+        self.state.free.store(free, Ordering::Relaxed);
+        self.state.total.store(total, Ordering::Relaxed);
+        self.state.checked.store(true, Ordering::Relaxed);
+
+        let read_only = free < min_free;
+        if self.state.read_only.swap(read_only, Ordering::Relaxed) != read_only {
+            if read_only {
+                warn!(
+                    "Free disk space {} below {} bytes, switching to read-only mode",
+                    free, min_free
+                );
+            } else {
+                info!(
+                    "Free disk space recovered ({} bytes), accepting uploads",
+                    free
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,224 @@
+use anyhow::{bail, Error};
+use log::info;
+use serde::{Deserialize, Serialize};
+use sqlx::{Error as SqlError, FromRow};
+
+use crate::background::disk::DiskState;
+use crate::background::retention::upload_expiry;
+#[cfg(feature = "torrent-v2")]
+use crate::background::torrent::queue_torrent;
+use crate::background::JobHandler;
+use crate::blocklist::{self, UploadOrigin};
+use crate::db::{Database, Job, JobStatus};
+use crate::filesystem::FileStore;
+use crate::hooks;
+use crate::metadata::fit_name;
+use crate::mirror;
+use crate::routes::{check_blocked_upload, check_disk_space, check_quota};
+use crate::settings::Settings;
+
+pub const MIRROR_JOB: &str = "mirror";
+
+/// Payload for mirroring a single url of a batch
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MirrorJob {
+    pub batch: u64,
+    pub index: u32,
+    pub url: String,
+    /// Pubkey (hex) the file is stored for
+    pub pubkey: String,
+}
+
+/// A url of a mirror batch with the state of its job
+#[derive(Debug, Clone, FromRow)]
+pub struct MirrorBatchItem {
+    pub idx: u32,
+    pub url: String,
+    pub file: Option<Vec<u8>>,
+    pub status: JobStatus,
+    pub last_error: Option<String>,
+}
+
+impl Database {
+    pub async fn add_mirror_batch(&self, user_id: u64) -> Result<u64, SqlError> {
+        let res = sqlx::query("insert into mirror_batches(user_id) values(?)")
+            .bind(user_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(res.last_insert_id())
+    }
+
+    pub async fn add_mirror_batch_item(
+        &self,
+        batch: u64,
+        idx: u32,
+        url: &str,
+        job: u64,
+    ) -> Result<(), SqlError> {
+        sqlx::query("insert into mirror_batch_items(batch,idx,url,job) values(?,?,?,?)")
+            .bind(batch)
+            .bind(idx)
+            .bind(url)
+            .bind(job)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Owner (user id) of a mirror batch
+    pub async fn get_mirror_batch_owner(&self, batch: u64) -> Result<Option<u64>, SqlError> {
+        sqlx::query_scalar("select user_id from mirror_batches where id = ?")
+            .bind(batch)
+            .fetch_optional(&self.pool)
+            .await
+    }
+
+    pub async fn list_mirror_batch_items(
+        &self,
+        batch: u64,
+    ) -> Result<Vec<MirrorBatchItem>, SqlError> {
+        sqlx::query_as(
+            "select i.idx, i.url, i.file, j.status, j.last_error \
+            from mirror_batch_items i join jobs j on j.id = i.job \
+            where i.batch = ? order by i.idx",
+        )
+        .bind(batch)
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    pub async fn set_mirror_batch_item_file(
+        &self,
+        batch: u64,
+        idx: u32,
+        file: &Vec<u8>,
+    ) -> Result<(), SqlError> {
+        sqlx::query("update mirror_batch_items set file = ? where batch = ? and idx = ?")
+            .bind(file)
+            .bind(batch)
+            .bind(idx)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+}
+
+/// Downloads the urls of mirror batches, applying the same rules as PUT /mirror
+pub struct MirrorHandler {
+    db: Database,
+    fs: FileStore,
+    settings: Settings,
+    disk: DiskState,
+}
+
+impl MirrorHandler {
+    pub fn new(db: Database, settings: Settings, disk: DiskState) -> Self {
+        Self {
+            db,
+            fs: FileStore::new(settings.clone()),
+            settings,
+            disk,
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl JobHandler for MirrorHandler {
+    fn kind(&self) -> &'static str {
+        MIRROR_JOB
+    }
+
+    fn concurrency(&self) -> usize {
+        4
+    }
+
+    fn max_attempts(&self) -> u32 {
+        2
+    }
+
+    async fn run(&self, job: &Job) -> Result<(), Error> {
+        let req: MirrorJob = job.payload()?;
+        let pubkey = hex::decode(&req.pubkey)?;
+        if let Some(wl) = &self.settings.whitelist {
+            if !wl.contains(&req.pubkey) {
+                bail!("Not on whitelist");
+            }
+        }
+        let origin = UploadOrigin {
+            pubkey: &pubkey,
+            client: None,
+            url: Some(&req.url),
+        };
+        if let Some(reason) = blocklist::check_origin(&self.db, origin).await? {
+            bail!("Denied: {}", reason);
+        }
+        check_disk_space(&self.disk, &self.settings, None)?;
+
+        let rsp = mirror::fetch(&self.settings, &req.url).await?;
+        check_disk_space(&self.disk, &self.settings, rsp.content_length())?;
+        check_quota(&self.db, &self.settings, &pubkey, rsp.content_length()).await?;
+        let mime_type = rsp
+            .headers()
+            .get("content-type")
+            .and_then(|h| h.to_str().ok())
+            .unwrap_or("application/octet-stream")
+            .to_string();
+        let name = req
+            .url
+            .split(['?', '#'])
+            .next()
+            .and_then(|u| u.rsplit('/').next())
+            .filter(|n| !n.is_empty())
+            .map(|n| fit_name(&self.settings, n));
+        let stream = mirror::response_reader(&self.settings, rsp);
+        let mut blob = self.fs.put(stream, &mime_type, false).await?;
+        check_blocked_upload(
+            &pubkey,
+            name.as_deref(),
+            &mime_type,
+            &blob,
+            &self.db,
+            &self.settings,
+        )
+        .await?;
+
+        if !self.settings.hide_file_names.unwrap_or(false) {
+            blob.upload.name = name.unwrap_or_default();
+        }
+        blob.upload.expires = upload_expiry(&self.db, &self.settings, &pubkey).await;
+        let user_id = self.db.upsert_user(&pubkey).await?;
+        match self.db.add_file(&blob.upload, user_id).await {
+            Ok(()) => {
+                #[cfg(feature = "torrent-v2")]
+                if let Err(e) = queue_torrent(&self.db, &self.settings, &blob.upload).await {
+                    log::warn!("Failed to queue torrent: {}", e);
+                }
+                hooks::post_store(&self.settings, &blob.upload, &pubkey);
+            }
+            // already uploaded by this user
+            Err(e)
+                if e.as_database_error()
+                    .and_then(|d| d.code())
+                    .is_some_and(|c| c == "23000") => {}
+            Err(e) => {
+                if let Ok(None) = self.db.get_file(&blob.upload.id).await {
+                    let _ = tokio::fs::remove_file(&blob.path).await;
+                }
+                return Err(e.into());
+            }
+        }
+        self.db
+            .set_mirror_batch_item_file(req.batch, req.index, &blob.upload.id)
+            .await?;
+        if let Err(e) = self.db.add_upload_source(&blob.upload.id, &req.url).await {
+            log::warn!("Failed to record source of {}: {}", req.url, e);
+        }
+        info!(
+            "Mirrored {} => {} (batch {})",
+            req.url,
+            hex::encode(&blob.upload.id),
+            req.batch
+        );
+        Ok(())
+    }
+}
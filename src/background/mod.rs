@@ -0,0 +1,219 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::Error;
+use chrono::{TimeDelta, Utc};
+use log::{error, info, warn};
+use nostr::serde_json;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tokio::sync::Semaphore;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+
+use crate::db::{Database, Job};
+
+pub mod announce;
+pub mod backup;
+pub mod bot;
+pub mod bulk;
+pub mod consistency;
+pub mod deletions;
+pub mod disk;
+pub mod mirror;
+pub mod profiles;
+#[cfg(feature = "media-compression")]
+pub mod rendition;
+pub mod replication;
+pub mod report;
+#[cfg(feature = "media-compression")]
+pub mod reprocess;
+pub mod retention;
+pub mod scrub;
+pub mod sources;
+#[cfg(feature = "torrent-v2")]
+pub mod torrent;
+pub mod users;
+
+/// How often the queue is checked for new jobs
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Upper bound in seconds for the delay between retries
+const MAX_BACKOFF: i64 = 3600;
+
+/// Time a cancelled job has to stop by itself before it is dropped
+const CANCEL_GRACE: Duration = Duration::from_secs(30);
+
+/// Handler for a single kind of background job
+#[rocket::async_trait]
+pub trait JobHandler: Send + Sync {
+    /// Unique name of the job kind, stored in the queue table
+    fn kind(&self) -> &'static str;
+
+    /// Maximum number of jobs of this kind running at the same time
+    fn concurrency(&self) -> usize {
+        1
+    }
+
+    /// Number of attempts before a job is marked as failed
+    fn max_attempts(&self) -> u32 {
+        5
+    }
+
+    async fn run(&self, job: &Job) -> Result<(), Error>;
+}
+
+impl Job {
+    /// Decode the json payload of this job
+    pub fn payload<T: DeserializeOwned>(&self) -> Result<T, Error> {
+        Ok(serde_json::from_str(&self.payload)?)
+    }
+}
+
+/// Add a job to the queue
+pub async fn enqueue<T: Serialize>(db: &Database, kind: &str, payload: &T) -> Result<u64, Error> {
+    let json = serde_json::to_string(payload)?;
+    Ok(db.enqueue_job(kind, &json).await?)
+}
+
+/// Cancellation tokens of the running jobs, shared with the admin routes
+#[derive(Clone, Default)]
+pub struct JobCancels {
+    inner: Arc<Mutex<HashMap<u64, CancellationToken>>>,
+}
+
+impl JobCancels {
+    /// Cancel a running job, returns false if it is not running here
+    pub fn cancel(&self, id: u64) -> bool {
+        match self.inner.lock().unwrap().get(&id) {
+            Some(t) => {
+                t.cancel();
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn insert(&self, id: u64, token: CancellationToken) {
+        self.inner.lock().unwrap().insert(id, token);
+    }
+
+    fn remove(&self, id: u64) {
+        self.inner.lock().unwrap().remove(&id);
+    }
+}
+
+struct RegisteredHandler {
+    handler: Arc<dyn JobHandler>,
+    slots: Arc<Semaphore>,
+}
+
+/// Polls the job queue and runs jobs with their registered handlers
+pub struct JobRunner {
+    db: Database,
+    handlers: HashMap<&'static str, RegisteredHandler>,
+    cancels: JobCancels,
+}
+
+impl JobRunner {
+    pub fn new(db: Database, cancels: JobCancels) -> Self {
+        Self {
+            db,
+            handlers: HashMap::new(),
+            cancels,
+        }
+    }
+
+    pub fn register<T>(&mut self, handler: T)
+    where
+        T: JobHandler + 'static,
+    {
+        let slots = Arc::new(Semaphore::new(handler.concurrency().max(1)));
+        self.handlers.insert(
+            handler.kind(),
+            RegisteredHandler {
+                handler: Arc::new(handler),
+                slots,
+            },
+        );
+    }
+
+    /// Start polling the queue in the background
+    pub fn start(self) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            match self.db.reset_running_jobs().await {
+                Ok(n) if n > 0 => info!("Re-queued {} interrupted jobs", n),
+                Err(e) => error!("Failed to reset running jobs: {}", e),
+                _ => {}
+            }
+            loop {
+                if let Err(e) = self.poll().await {
+                    error!("Job queue poll failed: {}", e);
+                }
+                tokio::time::sleep(POLL_INTERVAL).await;
+            }
+        })
+    }
+
+    async fn poll(&self) -> Result<(), Error> {
+        for (kind, reg) in &self.handlers {
+            let free = reg.slots.available_permits();
+            if free == 0 {
+                continue;
+            }
+            for job in self.db.list_runnable_jobs(kind, free as u32).await? {
+                let permit = match reg.slots.clone().try_acquire_owned() {
+                    Ok(p) => p,
+                    Err(_) => break,
+                };
+                if !self.db.claim_job(job.id).await? {
+                    continue;
+                }
+                let handler = reg.handler.clone();
+                let db = self.db.clone();
+                let cancels = self.cancels.clone();
+                cancels.insert(job.id, job.cancel.clone());
+                tokio::spawn(async move {
+                    let attempt = job.attempts + 1;
+                    info!("Running job {} ({}) attempt {}", job.id, job.kind, attempt);
+                    let run = handler.run(&job);
+                    tokio::pin!(run);
+                    let outcome = tokio::select! {
+                        r = &mut run => Some(r),
+                        _ = job.cancel.cancelled() => {
+                            tokio::time::timeout(CANCEL_GRACE, &mut run).await.ok()
+                        }
+                    };
+                    cancels.remove(job.id);
+                    let res = match outcome {
+                        Some(Ok(())) if !job.cancel.is_cancelled() => db.complete_job(job.id).await,
+                        Some(Err(e)) if !job.cancel.is_cancelled() => {
+                            warn!("Job {} ({}) failed: {}", job.id, job.kind, e);
+                            let retry_at = if attempt < handler.max_attempts() {
+                                Some(Utc::now() + backoff(attempt))
+                            } else {
+                                None
+                            };
+                            db.fail_job(job.id, &e.to_string(), retry_at).await
+                        }
+                        _ => {
+                            info!("Job {} ({}) cancelled", job.id, job.kind);
+                            db.set_job_cancelled(job.id).await
+                        }
+                    };
+                    if let Err(e) = res {
+                        error!("Failed to update job {}: {}", job.id, e);
+                    }
+                    drop(permit);
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Exponential backoff starting at 10s
+fn backoff(attempt: u32) -> TimeDelta {
+    TimeDelta::seconds((10i64 << attempt.min(16)).min(MAX_BACKOFF))
+}
@@ -0,0 +1,260 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use anyhow::{bail, Error};
+use chrono::{TimeDelta, Utc};
+use log::{error, info, warn};
+use nostr::{serde_json, Event, Filter, JsonUtil, Kind, Metadata, PublicKey};
+use nostr_sdk::Client;
+use reqwest::redirect::Policy;
+use reqwest::ClientBuilder;
+use sqlx::Error as SqlError;
+use tokio::task::JoinHandle;
+use url::Url;
+
+use crate::db::{Database, User};
+use crate::mirror::is_public_ip;
+use crate::settings::Settings;
+
+/// Default time between profile refreshes
+const DEFAULT_INTERVAL: u64 = 600;
+
+/// Default age after which a profile is fetched again, 1 day
+const DEFAULT_MAX_AGE: u64 = 86400;
+
+/// Number of users refreshed per run
+const BATCH_SIZE: u32 = 100;
+
+/// Maximum size of NIP-05 and LNURL documents
+const MAX_DOCUMENT_SIZE: usize = 64 * 1024;
+
+/// Profile metadata and verification state of an uploader
+#[derive(Debug, Clone, Default)]
+pub struct UserProfile {
+    pub display_name: Option<String>,
+    pub nip05: Option<String>,
+    pub nip05_verified: bool,
+    pub lud16: Option<String>,
+    pub lud16_verified: bool,
+}
+
+impl Database {
+    /// Users whose profile was never fetched or is older than `max_age` seconds
+    pub async fn list_users_for_profile(
+        &self,
+        max_age: u64,
+        limit: u32,
+    ) -> Result<Vec<User>, SqlError> {
+        sqlx::query_as(
+            "select * from users \
+            where profile_updated is null or profile_updated < ? \
+            order by profile_updated asc \
+            limit ?",
+        )
+        .bind(Utc::now() - TimeDelta::seconds(max_age as i64))
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    pub async fn set_user_profile(&self, id: u64, p: &UserProfile) -> Result<(), SqlError> {
+        sqlx::query(
+            "update users set display_name = ?, nip05 = ?, nip05_verified = ?, \
+            lud16 = ?, lud16_verified = ?, profile_updated = current_timestamp where id = ?",
+        )
+        .bind(&p.display_name)
+        .bind(&p.nip05)
+        .bind(p.nip05_verified)
+        .bind(&p.lud16)
+        .bind(p.lud16_verified)
+        .bind(id)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+}
+
+/// Periodically fetches uploader profiles (kind 0) from relays and checks their NIP-05 / lud16
+pub struct ProfileFetcher {
+    db: Database,
+    settings: Settings,
+}
+
+impl ProfileFetcher {
+    pub fn new(db: Database, settings: Settings) -> Self {
+        Self { db, settings }
+    }
+
+    pub fn start(self) -> JoinHandle<()> {
+        let interval = self
+            .settings
+            .profiles
+            .as_ref()
+            .and_then(|p| p.interval)
+            .unwrap_or(DEFAULT_INTERVAL);
+        tokio::spawn(async move {
+            loop {
+                match self.run_once().await {
+                    Ok(n) if n > 0 => info!("Refreshed {} user profiles", n),
+                    Ok(_) => {}
+                    Err(e) => error!("Profile refresh failed: {}", e),
+                }
+                tokio::time::sleep(Duration::from_secs(interval)).await;
+            }
+        })
+    }
+
+    pub async fn run_once(&self) -> Result<usize, Error> {
+        let cfg = match &self.settings.profiles {
+            Some(c) => c,
+            None => return Ok(0),
+        };
+        let users = self
+            .db
+            .list_users_for_profile(cfg.max_age.unwrap_or(DEFAULT_MAX_AGE), BATCH_SIZE)
+            .await?;
+        if users.is_empty() {
+            return Ok(0);
+        }
+        let events = self.fetch_metadata(&cfg.relays, &users).await?;
+        for user in &users {
+            let profile = match events.get(&user.pubkey) {
+                Some(ev) => self.build_profile(ev).await,
+                None => UserProfile::default(),
+            };
+            self.db.set_user_profile(user.id, &profile).await?;
+        }
+        Ok(users.len())
+    }
+
+    /// Latest kind 0 event per pubkey
+    async fn fetch_metadata(
+        &self,
+        relays: &[String],
+        users: &[User],
+    ) -> Result<HashMap<Vec<u8>, Event>, Error> {
+        let authors: Vec<PublicKey> = users
+            .iter()
+            .filter_map(|u| PublicKey::from_slice(&u.pubkey).ok())
+            .collect();
+        let client = Client::default();
+        for r in relays {
+            client.add_relay(r).await?;
+        }
+        client.connect().await;
+        let events = client
+            .fetch_events(
+                vec![Filter::new().kind(Kind::Metadata).authors(authors)],
+                Duration::from_secs(10),
+            )
+            .await;
+        client.disconnect().await?;
+
+        let mut latest: HashMap<Vec<u8>, Event> = HashMap::new();
+        for ev in events? {
+            let key = ev.pubkey.to_bytes().to_vec();
+            match latest.get(&key) {
+                Some(e) if e.created_at >= ev.created_at => {}
+                _ => {
+                    latest.insert(key, ev);
+                }
+            }
+        }
+        Ok(latest)
+    }
+
+    async fn build_profile(&self, ev: &Event) -> UserProfile {
+        let meta = match Metadata::from_json(&ev.content) {
+            Ok(m) => m,
+            Err(e) => {
+                warn!("Invalid profile for {}: {}", ev.pubkey, e);
+                return UserProfile::default();
+            }
+        };
+        let nip05_verified = match &meta.nip05 {
+            Some(n) => self.verify_nip05(n, &ev.pubkey).await,
+            None => false,
+        };
+        let lud16_verified = match &meta.lud16 {
+            Some(l) => self.verify_lud16(l).await,
+            None => false,
+        };
+        UserProfile {
+            display_name: meta.display_name.or(meta.name),
+            nip05: meta.nip05,
+            nip05_verified,
+            lud16: meta.lud16,
+            lud16_verified,
+        }
+    }
+
+    /// Check the NIP-05 identifier resolves to this pubkey
+    async fn verify_nip05(&self, nip05: &str, pubkey: &PublicKey) -> bool {
+        let (name, domain) = match nip05.split_once('@') {
+            Some((n, d)) => (n, d),
+            None => ("_", nip05),
+        };
+        let url = format!("https://{}/.well-known/nostr.json?name={}", domain, name);
+        let doc = match get_json(&url).await {
+            Ok(d) => d,
+            Err(_) => return false,
+        };
+        doc.get("names")
+            .and_then(|n| n.get(name))
+            .and_then(|p| p.as_str())
+            .is_some_and(|p| p.eq_ignore_ascii_case(&pubkey.to_hex()))
+    }
+
+    /// Check the lightning address resolves to an LNURL pay endpoint
+    async fn verify_lud16(&self, lud16: &str) -> bool {
+        let (name, domain) = match lud16.split_once('@') {
+            Some(p) => p,
+            None => return false,
+        };
+        let url = format!("https://{}/.well-known/lnurlp/{}", domain, name);
+        let doc = match get_json(&url).await {
+            Ok(d) => d,
+            Err(_) => return false,
+        };
+        doc.get("tag").and_then(|t| t.as_str()) == Some("payRequest")
+            && doc.get("callback").is_some()
+    }
+}
+
+/// Fetch a small json document from a public host, without following redirects
+async fn get_json(url: &str) -> Result<serde_json::Value, Error> {
+    let url = Url::parse(url)?;
+    let host = match url.host_str() {
+        Some(h) => h.to_string(),
+        None => bail!("Missing host"),
+    };
+    let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host.as_str(), 443))
+        .await?
+        .collect();
+    if addrs.is_empty() || addrs.iter().any(|a| !is_public_ip(&a.ip())) {
+        bail!("Host resolves to a private address");
+    }
+    let rsp = ClientBuilder::new()
+        .redirect(Policy::none())
+        .timeout(Duration::from_secs(10))
+        .resolve_to_addrs(&host, &addrs)
+        .build()?
+        .get(url)
+        .send()
+        .await?;
+    if !rsp.status().is_success() {
+        bail!("Server returned {}", rsp.status());
+    }
+    if rsp
+        .content_length()
+        .is_some_and(|l| l > MAX_DOCUMENT_SIZE as u64)
+    {
+        bail!("Document too large");
+    }
+    let body = rsp.bytes().await?;
+    if body.len() > MAX_DOCUMENT_SIZE {
+        bail!("Document too large");
+    }
+    Ok(serde_json::from_slice(&body)?)
+}
@@ -0,0 +1,200 @@
+use anyhow::{bail, Error};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use sqlx::Error as SqlError;
+
+use crate::background::JobHandler;
+use crate::db::{Database, FileUpload, Job};
+use crate::filesystem::FileStore;
+use crate::processing::watermark::watermark_file;
+use crate::processing::{compress_file, FileProcessorResult, MediaLimits};
+use crate::settings::{QualitySearchConfig, Settings, WatermarkConfig};
+
+pub const RENDITION_JOB: &str = "rendition";
+
+/// Derivation params of the WebP renditions served by content negotiation.
+///
+/// A derivation pointing back to the source means the original is already the smallest.
+pub const WEBP_RENDITION_PARAMS: &str = "negotiate:webp";
+
+/// Derivation params of the watermarked WebP renditions, see [crate::settings::WatermarkConfig]
+pub const WATERMARK_RENDITION_PARAMS: &str = "negotiate:webp+watermark";
+
+/// Payload for creating the WebP rendition of a stored image
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RenditionJob {
+    #[serde(with = "hex")]
+    pub file: Vec<u8>,
+    /// Draw the configured watermark on the rendition
+    #[serde(default)]
+    pub watermark: bool,
+}
+
+impl RenditionJob {
+    pub fn params(&self) -> &'static str {
+        if self.watermark {
+            WATERMARK_RENDITION_PARAMS
+        } else {
+            WEBP_RENDITION_PARAMS
+        }
+    }
+}
+
+/// Whether the renditions of a file are watermarked, going by the tiers of its owners.
+///
+/// Files with any owner in a tier without watermark are never watermarked.
+pub async fn wants_watermark(db: &Database, settings: &Settings, file: &Vec<u8>) -> bool {
+    let cfg = match &settings.watermark {
+        Some(c) => c,
+        None => return false,
+    };
+    let owners = match db.get_file_owners(file).await {
+        Ok(o) => o,
+        Err(e) => {
+            warn!("Failed to load owners of {}: {}", hex::encode(file), e);
+            return false;
+        }
+    };
+    !owners.is_empty()
+        && owners
+            .iter()
+            .all(|u| match u.tier.as_deref().and_then(|t| cfg.tiers.get(t)) {
+                Some(w) => *w,
+                None => cfg.default.unwrap_or(true),
+            })
+}
+
+impl Database {
+    /// Store a file nobody uploaded, eg. a rendition of another upload
+    pub async fn add_unowned_file(&self, file: &FileUpload) -> Result<(), SqlError> {
+        sqlx::query(
+            "insert ignore into \
+            uploads(id,name,size,mime_type,width,height,created,storage) values(?,?,?,?,?,?,?,?)",
+        )
+        .bind(&file.id)
+        .bind(&file.name)
+        .bind(file.size)
+        .bind(&file.mime_type)
+        .bind(file.width)
+        .bind(file.height)
+        .bind(file.created)
+        .bind(&file.storage)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+}
+
+/// Creates WebP renditions of images for clients which accept them
+pub struct RenditionHandler {
+    db: Database,
+    fs: FileStore,
+    limits: MediaLimits,
+    watermark: Option<WatermarkConfig>,
+    quality_search: Option<QualitySearchConfig>,
+}
+
+impl RenditionHandler {
+    pub fn new(db: Database, settings: Settings) -> Self {
+        Self {
+            db,
+            limits: MediaLimits::new(&settings),
+            watermark: settings.watermark.clone(),
+            quality_search: settings.quality_search.clone(),
+            fs: FileStore::new(settings),
+        }
+    }
+
+    /// Move a generated rendition into storage and record it as derived from the source
+    async fn store(
+        &self,
+        req: &RenditionJob,
+        params: &str,
+        path: &std::path::Path,
+        mime_type: &str,
+    ) -> Result<(), Error> {
+        let f = tokio::fs::File::open(path).await?;
+        let res = self.fs.put(f, mime_type, false).await;
+        tokio::fs::remove_file(path).await?;
+        let blob = res?;
+        self.db.add_unowned_file(&blob.upload).await?;
+        self.db
+            .add_derivation(&req.file, params, &blob.upload.id)
+            .await?;
+        info!(
+            "Created {} rendition {} => {}",
+            params,
+            hex::encode(&req.file),
+            hex::encode(&blob.upload.id)
+        );
+        Ok(())
+    }
+}
+
+#[rocket::async_trait]
+impl JobHandler for RenditionHandler {
+    fn kind(&self) -> &'static str {
+        RENDITION_JOB
+    }
+
+    fn concurrency(&self) -> usize {
+        2
+    }
+
+    async fn run(&self, job: &Job) -> Result<(), Error> {
+        let req: RenditionJob = job.payload()?;
+        let params = req.params();
+        if self.db.get_derived_file(&req.file, params).await?.is_some() {
+            return Ok(());
+        }
+        let info = match self.db.get_file(&req.file).await? {
+            Some(i) => i,
+            None => bail!("File not found"),
+        };
+        // quarantined since the job was queued, a rendition would be served by its own hash
+        if info.quarantined {
+            return Ok(());
+        }
+        if !self.fs.get(&req.file).exists() {
+            bail!("File missing from storage");
+        }
+        let plain = self.fs.plain_file(&req.file)?;
+        let plain_path = plain.path().to_path_buf();
+
+        if req.watermark {
+            let cfg = match &self.watermark {
+                Some(c) => c,
+                None => bail!("Watermark is not configured"),
+            };
+            // always stored, the original has no watermark even when smaller
+            let new_file = watermark_file(plain_path, cfg, &self.limits)?;
+            return self
+                .store(&req, params, &new_file.result, &new_file.mime_type)
+                .await;
+        }
+        let new_file = match compress_file(
+            plain_path,
+            &info.mime_type,
+            &self.limits,
+            self.quality_search.as_ref(),
+        )? {
+            FileProcessorResult::NewFile(f) => f,
+            FileProcessorResult::Skip => {
+                self.db
+                    .add_derivation(&req.file, WEBP_RENDITION_PARAMS, &req.file)
+                    .await?;
+                return Ok(());
+            }
+        };
+        if tokio::fs::metadata(&new_file.result).await?.len() >= info.size {
+            tokio::fs::remove_file(&new_file.result).await?;
+            self.db
+                .add_derivation(&req.file, WEBP_RENDITION_PARAMS, &req.file)
+                .await?;
+            info!("Original {} is smaller than WebP", hex::encode(&req.file));
+            return Ok(());
+        }
+        self.store(&req, params, &new_file.result, &new_file.mime_type)
+            .await
+    }
+}
@@ -0,0 +1,120 @@
+use std::time::Duration;
+
+use anyhow::{bail, Error};
+use base64::prelude::*;
+use log::info;
+use nostr::{
+    Alphabet, EventBuilder, JsonUtil, Keys, Kind, SingleLetterTag, Tag, TagKind, Timestamp,
+};
+use reqwest::{ClientBuilder, StatusCode};
+use serde::{Deserialize, Serialize};
+
+use crate::background::{enqueue, JobHandler};
+use crate::db::{Database, Job};
+use crate::settings::Settings;
+
+pub const PEER_DELETE_JOB: &str = "peer_delete";
+
+/// Payload for deleting a blob from a single replication peer
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PeerDeleteJob {
+    #[serde(with = "hex")]
+    pub file: Vec<u8>,
+    pub peer: String,
+}
+
+/// Check if deletes signed by this pubkey remove the file completely
+pub fn is_trusted_peer(settings: &Settings, pubkey: &str) -> bool {
+    settings
+        .replication
+        .as_ref()
+        .and_then(|r| r.trusted_peers.as_ref())
+        .map(|t| t.iter().any(|p| p.eq_ignore_ascii_case(pubkey)))
+        .unwrap_or(false)
+}
+
+/// Queue deletion of a removed file on every replication peer
+pub async fn propagate_delete(db: &Database, settings: &Settings, id: &[u8]) -> Result<(), Error> {
+    // without a server key there is no handler to sign the deletes
+    let cfg = match &settings.replication {
+        Some(c) if c.propagate_deletes && c.server_key.is_some() => c,
+        _ => return Ok(()),
+    };
+    for peer in &cfg.peers {
+        enqueue(
+            db,
+            PEER_DELETE_JOB,
+            &PeerDeleteJob {
+                file: id.to_vec(),
+                peer: peer.clone(),
+            },
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+/// Sends blossom DELETE requests to peers, signed with the server key
+pub struct PeerDeleteHandler {
+    keys: Keys,
+}
+
+impl PeerDeleteHandler {
+    pub fn new(settings: &Settings) -> Result<Option<Self>, Error> {
+        match settings
+            .replication
+            .as_ref()
+            .and_then(|r| r.server_key.as_ref())
+        {
+            Some(k) => Ok(Some(Self {
+                keys: Keys::parse(k)?,
+            })),
+            None => Ok(None),
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl JobHandler for PeerDeleteHandler {
+    fn kind(&self) -> &'static str {
+        PEER_DELETE_JOB
+    }
+
+    async fn run(&self, job: &Job) -> Result<(), Error> {
+        let req: PeerDeleteJob = job.payload()?;
+        let id = hex::encode(&req.file);
+        let auth = EventBuilder::new(Kind::Custom(24242), "Delete blob")
+            .tags([
+                Tag::custom(
+                    TagKind::SingleLetter(SingleLetterTag::lowercase(Alphabet::T)),
+                    ["delete"],
+                ),
+                Tag::custom(
+                    TagKind::SingleLetter(SingleLetterTag::lowercase(Alphabet::X)),
+                    [id.clone()],
+                ),
+                Tag::expiration(Timestamp::now() + 300),
+            ])
+            .sign_with_keys(&self.keys)?;
+
+        let client = ClientBuilder::new()
+            .timeout(Duration::from_secs(30))
+            .build()?;
+        let rsp = client
+            .delete(format!("{}/{}", req.peer.trim_end_matches('/'), id))
+            .header(
+                "authorization",
+                format!("Nostr {}", BASE64_STANDARD.encode(auth.as_json())),
+            )
+            .send()
+            .await?;
+        match rsp.status() {
+            // already gone, nothing left to do
+            s if s.is_success() || s == StatusCode::NOT_FOUND => {
+                info!("Deleted {} from peer {}", id, req.peer);
+                Ok(())
+            }
+            s => bail!("Peer {} returned {}", req.peer, s),
+        }
+    }
+}
@@ -0,0 +1,332 @@
+use std::fmt::{Display, Formatter};
+use std::time::Duration;
+
+use anyhow::Error;
+use chrono::{DateTime, TimeDelta, Utc};
+use lettre::message::header::ContentType;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use log::{error, info, warn};
+use nostr::serde_json;
+use nostr::{Keys, PublicKey};
+use nostr_sdk::Client;
+use serde::{Deserialize, Serialize};
+use sqlx::Row;
+use tokio::task::JoinHandle;
+
+use crate::db::Database;
+use crate::settings::{ReportsConfig, Settings, SmtpConfig};
+
+/// Days covered by a report
+const PERIOD_DAYS: i64 = 7;
+
+/// Time between checks whether a report is due
+const CHECK_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Default SMTP port, implicit TLS
+const DEFAULT_SMTP_PORT: u16 = 465;
+
+/// SMTP submission port, upgraded with STARTTLS
+const STARTTLS_PORT: u16 = 587;
+
+/// Usage of the server over a report period
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OperatorReport {
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    /// Users created in the period
+    pub new_users: u64,
+    pub total_users: u64,
+    /// Uploads in the period, including uploads of files which were already stored
+    pub uploads: u64,
+    /// Files first stored in the period which are still stored
+    pub new_files: u64,
+    pub new_bytes: u64,
+    pub total_files: u64,
+    pub total_bytes: u64,
+    /// Downloads in the period, only counted with the local analytics
+    pub downloads: u64,
+    pub egress_bytes: u64,
+    /// Files which failed an integrity check in the period
+    pub corrupt_files: u64,
+}
+
+impl OperatorReport {
+    /// Percent the stored bytes grew by over the period
+    pub fn storage_growth(&self) -> Option<f64> {
+        let before = self.total_bytes.saturating_sub(self.new_bytes);
+        if before == 0 {
+            None
+        } else {
+            Some(self.new_bytes as f64 * 100.0 / before as f64)
+        }
+    }
+
+    fn subject(&self) -> String {
+        format!(
+            "route96 report {} - {}",
+            self.start.format("%Y-%m-%d"),
+            self.end.format("%Y-%m-%d")
+        )
+    }
+}
+
+fn gib(bytes: u64) -> f64 {
+    bytes as f64 / (1024.0 * 1024.0 * 1024.0)
+}
+
+impl Display for OperatorReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{}", self.subject())?;
+        writeln!(f)?;
+        writeln!(
+            f,
+            "Users: {} new, {} total",
+            self.new_users, self.total_users
+        )?;
+        writeln!(
+            f,
+            "Uploads: {} ({} new files, {:.2} GiB)",
+            self.uploads,
+            self.new_files,
+            gib(self.new_bytes)
+        )?;
+        write!(
+            f,
+            "Storage: {} files, {:.2} GiB",
+            self.total_files,
+            gib(self.total_bytes)
+        )?;
+        match self.storage_growth() {
+            Some(g) => writeln!(f, " (+{:.1}%)", g)?,
+            None => writeln!(f)?,
+        }
+        writeln!(
+            f,
+            "Egress: {} downloads, {:.2} GiB",
+            self.downloads,
+            gib(self.egress_bytes)
+        )?;
+        write!(f, "Corrupt files detected: {}", self.corrupt_files)
+    }
+}
+
+impl Database {
+    /// Compute the report of a period from the current tables
+    pub async fn build_operator_report(
+        &self,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Result<OperatorReport, sqlx::Error> {
+        let users = sqlx::query(
+            "select cast(count(*) as unsigned), \
+            cast(coalesce(sum(created >= ? and created < ?), 0) as unsigned) from users",
+        )
+        .bind(start)
+        .bind(end)
+        .fetch_one(&self.pool)
+        .await?;
+        let uploads: u64 = sqlx::query_scalar(
+            "select cast(count(*) as unsigned) from user_uploads where created >= ? and created < ?",
+        )
+        .bind(start)
+        .bind(end)
+        .fetch_one(&self.pool)
+        .await?;
+        let files = sqlx::query(
+            "select cast(count(*) as unsigned), cast(coalesce(sum(size), 0) as unsigned), \
+            cast(coalesce(sum(created >= ? and created < ?), 0) as unsigned), \
+            cast(coalesce(sum(if(created >= ? and created < ?, size, 0)), 0) as unsigned) \
+            from uploads",
+        )
+        .bind(start)
+        .bind(end)
+        .bind(start)
+        .bind(end)
+        .fetch_one(&self.pool)
+        .await?;
+        let egress = sqlx::query(
+            "select cast(coalesce(sum(requests), 0) as unsigned), \
+            cast(coalesce(sum(bytes), 0) as unsigned) from analytics_hourly \
+            where event = 'download' and hour >= ? and hour < ?",
+        )
+        .bind(start)
+        .bind(end)
+        .fetch_one(&self.pool)
+        .await?;
+        let corrupt_files: u64 = sqlx::query_scalar(
+            "select cast(count(*) as unsigned) from corrupt_files where detected >= ? and detected < ?",
+        )
+        .bind(start)
+        .bind(end)
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(OperatorReport {
+            start,
+            end,
+            new_users: users.try_get(1)?,
+            total_users: users.try_get(0)?,
+            uploads,
+            new_files: files.try_get(2)?,
+            new_bytes: files.try_get(3)?,
+            total_files: files.try_get(0)?,
+            total_bytes: files.try_get(1)?,
+            downloads: egress.try_get(0)?,
+            egress_bytes: egress.try_get(1)?,
+            corrupt_files,
+        })
+    }
+
+    pub async fn add_operator_report(&self, report: &OperatorReport) -> Result<(), Error> {
+        sqlx::query("insert into operator_reports(period_start,period_end,report) values(?,?,?)")
+            .bind(report.start)
+            .bind(report.end)
+            .bind(serde_json::to_string(report)?)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn get_latest_operator_report(&self) -> Result<Option<OperatorReport>, Error> {
+        let report: Option<String> = sqlx::query_scalar(
+            "select report from operator_reports order by period_end desc limit 1",
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(match report {
+            Some(r) => Some(serde_json::from_str(&r)?),
+            None => None,
+        })
+    }
+}
+
+/// Report of the last week, computed now
+pub async fn current_report(db: &Database) -> Result<OperatorReport, Error> {
+    let end = Utc::now();
+    Ok(db
+        .build_operator_report(end - TimeDelta::days(PERIOD_DAYS), end)
+        .await?)
+}
+
+/// Sends the weekly usage report to the operators by webhook, email and nostr direct message.
+///
+/// Reports are stored before they are sent, so a restart does not send a period twice
+pub struct ReportTask {
+    db: Database,
+    settings: Settings,
+}
+
+impl ReportTask {
+    pub fn new(db: Database, settings: Settings) -> Self {
+        Self { db, settings }
+    }
+
+    pub fn start(self) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            loop {
+                if let Err(e) = self.run_once().await {
+                    error!("Operator report failed: {}", e);
+                }
+                tokio::time::sleep(CHECK_INTERVAL).await;
+            }
+        })
+    }
+
+    /// Build and send a report when the last one is a week old, returns if one was sent
+    pub async fn run_once(&self) -> Result<bool, Error> {
+        let cfg = match &self.settings.reports {
+            Some(c) => c,
+            None => return Ok(false),
+        };
+        let end = Utc::now();
+        let period = TimeDelta::days(PERIOD_DAYS);
+        let start = match self.db.get_latest_operator_report().await? {
+            Some(r) if end - r.end < period => return Ok(false),
+            Some(r) => r.end,
+            None => end - period,
+        };
+        let report = self.db.build_operator_report(start, end).await?;
+        self.db.add_operator_report(&report).await?;
+        info!("Created operator report {}", report.subject());
+
+        if let Some(url) = &cfg.webhook {
+            if let Err(e) = send_webhook(url, &report).await {
+                warn!("Failed to post operator report: {}", e);
+            }
+        }
+        if let Some(smtp) = &cfg.smtp {
+            if let Err(e) = send_email(smtp, &report).await {
+                warn!("Failed to email operator report: {}", e);
+            }
+        }
+        if cfg.nostr_pubkeys.as_ref().is_some_and(|p| !p.is_empty()) {
+            if let Err(e) = self.send_nostr(cfg, &report).await {
+                warn!("Failed to send operator report direct messages: {}", e);
+            }
+        }
+        Ok(true)
+    }
+
+    async fn send_nostr(&self, cfg: &ReportsConfig, report: &OperatorReport) -> Result<(), Error> {
+        let bot = self.settings.bot.as_ref();
+        let key = match cfg.nostr_key.as_ref().or(bot.map(|b| &b.server_key)) {
+            Some(k) => Keys::parse(k)?,
+            None => return Ok(()),
+        };
+        let client = Client::new(key);
+        for r in cfg
+            .relays
+            .as_ref()
+            .or(bot.map(|b| &b.relays))
+            .into_iter()
+            .flatten()
+        {
+            client.add_relay(r).await?;
+        }
+        client.connect().await;
+        let text = report.to_string();
+        for p in cfg.nostr_pubkeys.iter().flatten() {
+            let pubkey = PublicKey::parse(p)?;
+            if let Err(e) = client.send_private_msg(pubkey, text.clone(), []).await {
+                warn!("Failed to send operator report to {}: {}", p, e);
+            }
+        }
+        client.disconnect().await?;
+        Ok(())
+    }
+}
+
+async fn send_webhook(url: &str, report: &OperatorReport) -> Result<(), Error> {
+    reqwest::Client::new()
+        .post(url)
+        .header("content-type", "application/json")
+        .body(serde_json::to_string(report)?)
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(())
+}
+
+async fn send_email(cfg: &SmtpConfig, report: &OperatorReport) -> Result<(), Error> {
+    let port = cfg.port.unwrap_or(DEFAULT_SMTP_PORT);
+    let mut transport = if port == STARTTLS_PORT {
+        AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&cfg.host)?
+    } else {
+        AsyncSmtpTransport::<Tokio1Executor>::relay(&cfg.host)?
+    }
+    .port(port);
+    if let (Some(u), Some(p)) = (&cfg.username, &cfg.password) {
+        transport = transport.credentials(Credentials::new(u.clone(), p.clone()));
+    }
+    let mut msg = Message::builder()
+        .from(cfg.from.parse()?)
+        .subject(report.subject());
+    for to in &cfg.to {
+        msg = msg.to(to.parse()?);
+    }
+    let msg = msg
+        .header(ContentType::TEXT_PLAIN)
+        .body(report.to_string())?;
+    transport.build().send(msg).await?;
+    Ok(())
+}
@@ -0,0 +1,306 @@
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Error};
+use chrono::Utc;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use tokio::task::JoinHandle;
+
+use crate::background::{enqueue, JobHandler};
+use crate::db::{Database, Job, MetadataSource, ProcessingReport};
+use crate::filesystem::FileStore;
+use crate::processing::thumbnail::{blur_hash_file, thumbnail_file, THUMBNAIL_PARAMS};
+use crate::processing::{
+    compress_file, probe_file, FileProcessorResult, MediaLimits, COMPRESS_PARAMS,
+};
+use crate::settings::{QualitySearchConfig, Settings};
+
+pub const REPROCESS_JOB: &str = "reprocess";
+
+/// Default seconds between metadata backfill runs
+const DEFAULT_BACKFILL_INTERVAL: u64 = 3600;
+
+/// Default reprocess jobs the backfill keeps queued
+const DEFAULT_BACKFILL_BATCH: u32 = 100;
+
+/// Wait after a file without metadata is added, so a burst of uploads is queued in one run
+const BACKFILL_SETTLE: Duration = Duration::from_secs(30);
+
+/// Payload for re-running media processing on a stored file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReprocessJob {
+    #[serde(with = "hex")]
+    pub file: Vec<u8>,
+    /// Also create a compressed rendition of the file
+    pub transcode: bool,
+}
+
+/// Re-probes stored files to fill missing metadata, computes the blurhash of images and videos
+/// without one and creates their thumbnail, optionally transcoding them
+pub struct ReprocessHandler {
+    db: Database,
+    fs: FileStore,
+    limits: MediaLimits,
+    quality_search: Option<QualitySearchConfig>,
+}
+
+impl ReprocessHandler {
+    pub fn new(db: Database, settings: Settings) -> Self {
+        Self {
+            db,
+            limits: MediaLimits::new(&settings),
+            quality_search: settings.quality_search.clone(),
+            fs: FileStore::new(settings),
+        }
+    }
+
+    /// Create the thumbnail of a file and record it as derived from the file
+    async fn store_thumbnail(&self, file: &Vec<u8>, path: PathBuf) -> Result<(), Error> {
+        let thumb = thumbnail_file(path, &self.limits)?;
+        let f = tokio::fs::File::open(&thumb.result).await?;
+        let res = self.fs.put(f, &thumb.mime_type, false).await;
+        tokio::fs::remove_file(&thumb.result).await?;
+        let blob = res?;
+        self.db.add_unowned_file(&blob.upload).await?;
+        self.db
+            .add_derivation(file, THUMBNAIL_PARAMS, &blob.upload.id)
+            .await?;
+        info!(
+            "Created thumbnail {} => {}",
+            hex::encode(file),
+            hex::encode(&blob.upload.id)
+        );
+        Ok(())
+    }
+}
+
+#[rocket::async_trait]
+impl JobHandler for ReprocessHandler {
+    fn kind(&self) -> &'static str {
+        REPROCESS_JOB
+    }
+
+    fn concurrency(&self) -> usize {
+        2
+    }
+
+    async fn run(&self, job: &Job) -> Result<(), Error> {
+        let req: ReprocessJob = job.payload()?;
+        let info = match self.db.get_file(&req.file).await? {
+            Some(i) => i,
+            None => bail!("File not found"),
+        };
+        if !self.fs.get(&req.file).exists() {
+            bail!("File missing from storage");
+        }
+        let plain = self.fs.plain_file(&req.file)?;
+        let path = plain.path().to_path_buf();
+
+        let (mime_type, width, height) = {
+            let probe = probe_file(path.clone())?;
+            let v_stream = probe.best_video();
+            (
+                FileStore::hack_mime_type(&info.mime_type, &probe),
+                v_stream.map(|v| v.width as u32),
+                v_stream.map(|v| v.height as u32),
+            )
+        };
+        self.db
+            .update_file_metadata(&req.file, &mime_type, width, height)
+            .await?;
+
+        if width.is_some() {
+            if info.blur_hash.is_none() {
+                let hash = blur_hash_file(&path, &self.limits)?;
+                self.db
+                    .set_file_blur_hash(&req.file, &hash, MetadataSource::Server)
+                    .await?;
+            }
+            if self
+                .db
+                .get_derived_file(&req.file, THUMBNAIL_PARAMS)
+                .await?
+                .is_none()
+            {
+                self.store_thumbnail(&req.file, path.clone()).await?;
+            }
+        }
+
+        if req.transcode {
+            let start = Instant::now();
+            if let FileProcessorResult::NewFile(new_file) =
+                compress_file(path, &mime_type, &self.limits, self.quality_search.as_ref())?
+            {
+                let duration = start.elapsed();
+                let f = tokio::fs::File::open(&new_file.result).await?;
+                let res = self.fs.put(f, &new_file.mime_type, false).await;
+                tokio::fs::remove_file(&new_file.result).await?;
+                let mut blob = res?;
+                blob.upload.name = info.name.clone();
+                blob.upload.alt = info.alt.clone();
+                blob.upload.original_hash =
+                    Some(info.original_hash.clone().unwrap_or(req.file.clone()));
+                for owner in self.db.get_file_owners(&req.file).await? {
+                    self.db.add_file(&blob.upload, owner.id).await?;
+                }
+                self.db
+                    .add_derivation(&req.file, COMPRESS_PARAMS, &blob.upload.id)
+                    .await?;
+                self.db
+                    .add_processing_report(&ProcessingReport {
+                        file: blob.upload.id.clone(),
+                        source: req.file.clone(),
+                        source_size: info.size,
+                        source_mime_type: mime_type.clone(),
+                        params: COMPRESS_PARAMS.to_string(),
+                        duration_ms: duration.as_millis() as u32,
+                        quality: new_file.quality,
+                        created: Utc::now(),
+                    })
+                    .await?;
+                info!(
+                    "Transcoded {} => {}",
+                    hex::encode(&req.file),
+                    hex::encode(&blob.upload.id)
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Database {
+    /// Images and videos without dimensions or blurhash which were never queued for a
+    /// backfill, thumbnails are not reprocessed
+    pub async fn list_files_missing_metadata(
+        &self,
+        limit: u32,
+    ) -> Result<Vec<Vec<u8>>, sqlx::Error> {
+        sqlx::query_scalar(
+            "select id from uploads \
+            where (width is null or blur_hash is null) and metadata_queued is null \
+            and (mime_type like 'image/%' or mime_type like 'video/%') \
+            and not exists(select 1 from upload_derivations \
+                where derived = uploads.id and params = ?) \
+            order by created desc \
+            limit ?",
+        )
+        .bind(THUMBNAIL_PARAMS)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    /// Store a blurhash, replacing only one computed by the server
+    pub async fn set_file_blur_hash(
+        &self,
+        file: &Vec<u8>,
+        hash: &str,
+        source: MetadataSource,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "update uploads set blur_hash = ?, blur_hash_source = ? \
+            where id = ? and (blur_hash is null or blur_hash_source = 'server')",
+        )
+        .bind(hash)
+        .bind(source)
+        .bind(file)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn set_metadata_queued(&self, file: &Vec<u8>) -> Result<(), sqlx::Error> {
+        sqlx::query("update uploads set metadata_queued = current_timestamp where id = ?")
+            .bind(file)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Jobs of a kind waiting or running
+    pub async fn count_pending_jobs(&self, kind: &str) -> Result<u64, sqlx::Error> {
+        sqlx::query_scalar(
+            "select cast(count(*) as unsigned) from jobs \
+            where kind = ? and status in ('queued', 'running')",
+        )
+        .bind(kind)
+        .fetch_one(&self.pool)
+        .await
+    }
+}
+
+/// Queues reprocess jobs for images and videos stored without dimensions or blurhash, eg.
+/// uploaded while media processing was disabled or when probing failed.
+///
+/// Runs every interval and shortly after a file without metadata is added. Each file is queued
+/// once, failures can be retried from the admin API
+pub struct MetadataBackfill {
+    db: Database,
+    settings: Settings,
+}
+
+impl MetadataBackfill {
+    pub fn new(db: Database, settings: Settings) -> Self {
+        Self { db, settings }
+    }
+
+    pub fn start(self) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let cfg = self.settings.metadata_backfill.as_ref();
+            let interval = Duration::from_secs(
+                cfg.and_then(|c| c.interval)
+                    .unwrap_or(DEFAULT_BACKFILL_INTERVAL),
+            );
+            loop {
+                let wait = match self.run_once().await {
+                    // check again soon while the queued jobs are worked through
+                    Ok(true) => interval.min(BACKFILL_SETTLE),
+                    Ok(false) => interval,
+                    Err(e) => {
+                        error!("Metadata backfill failed: {}", e);
+                        interval
+                    }
+                };
+                tokio::select! {
+                    _ = tokio::time::sleep(wait) => {}
+                    _ = self.db.missing_metadata.notified() => {
+                        tokio::time::sleep(BACKFILL_SETTLE).await;
+                    }
+                }
+            }
+        })
+    }
+
+    /// Queue files missing metadata until the batch size of reprocess jobs is pending,
+    /// returns if more files may be waiting
+    pub async fn run_once(&self) -> Result<bool, Error> {
+        let batch = self
+            .settings
+            .metadata_backfill
+            .as_ref()
+            .and_then(|c| c.batch)
+            .unwrap_or(DEFAULT_BACKFILL_BATCH);
+        let pending = self.db.count_pending_jobs(REPROCESS_JOB).await?;
+        let free = (batch as u64).saturating_sub(pending) as u32;
+        if free == 0 {
+            return Ok(true);
+        }
+        let files = self.db.list_files_missing_metadata(free).await?;
+        let mut queued = 0;
+        for file in files {
+            let job = ReprocessJob {
+                file,
+                transcode: false,
+            };
+            enqueue(&self.db, REPROCESS_JOB, &job).await?;
+            self.db.set_metadata_queued(&job.file).await?;
+            queued += 1;
+        }
+        if queued > 0 {
+            info!("Queued {} files for metadata backfill", queued);
+        }
+        Ok(queued > 0)
+    }
+}
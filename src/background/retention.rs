@@ -0,0 +1,91 @@
+use std::time::Duration;
+
+use anyhow::Error;
+use chrono::{DateTime, TimeDelta, Utc};
+use log::{error, info};
+use tokio::task::JoinHandle;
+
+use crate::db::Database;
+use crate::deletion::{DeleteReason, DeletionService};
+use crate::filesystem::FileStore;
+use crate::settings::{RetentionConfig, Settings};
+
+/// Default time between expired upload cleanups
+const DEFAULT_INTERVAL: u64 = 3600;
+
+/// Number of expired uploads removed per query
+const BATCH_SIZE: u32 = 1000;
+
+/// Days uploads are kept for a tier, None keeps them forever.
+///
+/// Tiers missing from the config use the default retention.
+pub fn tier_days(cfg: &RetentionConfig, tier: Option<&str>) -> Option<u32> {
+    match tier.and_then(|t| cfg.tiers.get(t)) {
+        Some(days) => *days,
+        None => cfg.default_days,
+    }
+}
+
+/// Expiry for a new upload (or re-pin) by this pubkey
+pub async fn upload_expiry(
+    db: &Database,
+    settings: &Settings,
+    pubkey: &Vec<u8>,
+) -> Option<DateTime<Utc>> {
+    let cfg = settings.retention.as_ref()?;
+    let tier = db.get_user(pubkey).await.ok().and_then(|u| u.tier);
+    tier_days(cfg, tier.as_deref()).map(|d| Utc::now() + TimeDelta::days(d as i64))
+}
+
+/// Removes uploads whose retention period has ended
+pub struct RetentionCleaner {
+    db: Database,
+    fs: FileStore,
+    settings: Settings,
+}
+
+impl RetentionCleaner {
+    pub fn new(db: Database, settings: Settings) -> Self {
+        Self {
+            db,
+            fs: FileStore::new(settings.clone()),
+            settings,
+        }
+    }
+
+    pub fn start(self) -> JoinHandle<()> {
+        let interval = self
+            .settings
+            .retention
+            .as_ref()
+            .and_then(|r| r.interval)
+            .unwrap_or(DEFAULT_INTERVAL);
+        tokio::spawn(async move {
+            loop {
+                match self.run_once().await {
+                    Ok(n) if n > 0 => info!("Removed {} expired uploads", n),
+                    Ok(_) => {}
+                    Err(e) => error!("Retention cleanup failed: {}", e),
+                }
+                tokio::time::sleep(Duration::from_secs(interval)).await;
+            }
+        })
+    }
+
+    pub async fn run_once(&self) -> Result<u64, Error> {
+        let mut removed = 0;
+        loop {
+            let expired = self.db.list_expired_files(BATCH_SIZE).await?;
+            if expired.is_empty() {
+                break;
+            }
+            for id in expired {
+                DeletionService::new(&self.db, &self.fs, &self.settings)
+                    .purge(&id, None, DeleteReason::Retention)
+                    .await?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+}
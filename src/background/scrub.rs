@@ -0,0 +1,461 @@
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use anyhow::{bail, Error};
+use chrono::{DateTime, Utc};
+use log::{error, info, warn};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tokio::fs::File;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt, ReadBuf};
+use tokio::sync::RwLock;
+use tokio::task::JoinHandle;
+
+use crate::db::Database;
+use crate::filesystem::FileStore;
+use crate::mirror;
+use crate::settings::Settings;
+
+/// Default time between scrub runs
+const DEFAULT_INTERVAL: u64 = 86400;
+
+/// Default number of files checked per run
+const DEFAULT_BATCH_SIZE: u32 = 1000;
+
+/// Outcome of checking a single file
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum VerifyResult {
+    Ok,
+    Missing,
+    Corrupt,
+    Restored,
+}
+
+/// Summary of the most recent scrub run
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ScrubStatus {
+    pub running: bool,
+    pub last_started: Option<DateTime<Utc>>,
+    pub last_finished: Option<DateTime<Utc>>,
+    pub checked: u64,
+    pub missing: u64,
+    pub corrupt: u64,
+    pub restored: u64,
+}
+
+/// Shared scrub status, readable from the admin routes
+pub type ScrubState = Arc<RwLock<ScrubStatus>>;
+
+/// Re-hashes stored files to detect bit-rot and missing data
+#[derive(Clone)]
+pub struct Scrubber {
+    db: Database,
+    fs: FileStore,
+    settings: Settings,
+    state: ScrubState,
+}
+
+impl Scrubber {
+    pub fn new(db: Database, settings: Settings, state: ScrubState) -> Self {
+        Self {
+            db,
+            fs: FileStore::new(settings.clone()),
+            settings,
+            state,
+        }
+    }
+
+    /// Run the scrubber periodically in the background
+    pub fn start(self) -> JoinHandle<()> {
+        let interval = self
+            .settings
+            .scrub
+            .as_ref()
+            .and_then(|s| s.interval)
+            .unwrap_or(DEFAULT_INTERVAL);
+        tokio::spawn(async move {
+            loop {
+                if let Err(e) = self.run_once().await {
+                    error!("Scrub failed: {}", e);
+                }
+                tokio::time::sleep(Duration::from_secs(interval)).await;
+            }
+        })
+    }
+
+    /// Check the batch of files which have gone the longest without verification
+    pub async fn run_once(&self) -> Result<(), Error> {
+        let batch_size = self
+            .settings
+            .scrub
+            .as_ref()
+            .and_then(|s| s.batch_size)
+            .unwrap_or(DEFAULT_BATCH_SIZE);
+        {
+            let mut state = self.state.write().await;
+            *state = ScrubStatus {
+                running: true,
+                last_started: Some(Utc::now()),
+                ..Default::default()
+            };
+        }
+
+        let res = self.check_batch(batch_size).await;
+
+        #[cfg(feature = "labels")]
+        match self.db.delete_orphan_labels().await {
+            Ok(n) if n > 0 => info!("Removed {} orphaned labels", n),
+            Ok(_) => {}
+            Err(e) => warn!("Failed to remove orphaned labels: {}", e),
+        }
+
+        let mut state = self.state.write().await;
+        state.running = false;
+        state.last_finished = Some(Utc::now());
+        info!(
+            "Scrub finished: checked={}, missing={}, corrupt={}, restored={}",
+            state.checked, state.missing, state.corrupt, state.restored
+        );
+        res
+    }
+
+    async fn check_batch(&self, batch_size: u32) -> Result<(), Error> {
+        for id in self.db.list_files_to_verify(batch_size).await? {
+            let res = self.verify_file(&id, true).await;
+            if let Err(e) = self.db.set_file_scrubbed(&id).await {
+                warn!("Failed to record check of {}: {}", hex::encode(&id), e);
+            }
+            let res = match res {
+                Ok(r) => r,
+                Err(e) => {
+                    warn!("Failed to verify {}: {}", hex::encode(&id), e);
+                    continue;
+                }
+            };
+            let mut state = self.state.write().await;
+            state.checked += 1;
+            match res {
+                VerifyResult::Ok => {}
+                VerifyResult::Missing => state.missing += 1,
+                VerifyResult::Corrupt => state.corrupt += 1,
+                VerifyResult::Restored => state.restored += 1,
+            }
+        }
+        Ok(())
+    }
+
+    /// Hash a single stored file and record the result.
+    ///
+    /// With `fast` the stored BLAKE3 hash is checked when available, falling back
+    /// to a full SHA-256 check on mismatch.
+    pub async fn verify_file(&self, id: &Vec<u8>, fast: bool) -> Result<VerifyResult, Error> {
+        let mut file = match self.fs.open(id).await {
+            Ok(f) => Some(f),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+
+        #[cfg(feature = "blake3")]
+        if let (true, Some(f)) = (fast, file.as_mut()) {
+            let stored = self.db.get_file(id).await?.and_then(|u| u.blake3);
+            if let Some(stored) = stored {
+                if FileStore::hash_file_blake3(f).await? == stored {
+                    self.db.set_file_verified(id).await?;
+                    return Ok(VerifyResult::Ok);
+                }
+            }
+        }
+        #[cfg(not(feature = "blake3"))]
+        let _ = fast;
+
+        let actual_hash = match file.as_mut() {
+            Some(f) => Some(FileStore::hash_file(f).await?),
+            None => None,
+        };
+        if actual_hash.as_ref() == Some(id) {
+            #[cfg(feature = "blake3")]
+            if let Some(f) = file.as_mut() {
+                let b3 = FileStore::hash_file_blake3(f).await?;
+                self.db.set_file_blake3(id, &b3).await?;
+            }
+            self.db.set_file_verified(id).await?;
+            return Ok(VerifyResult::Ok);
+        }
+
+        warn!(
+            "File {} failed integrity check (found {})",
+            hex::encode(id),
+            actual_hash
+                .as_ref()
+                .map(hex::encode)
+                .unwrap_or("nothing".to_string())
+        );
+        self.db.add_corrupt_file(id, actual_hash.as_ref()).await?;
+        if self.restore_file(id).await {
+            self.db.set_corrupt_file_restored(id).await?;
+            self.db.set_file_verified(id).await?;
+            return Ok(VerifyResult::Restored);
+        }
+        Ok(if actual_hash.is_some() {
+            VerifyResult::Corrupt
+        } else {
+            VerifyResult::Missing
+        })
+    }
+
+    /// Try to download a good copy of the file from the configured peers
+    async fn restore_file(&self, id: &Vec<u8>) -> bool {
+        let peers = match self
+            .settings
+            .scrub
+            .as_ref()
+            .and_then(|s| s.restore_peers.as_ref())
+        {
+            Some(p) => p,
+            None => return false,
+        };
+        for peer in peers {
+            let url = format!("{}/{}", peer.trim_end_matches('/'), hex::encode(id));
+            match self.download_verified(&url, id).await {
+                Ok(()) => {
+                    info!("Restored {} from {}", hex::encode(id), peer);
+                    return true;
+                }
+                Err(e) => warn!("Failed to restore {} from {}: {}", hex::encode(id), peer, e),
+            }
+        }
+        false
+    }
+
+    /// Download a file with the mirror rules, timeout and size limit, keeping it only when
+    /// it matches the id
+    async fn download_verified(&self, url: &str, id: &Vec<u8>) -> Result<(), Error> {
+        let rsp = mirror::fetch(&self.settings, url).await?;
+        let mut reader = mirror::response_reader(&self.settings, rsp);
+
+        let dst = self.fs.get(id);
+        if let Some(parent) = dst.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let mut tmp_path = dst.clone().into_os_string();
+        tmp_path.push(".restore");
+        let mut tmp = File::create(&tmp_path).await?;
+        let mut hasher = Sha256::new();
+        let mut buf = vec![0u8; 64 * 1024];
+        let copied: Result<(), Error> = async {
+            loop {
+                let n = reader.read(&mut buf).await?;
+                if n == 0 {
+                    break;
+                }
+                hasher.update(&buf[..n]);
+                tmp.write_all(&buf[..n]).await?;
+            }
+            tmp.flush().await?;
+            Ok(())
+        }
+        .await;
+        drop(tmp);
+        if let Err(e) = copied {
+            tokio::fs::remove_file(&tmp_path).await?;
+            return Err(e);
+        }
+
+        if hasher.finalize().as_slice() != id.as_slice() {
+            tokio::fs::remove_file(&tmp_path).await?;
+            bail!("Hash mismatch");
+        }
+        if self.fs.encrypts() {
+            let res = FileStore::write_stored(Path::new(&tmp_path), &dst);
+            tokio::fs::remove_file(&tmp_path).await?;
+            res?;
+        } else {
+            tokio::fs::rename(&tmp_path, &dst).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Results of downloads verified while they were sent
+pub struct DownloadVerifyStats {
+    pub ok: AtomicU64,
+    pub failed: AtomicU64,
+}
+
+static DOWNLOAD_VERIFY_STATS: DownloadVerifyStats = DownloadVerifyStats {
+    ok: AtomicU64::new(0),
+    failed: AtomicU64::new(0),
+};
+
+pub fn download_verify_stats() -> &'static DownloadVerifyStats {
+    &DOWNLOAD_VERIFY_STATS
+}
+
+/// Size of the chunks read from the file, the last one is held back until it is verified
+const VERIFY_CHUNK: usize = 64 * 1024;
+
+/// Hashes a stored file while it is sent to a client (GET /<sha256>?verify=true).
+///
+/// The last chunk read is held back until the whole file is hashed, so a corrupt file never
+/// arrives as a complete response, also when its length is right. When the data does not
+/// match the id the stream fails instead of ending and the client receives less than the
+/// content-length. The file is recorded as corrupt for the scrubber to restore, a good file
+/// is marked as verified
+pub struct VerifyingReader<R> {
+    inner: R,
+    id: Vec<u8>,
+    hasher: Option<Sha256>,
+    db: Option<Database>,
+    /// Data released to the client and how much of it was sent
+    out: Vec<u8>,
+    out_pos: usize,
+    /// Last chunk read, released when the next chunk is read or the hash matched
+    held: Vec<u8>,
+    scratch: Vec<u8>,
+}
+
+impl<R> VerifyingReader<R> {
+    pub fn new(inner: R, id: &[u8], db: Option<Database>) -> Self {
+        Self {
+            inner,
+            id: id.to_vec(),
+            hasher: Some(Sha256::new()),
+            db,
+            out: Vec::new(),
+            out_pos: 0,
+            held: Vec::new(),
+            scratch: Vec::new(),
+        }
+    }
+
+    /// Record the result of a finished download, the database is updated in the background
+    fn finish(&mut self, hasher: Sha256) -> std::io::Result<()> {
+        let actual = hasher.finalize().to_vec();
+        let id = self.id.clone();
+        if actual == id {
+            DOWNLOAD_VERIFY_STATS.ok.fetch_add(1, Ordering::Relaxed);
+            if let Some(db) = self.db.clone() {
+                tokio::spawn(async move {
+                    if let Err(e) = db.set_file_verified(&id).await {
+                        warn!("Failed to mark {} as verified: {}", hex::encode(&id), e);
+                    }
+                });
+            }
+            return Ok(());
+        }
+
+        DOWNLOAD_VERIFY_STATS.failed.fetch_add(1, Ordering::Relaxed);
+        error!(
+            "File {} failed verification on download (found {})",
+            hex::encode(&id),
+            hex::encode(&actual)
+        );
+        if let Some(db) = self.db.clone() {
+            tokio::spawn(async move {
+                if let Err(e) = db.add_corrupt_file(&id, Some(&actual)).await {
+                    warn!("Failed to record corrupt file {}: {}", hex::encode(&id), e);
+                }
+            });
+        }
+        Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "File does not match its hash",
+        ))
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for VerifyingReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = &mut *self;
+        loop {
+            if this.out_pos < this.out.len() {
+                let n = buf.remaining().min(this.out.len() - this.out_pos);
+                buf.put_slice(&this.out[this.out_pos..this.out_pos + n]);
+                this.out_pos += n;
+                return Poll::Ready(Ok(()));
+            }
+            let hasher = match this.hasher.as_mut() {
+                Some(h) => h,
+                // verified, nothing left to send
+                None => return Poll::Ready(Ok(())),
+            };
+
+            this.scratch.resize(VERIFY_CHUNK, 0);
+            let mut read_buf = ReadBuf::new(&mut this.scratch);
+            match Pin::new(&mut this.inner).poll_read(cx, &mut read_buf) {
+                Poll::Ready(Ok(())) => {}
+                r => return r,
+            }
+            let n = read_buf.filled().len();
+            this.scratch.truncate(n);
+            this.out_pos = 0;
+            if n > 0 {
+                hasher.update(&this.scratch);
+                // release the chunk held so far and hold the new one
+                std::mem::swap(&mut this.out, &mut this.held);
+                std::mem::swap(&mut this.held, &mut this.scratch);
+            } else {
+                // end of the file, the last chunk is only released when the hash matches
+                let hasher = this.hasher.take().unwrap();
+                this.finish(hasher)?;
+                std::mem::swap(&mut this.out, &mut this.held);
+                this.held.clear();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Read until the end or an error, returning what was received
+    async fn read_all<R: AsyncRead + Unpin>(mut r: R) -> (Vec<u8>, std::io::Result<()>) {
+        let mut out = Vec::new();
+        let mut buf = [0u8; 1000];
+        loop {
+            match r.read(&mut buf).await {
+                Ok(0) => return (out, Ok(())),
+                Ok(n) => out.extend_from_slice(&buf[..n]),
+                Err(e) => return (out, Err(e)),
+            }
+        }
+    }
+
+    fn data(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[tokio::test]
+    async fn sends_good_file() {
+        for len in [0, 10, VERIFY_CHUNK, VERIFY_CHUNK * 3 + 7] {
+            let file = data(len);
+            let id = Sha256::digest(&file).to_vec();
+            let (out, res) = read_all(VerifyingReader::new(&file[..], &id, None)).await;
+            assert!(res.is_ok());
+            assert_eq!(out, file);
+        }
+    }
+
+    #[tokio::test]
+    async fn holds_back_end_of_corrupt_file() {
+        for len in [10, VERIFY_CHUNK * 3 + 7] {
+            let file = data(len);
+            let id = Sha256::digest(&file).to_vec();
+            let mut corrupt = file.clone();
+            corrupt[0] ^= 1;
+            let (out, res) = read_all(VerifyingReader::new(&corrupt[..], &id, None)).await;
+            assert_eq!(res.unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+            assert!(out.len() < corrupt.len());
+        }
+    }
+}
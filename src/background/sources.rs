@@ -0,0 +1,193 @@
+use std::time::Duration;
+
+use anyhow::Error;
+use chrono::{DateTime, TimeDelta, Utc};
+use log::{error, info};
+use reqwest::Method;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use sqlx::{Error as SqlError, FromRow, Row};
+use tokio::task::JoinHandle;
+
+use crate::db::Database;
+use crate::filesystem::FileTooLarge;
+use crate::mirror;
+use crate::settings::Settings;
+
+/// Default time between source checks
+const DEFAULT_INTERVAL: u64 = 3600;
+
+/// Default age after which a source is checked again, 7 days
+const DEFAULT_MAX_AGE: u64 = 7 * 86400;
+
+/// Default number of sources checked per run
+const DEFAULT_BATCH: u32 = 100;
+
+/// Longest error message stored for a dead source
+const MAX_ERROR_LEN: usize = 255;
+
+/// Url a file was mirrored from, with the result of the last liveness check
+#[derive(Debug, Clone, FromRow, Serialize)]
+pub struct UploadSource {
+    #[serde(with = "hex")]
+    pub file: Vec<u8>,
+    #[serde(skip)]
+    pub url_hash: Vec<u8>,
+    pub url: String,
+    pub created: DateTime<Utc>,
+    pub checked: Option<DateTime<Utc>>,
+    pub alive: Option<bool>,
+    /// First failed check since the source was last alive
+    pub dead_since: Option<DateTime<Utc>>,
+    pub last_error: Option<String>,
+}
+
+impl Database {
+    /// Record the url a file was mirrored from
+    pub async fn add_upload_source(&self, file: &Vec<u8>, url: &str) -> Result<(), SqlError> {
+        sqlx::query("insert ignore into upload_sources(file,url_hash,url) values(?,?,?)")
+            .bind(file)
+            .bind(Sha256::digest(url.as_bytes()).to_vec())
+            .bind(url)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Sources never checked or last checked more than `max_age` seconds ago
+    pub async fn list_sources_to_check(
+        &self,
+        max_age: u64,
+        limit: u32,
+    ) -> Result<Vec<UploadSource>, SqlError> {
+        sqlx::query_as(
+            "select * from upload_sources \
+            where checked is null or checked < ? \
+            order by checked asc \
+            limit ?",
+        )
+        .bind(Utc::now() - TimeDelta::seconds(max_age as i64))
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    pub async fn set_source_status(
+        &self,
+        source: &UploadSource,
+        error: Option<&str>,
+    ) -> Result<(), SqlError> {
+        sqlx::query(
+            "update upload_sources set checked = current_timestamp, alive = ?, \
+            dead_since = if(?, null, coalesce(dead_since, current_timestamp)), last_error = ? \
+            where file = ? and url_hash = ?",
+        )
+        .bind(error.is_none())
+        .bind(error.is_none())
+        .bind(error)
+        .bind(&source.file)
+        .bind(&source.url_hash)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Sources which failed their last check, longest dead first
+    pub async fn list_dead_sources(
+        &self,
+        offset: u32,
+        limit: u32,
+    ) -> Result<(Vec<UploadSource>, i64), SqlError> {
+        let results: Vec<UploadSource> = sqlx::query_as(
+            "select * from upload_sources \
+            where dead_since is not null \
+            order by dead_since asc \
+            limit ? offset ?",
+        )
+        .bind(limit)
+        .bind(offset)
+        .fetch_all(&self.pool)
+        .await?;
+        let count: i64 =
+            sqlx::query("select count(*) from upload_sources where dead_since is not null")
+                .fetch_one(&self.pool)
+                .await?
+                .try_get(0)?;
+        Ok((results, count))
+    }
+}
+
+/// Periodically checks that the urls files were mirrored from still respond
+pub struct SourceChecker {
+    db: Database,
+    settings: Settings,
+}
+
+impl SourceChecker {
+    pub fn new(db: Database, settings: Settings) -> Self {
+        Self { db, settings }
+    }
+
+    pub fn start(self) -> JoinHandle<()> {
+        let interval = self
+            .settings
+            .source_check
+            .as_ref()
+            .and_then(|s| s.interval)
+            .unwrap_or(DEFAULT_INTERVAL);
+        tokio::spawn(async move {
+            loop {
+                match self.run_once().await {
+                    Ok((alive, dead)) if alive + dead > 0 => {
+                        info!("Checked sources: {} alive, {} dead", alive, dead)
+                    }
+                    Ok(_) => {}
+                    Err(e) => error!("Source check failed: {}", e),
+                }
+                tokio::time::sleep(Duration::from_secs(interval)).await;
+            }
+        })
+    }
+
+    /// Check one batch of sources, returns the number of (alive, dead) sources
+    pub async fn run_once(&self) -> Result<(u32, u32), Error> {
+        let cfg = self.settings.source_check.as_ref();
+        let max_age = cfg.and_then(|s| s.max_age).unwrap_or(DEFAULT_MAX_AGE);
+        let batch = cfg.and_then(|s| s.batch).unwrap_or(DEFAULT_BATCH);
+        let (mut alive, mut dead) = (0, 0);
+        for source in self.db.list_sources_to_check(max_age, batch).await? {
+            let error = self.check(&source.url).await.err().map(|e| {
+                let mut msg = e.to_string();
+                if msg.len() > MAX_ERROR_LEN {
+                    let mut end = MAX_ERROR_LEN;
+                    while !msg.is_char_boundary(end) {
+                        end -= 1;
+                    }
+                    msg.truncate(end);
+                }
+                msg
+            });
+            if error.is_some() {
+                dead += 1;
+            } else {
+                alive += 1;
+            }
+            self.db.set_source_status(&source, error.as_deref()).await?;
+        }
+        Ok((alive, dead))
+    }
+
+    /// HEAD the url with the mirror rules, falling back to GET for servers which don't allow HEAD
+    async fn check(&self, url: &str) -> Result<(), Error> {
+        let res = match mirror::request(&self.settings, Method::HEAD, url).await {
+            Ok(_) => return Ok(()),
+            // the body is not read, dropping the response closes the connection
+            Err(_) => mirror::fetch(&self.settings, url).await.map(|_| ()),
+        };
+        match res {
+            // too large to mirror again, but still there
+            Err(e) if FileTooLarge::is(&e) => Ok(()),
+            r => r,
+        }
+    }
+}
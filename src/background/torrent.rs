@@ -0,0 +1,134 @@
+use std::path::PathBuf;
+
+use anyhow::{bail, Error};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::background::{enqueue, JobHandler};
+use crate::db::{Database, FileUpload, Job};
+use crate::filesystem::FileStore;
+use crate::settings::Settings;
+use crate::torrent::{create_torrent, magnet_link, DEFAULT_PIECE_LENGTH};
+
+pub const TORRENT_JOB: &str = "torrent";
+
+/// Payload for generating the torrent of a stored file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TorrentJob {
+    #[serde(with = "hex")]
+    pub file: Vec<u8>,
+}
+
+/// Path of the generated .torrent file for a blob
+pub fn torrent_path(settings: &Settings, id: &[u8]) -> PathBuf {
+    PathBuf::from(&settings.storage_dir)
+        .join("torrents")
+        .join(format!("{}.torrent", hex::encode(id)))
+}
+
+/// Magnet link for an upload, if a torrent exists for it
+pub fn upload_magnet(settings: &Settings, upload: &FileUpload) -> Option<String> {
+    let info_hash = upload.torrent_info_hash.as_ref()?;
+    let id = hex::encode(&upload.id);
+    Some(magnet_link(
+        info_hash,
+        &id,
+        &format!("{}/{}", settings.public_url, id),
+        &format!("{}/torrent/{}", settings.public_url, id),
+    ))
+}
+
+/// Queue torrent generation when the upload is large enough
+pub async fn queue_torrent(
+    db: &Database,
+    settings: &Settings,
+    upload: &FileUpload,
+) -> Result<(), Error> {
+    match &settings.torrent {
+        Some(cfg) if upload.size >= cfg.min_size && upload.torrent_info_hash.is_none() => {
+            enqueue(
+                db,
+                TORRENT_JOB,
+                &TorrentJob {
+                    file: upload.id.clone(),
+                },
+            )
+            .await?;
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Generates v2 torrents for large blobs using the public url as web seed
+pub struct TorrentHandler {
+    db: Database,
+    fs: FileStore,
+    settings: Settings,
+}
+
+impl TorrentHandler {
+    pub fn new(db: Database, settings: Settings) -> Self {
+        Self {
+            db,
+            fs: FileStore::new(settings.clone()),
+            settings,
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl JobHandler for TorrentHandler {
+    fn kind(&self) -> &'static str {
+        TORRENT_JOB
+    }
+
+    async fn run(&self, job: &Job) -> Result<(), Error> {
+        let cfg = match &self.settings.torrent {
+            Some(c) => c,
+            None => bail!("Torrents are not configured"),
+        };
+        let req: TorrentJob = job.payload()?;
+        if self.db.get_file(&req.file).await?.is_none() {
+            bail!("File not found");
+        }
+        let plain = self.fs.plain_file(&req.file)?;
+        let id = hex::encode(&req.file);
+        let torrent = create_torrent(
+            plain.path(),
+            &id,
+            cfg.piece_length.unwrap_or(DEFAULT_PIECE_LENGTH),
+            cfg.trackers.as_deref().unwrap_or_default(),
+            &format!("{}/{}", self.settings.public_url, id),
+        )
+        .await?;
+
+        let dst = torrent_path(&self.settings, &req.file);
+        if let Some(parent) = dst.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        tokio::fs::write(&dst, &torrent.data).await?;
+
+        if let Some(seed_dir) = &cfg.seed_dir {
+            tokio::fs::create_dir_all(seed_dir).await?;
+            tokio::fs::write(seed_dir.join(format!("{}.torrent", id)), &torrent.data).await?;
+            let link = seed_dir.join(&id);
+            if plain.is_copy() {
+                // the stored file is encrypted, the seeder can only use the web seed
+                warn!("Not linking encrypted file {} into seed_dir", id);
+            } else if !link.exists() {
+                tokio::fs::symlink(plain.path(), &link).await?;
+            }
+        }
+
+        self.db
+            .set_torrent_info_hash(&req.file, &torrent.info_hash)
+            .await?;
+        info!(
+            "Created torrent for {} ({})",
+            id,
+            hex::encode(&torrent.info_hash)
+        );
+        Ok(())
+    }
+}
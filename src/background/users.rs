@@ -0,0 +1,216 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use anyhow::Error;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use sqlx::{Error as SqlError, Executor, FromRow};
+
+use crate::background::JobHandler;
+use crate::db::{Database, Job};
+
+pub const MERGE_USERS_JOB: &str = "merge_users";
+
+/// Counters of merged users since startup, exported in the metrics
+pub struct MergeStats {
+    pub merged: AtomicU64,
+    pub files_moved: AtomicU64,
+    pub unresolved: AtomicU64,
+}
+
+static STATS: MergeStats = MergeStats {
+    merged: AtomicU64::new(0),
+    files_moved: AtomicU64::new(0),
+    unresolved: AtomicU64::new(0),
+};
+
+pub fn stats() -> &'static MergeStats {
+    &STATS
+}
+
+/// Payload for merging duplicate user rows
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct MergeUsersJob {
+    /// Only log the rows which would be merged
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+/// A user row whose pubkey was stored as hex text instead of raw bytes
+#[derive(Debug, Clone, FromRow)]
+struct DuplicateUser {
+    pub id: u64,
+    pub pubkey: Vec<u8>,
+    /// User with the real pubkey, when exactly one matches
+    pub into_id: Option<u64>,
+    pub into_pubkey: Option<Vec<u8>>,
+}
+
+impl Database {
+    /// Users whose pubkey column holds (truncated) hex text, with the user matching the full pubkey.
+    ///
+    /// The unique index on pubkey can't catch these, the text form is a different 32 byte value.
+    async fn list_duplicate_users(&self) -> Result<Vec<DuplicateUser>, SqlError> {
+        sqlx::query_as(
+            "select d.id, d.pubkey, \
+            (select cast(if(count(*) = 1, max(u.id), null) as unsigned integer) from users u \
+                where u.id <> d.id and left(lower(hex(u.pubkey)), 32) = lower(convert(d.pubkey using latin1))) \
+                as into_id, \
+            (select if(count(*) = 1, max(u.pubkey), null) from users u \
+                where u.id <> d.id and left(lower(hex(u.pubkey)), 32) = lower(convert(d.pubkey using latin1))) \
+                as into_pubkey \
+            from users d \
+            where convert(d.pubkey using latin1) regexp '^[0-9a-fA-F]{32}$'",
+        )
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    /// Move everything owned by user `from` to user `into` and remove `from`, returns the
+    /// number of files moved.
+    ///
+    /// Rows referencing the user cascade on delete, so each table must be moved here first
+    async fn merge_user(
+        &self,
+        from: u64,
+        from_pubkey: &Vec<u8>,
+        into: u64,
+        into_pubkey: &Vec<u8>,
+    ) -> Result<u64, SqlError> {
+        let mut tx = self.pool.begin().await?;
+        // files both users own stay with `into`, the leftovers are removed below
+        let moved = tx
+            .execute(
+                sqlx::query("update ignore user_uploads set user_id = ? where user_id = ?")
+                    .bind(into)
+                    .bind(from),
+            )
+            .await?
+            .rows_affected();
+        tx.execute(sqlx::query("delete from user_uploads where user_id = ?").bind(from))
+            .await?;
+        // collection names are unique per user, collections named like one of `into` are
+        // merged into it, the others are moved
+        tx.execute(
+            sqlx::query(
+                "insert ignore into collection_files(collection,file,added) \
+                select i.id, f.file, f.added from collections c \
+                join collections i on i.user_id = ? and i.name = c.name \
+                join collection_files f on f.collection = c.id \
+                where c.user_id = ?",
+            )
+            .bind(into)
+            .bind(from),
+        )
+        .await?;
+        tx.execute(
+            sqlx::query(
+                "delete c from collections c \
+                join collections i on i.user_id = ? and i.name = c.name \
+                where c.user_id = ?",
+            )
+            .bind(into)
+            .bind(from),
+        )
+        .await?;
+        // versions both users accepted keep the acceptance of `into`
+        tx.execute(
+            sqlx::query("update ignore tos_acceptances set user_id = ? where user_id = ?")
+                .bind(into)
+                .bind(from),
+        )
+        .await?;
+        for q in [
+            "update api_keys set user_id = ? where user_id = ?",
+            "update mirror_batches set user_id = ? where user_id = ?",
+            "update collections set user_id = ? where user_id = ?",
+        ] {
+            tx.execute(sqlx::query(q).bind(into).bind(from)).await?;
+        }
+        for q in [
+            "update upload_failures set pubkey = ? where pubkey = ?",
+            "update ignore nostr_deletions set pubkey = ? where pubkey = ?",
+        ] {
+            tx.execute(sqlx::query(q).bind(into_pubkey).bind(from_pubkey))
+                .await?;
+        }
+        tx.execute(
+            sqlx::query("insert into user_merges(from_id,into_id,pubkey,files) values(?,?,?,?)")
+                .bind(from)
+                .bind(into)
+                .bind(into_pubkey)
+                .bind(moved),
+        )
+        .await?;
+        tx.execute(sqlx::query("delete from users where id = ?").bind(from))
+            .await?;
+        tx.commit().await?;
+        Ok(moved)
+    }
+}
+
+/// Merges user rows created by external tools which stored the pubkey as hex text
+pub struct MergeUsersHandler {
+    db: Database,
+}
+
+impl MergeUsersHandler {
+    pub fn new(db: Database) -> Self {
+        Self { db }
+    }
+}
+
+#[rocket::async_trait]
+impl JobHandler for MergeUsersHandler {
+    fn kind(&self) -> &'static str {
+        MERGE_USERS_JOB
+    }
+
+    fn max_attempts(&self) -> u32 {
+        1
+    }
+
+    async fn run(&self, job: &Job) -> Result<(), Error> {
+        let req: MergeUsersJob = job.payload()?;
+        let dupes = self.db.list_duplicate_users().await?;
+        info!("Found {} duplicate user rows", dupes.len());
+        for d in dupes {
+            let text = String::from_utf8_lossy(&d.pubkey).to_string();
+            let (into, into_pubkey) = match (d.into_id, d.into_pubkey) {
+                (Some(i), Some(p)) => (i, p),
+                _ => {
+                    warn!(
+                        "User {} ({}) has no unique user with the full pubkey",
+                        d.id, text
+                    );
+                    STATS.unresolved.fetch_add(1, Ordering::Relaxed);
+                    continue;
+                }
+            };
+            if req.dry_run {
+                info!(
+                    "Would merge user {} ({}) into {} ({})",
+                    d.id,
+                    text,
+                    into,
+                    hex::encode(&into_pubkey)
+                );
+                continue;
+            }
+            let moved = self
+                .db
+                .merge_user(d.id, &d.pubkey, into, &into_pubkey)
+                .await?;
+            STATS.merged.fetch_add(1, Ordering::Relaxed);
+            STATS.files_moved.fetch_add(moved, Ordering::Relaxed);
+            info!(
+                "Merged user {} ({}) into {} ({}), moved {} files",
+                d.id,
+                text,
+                into,
+                hex::encode(&into_pubkey),
+                moved
+            );
+        }
+        Ok(())
+    }
+}
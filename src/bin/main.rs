@@ -1,54 +1,178 @@
 use std::net::{IpAddr, SocketAddr};
 
-use anyhow::Error;
+use anyhow::{anyhow, bail, Error};
 use clap::Parser;
-use config::Config;
-use log::{error, info};
+use log::{error, info, warn};
 use rocket::config::Ident;
 use rocket::data::{ByteUnit, Limits};
-use rocket::routes;
-use rocket::shield::Shield;
-#[cfg(feature = "analytics")]
-use route96::analytics::plausible::PlausibleAnalytics;
-#[cfg(feature = "analytics")]
-use route96::analytics::AnalyticsFairing;
-use route96::cors::CORS;
+use route96::app::build_rocket;
+use route96::background::announce::Announcer;
+use route96::background::backup::{BackupState, BackupTask};
+use route96::background::bot::UploadBot;
+use route96::background::bulk::BulkHandler;
+use route96::background::consistency::{ConsistencyScan, RepairHandler};
+use route96::background::deletions::DeletionWatcher;
+use route96::background::disk::{DiskState, DiskWatchdog};
+use route96::background::mirror::MirrorHandler;
+use route96::background::profiles::ProfileFetcher;
+#[cfg(feature = "media-compression")]
+use route96::background::rendition::RenditionHandler;
+use route96::background::replication::PeerDeleteHandler;
+use route96::background::report::ReportTask;
+#[cfg(feature = "media-compression")]
+use route96::background::reprocess::{MetadataBackfill, ReprocessHandler};
+use route96::background::retention::RetentionCleaner;
+use route96::background::scrub::{ScrubState, Scrubber};
+use route96::background::sources::SourceChecker;
+#[cfg(feature = "torrent-v2")]
+use route96::background::torrent::TorrentHandler;
+use route96::background::users::MergeUsersHandler;
+use route96::background::{JobCancels, JobRunner};
 use route96::db::Database;
-use route96::filesystem::FileStore;
-use route96::routes;
-use route96::routes::{get_blob, head_blob, root};
 use route96::settings::Settings;
-use route96::webhook::Webhook;
+use route96::validate::validate_settings;
+
+/// Default limit of JSON request bodies
+const DEFAULT_JSON_LIMIT: u64 = 256 * 1024;
 
 #[derive(Parser, Debug)]
 #[command(version, about)]
 struct Args {
     #[arg(long)]
     pub config: Option<String>,
+
+    /// Validate the config (and database connection) then exit
+    #[arg(long, default_value_t = false)]
+    pub check_config: bool,
 }
 
 #[rocket::main]
 async fn main() -> Result<(), Error> {
-    pretty_env_logger::init();
-
     let args: Args = Args::parse();
 
-    let builder = Config::builder()
-        .add_source(config::File::with_name(if let Some(ref c) = args.config {
-            c.as_str()
+    let config_path = args.config.as_deref().unwrap_or("config.yaml");
+    let loaded = Settings::load(config_path)
+        .map_err(|e| anyhow!("Failed to load config {}: {}", config_path, e))?;
+    let settings = loaded.settings;
+    route96::logging::init(settings.logging.as_ref())?;
+    match (&loaded.env, &loaded.overlay) {
+        (Some(env), Some((o, true))) => {
+            info!(
+                "Loaded config {} with {} overlay {}",
+                config_path,
+                env,
+                o.display()
+            )
+        }
+        (Some(env), Some((o, false))) => warn!(
+            "Loaded config {}, no overlay {} for {}",
+            config_path,
+            o.display(),
+            env
+        ),
+        _ => info!("Loaded config {}", config_path),
+    }
+    info!("Effective config: {}", settings.redacted());
+
+    let issues = validate_settings(&settings);
+    for i in &issues {
+        if i.fatal {
+            error!("{}", i);
         } else {
-            "config.yaml"
-        }))
-        .add_source(config::Environment::with_prefix("APP"))
-        .build()?;
+            warn!("{}", i);
+        }
+    }
+    let fatal = issues.iter().filter(|i| i.fatal).count();
+    if fatal > 0 {
+        bail!("{} problem(s) found in config {}", fatal, config_path);
+    }
+
+    let db = Database::new(&settings.database)
+        .await
+        .map_err(|e| anyhow!("Failed to connect to database: {}", e))?;
 
-    let settings: Settings = builder.try_deserialize()?;
+    if args.check_config {
+        info!("Config {} is valid", config_path);
+        return Ok(());
+    }
 
-    let db = Database::new(&settings.database).await?;
+    route96::capabilities::init(&settings);
 
     info!("Running DB migration");
     db.migrate().await?;
 
+    #[cfg(feature = "media-compression")]
+    route96::processing::hwaccel::init(settings.hwaccel.as_ref());
+    #[cfg(feature = "encryption")]
+    route96::encryption::init(settings.encryption.as_ref())?;
+    route96::capabilities::log();
+
+    let disk_state = DiskState::default();
+    let job_cancels = JobCancels::default();
+    let mut jobs = JobRunner::new(db.clone(), job_cancels.clone());
+    jobs.register(BulkHandler::new(db.clone(), settings.clone()));
+    jobs.register(RepairHandler::new(db.clone(), settings.clone()));
+    jobs.register(MergeUsersHandler::new(db.clone()));
+    jobs.register(MirrorHandler::new(
+        db.clone(),
+        settings.clone(),
+        disk_state.clone(),
+    ));
+    if let Some(h) = PeerDeleteHandler::new(&settings)? {
+        jobs.register(h);
+    }
+    #[cfg(feature = "media-compression")]
+    jobs.register(ReprocessHandler::new(db.clone(), settings.clone()));
+    #[cfg(feature = "media-compression")]
+    jobs.register(RenditionHandler::new(db.clone(), settings.clone()));
+    #[cfg(feature = "torrent-v2")]
+    jobs.register(TorrentHandler::new(db.clone(), settings.clone()));
+    jobs.start();
+
+    let scrub_state = ScrubState::default();
+    if settings.scrub.is_some() {
+        Scrubber::new(db.clone(), settings.clone(), scrub_state.clone()).start();
+    }
+    if settings.retention.is_some() {
+        RetentionCleaner::new(db.clone(), settings.clone()).start();
+    }
+    if settings.source_check.is_some() {
+        SourceChecker::new(db.clone(), settings.clone()).start();
+    }
+    if let Some(a) = Announcer::new(settings.clone())? {
+        a.start();
+    }
+    if settings.profiles.is_some() {
+        ProfileFetcher::new(db.clone(), settings.clone()).start();
+    }
+    if settings.deletions.is_some() {
+        DeletionWatcher::new(db.clone(), settings.clone()).start();
+    }
+    if settings.startup_scan.is_some() {
+        ConsistencyScan::new(db.clone(), settings.clone()).start();
+    }
+    #[cfg(feature = "grpc")]
+    if let Some(cfg) = &settings.grpc {
+        route96::grpc::AdminService::new(db.clone(), settings.clone()).start(cfg.clone());
+    }
+    if settings.disk.is_some() {
+        DiskWatchdog::new(settings.clone(), disk_state.clone()).start();
+    }
+    if let Some(b) = UploadBot::new(db.clone(), settings.clone(), disk_state.clone())? {
+        b.start();
+    }
+    if settings.reports.is_some() {
+        ReportTask::new(db.clone(), settings.clone()).start();
+    }
+    #[cfg(feature = "media-compression")]
+    if settings.metadata_backfill.is_some() {
+        MetadataBackfill::new(db.clone(), settings.clone()).start();
+    }
+    let backup_state = BackupState::default();
+    if settings.backup.is_some() {
+        BackupTask::new(db.clone(), settings.clone(), backup_state.clone()).start();
+    }
+
     let mut config = rocket::Config::default();
     let ip: SocketAddr = match &settings.listen {
         Some(i) => i.parse()?,
@@ -58,44 +182,25 @@ async fn main() -> Result<(), Error> {
     config.port = ip.port();
 
     let upload_limit = ByteUnit::from(settings.max_upload_bytes);
+    // JSON bodies are buffered before they are parsed, keep them small
+    let json_limit = ByteUnit::from(settings.max_json_bytes.unwrap_or(DEFAULT_JSON_LIMIT));
     config.limits = Limits::new()
         .limit("file", upload_limit)
         .limit("data-form", upload_limit)
-        .limit("form", upload_limit);
+        .limit("form", upload_limit)
+        .limit("json", json_limit)
+        .limit("bytes", json_limit);
     config.ident = Ident::try_new("route96").unwrap();
 
-    let mut rocket = rocket::Rocket::custom(config)
-        .manage(FileStore::new(settings.clone()))
-        .manage(settings.clone())
-        .manage(db.clone())
-        .manage(
-            settings
-                .webhook_url
-                .as_ref()
-                .map(|w| Webhook::new(w.clone())),
-        )
-        .attach(CORS)
-        .attach(Shield::new()) // disable
-        .mount(
-            "/",
-            routes![root, get_blob, head_blob, routes::void_cat_redirect],
-        )
-        .mount("/admin", routes::admin_routes());
-
-    #[cfg(feature = "analytics")]
-    {
-        if settings.plausible_url.is_some() {
-            rocket = rocket.attach(AnalyticsFairing::new(PlausibleAnalytics::new(&settings)))
-        }
-    }
-    #[cfg(feature = "blossom")]
-    {
-        rocket = rocket.mount("/", routes::blossom_routes());
-    }
-    #[cfg(feature = "nip96")]
-    {
-        rocket = rocket.mount("/", routes::nip96_routes());
-    }
+    let rocket = build_rocket(
+        config,
+        settings,
+        db,
+        scrub_state,
+        disk_state,
+        backup_state,
+        job_cancels,
+    );
     if let Err(e) = rocket.launch().await {
         error!("Rocker error {}", e);
         Err(Error::from(e))
@@ -0,0 +1,413 @@
+use std::borrow::Cow;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Error};
+use base64::prelude::*;
+use clap::Parser;
+use log::{info, warn};
+use nostr::{
+    Alphabet, EventBuilder, JsonUtil, Keys, Kind, SingleLetterTag, Tag, TagKind, Timestamp,
+};
+use rocket::futures::{stream, StreamExt};
+use sha2::{Digest, Sha256};
+
+/// Seconds the upload auth events are valid for
+const AUTH_EXPIRY: u64 = 300;
+
+/// Generates synthetic uploads and downloads against a running instance and reports
+/// throughput and latency percentiles per operation
+#[derive(Parser, Debug)]
+#[command(version, about)]
+struct Args {
+    /// Base url of the instance (eg. http://localhost:8000)
+    #[arg(long)]
+    pub url: String,
+
+    /// Secret key used to sign uploads (nsec or hex), a new key is generated when not set
+    #[arg(long)]
+    pub key: Option<String>,
+
+    /// Number of concurrent workers
+    #[arg(long, default_value_t = 8)]
+    pub concurrency: usize,
+
+    /// Seconds to run for
+    #[arg(long, default_value_t = 30)]
+    pub duration: u64,
+
+    /// Stop after this many operations, even when the duration has not passed
+    #[arg(long)]
+    pub requests: Option<u64>,
+
+    /// Upload sizes in bytes, picked at random for each upload
+    #[arg(long, value_delimiter = ',', default_values_t = [64 * 1024, 1024 * 1024, 8 * 1024 * 1024])]
+    pub sizes: Vec<usize>,
+
+    /// Relative weight of Blossom uploads (PUT /upload)
+    #[arg(long, default_value_t = 1)]
+    pub blossom: u32,
+
+    /// Relative weight of NIP-96 uploads (POST /n96)
+    #[arg(long, default_value_t = 1)]
+    pub nip96: u32,
+
+    /// Relative weight of downloads of previously uploaded blobs (GET /<sha256>)
+    #[arg(long, default_value_t = 2)]
+    pub download: u32,
+
+    /// Delete the uploaded blobs when finished
+    #[arg(long, default_value_t = false)]
+    pub cleanup: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    BlossomUpload,
+    Nip96Upload,
+    Download,
+}
+
+impl Op {
+    const ALL: [Op; 3] = [Op::BlossomUpload, Op::Nip96Upload, Op::Download];
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            Op::BlossomUpload => "blossom upload",
+            Op::Nip96Upload => "nip96 upload",
+            Op::Download => "download",
+        }
+    }
+}
+
+#[derive(Default)]
+struct OpResult {
+    bytes: u64,
+    failed: u32,
+    latencies: Vec<Duration>,
+}
+
+struct Bench {
+    client: reqwest::Client,
+    base: String,
+    keys: Keys,
+    sizes: Vec<usize>,
+    weights: [u32; 3],
+    deadline: Instant,
+    /// Operations left when --requests is set
+    remaining: Option<Mutex<u64>>,
+    /// Hashes of the completed uploads, used for downloads and cleanup
+    uploaded: Mutex<Vec<String>>,
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    pretty_env_logger::init();
+
+    let args: Args = Args::parse();
+    if args.sizes.is_empty() || args.sizes.contains(&0) {
+        bail!("--sizes must be a list of non-zero sizes");
+    }
+    if args.blossom + args.nip96 == 0 {
+        bail!("At least one of --blossom or --nip96 must have a weight");
+    }
+    let keys = match &args.key {
+        Some(k) => Keys::parse(k)?,
+        None => Keys::generate(),
+    };
+    info!(
+        "Running {} workers for {}s against {} as {}",
+        args.concurrency,
+        args.duration,
+        args.url,
+        keys.public_key().to_hex()
+    );
+
+    let concurrency = args.concurrency.max(1);
+    let bench = Bench {
+        client: reqwest::ClientBuilder::new()
+            .timeout(Duration::from_secs(120))
+            .build()?,
+        base: args.url.trim_end_matches('/').to_string(),
+        keys,
+        sizes: args.sizes,
+        weights: [args.blossom, args.nip96, args.download],
+        deadline: Instant::now() + Duration::from_secs(args.duration),
+        remaining: args.requests.map(Mutex::new),
+        uploaded: Mutex::new(Vec::new()),
+    };
+    let started = Instant::now();
+    let results = stream::iter(0..concurrency)
+        .map(|n| bench.worker(n as u64))
+        .buffer_unordered(concurrency)
+        .collect::<Vec<_>>()
+        .await;
+    let elapsed = started.elapsed().as_secs_f64();
+
+    for op in Op::ALL {
+        let mut res = OpResult::default();
+        for r in &results {
+            let r = &r[op as usize];
+            res.bytes += r.bytes;
+            res.failed += r.failed;
+            res.latencies.extend(&r.latencies);
+        }
+        if res.latencies.is_empty() && res.failed == 0 {
+            continue;
+        }
+        res.latencies.sort();
+        let pct = |p: usize| {
+            res.latencies
+                .get((res.latencies.len() * p / 100).min(res.latencies.len().saturating_sub(1)))
+                .map(|d| d.as_millis())
+                .unwrap_or(0)
+        };
+        info!(
+            "{}: {} requests ({} failed), {:.1} req/s, {:.1} MiB/s, latency p50 {}ms p90 {}ms p99 {}ms",
+            op.as_str(),
+            res.latencies.len(),
+            res.failed,
+            res.latencies.len() as f64 / elapsed,
+            res.bytes as f64 / elapsed / 1048576.0,
+            pct(50),
+            pct(90),
+            pct(99)
+        );
+    }
+
+    if args.cleanup {
+        let bench = &bench;
+        let hashes = std::mem::take(&mut *bench.uploaded.lock().unwrap());
+        info!("Deleting {} uploads", hashes.len());
+        let failed = stream::iter(hashes)
+            .map(|h| async move { bench.delete(&h).await })
+            .buffer_unordered(concurrency)
+            .filter(|r| std::future::ready(r.is_err()))
+            .count()
+            .await;
+        if failed > 0 {
+            warn!("{} deletes failed", failed);
+        }
+    }
+    Ok(())
+}
+
+impl Bench {
+    /// Run random operations until the deadline or the request count is reached
+    async fn worker(&self, seed: u64) -> [OpResult; 3] {
+        let mut res: [OpResult; 3] = Default::default();
+        // xorshift, the data only needs to differ between uploads
+        let mut state = (seed + 1).wrapping_mul(0x9E3779B97F4A7C15) ^ Timestamp::now().as_u64();
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        while Instant::now() < self.deadline && self.take_request() {
+            let mut op = self.pick(next());
+            let hash = match op {
+                Op::Download => self.random_upload(next()),
+                _ => None,
+            };
+            // nothing to download yet
+            if op == Op::Download && hash.is_none() {
+                op = if self.weights[Op::BlossomUpload as usize] > 0 {
+                    Op::BlossomUpload
+                } else {
+                    Op::Nip96Upload
+                };
+            }
+            let t = Instant::now();
+            let r = match (op, hash) {
+                (Op::Download, Some(h)) => self.download(&h).await,
+                _ => {
+                    let size = self.sizes[(next() % self.sizes.len() as u64) as usize];
+                    let data = random_data(size, next());
+                    self.upload(op, data).await
+                }
+            };
+            let out = &mut res[op as usize];
+            match r {
+                Ok(bytes) => {
+                    out.bytes += bytes;
+                    out.latencies.push(t.elapsed());
+                }
+                Err(e) => {
+                    warn!("{} failed: {}", op.as_str(), e);
+                    out.failed += 1;
+                }
+            }
+        }
+        res
+    }
+
+    fn take_request(&self) -> bool {
+        match &self.remaining {
+            Some(r) => {
+                let mut r = r.lock().unwrap();
+                if *r == 0 {
+                    return false;
+                }
+                *r -= 1;
+                true
+            }
+            None => true,
+        }
+    }
+
+    fn pick(&self, n: u64) -> Op {
+        let total: u32 = self.weights.iter().sum();
+        let mut n = (n % total.max(1) as u64) as u32;
+        for op in Op::ALL {
+            let w = self.weights[op as usize];
+            if n < w {
+                return op;
+            }
+            n -= w;
+        }
+        Op::BlossomUpload
+    }
+
+    fn random_upload(&self, n: u64) -> Option<String> {
+        let uploaded = self.uploaded.lock().unwrap();
+        if uploaded.is_empty() {
+            None
+        } else {
+            Some(uploaded[(n % uploaded.len() as u64) as usize].clone())
+        }
+    }
+
+    /// Upload a blob, returns the number of bytes sent
+    async fn upload(&self, op: Op, data: Vec<u8>) -> Result<u64, Error> {
+        let hash = hex::encode(Sha256::digest(&data));
+        let len = data.len() as u64;
+        let rsp = if op == Op::Nip96Upload {
+            let url = format!("{}/n96", self.base);
+            let boundary = format!("r96bench{}", &hash[..16]);
+            let mut body = Vec::with_capacity(data.len() + 512);
+            for (name, value) in [("size", len.to_string()), ("no_transform", "true".into())] {
+                body.extend(
+                    format!(
+                        "--{}\r\ncontent-disposition: form-data; name=\"{}\"\r\n\r\n{}\r\n",
+                        boundary, name, value
+                    )
+                    .as_bytes(),
+                );
+            }
+            body.extend(
+                format!(
+                    "--{}\r\ncontent-disposition: form-data; name=\"file\"; filename=\"{}.bin\"\r\n\
+                    content-type: application/octet-stream\r\n\r\n",
+                    boundary, hash
+                )
+                .as_bytes(),
+            );
+            body.extend(data);
+            body.extend(format!("\r\n--{}--\r\n", boundary).as_bytes());
+            let auth = EventBuilder::new(Kind::HttpAuth, "")
+                .tags([
+                    Tag::custom(
+                        TagKind::SingleLetter(SingleLetterTag::lowercase(Alphabet::U)),
+                        [url.as_str()],
+                    ),
+                    Tag::custom(TagKind::Custom(Cow::Borrowed("method")), ["POST"]),
+                ])
+                .sign_with_keys(&self.keys)?;
+            self.client
+                .post(&url)
+                .header(
+                    "content-type",
+                    format!("multipart/form-data; boundary={}", boundary),
+                )
+                .header("authorization", nostr_auth(&auth.as_json()))
+                .body(body)
+                .send()
+                .await?
+        } else {
+            let auth = self.blossom_auth("upload", &hash)?;
+            self.client
+                .put(format!("{}/upload", self.base))
+                .header("content-type", "application/octet-stream")
+                .header("x-sha-256", &hash)
+                .header("authorization", auth)
+                .body(data)
+                .send()
+                .await?
+        };
+        if !rsp.status().is_success() {
+            bail!(
+                "{} {}",
+                rsp.status(),
+                rsp.headers()
+                    .get("x-reason")
+                    .and_then(|r| r.to_str().ok())
+                    .unwrap_or("")
+            );
+        }
+        self.uploaded.lock().unwrap().push(hash);
+        Ok(len)
+    }
+
+    /// Download a blob, returns the number of bytes read
+    async fn download(&self, hash: &str) -> Result<u64, Error> {
+        let rsp = self
+            .client
+            .get(format!("{}/{}", self.base, hash))
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(rsp.bytes().await?.len() as u64)
+    }
+
+    async fn delete(&self, hash: &str) -> Result<(), Error> {
+        let auth = self.blossom_auth("delete", hash)?;
+        self.client
+            .delete(format!("{}/{}", self.base, hash))
+            .header("authorization", auth)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    /// Blossom authorization header for an action on a blob
+    fn blossom_auth(&self, action: &str, hash: &str) -> Result<String, Error> {
+        let expiration = Timestamp::now().as_u64() + AUTH_EXPIRY;
+        let auth = EventBuilder::new(Kind::Custom(24242), action)
+            .tags([
+                Tag::custom(
+                    TagKind::SingleLetter(SingleLetterTag::lowercase(Alphabet::T)),
+                    [action],
+                ),
+                Tag::custom(
+                    TagKind::SingleLetter(SingleLetterTag::lowercase(Alphabet::X)),
+                    [hash],
+                ),
+                Tag::custom(
+                    TagKind::Custom(Cow::Borrowed("expiration")),
+                    [expiration.to_string()],
+                ),
+            ])
+            .sign_with_keys(&self.keys)?;
+        Ok(nostr_auth(&auth.as_json()))
+    }
+}
+
+fn nostr_auth(event: &str) -> String {
+    format!("Nostr {}", BASE64_STANDARD.encode(event))
+}
+
+/// Pseudo-random bytes, so every upload is a new blob instead of a dedup hit
+fn random_data(size: usize, seed: u64) -> Vec<u8> {
+    let mut state = seed | 1;
+    let mut data = Vec::with_capacity(size + 8);
+    while data.len() < size {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        data.extend_from_slice(&state.to_le_bytes());
+    }
+    data.truncate(size);
+    data
+}
@@ -0,0 +1,815 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Error};
+use base64::prelude::*;
+use chrono::DateTime;
+use clap::{Parser, Subcommand};
+use log::{info, warn};
+use nostr::{
+    serde_json, Alphabet, EventBuilder, JsonUtil, Keys, Kind, PublicKey, SingleLetterTag, Tag,
+    TagKind,
+};
+use reqwest::Url;
+use rocket::futures::{stream, StreamExt};
+use route96::db::Database;
+use route96::filesystem::FileStore;
+use route96::metadata::sanitize_license;
+use route96::mirror::response_reader;
+use route96::settings::Settings;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use tokio::io::AsyncSeekExt;
+
+/// Number of files requested per page from the NIP-96 list API
+const LIST_PAGE_SIZE: u32 = 100;
+
+#[derive(Parser, Debug)]
+#[command(version, about)]
+struct Args {
+    #[arg(long)]
+    pub config: Option<String>,
+
+    #[clap(subcommand)]
+    pub command: Commands,
+}
+
+#[derive(Debug, Subcommand)]
+enum Commands {
+    /// Import a directory of files into the database
+    DatabaseImport {
+        /// Directory containing the files to import
+        #[arg(long)]
+        data_path: PathBuf,
+
+        /// CSV (sha256,pubkey per line) or JSON ({"sha256": "pubkey"}) ownership mapping
+        #[arg(long)]
+        mapping: Option<PathBuf>,
+
+        /// Use the first directory below data_path as the owner pubkey (<data_path>/<pubkey>/file)
+        #[arg(long, default_value_t = false)]
+        owner_from_dir: bool,
+    },
+
+    /// Import a user's files from another NIP-96 server
+    Nip96Import {
+        /// Base url of the NIP-96 server, files are listed using its list API
+        #[arg(long)]
+        server: Option<String>,
+
+        /// Secret key (nsec or hex) of the user, used for NIP-98 auth on the list API
+        #[arg(long)]
+        key: Option<String>,
+
+        /// CSV (url[,alt] per line) of files to import instead of using the list API
+        #[arg(long)]
+        urls: Option<PathBuf>,
+
+        /// Owner of the imported files, defaults to the pubkey of --key
+        #[arg(long)]
+        owner: Option<String>,
+
+        /// Output CSV mapping (old url,new url per line)
+        #[arg(long, default_value = "mapping.csv")]
+        output: PathBuf,
+    },
+
+    /// Move stored files between storage roots
+    MoveStorage {
+        /// Storage root to move files from, defaults to storage_dir
+        #[arg(long)]
+        from: Option<String>,
+
+        /// Storage root to move files to ("default" for storage_dir), when omitted
+        /// each file is moved to the root picked by the storage_roots rules
+        #[arg(long)]
+        to: Option<String>,
+
+        /// Only log the files which would be moved
+        #[arg(long, default_value_t = false)]
+        dry_run: bool,
+    },
+
+    /// Check which local files already exist on a remote Blossom server
+    DiffRemote {
+        /// Base url of the Blossom server
+        #[arg(long)]
+        url: String,
+
+        /// Only check the files of this pubkey (npub or hex)
+        #[arg(long)]
+        owner: Option<String>,
+
+        /// Number of HEAD requests in flight
+        #[arg(long, default_value_t = 16)]
+        concurrency: usize,
+
+        /// Output CSV upload plan (sha256,size,mime_type per line) of the files missing remotely
+        #[arg(long, default_value = "upload-plan.csv")]
+        output: PathBuf,
+    },
+
+    /// Measure range request throughput of a blob, like video players seeking in parallel
+    BenchRanges {
+        /// Url of a large blob (eg. a 4K video)
+        #[arg(long)]
+        url: String,
+
+        /// Number of concurrent streams
+        #[arg(long, default_value_t = 8)]
+        streams: usize,
+
+        /// Bytes requested per range request
+        #[arg(long, default_value_t = 4 * 1024 * 1024)]
+        range_size: u64,
+
+        /// Seconds to run for
+        #[arg(long, default_value_t = 30)]
+        duration: u64,
+    },
+
+    /// Re-wrap the keys of encrypted files with the current master key
+    #[cfg(feature = "encryption")]
+    RotateKey {
+        /// Also encrypt files which were stored before encryption was enabled
+        #[arg(long, default_value_t = false)]
+        encrypt_plain: bool,
+
+        /// Only count the files which would be changed
+        #[arg(long, default_value_t = false)]
+        dry_run: bool,
+    },
+}
+
+/// Root name used on the command line for storage_dir
+const DEFAULT_STORAGE: &str = "default";
+
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    pretty_env_logger::init();
+
+    let args: Args = Args::parse();
+
+    let settings = Settings::load(args.config.as_deref().unwrap_or("config.yaml"))?.settings;
+
+    let db = Database::new(&settings.database).await?;
+    db.migrate().await?;
+    #[cfg(feature = "encryption")]
+    route96::encryption::init(settings.encryption.as_ref())?;
+    let fs = FileStore::new(settings.clone());
+
+    match args.command {
+        Commands::DatabaseImport {
+            data_path,
+            mapping,
+            owner_from_dir,
+        } => {
+            let mapping = match mapping {
+                Some(m) => load_mapping(&m)?,
+                None => HashMap::new(),
+            };
+            let mut files = Vec::new();
+            list_files(&data_path, &mut files)?;
+            info!("Importing {} files", files.len());
+            let (mut imported, mut skipped) = (0, 0);
+            for path in files {
+                let dir_owner = if owner_from_dir {
+                    dir_owner(&data_path, &path)
+                } else {
+                    None
+                };
+                match import_file(&path, dir_owner, &mapping, &db, &fs).await {
+                    Ok(true) => imported += 1,
+                    Ok(false) => skipped += 1,
+                    Err(e) => {
+                        warn!("Failed to import {}: {}", path.display(), e);
+                        skipped += 1;
+                    }
+                }
+            }
+            info!("Imported {} files, skipped {}", imported, skipped);
+        }
+        Commands::Nip96Import {
+            server,
+            key,
+            urls,
+            owner,
+            output,
+        } => {
+            let keys = key.map(|k| Keys::parse(&k)).transpose()?;
+            let owner = match (owner, &keys) {
+                (Some(o), _) => parse_pubkey(&o)?,
+                (None, Some(k)) => k.public_key().to_bytes().to_vec(),
+                (None, None) => bail!("Either --owner or --key is required"),
+            };
+            let files = match (urls, server, &keys) {
+                (Some(u), _, _) => load_url_list(&u)?,
+                (None, Some(s), Some(k)) => list_remote_files(&s, k).await?,
+                _ => bail!("Either --urls or --server and --key are required"),
+            };
+            info!("Importing {} remote files", files.len());
+            let user_id = db.upsert_user(&owner).await?;
+            let mut mapping = String::new();
+            let (mut imported, mut skipped) = (0, 0);
+            for f in files {
+                match import_remote_file(&f, user_id, &db, &fs, &settings).await {
+                    Ok(new_url) => {
+                        info!("Imported {} => {}", f.url, new_url);
+                        mapping.push_str(&format!("{},{}\n", f.url, new_url));
+                        imported += 1;
+                    }
+                    Err(e) => {
+                        warn!("Failed to import {}: {}", f.url, e);
+                        skipped += 1;
+                    }
+                }
+            }
+            std::fs::write(&output, mapping)?;
+            info!(
+                "Imported {} files, skipped {}, mapping written to {}",
+                imported,
+                skipped,
+                output.display()
+            );
+        }
+        Commands::MoveStorage { from, to, dry_run } => {
+            let from = from.filter(|f| f != DEFAULT_STORAGE);
+            // list everything first, moved files drop out of the listing
+            let mut files = Vec::new();
+            loop {
+                let page = db
+                    .list_files_in_storage(from.as_deref(), files.len() as u32, LIST_PAGE_SIZE)
+                    .await?;
+                let n = page.len();
+                files.extend(page);
+                if n < LIST_PAGE_SIZE as usize {
+                    break;
+                }
+            }
+            info!("Checking {} files", files.len());
+            let (mut moved, mut failed) = (0, 0);
+            for f in files {
+                let target = match to.as_deref() {
+                    Some(DEFAULT_STORAGE) => None,
+                    Some(t) => Some(t.to_string()),
+                    None => fs.route(&f.mime_type, f.size).map(|r| r.name.clone()),
+                };
+                if target == f.storage {
+                    continue;
+                }
+                let id = hex::encode(&f.id);
+                let name = target.as_deref().unwrap_or(DEFAULT_STORAGE);
+                if dry_run {
+                    info!("Would move {} to {}", id, name);
+                    continue;
+                }
+                match fs.move_file(&f.id, target.as_deref()) {
+                    Ok(_) => {
+                        db.set_file_storage(&f.id, target.as_deref()).await?;
+                        info!("Moved {} to {}", id, name);
+                        moved += 1;
+                    }
+                    Err(e) => {
+                        warn!("Failed to move {}: {}", id, e);
+                        failed += 1;
+                    }
+                }
+            }
+            info!("Moved {} files, {} failed", moved, failed);
+        }
+        Commands::DiffRemote {
+            url,
+            owner,
+            concurrency,
+            output,
+        } => {
+            let owner = owner.map(|o| parse_pubkey(&o)).transpose()?;
+            let mut files = Vec::new();
+            loop {
+                let offset = files.len() as u32;
+                let (page, _) = match &owner {
+                    Some(o) => db.list_files(o, None, offset, LIST_PAGE_SIZE).await?,
+                    None => db.list_all_files(None, &[], offset, LIST_PAGE_SIZE).await?,
+                };
+                let n = page.len();
+                files.extend(page);
+                if n < LIST_PAGE_SIZE as usize {
+                    break;
+                }
+            }
+            info!("Checking {} files against {}", files.len(), url);
+            let client = reqwest::ClientBuilder::new()
+                .timeout(Duration::from_secs(30))
+                .build()?;
+            let base = url.trim_end_matches('/');
+            let checks = stream::iter(files)
+                .map(|f| {
+                    let client = &client;
+                    async move {
+                        let url = format!("{}/{}", base, hex::encode(&f.id));
+                        let res = client.head(&url).send().await.map(|r| r.status());
+                        (f, res)
+                    }
+                })
+                .buffer_unordered(concurrency.max(1));
+            tokio::pin!(checks);
+
+            let mut plan = String::new();
+            let (mut present, mut missing, mut failed, mut missing_bytes) = (0, 0, 0, 0u64);
+            while let Some((f, res)) = checks.next().await {
+                let id = hex::encode(&f.id);
+                match res {
+                    Ok(s) if s.is_success() => present += 1,
+                    Ok(s) if s == reqwest::StatusCode::NOT_FOUND => {
+                        plan.push_str(&format!("{},{},{}\n", id, f.size, f.mime_type));
+                        missing += 1;
+                        missing_bytes += f.size;
+                    }
+                    Ok(s) => {
+                        warn!("Unexpected status {} for {}", s, id);
+                        failed += 1;
+                    }
+                    Err(e) => {
+                        warn!("Failed to check {}: {}", id, e);
+                        failed += 1;
+                    }
+                }
+            }
+            std::fs::write(&output, plan)?;
+            info!(
+                "{} present, {} missing ({} bytes), {} failed, upload plan written to {}",
+                present,
+                missing,
+                missing_bytes,
+                failed,
+                output.display()
+            );
+        }
+        Commands::BenchRanges {
+            url,
+            streams,
+            range_size,
+            duration,
+        } => {
+            let client = reqwest::ClientBuilder::new()
+                .timeout(Duration::from_secs(60))
+                .build()?;
+            let size = client
+                .head(&url)
+                .send()
+                .await?
+                .error_for_status()?
+                .content_length()
+                .unwrap_or(0);
+            if size <= range_size {
+                bail!("{} is smaller than one range ({} bytes)", url, size);
+            }
+            info!(
+                "Reading {} ({} bytes) with {} streams for {}s",
+                url, size, streams, duration
+            );
+            let started = Instant::now();
+            let deadline = started + Duration::from_secs(duration);
+            let results = stream::iter(0..streams.max(1))
+                .map(|n| bench_stream(&client, &url, size, range_size, n as u64, deadline))
+                .buffer_unordered(streams.max(1))
+                .collect::<Vec<_>>()
+                .await;
+            let elapsed = started.elapsed().as_secs_f64();
+
+            let (mut bytes, mut failed, mut latencies) = (0u64, 0, Vec::new());
+            for r in results {
+                bytes += r.bytes;
+                failed += r.failed;
+                latencies.extend(r.latencies);
+            }
+            latencies.sort();
+            let pct = |p: usize| {
+                latencies
+                    .get((latencies.len() * p / 100).min(latencies.len().saturating_sub(1)))
+                    .map(|d| d.as_millis())
+                    .unwrap_or(0)
+            };
+            info!(
+                "{} requests ({} failed), {:.1} MiB/s, {:.1} MiB/s per stream, latency p50 {}ms p99 {}ms",
+                latencies.len(),
+                failed,
+                bytes as f64 / elapsed / 1048576.0,
+                bytes as f64 / elapsed / 1048576.0 / streams.max(1) as f64,
+                pct(50),
+                pct(99)
+            );
+        }
+        #[cfg(feature = "encryption")]
+        Commands::RotateKey {
+            encrypt_plain,
+            dry_run,
+        } => {
+            let ring = match route96::encryption::keyring() {
+                Some(r) => r,
+                None => bail!("Encryption is not configured"),
+            };
+            let mut files = Vec::new();
+            let roots = std::iter::once(PathBuf::from(&settings.storage_dir)).chain(
+                settings
+                    .storage_roots
+                    .iter()
+                    .flatten()
+                    .map(|r| r.path.clone()),
+            );
+            for root in roots {
+                if root.exists() {
+                    list_files(&root, &mut files)?;
+                }
+            }
+            // only stored blobs, named by their sha256
+            files.retain(|p| {
+                p.file_name()
+                    .and_then(|n| n.to_str())
+                    .is_some_and(|n| n.len() == 64 && hex::decode(n).is_ok())
+            });
+            info!("Checking {} files", files.len());
+            let (mut rewrapped, mut encrypted, mut failed) = (0, 0, 0);
+            for path in files {
+                let res = match ring.is_encrypted(&path) {
+                    Ok(true) if dry_run => Ok(true),
+                    Ok(true) => match ring.rewrap_file(&path) {
+                        // already uses the current key
+                        Ok(false) => continue,
+                        r => r,
+                    },
+                    Ok(false) if !encrypt_plain => continue,
+                    Ok(false) if dry_run => Ok(false),
+                    Ok(false) => encrypt_in_place(ring, &path).map(|_| false),
+                    Err(e) => Err(e.into()),
+                };
+                match res {
+                    Ok(true) => rewrapped += 1,
+                    Ok(false) => encrypted += 1,
+                    Err(e) => {
+                        warn!("Failed to update {}: {}", path.display(), e);
+                        failed += 1;
+                    }
+                }
+            }
+            info!(
+                "{}Re-wrapped {} files, encrypted {} files, {} failed",
+                if dry_run { "(dry run) " } else { "" },
+                rewrapped,
+                encrypted,
+                failed
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Replace a plaintext stored file with an encrypted copy
+#[cfg(feature = "encryption")]
+fn encrypt_in_place(ring: &route96::encryption::Keyring, path: &Path) -> Result<(), Error> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".encrypt");
+    let tmp = PathBuf::from(tmp);
+    ring.encrypt_file(path, &tmp)?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+fn list_files(dir: &Path, out: &mut Vec<PathBuf>) -> Result<(), Error> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            list_files(&path, out)?;
+        } else if path.is_file() {
+            out.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Parse a pubkey in hex or npub format
+fn parse_pubkey(s: &str) -> Result<Vec<u8>, Error> {
+    Ok(PublicKey::parse(s.trim())?.to_bytes().to_vec())
+}
+
+/// Load a sha256 => pubkey mapping file
+fn load_mapping(path: &Path) -> Result<HashMap<String, Vec<u8>>, Error> {
+    let data = std::fs::read_to_string(path)?;
+    let entries: Vec<(String, String)> = if path.extension().is_some_and(|e| e == "json") {
+        serde_json::from_str::<HashMap<String, String>>(&data)?
+            .into_iter()
+            .collect()
+    } else {
+        data.lines()
+            .map(|l| l.trim())
+            .filter(|l| !l.is_empty() && !l.starts_with('#'))
+            .map(|l| match l.split_once(',') {
+                Some((h, p)) => Ok((h.trim().to_string(), p.trim().to_string())),
+                None => bail!("Invalid mapping line: {}", l),
+            })
+            .collect::<Result<_, Error>>()?
+    };
+    let mut mapping = HashMap::new();
+    for (hash, pubkey) in entries {
+        mapping.insert(hash.to_lowercase(), parse_pubkey(&pubkey)?);
+    }
+    info!("Loaded {} owner mappings", mapping.len());
+    Ok(mapping)
+}
+
+fn dir_owner(base: &Path, path: &Path) -> Option<Vec<u8>> {
+    let first = path.strip_prefix(base).ok()?.components().next()?;
+    parse_pubkey(first.as_os_str().to_str()?).ok()
+}
+
+async fn import_file(
+    path: &Path,
+    dir_owner: Option<Vec<u8>>,
+    mapping: &HashMap<String, Vec<u8>>,
+    db: &Database,
+    fs: &FileStore,
+) -> Result<bool, Error> {
+    let mut file = tokio::fs::File::open(path).await?;
+    let id = hex::encode(FileStore::hash_file(&mut file).await?);
+    let owner = match mapping.get(&id).cloned().or(dir_owner) {
+        Some(o) => o,
+        None => {
+            warn!("No owner found for {} ({}), skipping", path.display(), id);
+            return Ok(false);
+        }
+    };
+    file.seek(SeekFrom::Start(0)).await?;
+    let blob = fs.put(file, "application/octet-stream", false).await?;
+    let user_id = db.upsert_user(&owner).await?;
+    db.add_file(&blob.upload, user_id).await?;
+    info!("Imported {} => {}", path.display(), id);
+    Ok(true)
+}
+
+/// A file listed on a remote server
+#[derive(Default)]
+struct RemoteFile {
+    url: String,
+    created: Option<i64>,
+    alt: Option<String>,
+    license: Option<String>,
+    name: Option<String>,
+    mime_type: Option<String>,
+    dim: Option<(u32, u32)>,
+    hash: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Nip96Info {
+    api_url: String,
+    delegated_to_url: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Nip96ListPage {
+    total: u32,
+    files: Vec<Nip94File>,
+}
+
+#[derive(Deserialize)]
+struct Nip94File {
+    tags: Vec<Vec<String>>,
+    content: String,
+    created_at: i64,
+}
+
+impl Nip94File {
+    fn tag(&self, key: &str) -> Option<String> {
+        self.tags
+            .iter()
+            .find(|t| t.len() > 1 && t[0] == key)
+            .map(|t| t[1].clone())
+    }
+
+    fn into_remote(self) -> Option<RemoteFile> {
+        Some(RemoteFile {
+            url: self.tag("url")?,
+            created: Some(self.created_at),
+            alt: self.tag("alt"),
+            license: self.tag("license"),
+            name: Some(self.content.clone()).filter(|c| !c.is_empty()),
+            mime_type: self.tag("m"),
+            dim: self.tag("dim").and_then(|d| {
+                let (w, h) = d.split_once('x')?;
+                Some((w.parse().ok()?, h.parse().ok()?))
+            }),
+            hash: self.tag("x"),
+        })
+    }
+}
+
+/// Load a list of urls to import, with optional alt text
+struct BenchResult {
+    bytes: u64,
+    failed: u32,
+    latencies: Vec<Duration>,
+}
+
+/// Request ranges at pseudo-random offsets until the deadline, like a player seeking
+async fn bench_stream(
+    client: &reqwest::Client,
+    url: &str,
+    size: u64,
+    range_size: u64,
+    seed: u64,
+    deadline: Instant,
+) -> BenchResult {
+    let mut res = BenchResult {
+        bytes: 0,
+        failed: 0,
+        latencies: Vec::new(),
+    };
+    // xorshift, the offsets only need to differ between streams
+    let mut state = seed.wrapping_mul(0x9E3779B97F4A7C15) | 1;
+    while Instant::now() < deadline {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        let start = state % (size - range_size);
+        let t = Instant::now();
+        let rsp = client
+            .get(url)
+            .header(
+                "range",
+                format!("bytes={}-{}", start, start + range_size - 1),
+            )
+            .send()
+            .await
+            .and_then(|r| r.error_for_status());
+        match rsp {
+            Ok(r) => match r.bytes().await {
+                Ok(b) => {
+                    res.bytes += b.len() as u64;
+                    res.latencies.push(t.elapsed());
+                }
+                Err(e) => {
+                    warn!("Failed to read range: {}", e);
+                    res.failed += 1;
+                }
+            },
+            Err(e) => {
+                warn!("Range request failed: {}", e);
+                res.failed += 1;
+            }
+        }
+    }
+    res
+}
+
+fn load_url_list(path: &Path) -> Result<Vec<RemoteFile>, Error> {
+    Ok(std::fs::read_to_string(path)?
+        .lines()
+        .map(|l| l.trim())
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(|l| {
+            let (url, alt) = match l.split_once(',') {
+                Some((u, a)) => (u.trim(), Some(a.trim().to_string())),
+                None => (l, None),
+            };
+            RemoteFile {
+                url: url.to_string(),
+                alt: alt.filter(|a| !a.is_empty()),
+                ..Default::default()
+            }
+        })
+        .collect())
+}
+
+async fn get_json<T: DeserializeOwned>(
+    client: &reqwest::Client,
+    url: &str,
+    keys: Option<&Keys>,
+) -> Result<T, Error> {
+    let mut req = client.get(url);
+    if let Some(k) = keys {
+        let auth = EventBuilder::new(Kind::HttpAuth, "")
+            .tags([
+                Tag::custom(
+                    TagKind::SingleLetter(SingleLetterTag::lowercase(Alphabet::U)),
+                    [url],
+                ),
+                Tag::custom(TagKind::Custom(Cow::Borrowed("method")), ["GET"]),
+            ])
+            .sign_with_keys(k)?;
+        req = req.header(
+            "authorization",
+            format!("Nostr {}", BASE64_STANDARD.encode(auth.as_json())),
+        );
+    }
+    let rsp = req.send().await?;
+    if !rsp.status().is_success() {
+        bail!("{} returned {}", url, rsp.status());
+    }
+    Ok(serde_json::from_slice(&rsp.bytes().await?)?)
+}
+
+/// List all files of a user using the NIP-96 list API
+async fn list_remote_files(server: &str, keys: &Keys) -> Result<Vec<RemoteFile>, Error> {
+    let client = reqwest::ClientBuilder::new()
+        .timeout(Duration::from_secs(30))
+        .build()?;
+    let mut base = server.trim_end_matches('/').to_string();
+    let mut info: Nip96Info = get_json(
+        &client,
+        &format!("{}/.well-known/nostr/nip96.json", base),
+        None,
+    )
+    .await?;
+    if let Some(d) = info.delegated_to_url.take().filter(|d| !d.is_empty()) {
+        base = d.trim_end_matches('/').to_string();
+        info = get_json(
+            &client,
+            &format!("{}/.well-known/nostr/nip96.json", base),
+            None,
+        )
+        .await?;
+    }
+    let api_url = match Url::parse(&info.api_url) {
+        Ok(u) => u.to_string(),
+        Err(_) => format!("{}/{}", base, info.api_url.trim_start_matches('/')),
+    };
+
+    let mut files = Vec::new();
+    let mut page = 0;
+    loop {
+        let url = format!("{}?page={}&count={}", api_url, page, LIST_PAGE_SIZE);
+        let rsp: Nip96ListPage = get_json(&client, &url, Some(keys)).await?;
+        let n = rsp.files.len();
+        files.extend(rsp.files.into_iter().filter_map(|f| f.into_remote()));
+        info!("Listed {}/{} remote files", files.len(), rsp.total);
+        if n == 0 || files.len() >= rsp.total as usize {
+            break;
+        }
+        page += 1;
+    }
+    Ok(files)
+}
+
+/// Download and store a remote file, returning its new url
+async fn import_remote_file(
+    remote: &RemoteFile,
+    user_id: u64,
+    db: &Database,
+    fs: &FileStore,
+    settings: &Settings,
+) -> Result<String, Error> {
+    let rsp = reqwest::get(&remote.url).await?;
+    if !rsp.status().is_success() {
+        bail!("Remote server returned {}", rsp.status());
+    }
+    let mime_type = remote
+        .mime_type
+        .clone()
+        .or(rsp
+            .headers()
+            .get("content-type")
+            .and_then(|h| h.to_str().ok())
+            .map(|h| h.to_string()))
+        .unwrap_or("application/octet-stream".to_string());
+    let mut blob = fs
+        .put(response_reader(settings, rsp), &mime_type, false)
+        .await?;
+    let id = hex::encode(&blob.upload.id);
+    if let Some(x) = &remote.hash {
+        if !x.eq_ignore_ascii_case(&id) {
+            warn!(
+                "Hash mismatch for {}, expected {} got {}",
+                remote.url, x, id
+            );
+        }
+    }
+    if let Some(created) = remote.created.and_then(|c| DateTime::from_timestamp(c, 0)) {
+        blob.upload.created = created;
+    }
+    if blob.upload.width.is_none() {
+        if let Some((w, h)) = remote.dim {
+            blob.upload.width = Some(w);
+            blob.upload.height = Some(h);
+        }
+    }
+    blob.upload.alt = remote.alt.clone();
+    blob.upload.license = remote
+        .license
+        .as_deref()
+        .and_then(|l| sanitize_license(l).ok().flatten());
+    if !settings.hide_file_names.unwrap_or(false) {
+        blob.upload.name = remote.name.clone().unwrap_or_default();
+    }
+    db.add_file(&blob.upload, user_id).await?;
+    Ok(format!(
+        "{}/{}{}",
+        settings.public_url,
+        id,
+        mime2ext::mime2ext(&blob.upload.mime_type)
+            .map(|m| format!(".{m}"))
+            .unwrap_or_default()
+    ))
+}
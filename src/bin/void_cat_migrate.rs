@@ -3,7 +3,7 @@ use clap::Parser;
 use config::Config;
 use log::{info, warn};
 use nostr::bitcoin::base58;
-use route96::db::{Database, FileUpload};
+use route96::db::{Database, FileUpload, MetadataSource};
 use route96::filesystem::FileStore;
 use route96::settings::Settings;
 use route96::void_db::VoidCatDb;
@@ -140,6 +140,7 @@ async fn migrate_file(
             Some(s) => Some(s[1].parse::<u32>()?),
             None => None,
         },
+        dim_source: md.as_ref().map(|_| MetadataSource::Server),
         blur_hash: None,
         alt: f.description.clone(),
         ..Default::default()
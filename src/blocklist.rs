@@ -0,0 +1,184 @@
+use std::path::Path;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{Error, FromRow};
+use url::Url;
+
+use crate::db::Database;
+use crate::filesystem::FileStore;
+use crate::settings::Settings;
+
+/// What an operator deny rule matches
+#[derive(Clone, Copy, Debug, PartialEq, Eq, sqlx::Type, Serialize, Deserialize)]
+#[sqlx(rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum DenyKind {
+    /// Mirror source host, subdomains included
+    Domain,
+    /// Client tag of the auth event, `*` matches any text
+    Client,
+    /// Uploader pubkey (hex)
+    Pubkey,
+}
+
+/// Operator rule rejecting uploads by their origin
+#[derive(Clone, Debug, FromRow, Serialize)]
+pub struct DenyRule {
+    pub id: u64,
+    pub kind: DenyKind,
+    pub value: String,
+    pub reason: Option<String>,
+    pub created: DateTime<Utc>,
+}
+
+impl Database {
+    pub async fn list_deny_rules(&self) -> Result<Vec<DenyRule>, Error> {
+        sqlx::query_as("select * from deny_rules order by id")
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    pub async fn add_deny_rule(
+        &self,
+        kind: DenyKind,
+        value: &str,
+        reason: Option<&str>,
+    ) -> Result<u64, Error> {
+        let res = sqlx::query("insert into deny_rules(kind,value,reason) values(?,?,?)")
+            .bind(kind)
+            .bind(value)
+            .bind(reason)
+            .execute(&self.pool)
+            .await?;
+        Ok(res.last_insert_id())
+    }
+
+    /// Remove a rule, false when it did not exist
+    pub async fn delete_deny_rule(&self, id: u64) -> Result<bool, Error> {
+        let res = sqlx::query("delete from deny_rules where id = ?")
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(res.rows_affected() > 0)
+    }
+}
+
+/// Where an upload came from, checked against the deny rules
+#[derive(Debug, Clone, Copy, Default)]
+pub struct UploadOrigin<'a> {
+    pub pubkey: &'a [u8],
+    /// Client tag of the auth event
+    pub client: Option<&'a str>,
+    /// Url of a mirrored file
+    pub url: Option<&'a str>,
+}
+
+/// Check an upload origin against the deny rules.
+///
+/// Returns a machine-readable reason (`pubkey`, `client:<tag>` or `domain:<host>`) when denied.
+pub async fn check_origin(
+    db: &Database,
+    origin: UploadOrigin<'_>,
+) -> Result<Option<String>, Error> {
+    let rules = db.list_deny_rules().await?;
+    if rules.is_empty() {
+        return Ok(None);
+    }
+    let pubkey = hex::encode(origin.pubkey);
+    let host = origin
+        .url
+        .and_then(|u| Url::parse(u).ok())
+        .and_then(|u| u.host_str().map(|h| h.trim_end_matches('.').to_lowercase()));
+    for r in rules {
+        let denied = match r.kind {
+            DenyKind::Pubkey => r.value.eq_ignore_ascii_case(&pubkey),
+            DenyKind::Client => origin
+                .client
+                .is_some_and(|c| glob_match(&r.value.to_lowercase(), &c.to_lowercase())),
+            DenyKind::Domain => host.as_ref().is_some_and(|h| {
+                let d = r.value.trim_matches('.').to_lowercase();
+                *h == d || h.ends_with(&format!(".{}", d))
+            }),
+        };
+        if denied {
+            return Ok(Some(match r.kind {
+                DenyKind::Pubkey => "pubkey".to_string(),
+                DenyKind::Client => format!("client:{}", origin.client.unwrap_or_default()),
+                DenyKind::Domain => format!("domain:{}", host.unwrap_or_default()),
+            }));
+        }
+    }
+    Ok(None)
+}
+
+/// Match text against a pattern where `*` matches any run of characters
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let mut rest = match text.strip_prefix(first) {
+        Some(r) => r,
+        None => return false,
+    };
+    let parts: Vec<&str> = parts.collect();
+    let last = match parts.split_last() {
+        Some((last, middle)) => {
+            for p in middle {
+                match rest.find(p) {
+                    Some(i) => rest = &rest[i + p.len()..],
+                    None => return false,
+                }
+            }
+            last
+        }
+        // no wildcard, the whole text must match
+        None => return rest.is_empty(),
+    };
+    rest.len() >= last.len() && rest.ends_with(last)
+}
+
+/// Detect executable and script formats from the magic bytes of a file
+pub fn sniff_mime(path: &Path) -> Option<&'static str> {
+    let mut buf = [0u8; 4];
+    let n = FileStore::read_head(path, &mut buf).ok()?;
+    match &buf[..n] {
+        [b'M', b'Z', ..] => Some("application/x-dosexec"),
+        [0x7f, b'E', b'L', b'F'] => Some("application/x-executable"),
+        [0xfe, 0xed, 0xfa, 0xce | 0xcf] | [0xce | 0xcf, 0xfa, 0xed, 0xfe] => {
+            Some("application/x-mach-binary")
+        }
+        [b'#', b'!', ..] => Some("text/x-shellscript"),
+        _ => None,
+    }
+}
+
+/// Check an upload against the configured deny lists.
+///
+/// Returns a machine-readable reason (`extension:<ext>` or `mime:<type>`) when blocked.
+pub fn check_upload(
+    settings: &Settings,
+    name: Option<&str>,
+    mime_types: &[&str],
+    path: &Path,
+) -> Option<String> {
+    let cfg = settings.blocked_uploads.as_ref()?;
+
+    if let Some(ext) = name.and_then(|n| n.rsplit_once('.')).map(|(_, e)| e.trim()) {
+        if cfg
+            .extensions
+            .iter()
+            .any(|b| b.trim_start_matches('.').eq_ignore_ascii_case(ext))
+        {
+            return Some(format!("extension:{}", ext.to_lowercase()));
+        }
+    }
+
+    let sniffed = sniff_mime(path);
+    mime_types
+        .iter()
+        .copied()
+        .chain(sniffed)
+        .map(|m| m.split(';').next().unwrap_or(m).trim())
+        .find(|m| cfg.mime_types.iter().any(|b| b.eq_ignore_ascii_case(m)))
+        .map(|m| format!("mime:{}", m.to_lowercase()))
+}
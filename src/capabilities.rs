@@ -0,0 +1,174 @@
+use std::sync::RwLock;
+
+use log::info;
+use serde::Serialize;
+
+use crate::settings::{AnalyticsSink, Settings};
+
+pub const IMAGE_NEGOTIATION: &str = "image-negotiation";
+pub const WATERMARK: &str = "watermark";
+pub const LABELS: &str = "labels";
+pub const ANALYTICS: &str = "analytics";
+pub const TORRENTS: &str = "torrents";
+pub const HWACCEL: &str = "hwaccel";
+pub const ENCRYPTION: &str = "encryption";
+pub const GRPC: &str = "grpc";
+
+/// Cargo features and whether they are compiled into this build
+const FEATURES: [(&str, bool); 12] = [
+    ("blossom", cfg!(feature = "blossom")),
+    ("nip96", cfg!(feature = "nip96")),
+    ("media-compression", cfg!(feature = "media-compression")),
+    ("labels", cfg!(feature = "labels")),
+    ("analytics", cfg!(feature = "analytics")),
+    ("ranges", cfg!(feature = "ranges")),
+    ("torrent-v2", cfg!(feature = "torrent-v2")),
+    ("void-cat-redirects", cfg!(feature = "void-cat-redirects")),
+    ("react-ui", cfg!(feature = "react-ui")),
+    ("blake3", cfg!(feature = "blake3")),
+    ("grpc", cfg!(feature = "grpc")),
+    ("encryption", cfg!(feature = "encryption")),
+];
+
+static REGISTRY: RwLock<Vec<Capability>> = RwLock::new(Vec::new());
+
+/// Something this server can do, which needs both a compiled feature and config
+#[derive(Debug, Clone, Serialize)]
+pub struct Capability {
+    pub name: &'static str,
+    /// Cargo feature providing it, None when always compiled
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub feature: Option<&'static str>,
+    pub enabled: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+/// Compiled features with the registered capabilities, see GET /admin/capabilities
+#[derive(Debug, Clone, Serialize)]
+pub struct CapabilityReport {
+    pub version: &'static str,
+    pub features: Vec<&'static str>,
+    pub capabilities: Vec<Capability>,
+}
+
+/// Cargo features compiled into this build
+pub fn features() -> Vec<&'static str> {
+    FEATURES
+        .iter()
+        .filter(|(_, on)| *on)
+        .map(|(f, _)| *f)
+        .collect()
+}
+
+fn compiled(feature: &str) -> bool {
+    FEATURES.iter().any(|(f, on)| *f == feature && *on)
+}
+
+/// Record a capability, replacing an earlier entry with the same name.
+///
+/// It is only enabled when its feature is compiled in.
+pub fn register(
+    name: &'static str,
+    feature: Option<&'static str>,
+    enabled: bool,
+    detail: Option<String>,
+) {
+    let cap = Capability {
+        name,
+        feature,
+        enabled: enabled && feature.map(compiled).unwrap_or(true),
+        detail,
+    };
+    let mut registry = REGISTRY.write().unwrap();
+    match registry.iter_mut().find(|c| c.name == name) {
+        Some(c) => *c = cap,
+        None => registry.push(cap),
+    }
+}
+
+/// Whether a registered capability is enabled
+pub fn is_enabled(name: &str) -> bool {
+    REGISTRY
+        .read()
+        .unwrap()
+        .iter()
+        .any(|c| c.name == name && c.enabled)
+}
+
+pub fn report() -> CapabilityReport {
+    CapabilityReport {
+        version: env!("CARGO_PKG_VERSION"),
+        features: features(),
+        capabilities: REGISTRY.read().unwrap().clone(),
+    }
+}
+
+/// Register the capabilities which only depend on the config, subsystems with their own
+/// setup (hardware acceleration, encryption) register theirs when they are initialized
+pub fn init(settings: &Settings) {
+    register(
+        IMAGE_NEGOTIATION,
+        Some("media-compression"),
+        settings.image_negotiation.unwrap_or(false),
+        None,
+    );
+    register(
+        WATERMARK,
+        Some("media-compression"),
+        settings.watermark.is_some() && settings.image_negotiation.unwrap_or(false),
+        settings
+            .watermark
+            .as_ref()
+            .map(|w| w.image.display().to_string()),
+    );
+    register(LABELS, Some("labels"), settings.vit_model.is_some(), None);
+    let sink = settings.analytics.or(if settings.plausible_url.is_some() {
+        Some(AnalyticsSink::Plausible)
+    } else {
+        None
+    });
+    register(
+        ANALYTICS,
+        Some("analytics"),
+        sink.is_some(),
+        sink.map(|s| format!("{:?}", s).to_lowercase()),
+    );
+    #[cfg(feature = "torrent-v2")]
+    register(
+        TORRENTS,
+        Some("torrent-v2"),
+        settings.torrent.is_some(),
+        None,
+    );
+    #[cfg(not(feature = "torrent-v2"))]
+    register(TORRENTS, Some("torrent-v2"), false, None);
+    #[cfg(feature = "grpc")]
+    register(
+        GRPC,
+        Some("grpc"),
+        settings.grpc.is_some(),
+        settings.grpc.as_ref().map(|g| g.listen.clone()),
+    );
+    #[cfg(not(feature = "grpc"))]
+    register(GRPC, Some("grpc"), false, None);
+    register(HWACCEL, Some("media-compression"), false, None);
+    register(ENCRYPTION, Some("encryption"), false, None);
+}
+
+/// Log the compiled features and capabilities
+pub fn log() {
+    let report = report();
+    info!("Features: {}", report.features.join(", "));
+    for c in &report.capabilities {
+        info!(
+            "Capability {}: {}{}",
+            c.name,
+            if c.enabled { "enabled" } else { "disabled" },
+            c.detail
+                .as_ref()
+                .map(|d| format!(" ({})", d))
+                .unwrap_or_default()
+        );
+    }
+}
@@ -0,0 +1,375 @@
+use std::io::{Cursor, Write};
+
+use flate2::write::GzEncoder;
+use log::warn;
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::{Method, Status};
+use rocket::{Request, Response};
+
+use crate::settings::Settings;
+
+/// Default smallest body which is compressed
+const DEFAULT_MIN_SIZE: usize = 1024;
+
+/// Default largest body which is compressed, bodies are buffered to compress them
+const DEFAULT_MAX_SIZE: usize = 8 * 1024 * 1024;
+
+/// Brotli quality used for responses, higher levels are too slow to compress on the fly
+const BROTLI_QUALITY: u32 = 5;
+
+/// Content types compressed when none are configured, media is already compressed
+const DEFAULT_CONTENT_TYPES: [&str; 11] = [
+    "application/json",
+    "application/nostr+json",
+    "application/manifest+json",
+    "application/javascript",
+    "text/javascript",
+    "text/css",
+    "text/html",
+    "text/plain",
+    "text/xml",
+    "application/xml",
+    "image/svg+xml",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Encoding {
+    Brotli,
+    Gzip,
+}
+
+impl Encoding {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+        }
+    }
+
+    fn compress(&self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            Encoding::Brotli => {
+                let mut w = brotli::CompressorWriter::new(Vec::new(), 4096, BROTLI_QUALITY, 22);
+                w.write_all(data)?;
+                Ok(w.into_inner())
+            }
+            Encoding::Gzip => {
+                let mut w = GzEncoder::new(Vec::new(), flate2::Compression::default());
+                w.write_all(data)?;
+                w.finish()
+            }
+        }
+    }
+}
+
+/// Compresses text responses (json, UI assets without a pre-compressed variant) for clients
+/// which accept it. Blob responses (with accept-ranges) and encoded responses are sent as is
+pub struct CompressionFairing {
+    min_size: usize,
+    max_size: usize,
+    content_types: Vec<String>,
+    brotli: bool,
+}
+
+impl CompressionFairing {
+    pub fn new(settings: &Settings) -> Self {
+        let cfg = settings.compression.as_ref();
+        Self {
+            min_size: cfg.and_then(|c| c.min_size).unwrap_or(DEFAULT_MIN_SIZE),
+            max_size: cfg.and_then(|c| c.max_size).unwrap_or(DEFAULT_MAX_SIZE),
+            content_types: cfg
+                .and_then(|c| c.content_types.clone())
+                .unwrap_or_else(|| {
+                    DEFAULT_CONTENT_TYPES
+                        .iter()
+                        .map(|t| t.to_string())
+                        .collect()
+                })
+                .iter()
+                .map(|t| t.to_lowercase())
+                .collect(),
+            brotli: cfg.and_then(|c| c.brotli).unwrap_or(true),
+        }
+    }
+
+    /// Pick the preferred encoding from an Accept-Encoding header, brotli wins ties
+    fn negotiate(&self, accept_encoding: &str) -> Option<Encoding> {
+        let mut best: Option<(Encoding, f32)> = None;
+        for e in accept_encoding.split(',') {
+            let mut parts = e.split(';');
+            let name = parts.next().unwrap_or("").trim().to_lowercase();
+            let q = parts
+                .find_map(|p| p.trim().strip_prefix("q="))
+                .and_then(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            let enc = match name.as_str() {
+                "br" if self.brotli => Encoding::Brotli,
+                "gzip" => Encoding::Gzip,
+                _ => continue,
+            };
+            if q <= 0.0 {
+                continue;
+            }
+            match best {
+                Some((b, bq)) if bq > q || (bq == q && b == Encoding::Brotli) => {}
+                _ => best = Some((enc, q)),
+            }
+        }
+        best.map(|(e, _)| e)
+    }
+
+    fn compressible(&self, response: &Response<'_>) -> bool {
+        let content_type = match response.content_type() {
+            Some(c) => format!("{}/{}", c.top(), c.sub()).to_lowercase(),
+            None => return false,
+        };
+        self.content_types.contains(&content_type)
+    }
+}
+
+#[rocket::async_trait]
+impl Fairing for CompressionFairing {
+    fn info(&self) -> Info {
+        Info {
+            name: "Response compression",
+            kind: Kind::Response,
+        }
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, response: &mut Response<'r>) {
+        if req.method() == Method::Head
+            || response.status() != Status::Ok
+            || response.headers().contains("content-encoding")
+            || response.headers().contains("accept-ranges")
+            || !self.compressible(response)
+        {
+            return;
+        }
+        let encoding = match req
+            .headers()
+            .get_one("accept-encoding")
+            .and_then(|a| self.negotiate(a))
+        {
+            Some(e) => e,
+            None => return,
+        };
+        match response.body().preset_size() {
+            Some(s) if s >= self.min_size && s <= self.max_size => {}
+            _ => return,
+        }
+        let body = match response.body_mut().to_bytes().await {
+            Ok(b) => b,
+            Err(e) => {
+                warn!("Failed to read response for compression: {}", e);
+                return;
+            }
+        };
+        let compressed = tokio::task::spawn_blocking(move || {
+            let res = encoding.compress(&body);
+            (body, res)
+        })
+        .await;
+        let bytes = match compressed {
+            Ok((_, Ok(c))) => {
+                response.set_raw_header("content-encoding", encoding.as_str());
+                c
+            }
+            Ok((body, Err(e))) => {
+                warn!("Failed to compress response: {}", e);
+                body
+            }
+            Err(e) => {
+                // the body was moved into the failed task
+                warn!("Failed to compress response: {}", e);
+                response.set_status(Status::InternalServerError);
+                Vec::new()
+            }
+        };
+        response.adjoin_raw_header("vary", "accept-encoding");
+        response.set_sized_body(bytes.len(), Cursor::new(bytes));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rocket::http::{ContentType, Header};
+    use rocket::local::blocking::Client;
+    use rocket::response::{self, Responder};
+    use rocket::{get, routes};
+
+    use super::*;
+    use crate::settings::CompressionConfig;
+
+    fn fairing(cfg: CompressionConfig) -> CompressionFairing {
+        CompressionFairing::new(&Settings {
+            compression: Some(cfg),
+            ..Default::default()
+        })
+    }
+
+    fn default_fairing() -> CompressionFairing {
+        CompressionFairing::new(&Settings::default())
+    }
+
+    #[test]
+    fn negotiate_prefers_higher_q() {
+        let f = default_fairing();
+        assert_eq!(f.negotiate("gzip;q=1.0, br;q=0.5"), Some(Encoding::Gzip));
+        assert_eq!(f.negotiate("gzip;q=0.5, br;q=0.8"), Some(Encoding::Brotli));
+        assert_eq!(f.negotiate("br;q=0.2, gzip"), Some(Encoding::Gzip));
+    }
+
+    #[test]
+    fn negotiate_brotli_wins_ties() {
+        let f = default_fairing();
+        assert_eq!(f.negotiate("gzip, br"), Some(Encoding::Brotli));
+        assert_eq!(f.negotiate("br, gzip"), Some(Encoding::Brotli));
+        assert_eq!(f.negotiate("gzip;q=0.5, br;q=0.5"), Some(Encoding::Brotli));
+        assert_eq!(f.negotiate("GZIP, BR"), Some(Encoding::Brotli));
+    }
+
+    #[test]
+    fn negotiate_skips_q_zero() {
+        let f = default_fairing();
+        assert_eq!(f.negotiate("br;q=0, gzip"), Some(Encoding::Gzip));
+        assert_eq!(f.negotiate("br;q=0, gzip;q=0.0"), None);
+        assert_eq!(f.negotiate("identity, deflate, *"), None);
+        assert_eq!(f.negotiate(""), None);
+    }
+
+    #[test]
+    fn negotiate_without_brotli() {
+        let f = fairing(CompressionConfig {
+            min_size: None,
+            max_size: None,
+            content_types: None,
+            brotli: Some(false),
+        });
+        assert_eq!(f.negotiate("br, gzip;q=0.1"), Some(Encoding::Gzip));
+        assert_eq!(f.negotiate("br"), None);
+    }
+
+    /// Body of `size` bytes with a content type and optionally an extra header
+    struct Body {
+        content_type: ContentType,
+        header: Option<Header<'static>>,
+        size: usize,
+    }
+
+    impl Body {
+        fn text(size: usize) -> Self {
+            Self {
+                content_type: ContentType::Plain,
+                header: None,
+                size,
+            }
+        }
+    }
+
+    impl<'r> Responder<'r, 'static> for Body {
+        fn respond_to(self, _: &'r Request<'_>) -> response::Result<'static> {
+            let mut r = Response::build();
+            r.header(self.content_type)
+                .sized_body(self.size, Cursor::new(vec![b'a'; self.size]));
+            if let Some(h) = self.header {
+                r.header(h);
+            }
+            r.ok()
+        }
+    }
+
+    #[get("/text/<size>")]
+    fn text(size: usize) -> Body {
+        Body::text(size)
+    }
+
+    #[get("/ranged")]
+    fn ranged() -> Body {
+        Body {
+            header: Some(Header::new("accept-ranges", "bytes")),
+            ..Body::text(4096)
+        }
+    }
+
+    #[get("/encoded")]
+    fn encoded() -> Body {
+        Body {
+            header: Some(Header::new("content-encoding", "gzip")),
+            ..Body::text(4096)
+        }
+    }
+
+    #[get("/image")]
+    fn image() -> Body {
+        Body {
+            content_type: ContentType::PNG,
+            ..Body::text(4096)
+        }
+    }
+
+    fn client(fairing: CompressionFairing) -> Client {
+        let rocket = rocket::build()
+            .mount("/", routes![text, ranged, encoded, image])
+            .attach(fairing);
+        Client::tracked(rocket).expect("valid rocket")
+    }
+
+    /// Content-Encoding of a GET with `Accept-Encoding: br, gzip`
+    fn get_encoding(client: &Client, uri: &str) -> Option<String> {
+        let rsp = client
+            .get(uri)
+            .header(Header::new("accept-encoding", "br, gzip"))
+            .dispatch();
+        assert_eq!(rsp.status(), Status::Ok);
+        rsp.headers().get_one("content-encoding").map(String::from)
+    }
+
+    #[test]
+    fn compresses_text() {
+        let client = client(default_fairing());
+        let rsp = client
+            .get("/text/4096")
+            .header(Header::new("accept-encoding", "gzip"))
+            .dispatch();
+        assert_eq!(rsp.headers().get_one("content-encoding"), Some("gzip"));
+        assert_eq!(rsp.headers().get_one("vary"), Some("accept-encoding"));
+        let body = rsp.into_bytes().unwrap();
+        let mut plain = Vec::new();
+        std::io::Read::read_to_end(&mut flate2::read::GzDecoder::new(&body[..]), &mut plain)
+            .unwrap();
+        assert_eq!(plain, vec![b'a'; 4096]);
+    }
+
+    #[test]
+    fn skips_head() {
+        let client = client(default_fairing());
+        let rsp = client
+            .head("/text/4096")
+            .header(Header::new("accept-encoding", "br, gzip"))
+            .dispatch();
+        assert_eq!(rsp.status(), Status::Ok);
+        assert_eq!(rsp.headers().get_one("content-encoding"), None);
+    }
+
+    #[test]
+    fn skips_ranged_and_encoded_responses() {
+        let client = client(default_fairing());
+        assert_eq!(get_encoding(&client, "/ranged"), None);
+        assert_eq!(get_encoding(&client, "/encoded").as_deref(), Some("gzip"));
+        assert_eq!(get_encoding(&client, "/image"), None);
+    }
+
+    #[test]
+    fn skips_sizes_out_of_bounds() {
+        let client = client(fairing(CompressionConfig {
+            min_size: Some(100),
+            max_size: Some(1000),
+            content_types: None,
+            brotli: None,
+        }));
+        assert_eq!(get_encoding(&client, "/text/99"), None);
+        assert_eq!(get_encoding(&client, "/text/100").as_deref(), Some("br"));
+        assert_eq!(get_encoding(&client, "/text/1000").as_deref(), Some("br"));
+        assert_eq!(get_encoding(&client, "/text/1001"), None);
+    }
+}
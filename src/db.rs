@@ -1,7 +1,21 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
 use chrono::{DateTime, Utc};
+use log::warn;
 use serde::Serialize;
+use serde_with::hex::Hex;
+use serde_with::serde_as;
 use sqlx::migrate::MigrateError;
 use sqlx::{Error, Executor, FromRow, Row};
+use tokio::sync::Notify;
+use tokio_util::sync::CancellationToken;
+
+/// Set the later of two expiry times, where null (keep forever) always wins
+const EXTEND_EXPIRY: &str = "update uploads set expires = \
+    case when expires is null or ? is null then null else greatest(expires, ?) end \
+    where id = ?";
 
 #[derive(Clone, FromRow, Default, Serialize)]
 pub struct FileUpload {
@@ -14,7 +28,34 @@ pub struct FileUpload {
     pub width: Option<u32>,
     pub height: Option<u32>,
     pub blur_hash: Option<String>,
+    #[serde(skip)]
+    pub blur_hash_source: Option<MetadataSource>,
+    /// Source of width and height
+    #[serde(skip)]
+    pub dim_source: Option<MetadataSource>,
     pub alt: Option<String>,
+    /// License of the file, eg. CC-BY-4.0
+    pub license: Option<String>,
+    /// When the upload is removed by the retention policy, None keeps it forever
+    pub expires: Option<DateTime<Utc>>,
+    /// Hidden from downloads by an admin
+    #[serde(skip)]
+    pub quarantined: bool,
+    /// Preserved by an admin, see [crate::legal_hold]
+    #[serde(skip)]
+    pub legal_hold: bool,
+    /// BitTorrent v2 info hash, set once a torrent has been generated
+    #[serde(skip)]
+    pub torrent_info_hash: Option<Vec<u8>>,
+    /// BLAKE3 of the file contents, used for fast integrity checks
+    #[serde(skip)]
+    pub blake3: Option<Vec<u8>>,
+    /// Name of the storage root holding the file, None for storage_dir
+    #[serde(skip)]
+    pub storage: Option<String>,
+    /// Hash of the data the client sent, when the server transformed it into this file
+    #[serde(skip)]
+    pub original_hash: Option<Vec<u8>>,
 
     #[sqlx(skip)]
     #[cfg(feature = "labels")]
@@ -28,6 +69,20 @@ pub struct User {
     pub pubkey: Vec<u8>,
     pub created: DateTime<Utc>,
     pub is_admin: bool,
+    /// Bypass the extension / file type deny list
+    pub allow_blocked_types: bool,
+    /// Retention tier, see [crate::settings::RetentionConfig]
+    pub tier: Option<String>,
+    /// Display name from the users kind 0 profile
+    pub display_name: Option<String>,
+    pub nip05: Option<String>,
+    /// nip05 resolves to this pubkey
+    pub nip05_verified: bool,
+    pub lud16: Option<String>,
+    /// lud16 resolves to an LNURL pay endpoint
+    pub lud16_verified: bool,
+    /// Last time the profile was fetched
+    pub profile_updated: Option<DateTime<Utc>>,
 }
 
 #[cfg(feature = "labels")]
@@ -39,6 +94,13 @@ pub struct FileLabel {
     pub model: String,
 }
 
+#[cfg(feature = "labels")]
+#[derive(Clone, FromRow, Serialize)]
+pub struct LabelCount {
+    pub label: String,
+    pub count: i64,
+}
+
 #[cfg(feature = "labels")]
 impl FileLabel {
     pub fn new(label: String, model: String) -> Self {
@@ -57,34 +119,173 @@ pub struct UserStats {
     pub total_size: u64,
 }
 
+#[serde_as]
+#[derive(Clone, FromRow, Serialize)]
+pub struct CorruptFile {
+    #[serde(with = "hex")]
+    pub file: Vec<u8>,
+    /// Hash of the data found on disk, empty when the file is missing
+    #[serde_as(as = "Option<Hex>")]
+    pub actual_hash: Option<Vec<u8>>,
+    pub detected: DateTime<Utc>,
+    pub restored: Option<DateTime<Utc>>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, sqlx::Type, Serialize)]
+#[sqlx(rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Complete,
+    Failed,
+    Cancelled,
+}
+
+/// Who computed a metadata field of an upload
+#[derive(Clone, Copy, Debug, PartialEq, Eq, sqlx::Type, Serialize)]
+#[sqlx(rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum MetadataSource {
+    Server,
+    /// Sent by the uploading client, see [crate::settings::ClientMetadataPolicy]
+    Client,
+}
+
+/// Lifecycle of an upload, available once it is in the uploads table
+#[derive(Clone, Copy, Debug, PartialEq, Eq, sqlx::Type, Serialize)]
+#[sqlx(rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum UploadState {
+    /// Upload is being received
+    Pending,
+    /// Upload was received and is being transformed
+    Processing,
+    Available,
+}
+
+/// An upload counted in the upload state of a blob, see [Database::track_upload_state]
+pub struct UploadStateGuard {
+    db: Database,
+    file: Vec<u8>,
+}
+
+impl Drop for UploadStateGuard {
+    fn drop(&mut self) {
+        let db = self.db.clone();
+        let file = std::mem::take(&mut self.file);
+        tokio::spawn(async move {
+            if let Err(e) = db.release_upload_state(&file).await {
+                warn!("Failed to clear upload state: {}", e);
+            }
+        });
+    }
+}
+
+/// A file generated from another stored file, e.g. a compressed copy
+#[derive(Clone, FromRow, Serialize)]
+pub struct Derivation {
+    #[serde(with = "hex")]
+    pub source: Vec<u8>,
+    /// Parameters used to generate the derived file
+    pub params: String,
+    #[serde(with = "hex")]
+    pub derived: Vec<u8>,
+    pub created: DateTime<Utc>,
+}
+
+/// What media processing did to produce an upload from the data the client sent
+#[derive(Clone, Debug, Default, FromRow, Serialize)]
+pub struct ProcessingReport {
+    #[serde(with = "hex")]
+    pub file: Vec<u8>,
+    /// Hash of the data received from the client
+    #[serde(with = "hex")]
+    pub source: Vec<u8>,
+    pub source_size: u64,
+    pub source_mime_type: String,
+    /// Parameters used for processing, see [Derivation::params]
+    pub params: String,
+    pub duration_ms: u32,
+    /// Encoder quality picked by the quality search, see [crate::settings::QualitySearchConfig]
+    pub quality: Option<u8>,
+    pub created: DateTime<Utc>,
+}
+
+/// The app a user uploaded a file with
+#[derive(Clone, Debug, FromRow, Serialize)]
+pub struct UploadClient {
+    #[serde(with = "hex")]
+    pub pubkey: Vec<u8>,
+    pub user_agent: Option<String>,
+    pub client: Option<String>,
+    pub created: DateTime<Utc>,
+}
+
+#[derive(Clone, FromRow, Serialize)]
+pub struct Job {
+    pub id: u64,
+    pub kind: String,
+    pub payload: String,
+    pub status: JobStatus,
+    pub attempts: u32,
+    pub last_error: Option<String>,
+    /// Progress message set by long running jobs
+    pub progress: Option<String>,
+    pub run_after: DateTime<Utc>,
+    pub created: DateTime<Utc>,
+    pub updated: DateTime<Utc>,
+    /// Set when an admin cancels the running job, long running handlers stop when it is
+    #[sqlx(skip)]
+    #[serde(skip)]
+    pub cancel: CancellationToken,
+}
+
 #[derive(Clone)]
 pub struct Database {
     pub(crate) pool: sqlx::pool::Pool<sqlx::mysql::MySql>,
+    /// Totals of the admin file list by filter, see [Database::list_all_files]
+    pub(crate) file_counts: Arc<Mutex<HashMap<String, (Instant, i64)>>>,
+    /// Woken when an image or video is added without dimensions, for the metadata backfill
+    pub(crate) missing_metadata: Arc<Notify>,
 }
 
 impl Database {
     pub async fn new(conn: &str) -> Result<Self, Error> {
         let db = sqlx::mysql::MySqlPool::connect(conn).await?;
-        Ok(Self { pool: db })
+        Ok(Self::from_pool(db))
+    }
+
+    /// Use an existing pool, eg. the one `#[sqlx::test]` creates for each test
+    pub fn from_pool(pool: sqlx::mysql::MySqlPool) -> Self {
+        Self {
+            pool,
+            file_counts: Default::default(),
+            missing_metadata: Default::default(),
+        }
     }
 
     pub async fn migrate(&self) -> Result<(), MigrateError> {
         sqlx::migrate!("./migrations/").run(&self.pool).await
     }
 
+    /// Check the database connection is alive
+    pub async fn ping(&self) -> Result<(), Error> {
+        sqlx::query("select 1").execute(&self.pool).await?;
+        Ok(())
+    }
+
     pub async fn upsert_user(&self, pubkey: &Vec<u8>) -> Result<u64, Error> {
-        let res = sqlx::query("insert ignore into users(pubkey) values(?) returning id")
-            .bind(pubkey)
-            .fetch_optional(&self.pool)
-            .await?;
-        match res {
-            None => sqlx::query("select id from users where pubkey = ?")
-                .bind(pubkey)
-                .fetch_one(&self.pool)
-                .await?
-                .try_get(0),
-            Some(res) => res.try_get(0),
-        }
+        // last_insert_id(id) returns the existing row when the pubkey is taken,
+        // also when a concurrent request inserted it first
+        let res = sqlx::query(
+            "insert into users(pubkey) values(?) \
+            on duplicate key update id = last_insert_id(id)",
+        )
+        .bind(pubkey)
+        .execute(&self.pool)
+        .await?;
+        Ok(res.last_insert_id())
     }
 
     pub async fn get_user(&self, pubkey: &Vec<u8>) -> Result<User, Error> {
@@ -118,16 +319,30 @@ impl Database {
     pub async fn add_file(&self, file: &FileUpload, user_id: u64) -> Result<(), Error> {
         let mut tx = self.pool.begin().await?;
         let q = sqlx::query("insert ignore into \
-        uploads(id,name,size,mime_type,blur_hash,width,height,alt,created) values(?,?,?,?,?,?,?,?,?)")
+        uploads(id,name,size,mime_type,blur_hash,blur_hash_source,width,height,dim_source,alt,license,created,expires,storage,original_hash) \
+        values(?,?,?,?,?,?,?,?,?,?,?,?,?,?,?)")
             .bind(&file.id)
             .bind(&file.name)
             .bind(file.size)
             .bind(&file.mime_type)
             .bind(&file.blur_hash)
+            .bind(file.blur_hash_source)
             .bind(file.width)
             .bind(file.height)
+            .bind(file.dim_source)
             .bind(&file.alt)
-            .bind(file.created);
+            .bind(&file.license)
+            .bind(file.created)
+            .bind(file.expires)
+            .bind(&file.storage)
+            .bind(&file.original_hash);
+        tx.execute(q).await?;
+
+        // existing uploads keep the longest retention of all owners
+        let q = sqlx::query(EXTEND_EXPIRY)
+            .bind(file.expires)
+            .bind(file.expires)
+            .bind(&file.id);
         tx.execute(q).await?;
 
         let q2 = sqlx::query("insert ignore into user_uploads(file,user_id) values(?,?)")
@@ -145,9 +360,56 @@ impl Database {
             tx.execute(q3).await?;
         }
         tx.commit().await?;
+        if (file.width.is_none() || file.blur_hash.is_none())
+            && (file.mime_type.starts_with("image/") || file.mime_type.starts_with("video/"))
+        {
+            self.missing_metadata.notify_one();
+        }
+        Ok(())
+    }
+
+    pub async fn set_file_license(
+        &self,
+        file: &Vec<u8>,
+        license: Option<&str>,
+    ) -> Result<(), Error> {
+        sqlx::query("update uploads set license = ? where id = ?")
+            .bind(license)
+            .bind(file)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Record the storage root a file was moved to
+    pub async fn set_file_storage(
+        &self,
+        file: &Vec<u8>,
+        storage: Option<&str>,
+    ) -> Result<(), Error> {
+        sqlx::query("update uploads set storage = ? where id = ?")
+            .bind(storage)
+            .bind(file)
+            .execute(&self.pool)
+            .await?;
         Ok(())
     }
 
+    /// List files kept in a storage root, None for storage_dir
+    pub async fn list_files_in_storage(
+        &self,
+        storage: Option<&str>,
+        offset: u32,
+        limit: u32,
+    ) -> Result<Vec<FileUpload>, Error> {
+        sqlx::query_as("select * from uploads where storage <=> ? order by id limit ? offset ?")
+            .bind(storage)
+            .bind(limit)
+            .bind(offset)
+            .fetch_all(&self.pool)
+            .await
+    }
+
     pub async fn get_file(&self, file: &Vec<u8>) -> Result<Option<FileUpload>, Error> {
         sqlx::query_as("select * from uploads where id = ?")
             .bind(file)
@@ -155,6 +417,126 @@ impl Database {
             .await
     }
 
+    /// Mark a blob as in progress until the returned guard is dropped, also when the
+    /// client disconnects. Concurrent uploads of the same blob are counted, the state is
+    /// removed when the last one ends. States older than an hour are ignored
+    pub async fn track_upload_state(
+        &self,
+        file: &Vec<u8>,
+        state: UploadState,
+    ) -> Result<UploadStateGuard, Error> {
+        // assignments run in order, active is reset before updated is refreshed
+        sqlx::query(
+            "insert into upload_states(id,state,active) values(?,?,1) \
+            on duplicate key update \
+            active = if(updated > current_timestamp - interval 1 hour, active + 1, 1), \
+            state = values(state), updated = current_timestamp",
+        )
+        .bind(file)
+        .bind(state)
+        .execute(&self.pool)
+        .await?;
+        Ok(UploadStateGuard {
+            db: self.clone(),
+            file: file.clone(),
+        })
+    }
+
+    /// End one upload of a blob, the state is removed once no upload is left
+    async fn release_upload_state(&self, file: &Vec<u8>) -> Result<(), Error> {
+        sqlx::query("update upload_states set active = active - 1 where id = ? and active > 0")
+            .bind(file)
+            .execute(&self.pool)
+            .await?;
+        sqlx::query("delete from upload_states where id = ? and active = 0")
+            .bind(file)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Remove the state of a deleted blob, unless it is being uploaded again
+    pub async fn delete_upload_state(&self, file: &Vec<u8>) -> Result<(), Error> {
+        sqlx::query(
+            "delete from upload_states where id = ? \
+            and (active = 0 or updated <= current_timestamp - interval 1 hour)",
+        )
+        .bind(file)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn get_upload_state(&self, file: &Vec<u8>) -> Result<Option<UploadState>, Error> {
+        if self.get_file(file).await?.is_some() {
+            return Ok(Some(UploadState::Available));
+        }
+        sqlx::query_scalar(
+            "select state from upload_states \
+            where id = ? and updated > current_timestamp - interval 1 hour",
+        )
+        .bind(file)
+        .fetch_optional(&self.pool)
+        .await
+    }
+
+    pub async fn set_torrent_info_hash(
+        &self,
+        file: &Vec<u8>,
+        info_hash: &Vec<u8>,
+    ) -> Result<(), Error> {
+        sqlx::query("update uploads set torrent_info_hash = ? where id = ?")
+            .bind(info_hash)
+            .bind(file)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn update_file_metadata(
+        &self,
+        file: &Vec<u8>,
+        mime_type: &str,
+        width: Option<u32>,
+        height: Option<u32>,
+    ) -> Result<(), Error> {
+        let q = if width.is_some() {
+            sqlx::query(
+                "update uploads set mime_type = ?, width = ?, height = ?, dim_source = 'server' \
+                where id = ?",
+            )
+            .bind(mime_type)
+            .bind(width)
+            .bind(height)
+        } else {
+            // keep client dimensions, the server still can't compute them
+            sqlx::query(
+                "update uploads set mime_type = ?, \
+                width = if(dim_source = 'client', width, null), \
+                height = if(dim_source = 'client', height, null), \
+                dim_source = if(dim_source = 'client', dim_source, null) \
+                where id = ?",
+            )
+            .bind(mime_type)
+        };
+        q.bind(file).execute(&self.pool).await?;
+        Ok(())
+    }
+
+    pub async fn list_users(&self, offset: u32, limit: u32) -> Result<(Vec<User>, i64), Error> {
+        let results: Vec<User> =
+            sqlx::query_as("select * from users order by id desc limit ? offset ?")
+                .bind(limit)
+                .bind(offset)
+                .fetch_all(&self.pool)
+                .await?;
+        let count: i64 = sqlx::query("select count(id) from users")
+            .fetch_one(&self.pool)
+            .await?
+            .try_get(0)?;
+        Ok((results, count))
+    }
+
     pub async fn get_file_owners(&self, file: &Vec<u8>) -> Result<Vec<User>, Error> {
         sqlx::query_as(
             "select users.* from users, user_uploads \
@@ -177,6 +559,111 @@ impl Database {
         .await
     }
 
+    /// Most used labels with the number of files, optionally only files owned by `pubkey`
+    #[cfg(feature = "labels")]
+    pub async fn list_top_labels(
+        &self,
+        pubkey: Option<&Vec<u8>>,
+        limit: u32,
+    ) -> Result<Vec<LabelCount>, Error> {
+        sqlx::query_as(
+            "select l.label, count(distinct l.file) as count from upload_labels l \
+            where ? is null or l.file in (\
+                select uu.file from user_uploads uu, users u \
+                where u.pubkey = ? and u.id = uu.user_id) \
+            group by l.label \
+            order by count desc, l.label \
+            limit ?",
+        )
+        .bind(pubkey)
+        .bind(pubkey)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    /// Files with a label, optionally only files owned by `pubkey`
+    #[cfg(feature = "labels")]
+    pub async fn list_files_by_label(
+        &self,
+        label: &str,
+        pubkey: Option<&Vec<u8>>,
+        offset: u32,
+        limit: u32,
+    ) -> Result<(Vec<FileUpload>, i64), Error> {
+        let filter = "from uploads \
+            where uploads.id in (select l.file from upload_labels l where l.label = ?) \
+            and (? is null or uploads.id in (\
+                select uu.file from user_uploads uu, users u \
+                where u.pubkey = ? and u.id = uu.user_id))";
+        let results: Vec<FileUpload> = sqlx::query_as(&format!(
+            "select uploads.* {} order by uploads.created desc limit ? offset ?",
+            filter
+        ))
+        .bind(label)
+        .bind(pubkey)
+        .bind(pubkey)
+        .bind(limit)
+        .bind(offset)
+        .fetch_all(&self.pool)
+        .await?;
+        let count: i64 = sqlx::query(&format!("select count(uploads.id) {}", filter))
+            .bind(label)
+            .bind(pubkey)
+            .bind(pubkey)
+            .fetch_one(&self.pool)
+            .await?
+            .try_get(0)?;
+        Ok((results, count))
+    }
+
+    /// Remove labels which point to files that no longer exist
+    #[cfg(feature = "labels")]
+    pub async fn delete_orphan_labels(&self) -> Result<u64, Error> {
+        let res = sqlx::query(
+            "delete l from upload_labels l \
+            left join uploads u on u.id = l.file \
+            where u.id is null",
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(res.rows_affected())
+    }
+
+    /// Extend the expiry of an upload, None removes the expiry
+    pub async fn extend_file_expiry(
+        &self,
+        file: &Vec<u8>,
+        expires: Option<DateTime<Utc>>,
+    ) -> Result<(), Error> {
+        sqlx::query(EXTEND_EXPIRY)
+            .bind(expires)
+            .bind(expires)
+            .bind(file)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn list_expired_files(&self, limit: u32) -> Result<Vec<Vec<u8>>, Error> {
+        sqlx::query_scalar(
+            "select id from uploads where expires is not null and expires < current_timestamp \
+            and legal_hold = 0 limit ?",
+        )
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    pub async fn set_user_tier(&self, id: u64, tier: Option<&str>) -> Result<(), Error> {
+        sqlx::query("update users set tier = ? where id = ?")
+            .bind(tier)
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
     pub async fn delete_file_owner(&self, file: &Vec<u8>, owner: u64) -> Result<(), Error> {
         sqlx::query("delete from user_uploads where file = ? and user_id = ?")
             .bind(file)
@@ -199,12 +686,150 @@ impl Database {
             .bind(file)
             .execute(&self.pool)
             .await?;
+        sqlx::query("delete from processing_reports where file = ?")
+            .bind(file)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn add_processing_report(&self, report: &ProcessingReport) -> Result<(), Error> {
+        sqlx::query(
+            "insert ignore into \
+            processing_reports(file,source,source_size,source_mime_type,params,duration_ms,quality) \
+            values(?,?,?,?,?,?,?)",
+        )
+        .bind(&report.file)
+        .bind(&report.source)
+        .bind(report.source_size)
+        .bind(&report.source_mime_type)
+        .bind(&report.params)
+        .bind(report.duration_ms)
+        .bind(report.quality)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Record the app used by a user to upload a file
+    pub async fn set_upload_client(
+        &self,
+        file: &Vec<u8>,
+        user_id: u64,
+        user_agent: Option<&str>,
+        client: Option<&str>,
+    ) -> Result<(), Error> {
+        sqlx::query(
+            "update user_uploads set user_agent = ?, client = ? where file = ? and user_id = ?",
+        )
+        .bind(user_agent)
+        .bind(client)
+        .bind(file)
+        .bind(user_id)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn list_upload_clients(&self, file: &Vec<u8>) -> Result<Vec<UploadClient>, Error> {
+        sqlx::query_as(
+            "select us.pubkey, uu.user_agent, uu.client, uu.created \
+            from user_uploads uu join users us on us.id = uu.user_id \
+            where uu.file = ? and (uu.user_agent is not null or uu.client is not null)",
+        )
+        .bind(file)
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    pub async fn get_processing_report(
+        &self,
+        file: &Vec<u8>,
+    ) -> Result<Option<ProcessingReport>, Error> {
+        sqlx::query_as("select * from processing_reports where file = ?")
+            .bind(file)
+            .fetch_optional(&self.pool)
+            .await
+    }
+
+    /// Record that `derived` was generated from `source` using `params`
+    pub async fn add_derivation(
+        &self,
+        source: &Vec<u8>,
+        params: &str,
+        derived: &Vec<u8>,
+    ) -> Result<(), Error> {
+        sqlx::query(
+            "insert into upload_derivations(source,params,derived) values(?,?,?) \
+            on duplicate key update derived = values(derived), created = current_timestamp",
+        )
+        .bind(source)
+        .bind(params)
+        .bind(derived)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Get the stored file previously generated from `source` using `params`
+    pub async fn get_derived_file(
+        &self,
+        source: &Vec<u8>,
+        params: &str,
+    ) -> Result<Option<FileUpload>, Error> {
+        sqlx::query_as(
+            "select uploads.* from upload_derivations, uploads \
+            where upload_derivations.source = ? \
+            and upload_derivations.params = ? \
+            and uploads.id = upload_derivations.derived",
+        )
+        .bind(source)
+        .bind(params)
+        .fetch_optional(&self.pool)
+        .await
+    }
+
+    /// List all files generated from `source`
+    pub async fn list_derivations(&self, source: &Vec<u8>) -> Result<Vec<Derivation>, Error> {
+        sqlx::query_as("select * from upload_derivations where source = ? order by created")
+            .bind(source)
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    /// Files generated using `params` from the files of a user, by source
+    pub async fn list_user_derivations(
+        &self,
+        pubkey: &Vec<u8>,
+        params: &str,
+    ) -> Result<HashMap<Vec<u8>, Vec<u8>>, Error> {
+        let rows: Vec<(Vec<u8>, Vec<u8>)> = sqlx::query_as(
+            "select d.source, d.derived from upload_derivations d, users u, user_uploads uu \
+            where u.pubkey = ? and uu.user_id = u.id \
+            and d.source = uu.file and d.params = ?",
+        )
+        .bind(pubkey)
+        .bind(params)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows.into_iter().collect())
+    }
+
+    /// Remove all derivations where the file is either the source or the result
+    pub async fn delete_derivations(&self, file: &Vec<u8>) -> Result<(), Error> {
+        sqlx::query("delete from upload_derivations where source = ? or derived = ?")
+            .bind(file)
+            .bind(file)
+            .execute(&self.pool)
+            .await?;
         Ok(())
     }
 
+    /// List the files of a user, newest first, optionally only those with a license
     pub async fn list_files(
         &self,
         pubkey: &Vec<u8>,
+        license: Option<&str>,
         offset: u32,
         limit: u32,
     ) -> Result<(Vec<FileUpload>, i64), Error> {
@@ -213,10 +838,13 @@ impl Database {
             where users.pubkey = ? \
             and users.id = user_uploads.user_id \
             and user_uploads.file = uploads.id \
+            and (? is null or uploads.license = ?) \
             order by uploads.created desc \
             limit ? offset ?",
         )
         .bind(pubkey)
+        .bind(license)
+        .bind(license)
         .bind(limit)
         .bind(offset)
         .fetch_all(&self.pool)
@@ -225,13 +853,256 @@ impl Database {
             "select count(uploads.id) from uploads, users, user_uploads \
             where users.pubkey = ? \
             and users.id = user_uploads.user_id \
-            and user_uploads.file = uploads.id",
+            and user_uploads.file = uploads.id \
+            and (? is null or uploads.license = ?)",
         )
         .bind(pubkey)
+        .bind(license)
+        .bind(license)
         .fetch_one(&self.pool)
         .await?
         .try_get(0)?;
 
         Ok((results, count))
     }
+
+    pub async fn enqueue_job(&self, kind: &str, payload: &str) -> Result<u64, Error> {
+        let res = sqlx::query("insert into jobs(kind,payload) values(?,?)")
+            .bind(kind)
+            .bind(payload)
+            .execute(&self.pool)
+            .await?;
+        Ok(res.last_insert_id())
+    }
+
+    /// Check if a job with the same payload was queued before and did not complete
+    pub async fn job_exists(&self, kind: &str, payload: &str) -> Result<bool, Error> {
+        let n: i64 = sqlx::query_scalar(
+            "select count(*) from jobs where kind = ? and payload = ? and status != 'complete'",
+        )
+        .bind(kind)
+        .bind(payload)
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(n > 0)
+    }
+
+    pub async fn get_job(&self, id: u64) -> Result<Option<Job>, Error> {
+        sqlx::query_as("select * from jobs where id = ?")
+            .bind(id)
+            .fetch_optional(&self.pool)
+            .await
+    }
+
+    /// List queued jobs of a given kind which are ready to run
+    pub async fn list_runnable_jobs(&self, kind: &str, limit: u32) -> Result<Vec<Job>, Error> {
+        sqlx::query_as(
+            "select * from jobs \
+            where kind = ? and status = 'queued' and run_after <= current_timestamp \
+            order by id \
+            limit ?",
+        )
+        .bind(kind)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    /// Mark a queued job as running, returns false if another worker claimed it first
+    pub async fn claim_job(&self, id: u64) -> Result<bool, Error> {
+        let res = sqlx::query(
+            "update jobs set status = 'running', attempts = attempts + 1 \
+            where id = ? and status = 'queued'",
+        )
+        .bind(id)
+        .execute(&self.pool)
+        .await?;
+        Ok(res.rows_affected() == 1)
+    }
+
+    pub async fn complete_job(&self, id: u64) -> Result<(), Error> {
+        sqlx::query("update jobs set status = 'complete', last_error = null where id = ?")
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Record a job failure, re-queueing it at `retry_at` or failing it permanently
+    pub async fn fail_job(
+        &self,
+        id: u64,
+        error: &str,
+        retry_at: Option<DateTime<Utc>>,
+    ) -> Result<(), Error> {
+        match retry_at {
+            Some(t) => {
+                sqlx::query(
+                    "update jobs set status = 'queued', last_error = ?, run_after = ? where id = ?",
+                )
+                .bind(error)
+                .bind(t)
+                .bind(id)
+                .execute(&self.pool)
+                .await?
+            }
+            None => {
+                sqlx::query("update jobs set status = 'failed', last_error = ? where id = ?")
+                    .bind(error)
+                    .bind(id)
+                    .execute(&self.pool)
+                    .await?
+            }
+        };
+        Ok(())
+    }
+
+    /// Put failed jobs back in the queue
+    pub async fn retry_job(&self, id: u64) -> Result<bool, Error> {
+        let res = sqlx::query(
+            "update jobs set status = 'queued', attempts = 0, run_after = current_timestamp \
+            where id = ? and status = 'failed'",
+        )
+        .bind(id)
+        .execute(&self.pool)
+        .await?;
+        Ok(res.rows_affected() == 1)
+    }
+
+    /// Cancel a job which has not started, returns false if it is not queued
+    pub async fn cancel_queued_job(&self, id: u64) -> Result<bool, Error> {
+        let res =
+            sqlx::query("update jobs set status = 'cancelled' where id = ? and status = 'queued'")
+                .bind(id)
+                .execute(&self.pool)
+                .await?;
+        Ok(res.rows_affected() == 1)
+    }
+
+    /// Mark a running job as cancelled once its handler stopped
+    pub async fn set_job_cancelled(&self, id: u64) -> Result<(), Error> {
+        sqlx::query("update jobs set status = 'cancelled' where id = ?")
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Re-queue jobs left in the running state, used after a restart
+    pub async fn reset_running_jobs(&self) -> Result<u64, Error> {
+        let res = sqlx::query("update jobs set status = 'queued' where status = 'running'")
+            .execute(&self.pool)
+            .await?;
+        Ok(res.rows_affected())
+    }
+
+    pub async fn list_jobs(
+        &self,
+        status: Option<JobStatus>,
+        offset: u32,
+        limit: u32,
+    ) -> Result<(Vec<Job>, i64), Error> {
+        let results: Vec<Job> = sqlx::query_as(
+            "select * from jobs \
+            where (? is null or status = ?) \
+            order by id desc \
+            limit ? offset ?",
+        )
+        .bind(status)
+        .bind(status)
+        .bind(limit)
+        .bind(offset)
+        .fetch_all(&self.pool)
+        .await?;
+        let count: i64 = sqlx::query("select count(id) from jobs where (? is null or status = ?)")
+            .bind(status)
+            .bind(status)
+            .fetch_one(&self.pool)
+            .await?
+            .try_get(0)?;
+        Ok((results, count))
+    }
+
+    /// List files which have gone the longest without an integrity check, whatever its result
+    pub async fn list_files_to_verify(&self, limit: u32) -> Result<Vec<Vec<u8>>, Error> {
+        sqlx::query_scalar("select id from uploads order by scrubbed asc limit ?")
+            .bind(limit)
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    /// Record that the scrubber checked a file, also when it was missing or corrupt,
+    /// so bad files do not keep the rest from being checked
+    pub async fn set_file_scrubbed(&self, file: &Vec<u8>) -> Result<(), Error> {
+        sqlx::query("update uploads set scrubbed = current_timestamp where id = ?")
+            .bind(file)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn set_file_verified(&self, file: &Vec<u8>) -> Result<(), Error> {
+        sqlx::query("update uploads set verified = current_timestamp where id = ?")
+            .bind(file)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn set_file_blake3(&self, file: &Vec<u8>, blake3: &[u8]) -> Result<(), Error> {
+        sqlx::query("update uploads set blake3 = ? where id = ?")
+            .bind(blake3)
+            .bind(file)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Record a corrupt or missing file, a file which is still corrupt keeps the time it
+    /// was first detected
+    pub async fn add_corrupt_file(
+        &self,
+        file: &Vec<u8>,
+        actual_hash: Option<&Vec<u8>>,
+    ) -> Result<(), Error> {
+        sqlx::query(
+            "insert into corrupt_files(file,actual_hash) values(?,?) \
+            on duplicate key update actual_hash = values(actual_hash), \
+            detected = if(restored is null, detected, current_timestamp), restored = null",
+        )
+        .bind(file)
+        .bind(actual_hash)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn set_corrupt_file_restored(&self, file: &Vec<u8>) -> Result<(), Error> {
+        sqlx::query("update corrupt_files set restored = current_timestamp where file = ?")
+            .bind(file)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn list_corrupt_files(
+        &self,
+        offset: u32,
+        limit: u32,
+    ) -> Result<(Vec<CorruptFile>, i64), Error> {
+        let results: Vec<CorruptFile> = sqlx::query_as(
+            "select * from corrupt_files \
+            order by detected desc \
+            limit ? offset ?",
+        )
+        .bind(limit)
+        .bind(offset)
+        .fetch_all(&self.pool)
+        .await?;
+        let count: i64 = sqlx::query("select count(file) from corrupt_files")
+            .fetch_one(&self.pool)
+            .await?
+            .try_get(0)?;
+        Ok((results, count))
+    }
 }
@@ -0,0 +1,347 @@
+use std::io::ErrorKind;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use anyhow::{bail, Error};
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use nostr::PublicKey;
+use serde::Serialize;
+use serde_with::{hex::Hex, serde_as};
+use sqlx::FromRow;
+
+use crate::background::replication::{is_trusted_peer, propagate_delete};
+#[cfg(feature = "torrent-v2")]
+use crate::background::torrent::torrent_path;
+use crate::db::Database;
+use crate::filesystem::FileStore;
+use crate::hooks;
+use crate::legal_hold::{LegalHoldAction, OnLegalHold};
+use crate::routes::error::{ApiError, ErrorCode};
+use crate::settings::Settings;
+
+/// Why a file or an ownership was removed, used in the logs, metrics and audit trail
+#[derive(Debug, Clone, Copy, PartialEq, Eq, sqlx::Type, Serialize)]
+#[sqlx(rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum DeleteReason {
+    /// An owner deleted their upload
+    Owner,
+    /// An admin or the admin APIs (bulk jobs, gRPC)
+    Admin,
+    /// A trusted replication peer
+    Peer,
+    /// The retention policy
+    Retention,
+    /// A NIP-09 deletion request
+    Nostr,
+}
+
+impl DeleteReason {
+    pub const ALL: [DeleteReason; 5] = [
+        DeleteReason::Owner,
+        DeleteReason::Admin,
+        DeleteReason::Peer,
+        DeleteReason::Retention,
+        DeleteReason::Nostr,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DeleteReason::Owner => "owner",
+            DeleteReason::Admin => "admin",
+            DeleteReason::Peer => "peer",
+            DeleteReason::Retention => "retention",
+            DeleteReason::Nostr => "nostr",
+        }
+    }
+}
+
+/// What a delete removed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, sqlx::Type, Serialize)]
+#[sqlx(rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum DeleteOutcome {
+    /// Only the ownership was removed, other owners keep the file
+    OwnerRemoved,
+    /// The file was removed from storage
+    Deleted,
+}
+
+/// Entry of the delete audit trail, kept after the file is gone
+#[serde_as]
+#[derive(Clone, Debug, FromRow, Serialize)]
+pub struct DeleteEvent {
+    pub id: u64,
+    #[serde(with = "hex")]
+    pub file: Vec<u8>,
+    pub outcome: DeleteOutcome,
+    pub reason: DeleteReason,
+    /// Owner, admin or peer who requested the delete, None for retention
+    #[serde_as(as = "Option<Hex>")]
+    pub pubkey: Option<Vec<u8>>,
+    pub created: DateTime<Utc>,
+}
+
+impl Database {
+    pub async fn add_delete_event(
+        &self,
+        file: &Vec<u8>,
+        outcome: DeleteOutcome,
+        reason: DeleteReason,
+        pubkey: Option<&[u8]>,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query("insert into delete_log(file,outcome,reason,pubkey) values(?,?,?,?)")
+            .bind(file)
+            .bind(outcome)
+            .bind(reason)
+            .bind(pubkey)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn list_delete_events(
+        &self,
+        file: &Vec<u8>,
+    ) -> Result<Vec<DeleteEvent>, sqlx::Error> {
+        sqlx::query_as("select * from delete_log where file = ? order by id")
+            .bind(file)
+            .fetch_all(&self.pool)
+            .await
+    }
+}
+
+/// Deletes by reason
+pub struct DeletionStats {
+    /// Files removed from storage, including derived files
+    pub files: AtomicU64,
+    /// Ownerships removed while other owners kept the file
+    pub owners: AtomicU64,
+}
+
+impl DeletionStats {
+    const fn new() -> Self {
+        Self {
+            files: AtomicU64::new(0),
+            owners: AtomicU64::new(0),
+        }
+    }
+}
+
+static STATS: [DeletionStats; 5] = [
+    DeletionStats::new(),
+    DeletionStats::new(),
+    DeletionStats::new(),
+    DeletionStats::new(),
+    DeletionStats::new(),
+];
+
+pub fn stats(reason: DeleteReason) -> &'static DeletionStats {
+    &STATS[reason as usize]
+}
+
+/// Removes files and ownerships with the same rules and events for every caller:
+/// derived files nobody owns are removed with their source, the post-delete hook runs,
+/// replication peers are told and each removal is logged and kept in the audit trail
+pub struct DeletionService<'a> {
+    db: &'a Database,
+    fs: &'a FileStore,
+    settings: &'a Settings,
+}
+
+impl<'a> DeletionService<'a> {
+    pub fn new(db: &'a Database, fs: &'a FileStore, settings: &'a Settings) -> Self {
+        Self { db, fs, settings }
+    }
+
+    /// Delete a file as requested by a pubkey.
+    ///
+    /// Admins and trusted peers remove the file for everyone, owners only remove their
+    /// ownership unless they are the last owner.
+    pub async fn delete_as(
+        &self,
+        id: &Vec<u8>,
+        pubkey: &PublicKey,
+    ) -> Result<DeleteOutcome, ApiError> {
+        let pubkey_vec = pubkey.to_bytes().to_vec();
+        match self.db.get_file(id).await {
+            Ok(Some(f)) if f.legal_hold => {
+                self.log_blocked(id, Some(&pubkey_vec)).await;
+                return Err(ErrorCode::LegalHold.into());
+            }
+            Ok(Some(_)) => {}
+            Ok(None) => return Err(ErrorCode::NotFound.into()),
+            Err(e) => return Err(ApiError::internal(e.to_string())),
+        }
+        let trusted_peer = is_trusted_peer(self.settings, &pubkey.to_hex());
+        let is_admin = match self.db.get_user(&pubkey_vec).await {
+            Ok(u) => u.is_admin,
+            Err(_) if trusted_peer => false,
+            Err(_) => return Err(ErrorCode::NotOwner.into()),
+        };
+        let res = if is_admin {
+            self.purge(id, Some(&pubkey_vec), DeleteReason::Admin)
+                .await
+                .map(|_| DeleteOutcome::Deleted)
+        } else if trusted_peer {
+            self.purge(id, Some(&pubkey_vec), DeleteReason::Peer)
+                .await
+                .map(|_| DeleteOutcome::Deleted)
+        } else {
+            match self
+                .remove_owner(id, &pubkey_vec, DeleteReason::Owner)
+                .await
+            {
+                Ok(None) => return Err(ErrorCode::NotOwner.into()),
+                Ok(Some(o)) => Ok(o),
+                Err(e) => Err(e),
+            }
+        };
+        res.map_err(|e| ApiError::internal(format!("Failed to delete: {}", e)))
+    }
+
+    /// Remove the ownership of a pubkey, the file is removed when it was the last owner.
+    ///
+    /// Returns None when the pubkey does not own the file
+    pub async fn remove_owner(
+        &self,
+        id: &Vec<u8>,
+        pubkey: &[u8],
+        reason: DeleteReason,
+    ) -> Result<Option<DeleteOutcome>, Error> {
+        let owners = self.db.get_file_owners(id).await?;
+        let owner = match owners.iter().find(|o| o.pubkey == pubkey) {
+            Some(o) => o,
+            None => return Ok(None),
+        };
+        if self.db.get_file(id).await?.is_some_and(|f| f.legal_hold) {
+            self.log_blocked(id, Some(pubkey)).await;
+            bail!(OnLegalHold(id.clone()));
+        }
+        if owners.len() == 1 {
+            self.purge(id, Some(pubkey), reason).await?;
+            return Ok(Some(DeleteOutcome::Deleted));
+        }
+        self.db.delete_file_owner(id, owner.id).await?;
+        stats(reason).owners.fetch_add(1, Ordering::Relaxed);
+        self.log_event(id, DeleteOutcome::OwnerRemoved, reason, Some(pubkey))
+            .await;
+        info!(
+            "Removed owner {} of {} ({})",
+            hex::encode(pubkey),
+            hex::encode(id),
+            reason.as_str()
+        );
+        Ok(Some(DeleteOutcome::OwnerRemoved))
+    }
+
+    /// Remove a file and all of its owners, queueing deletes on replication peers.
+    ///
+    /// Files derived from it which nobody else owns are removed too, unless they are under
+    /// legal hold. Fails with [OnLegalHold] when the file is held. Returns the number of
+    /// files removed
+    pub async fn purge(
+        &self,
+        id: &Vec<u8>,
+        pubkey: Option<&[u8]>,
+        reason: DeleteReason,
+    ) -> Result<u64, Error> {
+        let mut removed = 0;
+        let mut pending = vec![id.clone()];
+        while let Some(id) = pending.pop() {
+            let info = self.db.get_file(&id).await?;
+            if info.as_ref().is_some_and(|i| i.legal_hold) {
+                // the requested file, held derived files are only skipped
+                if removed == 0 {
+                    self.log_blocked(&id, pubkey).await;
+                    bail!(OnLegalHold(id));
+                }
+                warn!("Kept {} which is under legal hold", hex::encode(&id));
+                continue;
+            }
+            for d in self.db.list_derivations(&id).await? {
+                if d.derived != id && self.db.get_file_owners(&d.derived).await?.is_empty() {
+                    pending.push(d.derived);
+                }
+            }
+            // removed from storage first, so a failure leaves the file listed to retry
+            match tokio::fs::remove_file(self.fs.get(&id)).await {
+                Ok(_) => {}
+                Err(e) if e.kind() == ErrorKind::NotFound => {
+                    warn!("Deleted {} was missing from storage", hex::encode(&id))
+                }
+                Err(e) => return Err(e.into()),
+            }
+            #[cfg(feature = "torrent-v2")]
+            self.remove_torrent(&id).await;
+            self.db.delete_derivations(&id).await?;
+            self.db.delete_short_link(&id).await?;
+            self.db.delete_upload_state(&id).await?;
+            self.db.delete_all_file_owner(&id).await?;
+            self.db.delete_file(&id).await?;
+            if let Some(info) = info {
+                hooks::post_delete(self.settings, &info, pubkey);
+            }
+            if let Err(e) = propagate_delete(self.db, self.settings, &id).await {
+                warn!("Failed to queue peer deletes: {}", e);
+            }
+            stats(reason).files.fetch_add(1, Ordering::Relaxed);
+            self.log_event(&id, DeleteOutcome::Deleted, reason, pubkey)
+                .await;
+            info!("Deleted {} ({})", hex::encode(&id), reason.as_str());
+            removed += 1;
+        }
+        Ok(removed)
+    }
+
+    /// Remove the generated torrent and its copy and link in the seed directory
+    #[cfg(feature = "torrent-v2")]
+    async fn remove_torrent(&self, id: &[u8]) {
+        let hex_id = hex::encode(id);
+        let mut paths = vec![torrent_path(self.settings, id)];
+        if let Some(seed_dir) = self
+            .settings
+            .torrent
+            .as_ref()
+            .and_then(|t| t.seed_dir.as_ref())
+        {
+            paths.push(seed_dir.join(format!("{}.torrent", hex_id)));
+            paths.push(seed_dir.join(&hex_id));
+        }
+        for p in paths {
+            match tokio::fs::remove_file(&p).await {
+                Ok(_) => {}
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => warn!("Failed to remove {}: {}", p.display(), e),
+            }
+        }
+    }
+
+    /// Record a removal in the delete audit trail
+    async fn log_event(
+        &self,
+        id: &Vec<u8>,
+        outcome: DeleteOutcome,
+        reason: DeleteReason,
+        pubkey: Option<&[u8]>,
+    ) {
+        if let Err(e) = self.db.add_delete_event(id, outcome, reason, pubkey).await {
+            warn!("Failed to log delete of {}: {}", hex::encode(id), e);
+        }
+    }
+
+    /// Record a refused delete of a held file in its audit trail
+    async fn log_blocked(&self, id: &Vec<u8>, pubkey: Option<&[u8]>) {
+        warn!(
+            "Refused to delete {} which is under legal hold",
+            hex::encode(id)
+        );
+        if let Err(e) = self
+            .db
+            .add_legal_hold_event(id, LegalHoldAction::Blocked, pubkey, None)
+            .await
+        {
+            warn!("Failed to log refused delete: {}", e);
+        }
+    }
+}
@@ -0,0 +1,539 @@
+//! Encryption of stored files at rest.
+//!
+//! Each file is encrypted with its own random key, which is stored in the file header
+//! wrapped by the master key. Rotating the master key only rewrites the headers.
+//!
+//! Layout: `R96E | version | master key id (8) | nonce (24) | wrapped file key (48)`
+//! followed by the data in 64KiB chunks, each sealed with ChaCha20-Poly1305 using the
+//! chunk index (and a flag for the last chunk) as the nonce.
+//!
+//! Uploads can start with the magic too, a file only counts as encrypted when the file key in
+//! its header unwraps with one of the master keys, which user content can't produce.
+use std::fs;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::pin::Pin;
+use std::process::Command;
+use std::sync::OnceLock;
+use std::task::{ready, Context, Poll};
+
+use anyhow::{bail, Error};
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce, XChaCha20Poly1305, XNonce};
+use log::info;
+use sha2::{Digest, Sha256};
+use tokio::fs::File;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, ReadBuf};
+
+use crate::capabilities;
+use crate::settings::EncryptionConfig;
+
+pub const MAGIC: &[u8; 4] = b"R96E";
+const VERSION: u8 = 1;
+const KEY_ID_LEN: usize = 8;
+const WRAP_NONCE_LEN: usize = 24;
+const TAG_LEN: usize = 16;
+const WRAPPED_KEY_LEN: usize = 32 + TAG_LEN;
+pub const HEADER_LEN: usize = MAGIC.len() + 1 + KEY_ID_LEN + WRAP_NONCE_LEN + WRAPPED_KEY_LEN;
+
+/// Plaintext bytes per sealed chunk
+const CHUNK_LEN: usize = 64 * 1024;
+const SEALED_CHUNK_LEN: usize = CHUNK_LEN + TAG_LEN;
+
+static KEYRING: OnceLock<Option<Keyring>> = OnceLock::new();
+
+/// Load the master keys, new files are stored encrypted once this is called with a config
+pub fn init(cfg: Option<&EncryptionConfig>) -> Result<(), Error> {
+    let ring = cfg.map(Keyring::load).transpose()?;
+    if let Some(r) = &ring {
+        info!(
+            "Encryption at rest enabled, master key {}",
+            hex::encode(r.current.id)
+        );
+    }
+    capabilities::register(
+        capabilities::ENCRYPTION,
+        Some("encryption"),
+        ring.is_some(),
+        ring.as_ref().map(|r| hex::encode(r.current.id)),
+    );
+    let _ = KEYRING.set(ring);
+    Ok(())
+}
+
+/// Master keys loaded by [init]
+pub fn keyring() -> Option<&'static Keyring> {
+    KEYRING.get().and_then(|k| k.as_ref())
+}
+
+struct MasterKey {
+    id: [u8; KEY_ID_LEN],
+    cipher: XChaCha20Poly1305,
+}
+
+impl MasterKey {
+    fn from_hex(key: &str) -> Result<Self, Error> {
+        let key = hex::decode(key.trim())?;
+        if key.len() != 32 {
+            bail!("Master key must be 32 bytes (64 hex characters)");
+        }
+        let mut id = [0u8; KEY_ID_LEN];
+        id.copy_from_slice(&Sha256::digest(&key)[..KEY_ID_LEN]);
+        Ok(Self {
+            id,
+            cipher: XChaCha20Poly1305::new(Key::from_slice(&key)),
+        })
+    }
+}
+
+/// Current master key and the previous keys files may still be wrapped with
+pub struct Keyring {
+    current: MasterKey,
+    previous: Vec<MasterKey>,
+}
+
+impl Keyring {
+    pub fn load(cfg: &EncryptionConfig) -> Result<Self, Error> {
+        let current = match (&cfg.master_key, &cfg.key_command) {
+            (Some(k), None) => MasterKey::from_hex(k)?,
+            (None, Some(cmd)) => {
+                let out = Command::new(cmd).output()?;
+                if !out.status.success() {
+                    bail!("Key command {} failed: {}", cmd.display(), out.status);
+                }
+                MasterKey::from_hex(&String::from_utf8(out.stdout)?)?
+            }
+            _ => bail!("Either master_key or key_command must be set"),
+        };
+        let previous = cfg
+            .previous_keys
+            .iter()
+            .flatten()
+            .map(|k| MasterKey::from_hex(k))
+            .collect::<Result<_, _>>()?;
+        Ok(Self { current, previous })
+    }
+
+    fn find(&self, id: &[u8]) -> Option<&MasterKey> {
+        std::iter::once(&self.current)
+            .chain(self.previous.iter())
+            .find(|k| k.id == id)
+    }
+
+    /// New file key and the header storing it
+    fn new_header(&self) -> Result<(ChaCha20Poly1305, [u8; HEADER_LEN]), Error> {
+        let file_key = ChaCha20Poly1305::generate_key(&mut OsRng);
+        let header = self.wrap(&file_key)?;
+        Ok((ChaCha20Poly1305::new(&file_key), header))
+    }
+
+    fn wrap(&self, file_key: &Key) -> Result<[u8; HEADER_LEN], Error> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let wrapped = self
+            .current
+            .cipher
+            .encrypt(&nonce, file_key.as_slice())
+            .map_err(|_| Error::msg("Failed to wrap file key"))?;
+        let mut header = [0u8; HEADER_LEN];
+        let (magic, rest) = header.split_at_mut(MAGIC.len());
+        magic.copy_from_slice(MAGIC);
+        rest[0] = VERSION;
+        let (id, rest) = rest[1..].split_at_mut(KEY_ID_LEN);
+        id.copy_from_slice(&self.current.id);
+        let (n, rest) = rest.split_at_mut(WRAP_NONCE_LEN);
+        n.copy_from_slice(&nonce);
+        rest.copy_from_slice(&wrapped);
+        Ok(header)
+    }
+
+    fn unwrap(&self, header: &[u8; HEADER_LEN]) -> std::io::Result<Key> {
+        if &header[..MAGIC.len()] != MAGIC || header[MAGIC.len()] != VERSION {
+            return Err(invalid_data("Not an encrypted file"));
+        }
+        let id_start = MAGIC.len() + 1;
+        let nonce_start = id_start + KEY_ID_LEN;
+        let key_start = nonce_start + WRAP_NONCE_LEN;
+        let master = self
+            .find(&header[id_start..nonce_start])
+            .ok_or_else(|| invalid_data("File is encrypted with an unknown master key"))?;
+        let key = master
+            .cipher
+            .decrypt(
+                XNonce::from_slice(&header[nonce_start..key_start]),
+                &header[key_start..],
+            )
+            .map_err(|_| invalid_data("Failed to unwrap file key"))?;
+        Ok(*Key::from_slice(&key))
+    }
+
+    /// Check a header was written with one of the master keys
+    fn is_header(&self, header: &[u8; HEADER_LEN]) -> bool {
+        &header[..MAGIC.len()] == MAGIC && self.unwrap(header).is_ok()
+    }
+
+    /// Check if a stored file starts with an encryption header of one of the master keys
+    pub fn is_encrypted(&self, path: &Path) -> std::io::Result<bool> {
+        let mut header = [0u8; HEADER_LEN];
+        let n = read_full(&mut fs::File::open(path)?, &mut header)?;
+        Ok(n == HEADER_LEN && self.is_header(&header))
+    }
+
+    /// Read the encryption header of a file, the position is reset when the file is not encrypted
+    pub async fn read_header(&self, file: &mut File) -> std::io::Result<Option<[u8; HEADER_LEN]>> {
+        let mut header = [0u8; HEADER_LEN];
+        let mut n = 0;
+        while n < HEADER_LEN {
+            let r = file.read(&mut header[n..]).await?;
+            if r == 0 {
+                break;
+            }
+            n += r;
+        }
+        if n == HEADER_LEN && self.is_header(&header) {
+            return Ok(Some(header));
+        }
+        file.seek(SeekFrom::Start(0)).await?;
+        Ok(None)
+    }
+
+    fn cipher(&self, header: &[u8; HEADER_LEN]) -> std::io::Result<ChaCha20Poly1305> {
+        Ok(ChaCha20Poly1305::new(&self.unwrap(header)?))
+    }
+
+    /// Write an encrypted copy of `src` to `dst`
+    pub fn encrypt_file(&self, src: &Path, dst: &Path) -> Result<(), Error> {
+        let res = self.encrypt_file_inner(src, dst);
+        if res.is_err() {
+            let _ = fs::remove_file(dst);
+        }
+        res
+    }
+
+    fn encrypt_file_inner(&self, src: &Path, dst: &Path) -> Result<(), Error> {
+        let mut input = fs::File::open(src)?;
+        let len = input.metadata()?.len();
+        let mut output = fs::File::create(dst)?;
+        let (cipher, header) = self.new_header()?;
+        output.write_all(&header)?;
+        let chunks = chunk_count(len);
+        let mut buf = vec![0u8; CHUNK_LEN];
+        for idx in 0..chunks {
+            let n = plain_chunk_len(len, idx);
+            input.read_exact(&mut buf[..n])?;
+            let sealed = cipher
+                .encrypt(&chunk_nonce(idx, idx + 1 == chunks), &buf[..n])
+                .map_err(|_| Error::msg("Failed to encrypt chunk"))?;
+            output.write_all(&sealed)?;
+        }
+        output.sync_all()?;
+        Ok(())
+    }
+
+    /// Write a decrypted copy of `src` to `dst`
+    pub fn decrypt_file(&self, src: &Path, dst: &Path) -> Result<(), Error> {
+        let mut input = fs::File::open(src)?;
+        let len = match plaintext_len(input.metadata()?.len()) {
+            Some(l) => l,
+            None => bail!("Encrypted file {} is truncated", src.display()),
+        };
+        let mut header = [0u8; HEADER_LEN];
+        input.read_exact(&mut header)?;
+        let cipher = self.cipher(&header)?;
+        let mut output = fs::File::create(dst)?;
+        let chunks = chunk_count(len);
+        let mut buf = vec![0u8; SEALED_CHUNK_LEN];
+        for idx in 0..chunks {
+            let n = plain_chunk_len(len, idx) + TAG_LEN;
+            input.read_exact(&mut buf[..n])?;
+            output.write_all(&open_chunk(&cipher, idx, chunks, &buf[..n])?)?;
+        }
+        Ok(())
+    }
+
+    /// Re-wrap the file key of an encrypted file with the current master key.
+    ///
+    /// Returns false when the file already uses the current key.
+    pub fn rewrap_file(&self, path: &Path) -> Result<bool, Error> {
+        let mut file = fs::File::options().read(true).write(true).open(path)?;
+        let mut header = [0u8; HEADER_LEN];
+        file.read_exact(&mut header)?;
+        if header[MAGIC.len() + 1..MAGIC.len() + 1 + KEY_ID_LEN] == self.current.id {
+            return Ok(false);
+        }
+        let new_header = self.wrap(&self.unwrap(&header)?)?;
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(&new_header)?;
+        file.sync_data()?;
+        Ok(true)
+    }
+
+    /// Read the start of the plaintext of a stored file, encrypted or not
+    pub fn read_head(&self, path: &Path, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut file = fs::File::open(path)?;
+        let len = file.metadata()?.len();
+        let mut header = [0u8; HEADER_LEN];
+        let n = read_full(&mut file, &mut header)?;
+        if n < HEADER_LEN || !self.is_header(&header) {
+            let n = n.min(buf.len());
+            buf[..n].copy_from_slice(&header[..n]);
+            return Ok(n);
+        }
+        let plain_len = plaintext_len(len).ok_or_else(|| invalid_data("Truncated file"))?;
+        let cipher = self.cipher(&header)?;
+        let mut sealed = vec![0u8; plain_chunk_len(plain_len, 0) + TAG_LEN];
+        file.read_exact(&mut sealed)?;
+        let plain = open_chunk(&cipher, 0, chunk_count(plain_len), &sealed)?;
+        let n = plain.len().min(buf.len());
+        buf[..n].copy_from_slice(&plain[..n]);
+        Ok(n)
+    }
+}
+
+/// Size of the plaintext of an encrypted file, None when the size is invalid
+pub fn plaintext_len(stored_len: u64) -> Option<u64> {
+    let body = stored_len.checked_sub(HEADER_LEN as u64)?;
+    let full = body / SEALED_CHUNK_LEN as u64;
+    match body % SEALED_CHUNK_LEN as u64 {
+        0 if full > 0 => Some(full * CHUNK_LEN as u64),
+        r if r >= TAG_LEN as u64 => Some(full * CHUNK_LEN as u64 + r - TAG_LEN as u64),
+        _ => None,
+    }
+}
+
+/// Number of chunks, an empty file still has one (empty) sealed chunk
+fn chunk_count(len: u64) -> u64 {
+    len.div_ceil(CHUNK_LEN as u64).max(1)
+}
+
+fn plain_chunk_len(len: u64, idx: u64) -> usize {
+    (len - idx * CHUNK_LEN as u64).min(CHUNK_LEN as u64) as usize
+}
+
+fn chunk_nonce(idx: u64, last: bool) -> Nonce {
+    let mut nonce = [0u8; 12];
+    nonce[..8].copy_from_slice(&idx.to_be_bytes());
+    nonce[8] = last as u8;
+    *Nonce::from_slice(&nonce)
+}
+
+fn open_chunk(
+    cipher: &ChaCha20Poly1305,
+    idx: u64,
+    chunks: u64,
+    sealed: &[u8],
+) -> std::io::Result<Vec<u8>> {
+    cipher
+        .decrypt(&chunk_nonce(idx, idx + 1 == chunks), sealed)
+        .map_err(|_| invalid_data("Encrypted chunk failed authentication"))
+}
+
+fn read_full(file: &mut fs::File, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut n = 0;
+    while n < buf.len() {
+        match file.read(&mut buf[n..])? {
+            0 => break,
+            r => n += r,
+        }
+    }
+    Ok(n)
+}
+
+fn invalid_data(msg: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, msg.to_string())
+}
+
+enum ReadState {
+    Idle,
+    Seeking(u64),
+    Reading { idx: u64, filled: usize },
+}
+
+/// Decrypts a stored file while reading, seeking is supported for range requests
+pub struct DecryptReader {
+    file: File,
+    cipher: ChaCha20Poly1305,
+    len: u64,
+    pos: u64,
+    state: ReadState,
+    sealed: Vec<u8>,
+    /// Last decrypted chunk (index, plaintext)
+    chunk: Option<(u64, Vec<u8>)>,
+}
+
+impl DecryptReader {
+    pub fn new(
+        ring: &Keyring,
+        file: File,
+        header: &[u8; HEADER_LEN],
+        stored_len: u64,
+    ) -> std::io::Result<Self> {
+        Ok(Self {
+            file,
+            cipher: ring.cipher(header)?,
+            len: plaintext_len(stored_len).ok_or_else(|| invalid_data("Truncated file"))?,
+            pos: 0,
+            state: ReadState::Idle,
+            sealed: vec![0u8; SEALED_CHUNK_LEN],
+            chunk: None,
+        })
+    }
+
+    /// Size of the plaintext
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl AsyncRead for DecryptReader {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if this.pos >= this.len || buf.remaining() == 0 {
+                return Poll::Ready(Ok(()));
+            }
+            let idx = this.pos / CHUNK_LEN as u64;
+            if let Some((i, plain)) = &this.chunk {
+                if *i == idx {
+                    let offset = (this.pos - idx * CHUNK_LEN as u64) as usize;
+                    let n = buf.remaining().min(plain.len() - offset);
+                    buf.put_slice(&plain[offset..offset + n]);
+                    this.pos += n as u64;
+                    return Poll::Ready(Ok(()));
+                }
+            }
+            match this.state {
+                ReadState::Idle => {
+                    let offset = HEADER_LEN as u64 + idx * SEALED_CHUNK_LEN as u64;
+                    Pin::new(&mut this.file).start_seek(SeekFrom::Start(offset))?;
+                    this.state = ReadState::Seeking(idx);
+                }
+                ReadState::Seeking(i) => {
+                    ready!(Pin::new(&mut this.file).poll_complete(cx))?;
+                    this.state = ReadState::Reading { idx: i, filled: 0 };
+                }
+                ReadState::Reading { idx: i, filled } => {
+                    let want = plain_chunk_len(this.len, i) + TAG_LEN;
+                    if filled < want {
+                        let mut rb = ReadBuf::new(&mut this.sealed[filled..want]);
+                        ready!(Pin::new(&mut this.file).poll_read(cx, &mut rb))?;
+                        let n = rb.filled().len();
+                        if n == 0 {
+                            return Poll::Ready(Err(std::io::ErrorKind::UnexpectedEof.into()));
+                        }
+                        this.state = ReadState::Reading {
+                            idx: i,
+                            filled: filled + n,
+                        };
+                        continue;
+                    }
+                    let plain =
+                        open_chunk(&this.cipher, i, chunk_count(this.len), &this.sealed[..want])?;
+                    this.chunk = Some((i, plain));
+                    this.state = ReadState::Idle;
+                }
+            }
+        }
+    }
+}
+
+impl AsyncSeek for DecryptReader {
+    fn start_seek(self: Pin<&mut Self>, position: SeekFrom) -> std::io::Result<()> {
+        let this = self.get_mut();
+        let pos = match position {
+            SeekFrom::Start(p) => Some(p),
+            SeekFrom::End(p) => this.len.checked_add_signed(p),
+            SeekFrom::Current(p) => this.pos.checked_add_signed(p),
+        };
+        match pos {
+            Some(p) => {
+                this.pos = p;
+                Ok(())
+            }
+            None => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Invalid seek position",
+            )),
+        }
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<u64>> {
+        Poll::Ready(Ok(self.pos))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keyring(key: u8) -> Keyring {
+        Keyring::load(&EncryptionConfig {
+            master_key: Some(hex::encode([key; 32])),
+            key_command: None,
+            previous_keys: None,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn encrypted_file_is_detected() {
+        let dir = tempfile::tempdir().unwrap();
+        let (plain, stored) = (dir.path().join("plain"), dir.path().join("stored"));
+        fs::write(&plain, b"hello").unwrap();
+        let ring = keyring(1);
+        ring.encrypt_file(&plain, &stored).unwrap();
+        assert!(ring.is_encrypted(&stored).unwrap());
+        assert!(!ring.is_encrypted(&plain).unwrap());
+        let mut buf = [0u8; 16];
+        let n = ring.read_head(&stored, &mut buf).unwrap();
+        assert_eq!(&buf[..n], b"hello");
+    }
+
+    #[test]
+    fn plaintext_starting_with_magic_is_not_encrypted() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("upload");
+        let ring = keyring(1);
+        // the header of a real key id, as published in the capabilities, without its key
+        let mut data = MAGIC.to_vec();
+        data.push(VERSION);
+        data.extend_from_slice(&ring.current.id);
+        data.resize(HEADER_LEN + 100, b'x');
+        fs::write(&path, &data).unwrap();
+
+        assert!(!ring.is_encrypted(&path).unwrap());
+        let mut buf = vec![0u8; data.len()];
+        let n = ring.read_head(&path, &mut buf).unwrap();
+        assert_eq!(&buf[..n], &data[..n]);
+    }
+
+    #[test]
+    fn file_of_other_master_key_is_not_encrypted() {
+        let dir = tempfile::tempdir().unwrap();
+        let (plain, stored) = (dir.path().join("plain"), dir.path().join("stored"));
+        fs::write(&plain, b"hello").unwrap();
+        keyring(1).encrypt_file(&plain, &stored).unwrap();
+        assert!(!keyring(2).is_encrypted(&stored).unwrap());
+    }
+
+    #[tokio::test]
+    async fn read_header_resets_plaintext() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("upload");
+        let mut data = MAGIC.to_vec();
+        data.resize(HEADER_LEN * 2, 0);
+        fs::write(&path, &data).unwrap();
+        let mut file = File::open(&path).await.unwrap();
+        assert!(keyring(1).read_header(&mut file).await.unwrap().is_none());
+        let mut read = Vec::new();
+        file.read_to_end(&mut read).await.unwrap();
+        assert_eq!(read, data);
+    }
+}
@@ -1,34 +1,331 @@
 use std::env::temp_dir;
+use std::fmt::{Display, Formatter};
 use std::fs;
 use std::io::SeekFrom;
 use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{ready, Context, Poll};
 use std::time::SystemTime;
 
-use anyhow::Error;
+use anyhow::{bail, Error};
 use chrono::Utc;
 use ffmpeg_rs_raw::DemuxerInfo;
-use log::info;
+use log::{info, warn};
 use rocket::form::validate::Contains;
 use serde::Serialize;
+use serde_with::hex::Hex;
+use serde_with::serde_as;
 use sha2::{Digest, Sha256};
 use tokio::fs::File;
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, ReadBuf};
 
 #[cfg(feature = "labels")]
 use crate::db::FileLabel;
-use crate::db::FileUpload;
+#[cfg(feature = "media-compression")]
+use crate::db::MetadataSource;
+use crate::db::{Database, FileUpload, ProcessingReport};
+#[cfg(feature = "encryption")]
+use crate::encryption::{self, DecryptReader};
 #[cfg(feature = "labels")]
 use crate::processing::labeling::label_frame;
 #[cfg(feature = "media-compression")]
-use crate::processing::{compress_file, probe_file, FileProcessorResult};
-use crate::settings::Settings;
+use crate::processing::{
+    compress_file, probe_file, FileProcessorResult, MediaLimits, COMPRESS_PARAMS,
+};
+use crate::queue::ProcessingQueue;
+use crate::settings::{Settings, StorageRoot};
 
+#[serde_as]
 #[derive(Clone, Default, Serialize)]
 pub struct FileSystemResult {
     pub path: PathBuf,
     pub upload: FileUpload,
+    /// Hash of the received data when the stored file was derived from it
+    #[serde_as(as = "Option<Hex>")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source: Option<Vec<u8>>,
+    /// Set when media processing produced a new file
+    #[serde(skip)]
+    pub report: Option<ProcessingReport>,
+    /// Set when the file was already stored and the upload reused it
+    #[serde(skip)]
+    pub dedup: bool,
+}
+
+/// Uploads which reused an already stored file
+pub struct DedupStats {
+    pub hits: AtomicU64,
+    /// Bytes not written again because the file was already stored
+    pub bytes: AtomicU64,
+}
+
+static DEDUP_STATS: DedupStats = DedupStats {
+    hits: AtomicU64::new(0),
+    bytes: AtomicU64::new(0),
+};
+
+pub fn dedup_stats() -> &'static DedupStats {
+    &DEDUP_STATS
+}
+
+/// Upload stream went over the size limit
+#[derive(Debug)]
+pub struct FileTooLarge;
+
+impl Display for FileTooLarge {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "File too large")
+    }
+}
+
+impl std::error::Error for FileTooLarge {}
+
+impl FileTooLarge {
+    pub fn io_error() -> std::io::Error {
+        std::io::Error::new(std::io::ErrorKind::Other, FileTooLarge)
+    }
+
+    /// Check if storing a file failed because of [FileTooLarge]
+    pub fn is(e: &Error) -> bool {
+        e.chain().any(|c| {
+            c.is::<FileTooLarge>()
+                || c.downcast_ref::<std::io::Error>()
+                    .and_then(|e| e.get_ref())
+                    .is_some_and(|e| e.is::<FileTooLarge>())
+        })
+    }
+}
+
+/// Fails with [FileTooLarge] once more than `limit` bytes were read, instead of
+/// silently truncating the stream
+pub struct SizeLimited<R> {
+    inner: R,
+    remaining: u64,
+}
+
+impl<R> SizeLimited<R> {
+    pub fn new(inner: R, limit: u64) -> Self {
+        Self {
+            inner,
+            remaining: limit,
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for SizeLimited<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let before = buf.filled().len();
+        ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+        let n = (buf.filled().len() - before) as u64;
+        if n > self.remaining {
+            return Poll::Ready(Err(FileTooLarge::io_error()));
+        }
+        self.remaining -= n;
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Upload stream did not match the size declared by the client
+#[derive(Debug, Clone, Copy)]
+pub struct LengthMismatch {
+    pub declared: u64,
+    /// Bytes received, when aborted for going over the declared size only up to that point
+    pub received: u64,
+}
+
+impl Display for LengthMismatch {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if self.received > self.declared {
+            write!(f, "received more than the declared {} bytes", self.declared)
+        } else {
+            write!(
+                f,
+                "received {} of the declared {} bytes",
+                self.received, self.declared
+            )
+        }
+    }
+}
+
+impl std::error::Error for LengthMismatch {}
+
+impl LengthMismatch {
+    pub fn io_error(self) -> std::io::Error {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, self)
+    }
+
+    /// Find the [LengthMismatch] which made storing a file fail
+    pub fn find(e: &Error) -> Option<LengthMismatch> {
+        e.chain().find_map(|c| {
+            c.downcast_ref::<LengthMismatch>().copied().or_else(|| {
+                c.downcast_ref::<std::io::Error>()
+                    .and_then(|e| e.get_ref())
+                    .and_then(|e| e.downcast_ref::<LengthMismatch>())
+                    .copied()
+            })
+        })
+    }
+
+    /// Count the mismatch in [length_mismatch_stats]
+    pub fn record(&self) {
+        let counter = if self.received > self.declared {
+            &LENGTH_MISMATCH_STATS.over
+        } else {
+            &LENGTH_MISMATCH_STATS.under
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Uploads which did not match their declared size
+pub struct LengthMismatchStats {
+    /// Uploads larger than declared
+    pub over: AtomicU64,
+    /// Uploads smaller than declared
+    pub under: AtomicU64,
+}
+
+static LENGTH_MISMATCH_STATS: LengthMismatchStats = LengthMismatchStats {
+    over: AtomicU64::new(0),
+    under: AtomicU64::new(0),
+};
+
+pub fn length_mismatch_stats() -> &'static LengthMismatchStats {
+    &LENGTH_MISMATCH_STATS
+}
+
+/// Compares the bytes read with the size declared by the client.
+///
+/// When `strict`, reading past the declared size fails immediately and ending short of it
+/// fails at the end of the stream, otherwise mismatches are only logged and counted
+pub struct LengthChecked<R> {
+    inner: R,
+    declared: Option<u64>,
+    received: u64,
+    strict: bool,
+    flagged: bool,
+}
+
+impl<R> LengthChecked<R> {
+    /// Without a declared size the stream is passed through unchecked
+    pub fn new(inner: R, declared: Option<u64>, strict: bool) -> Self {
+        Self {
+            inner,
+            declared,
+            received: 0,
+            strict,
+            flagged: false,
+        }
+    }
+
+    fn mismatch(&mut self, declared: u64) -> std::io::Result<()> {
+        self.flagged = true;
+        let m = LengthMismatch {
+            declared,
+            received: self.received,
+        };
+        m.record();
+        if self.strict {
+            return Err(m.io_error());
+        }
+        warn!("Upload size mismatch: {}", m);
+        Ok(())
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for LengthChecked<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let before = buf.filled().len();
+        let capacity = buf.remaining();
+        ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+        let n = (buf.filled().len() - before) as u64;
+        self.received += n;
+        let eof = n == 0 && capacity > 0;
+        if let Some(declared) = self.declared.filter(|_| !self.flagged) {
+            if self.received > declared || (eof && self.received < declared) {
+                self.mismatch(declared)?;
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Stored file opened for reading, decrypted on the fly when stored encrypted
+pub enum BlobReader {
+    Plain(File),
+    #[cfg(feature = "encryption")]
+    Encrypted(Box<DecryptReader>),
+}
+
+impl AsyncRead for BlobReader {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            BlobReader::Plain(f) => Pin::new(f).poll_read(cx, buf),
+            #[cfg(feature = "encryption")]
+            BlobReader::Encrypted(f) => Pin::new(f.as_mut()).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncSeek for BlobReader {
+    fn start_seek(self: Pin<&mut Self>, position: SeekFrom) -> std::io::Result<()> {
+        match self.get_mut() {
+            BlobReader::Plain(f) => Pin::new(f).start_seek(position),
+            #[cfg(feature = "encryption")]
+            BlobReader::Encrypted(f) => Pin::new(f.as_mut()).start_seek(position),
+        }
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<u64>> {
+        match self.get_mut() {
+            BlobReader::Plain(f) => Pin::new(f).poll_complete(cx),
+            #[cfg(feature = "encryption")]
+            BlobReader::Encrypted(f) => Pin::new(f.as_mut()).poll_complete(cx),
+        }
+    }
+}
+
+/// Plaintext path of a stored file for tools which need one (ffmpeg).
+///
+/// Encrypted files are decrypted to a temp file, which is removed on drop.
+pub struct PlainFile {
+    path: PathBuf,
+    temp: bool,
+}
+
+impl PlainFile {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The path is a decrypted copy, not the stored file
+    pub fn is_copy(&self) -> bool {
+        self.temp
+    }
 }
 
+impl Drop for PlainFile {
+    fn drop(&mut self) {
+        if self.temp {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct FileStore {
     settings: Settings,
 }
@@ -38,9 +335,141 @@ impl FileStore {
         Self { settings }
     }
 
-    /// Get a file path by id
+    /// Get a file path by id, searching all storage roots
     pub fn get(&self, id: &Vec<u8>) -> PathBuf {
-        self.map_path(id)
+        if self.settings.storage_roots.is_none() {
+            return self.map_path(id);
+        }
+        self.locate(id)
+            .map(|(_, p)| p)
+            .unwrap_or_else(|| self.map_path(id))
+    }
+
+    /// New files are stored encrypted
+    pub fn encrypts(&self) -> bool {
+        #[cfg(feature = "encryption")]
+        let enabled = encryption::keyring().is_some();
+        #[cfg(not(feature = "encryption"))]
+        let enabled = false;
+        enabled
+    }
+
+    /// Open a stored file for reading
+    pub async fn open(&self, id: &Vec<u8>) -> std::io::Result<BlobReader> {
+        Self::open_path(&self.get(id)).await
+    }
+
+    /// Open a file in the storage tree for reading
+    pub async fn open_path(path: &Path) -> std::io::Result<BlobReader> {
+        #[allow(unused_mut)]
+        let mut file = File::open(path).await?;
+        #[cfg(feature = "encryption")]
+        if let Some(ring) = encryption::keyring() {
+            if let Some(header) = ring.read_header(&mut file).await? {
+                let len = file.metadata().await?.len();
+                let reader = DecryptReader::new(ring, file, &header, len)?;
+                return Ok(BlobReader::Encrypted(Box::new(reader)));
+            }
+        }
+        Ok(BlobReader::Plain(file))
+    }
+
+    /// Plaintext path of a stored file
+    pub fn plain_file(&self, id: &Vec<u8>) -> Result<PlainFile, Error> {
+        let path = self.get(id);
+        #[cfg(feature = "encryption")]
+        if let Some(ring) = encryption::keyring() {
+            if ring.is_encrypted(&path)? {
+                let tmp = Self::map_temp(uuid::Uuid::new_v4());
+                if let Err(e) = ring.decrypt_file(&path, &tmp) {
+                    let _ = fs::remove_file(&tmp);
+                    return Err(e);
+                }
+                return Ok(PlainFile {
+                    path: tmp,
+                    temp: true,
+                });
+            }
+        }
+        Ok(PlainFile { path, temp: false })
+    }
+
+    /// Read the start of a stored file, decrypted when stored encrypted
+    pub fn read_head(path: &Path, buf: &mut [u8]) -> std::io::Result<usize> {
+        #[cfg(feature = "encryption")]
+        if let Some(ring) = encryption::keyring() {
+            return ring.read_head(path, buf);
+        }
+        fs::File::open(path).and_then(|mut f| std::io::Read::read(&mut f, buf))
+    }
+
+    /// Copy a new file into the storage tree, encrypting it when enabled
+    pub fn write_stored(src: &Path, dst: &Path) -> Result<(), Error> {
+        #[cfg(feature = "encryption")]
+        if let Some(ring) = encryption::keyring() {
+            return ring.encrypt_file(src, dst);
+        }
+        fs::copy(src, dst)?;
+        Ok(())
+    }
+
+    /// Find the storage root name (None for storage_dir) and path of a stored file
+    pub fn locate(&self, id: &Vec<u8>) -> Option<(Option<String>, PathBuf)> {
+        let path = self.map_path(id);
+        if path.exists() {
+            return Some((None, path));
+        }
+        self.settings
+            .storage_roots
+            .iter()
+            .flatten()
+            .map(|r| (Some(r.name.clone()), Self::map_path_in(&r.path, id)))
+            .find(|(_, p)| p.exists())
+    }
+
+    /// Storage root a new file is stored in, None for storage_dir
+    pub fn route(&self, mime_type: &str, size: u64) -> Option<&StorageRoot> {
+        self.settings
+            .storage_roots
+            .iter()
+            .flatten()
+            .find(|r| r.matches(mime_type, size))
+    }
+
+    /// Directory of a storage root by name, None for storage_dir
+    pub fn root_dir(&self, root: Option<&str>) -> Result<PathBuf, Error> {
+        match root {
+            None => Ok(PathBuf::from(&self.settings.storage_dir)),
+            Some(n) => match self
+                .settings
+                .storage_roots
+                .iter()
+                .flatten()
+                .find(|r| r.name == n)
+            {
+                Some(r) => Ok(r.path.clone()),
+                None => bail!("Unknown storage root {}", n),
+            },
+        }
+    }
+
+    /// Move a stored file to another storage root, returns the new path
+    pub fn move_file(&self, id: &Vec<u8>, to: Option<&str>) -> Result<PathBuf, Error> {
+        let src = match self.locate(id) {
+            Some((_, p)) => p,
+            None => bail!("File {} not found", hex::encode(id)),
+        };
+        let dst = Self::map_path_in(&self.root_dir(to)?, id);
+        if src == dst {
+            return Ok(dst);
+        }
+        fs::create_dir_all(dst.parent().unwrap())?;
+        // roots are usually on different disks, rename only works on the same filesystem
+        if fs::rename(&src, &dst).is_err() {
+            fs::copy(&src, &dst)?;
+            fs::remove_file(&src)?;
+        }
+        Ok(dst)
     }
 
     /// Store a new file
@@ -53,21 +482,89 @@ impl FileStore {
     where
         S: AsyncRead + Unpin,
     {
-        let result = self
-            .store_compress_file(stream, mime_type, compress)
+        self.put_inner(stream, mime_type, compress, None, None)
+            .await
+    }
+
+    /// Store and compress a new file, waiting for a processing slot once the upload is received.
+    ///
+    /// When the same data was compressed before, the stored result is reused.
+    pub async fn put_queued<S>(
+        &self,
+        stream: S,
+        mime_type: &str,
+        queue: &ProcessingQueue,
+        user: &[u8],
+        db: &Database,
+    ) -> Result<FileSystemResult, Error>
+    where
+        S: AsyncRead + Unpin,
+    {
+        self.put_inner(stream, mime_type, true, Some((queue, user)), Some(db))
+            .await
+    }
+
+    async fn put_inner<S>(
+        &self,
+        stream: S,
+        mime_type: &str,
+        compress: bool,
+        queue: Option<(&ProcessingQueue, &[u8])>,
+        db: Option<&Database>,
+    ) -> Result<FileSystemResult, Error>
+    where
+        S: AsyncRead + Unpin,
+    {
+        let mut result = self
+            .store_compress_file(stream, mime_type, compress, queue, db)
             .await?;
-        let dst_path = self.map_path(&result.upload.id);
-        if dst_path.exists() {
+        let existing = self.locate(&result.upload.id);
+        if existing.is_some() {
+            result.dedup = true;
+            DEDUP_STATS.hits.fetch_add(1, Ordering::Relaxed);
+            DEDUP_STATS
+                .bytes
+                .fetch_add(result.upload.size, Ordering::Relaxed);
+        }
+        if let Some((_, p)) = &existing {
+            if *p == result.path {
+                // existing derived file was reused
+                return Ok(result);
+            }
+        }
+        #[cfg(feature = "media-compression")]
+        if let (Some(db), Some(source)) = (db, &result.source) {
+            if let Err(e) = db
+                .add_derivation(source, COMPRESS_PARAMS, &result.upload.id)
+                .await
+            {
+                log::warn!("Failed to record derivation: {}", e);
+            }
+        }
+        if let (Some(db), Some(report)) = (db, &result.report) {
+            if let Err(e) = db.add_processing_report(report).await {
+                log::warn!("Failed to record processing report: {}", e);
+            }
+        }
+        if let Some((storage, dst_path)) = existing {
             fs::remove_file(result.path)?;
+            result.upload.storage = storage;
             return Ok(FileSystemResult {
                 path: dst_path,
                 ..result
             });
         }
+        let dst_path = match self.route(&result.upload.mime_type, result.upload.size) {
+            Some(r) => {
+                result.upload.storage = Some(r.name.clone());
+                Self::map_path_in(&r.path, &result.upload.id)
+            }
+            None => self.map_path(&result.upload.id),
+        };
         fs::create_dir_all(dst_path.parent().unwrap())?;
-        if let Err(e) = fs::copy(&result.path, &dst_path) {
+        if let Err(e) = Self::write_stored(&result.path, &dst_path) {
             fs::remove_file(&result.path)?;
-            Err(Error::from(e))
+            Err(e)
         } else {
             fs::remove_file(result.path)?;
             Ok(FileSystemResult {
@@ -78,7 +575,7 @@ impl FileStore {
     }
 
     /// Try to replace the mime-type when unknown using ffmpeg probe result
-    fn hack_mime_type(mime_type: &str, p: &DemuxerInfo) -> String {
+    pub(crate) fn hack_mime_type(mime_type: &str, p: &DemuxerInfo) -> String {
         if mime_type == "application/octet-stream" {
             if p.format.contains("mp4") {
                 "video/mp4".to_string()
@@ -103,6 +600,8 @@ impl FileStore {
         mut stream: S,
         mime_type: &str,
         compress: bool,
+        queue: Option<(&ProcessingQueue, &[u8])>,
+        db: Option<&Database>,
     ) -> Result<FileSystemResult, Error>
     where
         S: AsyncRead + Unpin,
@@ -122,8 +621,44 @@ impl FileStore {
 
         #[cfg(feature = "media-compression")]
         if compress {
+            let source = FileStore::hash_file(&mut file).await?;
+            if let Some(db) = db {
+                if let Some(existing) = db.get_derived_file(&source, COMPRESS_PARAMS).await? {
+                    if let Some((_, path)) = self.locate(&existing.id) {
+                        info!(
+                            "Reusing derived file {} for {}",
+                            hex::encode(&existing.id),
+                            hex::encode(&source)
+                        );
+                        fs::remove_file(tmp_path)?;
+                        return Ok(FileSystemResult {
+                            path,
+                            upload: existing,
+                            source: Some(source),
+                            report: None,
+                            dedup: false,
+                        });
+                    }
+                }
+            }
+            let _permit = match queue {
+                Some((q, user)) => Some(q.acquire(user).await),
+                None => None,
+            };
             let start = SystemTime::now();
-            let proc_result = compress_file(tmp_path.clone(), mime_type)?;
+            let limits = MediaLimits::new(&self.settings);
+            let proc_result = match compress_file(
+                tmp_path.clone(),
+                mime_type,
+                &limits,
+                self.settings.quality_search.as_ref(),
+            ) {
+                Ok(r) => r,
+                Err(e) => {
+                    fs::remove_file(tmp_path)?;
+                    return Err(e);
+                }
+            };
             if let FileProcessorResult::NewFile(new_temp) = proc_result {
                 let old_size = tmp_path.metadata()?.len();
                 let new_size = new_temp.result.metadata()?.len();
@@ -164,19 +699,33 @@ impl FileStore {
 
                 return Ok(FileSystemResult {
                     path: new_temp.result,
+                    report: Some(ProcessingReport {
+                        file: hash.clone(),
+                        source: source.clone(),
+                        source_size: old_size,
+                        source_mime_type: mime_type.to_string(),
+                        params: COMPRESS_PARAMS.to_string(),
+                        duration_ms: time_compress.as_millis() as u32,
+                        quality: new_temp.quality,
+                        created: Utc::now(),
+                    }),
                     upload: FileUpload {
                         id: hash,
                         name: "".to_string(),
                         size: n,
                         width: Some(new_temp.width as u32),
                         height: Some(new_temp.height as u32),
+                        dim_source: Some(MetadataSource::Server),
                         blur_hash: None,
                         mime_type: new_temp.mime_type,
                         #[cfg(feature = "labels")]
                         labels,
                         created: Utc::now(),
+                        original_hash: Some(source.clone()),
                         ..Default::default()
                     },
+                    source: Some(source),
+                    dedup: false,
                 });
             }
         } else if let Ok(p) = probe_file(tmp_path.clone()) {
@@ -193,8 +742,12 @@ impl FileStore {
                     mime_type: Self::hack_mime_type(mime_type, &p),
                     width: v_stream.map(|v| v.width as u32),
                     height: v_stream.map(|v| v.height as u32),
+                    dim_source: v_stream.map(|_| MetadataSource::Server),
                     ..Default::default()
                 },
+                source: None,
+                report: None,
+                dedup: false,
             });
         }
 
@@ -210,10 +763,17 @@ impl FileStore {
                 mime_type: mime_type.to_string(),
                 ..Default::default()
             },
+            source: None,
+            report: None,
+            dedup: false,
         })
     }
 
-    async fn hash_file(file: &mut File) -> Result<Vec<u8>, Error> {
+    /// Sha256 of a file, read from the start in small chunks
+    pub async fn hash_file<R>(file: &mut R) -> Result<Vec<u8>, Error>
+    where
+        R: AsyncRead + AsyncSeek + Unpin,
+    {
         let mut hasher = Sha256::new();
         file.seek(SeekFrom::Start(0)).await?;
         let mut buf = [0; 4096];
@@ -228,15 +788,35 @@ impl FileStore {
         Ok(res.to_vec())
     }
 
+    #[cfg(feature = "blake3")]
+    pub(crate) async fn hash_file_blake3<R>(file: &mut R) -> Result<Vec<u8>, Error>
+    where
+        R: AsyncRead + AsyncSeek + Unpin,
+    {
+        let mut hasher = blake3::Hasher::new();
+        file.seek(SeekFrom::Start(0)).await?;
+        let mut buf = vec![0; 65536];
+        loop {
+            let n = file.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buf[..n]);
+        }
+        Ok(hasher.finalize().as_bytes().to_vec())
+    }
+
     fn map_temp(id: uuid::Uuid) -> PathBuf {
         temp_dir().join(id.to_string())
     }
 
+    /// Path of a file in storage_dir
     pub fn map_path(&self, id: &Vec<u8>) -> PathBuf {
+        Self::map_path_in(Path::new(&self.settings.storage_dir), id)
+    }
+
+    fn map_path_in(root: &Path, id: &Vec<u8>) -> PathBuf {
         let id = hex::encode(id);
-        Path::new(&self.settings.storage_dir)
-            .join(&id[0..2])
-            .join(&id[2..4])
-            .join(id)
+        root.join(&id[0..2]).join(&id[2..4]).join(id)
     }
 }
@@ -0,0 +1,279 @@
+use std::net::SocketAddr;
+
+use anyhow::Error;
+use log::{error, info};
+use nostr::PublicKey;
+use tokio::task::JoinHandle;
+use tonic::transport::{Certificate, Identity, Server, ServerTlsConfig};
+use tonic::{Request, Response, Status};
+
+use crate::db::{self, Database, JobStatus};
+use crate::deletion::{DeleteReason, DeletionService};
+use crate::filesystem::FileStore;
+use crate::legal_hold::OnLegalHold;
+use crate::routes::Sha256Param;
+use crate::settings::{GrpcConfig, Settings};
+
+pub mod proto {
+    tonic::include_proto!("route96.admin");
+}
+
+use proto::admin_server::{Admin, AdminServer};
+
+/// Admin API over gRPC, every client with a certificate signed by the
+/// configured CA has admin access
+pub struct AdminService {
+    db: Database,
+    fs: FileStore,
+    settings: Settings,
+}
+
+impl AdminService {
+    pub fn new(db: Database, settings: Settings) -> Self {
+        Self {
+            db,
+            fs: FileStore::new(settings.clone()),
+            settings,
+        }
+    }
+
+    /// Serve the admin API with mTLS
+    pub async fn serve(self, cfg: &GrpcConfig) -> Result<(), Error> {
+        let addr: SocketAddr = cfg.listen.parse()?;
+        let identity = Identity::from_pem(
+            tokio::fs::read(&cfg.cert).await?,
+            tokio::fs::read(&cfg.key).await?,
+        );
+        let client_ca = Certificate::from_pem(tokio::fs::read(&cfg.client_ca).await?);
+        info!("gRPC admin API listening on {}", addr);
+        Server::builder()
+            .tls_config(
+                ServerTlsConfig::new()
+                    .identity(identity)
+                    .client_ca_root(client_ca),
+            )?
+            .add_service(AdminServer::new(self))
+            .serve(addr)
+            .await?;
+        Ok(())
+    }
+
+    pub fn start(self, cfg: GrpcConfig) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            if let Err(e) = self.serve(&cfg).await {
+                error!("gRPC admin API failed: {}", e);
+            }
+        })
+    }
+}
+
+fn internal(e: impl ToString) -> Status {
+    Status::internal(e.to_string())
+}
+
+/// Parse a file id the same way the HTTP routes do
+fn parse_file_id(sha256: &str) -> Result<Vec<u8>, Status> {
+    Sha256Param::parse(sha256)
+        .map(|p| p.id)
+        .map_err(|e| Status::invalid_argument(e.to_string()))
+}
+
+fn job_status(status: JobStatus) -> &'static str {
+    match status {
+        JobStatus::Queued => "queued",
+        JobStatus::Running => "running",
+        JobStatus::Complete => "complete",
+        JobStatus::Failed => "failed",
+        JobStatus::Cancelled => "cancelled",
+    }
+}
+
+impl From<db::User> for proto::User {
+    fn from(u: db::User) -> Self {
+        Self {
+            pubkey: hex::encode(&u.pubkey),
+            created: u.created.timestamp(),
+            is_admin: u.is_admin,
+            tier: u.tier,
+        }
+    }
+}
+
+impl From<db::Job> for proto::Job {
+    fn from(j: db::Job) -> Self {
+        Self {
+            id: j.id,
+            kind: j.kind,
+            payload: j.payload,
+            status: job_status(j.status).to_string(),
+            attempts: j.attempts,
+            last_error: j.last_error,
+            progress: j.progress,
+            run_after: j.run_after.timestamp(),
+            created: j.created.timestamp(),
+            updated: j.updated.timestamp(),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl Admin for AdminService {
+    async fn list_files(
+        &self,
+        request: Request<proto::ListFilesRequest>,
+    ) -> Result<Response<proto::ListFilesResponse>, Status> {
+        let req = request.into_inner();
+        let count = req.count.clamp(1, 5_000);
+        let (files, total) = self
+            .db
+            .list_all_files(None, &[], req.page * count, count)
+            .await
+            .map_err(internal)?;
+        let mut ret = Vec::with_capacity(files.len());
+        for f in files {
+            let owners = self.db.get_file_owners(&f.id).await.map_err(internal)?;
+            ret.push(proto::File {
+                sha256: hex::encode(&f.id),
+                name: f.name,
+                size: f.size,
+                mime_type: f.mime_type,
+                created: f.created.timestamp(),
+                expires: f.expires.map(|e| e.timestamp()),
+                quarantined: f.quarantined,
+                owners: owners.iter().map(|o| hex::encode(&o.pubkey)).collect(),
+            });
+        }
+        Ok(Response::new(proto::ListFilesResponse {
+            page: req.page,
+            total: total as u64,
+            files: ret,
+        }))
+    }
+
+    async fn delete_file(
+        &self,
+        request: Request<proto::FileRequest>,
+    ) -> Result<Response<proto::Empty>, Status> {
+        let id = parse_file_id(&request.into_inner().sha256)?;
+        if self.db.get_file(&id).await.map_err(internal)?.is_none() {
+            return Err(Status::not_found("File not found"));
+        }
+        DeletionService::new(&self.db, &self.fs, &self.settings)
+            .purge(&id, None, DeleteReason::Admin)
+            .await
+            .map_err(|e| match e.downcast_ref::<OnLegalHold>() {
+                Some(h) => Status::failed_precondition(h.to_string()),
+                None => internal(e),
+            })?;
+        info!("Deleted {} (gRPC)", hex::encode(&id));
+        Ok(Response::new(proto::Empty {}))
+    }
+
+    async fn list_users(
+        &self,
+        request: Request<proto::ListUsersRequest>,
+    ) -> Result<Response<proto::ListUsersResponse>, Status> {
+        let req = request.into_inner();
+        let count = req.count.clamp(1, 5_000);
+        let (users, total) = self
+            .db
+            .list_users(req.page * count, count)
+            .await
+            .map_err(internal)?;
+        Ok(Response::new(proto::ListUsersResponse {
+            page: req.page,
+            total: total as u64,
+            users: users.into_iter().map(Into::into).collect(),
+        }))
+    }
+
+    async fn get_user_stats(
+        &self,
+        request: Request<proto::UserRequest>,
+    ) -> Result<Response<proto::UserStats>, Status> {
+        let pubkey = PublicKey::parse(&request.into_inner().pubkey)
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+        let user = match self.db.get_user(&pubkey.to_bytes().to_vec()).await {
+            Ok(u) => u,
+            Err(sqlx::Error::RowNotFound) => return Err(Status::not_found("User not found")),
+            Err(e) => return Err(internal(e)),
+        };
+        let stats = self.db.get_user_stats(user.id).await.map_err(internal)?;
+        Ok(Response::new(proto::UserStats {
+            user: Some(user.into()),
+            file_count: stats.file_count,
+            total_size: stats.total_size,
+        }))
+    }
+
+    async fn list_jobs(
+        &self,
+        request: Request<proto::ListJobsRequest>,
+    ) -> Result<Response<proto::ListJobsResponse>, Status> {
+        let req = request.into_inner();
+        let count = req.count.clamp(1, 5_000);
+        let status = match req.status.as_deref() {
+            None => None,
+            Some("queued") => Some(JobStatus::Queued),
+            Some("running") => Some(JobStatus::Running),
+            Some("complete") => Some(JobStatus::Complete),
+            Some("failed") => Some(JobStatus::Failed),
+            Some("cancelled") => Some(JobStatus::Cancelled),
+            Some(_) => return Err(Status::invalid_argument("Invalid job status")),
+        };
+        let (jobs, total) = self
+            .db
+            .list_jobs(status, req.page * count, count)
+            .await
+            .map_err(internal)?;
+        Ok(Response::new(proto::ListJobsResponse {
+            page: req.page,
+            total: total as u64,
+            jobs: jobs.into_iter().map(Into::into).collect(),
+        }))
+    }
+
+    async fn get_job(
+        &self,
+        request: Request<proto::JobRequest>,
+    ) -> Result<Response<proto::Job>, Status> {
+        match self.db.get_job(request.into_inner().id).await {
+            Ok(Some(job)) => Ok(Response::new(job.into())),
+            Ok(None) => Err(Status::not_found("Job not found")),
+            Err(e) => Err(internal(e)),
+        }
+    }
+
+    async fn retry_job(
+        &self,
+        request: Request<proto::JobRequest>,
+    ) -> Result<Response<proto::Empty>, Status> {
+        match self.db.retry_job(request.into_inner().id).await {
+            Ok(true) => Ok(Response::new(proto::Empty {})),
+            Ok(false) => Err(Status::not_found("Job not found or not in failed state")),
+            Err(e) => Err(internal(e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tonic::Code;
+
+    use super::*;
+
+    #[test]
+    fn file_id_is_parsed_like_http_routes() {
+        let hex_id = "ab".repeat(32);
+        assert_eq!(parse_file_id(&hex_id).unwrap(), vec![0xab; 32]);
+        assert_eq!(
+            parse_file_id(&format!("{}.png", hex_id)).unwrap(),
+            vec![0xab; 32]
+        );
+        for bad in ["", "abcd", "not-hex", &"zz".repeat(32)] {
+            let e = parse_file_id(bad).unwrap_err();
+            assert_eq!(e.code(), Code::InvalidArgument);
+            assert_eq!(e.message(), "Invalid file id");
+        }
+    }
+}
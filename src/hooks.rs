@@ -0,0 +1,97 @@
+use std::path::Path;
+use std::process::Stdio;
+use std::time::Duration;
+
+use anyhow::{bail, Error};
+use log::{info, warn};
+use nostr::serde_json;
+use serde::Serialize;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+use crate::db::FileUpload;
+use crate::settings::Settings;
+
+/// Default time a hook program may run before it is killed
+const DEFAULT_TIMEOUT: u64 = 30;
+
+/// JSON document written to the stdin of hook programs
+#[derive(Serialize)]
+struct HookEvent<'a> {
+    pub event: &'a str,
+    pub sha256: String,
+    pub size: u64,
+    pub mime_type: &'a str,
+    /// Uploader or deleter, missing for server initiated deletes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pubkey: Option<String>,
+}
+
+/// Run the post-store hook for a newly stored file
+pub fn post_store(settings: &Settings, upload: &FileUpload, pubkey: &[u8]) {
+    if let Some(p) = settings.hooks.as_ref().and_then(|h| h.post_store.as_ref()) {
+        spawn_hook(settings, p, "store", upload, Some(pubkey));
+    }
+}
+
+/// Run the post-delete hook for a file removed from storage
+pub fn post_delete(settings: &Settings, upload: &FileUpload, pubkey: Option<&[u8]>) {
+    if let Some(p) = settings.hooks.as_ref().and_then(|h| h.post_delete.as_ref()) {
+        spawn_hook(settings, p, "delete", upload, pubkey);
+    }
+}
+
+fn spawn_hook(
+    settings: &Settings,
+    program: &Path,
+    event: &str,
+    upload: &FileUpload,
+    pubkey: Option<&[u8]>,
+) {
+    let body = match serde_json::to_vec(&HookEvent {
+        event,
+        sha256: hex::encode(&upload.id),
+        size: upload.size,
+        mime_type: &upload.mime_type,
+        pubkey: pubkey.map(hex::encode),
+    }) {
+        Ok(b) => b,
+        Err(e) => {
+            warn!("Failed to encode hook event: {}", e);
+            return;
+        }
+    };
+    let timeout = Duration::from_secs(
+        settings
+            .hooks
+            .as_ref()
+            .and_then(|h| h.timeout)
+            .unwrap_or(DEFAULT_TIMEOUT),
+    );
+    let program = program.to_path_buf();
+    let id = hex::encode(&upload.id);
+    let event = event.to_string();
+    tokio::spawn(async move {
+        match tokio::time::timeout(timeout, run_hook(&program, &body)).await {
+            Ok(Ok(())) => info!("Hook {} finished for {}", event, id),
+            Ok(Err(e)) => warn!("Hook {} failed for {}: {}", event, id, e),
+            Err(_) => warn!("Hook {} timed out for {}", event, id),
+        }
+    });
+}
+
+async fn run_hook(program: &Path, body: &[u8]) -> Result<(), Error> {
+    let mut child = Command::new(program)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .kill_on_drop(true)
+        .spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(body).await?;
+    }
+    let status = child.wait().await?;
+    if !status.success() {
+        bail!("{} exited with {}", program.display(), status);
+    }
+    Ok(())
+}
@@ -0,0 +1,238 @@
+use std::fmt::{Display, Formatter};
+use std::net::IpAddr;
+
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use serde::Serialize;
+use serde_with::{hex::Hex, serde_as};
+use sqlx::mysql::MySqlArguments;
+use sqlx::query::Query;
+use sqlx::{Error, Executor, FromRow, MySql};
+
+use crate::db::{Database, FileUpload};
+
+/// Audited change or use of a file under legal hold
+#[derive(Clone, Copy, Debug, PartialEq, Eq, sqlx::Type, Serialize)]
+#[sqlx(rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum LegalHoldAction {
+    /// An admin placed the hold
+    Hold,
+    /// An admin asked for the hold to be released, a second admin must approve
+    ReleaseRequested,
+    /// A second admin approved the release
+    Released,
+    /// The file, or a file derived from it, was read
+    Access,
+    /// A delete was refused
+    Blocked,
+}
+
+/// Entry of the legal hold audit trail, kept after the hold is released
+#[serde_as]
+#[derive(Clone, Debug, FromRow, Serialize)]
+pub struct LegalHoldEvent {
+    pub id: u64,
+    #[serde(with = "hex")]
+    pub file: Vec<u8>,
+    pub action: LegalHoldAction,
+    /// Admin or owner who made the request, None for anonymous downloads
+    #[serde_as(as = "Option<Hex>")]
+    pub pubkey: Option<Vec<u8>>,
+    pub detail: Option<String>,
+    pub created: DateTime<Utc>,
+}
+
+/// Pending release of a hold, waiting for a second admin
+#[derive(Clone, Debug, FromRow, Serialize)]
+pub struct LegalHoldRelease {
+    #[serde(with = "hex")]
+    pub requested_by: Vec<u8>,
+    pub created: DateTime<Utc>,
+}
+
+/// Delete refused because the file is under legal hold
+#[derive(Debug, Clone)]
+pub struct OnLegalHold(pub Vec<u8>);
+
+impl Display for OnLegalHold {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} is under legal hold", hex::encode(&self.0))
+    }
+}
+
+impl std::error::Error for OnLegalHold {}
+
+impl Database {
+    /// Place a hold, cancelling a pending release
+    pub async fn set_legal_hold(
+        &self,
+        file: &Vec<u8>,
+        admin: &[u8],
+        reason: Option<&str>,
+    ) -> Result<(), Error> {
+        let mut tx = self.pool.begin().await?;
+        tx.execute(sqlx::query("update uploads set legal_hold = 1 where id = ?").bind(file))
+            .await?;
+        tx.execute(sqlx::query("delete from legal_hold_releases where file = ?").bind(file))
+            .await?;
+        tx.execute(event_query(
+            file,
+            LegalHoldAction::Hold,
+            Some(admin),
+            reason,
+        ))
+        .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    pub async fn get_legal_hold_release(
+        &self,
+        file: &Vec<u8>,
+    ) -> Result<Option<LegalHoldRelease>, Error> {
+        sqlx::query_as("select requested_by, created from legal_hold_releases where file = ?")
+            .bind(file)
+            .fetch_optional(&self.pool)
+            .await
+    }
+
+    /// Record the first admin of a release
+    pub async fn request_legal_hold_release(
+        &self,
+        file: &Vec<u8>,
+        admin: &[u8],
+        reason: Option<&str>,
+    ) -> Result<(), Error> {
+        let mut tx = self.pool.begin().await?;
+        tx.execute(
+            sqlx::query("insert into legal_hold_releases(file,requested_by) values(?,?)")
+                .bind(file)
+                .bind(admin),
+        )
+        .await?;
+        tx.execute(event_query(
+            file,
+            LegalHoldAction::ReleaseRequested,
+            Some(admin),
+            reason,
+        ))
+        .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Release a hold approved by a second admin
+    pub async fn release_legal_hold(
+        &self,
+        file: &Vec<u8>,
+        admin: &[u8],
+        reason: Option<&str>,
+    ) -> Result<(), Error> {
+        let mut tx = self.pool.begin().await?;
+        tx.execute(sqlx::query("update uploads set legal_hold = 0 where id = ?").bind(file))
+            .await?;
+        tx.execute(sqlx::query("delete from legal_hold_releases where file = ?").bind(file))
+            .await?;
+        tx.execute(event_query(
+            file,
+            LegalHoldAction::Released,
+            Some(admin),
+            reason,
+        ))
+        .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    pub async fn add_legal_hold_event(
+        &self,
+        file: &Vec<u8>,
+        action: LegalHoldAction,
+        pubkey: Option<&[u8]>,
+        detail: Option<&str>,
+    ) -> Result<(), Error> {
+        event_query(file, action, pubkey, detail)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Held files a derived file (rendition, thumbnail) was made from
+    pub async fn get_held_sources(&self, derived: &Vec<u8>) -> Result<Vec<Vec<u8>>, Error> {
+        sqlx::query_scalar(
+            "select uploads.id from upload_derivations, uploads \
+            where upload_derivations.derived = ? \
+            and uploads.id = upload_derivations.source \
+            and uploads.legal_hold = 1",
+        )
+        .bind(derived)
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    pub async fn list_legal_hold_events(
+        &self,
+        file: &Vec<u8>,
+    ) -> Result<Vec<LegalHoldEvent>, Error> {
+        sqlx::query_as("select * from legal_hold_log where file = ? order by id")
+            .bind(file)
+            .fetch_all(&self.pool)
+            .await
+    }
+}
+
+fn event_query<'q>(
+    file: &'q Vec<u8>,
+    action: LegalHoldAction,
+    pubkey: Option<&'q [u8]>,
+    detail: Option<&'q str>,
+) -> Query<'q, MySql, MySqlArguments> {
+    sqlx::query("insert into legal_hold_log(file,action,pubkey,detail) values(?,?,?,?)")
+        .bind(file)
+        .bind(action)
+        .bind(pubkey)
+        .bind(detail)
+}
+
+/// Record a read of a held file, with the client when known
+pub async fn log_access(
+    db: &Database,
+    file: &Vec<u8>,
+    route: &str,
+    pubkey: Option<&[u8]>,
+    ip: Option<IpAddr>,
+) {
+    let detail = match ip {
+        Some(ip) => format!("{} from {}", route, ip),
+        None => route.to_string(),
+    };
+    info!("Held file {} accessed: {}", hex::encode(file), detail);
+    if let Err(e) = db
+        .add_legal_hold_event(file, LegalHoldAction::Access, pubkey, Some(&detail))
+        .await
+    {
+        warn!("Failed to log access to held file: {}", e);
+    }
+}
+
+/// Record a read of a file on any route when it, or the file it was derived from, is held
+pub async fn log_read(
+    db: &Database,
+    file: &FileUpload,
+    route: &str,
+    pubkey: Option<&[u8]>,
+    ip: Option<IpAddr>,
+) {
+    if file.legal_hold {
+        log_access(db, &file.id, route, pubkey, ip).await;
+    }
+    match db.get_held_sources(&file.id).await {
+        Ok(sources) => {
+            for source in sources.iter().filter(|s| **s != file.id) {
+                log_access(db, source, route, pubkey, ip).await;
+            }
+        }
+        Err(e) => warn!("Failed to load held sources: {}", e),
+    }
+}
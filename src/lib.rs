@@ -1,13 +1,37 @@
 #[cfg(feature = "analytics")]
 pub mod analytics;
+pub mod app;
 pub mod auth;
+pub mod background;
+pub mod blocklist;
+pub mod capabilities;
+pub mod compression;
 pub mod cors;
 pub mod db;
+pub mod deletion;
+#[cfg(feature = "encryption")]
+pub mod encryption;
 pub mod filesystem;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+pub mod hooks;
+pub mod legal_hold;
+pub mod logging;
+pub mod metadata;
+pub mod mirror;
 #[cfg(feature = "media-compression")]
 pub mod processing;
+pub mod public_url;
+pub mod queue;
 pub mod routes;
 pub mod settings;
+pub mod shed;
+#[cfg(feature = "torrent-v2")]
+pub mod torrent;
+pub mod transfer;
+pub mod upload_limit;
+pub mod validate;
+pub mod vanity;
 #[cfg(any(feature = "void-cat-redirects", feature = "bin-void-cat-migrate"))]
 pub mod void_db;
 pub mod void_file;
@@ -0,0 +1,239 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use anyhow::{anyhow, Error};
+use chrono::{Local, Utc};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+use crate::settings::{LogFileConfig, LogRotation, LoggingConfig};
+
+/// Default number of rotated log files kept
+const DEFAULT_KEEP: usize = 7;
+
+/// Syslog facility "daemon"
+const SYSLOG_FACILITY: u8 = 3;
+
+/// Set up logging from the config, RUST_LOG still overrides the configured levels.
+///
+/// Without a logging section everything goes to stderr as configured by RUST_LOG.
+pub fn init(cfg: Option<&LoggingConfig>) -> Result<(), Error> {
+    let cfg = match cfg {
+        Some(c) => c,
+        None => {
+            pretty_env_logger::init();
+            return Ok(());
+        }
+    };
+    let mut builder = pretty_env_logger::formatted_timed_builder();
+    builder.filter_level(parse_level(cfg.level.as_deref().unwrap_or("info"))?);
+    for (target, level) in cfg.modules.iter().flatten() {
+        builder.filter_module(target, parse_level(level)?);
+    }
+    if let Ok(filters) = std::env::var("RUST_LOG") {
+        builder.parse_filters(&filters);
+    }
+    let stderr = builder.build();
+    let max_level = stderr.filter();
+
+    let logger = Logger {
+        stderr: Box::new(stderr),
+        to_stderr: cfg.stderr.unwrap_or(true),
+        file: cfg.file.as_ref().map(RollingFile::new),
+        syslog: match &cfg.syslog {
+            Some(path) => Some(Syslog::connect(path)?),
+            None => None,
+        },
+    };
+    log::set_boxed_logger(Box::new(logger))?;
+    log::set_max_level(max_level);
+    Ok(())
+}
+
+pub fn parse_level(level: &str) -> Result<LevelFilter, Error> {
+    LevelFilter::from_str(level).map_err(|_| anyhow!("Invalid log level \"{}\"", level))
+}
+
+/// Filters records with the env_logger rules and writes them to each configured output
+struct Logger {
+    stderr: Box<dyn Log>,
+    to_stderr: bool,
+    file: Option<RollingFile>,
+    syslog: Option<Syslog>,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.stderr.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        if self.to_stderr {
+            self.stderr.log(record);
+        }
+        if let Some(f) = &self.file {
+            f.write(record);
+        }
+        if let Some(s) = &self.syslog {
+            s.send(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.stderr.flush();
+        if let Some(f) = &self.file {
+            f.flush();
+        }
+    }
+}
+
+/// Log file which is switched to a new file every period, keeping the newest `keep` files
+struct RollingFile {
+    path: PathBuf,
+    rotation: LogRotation,
+    keep: usize,
+    /// (period suffix, open file)
+    current: Mutex<Option<(String, File)>>,
+}
+
+impl RollingFile {
+    fn new(cfg: &LogFileConfig) -> Self {
+        Self {
+            path: cfg.path.clone(),
+            rotation: cfg.rotate.unwrap_or(LogRotation::Daily),
+            keep: cfg.keep.unwrap_or(DEFAULT_KEEP),
+            current: Mutex::new(None),
+        }
+    }
+
+    fn period(&self) -> String {
+        match self.rotation {
+            LogRotation::Hourly => Utc::now().format("%Y-%m-%d-%H").to_string(),
+            LogRotation::Daily => Utc::now().format("%Y-%m-%d").to_string(),
+            LogRotation::Never => String::new(),
+        }
+    }
+
+    fn file_path(&self, period: &str) -> PathBuf {
+        if period.is_empty() {
+            return self.path.clone();
+        }
+        let mut p = self.path.as_os_str().to_owned();
+        p.push(".");
+        p.push(period);
+        PathBuf::from(p)
+    }
+
+    fn write(&self, record: &Record) {
+        let line = format!(
+            "{} {:<5} {}: {}\n",
+            Utc::now().to_rfc3339(),
+            record.level(),
+            record.target(),
+            record.args()
+        );
+        let mut current = self.current.lock().unwrap();
+        let period = self.period();
+        if current.as_ref().map(|(p, _)| p != &period).unwrap_or(true) {
+            *current = None;
+            let path = self.file_path(&period);
+            if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+                let _ = fs::create_dir_all(dir);
+            }
+            match OpenOptions::new().create(true).append(true).open(&path) {
+                Ok(f) => *current = Some((period, f)),
+                Err(e) => {
+                    eprintln!("Failed to open log file {}: {}", path.display(), e);
+                    return;
+                }
+            }
+            self.prune();
+        }
+        if let Some((_, f)) = current.as_mut() {
+            let _ = f.write_all(line.as_bytes());
+        }
+    }
+
+    fn flush(&self) {
+        if let Some((_, f)) = self.current.lock().unwrap().as_mut() {
+            let _ = f.flush();
+        }
+    }
+
+    /// Remove the oldest rotated files, the period suffix sorts by time
+    fn prune(&self) {
+        if self.rotation == LogRotation::Never {
+            return;
+        }
+        let (dir, name) = match (self.path.parent(), self.path.file_name()) {
+            (Some(d), Some(n)) => (d, n.to_string_lossy().to_string()),
+            _ => return,
+        };
+        let dir = if dir.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            dir
+        };
+        let prefix = format!("{}.", name);
+        let mut files: Vec<PathBuf> = match fs::read_dir(dir) {
+            Ok(entries) => entries
+                .filter_map(|e| e.ok())
+                .map(|e| e.path())
+                .filter(|p| {
+                    p.file_name()
+                        .and_then(|n| n.to_str())
+                        .is_some_and(|n| n.starts_with(&prefix))
+                })
+                .collect(),
+            Err(_) => return,
+        };
+        files.sort();
+        let remove = files.len().saturating_sub(self.keep);
+        for f in files.into_iter().take(remove) {
+            let _ = fs::remove_file(f);
+        }
+    }
+}
+
+/// RFC 3164 messages sent to a local syslog socket, which journald also listens on
+struct Syslog {
+    socket: UnixDatagram,
+    pid: u32,
+}
+
+impl Syslog {
+    fn connect(path: &Path) -> Result<Self, Error> {
+        let socket = UnixDatagram::unbound()?;
+        socket
+            .connect(path)
+            .map_err(|e| anyhow!("Failed to connect to syslog {}: {}", path.display(), e))?;
+        Ok(Self {
+            socket,
+            pid: std::process::id(),
+        })
+    }
+
+    fn send(&self, record: &Record) {
+        let severity = match record.level() {
+            Level::Error => 3,
+            Level::Warn => 4,
+            Level::Info => 6,
+            Level::Debug | Level::Trace => 7,
+        };
+        let msg = format!(
+            "<{}>{} route96[{}]: {}: {}",
+            SYSLOG_FACILITY * 8 + severity,
+            Local::now().format("%b %e %H:%M:%S"),
+            self.pid,
+            record.target(),
+            record.args()
+        );
+        let _ = self.socket.send(msg.as_bytes());
+    }
+}
@@ -0,0 +1,316 @@
+use anyhow::{bail, Error};
+
+use crate::db::{FileUpload, MetadataSource};
+use crate::routes::error::{ApiError, ErrorCode};
+use crate::settings::{ClientMetadataPolicy, Settings};
+
+/// Default longest file name in characters
+const DEFAULT_MAX_NAME_LENGTH: usize = 255;
+
+/// Default longest alt text in characters
+const DEFAULT_MAX_ALT_LENGTH: usize = 512;
+
+/// Longest license, size of the uploads.license column
+const MAX_LICENSE_LENGTH: usize = 128;
+
+/// Largest width or height accepted from a client when media_limits is not configured
+const MAX_CLIENT_DIMENSION: u32 = 65_535;
+
+/// Characters of the base 83 encoding used by blurhash
+const BASE83: &[u8] =
+    b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz#$%*+,-.:;=?@[]^_{|}~";
+
+/// Remove control and bidi override characters, which can break headers or make names
+/// display differently from what they are (eg. "txt.exe" shown as "exe.txt")
+pub fn clean_text(text: &str) -> String {
+    text.chars()
+        .filter_map(|c| match c {
+            '\t' | '\n' | '\r' => Some(' '),
+            '\u{202a}'..='\u{202e}' | '\u{2066}'..='\u{2069}' => None,
+            c if c.is_control() => None,
+            c => Some(c),
+        })
+        .collect::<String>()
+        .trim()
+        .to_string()
+}
+
+fn max_name_length(settings: &Settings) -> usize {
+    settings
+        .metadata_limits
+        .as_ref()
+        .and_then(|m| m.max_name_length)
+        .unwrap_or(DEFAULT_MAX_NAME_LENGTH)
+}
+
+/// Clean a file name sent by a client, rejecting names over the length limit
+pub fn sanitize_name(settings: &Settings, name: &str) -> Result<String, ApiError> {
+    let name = clean_text(name);
+    let max = max_name_length(settings);
+    if name.chars().count() > max {
+        return Err(ApiError::with_detail(
+            ErrorCode::NameTooLong,
+            format!("at most {} characters", max),
+        ));
+    }
+    Ok(name)
+}
+
+/// Clean alt text sent by a client, rejecting text over the length limit
+pub fn sanitize_alt(settings: &Settings, alt: &str) -> Result<String, ApiError> {
+    let alt = clean_text(alt);
+    let max = settings
+        .metadata_limits
+        .as_ref()
+        .and_then(|m| m.max_alt_length)
+        .unwrap_or(DEFAULT_MAX_ALT_LENGTH);
+    if alt.chars().count() > max {
+        return Err(ApiError::with_detail(
+            ErrorCode::AltTooLong,
+            format!("at most {} characters", max),
+        ));
+    }
+    Ok(alt)
+}
+
+/// Clean a license sent by a client (eg. CC-BY-4.0 or a url), None when empty
+pub fn sanitize_license(license: &str) -> Result<Option<String>, ApiError> {
+    let license = clean_text(license);
+    if license.chars().count() > MAX_LICENSE_LENGTH {
+        return Err(ApiError::with_detail(
+            ErrorCode::BadRequest,
+            format!("license must be at most {} characters", MAX_LICENSE_LENGTH),
+        ));
+    }
+    Ok(Some(license).filter(|l| !l.is_empty()))
+}
+
+/// Clean a file name taken from a url, cut to the length limit instead of rejected
+pub fn fit_name(settings: &Settings, name: &str) -> String {
+    clean_text(name)
+        .chars()
+        .take(max_name_length(settings))
+        .collect()
+}
+
+/// Metadata sent by the uploading client, already validated
+#[derive(Debug, Clone, Default)]
+pub struct ClientMetadata {
+    pub blur_hash: Option<String>,
+    pub dim: Option<(u32, u32)>,
+}
+
+impl ClientMetadata {
+    /// Validate the metadata sent by a client, None when the policy ignores it
+    pub fn parse(
+        settings: &Settings,
+        blur_hash: Option<&str>,
+        dim: Option<&str>,
+    ) -> Result<Option<Self>, Error> {
+        if settings
+            .client_metadata
+            .unwrap_or(ClientMetadataPolicy::Ignore)
+            == ClientMetadataPolicy::Ignore
+        {
+            return Ok(None);
+        }
+        let blur_hash = match blur_hash.map(str::trim).filter(|b| !b.is_empty()) {
+            Some(b) => {
+                check_blur_hash(b)?;
+                Some(b.to_string())
+            }
+            None => None,
+        };
+        let dim = match dim.map(str::trim).filter(|d| !d.is_empty()) {
+            Some(d) => Some(parse_dim(settings, d)?),
+            None => None,
+        };
+        Ok(Some(Self { blur_hash, dim }))
+    }
+
+    /// Fill the fields of an upload which the server could not compute
+    pub fn apply(&self, upload: &mut FileUpload) {
+        if upload.blur_hash.is_none() {
+            if let Some(b) = &self.blur_hash {
+                upload.blur_hash = Some(b.clone());
+                upload.blur_hash_source = Some(MetadataSource::Client);
+            }
+        }
+        if upload.width.is_none() && upload.height.is_none() {
+            if let Some((w, h)) = self.dim {
+                upload.width = Some(w);
+                upload.height = Some(h);
+                upload.dim_source = Some(MetadataSource::Client);
+            }
+        }
+    }
+}
+
+fn decode83(c: u8) -> Option<usize> {
+    BASE83.iter().position(|b| *b == c)
+}
+
+/// Check the alphabet and that the length matches the component count in the first character
+fn check_blur_hash(hash: &str) -> Result<(), Error> {
+    let bytes = hash.as_bytes();
+    if bytes.len() < 6 || !bytes.iter().all(|c| decode83(*c).is_some()) {
+        bail!("Invalid blurhash");
+    }
+    let size = decode83(bytes[0]).unwrap();
+    let (x, y) = (size % 9 + 1, size / 9 + 1);
+    if bytes.len() != 4 + 2 * x * y {
+        bail!("Invalid blurhash length");
+    }
+    Ok(())
+}
+
+/// Encode an RGBA image as a blurhash of `x` by `y` components (1 to 9 each).
+///
+/// The image should already be scaled down, every pixel is visited once per component
+pub fn encode_blur_hash(width: usize, height: usize, rgba: &[u8], x: usize, y: usize) -> String {
+    let mut factors = Vec::with_capacity(x * y);
+    for j in 0..y {
+        for i in 0..x {
+            let norm = if i == 0 && j == 0 { 1.0 } else { 2.0 };
+            let mut sum = [0f64; 3];
+            for py in 0..height {
+                let basis_y = (std::f64::consts::PI * (j * py) as f64 / height as f64).cos();
+                for px in 0..width {
+                    let basis =
+                        basis_y * (std::f64::consts::PI * (i * px) as f64 / width as f64).cos();
+                    let p = (py * width + px) * 4;
+                    for (s, v) in sum.iter_mut().zip(&rgba[p..p + 3]) {
+                        *s += basis * srgb_to_linear(*v);
+                    }
+                }
+            }
+            let scale = norm / (width * height) as f64;
+            factors.push(sum.map(|v| v * scale));
+        }
+    }
+
+    let mut hash = String::with_capacity(4 + 2 * x * y);
+    encode83(&mut hash, (x - 1) + (y - 1) * 9, 1);
+    let ac = &factors[1..];
+    let max = if ac.is_empty() {
+        encode83(&mut hash, 0, 1);
+        1.0
+    } else {
+        let actual = ac
+            .iter()
+            .flat_map(|f| f.iter())
+            .fold(0f64, |m, v| m.max(v.abs()));
+        let quantised = (actual * 166.0 - 0.5).floor().clamp(0.0, 82.0) as usize;
+        encode83(&mut hash, quantised, 1);
+        (quantised + 1) as f64 / 166.0
+    };
+    let dc = factors[0].map(linear_to_srgb);
+    encode83(&mut hash, (dc[0] << 16) + (dc[1] << 8) + dc[2], 4);
+    for f in ac {
+        let q = f.map(|v| {
+            let v = v / max;
+            (v.signum() * v.abs().sqrt() * 9.0 + 9.5)
+                .floor()
+                .clamp(0.0, 18.0) as usize
+        });
+        encode83(&mut hash, q[0] * 19 * 19 + q[1] * 19 + q[2], 2);
+    }
+    hash
+}
+
+fn encode83(out: &mut String, value: usize, length: u32) {
+    for i in 1..=length {
+        let digit = (value / 83usize.pow(length - i)) % 83;
+        out.push(BASE83[digit] as char);
+    }
+}
+
+fn srgb_to_linear(v: u8) -> f64 {
+    let v = v as f64 / 255.0;
+    if v <= 0.04045 {
+        v / 12.92
+    } else {
+        ((v + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn linear_to_srgb(v: f64) -> usize {
+    let v = v.clamp(0.0, 1.0);
+    if v <= 0.003_130_8 {
+        (v * 12.92 * 255.0 + 0.5) as usize
+    } else {
+        ((1.055 * v.powf(1.0 / 2.4) - 0.055) * 255.0 + 0.5) as usize
+    }
+}
+
+/// Parse a NIP-94 dim value, WIDTHxHEIGHT
+fn parse_dim(settings: &Settings, dim: &str) -> Result<(u32, u32), Error> {
+    let (w, h) = match dim.split_once('x') {
+        Some((w, h)) => match (w.parse::<u32>(), h.parse::<u32>()) {
+            (Ok(w), Ok(h)) => (w, h),
+            _ => bail!("Invalid dim, expected WIDTHxHEIGHT"),
+        },
+        None => bail!("Invalid dim, expected WIDTHxHEIGHT"),
+    };
+    let limits = settings.media_limits.as_ref();
+    let max = limits
+        .and_then(|l| l.max_dimension)
+        .unwrap_or(MAX_CLIENT_DIMENSION);
+    if w == 0 || h == 0 || w > max || h > max {
+        bail!(
+            "Invalid dim, width and height must be between 1 and {}",
+            max
+        );
+    }
+    if limits
+        .and_then(|l| l.max_pixels)
+        .is_some_and(|p| w as u64 * h as u64 > p)
+    {
+        bail!("Invalid dim, exceeds the maximum pixels");
+    }
+    Ok((w, h))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn solid(width: usize, height: usize, rgb: [u8; 3]) -> Vec<u8> {
+        (0..width * height)
+            .flat_map(|_| [rgb[0], rgb[1], rgb[2], 255])
+            .collect()
+    }
+
+    #[test]
+    fn blur_hash_of_solid_color() {
+        let hash = encode_blur_hash(8, 6, &solid(8, 6, [255, 0, 0]), 4, 3);
+        assert_eq!(hash.len(), 4 + 2 * 4 * 3);
+        check_blur_hash(&hash).unwrap();
+        // size 3 + 2 * 9, no AC, DC of pure red, then flat AC components
+        assert!(hash.starts_with("L0"));
+        assert_eq!(&hash[2..6], "TI:j");
+        assert!(hash[6..].as_bytes().chunks(2).all(|c| c == b"fQ"));
+    }
+
+    #[test]
+    fn blur_hash_with_one_component() {
+        let hash = encode_blur_hash(2, 2, &solid(2, 2, [0, 0, 0]), 1, 1);
+        assert_eq!(hash, "000000");
+        check_blur_hash(&hash).unwrap();
+    }
+
+    #[test]
+    fn blur_hash_of_gradient_has_ac() {
+        let rgba: Vec<u8> = (0..16 * 16)
+            .flat_map(|i| {
+                let v = ((i % 16) * 16) as u8;
+                [v, v, v, 255]
+            })
+            .collect();
+        let hash = encode_blur_hash(16, 16, &rgba, 4, 3);
+        check_blur_hash(&hash).unwrap();
+        // horizontal gradient, the first AC component is not flat
+        assert_ne!(&hash[6..8], "fQ");
+        assert_ne!(&hash[1..2], "0");
+    }
+}
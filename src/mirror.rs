@@ -0,0 +1,298 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use anyhow::{bail, Error, Result};
+use log::info;
+use nostr::serde_json;
+use reqwest::redirect::Policy;
+use reqwest::{ClientBuilder, Method, Response, Url};
+use rocket::futures::StreamExt;
+use serde::Deserialize;
+use tokio::io::AsyncRead;
+use tokio_util::io::StreamReader;
+use url::Host;
+
+use crate::filesystem::FileTooLarge;
+use crate::settings::Settings;
+use crate::transfer::{TransferGuard, TransferKind};
+
+/// Maximum number of redirects followed for a single mirror request
+const MAX_REDIRECTS: usize = 5;
+
+/// Default timeout for downloading a mirrored file
+const DEFAULT_TIMEOUT: u64 = 60;
+
+/// Largest blob list read from another server
+const MAX_LIST_BYTES: usize = 16 * 1024 * 1024;
+
+/// Blob descriptor returned by the list endpoint of another Blossom server (BUD-02)
+#[derive(Debug, Clone, Deserialize)]
+pub struct RemoteBlob {
+    pub url: String,
+    pub sha256: String,
+}
+
+/// Fetch a remote url for mirroring.
+///
+/// Every hop (including redirects) is checked against the mirror rules and the
+/// resolved addresses are pinned so DNS cannot be swapped between check and connect.
+pub async fn fetch(settings: &Settings, url: &str) -> Result<Response> {
+    request(settings, Method::GET, url).await
+}
+
+/// Issue a request to a remote url for mirroring, applying the same checks as [fetch]
+pub async fn request(settings: &Settings, method: Method, url: &str) -> Result<Response> {
+    request_with_headers(settings, method, url, &[]).await
+}
+
+async fn request_with_headers(
+    settings: &Settings,
+    method: Method,
+    url: &str,
+    headers: &[(&str, &str)],
+) -> Result<Response> {
+    let mut url = Url::parse(url)?;
+    for _ in 0..=MAX_REDIRECTS {
+        let addrs = check_url(settings, &url).await?;
+        let host = url.host_str().unwrap_or_default().to_string();
+        let client = ClientBuilder::new()
+            .redirect(Policy::none())
+            .timeout(Duration::from_secs(mirror_timeout(settings)))
+            .resolve_to_addrs(&host, &addrs)
+            .build()?;
+
+        let mut req = client.request(method.clone(), url.clone());
+        for (k, v) in headers {
+            req = req.header(*k, *v);
+        }
+        let rsp = req.send().await?;
+        if rsp.status().is_redirection() {
+            let location = match rsp.headers().get("location") {
+                Some(l) => l.to_str()?,
+                None => bail!("Redirect without location header"),
+            };
+            url = url.join(location)?;
+            info!("Mirror following redirect to {}", url);
+            continue;
+        }
+        if !rsp.status().is_success() {
+            bail!("Remote server returned {}", rsp.status());
+        }
+        if let Some(len) = rsp.content_length() {
+            if len > max_mirror_bytes(settings) {
+                return Err(FileTooLarge.into());
+            }
+        }
+        return Ok(rsp);
+    }
+    bail!("Too many redirects")
+}
+
+/// Size and type of a remote file, learned before downloading it
+#[derive(Debug, Clone, Default)]
+pub struct MirrorPreflight {
+    pub size: Option<u64>,
+    pub mime_type: Option<String>,
+}
+
+/// Ask the source of a mirror request for the size and type of the file.
+///
+/// Uses HEAD, falling back to a single byte ranged GET for servers which
+/// don't support HEAD or don't send a content length.
+pub async fn preflight(settings: &Settings, url: &str) -> Result<MirrorPreflight> {
+    let mut ret = MirrorPreflight::default();
+    if let Ok(rsp) = request(settings, Method::HEAD, url).await {
+        ret.mime_type = header_str(&rsp, "content-type");
+        // content_length() is the size of the (empty) HEAD body
+        ret.size = header_str(&rsp, "content-length").and_then(|v| v.parse().ok());
+    }
+    if ret.size.is_none() {
+        let rsp =
+            request_with_headers(settings, Method::GET, url, &[("range", "bytes=0-0")]).await?;
+        if ret.mime_type.is_none() {
+            ret.mime_type = header_str(&rsp, "content-type");
+        }
+        ret.size = match header_str(&rsp, "content-range") {
+            // bytes 0-0/<total>
+            Some(r) => r.rsplit('/').next().and_then(|t| t.parse().ok()),
+            // range not supported, the full file is returned
+            None => rsp.content_length(),
+        };
+    }
+    if let Some(size) = ret.size {
+        if size > max_mirror_bytes(settings) {
+            return Err(FileTooLarge.into());
+        }
+    }
+    Ok(ret)
+}
+
+fn header_str(rsp: &Response, name: &str) -> Option<String> {
+    rsp.headers()
+        .get(name)
+        .and_then(|h| h.to_str().ok())
+        .map(|s| s.to_string())
+}
+
+/// List the blobs of a pubkey on another Blossom server with the mirror rules.
+///
+/// `authorization` is sent as is, for servers which only list blobs to their owner
+pub async fn list_blobs(
+    settings: &Settings,
+    server: &str,
+    pubkey: &str,
+    authorization: Option<&str>,
+) -> Result<Vec<RemoteBlob>> {
+    let url = format!("{}/list/{}", server.trim_end_matches('/'), pubkey);
+    let headers: Vec<(&str, &str)> = authorization
+        .map(|a| vec![("authorization", a)])
+        .unwrap_or_default();
+    let rsp = request_with_headers(settings, Method::GET, &url, &headers).await?;
+    let mut body = Vec::new();
+    let mut stream = rsp.bytes_stream();
+    while let Some(chunk) = stream.next().await {
+        body.extend_from_slice(&chunk?);
+        if body.len() > MAX_LIST_BYTES {
+            bail!("Blob list is too large");
+        }
+    }
+    Ok(serde_json::from_slice(&body)?)
+}
+
+/// Stream the body of a mirror response, failing once the size limit is exceeded
+pub fn response_reader(settings: &Settings, rsp: Response) -> impl AsyncRead + Unpin {
+    let max_size = max_mirror_bytes(settings);
+    let mut total = 0u64;
+    let stream = StreamReader::new(rsp.bytes_stream().map(move |result| {
+        let chunk = result.map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err))?;
+        total += chunk.len() as u64;
+        if total > max_size {
+            return Err(FileTooLarge::io_error());
+        }
+        Ok(chunk)
+    }));
+    TransferGuard::new(stream, settings, TransferKind::Mirror)
+}
+
+/// Maximum number of bytes which can be downloaded by a mirror request
+pub fn max_mirror_bytes(settings: &Settings) -> u64 {
+    settings
+        .mirror
+        .as_ref()
+        .and_then(|m| m.max_bytes)
+        .unwrap_or(settings.max_upload_bytes)
+        .min(settings.max_upload_bytes)
+}
+
+fn mirror_timeout(settings: &Settings) -> u64 {
+    settings
+        .mirror
+        .as_ref()
+        .and_then(|m| m.timeout)
+        .unwrap_or(DEFAULT_TIMEOUT)
+}
+
+/// Validate a url against the mirror rules, returning the resolved addresses
+pub async fn check_url(settings: &Settings, url: &Url) -> Result<Vec<SocketAddr>> {
+    if url.scheme() != "http" && url.scheme() != "https" {
+        bail!("Unsupported url scheme");
+    }
+    if !url.username().is_empty() || url.password().is_some() {
+        bail!("Credentials in url are not allowed");
+    }
+    let host = match url.host() {
+        Some(Host::Domain(d)) => d.trim_end_matches('.').to_lowercase(),
+        Some(Host::Ipv4(ip)) => ip.to_string(),
+        Some(Host::Ipv6(ip)) => ip.to_string(),
+        None => bail!("Missing host"),
+    };
+
+    if let Some(cfg) = &settings.mirror {
+        if let Some(deny) = &cfg.deny_domains {
+            if deny.iter().any(|d| domain_matches(&host, d)) {
+                bail!("Domain is not allowed");
+            }
+        }
+        if let Some(allow) = &cfg.allow_domains {
+            if !allow.iter().any(|d| domain_matches(&host, d)) {
+                bail!("Domain is not allowed");
+            }
+        }
+    }
+
+    let port = url.port_or_known_default().unwrap_or(80);
+    let addrs: Vec<SocketAddr> = match url.host() {
+        Some(Host::Ipv4(ip)) => vec![SocketAddr::new(IpAddr::V4(ip), port)],
+        Some(Host::Ipv6(ip)) => vec![SocketAddr::new(IpAddr::V6(ip), port)],
+        _ => tokio::net::lookup_host((host.as_str(), port))
+            .await?
+            .collect(),
+    };
+    if addrs.is_empty() {
+        return Err(Error::msg("Host could not be resolved"));
+    }
+    let allow_private = settings
+        .mirror
+        .as_ref()
+        .map(|m| m.allow_private)
+        .unwrap_or(false);
+    if !allow_private && addrs.iter().any(|a| !is_public_ip(&a.ip())) {
+        bail!("Host resolves to a private address");
+    }
+    Ok(addrs)
+}
+
+/// Match a host against a domain rule, subdomains are included
+fn domain_matches(host: &str, rule: &str) -> bool {
+    let rule = rule.trim_start_matches("*.").trim_end_matches('.');
+    host.eq_ignore_ascii_case(rule) || host.ends_with(&format!(".{}", rule.to_lowercase()))
+}
+
+/// Check if an address is routable on the public internet
+pub fn is_public_ip(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_ipv4(ip),
+        IpAddr::V6(ip) => {
+            if let Some(v4) = ip.to_ipv4_mapped() {
+                return is_public_ipv4(&v4);
+            }
+            is_public_ipv6(ip)
+        }
+    }
+}
+
+fn is_public_ipv4(ip: &Ipv4Addr) -> bool {
+    let o = ip.octets();
+    !(ip.is_private()
+        || ip.is_loopback()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_unspecified()
+        || ip.is_multicast()
+        // 0.0.0.0/8
+        || o[0] == 0
+        // 100.64.0.0/10 carrier-grade NAT
+        || (o[0] == 100 && (o[1] & 0xc0) == 64)
+        // 192.0.0.0/24 protocol assignments
+        || (o[0] == 192 && o[1] == 0 && o[2] == 0)
+        // 198.18.0.0/15 benchmarking
+        || (o[0] == 198 && (o[1] & 0xfe) == 18)
+        // 240.0.0.0/4 reserved
+        || o[0] >= 240)
+}
+
+fn is_public_ipv6(ip: &Ipv6Addr) -> bool {
+    let s = ip.segments();
+    !(ip.is_loopback()
+        || ip.is_unspecified()
+        || ip.is_multicast()
+        // fc00::/7 unique local
+        || (s[0] & 0xfe00) == 0xfc00
+        // fe80::/10 link local
+        || (s[0] & 0xffc0) == 0xfe80
+        // 2001:db8::/32 documentation
+        || (s[0] == 0x2001 && s[1] == 0x0db8)
+        // 64:ff9b::/96 NAT64 can reach private v4 space
+        || (s[0] == 0x64 && s[1] == 0xff9b))
+}
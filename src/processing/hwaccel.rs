@@ -0,0 +1,218 @@
+use std::ffi::CString;
+use std::ptr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+use std::time::Instant;
+
+use anyhow::Result;
+use ffmpeg_rs_raw::ffmpeg_sys_the_third::AVHWDeviceType::{
+    AV_HWDEVICE_TYPE_CUDA, AV_HWDEVICE_TYPE_VAAPI, AV_HWDEVICE_TYPE_VIDEOTOOLBOX,
+};
+use ffmpeg_rs_raw::ffmpeg_sys_the_third::{
+    av_buffer_unref, av_hwdevice_ctx_create, avcodec_find_encoder_by_name, AVBufferRef,
+    AVHWDeviceType,
+};
+use log::{info, warn};
+
+use crate::capabilities;
+use crate::settings::{HwAccelConfig, HwAccelKind};
+
+/// Hardware backend selected at startup, None uses software only
+static ACTIVE: OnceLock<Option<HwAccelKind>> = OnceLock::new();
+
+/// Processing stages which are timed separately
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    Compress,
+    Label,
+    Transcode,
+}
+
+impl Stage {
+    pub const ALL: [Stage; 3] = [Stage::Compress, Stage::Label, Stage::Transcode];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Stage::Compress => "compress",
+            Stage::Label => "label",
+            Stage::Transcode => "transcode",
+        }
+    }
+}
+
+/// Run counts and total time of a processing stage
+pub struct StageStats {
+    pub hw_runs: AtomicU64,
+    pub hw_micros: AtomicU64,
+    pub sw_runs: AtomicU64,
+    pub sw_micros: AtomicU64,
+    /// Hardware runs which failed and were retried in software
+    pub fallbacks: AtomicU64,
+}
+
+impl StageStats {
+    const fn new() -> Self {
+        Self {
+            hw_runs: AtomicU64::new(0),
+            hw_micros: AtomicU64::new(0),
+            sw_runs: AtomicU64::new(0),
+            sw_micros: AtomicU64::new(0),
+            fallbacks: AtomicU64::new(0),
+        }
+    }
+
+    fn record(&self, hw: bool, start: Instant) {
+        let micros = start.elapsed().as_micros() as u64;
+        if hw {
+            self.hw_runs.fetch_add(1, Ordering::Relaxed);
+            self.hw_micros.fetch_add(micros, Ordering::Relaxed);
+        } else {
+            self.sw_runs.fetch_add(1, Ordering::Relaxed);
+            self.sw_micros.fetch_add(micros, Ordering::Relaxed);
+        }
+    }
+}
+
+static STATS: [StageStats; 3] = [StageStats::new(), StageStats::new(), StageStats::new()];
+
+pub fn stats(stage: Stage) -> &'static StageStats {
+    &STATS[stage as usize]
+}
+
+impl HwAccelKind {
+    fn device_type(&self) -> Option<AVHWDeviceType> {
+        match self {
+            HwAccelKind::Vaapi => Some(AV_HWDEVICE_TYPE_VAAPI),
+            HwAccelKind::Nvenc => Some(AV_HWDEVICE_TYPE_CUDA),
+            HwAccelKind::Videotoolbox => Some(AV_HWDEVICE_TYPE_VIDEOTOOLBOX),
+            HwAccelKind::Auto | HwAccelKind::None => None,
+        }
+    }
+
+    /// Suffix of the ffmpeg encoder names for this backend, eg. h264_vaapi
+    fn encoder_suffix(&self) -> Option<&'static str> {
+        match self {
+            HwAccelKind::Vaapi => Some("vaapi"),
+            HwAccelKind::Nvenc => Some("nvenc"),
+            HwAccelKind::Videotoolbox => Some("videotoolbox"),
+            HwAccelKind::Auto | HwAccelKind::None => None,
+        }
+    }
+}
+
+/// Check a hardware device can be opened
+unsafe fn probe_device(kind: HwAccelKind, device: Option<&str>) -> bool {
+    let dev_type = match kind.device_type() {
+        Some(t) => t,
+        None => return false,
+    };
+    let device = device.and_then(|d| CString::new(d).ok());
+    let mut ctx: *mut AVBufferRef = ptr::null_mut();
+    let ret = av_hwdevice_ctx_create(
+        &mut ctx,
+        dev_type,
+        device.as_ref().map(|d| d.as_ptr()).unwrap_or(ptr::null()),
+        ptr::null_mut(),
+        0,
+    );
+    if !ctx.is_null() {
+        av_buffer_unref(&mut ctx);
+    }
+    ret >= 0
+}
+
+/// Select the hardware backend, falling back to software when no device can be opened.
+///
+/// Must be called once at startup, before any media is processed.
+pub fn init(cfg: Option<&HwAccelConfig>) -> Option<HwAccelKind> {
+    *ACTIVE.get_or_init(|| {
+        let cfg = cfg?;
+        let candidates = match cfg.kind {
+            HwAccelKind::None => vec![],
+            HwAccelKind::Auto => vec![
+                HwAccelKind::Nvenc,
+                HwAccelKind::Vaapi,
+                HwAccelKind::Videotoolbox,
+            ],
+            k => vec![k],
+        };
+        let found = candidates
+            .into_iter()
+            .find(|k| unsafe { probe_device(*k, cfg.device.as_deref()) });
+        capabilities::register(
+            capabilities::HWACCEL,
+            Some("media-compression"),
+            found.is_some(),
+            found.map(|k| format!("{:?}", k).to_lowercase()),
+        );
+        match found {
+            Some(k) => info!("Using {:?} hardware acceleration", k),
+            None if cfg.kind != HwAccelKind::None => {
+                warn!("No hardware acceleration device available, using software")
+            }
+            None => {}
+        }
+        found
+    })
+}
+
+/// Hardware backend in use, if any
+pub fn active() -> Option<HwAccelKind> {
+    ACTIVE.get().copied().flatten()
+}
+
+/// Encoder name for a video codec (h264, hevc, av1), preferring the hardware encoder
+pub fn video_encoder(codec: &str) -> String {
+    if let Some(suffix) = active().and_then(|k| k.encoder_suffix()) {
+        let name = format!("{}_{}", codec, suffix);
+        if let Ok(c_name) = CString::new(name.as_str()) {
+            if !unsafe { avcodec_find_encoder_by_name(c_name.as_ptr()) }.is_null() {
+                return name;
+            }
+        }
+    }
+    match codec {
+        "h264" => "libx264",
+        "hevc" => "libx265",
+        "av1" => "libsvtav1",
+        c => c,
+    }
+    .to_string()
+}
+
+/// Run a processing step with the active hardware backend, retrying in software if it fails
+pub fn run_with_fallback<T>(
+    stage: Stage,
+    mut f: impl FnMut(Option<HwAccelKind>) -> Result<T>,
+) -> Result<T> {
+    let stats = stats(stage);
+    if let Some(hw) = active() {
+        let start = Instant::now();
+        match f(Some(hw)) {
+            Ok(r) => {
+                stats.record(true, start);
+                return Ok(r);
+            }
+            Err(e) => {
+                stats.fallbacks.fetch_add(1, Ordering::Relaxed);
+                warn!(
+                    "Hardware {} failed, retrying in software: {}",
+                    stage.as_str(),
+                    e
+                );
+            }
+        }
+    }
+    let start = Instant::now();
+    let r = f(None)?;
+    stats.record(false, start);
+    Ok(r)
+}
+
+/// Time a processing step which always runs in software
+pub fn run_software<T>(stage: Stage, f: impl FnOnce() -> Result<T>) -> Result<T> {
+    let start = Instant::now();
+    let r = f()?;
+    stats(stage).record(false, start);
+    Ok(r)
+}
@@ -12,6 +12,9 @@ use ffmpeg_rs_raw::{Decoder, Demuxer, Scaler};
 use nostr::serde_json;
 use serde::Deserialize;
 
+use crate::processing::hwaccel::{run_with_fallback, Stage};
+use crate::settings::HwAccelKind;
+
 #[derive(Deserialize)]
 struct MyVitConfig {
     pub id2label: HashMap<usize, String>,
@@ -45,7 +48,12 @@ pub fn label_frame(frame: &Path, model: PathBuf, config: PathBuf) -> Result<Hash
 }
 
 /// Load an image from disk into RGB pixel buffer
-unsafe fn load_image(path_buf: &Path, width: usize, height: usize) -> Result<Vec<u8>> {
+unsafe fn load_image(
+    path_buf: &Path,
+    width: usize,
+    height: usize,
+    hw: Option<HwAccelKind>,
+) -> Result<Vec<u8>> {
     let mut demux = Demuxer::new(path_buf.to_str().unwrap())?;
     let info = demux.probe_input()?;
     let image_stream = info
@@ -53,6 +61,9 @@ unsafe fn load_image(path_buf: &Path, width: usize, height: usize) -> Result<Vec
         .ok_or(Error::msg("No image stream found"))?;
 
     let mut decoder = Decoder::new();
+    if hw.is_some() {
+        decoder.enable_hw_decoder_any();
+    }
     decoder.setup_decoder(image_stream, None)?;
 
     // TODO: crop image square
@@ -81,7 +92,7 @@ unsafe fn load_image(path_buf: &Path, width: usize, height: usize) -> Result<Vec
 
 // https://github.com/huggingface/candle/blob/main/candle-examples/src/imagenet.rs
 unsafe fn load_frame_224(path: &Path) -> Result<Tensor> {
-    let pic = load_image(path, 224, 224)?;
+    let pic = run_with_fallback(Stage::Label, |hw| load_image(path, 224, 224, hw))?;
 
     std::fs::write("frame_224.raw", &pic)?;
     let d = Device::cuda_if_available(0)?;
@@ -1,13 +1,109 @@
-use std::path::PathBuf;
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
 
+use crate::processing::hwaccel::{run_software, Stage};
 use crate::processing::probe::FFProbe;
+use crate::settings::{QualitySearchConfig, Settings};
 use anyhow::{bail, Error, Result};
 use ffmpeg_rs_raw::ffmpeg_sys_the_third::AVPixelFormat::AV_PIX_FMT_YUV420P;
 use ffmpeg_rs_raw::{DemuxerInfo, Encoder, StreamType, Transcoder};
+use log::{debug, warn};
 
+pub mod hwaccel;
 #[cfg(feature = "labels")]
 pub mod labeling;
 mod probe;
+pub mod thumbnail;
+pub mod watermark;
+
+/// Default maximum width or height of decoded media
+const DEFAULT_MAX_DIMENSION: u32 = 16_384;
+
+/// Default maximum pixels of a decoded frame
+const DEFAULT_MAX_PIXELS: u64 = 100_000_000;
+
+/// Default lowest quality tried by the quality search
+const DEFAULT_MIN_QUALITY: u8 = 30;
+
+/// Default highest quality tried by the quality search
+const DEFAULT_MAX_QUALITY: u8 = 90;
+
+/// Types /media converts to WebP when none are configured
+const DEFAULT_MEDIA_TYPES: [&str; 5] = [
+    "image/jpeg",
+    "image/png",
+    "image/gif",
+    "image/bmp",
+    "image/tiff",
+];
+
+/// Default number of encodes per image by the quality search
+const DEFAULT_MAX_ATTEMPTS: u32 = 6;
+
+/// Media which would use too much memory to decode
+#[derive(Debug, Clone)]
+pub struct MediaTooLarge {
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Display for MediaTooLarge {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}x{} exceeds the maximum media dimensions",
+            self.width, self.height
+        )
+    }
+}
+
+impl std::error::Error for MediaTooLarge {}
+
+impl MediaTooLarge {
+    pub fn is(e: &Error) -> bool {
+        e.downcast_ref::<MediaTooLarge>().is_some()
+    }
+}
+
+/// Dimension limits checked against the probe data before media is decoded
+#[derive(Debug, Clone, Copy)]
+pub struct MediaLimits {
+    pub max_dimension: u32,
+    pub max_pixels: u64,
+}
+
+impl MediaLimits {
+    pub fn new(settings: &Settings) -> Self {
+        let cfg = settings.media_limits.as_ref();
+        Self {
+            max_dimension: cfg
+                .and_then(|c| c.max_dimension)
+                .unwrap_or(DEFAULT_MAX_DIMENSION),
+            max_pixels: cfg.and_then(|c| c.max_pixels).unwrap_or(DEFAULT_MAX_PIXELS),
+        }
+    }
+
+    pub fn check(&self, width: usize, height: usize) -> Result<(), MediaTooLarge> {
+        let max = self.max_dimension as usize;
+        if width > max || height > max || (width as u64 * height as u64) > self.max_pixels {
+            return Err(MediaTooLarge { width, height });
+        }
+        Ok(())
+    }
+}
+
+/// Input types PUT /media converts, lowercase
+pub fn media_input_types(settings: &Settings) -> Vec<String> {
+    settings
+        .media_types
+        .as_ref()
+        .and_then(|m| m.supported.clone())
+        .unwrap_or_else(|| DEFAULT_MEDIA_TYPES.iter().map(|t| t.to_string()).collect())
+        .iter()
+        .map(|t| t.to_lowercase())
+        .collect()
+}
 
 pub struct WebpProcessor;
 
@@ -22,9 +118,13 @@ impl WebpProcessor {
         Self
     }
 
-    pub fn process_file(&mut self, input: PathBuf, mime_type: &str) -> Result<FileProcessorResult> {
-        use ffmpeg_rs_raw::ffmpeg_sys_the_third::AVCodecID::AV_CODEC_ID_WEBP;
-
+    pub fn process_file(
+        &mut self,
+        input: PathBuf,
+        mime_type: &str,
+        limits: &MediaLimits,
+        search: Option<&QualitySearchConfig>,
+    ) -> Result<FileProcessorResult> {
         if !mime_type.starts_with("image/") {
             bail!("MIME type not supported");
         }
@@ -35,6 +135,83 @@ impl WebpProcessor {
 
         let mut out_path = input.clone();
         out_path.set_extension("compressed.webp");
+        let res = self.encode(&input, &out_path, limits, search);
+        if res.is_err() {
+            let _ = std::fs::remove_file(&out_path);
+        }
+        res.map(FileProcessorResult::NewFile)
+    }
+
+    /// Encode with the default quality, or search for the highest quality within the size
+    /// budget of the image
+    fn encode(
+        &mut self,
+        input: &Path,
+        out_path: &Path,
+        limits: &MediaLimits,
+        search: Option<&QualitySearchConfig>,
+    ) -> Result<NewFileProcessorResult> {
+        let search = match search {
+            Some(s) => s,
+            None => return Self::encode_quality(input, out_path, limits, None),
+        };
+        let min = search.min_quality.unwrap_or(DEFAULT_MIN_QUALITY);
+        let max = search.max_quality.unwrap_or(DEFAULT_MAX_QUALITY).max(min);
+        let mut result = Self::encode_quality(input, out_path, limits, Some(max))?;
+        let budget = match search.budget(result.width, result.height) {
+            Some(b) => b,
+            None => return Ok(result),
+        };
+        if max == min || std::fs::metadata(out_path)?.len() <= budget {
+            return Ok(result);
+        }
+
+        // highest quality known to fit, qualities above hi are over budget
+        let mut best = None;
+        let (mut lo, mut hi) = (min, max - 1);
+        let mut attempts = 1;
+        while lo <= hi && attempts < search.max_attempts.unwrap_or(DEFAULT_MAX_ATTEMPTS) {
+            let q = lo + (hi - lo) / 2;
+            result = Self::encode_quality(input, out_path, limits, Some(q))?;
+            attempts += 1;
+            let size = std::fs::metadata(out_path)?.len();
+            debug!("Quality search: q={} size={} budget={}", q, size, budget);
+            if size <= budget {
+                best = Some(q);
+                lo = q + 1;
+            } else if q == 0 {
+                break;
+            } else {
+                hi = q - 1;
+            }
+        }
+        let chosen = match best {
+            Some(q) => q,
+            None => {
+                warn!(
+                    "{} is over its size budget of {} bytes at quality {}",
+                    input.display(),
+                    budget,
+                    min
+                );
+                min
+            }
+        };
+        // the last attempt is not always the chosen quality
+        if result.quality != Some(chosen) {
+            result = Self::encode_quality(input, out_path, limits, Some(chosen))?;
+        }
+        Ok(result)
+    }
+
+    fn encode_quality(
+        input: &Path,
+        out_path: &Path,
+        limits: &MediaLimits,
+        quality: Option<u8>,
+    ) -> Result<NewFileProcessorResult> {
+        use ffmpeg_rs_raw::ffmpeg_sys_the_third::AVCodecID::AV_CODEC_ID_WEBP;
+
         unsafe {
             let mut trans = Transcoder::new(input.to_str().unwrap(), out_path.to_str().unwrap())?;
 
@@ -44,22 +221,26 @@ impl WebpProcessor {
                 .iter()
                 .find(|c| c.stream_type == StreamType::Video)
                 .ok_or(Error::msg("No image found, cant compress"))?;
+            // nothing has been decoded yet, only the headers were probed
+            limits.check(image_stream.width, image_stream.height)?;
 
+            let options = quality.map(|q| HashMap::from([("quality".to_string(), q.to_string())]));
             let enc = Encoder::new(AV_CODEC_ID_WEBP)?
                 .with_height(image_stream.height as i32)
                 .with_width(image_stream.width as i32)
                 .with_pix_fmt(AV_PIX_FMT_YUV420P)
-                .open(None)?;
+                .open(options)?;
 
             trans.transcode_stream(image_stream, enc)?;
             trans.run(None)?;
 
-            Ok(FileProcessorResult::NewFile(NewFileProcessorResult {
-                result: out_path,
+            Ok(NewFileProcessorResult {
+                result: out_path.to_path_buf(),
                 mime_type: "image/webp".to_string(),
                 width: image_stream.width,
                 height: image_stream.height,
-            }))
+                quality,
+            })
         }
     }
 }
@@ -74,16 +255,29 @@ pub struct NewFileProcessorResult {
     pub mime_type: String,
     pub width: usize,
     pub height: usize,
+    /// Encoder quality, None for the encoder default
+    pub quality: Option<u8>,
 }
 
-pub fn compress_file(in_file: PathBuf, mime_type: &str) -> Result<FileProcessorResult, Error> {
+/// Derivation params recorded for files produced by [compress_file]
+pub const COMPRESS_PARAMS: &str = "compress:webp";
+
+pub fn compress_file(
+    in_file: PathBuf,
+    mime_type: &str,
+    limits: &MediaLimits,
+    search: Option<&QualitySearchConfig>,
+) -> Result<FileProcessorResult, Error> {
     let proc = if mime_type.starts_with("image/") {
         Some(WebpProcessor::new())
     } else {
         None
     };
     if let Some(mut proc) = proc {
-        proc.process_file(in_file, mime_type)
+        // there is no hardware webp encoder
+        run_software(Stage::Compress, || {
+            proc.process_file(in_file, mime_type, limits, search)
+        })
     } else {
         Ok(FileProcessorResult::Skip)
     }
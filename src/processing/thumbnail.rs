@@ -0,0 +1,91 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+
+use crate::metadata::encode_blur_hash;
+use crate::processing::hwaccel::{run_software, Stage};
+use crate::processing::watermark::{decode_rgba, encode_webp};
+use crate::processing::{MediaLimits, NewFileProcessorResult};
+
+/// Derivation params of thumbnails
+pub const THUMBNAIL_PARAMS: &str = "thumbnail:webp";
+
+/// Longest side of thumbnails in pixels
+const THUMBNAIL_SIZE: usize = 320;
+
+/// Longest side the image is scaled to before computing the blurhash
+const BLUR_HASH_SIZE: usize = 32;
+
+/// Blurhash components (x, y)
+const BLUR_HASH_COMPONENTS: (usize, usize) = (4, 3);
+
+/// Size fitting inside `max` on the longest side, images are never scaled up
+fn fit(width: usize, height: usize, max: usize) -> (usize, usize) {
+    let longest = width.max(height).max(1);
+    if longest <= max {
+        return (width.max(1), height.max(1));
+    }
+    (
+        (width * max / longest).max(1),
+        (height * max / longest).max(1),
+    )
+}
+
+/// Blurhash of the first frame of an image or video
+pub fn blur_hash_file(input: &Path, limits: &MediaLimits) -> Result<String> {
+    run_software(Stage::Compress, || unsafe {
+        let image = decode_rgba(input, |w, h| {
+            limits.check(w, h)?;
+            Ok(fit(w, h, BLUR_HASH_SIZE))
+        })?;
+        let (x, y) = BLUR_HASH_COMPONENTS;
+        Ok(encode_blur_hash(
+            image.width,
+            image.height,
+            &image.data,
+            x,
+            y,
+        ))
+    })
+}
+
+/// Encode the first frame of an image or video as a small WebP
+pub fn thumbnail_file(input: PathBuf, limits: &MediaLimits) -> Result<NewFileProcessorResult> {
+    let mut out_path = input.clone();
+    out_path.set_extension("thumb.webp");
+    run_software(Stage::Compress, || unsafe {
+        let image = decode_rgba(&input, |w, h| {
+            limits.check(w, h)?;
+            Ok(fit(w, h, THUMBNAIL_SIZE))
+        })?;
+        if let Err(e) = encode_webp(&image, &out_path) {
+            let _ = std::fs::remove_file(&out_path);
+            return Err(e);
+        }
+        Ok(NewFileProcessorResult {
+            result: out_path.clone(),
+            mime_type: "image/webp".to_string(),
+            width: image.width,
+            height: image.height,
+            quality: None,
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fit_scales_longest_side() {
+        assert_eq!(fit(1920, 1080, 320), (320, 180));
+        assert_eq!(fit(1080, 1920, 320), (180, 320));
+        assert_eq!(fit(10_000, 1, 32), (32, 1));
+    }
+
+    #[test]
+    fn fit_does_not_upscale() {
+        assert_eq!(fit(100, 50, 320), (100, 50));
+        assert_eq!(fit(0, 0, 32), (1, 1));
+    }
+}
@@ -0,0 +1,196 @@
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::slice;
+
+use anyhow::{bail, Error, Result};
+use ffmpeg_rs_raw::ffmpeg_sys_the_third::AVCodecID::AV_CODEC_ID_WEBP;
+use ffmpeg_rs_raw::ffmpeg_sys_the_third::AVPixelFormat::{AV_PIX_FMT_RGBA, AV_PIX_FMT_YUV420P};
+use ffmpeg_rs_raw::ffmpeg_sys_the_third::{
+    av_frame_alloc, av_frame_free, av_frame_get_buffer, av_packet_free,
+};
+use ffmpeg_rs_raw::{Decoder, Demuxer, Encoder, Muxer, Scaler};
+
+use crate::processing::hwaccel::{run_software, Stage};
+use crate::processing::{MediaLimits, NewFileProcessorResult};
+use crate::settings::{WatermarkConfig, WatermarkPosition};
+
+/// Default overlay width as a fraction of the image width
+const DEFAULT_SCALE: f32 = 0.2;
+
+/// Default pixels between the overlay and the image edges
+const DEFAULT_MARGIN: u32 = 16;
+
+/// Decoded RGBA image
+pub(crate) struct Image {
+    pub width: usize,
+    pub height: usize,
+    pub data: Vec<u8>,
+}
+
+/// Encode an image as WebP with the configured overlay drawn on it
+pub fn watermark_file(
+    input: PathBuf,
+    cfg: &WatermarkConfig,
+    limits: &MediaLimits,
+) -> Result<NewFileProcessorResult> {
+    let mut out_path = input.clone();
+    out_path.set_extension("watermark.webp");
+    run_software(Stage::Compress, || unsafe {
+        let mut image = decode_rgba(&input, |w, h| {
+            limits.check(w, h)?;
+            Ok((w, h))
+        })?;
+        let scale = cfg.scale.unwrap_or(DEFAULT_SCALE);
+        let overlay = decode_rgba(&cfg.image, |w, h| {
+            let width = ((image.width as f32 * scale).round() as usize).clamp(1, image.width);
+            let height = (h * width / w.max(1)).clamp(1, image.height);
+            Ok((width, height))
+        })?;
+        let (x, y) = placement(
+            cfg.position.unwrap_or(WatermarkPosition::BottomRight),
+            cfg.margin.unwrap_or(DEFAULT_MARGIN) as usize,
+            &image,
+            &overlay,
+        );
+        blend(&mut image, &overlay, x, y, cfg.opacity.unwrap_or(1.0));
+        if let Err(e) = encode_webp(&image, &out_path) {
+            let _ = std::fs::remove_file(&out_path);
+            return Err(e);
+        }
+        Ok(NewFileProcessorResult {
+            result: out_path.clone(),
+            mime_type: "image/webp".to_string(),
+            width: image.width,
+            height: image.height,
+            quality: None,
+        })
+    })
+}
+
+/// Top left corner of the overlay, clamped so it starts inside the image
+fn placement(
+    pos: WatermarkPosition,
+    margin: usize,
+    image: &Image,
+    overlay: &Image,
+) -> (usize, usize) {
+    let right = image.width.saturating_sub(overlay.width + margin);
+    let bottom = image.height.saturating_sub(overlay.height + margin);
+    let left = margin.min(right);
+    let top = margin.min(bottom);
+    match pos {
+        WatermarkPosition::TopLeft => (left, top),
+        WatermarkPosition::TopRight => (right, top),
+        WatermarkPosition::BottomLeft => (left, bottom),
+        WatermarkPosition::BottomRight => (right, bottom),
+        WatermarkPosition::Center => (
+            image.width.saturating_sub(overlay.width) / 2,
+            image.height.saturating_sub(overlay.height) / 2,
+        ),
+    }
+}
+
+/// Alpha blend the overlay onto the image at (x, y)
+fn blend(image: &mut Image, overlay: &Image, x: usize, y: usize, opacity: f32) {
+    for row in 0..overlay.height.min(image.height.saturating_sub(y)) {
+        for col in 0..overlay.width.min(image.width.saturating_sub(x)) {
+            let o = (row * overlay.width + col) * 4;
+            let i = ((y + row) * image.width + x + col) * 4;
+            let a = overlay.data[o + 3] as f32 / 255.0 * opacity;
+            for c in 0..3 {
+                let v = overlay.data[o + c] as f32 * a + image.data[i + c] as f32 * (1.0 - a);
+                image.data[i + c] = v.round() as u8;
+            }
+        }
+    }
+}
+
+/// Decode the first frame of an image to RGBA, `size` picks the output size from the
+/// source size before anything is decoded
+pub(crate) unsafe fn decode_rgba(
+    path: &Path,
+    size: impl FnOnce(usize, usize) -> Result<(usize, usize)>,
+) -> Result<Image> {
+    let mut demux = Demuxer::new(path.to_str().unwrap())?;
+    let info = demux.probe_input()?;
+    let stream = info
+        .best_video()
+        .ok_or(Error::msg("No image stream found"))?;
+    let (width, height) = size(stream.width, stream.height)?;
+
+    let mut decoder = Decoder::new();
+    decoder.setup_decoder(stream, None)?;
+    let mut scaler = Scaler::new();
+    while let Ok((mut pkt, _)) = demux.get_packet() {
+        if let Some(mut frame) = decoder.decode_pkt(pkt)?.into_iter().next() {
+            let mut rgba =
+                scaler.process_frame(frame, width as u16, height as u16, AV_PIX_FMT_RGBA)?;
+            let line_size = (*rgba).linesize[0] as usize;
+            let mut data = Vec::with_capacity(4 * width * height);
+            for row in 0..height {
+                data.extend_from_slice(slice::from_raw_parts(
+                    (*rgba).data[0].add(line_size * row),
+                    4 * width,
+                ));
+            }
+            av_frame_free(&mut frame);
+            av_frame_free(&mut rgba);
+            av_packet_free(&mut pkt);
+            return Ok(Image {
+                width,
+                height,
+                data,
+            });
+        }
+    }
+    bail!("No image data found in {}", path.display())
+}
+
+pub(crate) unsafe fn encode_webp(image: &Image, out: &Path) -> Result<()> {
+    let mut frame = av_frame_alloc();
+    (*frame).width = image.width as i32;
+    (*frame).height = image.height as i32;
+    (*frame).format = AV_PIX_FMT_RGBA as i32;
+    if av_frame_get_buffer(frame, 0) < 0 {
+        av_frame_free(&mut frame);
+        bail!("Failed to allocate frame");
+    }
+    let line_size = (*frame).linesize[0] as usize;
+    for row in 0..image.height {
+        ptr::copy_nonoverlapping(
+            image.data.as_ptr().add(row * image.width * 4),
+            (*frame).data[0].add(row * line_size),
+            image.width * 4,
+        );
+    }
+    let mut scaler = Scaler::new();
+    let yuv = scaler.process_frame(
+        frame,
+        image.width as u16,
+        image.height as u16,
+        AV_PIX_FMT_YUV420P,
+    );
+    av_frame_free(&mut frame);
+    let mut yuv = yuv?;
+
+    let mut enc = Encoder::new(AV_CODEC_ID_WEBP)?
+        .with_width(image.width as i32)
+        .with_height(image.height as i32)
+        .with_pix_fmt(AV_PIX_FMT_YUV420P)
+        .open(None)?;
+    let mut muxer = Muxer::builder()
+        .with_output_path(out.to_str().unwrap(), Some("webp"))?
+        .with_stream_encoder(&enc)?
+        .build()?;
+    muxer.open(None)?;
+    let mut packets = enc.encode_frame(yuv)?;
+    av_frame_free(&mut yuv);
+    // flush
+    packets.extend(enc.encode_frame(ptr::null_mut())?);
+    for mut pkt in packets {
+        muxer.write_packet(pkt)?;
+        av_packet_free(&mut pkt);
+    }
+    muxer.close()?;
+    Ok(())
+}
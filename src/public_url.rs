@@ -0,0 +1,107 @@
+use std::io::Cursor;
+
+use log::warn;
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::{ContentType, Header};
+use rocket::{Data, Request, Response};
+use url::Url;
+
+use crate::settings::Settings;
+
+/// Public url picked for a request, None when the main public_url is used
+struct RequestPublicUrl(Option<String>);
+
+/// Check if the request was made to an onion address
+pub fn is_onion(req: &Request<'_>) -> bool {
+    req.host()
+        .map(|h| h.domain().as_str().to_lowercase().ends_with(".onion"))
+        .unwrap_or(false)
+}
+
+/// Serves generated urls with the public url matching the Host header.
+///
+/// Responses are generated with `public_url`, json/html/text bodies and
+/// Location headers are rewritten when another public url was requested.
+pub struct PublicUrlFairing {
+    public_url: String,
+    /// (host, url) of the other public urls
+    alternates: Vec<(String, String)>,
+}
+
+impl PublicUrlFairing {
+    pub fn new(settings: &Settings) -> Self {
+        let alternates = settings
+            .public_urls
+            .iter()
+            .flatten()
+            .filter_map(|u| match Url::parse(u) {
+                Ok(p) => Some((
+                    p.host_str()?.to_lowercase(),
+                    u.trim_end_matches('/').to_string(),
+                )),
+                Err(e) => {
+                    warn!("Ignoring invalid public url {}: {}", u, e);
+                    None
+                }
+            })
+            .collect();
+        Self {
+            public_url: settings.public_url.trim_end_matches('/').to_string(),
+            alternates,
+        }
+    }
+
+    fn url_for(&self, req: &Request<'_>) -> Option<String> {
+        let host = req.host()?.domain().as_str().to_lowercase();
+        self.alternates
+            .iter()
+            .find(|(h, _)| *h == host)
+            .map(|(_, u)| u.clone())
+            .filter(|u| *u != self.public_url)
+    }
+}
+
+#[rocket::async_trait]
+impl Fairing for PublicUrlFairing {
+    fn info(&self) -> Info {
+        Info {
+            name: "Public URL",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, req: &mut Request<'_>, _data: &mut Data<'_>) {
+        let url = self.url_for(req);
+        req.local_cache(|| RequestPublicUrl(url));
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, rsp: &mut Response<'r>) {
+        let url = match &req.local_cache(|| RequestPublicUrl(None)).0 {
+            Some(u) => u,
+            None => return,
+        };
+        if let Some(loc) = rsp.headers().get_one("location") {
+            let loc = loc.replace(&self.public_url, url);
+            rsp.set_header(Header::new("location", loc));
+        }
+        let rewrite = match rsp.content_type() {
+            Some(ct) => {
+                ct == ContentType::JSON
+                    || ct == ContentType::HTML
+                    || ct == ContentType::Plain
+                    || ct == ContentType::XML
+            }
+            None => false,
+        };
+        if !rewrite {
+            return;
+        }
+        match rsp.body_mut().to_string().await {
+            Ok(body) => {
+                let body = body.replace(&self.public_url, url);
+                rsp.set_sized_body(body.len(), Cursor::new(body));
+            }
+            Err(e) => warn!("Failed to rewrite public url: {}", e),
+        }
+    }
+}
@@ -0,0 +1,163 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tokio::sync::oneshot;
+
+use crate::settings::Settings;
+
+/// Media processing time assumed before any upload has been processed
+const DEFAULT_ESTIMATE: Duration = Duration::from_secs(5);
+
+/// Limits how many uploads are processed (transcoded) at the same time.
+///
+/// Waiting uploads are granted a slot by fewest running jobs per user, so one
+/// user sending many files cannot starve everyone else.
+#[derive(Clone)]
+pub struct ProcessingQueue {
+    inner: Arc<QueueInner>,
+}
+
+struct QueueInner {
+    parallel: usize,
+    state: Mutex<QueueState>,
+    processed: AtomicU64,
+    processed_micros: AtomicU64,
+}
+
+#[derive(Default)]
+struct QueueState {
+    active: usize,
+    running: HashMap<Vec<u8>, usize>,
+    waiting: VecDeque<Waiter>,
+}
+
+struct Waiter {
+    user: Vec<u8>,
+    tx: oneshot::Sender<ProcessingPermit>,
+}
+
+/// A processing slot, released when dropped
+pub struct ProcessingPermit {
+    queue: ProcessingQueue,
+    user: Vec<u8>,
+    start: Instant,
+}
+
+impl ProcessingQueue {
+    pub fn new(settings: &Settings) -> Self {
+        let parallel = settings
+            .processing
+            .as_ref()
+            .and_then(|p| p.parallel)
+            .unwrap_or_else(|| {
+                std::thread::available_parallelism()
+                    .map(|n| n.get())
+                    .unwrap_or(1)
+            })
+            .max(1);
+        Self {
+            inner: Arc::new(QueueInner {
+                parallel,
+                state: Mutex::new(QueueState::default()),
+                processed: AtomicU64::new(0),
+                processed_micros: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    /// Wait for a processing slot
+    pub async fn acquire(&self, user: &[u8]) -> ProcessingPermit {
+        let rx = {
+            let mut state = self.inner.state.lock().unwrap();
+            if state.active < self.inner.parallel && state.waiting.is_empty() {
+                state.active += 1;
+                *state.running.entry(user.to_vec()).or_default() += 1;
+                return self.permit(user);
+            }
+            let (tx, rx) = oneshot::channel();
+            state.waiting.push_back(Waiter {
+                user: user.to_vec(),
+                tx,
+            });
+            rx
+        };
+        // the sender is only dropped together with the queue
+        rx.await.expect("processing queue closed")
+    }
+
+    fn permit(&self, user: &[u8]) -> ProcessingPermit {
+        ProcessingPermit {
+            queue: self.clone(),
+            user: user.to_vec(),
+            start: Instant::now(),
+        }
+    }
+
+    fn release(&self, user: &[u8], elapsed: Duration) {
+        self.inner.processed.fetch_add(1, Ordering::Relaxed);
+        self.inner
+            .processed_micros
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+
+        let next = {
+            let mut state = self.inner.state.lock().unwrap();
+            state.active -= 1;
+            if let Some(n) = state.running.get_mut(user) {
+                *n -= 1;
+                if *n == 0 {
+                    state.running.remove(user);
+                }
+            }
+            // requests which went away while waiting don't need a slot
+            state.waiting.retain(|w| !w.tx.is_closed());
+            let pick = state
+                .waiting
+                .iter()
+                .enumerate()
+                .min_by_key(|(i, w)| (state.running.get(&w.user).copied().unwrap_or(0), *i))
+                .map(|(i, _)| i);
+            match pick.and_then(|i| state.waiting.remove(i)) {
+                Some(w) => {
+                    state.active += 1;
+                    *state.running.entry(w.user.clone()).or_default() += 1;
+                    Some(w)
+                }
+                None => None,
+            }
+        };
+        if let Some(w) = next {
+            // dropping a permit which could not be delivered releases the slot again
+            let _ = w.tx.send(self.permit(&w.user));
+        }
+    }
+
+    /// Number of uploads waiting for a slot
+    pub fn depth(&self) -> usize {
+        self.inner.state.lock().unwrap().waiting.len()
+    }
+
+    /// Number of uploads being processed
+    pub fn active(&self) -> usize {
+        self.inner.state.lock().unwrap().active
+    }
+
+    /// Estimated time until a newly queued upload is processed
+    pub fn estimate(&self) -> Duration {
+        let processed = self.inner.processed.load(Ordering::Relaxed);
+        let avg = if processed == 0 {
+            DEFAULT_ESTIMATE
+        } else {
+            Duration::from_micros(self.inner.processed_micros.load(Ordering::Relaxed) / processed)
+        };
+        let rounds = self.depth() / self.inner.parallel + 1;
+        avg * rounds as u32
+    }
+}
+
+impl Drop for ProcessingPermit {
+    fn drop(&mut self) {
+        self.queue.release(&self.user, self.start.elapsed());
+    }
+}
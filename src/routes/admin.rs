@@ -1,14 +1,89 @@
+#[cfg(feature = "analytics")]
+use crate::analytics::database::{AnalyticsClient, AnalyticsDay, AnalyticsHour};
+use crate::auth::api_key::{generate_key, ApiKey, ApiKeyScope};
 use crate::auth::nip98::Nip98Auth;
-use crate::db::{Database, FileUpload};
-use crate::routes::{Nip94Event, PagedResult};
+use crate::auth::replay::{ReplayCache, ReplayCacheStats};
+use crate::background;
+use crate::background::backup::{BackupState, BackupStatus};
+#[cfg(not(feature = "media-compression"))]
+use crate::background::bulk::BulkAction;
+use crate::background::bulk::{BulkJob, BULK_JOB};
+use crate::background::report::{current_report, OperatorReport};
+#[cfg(feature = "media-compression")]
+use crate::background::reprocess::{ReprocessJob, REPROCESS_JOB};
+use crate::background::scrub::{ScrubState, ScrubStatus, Scrubber, VerifyResult};
+use crate::background::sources::UploadSource;
+use crate::background::users::{MergeUsersJob, MERGE_USERS_JOB};
+use crate::background::JobCancels;
+use crate::blocklist::{DenyKind, DenyRule};
+use crate::capabilities::{self, CapabilityReport};
+use crate::db::{
+    CorruptFile, Database, FileUpload, Job, JobStatus, MetadataSource, ProcessingReport,
+    UploadClient, User,
+};
+use crate::deletion::DeleteEvent;
+use crate::legal_hold::{LegalHoldEvent, LegalHoldRelease};
+use crate::metadata::clean_text;
+use crate::routes::error::{ApiError, ErrorCode};
+#[cfg(any(feature = "blossom", feature = "nip96"))]
+use crate::routes::failures::UploadFailure;
+use crate::routes::{Nip94Event, PagedResult, Sha256Param};
 use crate::settings::Settings;
+use log::info;
 use rocket::serde::json::Json;
+use rocket::serde::Deserialize;
 use rocket::serde::Serialize;
 use rocket::{routes, Responder, Route, State};
-use sqlx::{Error, Row};
+use sqlx::{Error, MySql, QueryBuilder, Row};
+use std::time::{Duration, Instant};
+
+/// Most mime types or classes the file list can be filtered on
+const MAX_MIME_FILTERS: usize = 16;
+
+/// How long the total of a file list filter is reused for
+const FILE_COUNT_TTL: Duration = Duration::from_secs(60);
 
 pub fn admin_routes() -> Vec<Route> {
-    routes![admin_list_files, admin_get_self]
+    #[allow(unused_mut)]
+    let mut routes = routes![
+        admin_list_files,
+        admin_list_users,
+        admin_get_self,
+        admin_list_jobs,
+        admin_get_job,
+        admin_retry_job,
+        admin_cancel_job,
+        admin_integrity,
+        admin_verify_file,
+        admin_auth_stats,
+        admin_allow_blocked_types,
+        admin_set_user_tier,
+        admin_list_api_keys,
+        admin_create_api_key,
+        admin_revoke_api_key,
+        admin_bulk_files,
+        admin_merge_users,
+        admin_dead_sources,
+        admin_list_deny_rules,
+        admin_add_deny_rule,
+        admin_delete_deny_rule,
+        admin_capabilities,
+        admin_backup_status,
+        admin_get_hold,
+        admin_hold_file,
+        admin_release_file,
+        admin_delete_log,
+        admin_weekly_report
+    ];
+    #[cfg(any(feature = "blossom", feature = "nip96"))]
+    routes.extend(routes![admin_list_failures]);
+    #[cfg(feature = "media-compression")]
+    routes.extend(routes![admin_reprocess_file, admin_reprocess_files]);
+    #[cfg(feature = "analytics")]
+    routes.extend(routes![admin_analytics]);
+    #[cfg(feature = "labels")]
+    routes.extend(routes![admin_cleanup_labels]);
+    routes
 }
 
 #[derive(Serialize, Default)]
@@ -23,20 +98,27 @@ struct AdminResponseBase<T> {
 
 #[derive(Responder)]
 enum AdminResponse<T> {
-    #[response(status = 500)]
-    GenericError(Json<AdminResponseBase<T>>),
+    Error(ApiError),
 
     #[response(status = 200)]
     Ok(Json<AdminResponseBase<T>>),
 }
 
+impl<T> From<ApiError> for AdminResponse<T> {
+    fn from(e: ApiError) -> Self {
+        Self::Error(e)
+    }
+}
+
+impl<T> From<ErrorCode> for AdminResponse<T> {
+    fn from(e: ErrorCode) -> Self {
+        Self::Error(e.into())
+    }
+}
+
 impl<T> AdminResponse<T> {
     pub fn error(msg: &str) -> Self {
-        Self::GenericError(Json(AdminResponseBase {
-            status: "error".to_string(),
-            message: Some(msg.to_string()),
-            data: None,
-        }))
+        Self::Error(ApiError::internal(msg))
     }
 
     pub fn success(msg: T) -> Self {
@@ -48,6 +130,27 @@ impl<T> AdminResponse<T> {
     }
 }
 
+/// File with the profiles of its uploaders, for moderation
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct AdminFile {
+    #[serde(flatten)]
+    pub file: Nip94Event,
+    pub uploader: Vec<User>,
+    /// Set when media processing produced this file
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub processing: Option<ProcessingReport>,
+    /// Apps the file was uploaded with, when client hints are recorded
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub clients: Vec<UploadClient>,
+    /// Who computed the blurhash tag
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub blurhash_source: Option<MetadataSource>,
+    /// Who computed the dim tag
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dim_source: Option<MetadataSource>,
+}
+
 #[derive(Serialize)]
 pub struct SelfUser {
     pub is_admin: bool,
@@ -57,7 +160,7 @@ pub struct SelfUser {
 
 #[rocket::get("/self")]
 async fn admin_get_self(auth: Nip98Auth, db: &State<Database>) -> AdminResponse<SelfUser> {
-    let pubkey_vec = auth.event.pubkey.to_bytes().to_vec();
+    let pubkey_vec = auth.pubkey.to_bytes().to_vec();
     match db.get_user(&pubkey_vec).await {
         Ok(user) => {
             let s = match db.get_user_stats(user.id).await {
@@ -72,63 +175,1078 @@ async fn admin_get_self(auth: Nip98Auth, db: &State<Database>) -> AdminResponse<
                 total_size: s.total_size,
             })
         }
-        Err(_) => AdminResponse::error("User not found"),
+        Err(_) => ErrorCode::UserNotFound.into(),
     }
 }
 
-#[rocket::get("/files?<page>&<count>")]
+/// Load the authenticated user and make sure they are an admin
+async fn get_admin(auth: &Nip98Auth, db: &Database) -> Result<User, ApiError> {
+    // API keys are for uploads only, admin actions need a signed event
+    if auth.event.is_none() {
+        return Err(ErrorCode::NotAdmin.into());
+    }
+    let pubkey_vec = auth.pubkey.to_bytes().to_vec();
+    let user = match db.get_user(&pubkey_vec).await {
+        Ok(user) => user,
+        Err(_) => return Err(ErrorCode::UserNotFound.into()),
+    };
+    if !user.is_admin {
+        return Err(ErrorCode::NotAdmin.into());
+    }
+    Ok(user)
+}
+
+/// List every file, `mime` is a comma separated list of types or classes, eg. `image,video/mp4`
+#[rocket::get("/files?<page>&<count>&<license>&<mime>")]
 async fn admin_list_files(
     auth: Nip98Auth,
     page: u32,
     count: u32,
+    license: Option<&str>,
+    mime: Option<&str>,
     db: &State<Database>,
     settings: &State<Settings>,
-) -> AdminResponse<PagedResult<Nip94Event>> {
-    let pubkey_vec = auth.event.pubkey.to_bytes().to_vec();
+) -> AdminResponse<PagedResult<AdminFile>> {
     let server_count = count.clamp(1, 5_000);
 
-    let user = match db.get_user(&pubkey_vec).await {
-        Ok(user) => user,
-        Err(_) => return AdminResponse::error("User not found"),
+    if let Err(e) = get_admin(&auth, db).await {
+        return e.into();
+    }
+    let mime: Vec<String> = mime
+        .into_iter()
+        .flat_map(|m| m.split(','))
+        .map(str::trim)
+        .filter(|m| !m.is_empty())
+        .map(str::to_string)
+        .collect();
+    if mime.len() > MAX_MIME_FILTERS {
+        return AdminResponse::error(&format!(
+            "At most {} mime types can be filtered on",
+            MAX_MIME_FILTERS
+        ));
+    }
+    let (files, count) = match db
+        .list_all_files(license, &mime, page * server_count, server_count)
+        .await
+    {
+        Ok(r) => r,
+        Err(e) => return AdminResponse::error(&format!("Could not list files: {}", e)),
     };
+    let mut admin_files = Vec::with_capacity(files.len());
+    for f in &files {
+        let uploader = match db.get_file_owners(&f.id).await {
+            Ok(u) => u,
+            Err(e) => return AdminResponse::error(&format!("Could not load owners: {}", e)),
+        };
+        let processing = match db.get_processing_report(&f.id).await {
+            Ok(r) => r,
+            Err(e) => return AdminResponse::error(&format!("Could not load report: {}", e)),
+        };
+        let clients = match db.list_upload_clients(&f.id).await {
+            Ok(c) => c,
+            Err(e) => return AdminResponse::error(&format!("Could not load clients: {}", e)),
+        };
+        admin_files.push(AdminFile {
+            file: Nip94Event::from_upload(settings, f),
+            uploader,
+            processing,
+            clients,
+            blurhash_source: f.blur_hash_source,
+            dim_source: f.dim_source,
+        });
+    }
+    AdminResponse::success(PagedResult {
+        count: admin_files.len() as u32,
+        page,
+        total: count as u32,
+        files: admin_files,
+    })
+}
 
-    if !user.is_admin {
-        return AdminResponse::error("User is not an admin");
+#[rocket::get("/users?<page>&<count>")]
+async fn admin_list_users(
+    auth: Nip98Auth,
+    page: u32,
+    count: u32,
+    db: &State<Database>,
+) -> AdminResponse<PagedResult<User>> {
+    let server_count = count.clamp(1, 5_000);
+
+    if let Err(e) = get_admin(&auth, db).await {
+        return e.into();
     }
-    match db.list_all_files(page * server_count, server_count).await {
-        Ok((files, count)) => AdminResponse::success(PagedResult {
-            count: files.len() as u32,
+    match db.list_users(page * server_count, server_count).await {
+        Ok((users, count)) => AdminResponse::success(PagedResult {
+            count: users.len() as u32,
             page,
             total: count as u32,
-            files: files
-                .iter()
-                .map(|f| Nip94Event::from_upload(settings, f))
-                .collect(),
+            files: users,
+        }),
+        Err(e) => AdminResponse::error(&format!("Could not list users: {}", e)),
+    }
+}
+
+#[cfg(any(feature = "blossom", feature = "nip96"))]
+#[rocket::get("/failures?<page>&<count>&<pubkey>")]
+async fn admin_list_failures(
+    auth: Nip98Auth,
+    page: u32,
+    count: u32,
+    pubkey: Option<&str>,
+    db: &State<Database>,
+) -> AdminResponse<PagedResult<UploadFailure>> {
+    let server_count = count.clamp(1, 5_000);
+
+    if let Err(e) = get_admin(&auth, db).await {
+        return e.into();
+    }
+    let pubkey = match pubkey.map(nostr::PublicKey::parse) {
+        None => None,
+        Some(Ok(p)) => Some(p.to_bytes().to_vec()),
+        Some(Err(e)) => return ApiError::with_detail(ErrorCode::BadRequest, e.to_string()).into(),
+    };
+    match db
+        .list_upload_failures(pubkey.as_ref(), page * server_count, server_count)
+        .await
+    {
+        Ok((failures, count)) => AdminResponse::success(PagedResult {
+            count: failures.len() as u32,
+            page,
+            total: count as u32,
+            files: failures,
+        }),
+        Err(e) => AdminResponse::error(&format!("Could not list failures: {}", e)),
+    }
+}
+
+#[rocket::get("/jobs?<page>&<count>&<status>")]
+async fn admin_list_jobs(
+    auth: Nip98Auth,
+    page: u32,
+    count: u32,
+    status: Option<&str>,
+    db: &State<Database>,
+) -> AdminResponse<PagedResult<Job>> {
+    let server_count = count.clamp(1, 5_000);
+
+    if let Err(e) = get_admin(&auth, db).await {
+        return e.into();
+    }
+    let status = match status {
+        None => None,
+        Some("queued") => Some(JobStatus::Queued),
+        Some("running") => Some(JobStatus::Running),
+        Some("complete") => Some(JobStatus::Complete),
+        Some("failed") => Some(JobStatus::Failed),
+        Some("cancelled") => Some(JobStatus::Cancelled),
+        Some(_) => {
+            return ApiError::with_detail(ErrorCode::BadRequest, "Invalid job status").into()
+        }
+    };
+    match db
+        .list_jobs(status, page * server_count, server_count)
+        .await
+    {
+        Ok((jobs, count)) => AdminResponse::success(PagedResult {
+            count: jobs.len() as u32,
+            page,
+            total: count as u32,
+            files: jobs,
+        }),
+        Err(e) => AdminResponse::error(&format!("Could not list jobs: {}", e)),
+    }
+}
+
+#[rocket::get("/jobs/<id>")]
+async fn admin_get_job(auth: Nip98Auth, id: u64, db: &State<Database>) -> AdminResponse<Job> {
+    if let Err(e) = get_admin(&auth, db).await {
+        return e.into();
+    }
+    match db.get_job(id).await {
+        Ok(Some(job)) => AdminResponse::success(job),
+        Ok(None) => ApiError::with_detail(ErrorCode::NotFound, "Job not found").into(),
+        Err(e) => AdminResponse::error(&format!("Could not load job: {}", e)),
+    }
+}
+
+#[rocket::post("/jobs/<id>/retry")]
+async fn admin_retry_job(auth: Nip98Auth, id: u64, db: &State<Database>) -> AdminResponse<()> {
+    if let Err(e) = get_admin(&auth, db).await {
+        return e.into();
+    }
+    match db.retry_job(id).await {
+        Ok(true) => AdminResponse::success(()),
+        Ok(false) => {
+            ApiError::with_detail(ErrorCode::NotFound, "Job not found or not in failed state")
+                .into()
+        }
+        Err(e) => AdminResponse::error(&format!("Could not retry job: {}", e)),
+    }
+}
+
+/// Cancel a queued job, or stop a running job once its handler reaches a safe point
+#[rocket::post("/jobs/<id>/cancel")]
+async fn admin_cancel_job(
+    auth: Nip98Auth,
+    id: u64,
+    db: &State<Database>,
+    cancels: &State<JobCancels>,
+) -> AdminResponse<JobStatus> {
+    if let Err(e) = get_admin(&auth, db).await {
+        return e.into();
+    }
+    if cancels.cancel(id) {
+        return AdminResponse::success(JobStatus::Running);
+    }
+    match db.cancel_queued_job(id).await {
+        Ok(true) => AdminResponse::success(JobStatus::Cancelled),
+        Ok(false) => ApiError::with_detail(
+            ErrorCode::NotFound,
+            "Job not found or not running or queued",
+        )
+        .into(),
+        Err(e) => AdminResponse::error(&format!("Could not cancel job: {}", e)),
+    }
+}
+
+/// Replay protection counters
+#[rocket::get("/auth-stats")]
+async fn admin_auth_stats(
+    auth: Nip98Auth,
+    db: &State<Database>,
+    replay: &State<ReplayCache>,
+) -> AdminResponse<ReplayCacheStats> {
+    if let Err(e) = get_admin(&auth, db).await {
+        return e.into();
+    }
+    AdminResponse::success(replay.stats())
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct IntegrityReport {
+    pub scrub: ScrubStatus,
+    pub corrupt: PagedResult<CorruptFile>,
+}
+
+#[rocket::get("/integrity?<page>&<count>")]
+async fn admin_integrity(
+    auth: Nip98Auth,
+    page: u32,
+    count: u32,
+    db: &State<Database>,
+    scrub: &State<ScrubState>,
+) -> AdminResponse<IntegrityReport> {
+    let server_count = count.clamp(1, 5_000);
+
+    if let Err(e) = get_admin(&auth, db).await {
+        return e.into();
+    }
+    match db
+        .list_corrupt_files(page * server_count, server_count)
+        .await
+    {
+        Ok((files, total)) => AdminResponse::success(IntegrityReport {
+            scrub: scrub.read().await.clone(),
+            corrupt: PagedResult {
+                count: files.len() as u32,
+                page,
+                total: total as u32,
+                files,
+            },
         }),
-        Err(e) => AdminResponse::error(&format!("Could not list files: {}", e)),
+        Err(e) => AdminResponse::error(&format!("Could not list corrupt files: {}", e)),
     }
 }
 
+#[rocket::post("/integrity/<sha256>/verify")]
+async fn admin_verify_file(
+    auth: Nip98Auth,
+    sha256: Result<Sha256Param, ApiError>,
+    db: &State<Database>,
+    settings: &State<Settings>,
+    scrub: &State<ScrubState>,
+) -> AdminResponse<VerifyResult> {
+    if let Err(e) = get_admin(&auth, db).await {
+        return e.into();
+    }
+    let id = match sha256 {
+        Ok(p) => p.id,
+        Err(e) => return e.into(),
+    };
+    match db.get_file(&id).await {
+        Ok(Some(_)) => {}
+        Ok(None) => return ErrorCode::NotFound.into(),
+        Err(e) => return AdminResponse::error(&format!("Could not load file: {}", e)),
+    }
+    let scrubber = Scrubber::new(
+        db.inner().clone(),
+        settings.inner().clone(),
+        scrub.inner().clone(),
+    );
+    match scrubber.verify_file(&id, false).await {
+        Ok(r) => AdminResponse::success(r),
+        Err(e) => AdminResponse::error(&format!("Could not verify file: {}", e)),
+    }
+}
+
+/// Allow or deny a pubkey to upload file types on the deny list
+#[rocket::post("/users/<pubkey>/allow-blocked-types?<allow>")]
+async fn admin_allow_blocked_types(
+    auth: Nip98Auth,
+    pubkey: &str,
+    allow: bool,
+    db: &State<Database>,
+) -> AdminResponse<bool> {
+    if let Err(e) = get_admin(&auth, db).await {
+        return e.into();
+    }
+    let pubkey = match nostr::PublicKey::parse(pubkey) {
+        Ok(p) => p.to_bytes().to_vec(),
+        Err(e) => return ApiError::with_detail(ErrorCode::BadRequest, e.to_string()).into(),
+    };
+    let user_id = match db.upsert_user(&pubkey).await {
+        Ok(u) => u,
+        Err(e) => return AdminResponse::error(&format!("Could not save user: {}", e)),
+    };
+    match db.set_user_allow_blocked_types(user_id, allow).await {
+        Ok(_) => AdminResponse::success(allow),
+        Err(e) => AdminResponse::error(&format!("Could not update user: {}", e)),
+    }
+}
+
+/// Set the retention tier of a pubkey, an empty tier uses the default retention
+#[rocket::post("/users/<pubkey>/tier?<tier>")]
+async fn admin_set_user_tier(
+    auth: Nip98Auth,
+    pubkey: &str,
+    tier: Option<&str>,
+    db: &State<Database>,
+) -> AdminResponse<Option<String>> {
+    if let Err(e) = get_admin(&auth, db).await {
+        return e.into();
+    }
+    let pubkey = match nostr::PublicKey::parse(pubkey) {
+        Ok(p) => p.to_bytes().to_vec(),
+        Err(e) => return ApiError::with_detail(ErrorCode::BadRequest, e.to_string()).into(),
+    };
+    let user_id = match db.upsert_user(&pubkey).await {
+        Ok(u) => u,
+        Err(e) => return AdminResponse::error(&format!("Could not save user: {}", e)),
+    };
+    let tier = tier.filter(|t| !t.is_empty());
+    match db.set_user_tier(user_id, tier).await {
+        Ok(_) => AdminResponse::success(tier.map(|t| t.to_string())),
+        Err(e) => AdminResponse::error(&format!("Could not update user: {}", e)),
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct NewApiKey {
+    /// Owner of uploads made with the key, hex or npub
+    pub pubkey: String,
+    pub description: Option<String>,
+    pub scopes: Vec<ApiKeyScope>,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct CreatedApiKey {
+    pub id: u64,
+    /// Plaintext key, only returned once
+    pub key: String,
+}
+
+#[rocket::get("/api-keys")]
+async fn admin_list_api_keys(auth: Nip98Auth, db: &State<Database>) -> AdminResponse<Vec<ApiKey>> {
+    if let Err(e) = get_admin(&auth, db).await {
+        return e.into();
+    }
+    match db.list_api_keys().await {
+        Ok(k) => AdminResponse::success(k),
+        Err(e) => AdminResponse::error(&format!("Could not list api keys: {}", e)),
+    }
+}
+
+#[rocket::post("/api-keys", data = "<body>", format = "json")]
+async fn admin_create_api_key(
+    auth: Nip98Auth,
+    body: Vec<u8>,
+    db: &State<Database>,
+) -> AdminResponse<CreatedApiKey> {
+    if let Err(e) = get_admin(&auth, db).await {
+        return e.into();
+    }
+    let req: NewApiKey = match auth.parse_json(&body) {
+        Ok(r) => r,
+        Err(e) => return e.into(),
+    };
+    if req.scopes.is_empty() {
+        return ApiError::with_detail(ErrorCode::BadRequest, "At least one scope is required")
+            .into();
+    }
+    let pubkey = match nostr::PublicKey::parse(&req.pubkey) {
+        Ok(p) => p.to_bytes().to_vec(),
+        Err(e) => return ApiError::with_detail(ErrorCode::BadRequest, e.to_string()).into(),
+    };
+    let user_id = match db.upsert_user(&pubkey).await {
+        Ok(u) => u,
+        Err(e) => return AdminResponse::error(&format!("Could not save user: {}", e)),
+    };
+    let (key, hash) = generate_key();
+    match db
+        .add_api_key(user_id, &hash, req.description.as_deref(), &req.scopes)
+        .await
+    {
+        Ok(id) => AdminResponse::success(CreatedApiKey { id, key }),
+        Err(e) => AdminResponse::error(&format!("Could not save api key: {}", e)),
+    }
+}
+
+#[rocket::delete("/api-keys/<id>")]
+async fn admin_revoke_api_key(auth: Nip98Auth, id: u64, db: &State<Database>) -> AdminResponse<()> {
+    if let Err(e) = get_admin(&auth, db).await {
+        return e.into();
+    }
+    match db.revoke_api_key(id).await {
+        Ok(true) => AdminResponse::success(()),
+        Ok(false) => ErrorCode::NotFound.into(),
+        Err(e) => AdminResponse::error(&format!("Could not revoke api key: {}", e)),
+    }
+}
+
+/// Remove labels of files which no longer exist, returns the number removed
+#[cfg(feature = "labels")]
+#[rocket::post("/labels/cleanup")]
+async fn admin_cleanup_labels(auth: Nip98Auth, db: &State<Database>) -> AdminResponse<u64> {
+    if let Err(e) = get_admin(&auth, db).await {
+        return e.into();
+    }
+    match db.delete_orphan_labels().await {
+        Ok(n) => AdminResponse::success(n),
+        Err(e) => AdminResponse::error(&format!("Could not remove labels: {}", e)),
+    }
+}
+
+#[cfg(feature = "analytics")]
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct AnalyticsReport {
+    pub hourly: Vec<AnalyticsHour>,
+    pub daily_uniques: Vec<AnalyticsDay>,
+    /// Uploads per client app, when client hints are recorded
+    pub clients: Vec<AnalyticsClient>,
+}
+
+/// Rollups written by the database analytics sink
+#[cfg(feature = "analytics")]
+#[rocket::get("/analytics?<days>")]
+async fn admin_analytics(
+    auth: Nip98Auth,
+    days: Option<u32>,
+    db: &State<Database>,
+) -> AdminResponse<AnalyticsReport> {
+    if let Err(e) = get_admin(&auth, db).await {
+        return e.into();
+    }
+    let since =
+        chrono::Utc::now() - chrono::TimeDelta::days(days.unwrap_or(7).clamp(1, 365) as i64);
+    let hourly = match db.list_analytics_hourly(since).await {
+        Ok(h) => h,
+        Err(e) => return AdminResponse::error(&format!("Could not load analytics: {}", e)),
+    };
+    let daily_uniques = match db.list_analytics_daily_uniques(since).await {
+        Ok(d) => d,
+        Err(e) => return AdminResponse::error(&format!("Could not load analytics: {}", e)),
+    };
+    match db.list_analytics_clients(since).await {
+        Ok(clients) => AdminResponse::success(AnalyticsReport {
+            hourly,
+            daily_uniques,
+            clients,
+        }),
+        Err(e) => AdminResponse::error(&format!("Could not load analytics: {}", e)),
+    }
+}
+
+/// Last weekly operator report, or the report of the last 7 days computed now with
+/// `fresh=true` or when none was created yet
+#[rocket::get("/reports/weekly?<fresh>")]
+async fn admin_weekly_report(
+    auth: Nip98Auth,
+    fresh: Option<bool>,
+    db: &State<Database>,
+) -> AdminResponse<OperatorReport> {
+    if let Err(e) = get_admin(&auth, db).await {
+        return e.into();
+    }
+    if !fresh.unwrap_or(false) {
+        match db.get_latest_operator_report().await {
+            Ok(Some(r)) => return AdminResponse::success(r),
+            Ok(None) => {}
+            Err(e) => return AdminResponse::error(&format!("Could not load report: {}", e)),
+        }
+    }
+    match current_report(db).await {
+        Ok(r) => AdminResponse::success(r),
+        Err(e) => AdminResponse::error(&format!("Could not build report: {}", e)),
+    }
+}
+
+/// Queue an action on all files matching a filter, returns the job id to poll
+#[rocket::post("/files/bulk", data = "<body>", format = "json")]
+async fn admin_bulk_files(
+    auth: Nip98Auth,
+    body: Vec<u8>,
+    db: &State<Database>,
+) -> AdminResponse<u64> {
+    if let Err(e) = get_admin(&auth, db).await {
+        return e.into();
+    }
+    let req: BulkJob = match auth.parse_json(&body) {
+        Ok(r) => r,
+        Err(e) => return e.into(),
+    };
+    if req.filter.is_empty() {
+        return ApiError::with_detail(ErrorCode::BadRequest, "Filter must not be empty").into();
+    }
+    if let Some(p) = &req.filter.uploader {
+        if let Err(e) = nostr::PublicKey::parse(p) {
+            return ApiError::with_detail(ErrorCode::BadRequest, e.to_string()).into();
+        }
+    }
+    #[cfg(not(feature = "media-compression"))]
+    if let BulkAction::Reprocess { .. } = req.action {
+        return ApiError::with_detail(
+            ErrorCode::BadRequest,
+            "Reprocessing requires media-compression",
+        )
+        .into();
+    }
+    match background::enqueue(db, BULK_JOB, &req).await {
+        Ok(id) => AdminResponse::success(id),
+        Err(e) => AdminResponse::error(&format!("Could not queue job: {}", e)),
+    }
+}
+
+/// Queue a job merging user rows whose pubkey was stored as hex text into the real user
+#[rocket::post("/users/merge-duplicates?<dry_run>")]
+async fn admin_merge_users(
+    auth: Nip98Auth,
+    dry_run: Option<bool>,
+    db: &State<Database>,
+) -> AdminResponse<u64> {
+    if let Err(e) = get_admin(&auth, db).await {
+        return e.into();
+    }
+    let job = MergeUsersJob {
+        dry_run: dry_run.unwrap_or(false),
+    };
+    match background::enqueue(db, MERGE_USERS_JOB, &job).await {
+        Ok(id) => AdminResponse::success(id),
+        Err(e) => AdminResponse::error(&format!("Could not queue job: {}", e)),
+    }
+}
+
+/// Files whose mirror source stopped responding, longest dead first
+#[rocket::get("/sources/dead?<page>&<count>")]
+async fn admin_dead_sources(
+    auth: Nip98Auth,
+    page: u32,
+    count: u32,
+    db: &State<Database>,
+) -> AdminResponse<PagedResult<UploadSource>> {
+    let server_count = count.clamp(1, 5_000);
+
+    if let Err(e) = get_admin(&auth, db).await {
+        return e.into();
+    }
+    match db
+        .list_dead_sources(page * server_count, server_count)
+        .await
+    {
+        Ok((files, total)) => AdminResponse::success(PagedResult {
+            count: files.len() as u32,
+            page,
+            total: total as u32,
+            files,
+        }),
+        Err(e) => AdminResponse::error(&format!("Could not list sources: {}", e)),
+    }
+}
+
+/// Compiled features and which capabilities the config enables
+#[rocket::get("/capabilities")]
+async fn admin_capabilities(
+    auth: Nip98Auth,
+    db: &State<Database>,
+) -> AdminResponse<CapabilityReport> {
+    if let Err(e) = get_admin(&auth, db).await {
+        return e.into();
+    }
+    AdminResponse::success(capabilities::report())
+}
+
+/// Result of the last scheduled backup
+#[rocket::get("/backup")]
+async fn admin_backup_status(
+    auth: Nip98Auth,
+    db: &State<Database>,
+    backup: &State<BackupState>,
+) -> AdminResponse<BackupStatus> {
+    if let Err(e) = get_admin(&auth, db).await {
+        return e.into();
+    }
+    AdminResponse::success(backup.read().await.clone())
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct LegalHoldStatus {
+    pub held: bool,
+    /// Release waiting for a second admin
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pending_release: Option<LegalHoldRelease>,
+    /// Audit trail, including holds which were released
+    pub log: Vec<LegalHoldEvent>,
+}
+
+async fn legal_hold_status(db: &Database, id: &Vec<u8>) -> AdminResponse<LegalHoldStatus> {
+    let held = match db.get_file(id).await {
+        Ok(Some(f)) => f.legal_hold,
+        Ok(None) => return ErrorCode::NotFound.into(),
+        Err(e) => return AdminResponse::error(&format!("Could not load file: {}", e)),
+    };
+    let pending_release = match db.get_legal_hold_release(id).await {
+        Ok(r) => r,
+        Err(e) => return AdminResponse::error(&format!("Could not load release: {}", e)),
+    };
+    match db.list_legal_hold_events(id).await {
+        Ok(log) => AdminResponse::success(LegalHoldStatus {
+            held,
+            pending_release,
+            log,
+        }),
+        Err(e) => AdminResponse::error(&format!("Could not load audit log: {}", e)),
+    }
+}
+
+/// Clean the reason of a hold or release
+fn hold_reason(reason: Option<&str>) -> Result<Option<String>, ApiError> {
+    let reason = reason.map(clean_text).filter(|r| !r.is_empty());
+    if reason.as_ref().is_some_and(|r| r.chars().count() > 255) {
+        return Err(ApiError::with_detail(
+            ErrorCode::BadRequest,
+            "Reason must be at most 255 characters",
+        ));
+    }
+    Ok(reason)
+}
+
+#[rocket::get("/files/<sha256>/hold")]
+async fn admin_get_hold(
+    auth: Nip98Auth,
+    sha256: Result<Sha256Param, ApiError>,
+    db: &State<Database>,
+) -> AdminResponse<LegalHoldStatus> {
+    if let Err(e) = get_admin(&auth, db).await {
+        return e.into();
+    }
+    let id = match sha256 {
+        Ok(p) => p.id,
+        Err(e) => return e.into(),
+    };
+    legal_hold_status(db, &id).await
+}
+
+/// Delete audit trail of a file, also after it was removed
+#[rocket::get("/files/<sha256>/deletes")]
+async fn admin_delete_log(
+    auth: Nip98Auth,
+    sha256: Result<Sha256Param, ApiError>,
+    db: &State<Database>,
+) -> AdminResponse<Vec<DeleteEvent>> {
+    if let Err(e) = get_admin(&auth, db).await {
+        return e.into();
+    }
+    let id = match sha256 {
+        Ok(p) => p.id,
+        Err(e) => return e.into(),
+    };
+    match db.list_delete_events(&id).await {
+        Ok(log) => AdminResponse::success(log),
+        Err(e) => AdminResponse::error(&format!("Could not load delete log: {}", e)),
+    }
+}
+
+/// Place a file under legal hold: it cannot be deleted, does not expire and every
+/// download is logged until two admins release it
+#[rocket::post("/files/<sha256>/hold?<reason>")]
+async fn admin_hold_file(
+    auth: Nip98Auth,
+    sha256: Result<Sha256Param, ApiError>,
+    reason: Option<&str>,
+    db: &State<Database>,
+) -> AdminResponse<LegalHoldStatus> {
+    let admin = match get_admin(&auth, db).await {
+        Ok(a) => a,
+        Err(e) => return e.into(),
+    };
+    let id = match sha256 {
+        Ok(p) => p.id,
+        Err(e) => return e.into(),
+    };
+    let reason = match hold_reason(reason) {
+        Ok(r) => r,
+        Err(e) => return e.into(),
+    };
+    match db.get_file(&id).await {
+        Ok(Some(_)) => {}
+        Ok(None) => return ErrorCode::NotFound.into(),
+        Err(e) => return AdminResponse::error(&format!("Could not load file: {}", e)),
+    }
+    if let Err(e) = db
+        .set_legal_hold(&id, &admin.pubkey, reason.as_deref())
+        .await
+    {
+        return AdminResponse::error(&format!("Could not place hold: {}", e));
+    }
+    info!(
+        "Legal hold placed on {} by {}",
+        hex::encode(&id),
+        hex::encode(&admin.pubkey)
+    );
+    legal_hold_status(db, &id).await
+}
+
+/// Release a legal hold. The first call requests the release, which a different admin
+/// has to confirm with a second call
+#[rocket::post("/files/<sha256>/release?<reason>")]
+async fn admin_release_file(
+    auth: Nip98Auth,
+    sha256: Result<Sha256Param, ApiError>,
+    reason: Option<&str>,
+    db: &State<Database>,
+) -> AdminResponse<LegalHoldStatus> {
+    let admin = match get_admin(&auth, db).await {
+        Ok(a) => a,
+        Err(e) => return e.into(),
+    };
+    let id = match sha256 {
+        Ok(p) => p.id,
+        Err(e) => return e.into(),
+    };
+    let reason = match hold_reason(reason) {
+        Ok(r) => r,
+        Err(e) => return e.into(),
+    };
+    match db.get_file(&id).await {
+        Ok(Some(f)) if f.legal_hold => {}
+        Ok(Some(_)) => {
+            return ApiError::with_detail(ErrorCode::BadRequest, "File is not under legal hold")
+                .into()
+        }
+        Ok(None) => return ErrorCode::NotFound.into(),
+        Err(e) => return AdminResponse::error(&format!("Could not load file: {}", e)),
+    }
+    let res = match db.get_legal_hold_release(&id).await {
+        Ok(None) => {
+            db.request_legal_hold_release(&id, &admin.pubkey, reason.as_deref())
+                .await
+        }
+        Ok(Some(r)) if r.requested_by == admin.pubkey => {
+            return ApiError::with_detail(
+                ErrorCode::BadRequest,
+                "Release must be confirmed by a second admin",
+            )
+            .into()
+        }
+        Ok(Some(_)) => {
+            let res = db
+                .release_legal_hold(&id, &admin.pubkey, reason.as_deref())
+                .await;
+            if res.is_ok() {
+                info!(
+                    "Legal hold on {} released by {}",
+                    hex::encode(&id),
+                    hex::encode(&admin.pubkey)
+                );
+            }
+            res
+        }
+        Err(e) => Err(e),
+    };
+    if let Err(e) = res {
+        return AdminResponse::error(&format!("Could not release hold: {}", e));
+    }
+    legal_hold_status(db, &id).await
+}
+
+#[rocket::get("/deny-rules")]
+async fn admin_list_deny_rules(
+    auth: Nip98Auth,
+    db: &State<Database>,
+) -> AdminResponse<Vec<DenyRule>> {
+    if let Err(e) = get_admin(&auth, db).await {
+        return e.into();
+    }
+    match db.list_deny_rules().await {
+        Ok(r) => AdminResponse::success(r),
+        Err(e) => AdminResponse::error(&format!("Could not list deny rules: {}", e)),
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct NewDenyRule {
+    pub kind: DenyKind,
+    /// Host, client tag pattern or hex pubkey
+    pub value: String,
+    pub reason: Option<String>,
+}
+
+/// Reject future uploads and mirrors from a domain, client or pubkey, returns the rule id
+#[rocket::post("/deny-rules", data = "<body>", format = "json")]
+async fn admin_add_deny_rule(
+    auth: Nip98Auth,
+    body: Vec<u8>,
+    db: &State<Database>,
+) -> AdminResponse<u64> {
+    if let Err(e) = get_admin(&auth, db).await {
+        return e.into();
+    }
+    let req: NewDenyRule = match auth.parse_json(&body) {
+        Ok(r) => r,
+        Err(e) => return e.into(),
+    };
+    let value = match req.kind {
+        DenyKind::Pubkey => match nostr::PublicKey::parse(&req.value) {
+            Ok(p) => p.to_hex(),
+            Err(e) => return ApiError::with_detail(ErrorCode::BadRequest, e.to_string()).into(),
+        },
+        DenyKind::Domain => req.value.trim().trim_matches('.').to_lowercase(),
+        DenyKind::Client => req.value.trim().to_lowercase(),
+    };
+    if value.is_empty() || value.len() > 255 {
+        return ApiError::with_detail(ErrorCode::BadRequest, "Value must be 1-255 characters")
+            .into();
+    }
+    match db
+        .add_deny_rule(req.kind, &value, req.reason.as_deref())
+        .await
+    {
+        Ok(id) => AdminResponse::success(id),
+        Err(Error::Database(e)) if e.code().is_some_and(|c| c == "23000") => {
+            ApiError::with_detail(ErrorCode::BadRequest, "Rule already exists").into()
+        }
+        Err(e) => AdminResponse::error(&format!("Could not save deny rule: {}", e)),
+    }
+}
+
+#[rocket::delete("/deny-rules/<id>")]
+async fn admin_delete_deny_rule(
+    auth: Nip98Auth,
+    id: u64,
+    db: &State<Database>,
+) -> AdminResponse<()> {
+    if let Err(e) = get_admin(&auth, db).await {
+        return e.into();
+    }
+    match db.delete_deny_rule(id).await {
+        Ok(true) => AdminResponse::success(()),
+        Ok(false) => ErrorCode::NotFound.into(),
+        Err(e) => AdminResponse::error(&format!("Could not delete deny rule: {}", e)),
+    }
+}
+
+#[cfg(feature = "media-compression")]
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct ReprocessFilter {
+    /// Only files whose mime type starts with this value
+    pub mime_prefix: Option<String>,
+    /// Only files without width/height metadata
+    #[serde(default)]
+    pub missing_metadata: bool,
+    #[serde(default)]
+    pub transcode: bool,
+    pub limit: Option<u32>,
+}
+
+#[cfg(feature = "media-compression")]
+#[rocket::post("/files/<sha256>/reprocess?<transcode>")]
+async fn admin_reprocess_file(
+    auth: Nip98Auth,
+    sha256: Result<Sha256Param, ApiError>,
+    transcode: Option<bool>,
+    db: &State<Database>,
+) -> AdminResponse<u64> {
+    if let Err(e) = get_admin(&auth, db).await {
+        return e.into();
+    }
+    let id = match sha256 {
+        Ok(p) => p.id,
+        Err(e) => return e.into(),
+    };
+    match db.get_file(&id).await {
+        Ok(Some(_)) => {}
+        Ok(None) => return ErrorCode::NotFound.into(),
+        Err(e) => return AdminResponse::error(&format!("Could not load file: {}", e)),
+    }
+    let job = ReprocessJob {
+        file: id,
+        transcode: transcode.unwrap_or(false),
+    };
+    match background::enqueue(db, REPROCESS_JOB, &job).await {
+        Ok(id) => AdminResponse::success(id),
+        Err(e) => AdminResponse::error(&format!("Could not queue job: {}", e)),
+    }
+}
+
+#[cfg(feature = "media-compression")]
+#[rocket::post("/files/reprocess", data = "<body>", format = "json")]
+async fn admin_reprocess_files(
+    auth: Nip98Auth,
+    body: Vec<u8>,
+    db: &State<Database>,
+) -> AdminResponse<Vec<u64>> {
+    if let Err(e) = get_admin(&auth, db).await {
+        return e.into();
+    }
+    let filter: ReprocessFilter = match auth.parse_json(&body) {
+        Ok(f) => f,
+        Err(e) => return e.into(),
+    };
+    let files = match db
+        .list_files_for_reprocess(
+            filter.mime_prefix.as_deref(),
+            filter.missing_metadata,
+            filter.limit.unwrap_or(100).clamp(1, 5_000),
+        )
+        .await
+    {
+        Ok(f) => f,
+        Err(e) => return AdminResponse::error(&format!("Could not list files: {}", e)),
+    };
+    let mut jobs = Vec::with_capacity(files.len());
+    for file in files {
+        let job = ReprocessJob {
+            file,
+            transcode: filter.transcode,
+        };
+        match background::enqueue(db, REPROCESS_JOB, &job).await {
+            Ok(id) => jobs.push(id),
+            Err(e) => return AdminResponse::error(&format!("Could not queue job: {}", e)),
+        }
+    }
+    AdminResponse::success(jobs)
+}
+
 impl Database {
-    pub async fn list_all_files(
+    #[cfg(feature = "media-compression")]
+    pub async fn list_files_for_reprocess(
         &self,
-        offset: u32,
+        mime_prefix: Option<&str>,
+        missing_metadata: bool,
         limit: u32,
-    ) -> Result<(Vec<FileUpload>, i64), Error> {
-        let results: Vec<FileUpload> = sqlx::query_as(
-            "select u.* \
+    ) -> Result<Vec<Vec<u8>>, Error> {
+        sqlx::query_scalar(
+            "select u.id \
             from uploads u \
+            where (? is null or u.mime_type like concat(?, '%')) \
+            and (? = false or u.width is null) \
             order by u.created desc \
-            limit ? offset ?",
+            limit ?",
         )
+        .bind(mime_prefix)
+        .bind(mime_prefix)
+        .bind(missing_metadata)
         .bind(limit)
-        .bind(offset)
         .fetch_all(&self.pool)
-        .await?;
-        let count: i64 = sqlx::query("select count(u.id) from uploads u")
-            .fetch_one(&self.pool)
-            .await?
-            .try_get(0)?;
+        .await
+    }
+
+    pub async fn set_user_allow_blocked_types(&self, id: u64, allow: bool) -> Result<(), Error> {
+        sqlx::query("update users set allow_blocked_types = ? where id = ?")
+            .bind(allow)
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Files of every user, newest first. `mime` lists types or classes (`image`, `video/mp4`)
+    /// which are matched by prefix, so the mime_type index can be used.
+    ///
+    /// The total is cached for [FILE_COUNT_TTL], counting millions of rows for every page is slow
+    pub async fn list_all_files(
+        &self,
+        license: Option<&str>,
+        mime: &[String],
+        offset: u32,
+        limit: u32,
+    ) -> Result<(Vec<FileUpload>, i64), Error> {
+        let patterns: Vec<String> = mime.iter().map(|m| mime_pattern(m)).collect();
+        let mut q = QueryBuilder::new("select u.* from uploads u");
+        push_file_filter(&mut q, license, &patterns);
+        q.push(" order by u.created desc limit ")
+            .push_bind(limit)
+            .push(" offset ")
+            .push_bind(offset);
+        let results: Vec<FileUpload> = q.build_query_as().fetch_all(&self.pool).await?;
+
+        let key = format!("{:?}", (license, &patterns));
+        let cached = self
+            .file_counts
+            .lock()
+            .unwrap()
+            .get(&key)
+            .filter(|(t, _)| t.elapsed() < FILE_COUNT_TTL)
+            .map(|(_, c)| *c);
+        let count = match cached {
+            Some(c) => c,
+            None => {
+                let mut q = QueryBuilder::new("select count(u.id) from uploads u");
+                push_file_filter(&mut q, license, &patterns);
+                let count: i64 = q.build().fetch_one(&self.pool).await?.try_get(0)?;
+                let mut counts = self.file_counts.lock().unwrap();
+                counts.retain(|_, (t, _)| t.elapsed() < FILE_COUNT_TTL);
+                counts.insert(key, (Instant::now(), count));
+                count
+            }
+        };
         Ok((results, count))
     }
 }
+
+/// LIKE pattern of a mime type or class, `image` matches every image/* type
+fn mime_pattern(mime: &str) -> String {
+    let m = mime
+        .to_lowercase()
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_");
+    if m.contains('/') {
+        format!("{}%", m)
+    } else {
+        format!("{}/%", m)
+    }
+}
+
+fn push_file_filter(q: &mut QueryBuilder<'_, MySql>, license: Option<&str>, patterns: &[String]) {
+    let mut sep = " where ";
+    if let Some(l) = license {
+        q.push(sep).push("u.license = ").push_bind(l.to_string());
+        sep = " and ";
+    }
+    if !patterns.is_empty() {
+        q.push(sep).push("(");
+        let mut or = q.separated(" or ");
+        for p in patterns {
+            or.push("u.mime_type like ")
+                .push_bind_unseparated(p.clone());
+        }
+        q.push(")");
+    }
+}
@@ -1,23 +1,49 @@
+use crate::auth::api_key::ApiKeyScope;
 use crate::auth::blossom::BlossomAuth;
-use crate::db::{Database, FileUpload};
-use crate::filesystem::FileStore;
-use crate::routes::{delete_file, Nip94Event};
-use crate::settings::Settings;
+use crate::background;
+use crate::background::disk::DiskState;
+use crate::background::mirror::{MirrorJob, MIRROR_JOB};
+use crate::background::retention::upload_expiry;
+#[cfg(feature = "torrent-v2")]
+use crate::background::torrent::queue_torrent;
+use crate::blocklist::UploadOrigin;
+use crate::db::{Database, FileUpload, JobStatus, UploadState};
+use crate::filesystem::{FileStore, FileTooLarge, LengthChecked, LengthMismatch, SizeLimited};
+use crate::hooks;
+use crate::metadata::{fit_name, sanitize_license, sanitize_name};
+use crate::mirror::{self, MirrorPreflight};
+#[cfg(feature = "media-compression")]
+use crate::processing::{media_input_types, MediaTooLarge};
+use crate::queue::ProcessingQueue;
+use crate::routes::error::{ApiError, ErrorCode};
+use crate::routes::progress::{UploadSession, UploadStage};
+use crate::routes::{
+    check_blocked_upload, check_denied_origin, check_disk_space, check_quota, check_tos,
+    delete_file, first_uploaded, quota_usage, record_client_hints, thumb_url, upload_limits,
+    user_thumbnails, Nip94Event, Sha256Param, UploadLimits, WithQuota,
+};
+#[cfg(feature = "media-compression")]
+use crate::settings::UnsupportedMediaPolicy;
+use crate::settings::{LengthPolicy, Settings, XTagPolicy};
+#[cfg(feature = "media-compression")]
+use crate::shed::MediaSlot;
+use crate::shed::{MirrorSlot, UploadSlot};
+use crate::transfer::{SlowTransfer, TransferGuard, TransferKind};
+use crate::upload_limit::UploadPermit;
+use crate::vanity::VanityHosts;
 use crate::webhook::Webhook;
-use log::error;
+use log::{error, warn};
 use nostr::prelude::hex;
 use nostr::{Alphabet, SingleLetterTag, TagKind};
 use rocket::data::ByteUnit;
-use rocket::futures::StreamExt;
 use rocket::http::{Header, Status};
 use rocket::response::Responder;
 use rocket::serde::json::Json;
 use rocket::{routes, Data, Request, Response, Route, State};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fs;
 use tokio::io::AsyncRead;
-use tokio_util::io::StreamReader;
 
 #[derive(Debug, Clone, Serialize)]
 #[serde(crate = "rocket::serde")]
@@ -28,8 +54,23 @@ pub struct BlobDescriptor {
     #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
     pub mime_type: Option<String>,
     pub created: u64,
+    /// Unix timestamp the blob is removed by the retention policy
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires: Option<u64>,
     #[serde(rename = "nip94", skip_serializing_if = "Option::is_none")]
     pub nip94: Option<HashMap<String, String>>,
+    /// Quota warning for the uploader
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub warning: Option<String>,
+    /// Ids of the owner's collections containing the blob, only in file listings
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub collections: Option<Vec<u64>>,
+    /// Set on uploads of a blob which was already stored
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dedup: Option<bool>,
+    /// Unix timestamp the blob was first uploaded, only when deduplicated
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub first_uploaded: Option<u64>,
 }
 
 impl BlobDescriptor {
@@ -48,6 +89,7 @@ impl BlobDescriptor {
             size: value.size,
             mime_type: Some(value.mime_type.clone()),
             created: value.created.timestamp() as u64,
+            expires: value.expires.map(|e| e.timestamp() as u64),
             nip94: Some(
                 Nip94Event::from_upload(settings, value)
                     .tags
@@ -55,7 +97,30 @@ impl BlobDescriptor {
                     .map(|r| (r[0].clone(), r[1].clone()))
                     .collect(),
             ),
+            warning: None,
+            collections: None,
+            dedup: None,
+            first_uploaded: None,
+        }
+    }
+
+    /// Replace the public url in the urls of the descriptor, eg. with a user's vanity host
+    pub fn rebase(mut self, settings: &Settings, public_url: &str) -> Self {
+        self.url = self.url.replacen(&settings.public_url, public_url, 1);
+        for k in ["url", "thumb"] {
+            if let Some(u) = self.nip94.as_mut().and_then(|n| n.get_mut(k)) {
+                *u = u.replacen(&settings.public_url, public_url, 1);
+            }
         }
+        self
+    }
+
+    /// Add the content addressed url of the thumbnail of the blob
+    pub fn with_thumbnail(mut self, settings: &Settings, thumb: Option<&Vec<u8>>) -> Self {
+        if let (Some(n), Some(t)) = (self.nip94.as_mut(), thumb) {
+            n.insert("thumb".to_string(), thumb_url(settings, t));
+        }
+        self
     }
 }
 
@@ -64,6 +129,48 @@ struct MirrorRequest {
     pub url: String,
 }
 
+/// Maximum number of urls in a mirror batch
+const MAX_MIRROR_BATCH: usize = 100;
+
+/// Maximum number of blobs mirrored by a migration
+const MAX_MIGRATE_BLOBS: usize = 10_000;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct MigrateRequest {
+    /// Blossom server to copy the blobs from
+    pub server: String,
+    /// Authorization header for GET /list on that server, eg. "Nostr <base64 event>"
+    pub authorization: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(crate = "rocket::serde")]
+struct MigrateResult {
+    #[serde(flatten)]
+    pub batch: MirrorBatch,
+    /// Blobs which are already stored for the user or have an invalid descriptor
+    pub skipped: u32,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(crate = "rocket::serde")]
+struct MirrorBatch {
+    pub id: u64,
+    pub items: Vec<MirrorBatchStatus>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(crate = "rocket::serde")]
+struct MirrorBatchStatus {
+    pub url: String,
+    pub status: JobStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub blob: Option<BlobDescriptor>,
+}
+
 #[cfg(feature = "media-compression")]
 pub fn blossom_routes() -> Vec<Route> {
     routes![
@@ -73,13 +180,29 @@ pub fn blossom_routes() -> Vec<Route> {
         upload_head,
         upload_media,
         head_media,
-        mirror
+        mirror,
+        mirror_head,
+        mirror_batch,
+        mirror_batch_status,
+        migrate,
+        limits
     ]
 }
 
 #[cfg(not(feature = "media-compression"))]
 pub fn blossom_routes() -> Vec<Route> {
-    routes![delete_blob, upload, list_files, upload_head, mirror]
+    routes![
+        delete_blob,
+        upload,
+        list_files,
+        upload_head,
+        mirror,
+        mirror_head,
+        mirror_batch,
+        mirror_batch_status,
+        migrate,
+        limits
+    ]
 }
 
 /// Generic holder response, mostly for errors
@@ -102,63 +225,82 @@ impl<'r> Responder<'r, 'static> for BlossomGenericResponse {
 enum BlossomResponse {
     Generic(BlossomGenericResponse),
 
+    Error(ApiError),
+
     #[response(status = 200)]
     BlobDescriptor(Json<BlobDescriptor>),
 
+    #[response(status = 200)]
+    Uploaded(WithQuota<Json<BlobDescriptor>>),
+
     #[response(status = 200)]
     BlobDescriptorList(Json<Vec<BlobDescriptor>>),
 }
 
 impl BlossomResponse {
     pub fn error(msg: impl Into<String>) -> Self {
-        Self::Generic(BlossomGenericResponse {
-            message: Some(msg.into()),
-            status: Status::InternalServerError,
-        })
+        Self::Error(ApiError::internal(msg))
+    }
+
+    /// Use the uploader's vanity host in the url of an uploaded blob
+    async fn with_vanity_url(
+        self,
+        settings: &Settings,
+        vanity: &Option<VanityHosts>,
+        pubkey: &Vec<u8>,
+    ) -> Self {
+        match (self, vanity) {
+            (Self::Uploaded(WithQuota(Json(d), q)), Some(v)) => {
+                let d = match v.public_url_for(pubkey).await {
+                    Some(base) => d.rebase(settings, &base),
+                    None => d,
+                };
+                Self::Uploaded(WithQuota(Json(d), q))
+            }
+            (r, _) => r,
+        }
+    }
+}
+
+impl From<ApiError> for BlossomResponse {
+    fn from(e: ApiError) -> Self {
+        Self::Error(e)
+    }
+}
+
+impl From<ErrorCode> for BlossomResponse {
+    fn from(e: ErrorCode) -> Self {
+        Self::Error(e.into())
     }
 }
 
 struct BlossomHead {
-    pub msg: Option<&'static str>,
+    pub error: Option<ApiError>,
 }
 
 impl<'r> Responder<'r, 'static> for BlossomHead {
-    fn respond_to(self, _request: &'r Request<'_>) -> rocket::response::Result<'static> {
-        let mut response = Response::new();
-        match self.msg {
-            Some(m) => {
-                response.set_status(Status::InternalServerError);
-                response.set_header(Header::new("x-upload-message", m));
+    fn respond_to(self, request: &'r Request<'_>) -> rocket::response::Result<'static> {
+        match self.error {
+            Some(e) => {
+                let message = e.message(request);
+                let mut response = e.respond_to(request)?;
+                response.set_header(Header::new("x-upload-message", message));
+                Ok(response)
             }
             None => {
+                let mut response = Response::new();
                 response.set_status(Status::Ok);
+                Ok(response)
             }
         }
-        Ok(response)
-    }
-}
-
-fn check_method(event: &nostr::Event, method: &str) -> bool {
-    if let Some(t) = event.tags.iter().find_map(|t| {
-        if t.kind() == TagKind::SingleLetter(SingleLetterTag::lowercase(Alphabet::T)) {
-            t.content()
-        } else {
-            None
-        }
-    }) {
-        return t.eq_ignore_ascii_case(method);
     }
-    false
 }
 
 fn check_whitelist(auth: &BlossomAuth, settings: &Settings) -> Option<BlossomResponse> {
     // check whitelist
     if let Some(wl) = &settings.whitelist {
-        if !wl.contains(&auth.event.pubkey.to_hex()) {
-            return Some(BlossomResponse::Generic(BlossomGenericResponse {
-                status: Status::Forbidden,
-                message: Some("Not on whitelist".to_string()),
-            }));
+        if !wl.contains(&auth.pubkey.to_hex()) {
+            return Some(ErrorCode::NotWhitelisted.into());
         }
     }
     None
@@ -166,170 +308,675 @@ fn check_whitelist(auth: &BlossomAuth, settings: &Settings) -> Option<BlossomRes
 
 #[rocket::delete("/<sha256>")]
 async fn delete_blob(
-    sha256: &str,
+    sha256: Result<Sha256Param, ApiError>,
     auth: BlossomAuth,
     fs: &State<FileStore>,
     db: &State<Database>,
+    settings: &State<Settings>,
 ) -> BlossomResponse {
-    match delete_file(sha256, &auth.event, fs, db).await {
+    if let Some(key) = &auth.api_key {
+        if !key.has_scope(ApiKeyScope::Delete) {
+            return ApiError::with_detail(ErrorCode::InvalidAuth, "API key scope missing").into();
+        }
+    }
+    if let Ok(p) = &sha256 {
+        let hashes = claimed_hashes(&auth);
+        if let Err(e) = require_x_tag(&auth, &hashes, settings) {
+            return e.into();
+        }
+        if !hashes.is_empty() && !hashes.contains(&p.id) {
+            return ApiError::with_detail(
+                ErrorCode::InvalidAuth,
+                "Blob does not match the x tag of the auth event",
+            )
+            .into();
+        }
+    }
+    match delete_file(sha256, &auth.pubkey, fs, db, settings).await {
         Ok(()) => BlossomResponse::Generic(BlossomGenericResponse {
             status: Status::Ok,
             message: None,
         }),
-        Err(e) => BlossomResponse::error(format!("Failed to delete file: {}", e)),
+        Err(e) => e.into(),
     }
 }
 
-#[rocket::get("/list/<pubkey>")]
+#[rocket::get("/list/<pubkey>?<license>")]
 async fn list_files(
     db: &State<Database>,
     settings: &State<Settings>,
+    vanity: &State<Option<VanityHosts>>,
     pubkey: &str,
+    license: Option<&str>,
 ) -> BlossomResponse {
     let id = if let Ok(i) = hex::decode(pubkey) {
         i
     } else {
-        return BlossomResponse::error("invalid pubkey");
+        return ApiError::with_detail(ErrorCode::BadRequest, "invalid pubkey").into();
+    };
+    let base = match vanity.inner() {
+        Some(v) => v.public_url_for(&id).await,
+        None => None,
     };
-    match db.list_files(&id, 0, 10_000).await {
+    let mut collections = match db.list_file_collections(&id).await {
+        Ok(c) => c,
+        Err(e) => return BlossomResponse::error(format!("Could not list files: {}", e)),
+    };
+    let thumbs = user_thumbnails(db, &id).await;
+    match db.list_files(&id, license, 0, 10_000).await {
         Ok((files, _count)) => BlossomResponse::BlobDescriptorList(Json(
             files
                 .iter()
-                .map(|f| BlobDescriptor::from_upload(settings, f))
+                .map(|f| {
+                    let mut d = BlobDescriptor::from_upload(settings, f)
+                        .with_thumbnail(settings, thumbs.get(&f.id));
+                    d.collections = collections.remove(&f.id);
+                    match &base {
+                        Some(b) => d.rebase(settings, b),
+                        None => d,
+                    }
+                })
                 .collect(),
         )),
         Err(e) => BlossomResponse::error(format!("Could not list files: {}", e)),
     }
 }
 
+/// Upload requirements for the authenticated pubkey
+#[rocket::get("/limits")]
+async fn limits(
+    auth: BlossomAuth,
+    db: &State<Database>,
+    settings: &State<Settings>,
+) -> Result<Json<UploadLimits>, ApiError> {
+    Ok(Json(upload_limits(&auth.pubkey, db, settings).await?))
+}
+
 #[rocket::head("/upload")]
-fn upload_head(auth: BlossomAuth, settings: &State<Settings>) -> BlossomHead {
-    check_head(auth, settings)
+fn upload_head(
+    auth: BlossomAuth,
+    settings: &State<Settings>,
+    disk: &State<DiskState>,
+) -> BlossomHead {
+    check_head(auth, settings, disk)
 }
 
 #[rocket::put("/upload", data = "<data>")]
 async fn upload(
     auth: BlossomAuth,
+    _slot: UploadSlot,
+    _permit: UploadPermit,
     fs: &State<FileStore>,
     db: &State<Database>,
     settings: &State<Settings>,
     webhook: &State<Option<Webhook>>,
+    disk: &State<DiskState>,
+    queue: &State<ProcessingQueue>,
+    vanity: &State<Option<VanityHosts>>,
+    session: UploadSession,
     data: Data<'_>,
 ) -> BlossomResponse {
-    process_upload("upload", false, auth, fs, db, settings, webhook, data).await
+    let pubkey = auth.pubkey.to_bytes().to_vec();
+    process_upload(
+        "upload", false, auth, fs, db, settings, webhook, disk, queue, &session, data,
+    )
+    .await
+    .with_vanity_url(settings, vanity, &pubkey)
+    .await
 }
 
 #[rocket::put("/mirror", data = "<req>", format = "json")]
 async fn mirror(
     auth: BlossomAuth,
+    _slot: MirrorSlot,
     fs: &State<FileStore>,
     db: &State<Database>,
     settings: &State<Settings>,
     webhook: &State<Option<Webhook>>,
+    disk: &State<DiskState>,
+    queue: &State<ProcessingQueue>,
+    vanity: &State<Option<VanityHosts>>,
+    session: UploadSession,
     req: Json<MirrorRequest>,
 ) -> BlossomResponse {
-    if !check_method(&auth.event, "mirror") {
-        return BlossomResponse::error("Invalid request method tag");
-    }
-    if let Some(e) = check_whitelist(&auth, settings) {
-        return e;
+    let client = session.client().client.as_deref();
+    if let Err(e) = check_mirror(&auth, client, &req.url, db, settings, disk).await {
+        return e.into();
     }
 
     // download file
-    let rsp = match reqwest::get(&req.url).await {
+    let rsp = match mirror::fetch(settings, &req.url).await {
+        Err(e) if FileTooLarge::is(&e) => return ErrorCode::TooLarge.into(),
         Err(e) => {
             error!("Error downloading file: {}", e);
-            return BlossomResponse::error("Failed to mirror file");
+            return ApiError::with_detail(ErrorCode::MirrorFailed, e.to_string()).into();
         }
         Ok(rsp) => rsp,
     };
+    if let Err(e) = check_disk_space(disk, settings, rsp.content_length()) {
+        return e.into();
+    }
+    let pubkey = auth.pubkey.to_bytes().to_vec();
+    if let Err(e) = check_quota(db, settings, &pubkey, rsp.content_length()).await {
+        return e.into();
+    }
+    if let Err(e) = check_tos(db, settings, &pubkey).await {
+        return e.into();
+    }
 
     let mime_type = rsp
         .headers()
         .get("content-type")
-        .map(|h| h.to_str().unwrap())
+        .and_then(|h| h.to_str().ok())
         .unwrap_or("application/octet-stream")
         .to_string();
-    let pubkey = auth.event.pubkey.to_bytes().to_vec();
+    let name = req
+        .url
+        .split(['?', '#'])
+        .next()
+        .and_then(|u| u.rsplit('/').next())
+        .filter(|n| !n.is_empty())
+        .map(|n| fit_name(settings, n));
 
-    process_stream(
-        StreamReader::new(rsp.bytes_stream().map(|result| {
-            result.map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err))
-        })),
+    let res = process_stream(
+        mirror::response_reader(settings, rsp),
         &mime_type,
-        &None,
+        &name.as_deref(),
+        None,
         &pubkey,
         false,
+        &claimed_hashes(&auth),
         fs,
         db,
         settings,
         webhook,
+        queue,
+        &session,
+    )
+    .await;
+    if let BlossomResponse::Uploaded(WithQuota(Json(d), _)) = &res {
+        if let Ok(id) = hex::decode(&d.sha256) {
+            if let Err(e) = db.add_upload_source(&id, &req.url).await {
+                warn!("Failed to record source of {}: {}", req.url, e);
+            }
+        }
+    }
+    res.with_vanity_url(settings, vanity, &pubkey).await
+}
+
+/// Check if a mirror request for the url would be accepted
+#[rocket::head("/mirror?<url>")]
+async fn mirror_head(
+    auth: BlossomAuth,
+    url: &str,
+    db: &State<Database>,
+    settings: &State<Settings>,
+    disk: &State<DiskState>,
+) -> BlossomHead {
+    BlossomHead {
+        error: check_mirror(&auth, None, url, db, settings, disk)
+            .await
+            .err(),
+    }
+}
+
+/// Queue a mirror job for each url, returns the batch to poll for results
+#[rocket::put("/mirror/batch", data = "<req>", format = "json")]
+async fn mirror_batch(
+    auth: BlossomAuth,
+    _slot: MirrorSlot,
+    db: &State<Database>,
+    settings: &State<Settings>,
+    disk: &State<DiskState>,
+    req: Json<Vec<String>>,
+) -> Result<Json<MirrorBatch>, ApiError> {
+    if !auth.allows("mirror") {
+        return Err(ApiError::with_detail(
+            ErrorCode::InvalidAuth,
+            "Invalid request method tag",
+        ));
+    }
+    if let Some(wl) = &settings.whitelist {
+        if !wl.contains(&auth.pubkey.to_hex()) {
+            return Err(ErrorCode::NotWhitelisted.into());
+        }
+    }
+    if req.is_empty() || req.len() > MAX_MIRROR_BATCH {
+        return Err(ApiError::with_detail(
+            ErrorCode::BadRequest,
+            format!("Batch must contain 1-{} urls", MAX_MIRROR_BATCH),
+        ));
+    }
+    for u in req.iter() {
+        match url::Url::parse(u) {
+            Ok(p) if p.scheme() == "http" || p.scheme() == "https" => {}
+            _ => {
+                return Err(ApiError::with_detail(
+                    ErrorCode::BadRequest,
+                    format!("Invalid url {}", u),
+                ))
+            }
+        }
+    }
+    let batch = queue_mirror_batch(&auth, db, settings, disk, &req).await?;
+    Ok(Json(mirror_batch_result(db, settings, batch).await?))
+}
+
+/// Check the urls and queue a mirror job for each, returns the batch id
+async fn queue_mirror_batch(
+    auth: &BlossomAuth,
+    db: &Database,
+    settings: &Settings,
+    disk: &DiskState,
+    urls: &[String],
+) -> Result<u64, ApiError> {
+    for u in urls {
+        let origin = UploadOrigin {
+            pubkey: auth.pubkey.as_bytes(),
+            client: None,
+            url: Some(u),
+        };
+        check_denied_origin(db, origin).await?;
+    }
+    check_disk_space(disk, settings, None)?;
+    let pubkey = auth.pubkey.to_bytes().to_vec();
+    check_quota(db, settings, &pubkey, None).await?;
+    check_tos(db, settings, &pubkey).await?;
+
+    let user_id = db
+        .upsert_user(&pubkey)
+        .await
+        .map_err(|e| ApiError::internal(e.to_string()))?;
+    let batch = db
+        .add_mirror_batch(user_id)
+        .await
+        .map_err(|e| ApiError::internal(e.to_string()))?;
+    for (idx, u) in urls.iter().enumerate() {
+        let job = MirrorJob {
+            batch,
+            index: idx as u32,
+            url: u.clone(),
+            pubkey: auth.pubkey.to_hex(),
+        };
+        let job_id = background::enqueue(db, MIRROR_JOB, &job)
+            .await
+            .map_err(|e| ApiError::internal(e.to_string()))?;
+        db.add_mirror_batch_item(batch, idx as u32, u, job_id)
+            .await
+            .map_err(|e| ApiError::internal(e.to_string()))?;
+    }
+    Ok(batch)
+}
+
+/// Mirror every blob a user has on another Blossom server, returns the mirror batch to
+/// poll at GET /mirror/batch/<id>
+#[rocket::post("/migrate", data = "<req>", format = "json")]
+async fn migrate(
+    auth: BlossomAuth,
+    _slot: MirrorSlot,
+    db: &State<Database>,
+    settings: &State<Settings>,
+    disk: &State<DiskState>,
+    req: Json<MigrateRequest>,
+) -> Result<Json<MigrateResult>, ApiError> {
+    if !auth.allows("mirror") {
+        return Err(ApiError::with_detail(
+            ErrorCode::InvalidAuth,
+            "Invalid request method tag",
+        ));
+    }
+    if let Some(wl) = &settings.whitelist {
+        if !wl.contains(&auth.pubkey.to_hex()) {
+            return Err(ErrorCode::NotWhitelisted.into());
+        }
+    }
+    match url::Url::parse(&req.server) {
+        Ok(p) if p.scheme() == "http" || p.scheme() == "https" => {}
+        _ => {
+            return Err(ApiError::with_detail(
+                ErrorCode::BadRequest,
+                "Invalid server url",
+            ))
+        }
+    }
+    let blobs = mirror::list_blobs(
+        settings,
+        &req.server,
+        &auth.pubkey.to_hex(),
+        req.authorization.as_deref(),
     )
     .await
+    .map_err(|e| {
+        ApiError::with_detail(
+            ErrorCode::MirrorFailed,
+            format!("Could not list blobs: {}", e),
+        )
+    })?;
+
+    let pubkey = auth.pubkey.to_bytes().to_vec();
+    let owned: HashSet<Vec<u8>> = db
+        .list_files(&pubkey, None, 0, u32::MAX)
+        .await
+        .map_err(|e| ApiError::internal(e.to_string()))?
+        .0
+        .into_iter()
+        .map(|f| f.id)
+        .collect();
+    let mut urls = Vec::new();
+    let mut skipped = 0;
+    for b in blobs {
+        let valid_url =
+            url::Url::parse(&b.url).is_ok_and(|u| u.scheme() == "http" || u.scheme() == "https");
+        match hex::decode(&b.sha256) {
+            Ok(id) if id.len() == 32 && valid_url && !owned.contains(&id) => urls.push(b.url),
+            _ => skipped += 1,
+        }
+    }
+    if urls.len() > MAX_MIGRATE_BLOBS {
+        return Err(ApiError::with_detail(
+            ErrorCode::BadRequest,
+            format!(
+                "{} blobs to migrate, at most {} are accepted per request",
+                urls.len(),
+                MAX_MIGRATE_BLOBS
+            ),
+        ));
+    }
+    if urls.is_empty() {
+        return Err(ApiError::with_detail(
+            ErrorCode::NotFound,
+            "No blobs to migrate",
+        ));
+    }
+    let batch = queue_mirror_batch(&auth, db, settings, disk, &urls).await?;
+    Ok(Json(MigrateResult {
+        batch: mirror_batch_result(db, settings, batch).await?,
+        skipped,
+    }))
+}
+
+/// Status of each url in a mirror batch, only visible to the uploader
+#[rocket::get("/mirror/batch/<id>")]
+async fn mirror_batch_status(
+    auth: BlossomAuth,
+    id: u64,
+    db: &State<Database>,
+    settings: &State<Settings>,
+) -> Result<Json<MirrorBatch>, ApiError> {
+    let owner = db
+        .get_mirror_batch_owner(id)
+        .await
+        .map_err(|e| ApiError::internal(e.to_string()))?;
+    let user_id = db.get_user_id(&auth.pubkey.to_bytes().to_vec()).await.ok();
+    if owner.is_none() || owner != user_id {
+        return Err(ErrorCode::NotFound.into());
+    }
+    Ok(Json(mirror_batch_result(db, settings, id).await?))
+}
+
+async fn mirror_batch_result(
+    db: &Database,
+    settings: &Settings,
+    batch: u64,
+) -> Result<MirrorBatch, ApiError> {
+    let items = db
+        .list_mirror_batch_items(batch)
+        .await
+        .map_err(|e| ApiError::internal(e.to_string()))?;
+    let mut ret = Vec::with_capacity(items.len());
+    for i in items {
+        let blob = match &i.file {
+            Some(f) => db
+                .get_file(f)
+                .await
+                .ok()
+                .flatten()
+                .map(|u| BlobDescriptor::from_upload(settings, &u)),
+            None => None,
+        };
+        ret.push(MirrorBatchStatus {
+            url: i.url,
+            status: i.status,
+            error: i.last_error,
+            blob,
+        });
+    }
+    Ok(MirrorBatch {
+        id: batch,
+        items: ret,
+    })
+}
+
+/// Check auth and learn the size and type of the source, enforcing the size limit,
+/// free space and quota before anything is downloaded
+async fn check_mirror(
+    auth: &BlossomAuth,
+    client: Option<&str>,
+    url: &str,
+    db: &Database,
+    settings: &Settings,
+    disk: &DiskState,
+) -> Result<MirrorPreflight, ApiError> {
+    if !auth.allows("mirror") {
+        return Err(ApiError::with_detail(
+            ErrorCode::InvalidAuth,
+            "Invalid request method tag",
+        ));
+    }
+    if let Some(wl) = &settings.whitelist {
+        if !wl.contains(&auth.pubkey.to_hex()) {
+            return Err(ErrorCode::NotWhitelisted.into());
+        }
+    }
+    let origin = UploadOrigin {
+        pubkey: auth.pubkey.as_bytes(),
+        client,
+        url: Some(url),
+    };
+    check_denied_origin(db, origin).await?;
+    check_disk_space(disk, settings, None)?;
+    let info = match mirror::preflight(settings, url).await {
+        Ok(i) => i,
+        Err(e) if FileTooLarge::is(&e) => return Err(ErrorCode::TooLarge.into()),
+        Err(e) => {
+            return Err(ApiError::with_detail(
+                ErrorCode::MirrorFailed,
+                e.to_string(),
+            ))
+        }
+    };
+    check_disk_space(disk, settings, info.size)?;
+    let pubkey = auth.pubkey.to_bytes().to_vec();
+    check_quota(db, settings, &pubkey, info.size).await?;
+    check_tos(db, settings, &pubkey).await?;
+    Ok(info)
 }
 
 #[cfg(feature = "media-compression")]
 #[rocket::head("/media")]
-fn head_media(auth: BlossomAuth, settings: &State<Settings>) -> BlossomHead {
-    check_head(auth, settings)
+fn head_media(
+    auth: BlossomAuth,
+    settings: &State<Settings>,
+    disk: &State<DiskState>,
+) -> BlossomHead {
+    let mime_type = auth.x_content_type.clone();
+    let head = check_head(auth, settings, disk);
+    if head.error.is_some() {
+        return head;
+    }
+    BlossomHead {
+        error: check_media_type(settings, mime_type.as_deref()).err(),
+    }
+}
+
+/// Check /media can convert the declared type, returns false when the upload should be
+/// stored as is. Rejected types get 415 with the supported types in the reason
+#[cfg(feature = "media-compression")]
+fn check_media_type(settings: &Settings, mime_type: Option<&str>) -> Result<bool, ApiError> {
+    let supported = media_input_types(settings);
+    let mime_type = mime_type
+        .and_then(|m| m.split(';').next())
+        .map(|m| m.trim().to_lowercase())
+        .filter(|m| !m.is_empty());
+    if mime_type.as_ref().is_some_and(|m| supported.contains(m)) {
+        return Ok(true);
+    }
+    let policy = settings
+        .media_types
+        .as_ref()
+        .and_then(|m| m.unsupported)
+        .unwrap_or(UnsupportedMediaPolicy::Store);
+    match policy {
+        UnsupportedMediaPolicy::Store => Ok(false),
+        UnsupportedMediaPolicy::Reject => Err(ApiError::with_detail(
+            ErrorCode::UnsupportedMediaType,
+            format!(
+                "{} cannot be processed, supported types: {}",
+                mime_type.as_deref().unwrap_or("unknown type"),
+                supported.join(", ")
+            ),
+        )),
+    }
 }
 
 #[cfg(feature = "media-compression")]
 #[rocket::put("/media", data = "<data>")]
 async fn upload_media(
     auth: BlossomAuth,
+    _slot: MediaSlot,
+    _permit: UploadPermit,
     fs: &State<FileStore>,
     db: &State<Database>,
     settings: &State<Settings>,
     webhook: &State<Option<Webhook>>,
+    disk: &State<DiskState>,
+    queue: &State<ProcessingQueue>,
+    vanity: &State<Option<VanityHosts>>,
+    session: UploadSession,
     data: Data<'_>,
 ) -> BlossomResponse {
-    process_upload("media", true, auth, fs, db, settings, webhook, data).await
+    let compress = match check_media_type(settings, auth.content_type.as_deref()) {
+        Ok(c) => c,
+        Err(e) => return e.into(),
+    };
+    let pubkey = auth.pubkey.to_bytes().to_vec();
+    process_upload(
+        "media", compress, auth, fs, db, settings, webhook, disk, queue, &session, data,
+    )
+    .await
+    .with_vanity_url(settings, vanity, &pubkey)
+    .await
 }
 
-fn check_head(auth: BlossomAuth, settings: &State<Settings>) -> BlossomHead {
-    if !check_method(&auth.event, "upload") {
-        return BlossomHead {
-            msg: Some("Invalid auth method tag"),
-        };
+fn check_head(auth: BlossomAuth, settings: &State<Settings>, disk: &DiskState) -> BlossomHead {
+    BlossomHead {
+        error: check_head_request(auth, settings, disk).err(),
+    }
+}
+
+fn check_head_request(
+    auth: BlossomAuth,
+    settings: &State<Settings>,
+    disk: &DiskState,
+) -> Result<(), ApiError> {
+    if !auth.allows("upload") {
+        return Err(ApiError::with_detail(
+            ErrorCode::InvalidAuth,
+            "Invalid auth method tag",
+        ));
     }
 
     if let Some(z) = auth.x_content_length {
         if z > settings.max_upload_bytes {
-            return BlossomHead {
-                msg: Some("File too large"),
-            };
+            return Err(ErrorCode::TooLarge.into());
         }
+        check_disk_space(disk, settings, Some(z))?;
     } else {
-        return BlossomHead {
-            msg: Some("Missing x-content-length header"),
-        };
+        return Err(ApiError::with_detail(
+            ErrorCode::LengthRequired,
+            "Missing x-content-length header",
+        ));
     }
 
     if auth.x_sha_256.is_none() {
-        return BlossomHead {
-            msg: Some("Missing x-sha-256 header"),
-        };
+        return Err(ApiError::with_detail(
+            ErrorCode::BadRequest,
+            "Missing x-sha-256 header",
+        ));
     }
 
     if auth.x_content_type.is_none() {
-        return BlossomHead {
-            msg: Some("Missing x-content-type header"),
-        };
+        return Err(ApiError::with_detail(
+            ErrorCode::BadRequest,
+            "Missing x-content-type header",
+        ));
+    }
+
+    let hashes = claimed_hashes(&auth);
+    require_x_tag(&auth, &hashes, settings)?;
+    if let Some(x) = auth.x_sha_256.as_ref().and_then(|x| hex::decode(x).ok()) {
+        if !hashes.is_empty() && !hashes.contains(&x) {
+            return Err(ApiError::with_detail(
+                ErrorCode::HashMismatch,
+                "x-sha-256 does not match the x tag of the auth event",
+            ));
+        }
     }
 
     // check whitelist
     if let Some(wl) = &settings.whitelist {
-        if !wl.contains(&auth.event.pubkey.to_hex()) {
-            return BlossomHead {
-                msg: Some("Not on whitelist"),
-            };
+        if !wl.contains(&auth.pubkey.to_hex()) {
+            return Err(ErrorCode::NotWhitelisted.into());
         }
     }
 
-    BlossomHead { msg: None }
+    Ok(())
+}
+
+/// Hash the client expects for the upload, from the auth event or x-sha-256 header
+fn claimed_hash(auth: &BlossomAuth) -> Option<Vec<u8>> {
+    let x = auth
+        .event
+        .iter()
+        .flat_map(|e| e.tags.iter())
+        .find_map(|t| {
+            if t.kind() == TagKind::SingleLetter(SingleLetterTag::lowercase(Alphabet::X)) {
+                t.content().map(|c| c.to_string())
+            } else {
+                None
+            }
+        })
+        .or(auth.x_sha_256.clone())?;
+    hex::decode(x).ok().filter(|id| id.len() == 32)
 }
 
+/// All hashes the auth event is valid for (`x` tags), empty when not restricted
+fn claimed_hashes(auth: &BlossomAuth) -> Vec<Vec<u8>> {
+    auth.event
+        .iter()
+        .flat_map(|e| e.tags.iter())
+        .filter(|t| t.kind() == TagKind::SingleLetter(SingleLetterTag::lowercase(Alphabet::X)))
+        .filter_map(|t| t.content().and_then(|c| hex::decode(c).ok()))
+        .collect()
+}
+
+/// Reject auth events without an `x` tag under [XTagPolicy::Strict], API keys have no event
+fn require_x_tag(
+    auth: &BlossomAuth,
+    hashes: &[Vec<u8>],
+    settings: &Settings,
+) -> Result<(), ApiError> {
+    if settings.auth_x_tag == Some(XTagPolicy::Strict) && auth.event.is_some() && hashes.is_empty()
+    {
+        return Err(ApiError::with_detail(
+            ErrorCode::InvalidAuth,
+            "Auth event has no x tag",
+        ));
+    }
+    Ok(())
+}
+
+/// Check and store an upload of PUT /upload or /media
 async fn process_upload(
     method: &str,
     compress: bool,
@@ -338,49 +985,179 @@ async fn process_upload(
     db: &State<Database>,
     settings: &State<Settings>,
     webhook: &State<Option<Webhook>>,
+    disk: &DiskState,
+    queue: &ProcessingQueue,
+    session: &UploadSession,
     data: Data<'_>,
 ) -> BlossomResponse {
-    if !check_method(&auth.event, method) {
-        return BlossomResponse::error("Invalid request method tag");
+    if !auth.allows(method) {
+        return ApiError::with_detail(ErrorCode::InvalidAuth, "Invalid request method tag").into();
+    }
+    let hashes = claimed_hashes(&auth);
+    if let Err(e) = require_x_tag(&auth, &hashes, settings) {
+        return e.into();
     }
 
-    let name = auth.event.tags.iter().find_map(|t| {
+    let name = auth.event.iter().flat_map(|e| e.tags.iter()).find_map(|t| {
         if t.kind() == TagKind::Name {
             t.content()
         } else {
             None
         }
     });
-    let size = auth.event.tags.iter().find_map(|t| {
+    let name = match name.map(|n| sanitize_name(settings, n)).transpose() {
+        Ok(n) => n,
+        Err(e) => return e.into(),
+    };
+    let name = name.as_deref();
+    let license = auth
+        .event
+        .iter()
+        .flat_map(|e| e.tags.iter())
+        .find_map(|t| match t.as_slice() {
+            [k, v, ..] if k == "license" => Some(v.as_str()),
+            _ => None,
+        });
+    let license = match license.map(sanitize_license).transpose() {
+        Ok(l) => l.flatten(),
+        Err(e) => return e.into(),
+    };
+    let license = license.as_deref();
+    let size_tag = auth.event.iter().flat_map(|e| e.tags.iter()).find_map(|t| {
         if t.kind() == TagKind::Size {
             t.content().and_then(|v| v.parse::<u64>().ok())
         } else {
             None
         }
     });
+    let policy = settings.upload_length.unwrap_or(LengthPolicy::Strict);
+    if let (Some(s), Some(l)) = (size_tag, auth.content_length) {
+        if s != l && policy != LengthPolicy::Lenient {
+            return ApiError::with_detail(
+                ErrorCode::LengthMismatch,
+                format!("size tag {} does not match Content-Length {}", s, l),
+            )
+            .into();
+        }
+    }
+    let size = size_tag.or(auth.content_length);
+    if size.is_none() && policy == LengthPolicy::Require {
+        return ApiError::with_detail(
+            ErrorCode::LengthRequired,
+            "Missing size tag or Content-Length header",
+        )
+        .into();
+    }
     if let Some(z) = size {
         if z > settings.max_upload_bytes {
-            return BlossomResponse::error("File too large");
+            return ErrorCode::TooLarge.into();
         }
     }
+    if let Err(e) = check_disk_space(disk, settings, size) {
+        return e.into();
+    }
+    let pubkey = auth.pubkey.to_bytes().to_vec();
+    if let Err(e) = check_quota(db, settings, &pubkey, size).await {
+        return e.into();
+    }
+    if let Err(e) = check_tos(db, settings, &pubkey).await {
+        return e.into();
+    }
 
     // check whitelist
     if let Some(e) = check_whitelist(&auth, settings) {
         return e;
     }
+    let origin = UploadOrigin {
+        pubkey: auth.pubkey.as_bytes(),
+        client: session.client().client.as_deref(),
+        url: None,
+    };
+    if let Err(e) = check_denied_origin(db, origin).await {
+        return e.into();
+    }
 
-    process_stream(
-        data.open(ByteUnit::Byte(settings.max_upload_bytes)),
-        &auth
-            .content_type
-            .unwrap_or("application/octet-stream".to_string()),
+    // GETs of the claimed hash return 202 while it is received, only for accepted uploads
+    let _state = match claimed_hash(&auth) {
+        Some(x) => {
+            let state = if compress {
+                UploadState::Processing
+            } else {
+                UploadState::Pending
+            };
+            match db.track_upload_state(&x, state).await {
+                Ok(g) => Some(g),
+                Err(e) => {
+                    warn!("Failed to set upload state: {}", e);
+                    None
+                }
+            }
+        }
+        None => None,
+    };
+
+    let mime_type = auth
+        .content_type
+        .unwrap_or("application/octet-stream".to_string());
+    // read one byte past the limit so oversized uploads fail instead of being truncated
+    let kind = if compress {
+        TransferKind::Media
+    } else {
+        TransferKind::Upload
+    };
+    let stream = LengthChecked::new(
+        SizeLimited::new(
+            TransferGuard::new(
+                data.open(ByteUnit::Byte(settings.max_upload_bytes + 1)),
+                settings,
+                kind,
+            ),
+            settings.max_upload_bytes,
+        ),
+        size,
+        policy != LengthPolicy::Lenient,
+    );
+    if !compress || !settings.media_keep_original.unwrap_or(false) {
+        return process_stream(
+            stream, &mime_type, &name, license, &pubkey, compress, &hashes, fs, db, settings,
+            webhook, queue, session,
+        )
+        .await;
+    }
+
+    // store the original bytes first so the hash the client signed still resolves
+    // the session completes once the compressed copy is stored
+    let original = match process_stream(
+        session.wrap(stream),
+        &mime_type,
         &name,
-        &auth.event.pubkey.to_bytes().to_vec(),
-        compress,
+        license,
+        &pubkey,
+        false,
+        &hashes,
         fs,
         db,
         settings,
         webhook,
+        queue,
+        &UploadSession::default(),
+    )
+    .await
+    {
+        BlossomResponse::Uploaded(WithQuota(d, _)) => d,
+        r => return r,
+    };
+    let original_id = match hex::decode(&original.sha256) {
+        Ok(id) => id,
+        Err(e) => return BlossomResponse::error(e.to_string()),
+    };
+    let file = match fs.open(&original_id).await {
+        Ok(f) => f,
+        Err(e) => return BlossomResponse::error(format!("Failed to open original: {}", e)),
+    };
+    process_stream(
+        file, &mime_type, &name, license, &pubkey, true, &hashes, fs, db, settings, webhook, queue,
+        session,
     )
     .await
 }
@@ -389,25 +1166,55 @@ async fn process_stream<S>(
     stream: S,
     mime_type: &str,
     name: &Option<&str>,
+    license: Option<&str>,
     pubkey: &Vec<u8>,
     compress: bool,
+    allowed_hashes: &[Vec<u8>],
     fs: &State<FileStore>,
     db: &State<Database>,
     settings: &State<Settings>,
     webhook: &State<Option<Webhook>>,
+    queue: &ProcessingQueue,
+    session: &UploadSession,
 ) -> BlossomResponse
 where
     S: AsyncRead + Unpin,
 {
-    match fs.put(stream, mime_type, compress).await {
+    let stored = if compress {
+        fs.put_queued(session.wrap(stream), mime_type, queue, pubkey, db)
+            .await
+    } else {
+        fs.put(session.wrap(stream), mime_type, false).await
+    };
+    match stored {
         Ok(mut blob) => {
-            blob.upload.name = name.unwrap_or("").to_owned();
+            // the media endpoint stores a derived file, x tags refer to the uploaded bytes
+            let received = blob.source.as_ref().unwrap_or(&blob.upload.id);
+            if !allowed_hashes.is_empty() && !allowed_hashes.contains(received) {
+                if let Ok(None) = db.get_file(&blob.upload.id).await {
+                    let _ = fs::remove_file(blob.path);
+                }
+                return ApiError::with_detail(
+                    ErrorCode::HashMismatch,
+                    "Blob does not match the x tag of the auth event",
+                )
+                .into();
+            }
+            if let Err(e) =
+                check_blocked_upload(pubkey, *name, mime_type, &blob, db, settings).await
+            {
+                return e.into();
+            }
+            if !settings.hide_file_names.unwrap_or(false) {
+                blob.upload.name = name.unwrap_or("").to_owned();
+            }
+            blob.upload.license = license.map(str::to_string);
             if let Some(wh) = webhook.as_ref() {
                 match wh.store_file(pubkey, blob.clone()).await {
                     Ok(store) => {
                         if !store {
                             let _ = fs::remove_file(blob.path);
-                            return BlossomResponse::error("Upload rejected");
+                            return ErrorCode::UploadRejected.into();
                         }
                     }
                     Err(e) => {
@@ -419,6 +1226,8 @@ where
                     }
                 }
             }
+            blob.upload.expires = upload_expiry(db, settings, pubkey).await;
+            let first_uploaded = first_uploaded(db, &blob).await;
             let user_id = match db.upsert_user(pubkey).await {
                 Ok(u) => u,
                 Err(e) => {
@@ -431,19 +1240,41 @@ where
                 if let Some(dbe) = e.as_database_error() {
                     if let Some(c) = dbe.code() {
                         if c == "23000" {
-                            return BlossomResponse::error("File already exists");
+                            return ErrorCode::FileExists.into();
                         }
                     }
                 }
                 BlossomResponse::error(format!("Error saving file (db): {}", e))
             } else {
-                BlossomResponse::BlobDescriptor(Json(BlobDescriptor::from_upload(
-                    settings,
-                    &blob.upload,
-                )))
+                #[cfg(feature = "torrent-v2")]
+                if let Err(e) = queue_torrent(db, settings, &blob.upload).await {
+                    log::warn!("Failed to queue torrent: {}", e);
+                }
+                record_client_hints(db, settings, &blob.upload.id, user_id, session.client()).await;
+                hooks::post_store(settings, &blob.upload, pubkey);
+                session.set_stage(UploadStage::Complete);
+                let quota = quota_usage(db, settings, pubkey).await.ok();
+                let mut descriptor = BlobDescriptor::from_upload(settings, &blob.upload);
+                descriptor.warning = quota.and_then(|q| q.warning());
+                if blob.dedup {
+                    descriptor.dedup = Some(true);
+                    descriptor.first_uploaded = first_uploaded.map(|c| c.timestamp() as u64);
+                }
+                BlossomResponse::Uploaded(WithQuota(Json(descriptor), quota))
             }
         }
+        Err(e) if FileTooLarge::is(&e) => ErrorCode::TooLarge.into(),
+        #[cfg(feature = "media-compression")]
+        Err(e) if MediaTooLarge::is(&e) => {
+            ApiError::with_detail(ErrorCode::TooLarge, e.to_string()).into()
+        }
         Err(e) => {
+            if let Some(m) = LengthMismatch::find(&e) {
+                return ApiError::with_detail(ErrorCode::LengthMismatch, m.to_string()).into();
+            }
+            if let Some(s) = SlowTransfer::find(&e) {
+                return ApiError::with_detail(ErrorCode::TransferTooSlow, s.to_string()).into();
+            }
             error!("{}", e.to_string());
             BlossomResponse::error(format!("Error saving file (disk): {}", e))
         }
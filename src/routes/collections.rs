@@ -0,0 +1,442 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+use rocket::serde::json::Json;
+use rocket::serde::{Deserialize, Serialize};
+use rocket::{routes, Route, State};
+use sqlx::{Error, FromRow, Row};
+
+use crate::auth::nip98::Nip98Auth;
+use crate::db::{Database, FileUpload};
+use crate::routes::error::{ApiError, ErrorCode};
+use crate::routes::{Nip94Event, PagedResult, Sha256Param};
+use crate::settings::Settings;
+
+/// Longest collection name
+const MAX_NAME_LEN: usize = 255;
+
+/// Largest page of collection files
+const MAX_PAGE: u32 = 1_000;
+
+pub fn collection_routes() -> Vec<Route> {
+    routes![
+        list_own_collections,
+        list_user_collections,
+        create_collection,
+        rename_collection,
+        delete_collection,
+        add_collection_file,
+        remove_collection_file,
+        list_collection,
+        list_shared_collection
+    ]
+}
+
+/// Named group of a user's uploads
+#[derive(Clone, FromRow, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Collection {
+    pub id: u64,
+    #[serde(skip)]
+    pub user_id: u64,
+    /// Owner pubkey
+    #[serde(with = "hex")]
+    pub pubkey: Vec<u8>,
+    pub name: String,
+    /// Lists the collection at /c/<share_token>
+    pub share_token: String,
+    pub created: DateTime<Utc>,
+    /// Number of files in the collection
+    pub files: i64,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct CollectionRequest {
+    pub name: String,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct CollectionListing {
+    pub collection: Collection,
+    pub files: PagedResult<Nip94Event>,
+}
+
+/// Collections with their file count
+const COLLECTION_SELECT: &str = "select c.*, u.pubkey, \
+    (select count(*) from collection_files f where f.collection = c.id) as files \
+    from collections c join users u on u.id = c.user_id";
+
+impl Database {
+    pub async fn create_collection(
+        &self,
+        user_id: u64,
+        name: &str,
+        share_token: &str,
+    ) -> Result<u64, Error> {
+        let res = sqlx::query("insert into collections(user_id,name,share_token) values(?,?,?)")
+            .bind(user_id)
+            .bind(name)
+            .bind(share_token)
+            .execute(&self.pool)
+            .await?;
+        Ok(res.last_insert_id())
+    }
+
+    pub async fn get_collection(&self, id: u64) -> Result<Option<Collection>, Error> {
+        sqlx::query_as(&format!("{} where c.id = ?", COLLECTION_SELECT))
+            .bind(id)
+            .fetch_optional(&self.pool)
+            .await
+    }
+
+    pub async fn get_collection_by_token(&self, token: &str) -> Result<Option<Collection>, Error> {
+        sqlx::query_as(&format!("{} where c.share_token = ?", COLLECTION_SELECT))
+            .bind(token)
+            .fetch_optional(&self.pool)
+            .await
+    }
+
+    pub async fn list_collections(&self, user_id: u64) -> Result<Vec<Collection>, Error> {
+        sqlx::query_as(&format!(
+            "{} where c.user_id = ? order by c.name",
+            COLLECTION_SELECT
+        ))
+        .bind(user_id)
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    pub async fn rename_collection(&self, id: u64, name: &str) -> Result<(), Error> {
+        sqlx::query("update collections set name = ? where id = ?")
+            .bind(name)
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn delete_collection(&self, id: u64) -> Result<(), Error> {
+        sqlx::query("delete from collections where id = ?")
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn add_collection_file(&self, id: u64, file: &Vec<u8>) -> Result<(), Error> {
+        sqlx::query("insert ignore into collection_files(collection,file) values(?,?)")
+            .bind(id)
+            .bind(file)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Returns false when the file was not in the collection
+    pub async fn remove_collection_file(&self, id: u64, file: &Vec<u8>) -> Result<bool, Error> {
+        let res = sqlx::query("delete from collection_files where collection = ? and file = ?")
+            .bind(id)
+            .bind(file)
+            .execute(&self.pool)
+            .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    /// Files of a collection which its owner still owns, newest added first
+    pub async fn list_collection_files(
+        &self,
+        collection: &Collection,
+        offset: u32,
+        limit: u32,
+    ) -> Result<(Vec<FileUpload>, i64), Error> {
+        let filter = "from collection_files f, uploads, user_uploads uu \
+            where f.collection = ? \
+            and uploads.id = f.file \
+            and uu.file = f.file \
+            and uu.user_id = ?";
+        let results: Vec<FileUpload> = sqlx::query_as(&format!(
+            "select uploads.* {} order by f.added desc limit ? offset ?",
+            filter
+        ))
+        .bind(collection.id)
+        .bind(collection.user_id)
+        .bind(limit)
+        .bind(offset)
+        .fetch_all(&self.pool)
+        .await?;
+        let count: i64 = sqlx::query(&format!("select count(uploads.id) {}", filter))
+            .bind(collection.id)
+            .bind(collection.user_id)
+            .fetch_one(&self.pool)
+            .await?
+            .try_get(0)?;
+        Ok((results, count))
+    }
+
+    /// Collections of a user each file is in, by file id
+    pub async fn list_file_collections(
+        &self,
+        pubkey: &Vec<u8>,
+    ) -> Result<HashMap<Vec<u8>, Vec<u64>>, Error> {
+        let rows = sqlx::query(
+            "select f.file, f.collection from collection_files f, collections c, users u \
+            where u.pubkey = ? and c.user_id = u.id and f.collection = c.id",
+        )
+        .bind(pubkey)
+        .fetch_all(&self.pool)
+        .await?;
+        let mut ret: HashMap<Vec<u8>, Vec<u64>> = HashMap::new();
+        for r in rows {
+            ret.entry(r.try_get(0)?).or_default().push(r.try_get(1)?);
+        }
+        Ok(ret)
+    }
+}
+
+fn parse_name(name: &str) -> Result<String, ApiError> {
+    let name = name.trim();
+    if name.is_empty() || name.chars().count() > MAX_NAME_LEN {
+        return Err(ApiError::with_detail(
+            ErrorCode::BadRequest,
+            format!("Name must be 1-{} characters", MAX_NAME_LEN),
+        ));
+    }
+    Ok(name.to_string())
+}
+
+fn map_name_error(e: Error) -> ApiError {
+    match e.as_database_error().and_then(|d| d.code()) {
+        Some(c) if c == "23000" => {
+            ApiError::with_detail(ErrorCode::BadRequest, "A collection with this name exists")
+        }
+        _ => ApiError::internal(e.to_string()),
+    }
+}
+
+/// Collection owned by the caller, NotFound for collections of other users
+async fn owned_collection(
+    db: &Database,
+    auth: &Nip98Auth,
+    id: u64,
+) -> Result<Collection, ApiError> {
+    let user_id = db
+        .get_user_id(&auth.pubkey.to_bytes().to_vec())
+        .await
+        .map_err(|_| ApiError::from(ErrorCode::NotFound))?;
+    match db.get_collection(id).await {
+        Ok(Some(c)) if c.user_id == user_id => Ok(c),
+        Ok(_) => Err(ErrorCode::NotFound.into()),
+        Err(e) => Err(ApiError::internal(e.to_string())),
+    }
+}
+
+async fn listing(
+    db: &Database,
+    settings: &Settings,
+    collection: Collection,
+    page: Option<u32>,
+    count: Option<u32>,
+) -> Result<Json<CollectionListing>, ApiError> {
+    let page = page.unwrap_or(0);
+    let server_count = count.unwrap_or(100).clamp(1, MAX_PAGE);
+    let (files, total) = db
+        .list_collection_files(&collection, page * server_count, server_count)
+        .await
+        .map_err(|e| ApiError::internal(e.to_string()))?;
+    Ok(Json(CollectionListing {
+        collection,
+        files: PagedResult {
+            count: files.len() as u32,
+            page,
+            total: total as u32,
+            files: files
+                .iter()
+                .map(|f| Nip94Event::from_upload(settings, f))
+                .collect(),
+        },
+    }))
+}
+
+/// Collections of the caller
+#[rocket::get("/collections")]
+async fn list_own_collections(
+    auth: Nip98Auth,
+    db: &State<Database>,
+) -> Result<Json<Vec<Collection>>, ApiError> {
+    let user_id = match db.get_user_id(&auth.pubkey.to_bytes().to_vec()).await {
+        Ok(u) => u,
+        Err(Error::RowNotFound) => return Ok(Json(vec![])),
+        Err(e) => return Err(ApiError::internal(e.to_string())),
+    };
+    db.list_collections(user_id)
+        .await
+        .map(Json)
+        .map_err(|e| ApiError::internal(e.to_string()))
+}
+
+/// Collections of any user, like their file list
+#[rocket::get("/collections/<pubkey>")]
+async fn list_user_collections(
+    pubkey: &str,
+    db: &State<Database>,
+) -> Result<Json<Vec<Collection>>, ApiError> {
+    let pubkey = match hex::decode(pubkey) {
+        Ok(p) if p.len() == 32 => p,
+        _ => {
+            return Err(ApiError::with_detail(
+                ErrorCode::BadRequest,
+                "invalid pubkey",
+            ))
+        }
+    };
+    let user_id = match db.get_user_id(&pubkey).await {
+        Ok(u) => u,
+        Err(Error::RowNotFound) => return Ok(Json(vec![])),
+        Err(e) => return Err(ApiError::internal(e.to_string())),
+    };
+    db.list_collections(user_id)
+        .await
+        .map(Json)
+        .map_err(|e| ApiError::internal(e.to_string()))
+}
+
+#[rocket::post("/collections", data = "<body>", format = "json")]
+async fn create_collection(
+    auth: Nip98Auth,
+    db: &State<Database>,
+    body: Vec<u8>,
+) -> Result<Json<Collection>, ApiError> {
+    let req: CollectionRequest = auth.parse_json(&body)?;
+    let name = parse_name(&req.name)?;
+    let user_id = db
+        .upsert_user(&auth.pubkey.to_bytes().to_vec())
+        .await
+        .map_err(|e| ApiError::internal(e.to_string()))?;
+    let token = uuid::Uuid::new_v4().simple().to_string();
+    let id = db
+        .create_collection(user_id, &name, &token)
+        .await
+        .map_err(map_name_error)?;
+    match db.get_collection(id).await {
+        Ok(Some(c)) => Ok(Json(c)),
+        Ok(None) => Err(ErrorCode::NotFound.into()),
+        Err(e) => Err(ApiError::internal(e.to_string())),
+    }
+}
+
+#[rocket::put("/collections/<id>", data = "<body>", format = "json")]
+async fn rename_collection(
+    auth: Nip98Auth,
+    id: u64,
+    db: &State<Database>,
+    body: Vec<u8>,
+) -> Result<Json<Collection>, ApiError> {
+    let req: CollectionRequest = auth.parse_json(&body)?;
+    let mut collection = owned_collection(db, &auth, id).await?;
+    let name = parse_name(&req.name)?;
+    db.rename_collection(id, &name)
+        .await
+        .map_err(map_name_error)?;
+    collection.name = name;
+    Ok(Json(collection))
+}
+
+/// Delete a collection, its files are kept
+#[rocket::delete("/collections/<id>")]
+async fn delete_collection(auth: Nip98Auth, id: u64, db: &State<Database>) -> Result<(), ApiError> {
+    owned_collection(db, &auth, id).await?;
+    db.delete_collection(id)
+        .await
+        .map_err(|e| ApiError::internal(e.to_string()))
+}
+
+/// Add a file owned by the caller to one of their collections
+#[rocket::put("/collections/<id>/<sha256>")]
+async fn add_collection_file(
+    auth: Nip98Auth,
+    id: u64,
+    sha256: Result<Sha256Param, ApiError>,
+    db: &State<Database>,
+) -> Result<(), ApiError> {
+    let file = sha256?.id;
+    let collection = owned_collection(db, &auth, id).await?;
+    let owners = db
+        .get_file_owners(&file)
+        .await
+        .map_err(|e| ApiError::internal(e.to_string()))?;
+    if owners.is_empty() {
+        return Err(ErrorCode::NotFound.into());
+    }
+    if !owners.iter().any(|o| o.id == collection.user_id) {
+        return Err(ErrorCode::NotOwner.into());
+    }
+    db.add_collection_file(id, &file)
+        .await
+        .map_err(|e| ApiError::internal(e.to_string()))
+}
+
+#[rocket::delete("/collections/<id>/<sha256>")]
+async fn remove_collection_file(
+    auth: Nip98Auth,
+    id: u64,
+    sha256: Result<Sha256Param, ApiError>,
+    db: &State<Database>,
+) -> Result<(), ApiError> {
+    let file = sha256?.id;
+    owned_collection(db, &auth, id).await?;
+    match db.remove_collection_file(id, &file).await {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(ErrorCode::NotFound.into()),
+        Err(e) => Err(ApiError::internal(e.to_string())),
+    }
+}
+
+/// Files of a collection, by owner pubkey and collection id
+#[rocket::get("/collections/<pubkey>/<id>?<page>&<count>")]
+async fn list_collection(
+    pubkey: &str,
+    id: u64,
+    page: Option<u32>,
+    count: Option<u32>,
+    db: &State<Database>,
+    settings: &State<Settings>,
+) -> Result<Json<CollectionListing>, ApiError> {
+    let pubkey = match hex::decode(pubkey) {
+        Ok(p) if p.len() == 32 => p,
+        _ => {
+            return Err(ApiError::with_detail(
+                ErrorCode::BadRequest,
+                "invalid pubkey",
+            ))
+        }
+    };
+    let user_id = db
+        .get_user_id(&pubkey)
+        .await
+        .map_err(|_| ApiError::from(ErrorCode::NotFound))?;
+    let collection = match db.get_collection(id).await {
+        Ok(Some(c)) if c.user_id == user_id => c,
+        Ok(_) => return Err(ErrorCode::NotFound.into()),
+        Err(e) => return Err(ApiError::internal(e.to_string())),
+    };
+    listing(db, settings, collection, page, count).await
+}
+
+/// Files of a collection, by share token
+#[rocket::get("/c/<token>?<page>&<count>")]
+async fn list_shared_collection(
+    token: &str,
+    page: Option<u32>,
+    count: Option<u32>,
+    db: &State<Database>,
+    settings: &State<Settings>,
+) -> Result<Json<CollectionListing>, ApiError> {
+    let collection = match db.get_collection_by_token(token).await {
+        Ok(Some(c)) => c,
+        Ok(None) => return Err(ErrorCode::NotFound.into()),
+        Err(e) => return Err(ApiError::internal(e.to_string())),
+    };
+    listing(db, settings, collection, page, count).await
+}
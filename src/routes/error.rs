@@ -0,0 +1,330 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::io::Cursor;
+
+use rocket::catcher::BoxFuture;
+use rocket::http::{ContentType, Status};
+use rocket::response::Responder;
+use rocket::serde::{json, Serialize};
+use rocket::{Catcher, Request, Response};
+
+use crate::settings::Settings;
+use crate::shed::DEFAULT_RETRY_AFTER;
+
+/// Stable machine-readable error codes returned by all routes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(crate = "rocket::serde", rename_all = "snake_case")]
+pub enum ErrorCode {
+    BadRequest,
+    InvalidFileId,
+    InvalidAuth,
+    NotWhitelisted,
+    NotOwner,
+    NotAdmin,
+    UploadRejected,
+    BlockedFileType,
+    NotFound,
+    UserNotFound,
+    FileExists,
+    AmbiguousId,
+    LengthRequired,
+    LengthMismatch,
+    TooLarge,
+    QuotaExceeded,
+    InsufficientStorage,
+    HashMismatch,
+    UnsupportedMediaType,
+    MirrorFailed,
+    NameTooLong,
+    AltTooLong,
+    TosNotAccepted,
+    TransferTooSlow,
+    LegalHold,
+    TooManyUploads,
+    Overloaded,
+    Internal,
+}
+
+impl ErrorCode {
+    pub fn status(&self) -> Status {
+        match self {
+            ErrorCode::BadRequest
+            | ErrorCode::InvalidFileId
+            | ErrorCode::HashMismatch
+            | ErrorCode::LengthMismatch
+            | ErrorCode::NameTooLong
+            | ErrorCode::AltTooLong => Status::BadRequest,
+            ErrorCode::InvalidAuth => Status::Unauthorized,
+            ErrorCode::NotWhitelisted
+            | ErrorCode::NotOwner
+            | ErrorCode::NotAdmin
+            | ErrorCode::UploadRejected
+            | ErrorCode::BlockedFileType
+            | ErrorCode::QuotaExceeded
+            | ErrorCode::TosNotAccepted => Status::Forbidden,
+            ErrorCode::NotFound | ErrorCode::UserNotFound => Status::NotFound,
+            ErrorCode::FileExists | ErrorCode::AmbiguousId => Status::Conflict,
+            ErrorCode::LengthRequired => Status::LengthRequired,
+            ErrorCode::TransferTooSlow => Status::RequestTimeout,
+            ErrorCode::LegalHold => Status::Locked,
+            ErrorCode::TooManyUploads => Status::TooManyRequests,
+            ErrorCode::TooLarge => Status::PayloadTooLarge,
+            ErrorCode::InsufficientStorage => Status::InsufficientStorage,
+            ErrorCode::UnsupportedMediaType => Status::UnsupportedMediaType,
+            ErrorCode::MirrorFailed => Status::BadGateway,
+            ErrorCode::Overloaded => Status::ServiceUnavailable,
+            ErrorCode::Internal => Status::InternalServerError,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorCode::BadRequest => "bad_request",
+            ErrorCode::InvalidFileId => "invalid_file_id",
+            ErrorCode::InvalidAuth => "invalid_auth",
+            ErrorCode::NotWhitelisted => "not_whitelisted",
+            ErrorCode::NotOwner => "not_owner",
+            ErrorCode::NotAdmin => "not_admin",
+            ErrorCode::UploadRejected => "upload_rejected",
+            ErrorCode::BlockedFileType => "blocked_file_type",
+            ErrorCode::NotFound => "not_found",
+            ErrorCode::UserNotFound => "user_not_found",
+            ErrorCode::FileExists => "file_exists",
+            ErrorCode::AmbiguousId => "ambiguous_id",
+            ErrorCode::LengthRequired => "length_required",
+            ErrorCode::LengthMismatch => "length_mismatch",
+            ErrorCode::TooLarge => "too_large",
+            ErrorCode::QuotaExceeded => "quota_exceeded",
+            ErrorCode::InsufficientStorage => "insufficient_storage",
+            ErrorCode::HashMismatch => "hash_mismatch",
+            ErrorCode::UnsupportedMediaType => "unsupported_media_type",
+            ErrorCode::MirrorFailed => "mirror_failed",
+            ErrorCode::NameTooLong => "name_too_long",
+            ErrorCode::AltTooLong => "alt_too_long",
+            ErrorCode::TosNotAccepted => "tos_not_accepted",
+            ErrorCode::TransferTooSlow => "transfer_too_slow",
+            ErrorCode::LegalHold => "legal_hold",
+            ErrorCode::TooManyUploads => "too_many_uploads",
+            ErrorCode::Overloaded => "overloaded",
+            ErrorCode::Internal => "internal",
+        }
+    }
+
+    /// Default (english) message
+    pub fn message(&self) -> &'static str {
+        match self {
+            ErrorCode::BadRequest => "Bad request",
+            ErrorCode::InvalidFileId => "Invalid file id",
+            ErrorCode::InvalidAuth => "Invalid authorization",
+            ErrorCode::NotWhitelisted => "Not on whitelist",
+            ErrorCode::NotOwner => "You dont own this file",
+            ErrorCode::NotAdmin => "User is not an admin",
+            ErrorCode::UploadRejected => "Upload rejected",
+            ErrorCode::BlockedFileType => "File type not allowed",
+            ErrorCode::NotFound => "Not found",
+            ErrorCode::UserNotFound => "User not found",
+            ErrorCode::FileExists => "File already exists",
+            ErrorCode::AmbiguousId => "Id matches more than one file",
+            ErrorCode::LengthRequired => "Missing content length",
+            ErrorCode::LengthMismatch => "Upload does not match the declared size",
+            ErrorCode::TooLarge => "File too large",
+            ErrorCode::QuotaExceeded => "Quota exceeded",
+            ErrorCode::InsufficientStorage => "Server is out of storage space",
+            ErrorCode::HashMismatch => "Hash mismatch",
+            ErrorCode::UnsupportedMediaType => "Unsupported media type",
+            ErrorCode::MirrorFailed => "Failed to mirror file",
+            ErrorCode::NameTooLong => "File name too long",
+            ErrorCode::AltTooLong => "Alt text too long",
+            ErrorCode::TosNotAccepted => "Terms of service not accepted",
+            ErrorCode::TransferTooSlow => "Transfer too slow",
+            ErrorCode::LegalHold => "File is under legal hold",
+            ErrorCode::TooManyUploads => "Too many uploads in progress, try again later",
+            ErrorCode::Overloaded => "Server is busy, try again later",
+            ErrorCode::Internal => "Internal error",
+        }
+    }
+
+    /// Error code for a status returned without an [ApiError], eg. from a failed guard
+    pub fn from_status(status: Status) -> Option<Self> {
+        match status.code {
+            400 | 422 => Some(ErrorCode::BadRequest),
+            401 => Some(ErrorCode::InvalidAuth),
+            404 => Some(ErrorCode::NotFound),
+            408 => Some(ErrorCode::TransferTooSlow),
+            411 => Some(ErrorCode::LengthRequired),
+            413 => Some(ErrorCode::TooLarge),
+            415 => Some(ErrorCode::UnsupportedMediaType),
+            423 => Some(ErrorCode::LegalHold),
+            429 => Some(ErrorCode::TooManyUploads),
+            500 => Some(ErrorCode::Internal),
+            503 => Some(ErrorCode::Overloaded),
+            _ => None,
+        }
+    }
+}
+
+impl Display for ErrorCode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// Error returned from routes, rendered as json with an X-Reason header
+#[derive(Debug, Clone)]
+pub struct ApiError {
+    pub code: ErrorCode,
+    pub detail: Option<String>,
+}
+
+impl ApiError {
+    pub fn new(code: ErrorCode) -> Self {
+        Self { code, detail: None }
+    }
+
+    pub fn with_detail(code: ErrorCode, detail: impl Into<String>) -> Self {
+        Self {
+            code,
+            detail: Some(detail.into()),
+        }
+    }
+
+    /// Internal error with details about the failure
+    pub fn internal(detail: impl Into<String>) -> Self {
+        Self::with_detail(ErrorCode::Internal, detail)
+    }
+
+    /// Human-readable message, localized using the request Accept-Language header
+    pub fn message(&self, request: &Request<'_>) -> String {
+        let base = request
+            .rocket()
+            .state::<Settings>()
+            .and_then(|s| s.error_messages.as_ref())
+            .and_then(|m| {
+                localized_message(m, request.headers().get_one("accept-language"), self.code)
+            })
+            .unwrap_or(self.code.message());
+        match &self.detail {
+            Some(d) => format!("{}: {}", base, d),
+            None => base.to_string(),
+        }
+    }
+}
+
+impl From<ErrorCode> for ApiError {
+    fn from(code: ErrorCode) -> Self {
+        Self::new(code)
+    }
+}
+
+impl Display for ApiError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match &self.detail {
+            Some(d) => write!(f, "{}: {}", self.code.message(), d),
+            None => write!(f, "{}", self.code.message()),
+        }
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct ApiErrorBody<'a> {
+    pub status: &'a str,
+    pub code: ErrorCode,
+    pub message: &'a str,
+}
+
+impl<'r> Responder<'r, 'static> for ApiError {
+    fn respond_to(self, request: &'r Request<'_>) -> rocket::response::Result<'static> {
+        // kept for fairings, eg. the upload failure log
+        request.local_cache(|| Some(self.clone()));
+        let message = self.message(request);
+        let body = json::to_string(&ApiErrorBody {
+            status: "error",
+            code: self.code,
+            message: &message,
+        })
+        .map_err(|_| Status::InternalServerError)?;
+
+        let mut response = Response::new();
+        response.set_status(self.code.status());
+        response.set_header(ContentType::JSON);
+        response.set_raw_header("X-Error-Code", self.code.as_str());
+        // header values cannot contain line breaks
+        response.set_raw_header("X-Reason", message.replace(|c| c == '\r' || c == '\n', " "));
+        if matches!(self.code, ErrorCode::Overloaded | ErrorCode::TooManyUploads) {
+            let retry_after = request
+                .rocket()
+                .state::<Settings>()
+                .and_then(|s| s.load_shedding.as_ref())
+                .and_then(|l| l.retry_after)
+                .unwrap_or(DEFAULT_RETRY_AFTER);
+            response.set_raw_header("Retry-After", retry_after.to_string());
+        }
+        if self.code == ErrorCode::TosNotAccepted {
+            if let Some(s) = request.rocket().state::<Settings>() {
+                response.set_raw_header(
+                    "Link",
+                    format!("<{}/tos>; rel=\"terms-of-service\"", s.public_url),
+                );
+                if let Some(t) = &s.tos {
+                    response.set_raw_header("X-Tos-Version", t.version.clone());
+                }
+            }
+        }
+        response.set_sized_body(body.len(), Cursor::new(body));
+        Ok(response)
+    }
+}
+
+/// Render errors which did not come from a route (guards, unmatched requests) like [ApiError]
+pub fn error_catchers() -> Vec<Catcher> {
+    vec![Catcher::new(None, catch_error)]
+}
+
+fn catch_error<'r>(status: Status, request: &'r Request<'_>) -> BoxFuture<'r> {
+    Box::pin(async move {
+        if let Some(e) = request.local_cache(|| None::<ApiError>) {
+            return e.clone().respond_to(request);
+        }
+        match ErrorCode::from_status(status) {
+            Some(code) => ApiError::new(code).respond_to(request),
+            None => Response::build()
+                .status(status)
+                .raw_header("X-Reason", status.reason_lossy())
+                .ok(),
+        }
+    })
+}
+
+/// Pick a translated message by the client's preferred languages
+fn localized_message<'a>(
+    messages: &'a HashMap<String, HashMap<String, String>>,
+    accept_language: Option<&str>,
+    code: ErrorCode,
+) -> Option<&'a str> {
+    let mut langs: Vec<(&str, f32)> = accept_language?
+        .split(',')
+        .filter_map(|l| {
+            let mut parts = l.trim().split(';');
+            let lang = parts.next()?.trim();
+            let q = parts
+                .find_map(|p| p.trim().strip_prefix("q="))
+                .and_then(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            Some((lang, q))
+        })
+        .collect();
+    langs.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+    langs.iter().find_map(|(lang, _)| {
+        let lang = lang.to_lowercase();
+        let primary = lang.split('-').next().unwrap_or(&lang).to_string();
+        messages
+            .get(&lang)
+            .or_else(|| messages.get(&primary))
+            .and_then(|m| m.get(code.as_str()))
+            .map(|m| m.as_str())
+    })
+}
@@ -0,0 +1,181 @@
+use chrono::{DateTime, Utc};
+use log::warn;
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::serde::json::Json;
+use rocket::serde::Serialize;
+use rocket::{routes, Request, Response, Route, State};
+use sqlx::{Error, FromRow, Row};
+
+use crate::auth::nip98::Nip98Auth;
+use crate::auth::AuthPubkey;
+use crate::db::Database;
+use crate::routes::error::ApiError;
+use crate::routes::progress::UploadTrace;
+use crate::routes::PagedResult;
+use crate::settings::Settings;
+
+/// Default number of upload failures kept
+const DEFAULT_LOG_SIZE: u64 = 10_000;
+
+pub fn failure_routes() -> Vec<Route> {
+    routes![account_failures]
+}
+
+/// A failed upload, kept so users and operators can see why it failed
+#[derive(Clone, FromRow, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct UploadFailure {
+    pub id: u64,
+    #[serde(with = "hex")]
+    pub pubkey: Vec<u8>,
+    pub created: DateTime<Utc>,
+    /// Request path, eg. /upload
+    pub route: String,
+    /// Declared size of the upload
+    pub size: Option<u64>,
+    /// Declared content type of the upload
+    pub mime_type: Option<String>,
+    /// request, receiving or processing
+    pub stage: String,
+    pub code: String,
+    pub detail: Option<String>,
+}
+
+impl Database {
+    /// Store an upload failure, dropping the oldest entries over `keep`
+    pub async fn add_upload_failure(&self, f: &UploadFailure, keep: u64) -> Result<(), Error> {
+        let res = sqlx::query(
+            "insert into upload_failures(pubkey,route,size,mime_type,stage,code,detail) \
+            values(?,?,?,?,?,?,?)",
+        )
+        .bind(&f.pubkey)
+        .bind(&f.route)
+        .bind(f.size)
+        .bind(&f.mime_type)
+        .bind(&f.stage)
+        .bind(&f.code)
+        .bind(&f.detail)
+        .execute(&self.pool)
+        .await?;
+        let id = res.last_insert_id();
+        if id > keep {
+            sqlx::query("delete from upload_failures where id <= ?")
+                .bind(id - keep)
+                .execute(&self.pool)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// List recent upload failures, newest first, for a single user or everyone
+    pub async fn list_upload_failures(
+        &self,
+        pubkey: Option<&Vec<u8>>,
+        offset: u32,
+        limit: u32,
+    ) -> Result<(Vec<UploadFailure>, i64), Error> {
+        let results: Vec<UploadFailure> = sqlx::query_as(
+            "select * from upload_failures \
+            where (? is null or pubkey = ?) \
+            order by id desc \
+            limit ? offset ?",
+        )
+        .bind(pubkey)
+        .bind(pubkey)
+        .bind(limit)
+        .bind(offset)
+        .fetch_all(&self.pool)
+        .await?;
+        let count: i64 =
+            sqlx::query("select count(id) from upload_failures where (? is null or pubkey = ?)")
+                .bind(pubkey)
+                .bind(pubkey)
+                .fetch_one(&self.pool)
+                .await?
+                .try_get(0)?;
+        Ok((results, count))
+    }
+}
+
+/// Records upload requests which ended with an error
+pub struct UploadFailureFairing;
+
+#[rocket::async_trait]
+impl Fairing for UploadFailureFairing {
+    fn info(&self) -> Info {
+        Info {
+            name: "Upload failures",
+            kind: Kind::Response,
+        }
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, _rsp: &mut Response<'r>) {
+        // only upload routes have a trace, and only errors from handlers are cached
+        let trace = req.local_cache(UploadTrace::default);
+        let stage = match trace.stage() {
+            Some(s) => s,
+            None => return,
+        };
+        let error = match req.local_cache(|| None::<ApiError>) {
+            Some(e) => e,
+            None => return,
+        };
+        let pubkey = match req.local_cache(|| AuthPubkey(None)).0 {
+            Some(p) => p,
+            None => return,
+        };
+        let (db, settings) = match (
+            req.rocket().state::<Database>(),
+            req.rocket().state::<Settings>(),
+        ) {
+            (Some(d), Some(s)) => (d.clone(), s),
+            _ => return,
+        };
+        let mime_type = req
+            .headers()
+            .get_one("x-content-type")
+            .or(req.headers().get_one("content-type"))
+            .filter(|c| !c.starts_with("multipart/"))
+            .map(|c| c.to_string());
+        let failure = UploadFailure {
+            id: 0,
+            pubkey: pubkey.to_bytes().to_vec(),
+            created: Utc::now(),
+            route: req.uri().path().to_string(),
+            size: trace.total(),
+            mime_type,
+            stage: stage.to_string(),
+            code: error.code.as_str().to_string(),
+            detail: error.detail.clone(),
+        };
+        let keep = settings.failure_log_size.unwrap_or(DEFAULT_LOG_SIZE);
+        // don't hold up the response on the insert
+        tokio::spawn(async move {
+            if let Err(e) = db.add_upload_failure(&failure, keep).await {
+                warn!("Failed to record upload failure: {}", e);
+            }
+        });
+    }
+}
+
+#[rocket::get("/account/failures?<page>&<count>")]
+async fn account_failures(
+    auth: Nip98Auth,
+    page: Option<u32>,
+    count: Option<u32>,
+    db: &State<Database>,
+) -> Result<Json<PagedResult<UploadFailure>>, ApiError> {
+    let pubkey = auth.pubkey.to_bytes().to_vec();
+    let page = page.unwrap_or(0);
+    let server_count = count.unwrap_or(50).clamp(1, 1_000);
+    let (failures, total) = db
+        .list_upload_failures(Some(&pubkey), page * server_count, server_count)
+        .await
+        .map_err(|e| ApiError::internal(format!("Could not list failures: {}", e)))?;
+    Ok(Json(PagedResult {
+        count: failures.len() as u32,
+        page,
+        total: total as u32,
+        files: failures,
+    }))
+}
@@ -0,0 +1,374 @@
+use rocket::http::{ContentType, Status};
+use rocket::serde::json::Json;
+use rocket::serde::Serialize;
+use rocket::{routes, Route, State};
+
+use crate::background::disk::{DiskReport, DiskState};
+use crate::db::Database;
+use crate::queue::ProcessingQueue;
+use crate::shed::{LoadShedder, RouteClass};
+use crate::upload_limit::{UploadLimiter, UploadScope};
+
+pub fn health_routes() -> Vec<Route> {
+    routes![healthz, metrics]
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct Health {
+    pub status: &'static str,
+    pub database: bool,
+    pub disk: DiskReport,
+}
+
+#[rocket::get("/healthz")]
+async fn healthz(db: &State<Database>, disk: &State<DiskState>) -> (Status, Json<Health>) {
+    let database = db.ping().await.is_ok();
+    let disk = disk.report();
+    let (status, code) = match (database, disk.read_only) {
+        (false, _) => ("error", Status::ServiceUnavailable),
+        (true, true) => ("read_only", Status::Ok),
+        (true, false) => ("ok", Status::Ok),
+    };
+    (
+        code,
+        Json(Health {
+            status,
+            database,
+            disk,
+        }),
+    )
+}
+
+/// Prometheus text exposition of the disk and processing state
+#[rocket::get("/metrics")]
+async fn metrics(
+    disk: &State<DiskState>,
+    queue: &State<ProcessingQueue>,
+    shed: &State<LoadShedder>,
+    uploads: &State<UploadLimiter>,
+) -> (ContentType, String) {
+    let report = disk.report();
+    let mut out = String::new();
+    out.push_str("# HELP route96_read_only Uploads are rejected because of low disk space\n");
+    out.push_str("# TYPE route96_read_only gauge\n");
+    out.push_str(&format!("route96_read_only {}\n", report.read_only as u8));
+    if let (Some(free), Some(total)) = (report.free_bytes, report.total_bytes) {
+        out.push_str("# HELP route96_disk_free_bytes Free bytes in the storage directory\n");
+        out.push_str("# TYPE route96_disk_free_bytes gauge\n");
+        out.push_str(&format!("route96_disk_free_bytes {}\n", free));
+        out.push_str("# HELP route96_disk_total_bytes Size of the storage directory filesystem\n");
+        out.push_str("# TYPE route96_disk_total_bytes gauge\n");
+        out.push_str(&format!("route96_disk_total_bytes {}\n", total));
+    }
+    out.push_str("# HELP route96_processing_queue_depth Uploads waiting for a processing slot\n");
+    out.push_str("# TYPE route96_processing_queue_depth gauge\n");
+    out.push_str(&format!(
+        "route96_processing_queue_depth {}\n",
+        queue.depth()
+    ));
+    out.push_str("# HELP route96_processing_active Uploads being processed\n");
+    out.push_str("# TYPE route96_processing_active gauge\n");
+    out.push_str(&format!("route96_processing_active {}\n", queue.active()));
+    load_shedding_metrics(&mut out, shed);
+    upload_limit_metrics(&mut out, uploads);
+    consistency_metrics(&mut out);
+    user_merge_metrics(&mut out);
+    dedup_metrics(&mut out);
+    length_mismatch_metrics(&mut out);
+    slow_transfer_metrics(&mut out);
+    deletion_metrics(&mut out);
+    download_verify_metrics(&mut out);
+    #[cfg(feature = "media-compression")]
+    processing_metrics(&mut out);
+    (ContentType::Plain, out)
+}
+
+fn load_shedding_metrics(out: &mut String, shed: &LoadShedder) {
+    out.push_str("# HELP route96_requests_in_flight Requests running by route class\n");
+    out.push_str("# TYPE route96_requests_in_flight gauge\n");
+    for class in RouteClass::ALL {
+        out.push_str(&format!(
+            "route96_requests_in_flight{{class=\"{}\"}} {}\n",
+            class.as_str(),
+            shed.in_flight(class)
+        ));
+    }
+    out.push_str("# HELP route96_requests_limit Concurrency ceiling by route class\n");
+    out.push_str("# TYPE route96_requests_limit gauge\n");
+    for class in RouteClass::ALL {
+        if let Some(limit) = shed.limit(class) {
+            out.push_str(&format!(
+                "route96_requests_limit{{class=\"{}\"}} {}\n",
+                class.as_str(),
+                limit
+            ));
+        }
+    }
+    out.push_str("# HELP route96_requests_shed_total Requests rejected with 503 by route class\n");
+    out.push_str("# TYPE route96_requests_shed_total counter\n");
+    for class in RouteClass::ALL {
+        out.push_str(&format!(
+            "route96_requests_shed_total{{class=\"{}\"}} {}\n",
+            class.as_str(),
+            shed.rejected(class)
+        ));
+    }
+}
+
+fn upload_limit_metrics(out: &mut String, uploads: &UploadLimiter) {
+    out.push_str("# HELP route96_uploads_active Uploads in progress\n");
+    out.push_str("# TYPE route96_uploads_active gauge\n");
+    out.push_str(&format!("route96_uploads_active {}\n", uploads.active()));
+    out.push_str("# HELP route96_upload_users_active Users with an upload in progress\n");
+    out.push_str("# TYPE route96_upload_users_active gauge\n");
+    out.push_str(&format!(
+        "route96_upload_users_active {}\n",
+        uploads.active_users()
+    ));
+    out.push_str("# HELP route96_uploads_limit Concurrent upload ceiling by scope\n");
+    out.push_str("# TYPE route96_uploads_limit gauge\n");
+    for scope in UploadScope::ALL {
+        if let Some(limit) = uploads.limit(scope) {
+            out.push_str(&format!(
+                "route96_uploads_limit{{scope=\"{}\"}} {}\n",
+                scope.as_str(),
+                limit
+            ));
+        }
+    }
+    out.push_str("# HELP route96_uploads_rejected_total Uploads rejected with 429 by scope\n");
+    out.push_str("# TYPE route96_uploads_rejected_total counter\n");
+    for scope in UploadScope::ALL {
+        out.push_str(&format!(
+            "route96_uploads_rejected_total{{scope=\"{}\"}} {}\n",
+            scope.as_str(),
+            uploads.rejected(scope)
+        ));
+    }
+}
+
+fn consistency_metrics(out: &mut String) {
+    use crate::background::consistency::stats;
+    use std::sync::atomic::Ordering;
+
+    let s = stats();
+    out.push_str("# HELP route96_startup_scan_running Startup consistency scan is in progress\n");
+    out.push_str("# TYPE route96_startup_scan_running gauge\n");
+    out.push_str(&format!(
+        "route96_startup_scan_running {}\n",
+        s.running.load(Ordering::Relaxed) as u8
+    ));
+    out.push_str(
+        "# HELP route96_startup_scan_checked Rows and files checked by the startup scan\n",
+    );
+    out.push_str("# TYPE route96_startup_scan_checked counter\n");
+    for (kind, n) in [("row", &s.checked_rows), ("file", &s.checked_files)] {
+        out.push_str(&format!(
+            "route96_startup_scan_checked{{kind=\"{}\"}} {}\n",
+            kind,
+            n.load(Ordering::Relaxed)
+        ));
+    }
+    out.push_str("# HELP route96_startup_scan_problems Problems found by the startup scan\n");
+    out.push_str("# TYPE route96_startup_scan_problems counter\n");
+    for (problem, n) in [
+        ("missing_blob", &s.missing_blobs),
+        ("orphan_file", &s.orphan_files),
+    ] {
+        out.push_str(&format!(
+            "route96_startup_scan_problems{{problem=\"{}\"}} {}\n",
+            problem,
+            n.load(Ordering::Relaxed)
+        ));
+    }
+    out.push_str(
+        "# HELP route96_startup_scan_fixes_queued Repair jobs queued by the startup scan\n",
+    );
+    out.push_str("# TYPE route96_startup_scan_fixes_queued counter\n");
+    out.push_str(&format!(
+        "route96_startup_scan_fixes_queued {}\n",
+        s.fixes_queued.load(Ordering::Relaxed)
+    ));
+}
+
+fn user_merge_metrics(out: &mut String) {
+    use crate::background::users::stats;
+    use std::sync::atomic::Ordering;
+
+    let s = stats();
+    out.push_str("# HELP route96_user_merges_total Duplicate user rows merged\n");
+    out.push_str("# TYPE route96_user_merges_total counter\n");
+    out.push_str(&format!(
+        "route96_user_merges_total {}\n",
+        s.merged.load(Ordering::Relaxed)
+    ));
+    out.push_str(
+        "# HELP route96_user_merge_files_total Files moved to the remaining user by merges\n",
+    );
+    out.push_str("# TYPE route96_user_merge_files_total counter\n");
+    out.push_str(&format!(
+        "route96_user_merge_files_total {}\n",
+        s.files_moved.load(Ordering::Relaxed)
+    ));
+    out.push_str(
+        "# HELP route96_user_merge_unresolved_total Duplicate user rows without a unique match\n",
+    );
+    out.push_str("# TYPE route96_user_merge_unresolved_total counter\n");
+    out.push_str(&format!(
+        "route96_user_merge_unresolved_total {}\n",
+        s.unresolved.load(Ordering::Relaxed)
+    ));
+}
+
+fn dedup_metrics(out: &mut String) {
+    use crate::filesystem::dedup_stats;
+    use std::sync::atomic::Ordering;
+
+    let s = dedup_stats();
+    out.push_str("# HELP route96_upload_dedup_total Uploads of a file which was already stored\n");
+    out.push_str("# TYPE route96_upload_dedup_total counter\n");
+    out.push_str(&format!(
+        "route96_upload_dedup_total {}\n",
+        s.hits.load(Ordering::Relaxed)
+    ));
+    out.push_str(
+        "# HELP route96_upload_dedup_bytes_total Bytes not stored again because of deduplication\n",
+    );
+    out.push_str("# TYPE route96_upload_dedup_bytes_total counter\n");
+    out.push_str(&format!(
+        "route96_upload_dedup_bytes_total {}\n",
+        s.bytes.load(Ordering::Relaxed)
+    ));
+}
+
+fn length_mismatch_metrics(out: &mut String) {
+    use crate::filesystem::length_mismatch_stats;
+    use std::sync::atomic::Ordering;
+
+    let s = length_mismatch_stats();
+    out.push_str(
+        "# HELP route96_upload_length_mismatch_total Uploads which did not match their declared size\n",
+    );
+    out.push_str("# TYPE route96_upload_length_mismatch_total counter\n");
+    out.push_str(&format!(
+        "route96_upload_length_mismatch_total{{kind=\"over\"}} {}\n",
+        s.over.load(Ordering::Relaxed)
+    ));
+    out.push_str(&format!(
+        "route96_upload_length_mismatch_total{{kind=\"under\"}} {}\n",
+        s.under.load(Ordering::Relaxed)
+    ));
+}
+
+fn slow_transfer_metrics(out: &mut String) {
+    use crate::transfer::{stats, TransferKind};
+    use std::sync::atomic::Ordering;
+
+    out.push_str(
+        "# HELP route96_slow_transfers_aborted_total Transfers aborted for being too slow\n",
+    );
+    out.push_str("# TYPE route96_slow_transfers_aborted_total counter\n");
+    for kind in TransferKind::ALL {
+        let s = stats(kind);
+        out.push_str(&format!(
+            "route96_slow_transfers_aborted_total{{path=\"{}\",reason=\"timeout\"}} {}\n",
+            kind.as_str(),
+            s.timeout.load(Ordering::Relaxed)
+        ));
+        out.push_str(&format!(
+            "route96_slow_transfers_aborted_total{{path=\"{}\",reason=\"rate\"}} {}\n",
+            kind.as_str(),
+            s.rate.load(Ordering::Relaxed)
+        ));
+    }
+}
+
+fn deletion_metrics(out: &mut String) {
+    use crate::deletion::{stats, DeleteReason};
+    use std::sync::atomic::Ordering;
+
+    out.push_str("# HELP route96_deleted_files_total Files removed from storage by reason\n");
+    out.push_str("# TYPE route96_deleted_files_total counter\n");
+    for reason in DeleteReason::ALL {
+        out.push_str(&format!(
+            "route96_deleted_files_total{{reason=\"{}\"}} {}\n",
+            reason.as_str(),
+            stats(reason).files.load(Ordering::Relaxed)
+        ));
+    }
+    out.push_str(
+        "# HELP route96_deleted_owners_total Ownerships removed while other owners kept the file\n",
+    );
+    out.push_str("# TYPE route96_deleted_owners_total counter\n");
+    for reason in DeleteReason::ALL {
+        out.push_str(&format!(
+            "route96_deleted_owners_total{{reason=\"{}\"}} {}\n",
+            reason.as_str(),
+            stats(reason).owners.load(Ordering::Relaxed)
+        ));
+    }
+}
+
+fn download_verify_metrics(out: &mut String) {
+    use crate::background::scrub::download_verify_stats;
+    use std::sync::atomic::Ordering;
+
+    let s = download_verify_stats();
+    out.push_str(
+        "# HELP route96_download_verify_total Downloads hashed while they were sent, by result\n",
+    );
+    out.push_str("# TYPE route96_download_verify_total counter\n");
+    out.push_str(&format!(
+        "route96_download_verify_total{{result=\"ok\"}} {}\n",
+        s.ok.load(Ordering::Relaxed)
+    ));
+    out.push_str(&format!(
+        "route96_download_verify_total{{result=\"failed\"}} {}\n",
+        s.failed.load(Ordering::Relaxed)
+    ));
+}
+
+#[cfg(feature = "media-compression")]
+fn processing_metrics(out: &mut String) {
+    use crate::processing::hwaccel::{stats, Stage};
+    use std::sync::atomic::Ordering;
+
+    out.push_str(
+        "# HELP route96_processing_runs_total Media processing runs by stage and backend\n",
+    );
+    out.push_str("# TYPE route96_processing_runs_total counter\n");
+    for stage in Stage::ALL {
+        let s = stats(stage);
+        for (accel, runs) in [("hw", &s.hw_runs), ("sw", &s.sw_runs)] {
+            out.push_str(&format!(
+                "route96_processing_runs_total{{stage=\"{}\",accel=\"{}\"}} {}\n",
+                stage.as_str(),
+                accel,
+                runs.load(Ordering::Relaxed)
+            ));
+        }
+    }
+    out.push_str("# HELP route96_processing_seconds_total Time spent processing media by stage and backend\n");
+    out.push_str("# TYPE route96_processing_seconds_total counter\n");
+    for stage in Stage::ALL {
+        let s = stats(stage);
+        for (accel, micros) in [("hw", &s.hw_micros), ("sw", &s.sw_micros)] {
+            out.push_str(&format!(
+                "route96_processing_seconds_total{{stage=\"{}\",accel=\"{}\"}} {:.3}\n",
+                stage.as_str(),
+                accel,
+                micros.load(Ordering::Relaxed) as f64 / 1_000_000.0
+            ));
+        }
+    }
+    out.push_str("# HELP route96_processing_fallbacks_total Hardware runs retried in software\n");
+    out.push_str("# TYPE route96_processing_fallbacks_total counter\n");
+    for stage in Stage::ALL {
+        out.push_str(&format!(
+            "route96_processing_fallbacks_total{{stage=\"{}\"}} {}\n",
+            stage.as_str(),
+            stats(stage).fallbacks.load(Ordering::Relaxed)
+        ));
+    }
+}
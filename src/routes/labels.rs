@@ -0,0 +1,68 @@
+use rocket::serde::json::Json;
+use rocket::{routes, Route, State};
+
+use crate::auth::api_key::ApiKeyScope;
+use crate::auth::nip98::Nip98Auth;
+use crate::db::{Database, LabelCount};
+use crate::routes::error::{ApiError, ErrorCode};
+use crate::routes::{Nip94Event, PagedResult};
+use crate::settings::Settings;
+
+pub fn label_routes() -> Vec<Route> {
+    routes![list_labels, list_files_by_label]
+}
+
+/// Admins see all files, other users only see files they own
+async fn visible_to(auth: &Nip98Auth, db: &Database) -> Result<Option<Vec<u8>>, ApiError> {
+    if !auth.has_scope(ApiKeyScope::List) {
+        return Err(ApiError::with_detail(
+            ErrorCode::InvalidAuth,
+            "API key scope missing",
+        ));
+    }
+    let pubkey = auth.pubkey.to_bytes().to_vec();
+    match db.get_user(&pubkey).await {
+        Ok(u) if u.is_admin && auth.event.is_some() => Ok(None),
+        _ => Ok(Some(pubkey)),
+    }
+}
+
+#[rocket::get("/labels?<count>")]
+async fn list_labels(
+    auth: Nip98Auth,
+    count: Option<u32>,
+    db: &State<Database>,
+) -> Result<Json<Vec<LabelCount>>, ApiError> {
+    let owner = visible_to(&auth, db).await?;
+    db.list_top_labels(owner.as_ref(), count.unwrap_or(100).clamp(1, 1_000))
+        .await
+        .map(Json)
+        .map_err(|e| ApiError::internal(format!("Could not list labels: {}", e)))
+}
+
+#[rocket::get("/files/by-label/<label>?<page>&<count>")]
+async fn list_files_by_label(
+    auth: Nip98Auth,
+    label: &str,
+    page: Option<u32>,
+    count: Option<u32>,
+    db: &State<Database>,
+    settings: &State<Settings>,
+) -> Result<Json<PagedResult<Nip94Event>>, ApiError> {
+    let owner = visible_to(&auth, db).await?;
+    let page = page.unwrap_or(0);
+    let server_count = count.unwrap_or(50).clamp(1, 5_000);
+    let (files, total) = db
+        .list_files_by_label(label, owner.as_ref(), page * server_count, server_count)
+        .await
+        .map_err(|e| ApiError::internal(format!("Could not list files: {}", e)))?;
+    Ok(Json(PagedResult {
+        count: server_count,
+        page,
+        total: total as u32,
+        files: files
+            .iter()
+            .map(|f| Nip94Event::from_upload(settings, f))
+            .collect(),
+    }))
+}
@@ -1,39 +1,106 @@
-use crate::db::{Database, FileUpload};
-use crate::filesystem::FileStore;
+use crate::auth::api_key::ApiKeyScope;
+use crate::auth::nip98::Nip98Auth;
+use crate::background::disk::DiskStatus;
+use crate::background::retention::upload_expiry;
+use crate::background::scrub::VerifyingReader;
+#[cfg(feature = "torrent-v2")]
+use crate::background::torrent::{torrent_path, upload_magnet};
+use crate::blocklist::{self, UploadOrigin};
+use crate::capabilities;
+use crate::db::{Database, FileUpload, UploadState};
+use crate::deletion::DeletionService;
+use crate::filesystem::{BlobReader, FileStore, FileSystemResult};
+use crate::legal_hold;
+use crate::metadata::sanitize_license;
+#[cfg(feature = "media-compression")]
+use crate::processing::thumbnail::THUMBNAIL_PARAMS;
+use crate::queue::ProcessingQueue;
 pub use crate::routes::admin::admin_routes;
 #[cfg(feature = "blossom")]
 pub use crate::routes::blossom::blossom_routes;
+pub use crate::routes::collections::collection_routes;
+use crate::routes::error::{ApiError, ErrorCode};
+#[cfg(any(feature = "blossom", feature = "nip96"))]
+pub use crate::routes::failures::{failure_routes, UploadFailureFairing};
+pub use crate::routes::health::health_routes;
+#[cfg(feature = "labels")]
+pub use crate::routes::labels::label_routes;
 #[cfg(feature = "nip96")]
-pub use crate::routes::nip96::nip96_routes;
+pub use crate::routes::nip96::{nip96_routes, DeferredUploads};
+pub use crate::routes::param::Sha256Param;
+pub use crate::routes::preview::preview_routes;
+pub use crate::routes::progress::{progress_routes, ClientHints, ProgressTracker};
+#[cfg(feature = "ranges")]
+use crate::routes::range::{range_body, DEFAULT_MAX_UNBOUNDED_RANGE};
+pub use crate::routes::short::short_routes;
+pub use crate::routes::tos::{check_tos, tos_routes, tos_url};
+#[cfg(feature = "react-ui")]
+pub use crate::routes::ui::{ui_catchers, ui_routes};
+pub use crate::routes::zip::zip_routes;
 use crate::settings::Settings;
+use crate::shed::ReadSlot;
+use crate::vanity::VanityHost;
 use crate::void_file::VoidFile;
-use anyhow::Error;
+use chrono::{DateTime, Utc};
 use http_range_header::{parse_range_header, EndPosition, StartPosition};
 use log::{debug, warn};
-use nostr::Event;
+use nostr::PublicKey;
 use rocket::fs::NamedFile;
-use rocket::http::{ContentType, Header, Status};
+use rocket::http::{Accept, ContentType, Header, Status};
+use rocket::response::content::RawHtml;
+#[cfg(feature = "media-compression")]
+use rocket::response::Redirect;
 use rocket::response::Responder;
-use rocket::serde::Serialize;
+use rocket::serde::json::Json;
+use rocket::serde::{Deserialize, Serialize};
 use rocket::{Request, Response, State};
-use std::io::SeekFrom;
-use std::ops::Range;
-use std::pin::{pin, Pin};
+#[cfg(any(feature = "blossom", feature = "nip96"))]
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::PathBuf;
 use std::str::FromStr;
-use std::task::{Context, Poll};
-use tokio::fs::File;
-use tokio::io::{AsyncRead, AsyncSeek, ReadBuf};
 
 #[cfg(feature = "blossom")]
 mod blossom;
+mod collections;
+#[cfg(any(feature = "blossom", feature = "nip96"))]
+mod failures;
+mod health;
+#[cfg(feature = "labels")]
+mod labels;
 #[cfg(feature = "nip96")]
 mod nip96;
+mod param;
+mod preview;
+pub mod progress;
+#[cfg(feature = "ranges")]
+mod range;
+mod short;
+mod tos;
+#[cfg(feature = "react-ui")]
+mod ui;
+pub mod zip;
 
 mod admin;
+pub mod error;
+
+/// Retry-After for blobs which are still being received
+const PENDING_RETRY_AFTER: u64 = 5;
+
+/// Default percentage of the quota after which upload responses include a warning
+const DEFAULT_QUOTA_WARN_PERCENT: u8 = 80;
 
 pub struct FilePayload {
-    pub file: File,
+    pub file: BlobReader,
     pub info: FileUpload,
+    /// Send as attachment instead of inline
+    pub download: bool,
+    /// Include the original file name in content-disposition
+    pub show_name: bool,
+    /// Content depends on the Accept header (image format negotiation)
+    pub vary_accept: bool,
+    /// Hash the file while it is sent, see [VerifyingReader]
+    pub verify: bool,
 }
 
 #[derive(Clone, Debug, Serialize, Default)]
@@ -42,6 +109,9 @@ struct Nip94Event {
     pub created_at: i64,
     pub content: String,
     pub tags: Vec<Vec<String>>,
+    /// Ids of the owner's collections containing the file, only in file listings
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub collections: Option<Vec<u64>>,
 }
 
 #[derive(Serialize, Default)]
@@ -53,6 +123,24 @@ struct PagedResult<T> {
     pub files: Vec<T>,
 }
 
+/// Tags set by the server which cannot be overridden with `nip94_tags`
+pub const NIP94_RESERVED_TAGS: &[&str] = &[
+    "url",
+    "x",
+    "ox",
+    "m",
+    "size",
+    "blurhash",
+    "dim",
+    "expiration",
+    "magnet",
+    "t",
+    "alt",
+    "i",
+    "license",
+    "thumb",
+];
+
 impl Nip94Event {
     pub fn from_upload(settings: &Settings, upload: &FileUpload) -> Self {
         let hex_id = hex::encode(&upload.id);
@@ -69,6 +157,11 @@ impl Nip94Event {
                 ),
             ],
             vec!["x".to_string(), hex_id],
+            // hash of the data the client sent, before the server transformed it
+            vec![
+                "ox".to_string(),
+                hex::encode(upload.original_hash.as_ref().unwrap_or(&upload.id)),
+            ],
             vec!["m".to_string(), upload.mime_type.clone()],
             vec!["size".to_string(), upload.size.to_string()],
         ];
@@ -78,6 +171,16 @@ impl Nip94Event {
         if let (Some(w), Some(h)) = (upload.width, upload.height) {
             tags.push(vec!["dim".to_string(), format!("{}x{}", w, h)])
         }
+        if let Some(e) = &upload.expires {
+            tags.push(vec!["expiration".to_string(), e.timestamp().to_string()]);
+        }
+        if let Some(l) = &upload.license {
+            tags.push(vec!["license".to_string(), l.clone()]);
+        }
+        #[cfg(feature = "torrent-v2")]
+        if let Some(magnet) = upload_magnet(settings, upload) {
+            tags.push(vec!["magnet".to_string(), magnet]);
+        }
         #[cfg(feature = "labels")]
         for l in &upload.labels {
             let val = if l.label.contains(',') {
@@ -88,268 +191,961 @@ impl Nip94Event {
             };
             tags.push(vec!["t".to_string(), val])
         }
+        for (k, v) in settings.nip94_tags.iter().flatten() {
+            if NIP94_RESERVED_TAGS.contains(&k.as_str()) {
+                continue;
+            }
+            let val = v
+                .replace("{sha256}", &hex::encode(&upload.id))
+                .replace("{size}", &upload.size.to_string())
+                .replace("{mime}", &upload.mime_type);
+            tags.push(vec![k.clone(), val]);
+        }
 
         Self {
             content: upload.name.clone(),
             created_at: upload.created.timestamp(),
             tags,
+            collections: None,
         }
     }
-}
-
-/// Range request handler over file handle
-struct RangeBody {
-    file: File,
-    range_start: u64,
-    range_end: u64,
-    current_offset: u64,
-    poll_complete: bool,
-}
 
-impl RangeBody {
-    pub fn new(file: File, range: Range<u64>) -> Self {
-        Self {
-            file,
-            range_start: range.start,
-            range_end: range.end,
-            current_offset: 0,
-            poll_complete: false,
+    /// Add the content addressed url of the thumbnail of the file
+    pub fn with_thumbnail(mut self, settings: &Settings, thumb: Option<&Vec<u8>>) -> Self {
+        if let Some(t) = thumb {
+            self.tags.push(vec![
+                "thumb".to_string(),
+                thumb_url(settings, t),
+                hex::encode(t),
+            ]);
         }
+        self
     }
 }
 
-impl AsyncRead for RangeBody {
-    fn poll_read(
-        mut self: Pin<&mut Self>,
-        cx: &mut Context<'_>,
-        buf: &mut ReadBuf<'_>,
-    ) -> Poll<std::io::Result<()>> {
-        let range_start = self.range_start + self.current_offset;
-        let range_len = self.range_end - range_start;
-        let bytes_to_read = buf.remaining().min(range_len as usize) as u64;
+/// Url of a thumbnail by its own hash, which clients and CDNs can cache forever
+fn thumb_url(settings: &Settings, thumb: &[u8]) -> String {
+    format!("{}/{}.webp", settings.public_url, hex::encode(thumb))
+}
 
-        if bytes_to_read == 0 {
-            return Poll::Ready(Ok(()));
+/// Thumbnail of a file, once it was generated
+async fn file_thumbnail(db: &Database, file: &Vec<u8>) -> Option<FileUpload> {
+    #[cfg(feature = "media-compression")]
+    match db.get_derived_file(file, THUMBNAIL_PARAMS).await {
+        Ok(t) => t,
+        Err(e) => {
+            warn!("Failed to load thumbnail: {}", e);
+            None
         }
+    }
+    #[cfg(not(feature = "media-compression"))]
+    {
+        let _ = (db, file);
+        None
+    }
+}
 
-        // when no pending poll, seek to starting position
-        if !self.poll_complete {
-            let pinned = pin!(&mut self.file);
-            pinned.start_seek(SeekFrom::Start(range_start))?;
-            self.poll_complete = true;
+/// Thumbnails of the files of a user, by file
+#[cfg(any(feature = "blossom", feature = "nip96"))]
+async fn user_thumbnails(db: &Database, pubkey: &Vec<u8>) -> HashMap<Vec<u8>, Vec<u8>> {
+    #[cfg(feature = "media-compression")]
+    match db.list_user_derivations(pubkey, THUMBNAIL_PARAMS).await {
+        Ok(t) => t,
+        Err(e) => {
+            warn!("Failed to list thumbnails: {}", e);
+            HashMap::new()
         }
+    }
+    #[cfg(not(feature = "media-compression"))]
+    {
+        let _ = (db, pubkey);
+        HashMap::new()
+    }
+}
 
-        // check poll completion
-        if self.poll_complete {
-            let pinned = pin!(&mut self.file);
-            match pinned.poll_complete(cx) {
-                Poll::Ready(Ok(_)) => {
-                    self.poll_complete = false;
-                }
-                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
-                Poll::Pending => return Poll::Pending,
-            }
-        }
+/// Blobs are content addressed, the hash of the served file never changes
+const BLOB_CACHE_CONTROL: &str = "public, max-age=31536000, immutable";
 
-        // Read data from the file
-        let pinned = pin!(&mut self.file);
-        match pinned.poll_read(cx, buf) {
-            Poll::Ready(Ok(_)) => {
-                self.current_offset += bytes_to_read;
-                Poll::Ready(Ok(()))
-            }
-            Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
-            Poll::Pending => {
-                self.poll_complete = true;
-                Poll::Pending
-            }
-        }
-    }
+/// Check if any entity tag of an If-None-Match header matches
+fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    if_none_match
+        .split(',')
+        .map(|t| t.trim())
+        .any(|t| t == "*" || t.trim_start_matches("W/") == etag)
 }
 
 impl<'r> Responder<'r, 'static> for FilePayload {
     fn respond_to(self, request: &'r Request<'_>) -> rocket::response::Result<'static> {
         let mut response = Response::new();
 
-        // handle ranges
-        #[cfg(feature = "ranges")]
+        // the served file can be a rendition, tag the bytes which are actually sent
+        let etag = format!("\"{}\"", hex::encode(&self.info.id));
+        response.set_header(Header::new("etag", etag.clone()));
+        response.set_header(Header::new("cache-control", BLOB_CACHE_CONTROL));
+        if self.vary_accept {
+            response.set_header(Header::new("vary", "accept"));
+        }
+        if request
+            .headers()
+            .get("if-none-match")
+            .any(|v| etag_matches(v, &etag))
         {
-            response.set_header(Header::new("accept-ranges", "bytes"));
-            if let Some(r) = request.headers().get("range").next() {
-                if let Ok(ranges) = parse_range_header(r) {
-                    if ranges.ranges.len() > 1 {
-                        warn!("Multipart ranges are not supported, fallback to non-range request");
-                        response.set_streamed_body(self.file);
-                    } else {
-                        const MAX_UNBOUNDED_RANGE: u64 = 1024 * 1024;
-                        let single_range = ranges.ranges.first().unwrap();
-                        let range_start = match single_range.start {
-                            StartPosition::Index(i) => i,
-                            StartPosition::FromLast(i) => self.info.size - i,
-                        };
-                        let range_end = match single_range.end {
-                            EndPosition::Index(i) => i,
-                            EndPosition::LastByte => {
-                                (range_start + MAX_UNBOUNDED_RANGE).min(self.info.size)
-                            }
-                        };
-                        let r_len = range_end - range_start;
-                        let r_body = RangeBody::new(self.file, range_start..range_end);
-
-                        response.set_status(Status::PartialContent);
-                        response.set_header(Header::new("content-length", r_len.to_string()));
-                        response.set_header(Header::new(
-                            "content-range",
-                            format!("bytes {}-{}/{}", range_start, range_end - 1, self.info.size),
-                        ));
-                        response.set_streamed_body(Box::pin(r_body));
+            response.set_status(Status::NotModified);
+            return Ok(response);
+        }
+
+        if self.verify {
+            // the whole file is hashed while it is sent, ranges cannot be checked
+            let db = request.rocket().state::<Database>().cloned();
+            response.set_header(Header::new("x-sha-256", hex::encode(&self.info.id)));
+            response.set_streamed_body(VerifyingReader::new(self.file, &self.info.id, db));
+            response.set_header(Header::new("content-length", self.info.size.to_string()));
+        } else {
+            // handle ranges
+            #[cfg(feature = "ranges")]
+            {
+                response.set_header(Header::new("accept-ranges", "bytes"));
+                if let Some(r) = request.headers().get("range").next() {
+                    if let Ok(ranges) = parse_range_header(r) {
+                        if ranges.ranges.len() > 1 {
+                            warn!(
+                                "Multipart ranges are not supported, fallback to non-range request"
+                            );
+                            response.set_streamed_body(self.file);
+                        } else {
+                            let settings = request.rocket().state::<Settings>();
+                            let max_unbounded = settings
+                                .and_then(|s| s.range_reads.as_ref())
+                                .and_then(|r| r.max_unbounded)
+                                .unwrap_or(DEFAULT_MAX_UNBOUNDED_RANGE);
+                            let single_range = ranges.ranges.first().unwrap();
+                            let range_start = match single_range.start {
+                                StartPosition::Index(i) => i,
+                                StartPosition::FromLast(i) => self.info.size - i,
+                            };
+                            let range_end = match single_range.end {
+                                EndPosition::Index(i) => i,
+                                EndPosition::LastByte => {
+                                    (range_start + max_unbounded).min(self.info.size)
+                                }
+                            };
+                            let r_len = range_end - range_start;
+                            let r_body = range_body(self.file, range_start..range_end, settings);
+
+                            response.set_status(Status::PartialContent);
+                            response.set_header(Header::new("content-length", r_len.to_string()));
+                            response.set_header(Header::new(
+                                "content-range",
+                                format!(
+                                    "bytes {}-{}/{}",
+                                    range_start,
+                                    range_end - 1,
+                                    self.info.size
+                                ),
+                            ));
+                            response.set_streamed_body(r_body);
+                        }
                     }
+                } else {
+                    response.set_streamed_body(self.file);
                 }
-            } else {
+            }
+            #[cfg(not(feature = "ranges"))]
+            {
                 response.set_streamed_body(self.file);
+                response.set_header(Header::new("content-length", self.info.size.to_string()));
             }
         }
-        #[cfg(not(feature = "ranges"))]
-        {
-            response.set_streamed_body(self.file);
-            response.set_header(Header::new("content-length", self.info.size.to_string()));
-        }
 
         if let Ok(ct) = ContentType::from_str(&self.info.mime_type) {
             response.set_header(ct);
         }
-        if !self.info.name.is_empty() {
+        let name = if self.show_name {
+            self.info.name.as_str()
+        } else {
+            ""
+        };
+        if self.download || !name.is_empty() {
             response.set_header(Header::new(
                 "content-disposition",
-                format!("inline; filename=\"{}\"", self.info.name),
+                content_disposition(self.download, name),
             ));
         }
         Ok(response)
     }
 }
 
-async fn delete_file(
-    sha256: &str,
-    auth: &Event,
-    fs: &FileStore,
-    db: &Database,
-) -> Result<(), Error> {
-    let sha256 = if sha256.contains(".") {
-        sha256.split('.').next().unwrap()
-    } else {
-        sha256
-    };
-    let id = if let Ok(i) = hex::decode(sha256) {
-        i
+/// Build a content-disposition header value with a sanitized file name
+fn content_disposition(download: bool, name: &str) -> String {
+    let kind = if download { "attachment" } else { "inline" };
+    let name: String = name.chars().filter(|c| !c.is_control()).collect();
+    let name = name.trim();
+    if name.is_empty() {
+        return kind.to_string();
+    }
+    let ascii: String = name
+        .chars()
+        .map(|c| match c {
+            '"' | '\\' | ';' | '/' => '_',
+            c if c.is_ascii() => c,
+            _ => '_',
+        })
+        .collect();
+    if ascii == name {
+        format!("{}; filename=\"{}\"", kind, ascii)
     } else {
-        return Err(Error::msg("Invalid file id"));
+        // RFC 6266 / RFC 5987 encoded name for non-ascii file names
+        let encoded: String = name
+            .bytes()
+            .map(|b| match b {
+                b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' => (b as char).to_string(),
+                b'!' | b'#' | b'$' | b'&' | b'+' | b'-' | b'.' | b'^' | b'_' | b'`' | b'|'
+                | b'~' => (b as char).to_string(),
+                _ => format!("%{:02X}", b),
+            })
+            .collect();
+        format!(
+            "{}; filename=\"{}\"; filename*=UTF-8''{}",
+            kind, ascii, encoded
+        )
+    }
+}
+
+/// Effective upload limits for a pubkey, used by clients to preflight uploads
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct UploadLimits {
+    pub max_upload_bytes: u64,
+    /// Bytes currently stored by this pubkey
+    pub used_bytes: u64,
+    /// Remaining storage quota, null when unlimited
+    pub remaining_bytes: Option<u64>,
+    /// Accepted mime types, null when any type is accepted
+    pub allowed_mime_types: Option<Vec<String>>,
+    pub payment_required: bool,
+    pub whitelisted: bool,
+}
+
+async fn upload_limits(
+    pubkey: &PublicKey,
+    db: &Database,
+    settings: &Settings,
+) -> Result<UploadLimits, ApiError> {
+    let quota = quota_usage(db, settings, &pubkey.to_bytes().to_vec()).await?;
+    Ok(UploadLimits {
+        max_upload_bytes: settings.max_upload_bytes,
+        used_bytes: quota.used,
+        remaining_bytes: quota.remaining(),
+        allowed_mime_types: None,
+        payment_required: false,
+        whitelisted: match &settings.whitelist {
+            Some(wl) => wl.contains(&pubkey.to_hex()),
+            None => true,
+        },
+    })
+}
+
+/// Storage used by a pubkey against its quota
+#[derive(Clone, Copy)]
+pub struct QuotaUsage {
+    pub used: u64,
+    /// Quota in bytes, None when unlimited
+    pub limit: Option<u64>,
+    warn_percent: u8,
+}
+
+impl QuotaUsage {
+    pub fn remaining(&self) -> Option<u64> {
+        self.limit.map(|l| l.saturating_sub(self.used))
+    }
+
+    /// Warning for clients once usage passes the configured percentage of the quota
+    pub fn warning(&self) -> Option<String> {
+        let limit = self.limit?;
+        let percent = match limit {
+            0 => 100,
+            l => (self.used.saturating_mul(100) / l).min(100),
+        };
+        if percent < self.warn_percent as u64 {
+            return None;
+        }
+        Some(format!(
+            "Storage is {}% full, {} of {} bytes used",
+            percent, self.used, limit
+        ))
+    }
+}
+
+async fn quota_usage(
+    db: &Database,
+    settings: &Settings,
+    pubkey: &Vec<u8>,
+) -> Result<QuotaUsage, ApiError> {
+    let (used, tier) = match db.get_user(pubkey).await {
+        Ok(u) => (
+            db.get_user_stats(u.id)
+                .await
+                .map_err(|e| ApiError::internal(e.to_string()))?
+                .total_size,
+            u.tier,
+        ),
+        Err(sqlx::Error::RowNotFound) => (0, None),
+        Err(e) => return Err(ApiError::internal(e.to_string())),
     };
+    let cfg = settings.quota.as_ref();
+    Ok(QuotaUsage {
+        used,
+        limit: cfg.and_then(|q| match tier.as_deref().and_then(|t| q.tiers.get(t)) {
+            Some(bytes) => *bytes,
+            None => q.default_bytes,
+        }),
+        warn_percent: cfg
+            .and_then(|q| q.warn_percent)
+            .unwrap_or(DEFAULT_QUOTA_WARN_PERCENT),
+    })
+}
 
-    if id.len() != 32 {
-        return Err(Error::msg("Invalid file id"));
+/// Reject an upload of a known size which would go over the uploader's quota
+pub(crate) async fn check_quota(
+    db: &Database,
+    settings: &Settings,
+    pubkey: &Vec<u8>,
+    size: Option<u64>,
+) -> Result<(), ApiError> {
+    let size = match (size, &settings.quota) {
+        (Some(s), Some(_)) => s,
+        _ => return Ok(()),
+    };
+    match quota_usage(db, settings, pubkey).await?.remaining() {
+        Some(r) if size > r => Err(ErrorCode::QuotaExceeded.into()),
+        _ => Ok(()),
     }
-    if let Ok(Some(_info)) = db.get_file(&id).await {
-        let pubkey_vec = auth.pubkey.to_bytes().to_vec();
-        let auth_user = db.get_user(&pubkey_vec).await?;
-        let owners = db.get_file_owners(&id).await?;
-        if auth_user.is_admin {
-            if let Err(e) = db.delete_all_file_owner(&id).await {
-                return Err(Error::msg(format!("Failed to delete (db): {}", e)));
-            }
-            if let Err(e) = db.delete_file(&id).await {
-                return Err(Error::msg(format!("Failed to delete (fs): {}", e)));
-            }
-            if let Err(e) = tokio::fs::remove_file(fs.get(&id)).await {
-                warn!("Failed to delete (fs): {}", e);
-            }
-        } else {
-            let this_owner = match owners.iter().find(|o| o.pubkey.eq(&pubkey_vec)) {
-                Some(o) => o,
-                None => return Err(Error::msg("You dont own this file, you cannot delete it")),
-            };
-            if let Err(e) = db.delete_file_owner(&id, this_owner.id).await {
-                return Err(Error::msg(format!("Failed to delete (db): {}", e)));
-            }
-            // only 1 owner was left, delete file completely
-            if owners.len() == 1 {
-                if let Err(e) = db.delete_file(&id).await {
-                    return Err(Error::msg(format!("Failed to delete (fs): {}", e)));
-                }
-                if let Err(e) = tokio::fs::remove_file(fs.get(&id)).await {
-                    warn!("Failed to delete (fs): {}", e);
-                }
+}
+
+/// Upload response with X-Quota-Used and X-Quota-Remaining headers
+pub struct WithQuota<R>(pub R, pub Option<QuotaUsage>);
+
+impl<'r, 'o: 'r, R: Responder<'r, 'o>> Responder<'r, 'o> for WithQuota<R> {
+    fn respond_to(self, request: &'r Request<'_>) -> rocket::response::Result<'o> {
+        let mut response = self.0.respond_to(request)?;
+        if let Some(q) = self.1 {
+            response.set_header(Header::new("x-quota-used", q.used.to_string()));
+            if let Some(r) = q.remaining() {
+                response.set_header(Header::new("x-quota-remaining", r.to_string()));
             }
         }
-        Ok(())
-    } else {
-        Err(Error::msg("File not found"))
+        Ok(response)
     }
 }
 
-#[rocket::get("/")]
-pub async fn root() -> Result<NamedFile, Status> {
+/// Reject uploads in read-only mode, or when the upload would use the remaining free space
+pub(crate) fn check_disk_space(
+    disk: &DiskStatus,
+    settings: &Settings,
+    size: Option<u64>,
+) -> Result<(), ApiError> {
+    if disk.read_only() {
+        return Err(ApiError::with_detail(
+            ErrorCode::InsufficientStorage,
+            "Server is in read-only mode",
+        ));
+    }
+    if let (Some(size), Some(free), Some(cfg)) = (size, disk.free(), &settings.disk) {
+        if free.saturating_sub(size) < cfg.min_free_bytes {
+            return Err(ErrorCode::InsufficientStorage.into());
+        }
+    }
+    Ok(())
+}
+
+/// Store the app used for an upload when client hints are enabled
+pub(crate) async fn record_client_hints(
+    db: &Database,
+    settings: &Settings,
+    file: &Vec<u8>,
+    user_id: u64,
+    hints: &ClientHints,
+) {
+    if !settings.client_hints.unwrap_or(false) || hints.is_empty() {
+        return;
+    }
+    if let Err(e) = db
+        .set_upload_client(
+            file,
+            user_id,
+            hints.user_agent.as_deref(),
+            hints.client.as_deref(),
+        )
+        .await
+    {
+        warn!("Failed to record upload client: {}", e);
+    }
+}
+
+/// Time a deduplicated blob was first uploaded, looked up before the new upload is saved
+pub(crate) async fn first_uploaded(
+    db: &Database,
+    blob: &FileSystemResult,
+) -> Option<DateTime<Utc>> {
+    if !blob.dedup {
+        return None;
+    }
+    match db.get_file(&blob.upload.id).await {
+        Ok(f) => f.map(|f| f.created),
+        Err(e) => {
+            warn!("Failed to load deduplicated file: {}", e);
+            None
+        }
+    }
+}
+
+/// Reject blocked file types unless the uploader is allowed to bypass the deny list.
+///
+/// The stored file is removed on rejection unless another upload already references it.
+pub(crate) async fn check_blocked_upload(
+    pubkey: &Vec<u8>,
+    name: Option<&str>,
+    claimed_mime: &str,
+    blob: &FileSystemResult,
+    db: &Database,
+    settings: &Settings,
+) -> Result<(), ApiError> {
+    let reason = match blocklist::check_upload(
+        settings,
+        name,
+        &[claimed_mime, &blob.upload.mime_type],
+        &blob.path,
+    ) {
+        Some(r) => r,
+        None => return Ok(()),
+    };
+    if let Ok(u) = db.get_user(pubkey).await {
+        if u.allow_blocked_types {
+            return Ok(());
+        }
+    }
+    if let Ok(None) = db.get_file(&blob.upload.id).await {
+        let _ = std::fs::remove_file(&blob.path);
+    }
+    Err(ApiError::with_detail(ErrorCode::BlockedFileType, reason))
+}
+
+/// Reject uploads from an origin denied by the operator
+pub(crate) async fn check_denied_origin(
+    db: &Database,
+    origin: UploadOrigin<'_>,
+) -> Result<(), ApiError> {
+    match blocklist::check_origin(db, origin).await {
+        Ok(None) => Ok(()),
+        Ok(Some(reason)) => Err(ApiError::with_detail(ErrorCode::UploadRejected, reason)),
+        Err(e) => Err(ApiError::internal(e.to_string())),
+    }
+}
+
+async fn delete_file(
+    sha256: Result<Sha256Param, ApiError>,
+    pubkey: &PublicKey,
+    fs: &FileStore,
+    db: &Database,
+    settings: &Settings,
+) -> Result<(), ApiError> {
+    let id = sha256?.id;
+    DeletionService::new(db, fs, settings)
+        .delete_as(&id, pubkey)
+        .await?;
+    Ok(())
+}
+
+/// Public server information, shown when no UI is available
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct ServerInfo {
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pubkey: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tos_url: Option<String>,
+    pub public_url: String,
+    pub max_upload_bytes: u64,
+    pub whitelist: bool,
+    pub features: Vec<&'static str>,
+}
+
+impl ServerInfo {
+    pub fn from_settings(settings: &Settings) -> Self {
+        let info = settings.server_info.clone().unwrap_or_default();
+        Self {
+            name: info.name.unwrap_or("route96".to_string()),
+            description: info.description,
+            pubkey: info.pubkey,
+            tos_url: tos_url(settings),
+            public_url: settings.public_url.clone(),
+            max_upload_bytes: settings.max_upload_bytes,
+            whitelist: settings.whitelist.is_some(),
+            features: capabilities::features(),
+        }
+    }
+
+    fn to_html(&self) -> String {
+        let mut rows = vec![
+            ("Public URL", self.public_url.clone()),
+            (
+                "Max upload size",
+                format!("{} bytes", self.max_upload_bytes),
+            ),
+            (
+                "Whitelist",
+                if self.whitelist { "yes" } else { "no" }.to_string(),
+            ),
+            ("Features", self.features.join(", ")),
+        ];
+        if let Some(pk) = &self.pubkey {
+            rows.push(("Operator", pk.clone()));
+        }
+        if let Some(tos) = &self.tos_url {
+            rows.push(("Terms of service", tos.clone()));
+        }
+        let rows: String = rows
+            .iter()
+            .map(|(k, v)| format!("<tr><th>{}</th><td>{}</td></tr>", k, html_escape(v)))
+            .collect();
+        format!(
+            "<!DOCTYPE html><html lang=\"en\"><head><meta charset=\"UTF-8\"><title>{name}</title></head>\
+            <body><h1>{name}</h1><p>{desc}</p><table>{rows}</table></body></html>",
+            name = html_escape(&self.name),
+            desc = html_escape(self.description.as_deref().unwrap_or("")),
+            rows = rows
+        )
+    }
+}
+
+fn html_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+/// Directory the web UI is served from
+pub fn static_dir(settings: &Settings) -> PathBuf {
+    if let Some(dir) = &settings.static_dir {
+        return dir.clone();
+    }
     #[cfg(all(debug_assertions, feature = "react-ui"))]
-    let index = "./ui_src/dist/index.html";
+    let dir = "./ui_src/dist";
     #[cfg(all(not(debug_assertions), feature = "react-ui"))]
-    let index = "./ui/index.html";
+    let dir = "./ui";
     #[cfg(not(feature = "react-ui"))]
-    let index = "./index.html";
-    if let Ok(f) = NamedFile::open(index).await {
-        Ok(f)
+    let dir = ".";
+    PathBuf::from(dir)
+}
+
+#[derive(Responder)]
+pub enum RootResponse {
+    File(NamedFile),
+    Html(RawHtml<String>),
+    Json(Json<ServerInfo>),
+}
+
+#[rocket::get("/")]
+pub async fn root(settings: &State<Settings>, accept: Option<&Accept>) -> RootResponse {
+    let wants_json = accept
+        .map(|a| a.preferred().media_type().is_json())
+        .unwrap_or(false);
+    if !wants_json {
+        if let Ok(f) = NamedFile::open(static_dir(settings).join("index.html")).await {
+            return RootResponse::File(f);
+        }
+    }
+    let info = ServerInfo::from_settings(settings);
+    if wants_json {
+        RootResponse::Json(Json(info))
     } else {
-        Err(Status::InternalServerError)
+        RootResponse::Html(RawHtml(info.to_html()))
     }
 }
 
-#[rocket::get("/<sha256>")]
+/// Why a blob cannot be served
+pub enum BlobUnavailable {
+    NotFound,
+    /// Upload is still in progress, retry after this many seconds
+    InProgress(u64),
+}
+
+impl<'r> Responder<'r, 'static> for BlobUnavailable {
+    fn respond_to(self, _request: &'r Request<'_>) -> rocket::response::Result<'static> {
+        match self {
+            BlobUnavailable::NotFound => Err(Status::NotFound),
+            BlobUnavailable::InProgress(secs) => Response::build()
+                .status(Status::Accepted)
+                .raw_header("retry-after", secs.to_string())
+                .ok(),
+        }
+    }
+}
+
+/// Check if a missing blob is still being uploaded or processed
+async fn blob_in_progress(db: &Database, queue: &ProcessingQueue, id: &Vec<u8>) -> BlobUnavailable {
+    match db.get_upload_state(id).await {
+        Ok(Some(UploadState::Pending)) => BlobUnavailable::InProgress(PENDING_RETRY_AFTER),
+        Ok(Some(UploadState::Processing)) => {
+            BlobUnavailable::InProgress(queue.estimate().as_secs().max(1))
+        }
+        _ => BlobUnavailable::NotFound,
+    }
+}
+
+/// Check if WebP renditions can be served for an image
+fn negotiable_image(info: &FileUpload) -> bool {
+    capabilities::is_enabled(capabilities::IMAGE_NEGOTIATION)
+        && info.mime_type.starts_with("image/")
+        // animations and vectors would lose content
+        && !matches!(
+            info.mime_type.as_str(),
+            "image/webp" | "image/gif" | "image/svg+xml"
+        )
+}
+
+/// WebP rendition of an image when the client explicitly accepts WebP or asked for it with
+/// a .webp extension, queueing one when missing
+#[cfg(feature = "media-compression")]
+async fn negotiated_rendition(
+    db: &Database,
+    fs: &FileStore,
+    settings: &Settings,
+    info: &FileUpload,
+    accept: Option<&Accept>,
+    wants_webp: bool,
+) -> Option<(FileUpload, BlobReader)> {
+    use crate::background::rendition::{wants_watermark, RenditionJob, RENDITION_JOB};
+
+    // wildcards don't count, clients checking the hash of the blob send */*
+    let accepts_webp = wants_webp
+        || accept.is_some_and(|a| {
+            a.iter().any(|m| {
+                let t = m.media_type();
+                t.top() == "image" && t.sub() == "webp" && m.weight_or(1.0) > 0.0
+            })
+        });
+    if !accepts_webp {
+        return None;
+    }
+    let job = RenditionJob {
+        file: info.id.clone(),
+        watermark: wants_watermark(db, settings, &info.id).await,
+    };
+    match db.get_derived_file(&info.id, job.params()).await {
+        Ok(Some(r)) if r.id != info.id => {
+            let f = fs.open(&r.id).await.ok()?;
+            Some((r, f))
+        }
+        Ok(Some(_)) => None,
+        Ok(None) => {
+            let payload = rocket::serde::json::to_string(&job).ok()?;
+            match db.job_exists(RENDITION_JOB, &payload).await {
+                Ok(false) => {
+                    if let Err(e) = db.enqueue_job(RENDITION_JOB, &payload).await {
+                        warn!("Failed to queue rendition: {}", e);
+                    }
+                }
+                Ok(true) => {}
+                Err(e) => warn!("Failed to check rendition jobs: {}", e),
+            }
+            None
+        }
+        Err(e) => {
+            warn!("Failed to load rendition: {}", e);
+            None
+        }
+    }
+}
+
+#[rocket::get("/<sha256>?<download>&<original>&<verify>")]
 pub async fn get_blob(
-    sha256: &str,
+    sha256: Result<Sha256Param, ApiError>,
+    download: Option<bool>,
+    original: Option<bool>,
+    verify: Option<&str>,
+    accept: Option<&Accept>,
+    ip: Option<IpAddr>,
+    _slot: ReadSlot,
+    vanity: VanityHost,
     fs: &State<FileStore>,
     db: &State<Database>,
-) -> Result<FilePayload, Status> {
-    let sha256 = if sha256.contains(".") {
-        sha256.split('.').next().unwrap()
-    } else {
-        sha256
-    };
-    let id = if let Ok(i) = hex::decode(sha256) {
-        i
-    } else {
-        return Err(Status::NotFound);
-    };
-
-    if id.len() != 32 {
-        return Err(Status::NotFound);
+    settings: &State<Settings>,
+    queue: &State<ProcessingQueue>,
+) -> Result<FilePayload, BlobUnavailable> {
+    let param = sha256.map_err(|_| BlobUnavailable::NotFound)?;
+    let id = &param.id;
+    match db.get_file(id).await {
+        Ok(Some(info)) => {
+            if info.quarantined || !vanity.allows(db, id).await {
+                return Err(BlobUnavailable::NotFound);
+            }
+            legal_hold::log_read(db, &info, "download", None, ip).await;
+            let vary_accept = negotiable_image(&info);
+            // accepts verify=1 as well as the usual boolean values
+            let verify = matches!(verify, Some("1" | "true" | "yes" | "on" | ""));
+            // verified downloads are always the stored file the id is the hash of
+            #[cfg(feature = "media-compression")]
+            if vary_accept && !original.unwrap_or(false) && !verify {
+                if let Some((r, f)) =
+                    negotiated_rendition(db, fs, settings, &info, accept, param.wants_webp()).await
+                {
+                    return Ok(FilePayload {
+                        file: f,
+                        info: FileUpload {
+                            name: info.name.clone(),
+                            ..r
+                        },
+                        download: download.unwrap_or(false),
+                        show_name: !settings.hide_file_names.unwrap_or(false),
+                        vary_accept,
+                        verify: false,
+                    });
+                }
+            }
+            #[cfg(not(feature = "media-compression"))]
+            let _ = (original, accept);
+            if let Ok(f) = fs.open(id).await {
+                return Ok(FilePayload {
+                    file: f,
+                    info,
+                    download: download.unwrap_or(false),
+                    show_name: !settings.hide_file_names.unwrap_or(false),
+                    vary_accept: vary_accept && !verify,
+                    verify,
+                });
+            }
+            Err(BlobUnavailable::NotFound)
+        }
+        Ok(None) => Err(blob_in_progress(db, queue, id).await),
+        Err(_) => Err(BlobUnavailable::NotFound),
     }
-    if let Ok(Some(info)) = db.get_file(&id).await {
-        if let Ok(f) = File::open(fs.get(&id)).await {
-            return Ok(FilePayload { file: f, info });
+}
+
+/// Headers of a stored blob, served from the database for HEAD requests
+pub struct BlobHead {
+    info: FileUpload,
+}
+
+impl<'r> Responder<'r, 'static> for BlobHead {
+    fn respond_to(self, _request: &'r Request<'_>) -> rocket::response::Result<'static> {
+        let mut response = Response::new();
+        response.set_header(Header::new("content-length", self.info.size.to_string()));
+        #[cfg(feature = "ranges")]
+        response.set_header(Header::new("accept-ranges", "bytes"));
+        if let Ok(ct) = ContentType::from_str(&self.info.mime_type) {
+            response.set_header(ct);
         }
+        response.set_header(Header::new(
+            "etag",
+            format!("\"{}\"", hex::encode(&self.info.id)),
+        ));
+        response.set_header(Header::new("cache-control", BLOB_CACHE_CONTROL));
+        Ok(response)
     }
-    Err(Status::NotFound)
 }
 
 #[rocket::head("/<sha256>")]
-pub async fn head_blob(sha256: &str, fs: &State<FileStore>) -> Status {
-    let sha256 = if sha256.contains(".") {
-        sha256.split('.').next().unwrap()
-    } else {
-        sha256
-    };
-    let id = if let Ok(i) = hex::decode(sha256) {
-        i
-    } else {
-        return Status::NotFound;
-    };
+pub async fn head_blob(
+    sha256: Result<Sha256Param, ApiError>,
+    ip: Option<IpAddr>,
+    vanity: VanityHost,
+    fs: &State<FileStore>,
+    db: &State<Database>,
+    queue: &State<ProcessingQueue>,
+) -> Result<BlobHead, BlobUnavailable> {
+    let id = sha256.map_err(|_| BlobUnavailable::NotFound)?.id;
+    match db.get_file(&id).await {
+        Ok(Some(info))
+            if !info.quarantined && fs.get(&id).exists() && vanity.allows(db, &id).await =>
+        {
+            legal_hold::log_read(db, &info, "head", None, ip).await;
+            Ok(BlobHead { info })
+        }
+        Ok(None) => Err(blob_in_progress(db, queue, &id).await),
+        _ => Err(BlobUnavailable::NotFound),
+    }
+}
 
-    if id.len() != 32 {
-        return Status::NotFound;
+/// Re-pin an owned blob, replacing its expiry with the retention of the caller's tier
+#[rocket::post("/pin/<sha256>")]
+pub async fn pin_blob(
+    sha256: Result<Sha256Param, ApiError>,
+    auth: Nip98Auth,
+    db: &State<Database>,
+    settings: &State<Settings>,
+) -> Result<Json<Option<i64>>, ApiError> {
+    if !auth.has_scope(ApiKeyScope::Upload) {
+        return Err(ApiError::with_detail(
+            ErrorCode::InvalidAuth,
+            "API key scope missing",
+        ));
     }
-    if fs.get(&id).exists() {
-        Status::Ok
-    } else {
-        Status::NotFound
+    let id = sha256?.id;
+    let pubkey_vec = auth.pubkey.to_bytes().to_vec();
+    let owners = db
+        .get_file_owners(&id)
+        .await
+        .map_err(|e| ApiError::internal(e.to_string()))?;
+    if owners.is_empty() {
+        return Err(ErrorCode::NotFound.into());
+    }
+    if !owners.iter().any(|o| o.pubkey == pubkey_vec) {
+        return Err(ErrorCode::NotOwner.into());
     }
+    let expires = upload_expiry(db, settings, &pubkey_vec).await;
+    db.extend_file_expiry(&id, expires)
+        .await
+        .map_err(|e| ApiError::internal(e.to_string()))?;
+    let file = db
+        .get_file(&id)
+        .await
+        .map_err(|e| ApiError::internal(e.to_string()))?
+        .ok_or(ApiError::new(ErrorCode::NotFound))?;
+    Ok(Json(file.expires.map(|e| e.timestamp())))
+}
+
+/// Metadata of a blob which owners can change after the upload
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct BlobMetadata {
+    /// License of the file, an empty string removes it
+    pub license: Option<String>,
+}
+
+/// Update the metadata of an owned blob, fields which are not set are kept
+#[rocket::patch("/<sha256>", data = "<body>", format = "json")]
+pub async fn update_blob(
+    sha256: Result<Sha256Param, ApiError>,
+    auth: Nip98Auth,
+    body: Vec<u8>,
+    db: &State<Database>,
+) -> Result<Json<BlobMetadata>, ApiError> {
+    if !auth.has_scope(ApiKeyScope::Upload) {
+        return Err(ApiError::with_detail(
+            ErrorCode::InvalidAuth,
+            "API key scope missing",
+        ));
+    }
+    let req: BlobMetadata = auth.parse_json(&body)?;
+    let id = sha256?.id;
+    let pubkey_vec = auth.pubkey.to_bytes().to_vec();
+    let owners = db
+        .get_file_owners(&id)
+        .await
+        .map_err(|e| ApiError::internal(e.to_string()))?;
+    if owners.is_empty() {
+        return Err(ErrorCode::NotFound.into());
+    }
+    if !owners.iter().any(|o| o.pubkey == pubkey_vec) {
+        return Err(ErrorCode::NotOwner.into());
+    }
+    if let Some(l) = &req.license {
+        let license = sanitize_license(l)?;
+        db.set_file_license(&id, license.as_deref())
+            .await
+            .map_err(|e| ApiError::internal(e.to_string()))?;
+    }
+    let file = db
+        .get_file(&id)
+        .await
+        .map_err(|e| ApiError::internal(e.to_string()))?
+        .ok_or(ApiError::new(ErrorCode::NotFound))?;
+    Ok(Json(BlobMetadata {
+        license: file.license,
+    }))
+}
+
+/// A stored file generated from another blob
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct BlobVariant {
+    pub params: String,
+    pub sha256: String,
+    pub url: String,
+    pub size: u64,
+    #[serde(rename = "type")]
+    pub mime_type: String,
+}
+
+/// List the derived files of a blob, each is served by its own hash
+#[rocket::get("/variants/<sha256>")]
+pub async fn list_variants(
+    sha256: Result<Sha256Param, ApiError>,
+    db: &State<Database>,
+    settings: &State<Settings>,
+) -> Result<Json<Vec<BlobVariant>>, ApiError> {
+    let id = sha256?.id;
+    let derivations = db
+        .list_derivations(&id)
+        .await
+        .map_err(|e| ApiError::internal(e.to_string()))?;
+    let mut ret = Vec::new();
+    for d in derivations.into_iter().filter(|d| d.derived != id) {
+        let file = match db.get_file(&d.derived).await {
+            Ok(Some(f)) if !f.quarantined => f,
+            Ok(_) => continue,
+            Err(e) => return Err(ApiError::internal(e.to_string())),
+        };
+        let hex_id = hex::encode(&file.id);
+        ret.push(BlobVariant {
+            params: d.params,
+            url: format!(
+                "{}/{}{}",
+                settings.public_url,
+                hex_id,
+                mime2ext::mime2ext(&file.mime_type)
+                    .map(|m| format!(".{m}"))
+                    .unwrap_or("".to_string())
+            ),
+            sha256: hex_id,
+            size: file.size,
+            mime_type: file.mime_type,
+        });
+    }
+    Ok(Json(ret))
+}
+
+/// Generated v2 torrent for a blob, only while the blob can be downloaded
+#[cfg(feature = "torrent-v2")]
+#[rocket::get("/torrent/<sha256>")]
+pub async fn get_torrent(
+    sha256: Result<Sha256Param, ApiError>,
+    ip: Option<IpAddr>,
+    db: &State<Database>,
+    settings: &State<Settings>,
+) -> Option<(ContentType, NamedFile)> {
+    let id = sha256.ok()?.id;
+    let info = match db.get_file(&id).await {
+        Ok(Some(f)) if !f.quarantined => f,
+        _ => return None,
+    };
+    let file = NamedFile::open(torrent_path(settings, &id)).await.ok()?;
+    legal_hold::log_read(db, &info, "torrent", None, ip).await;
+    Some((ContentType::new("application", "x-bittorrent"), file))
+}
+
+/// Legacy thumbnail url, redirects to the content addressed url of the current thumbnail
+#[cfg(feature = "media-compression")]
+#[rocket::get("/thumb/<sha256>")]
+pub async fn thumb_redirect(
+    sha256: Result<Sha256Param, ApiError>,
+    db: &State<Database>,
+    settings: &State<Settings>,
+) -> Option<Redirect> {
+    let id = sha256.ok()?.id;
+    match db.get_file(&id).await {
+        Ok(Some(f)) if !f.quarantined => {}
+        _ => return None,
+    }
+    let thumb = file_thumbnail(db, &id).await?;
+    // regenerating replaces the thumbnail, only the target may be cached
+    Some(Redirect::temporary(thumb_url(settings, &thumb.id)))
 }
 
 /// Legacy URL redirect for void.cat uploads
@@ -375,3 +1171,91 @@ pub async fn void_cat_redirect(id: &str, settings: &State<Settings>) -> Option<N
         None
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+
+    fn upload() -> FileUpload {
+        FileUpload {
+            id: vec![0xab; 32],
+            name: "cat.png".to_string(),
+            size: 1234,
+            mime_type: "image/png".to_string(),
+            width: Some(640),
+            height: Some(480),
+            ..Default::default()
+        }
+    }
+
+    fn tags_named<'a>(event: &'a Nip94Event, name: &str) -> Vec<&'a Vec<String>> {
+        event.tags.iter().filter(|t| t[0] == name).collect()
+    }
+
+    #[test]
+    fn operator_tags_cannot_override_reserved_tags() {
+        let plain = Nip94Event::from_upload(&Settings::default(), &upload());
+        let mut tags = BTreeMap::new();
+        for (i, t) in NIP94_RESERVED_TAGS.iter().enumerate() {
+            // half collide with a literal value, half with a templated one
+            let v = if i % 2 == 0 {
+                "spoofed".to_string()
+            } else {
+                "{sha256}-{size}-{mime}".to_string()
+            };
+            tags.insert(t.to_string(), v);
+        }
+        let settings = Settings {
+            nip94_tags: Some(tags),
+            ..Default::default()
+        };
+        let event = Nip94Event::from_upload(&settings, &upload());
+        assert_eq!(event.tags, plain.tags);
+        for t in NIP94_RESERVED_TAGS {
+            assert!(tags_named(&event, t).len() <= 1, "{} added twice", t);
+        }
+        assert_eq!(tags_named(&event, "dim")[0][1], "640x480");
+        assert!(tags_named(&event, "expiration").is_empty());
+        assert!(tags_named(&event, "license").is_empty());
+    }
+
+    #[test]
+    fn thumbnail_url_is_content_addressed() {
+        let settings = Settings {
+            public_url: "https://example.com".to_string(),
+            ..Default::default()
+        };
+        let event = Nip94Event::from_upload(&settings, &upload())
+            .with_thumbnail(&settings, Some(&vec![0xcd; 32]));
+        let thumb = tags_named(&event, "thumb");
+        assert_eq!(thumb.len(), 1);
+        assert_eq!(
+            thumb[0][1],
+            format!("https://example.com/{}.webp", hex::encode([0xcd; 32]))
+        );
+        assert_eq!(thumb[0][2], hex::encode([0xcd; 32]));
+        let plain = Nip94Event::from_upload(&settings, &upload()).with_thumbnail(&settings, None);
+        assert!(tags_named(&plain, "thumb").is_empty());
+    }
+
+    #[test]
+    fn operator_tags_are_templated() {
+        let settings = Settings {
+            nip94_tags: Some(BTreeMap::from([
+                ("x".to_string(), "{sha256}".to_string()),
+                ("server".to_string(), "{sha256}/{size}/{mime}".to_string()),
+            ])),
+            ..Default::default()
+        };
+        let event = Nip94Event::from_upload(&settings, &upload());
+        let server = tags_named(&event, "server");
+        assert_eq!(server.len(), 1);
+        assert_eq!(
+            server[0][1],
+            format!("{}/1234/image/png", hex::encode([0xab; 32]))
+        );
+        assert_eq!(tags_named(&event, "x").len(), 1);
+    }
+}
@@ -1,9 +1,11 @@
 use std::collections::HashMap;
 use std::fs;
 use std::ops::Sub;
-use std::time::Duration;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-use log::error;
+use chrono::{DateTime, Utc};
+use log::{error, warn};
 use nostr::Timestamp;
 use rocket::data::ToByteUnit;
 use rocket::form::Form;
@@ -11,14 +13,51 @@ use rocket::fs::TempFile;
 use rocket::serde::json::Json;
 use rocket::serde::Serialize;
 use rocket::{routes, FromForm, Responder, Route, State};
+use tokio::io::AsyncRead;
 
+use crate::auth::api_key::ApiKeyScope;
 use crate::auth::nip98::Nip98Auth;
-use crate::db::{Database, FileUpload};
-use crate::filesystem::FileStore;
-use crate::routes::{delete_file, Nip94Event, PagedResult};
-use crate::settings::Settings;
+use crate::background::disk::DiskState;
+use crate::background::retention::upload_expiry;
+#[cfg(feature = "torrent-v2")]
+use crate::background::torrent::queue_torrent;
+use crate::blocklist::UploadOrigin;
+use crate::db::{Database, FileUpload, ProcessingReport};
+use crate::filesystem::{FileStore, LengthMismatch};
+use crate::hooks;
+use crate::metadata::{clean_text, sanitize_alt, sanitize_license, sanitize_name, ClientMetadata};
+#[cfg(feature = "media-compression")]
+use crate::processing::MediaTooLarge;
+use crate::queue::ProcessingQueue;
+use crate::routes::error::{ApiError, ErrorCode};
+use crate::routes::progress::{ClientHints, UploadSession, UploadStage};
+use crate::routes::{
+    check_blocked_upload, check_denied_origin, check_disk_space, check_quota, check_tos,
+    delete_file, first_uploaded, quota_usage, record_client_hints, tos_url, upload_limits,
+    user_thumbnails, Nip94Event, PagedResult, Sha256Param, UploadLimits, WithQuota,
+};
+use crate::settings::{LengthPolicy, Settings};
+use crate::shed::UploadSlot;
+use crate::upload_limit::UploadPermit;
 use crate::webhook::Webhook;
 
+/// Finished background uploads are kept this long for clients to collect
+const DEFERRED_TTL: Duration = Duration::from_secs(3600);
+
+/// Maximum page size of /n96/events, each file needs a processing report lookup
+const MAX_EVENTS_PAGE: u32 = 100;
+
+/// Unsigned NIP-94 file metadata event (kind 1063), to be signed by the owner
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct Nip94Template {
+    pub kind: u16,
+    pub pubkey: String,
+    pub created_at: i64,
+    pub content: String,
+    pub tags: Vec<Vec<String>>,
+}
+
 #[derive(Serialize, Default)]
 #[serde(crate = "rocket::serde")]
 struct Nip96InfoDoc {
@@ -68,22 +107,42 @@ struct Nip96MediaTransformations {
 
 #[derive(Responder)]
 enum Nip96Response {
-    #[response(status = 500)]
-    GenericError(Json<Nip96UploadResult>),
+    Error(ApiError),
 
     #[response(status = 200)]
     UploadResult(Json<Nip96UploadResult>),
 
+    #[response(status = 200)]
+    Uploaded(WithQuota<Json<Nip96UploadResult>>),
+
+    #[response(status = 202)]
+    Accepted(Json<Nip96UploadResult>),
+
+    #[response(status = 201)]
+    Created(Json<Nip96UploadResult>),
+
+    #[response(status = 200)]
+    Processing(Json<Nip96ProcessingStatus>),
+
     #[response(status = 200)]
     FileList(Json<PagedResult<Nip94Event>>),
+}
 
-    #[response(status = 403)]
-    Forbidden(Json<Nip96UploadResult>),
+impl From<ApiError> for Nip96Response {
+    fn from(e: ApiError) -> Self {
+        Nip96Response::Error(e)
+    }
+}
+
+impl From<ErrorCode> for Nip96Response {
+    fn from(e: ErrorCode) -> Self {
+        Nip96Response::Error(e.into())
+    }
 }
 
 impl Nip96Response {
     pub(crate) fn error(msg: &str) -> Self {
-        Nip96Response::GenericError(Json(Nip96UploadResult::error(msg)))
+        Nip96Response::Error(ApiError::internal(msg))
     }
 
     fn success(msg: &str) -> Self {
@@ -101,6 +160,15 @@ struct Nip96UploadResult {
     pub processing_url: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub nip94_event: Option<Nip94Event>,
+    /// Quota warning for the uploader
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub warning: Option<String>,
+    /// Set on uploads of a file which was already stored
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dedup: Option<bool>,
+    /// Unix timestamp the file was first uploaded, only when deduplicated
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub first_uploaded: Option<u64>,
 }
 
 impl Nip96UploadResult {
@@ -112,15 +180,16 @@ impl Nip96UploadResult {
         }
     }
 
-    pub fn success(msg: &str) -> Self {
-        Nip96UploadResult {
-            status: "error".to_string(),
-            message: Some(msg.to_string()),
-            ..Default::default()
+    fn from_stored(settings: &Settings, stored: &StoredUpload) -> Self {
+        let mut result = Self::from_upload(settings, &stored.upload);
+        if stored.dedup {
+            result.dedup = Some(true);
+            result.first_uploaded = stored.first_uploaded.map(|c| c.timestamp() as u64);
         }
+        result
     }
 
-    pub fn error(msg: &str) -> Self {
+    pub fn success(msg: &str) -> Self {
         Nip96UploadResult {
             status: "error".to_string(),
             message: Some(msg.to_string()),
@@ -138,10 +207,24 @@ struct Nip96Form<'r> {
     caption: Option<&'r str>,
     content_type: Option<&'r str>,
     no_transform: Option<bool>,
+    /// Client computed NIP-94 metadata, see [crate::settings::ClientMetadataPolicy]
+    blurhash: Option<&'r str>,
+    dim: Option<&'r str>,
+    /// License of the file, eg. CC-BY-4.0
+    license: Option<&'r str>,
 }
 
 pub fn nip96_routes() -> Vec<Route> {
-    routes![get_info_doc, upload, delete, list_files]
+    routes![
+        get_info_doc,
+        upload,
+        processing_status,
+        processing_report,
+        delete,
+        list_files,
+        list_events,
+        limits
+    ]
 }
 
 #[rocket::get("/.well-known/nostr/nip96.json")]
@@ -164,6 +247,7 @@ async fn get_info_doc(settings: &State<Settings>) -> Json<Nip96InfoDoc> {
             "audio/*".to_string(),
         ]),
         plans: Some(plans),
+        tos_url: tos_url(settings),
         ..Default::default()
     })
 }
@@ -171,126 +255,444 @@ async fn get_info_doc(settings: &State<Settings>) -> Json<Nip96InfoDoc> {
 #[rocket::post("/n96", data = "<form>")]
 async fn upload(
     auth: Nip98Auth,
+    _slot: UploadSlot,
+    _permit: UploadPermit,
     fs: &State<FileStore>,
     db: &State<Database>,
     settings: &State<Settings>,
     webhook: &State<Option<Webhook>>,
-    form: Form<Nip96Form<'_>>,
+    disk: &State<DiskState>,
+    queue: &State<ProcessingQueue>,
+    deferred: &State<DeferredUploads>,
+    session: UploadSession,
+    mut form: Form<Nip96Form<'_>>,
 ) -> Nip96Response {
+    if !auth.has_scope(ApiKeyScope::Upload) {
+        return ApiError::with_detail(ErrorCode::InvalidAuth, "API key scope missing").into();
+    }
     if let Some(size) = auth.content_length {
         if size > settings.max_upload_bytes {
-            return Nip96Response::error("File too large");
+            return ErrorCode::TooLarge.into();
         }
     }
     if form.size > settings.max_upload_bytes {
-        return Nip96Response::error("File too large");
+        return ErrorCode::TooLarge.into();
+    }
+    if form.file.len() != form.size {
+        let m = LengthMismatch {
+            declared: form.size,
+            received: form.file.len(),
+        };
+        m.record();
+        if settings.upload_length == Some(LengthPolicy::Lenient) {
+            warn!("Upload size mismatch: {}", m);
+        } else {
+            return ApiError::with_detail(ErrorCode::LengthMismatch, m.to_string()).into();
+        }
+    }
+    if let Err(e) = check_disk_space(disk, settings, Some(form.size)) {
+        return e.into();
+    }
+    let pubkey = auth.pubkey.to_bytes().to_vec();
+    if let Err(e) = check_quota(db, settings, &pubkey, Some(form.size)).await {
+        return e.into();
+    }
+    if let Err(e) = check_tos(db, settings, &pubkey).await {
+        return e.into();
     }
-    let file = match form.file.open().await {
-        Ok(f) => f,
-        Err(e) => return Nip96Response::error(&format!("Could not open file: {}", e)),
-    };
     let content_type = form.content_type.unwrap_or("application/octet-stream");
 
     if form.expiration.is_some() {
-        return Nip96Response::error("Expiration not supported");
+        return ApiError::with_detail(ErrorCode::BadRequest, "Expiration not supported").into();
     }
 
     // account for upload speeds as slow as 1MB/s (8 Mbps)
     let mbs = form.size / 1.megabytes().as_u64();
     let max_time = 60.max(mbs);
-    if auth.event.created_at < Timestamp::now().sub(Duration::from_secs(max_time)) {
-        return Nip96Response::error("Auth event timestamp out of range");
+    if let Some(event) = &auth.event {
+        if event.created_at < Timestamp::now().sub(Duration::from_secs(max_time)) {
+            return ApiError::with_detail(
+                ErrorCode::InvalidAuth,
+                "Auth event timestamp out of range",
+            )
+            .into();
+        }
+    }
+
+    // the multipart body is not kept, the payload tag is checked against the file
+    if auth.payload.is_some() {
+        let matches = match form.file.open().await {
+            Ok(f) => auth.check_payload_reader(f).await,
+            Err(e) => Err(e),
+        };
+        match matches {
+            Ok(true) => {}
+            Ok(false) => {
+                return ApiError::with_detail(ErrorCode::InvalidAuth, "Payload hash does not match")
+                    .into()
+            }
+            Err(e) => return Nip96Response::error(&format!("Could not read file: {}", e)),
+        }
     }
 
     // check whitelist
     if let Some(wl) = &settings.whitelist {
-        if !wl.contains(&auth.event.pubkey.to_hex()) {
-            return Nip96Response::Forbidden(Json(Nip96UploadResult::error("Not on whitelist")));
+        if !wl.contains(&auth.pubkey.to_hex()) {
+            return ErrorCode::NotWhitelisted.into();
         }
     }
-    match fs
-        .put(file, content_type, !form.no_transform.unwrap_or(false))
-        .await
-    {
-        Ok(mut blob) => {
-            blob.upload.name = match &form.caption {
-                Some(c) => c.to_string(),
-                None => "".to_string(),
-            };
-            blob.upload.alt = form.alt.as_ref().map(|s| s.to_string());
-            let pubkey_vec = auth.event.pubkey.to_bytes().to_vec();
-            if let Some(wh) = webhook.as_ref() {
-                match wh.store_file(&pubkey_vec, blob.clone()).await {
-                    Ok(store) => {
-                        if !store {
-                            let _ = fs::remove_file(blob.path);
-                            return Nip96Response::error("Upload rejected");
-                        }
-                    }
-                    Err(e) => {
-                        let _ = fs::remove_file(blob.path);
-                        return Nip96Response::error(&format!(
-                            "Internal error, failed to call webhook: {}",
-                            e
-                        ));
+    let origin = UploadOrigin {
+        pubkey: auth.pubkey.as_bytes(),
+        client: session.client().client.as_deref(),
+        url: None,
+    };
+    if let Err(e) = check_denied_origin(db, origin).await {
+        return e.into();
+    }
+    let metadata = match ClientMetadata::parse(settings, form.blurhash, form.dim) {
+        Ok(m) => m,
+        Err(e) => return ApiError::with_detail(ErrorCode::BadRequest, e.to_string()).into(),
+    };
+    let caption = match form.caption.map(|c| sanitize_name(settings, c)).transpose() {
+        Ok(c) => c,
+        Err(e) => return e.into(),
+    };
+    let alt = match form.alt.map(|a| sanitize_alt(settings, a)).transpose() {
+        Ok(a) => a,
+        Err(e) => return e.into(),
+    };
+    let license = match form.license.map(sanitize_license).transpose() {
+        Ok(l) => l.flatten(),
+        Err(e) => return e.into(),
+    };
+    let upload = Nip96Upload {
+        pubkey,
+        content_type: content_type.to_string(),
+        file_name: form
+            .file
+            .raw_name()
+            .map(|n| clean_text(n.dangerous_unsafe_unsanitized_raw().as_str())),
+        caption,
+        alt,
+        license,
+        compress: !form.no_transform.unwrap_or(false),
+        client: session.client().clone(),
+        metadata,
+    };
+
+    // the multipart form is fully received before the handler runs
+    session.set_stage(UploadStage::Processing);
+    let defer_depth = settings.processing.as_ref().and_then(|p| p.defer_depth);
+    if upload.compress && defer_depth.is_some_and(|d| queue.depth() >= d) {
+        let id = uuid::Uuid::new_v4().to_string();
+        let path = std::env::temp_dir().join(format!("n96-{}", id));
+        if let Err(e) = form.file.move_copy_to(&path).await {
+            return Nip96Response::error(&format!("Could not save file: {}", e));
+        }
+        deferred.set(&id, DeferredStatus::Processing);
+        let message = format!(
+            "Processing, estimated {}s",
+            queue.estimate().as_secs().max(1)
+        );
+        let (fs, db, settings_c, webhook, queue, deferred) = (
+            fs.inner().clone(),
+            db.inner().clone(),
+            settings.inner().clone(),
+            webhook.inner().clone(),
+            queue.inner().clone(),
+            deferred.inner().clone(),
+        );
+        let task_id = id.clone();
+        tokio::spawn(async move {
+            let status = match tokio::fs::File::open(&path).await {
+                Ok(f) => {
+                    match store_upload(f, &upload, &fs, &db, &settings_c, webhook.as_ref(), &queue)
+                        .await
+                    {
+                        Ok(u) => DeferredStatus::Done(Box::new(u)),
+                        Err(e) => DeferredStatus::Failed(e),
                     }
                 }
-            }
-            let user_id = match db.upsert_user(&pubkey_vec).await {
-                Ok(u) => u,
-                Err(e) => return Nip96Response::error(&format!("Could not save user: {}", e)),
+                Err(e) => DeferredStatus::Failed(ApiError::internal(format!(
+                    "Could not open file: {}",
+                    e
+                ))),
             };
-            let tmp_file = blob.path.clone();
-            if let Err(e) = db.add_file(&blob.upload, user_id).await {
-                error!("{}", e.to_string());
-                let _ = fs::remove_file(tmp_file);
-                if let Some(dbe) = e.as_database_error() {
-                    if let Some(c) = dbe.code() {
-                        if c == "23000" {
-                            return Nip96Response::error("File already exists");
-                        }
-                    }
-                }
-                return Nip96Response::error(&format!("Could not save file (db): {}", e));
-            }
+            let _ = tokio::fs::remove_file(&path).await;
+            deferred.set(&task_id, status);
+        });
+        // the upload itself is done, processing continues at processing_url
+        session.set_stage(UploadStage::Complete);
+        return Nip96Response::Accepted(Json(Nip96UploadResult {
+            status: "processing".to_string(),
+            message: Some(message),
+            processing_url: Some(format!("{}/n96/processing/{}", settings.public_url, id)),
+            ..Default::default()
+        }));
+    }
 
-            Nip96Response::UploadResult(Json(Nip96UploadResult::from_upload(
-                settings,
-                &blob.upload,
-            )))
+    let file = match form.file.open().await {
+        Ok(f) => f,
+        Err(e) => {
+            return ApiError::with_detail(
+                ErrorCode::BadRequest,
+                format!("Could not open file: {}", e),
+            )
+            .into()
+        }
+    };
+    match store_upload(file, &upload, fs, db, settings, webhook.as_ref(), queue).await {
+        Ok(u) => {
+            session.set_stage(UploadStage::Complete);
+            let quota = quota_usage(db, settings, &upload.pubkey).await.ok();
+            let mut result = Nip96UploadResult::from_stored(settings, &u);
+            result.warning = quota.and_then(|q| q.warning());
+            Nip96Response::Uploaded(WithQuota(Json(result), quota))
+        }
+        Err(e) => e.into(),
+    }
+}
+
+/// Owned details of an upload, so processing can continue after the request ends
+struct Nip96Upload {
+    pubkey: Vec<u8>,
+    content_type: String,
+    file_name: Option<String>,
+    caption: Option<String>,
+    alt: Option<String>,
+    license: Option<String>,
+    compress: bool,
+    client: ClientHints,
+    metadata: Option<ClientMetadata>,
+}
+
+async fn store_upload<S>(
+    stream: S,
+    upload: &Nip96Upload,
+    fs: &FileStore,
+    db: &Database,
+    settings: &Settings,
+    webhook: Option<&Webhook>,
+    queue: &ProcessingQueue,
+) -> Result<StoredUpload, ApiError>
+where
+    S: AsyncRead + Unpin,
+{
+    let pubkey_vec = &upload.pubkey;
+    let stored = if upload.compress {
+        fs.put_queued(stream, &upload.content_type, queue, pubkey_vec, db)
+            .await
+    } else {
+        fs.put(stream, &upload.content_type, false).await
+    };
+    let mut blob = match stored {
+        Ok(b) => b,
+        #[cfg(feature = "media-compression")]
+        Err(e) if MediaTooLarge::is(&e) => {
+            return Err(ApiError::with_detail(ErrorCode::TooLarge, e.to_string()))
         }
         Err(e) => {
             error!("{}", e.to_string());
-            Nip96Response::error(&format!("Could not save file: {}", e))
+            return Err(ApiError::internal(format!("Could not save file: {}", e)));
+        }
+    };
+    check_blocked_upload(
+        pubkey_vec,
+        upload.file_name.as_deref().or(upload.caption.as_deref()),
+        &upload.content_type,
+        &blob,
+        db,
+        settings,
+    )
+    .await?;
+    blob.upload.name = match &upload.caption {
+        Some(c) if !settings.hide_file_names.unwrap_or(false) => c.to_string(),
+        _ => "".to_string(),
+    };
+    blob.upload.alt = upload.alt.clone();
+    blob.upload.license = upload.license.clone();
+    if let Some(m) = &upload.metadata {
+        m.apply(&mut blob.upload);
+    }
+    if let Some(wh) = webhook {
+        match wh.store_file(pubkey_vec, blob.clone()).await {
+            Ok(store) => {
+                if !store {
+                    let _ = fs::remove_file(blob.path);
+                    return Err(ErrorCode::UploadRejected.into());
+                }
+            }
+            Err(e) => {
+                let _ = fs::remove_file(blob.path);
+                return Err(ApiError::internal(format!(
+                    "Internal error, failed to call webhook: {}",
+                    e
+                )));
+            }
+        }
+    }
+    blob.upload.expires = upload_expiry(db, settings, pubkey_vec).await;
+    let first_uploaded = first_uploaded(db, &blob).await;
+    let user_id = db
+        .upsert_user(pubkey_vec)
+        .await
+        .map_err(|e| ApiError::internal(format!("Could not save user: {}", e)))?;
+    let tmp_file = blob.path.clone();
+    if let Err(e) = db.add_file(&blob.upload, user_id).await {
+        error!("{}", e.to_string());
+        let _ = fs::remove_file(tmp_file);
+        if let Some(dbe) = e.as_database_error() {
+            if let Some(c) = dbe.code() {
+                if c == "23000" {
+                    return Err(ErrorCode::FileExists.into());
+                }
+            }
         }
+        return Err(ApiError::internal(format!(
+            "Could not save file (db): {}",
+            e
+        )));
+    }
+    #[cfg(feature = "torrent-v2")]
+    if let Err(e) = queue_torrent(db, settings, &blob.upload).await {
+        log::warn!("Failed to queue torrent: {}", e);
+    }
+    record_client_hints(db, settings, &blob.upload.id, user_id, &upload.client).await;
+    hooks::post_store(settings, &blob.upload, pubkey_vec);
+    Ok(StoredUpload {
+        upload: blob.upload,
+        dedup: blob.dedup,
+        first_uploaded,
+    })
+}
+
+/// Upload saved by [store_upload]
+#[derive(Clone)]
+struct StoredUpload {
+    upload: FileUpload,
+    /// The file was already stored
+    dedup: bool,
+    first_uploaded: Option<DateTime<Utc>>,
+}
+
+/// Result of an upload accepted with 202 and processed in the background
+#[derive(Clone)]
+enum DeferredStatus {
+    Processing,
+    Done(Box<StoredUpload>),
+    Failed(ApiError),
+}
+
+/// Uploads processed in the background, by processing_url id
+#[derive(Clone, Default)]
+pub struct DeferredUploads {
+    inner: Arc<Mutex<HashMap<String, (Instant, DeferredStatus)>>>,
+}
+
+impl DeferredUploads {
+    fn set(&self, id: &str, status: DeferredStatus) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.retain(|_, (t, s)| {
+            matches!(s, DeferredStatus::Processing) || t.elapsed() < DEFERRED_TTL
+        });
+        inner.insert(id.to_string(), (Instant::now(), status));
+    }
+
+    fn get(&self, id: &str) -> Option<DeferredStatus> {
+        self.inner.lock().unwrap().get(id).map(|(_, s)| s.clone())
     }
 }
 
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct Nip96ProcessingStatus {
+    pub status: &'static str,
+    pub message: &'static str,
+}
+
+#[rocket::get("/n96/processing/<id>")]
+async fn processing_status(
+    id: &str,
+    deferred: &State<DeferredUploads>,
+    settings: &State<Settings>,
+) -> Nip96Response {
+    match deferred.get(id) {
+        None => ErrorCode::NotFound.into(),
+        Some(DeferredStatus::Processing) => {
+            Nip96Response::Processing(Json(Nip96ProcessingStatus {
+                status: "processing",
+                message: "Waiting for processing",
+            }))
+        }
+        Some(DeferredStatus::Done(u)) => {
+            Nip96Response::Created(Json(Nip96UploadResult::from_stored(settings, &u)))
+        }
+        Some(DeferredStatus::Failed(e)) => e.into(),
+    }
+}
+
+/// Upload requirements for the authenticated pubkey
+/// How media processing changed an upload, ranked below the deferred status route
+#[rocket::get("/n96/<sha256>/processing", rank = 2)]
+async fn processing_report(
+    sha256: Result<Sha256Param, ApiError>,
+    db: &State<Database>,
+) -> Result<Json<ProcessingReport>, ApiError> {
+    let id = sha256?.id;
+    match db.get_processing_report(&id).await {
+        Ok(Some(r)) => Ok(Json(r)),
+        Ok(None) => Err(ErrorCode::NotFound.into()),
+        Err(e) => Err(ApiError::internal(e.to_string())),
+    }
+}
+
+#[rocket::get("/n96/limits")]
+async fn limits(
+    auth: Nip98Auth,
+    db: &State<Database>,
+    settings: &State<Settings>,
+) -> Result<Json<UploadLimits>, ApiError> {
+    Ok(Json(upload_limits(&auth.pubkey, db, settings).await?))
+}
+
 #[rocket::delete("/n96/<sha256>")]
 async fn delete(
-    sha256: &str,
+    sha256: Result<Sha256Param, ApiError>,
     auth: Nip98Auth,
     fs: &State<FileStore>,
     db: &State<Database>,
+    settings: &State<Settings>,
 ) -> Nip96Response {
-    match delete_file(sha256, &auth.event, fs, db).await {
+    if !auth.has_scope(ApiKeyScope::Delete) {
+        return ApiError::with_detail(ErrorCode::InvalidAuth, "API key scope missing").into();
+    }
+    match delete_file(sha256, &auth.pubkey, fs, db, settings).await {
         Ok(()) => Nip96Response::success("File deleted."),
-        Err(e) => Nip96Response::error(&format!("Failed to delete file: {}", e)),
+        Err(e) => e.into(),
     }
 }
 
-#[rocket::get("/n96?<page>&<count>")]
+#[rocket::get("/n96?<page>&<count>&<license>")]
 async fn list_files(
     auth: Nip98Auth,
     page: u32,
     count: u32,
+    license: Option<&str>,
     db: &State<Database>,
     settings: &State<Settings>,
 ) -> Nip96Response {
-    let pubkey_vec = auth.event.pubkey.to_bytes().to_vec();
+    if !auth.has_scope(ApiKeyScope::List) {
+        return ApiError::with_detail(ErrorCode::InvalidAuth, "API key scope missing").into();
+    }
+    let pubkey_vec = auth.pubkey.to_bytes().to_vec();
     let server_count = count.min(5_000).max(1);
+    let mut collections = match db.list_file_collections(&pubkey_vec).await {
+        Ok(c) => c,
+        Err(e) => return Nip96Response::error(&format!("Could not list files: {}", e)),
+    };
+    let thumbs = user_thumbnails(db, &pubkey_vec).await;
     match db
-        .list_files(&pubkey_vec, page * server_count, server_count)
+        .list_files(&pubkey_vec, license, page * server_count, server_count)
         .await
     {
         Ok((files, total)) => Nip96Response::FileList(Json(PagedResult {
@@ -300,12 +702,61 @@ async fn list_files(
             files: files
                 .iter()
                 .map(|f| {
-                    Nip96UploadResult::from_upload(settings, f)
+                    let mut ev = Nip96UploadResult::from_upload(settings, f)
                         .nip94_event
                         .unwrap()
+                        .with_thumbnail(settings, thumbs.get(&f.id));
+                    ev.collections = collections.remove(&f.id);
+                    ev
                 })
                 .collect(),
         })),
         Err(e) => Nip96Response::error(&format!("Could not list files: {}", e)),
     }
 }
+
+/// NIP-94 event templates for the caller's files, for publishing to relays
+#[rocket::get("/n96/events?<page>&<count>")]
+async fn list_events(
+    auth: Nip98Auth,
+    page: Option<u32>,
+    count: Option<u32>,
+    db: &State<Database>,
+    settings: &State<Settings>,
+) -> Result<Json<PagedResult<Nip94Template>>, ApiError> {
+    if !auth.has_scope(ApiKeyScope::List) {
+        return Err(ApiError::with_detail(
+            ErrorCode::InvalidAuth,
+            "API key scope missing",
+        ));
+    }
+    let pubkey_vec = auth.pubkey.to_bytes().to_vec();
+    let page = page.unwrap_or(0);
+    let server_count = count.unwrap_or(50).clamp(1, MAX_EVENTS_PAGE);
+    let (files, total) = db
+        .list_files(&pubkey_vec, None, page * server_count, server_count)
+        .await
+        .map_err(|e| ApiError::internal(format!("Could not list files: {}", e)))?;
+    let thumbs = user_thumbnails(db, &pubkey_vec).await;
+    let mut events = Vec::with_capacity(files.len());
+    for f in &files {
+        let ev = Nip94Event::from_upload(settings, f).with_thumbnail(settings, thumbs.get(&f.id));
+        let mut tags = ev.tags;
+        if let Some(alt) = f.alt.as_ref().filter(|a| !a.is_empty()) {
+            tags.push(vec!["alt".to_string(), alt.clone()]);
+        }
+        events.push(Nip94Template {
+            kind: 1063,
+            pubkey: auth.pubkey.to_hex(),
+            created_at: ev.created_at,
+            content: ev.content,
+            tags,
+        });
+    }
+    Ok(Json(PagedResult {
+        count: server_count,
+        page,
+        total: total as u32,
+        files: events,
+    }))
+}
@@ -0,0 +1,44 @@
+use rocket::request::FromParam;
+
+use crate::routes::error::{ApiError, ErrorCode};
+
+/// Blob id from a path segment: a hex SHA-256 with an optional extension (eg. `<sha256>.webp`).
+///
+/// Routes take `Result<Sha256Param, ApiError>` to answer invalid ids with their own error
+/// instead of forwarding to the next route
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Sha256Param {
+    pub id: Vec<u8>,
+    /// Extension after the hash, lowercase without the dot
+    pub ext: Option<String>,
+}
+
+impl Sha256Param {
+    pub fn parse(param: &str) -> Result<Self, ApiError> {
+        let (hash, ext) = match param.split_once('.') {
+            Some((h, e)) => (h, Some(e.to_lowercase()).filter(|e| !e.is_empty())),
+            None => (param, None),
+        };
+        match hex::decode(hash) {
+            Ok(id) if id.len() == 32 => Ok(Self { id, ext }),
+            _ => Err(ErrorCode::InvalidFileId.into()),
+        }
+    }
+
+    pub fn hex(&self) -> String {
+        hex::encode(&self.id)
+    }
+
+    /// Image format asked for by the extension, only WebP renditions are generated
+    pub fn wants_webp(&self) -> bool {
+        self.ext.as_deref() == Some("webp")
+    }
+}
+
+impl<'a> FromParam<'a> for Sha256Param {
+    type Error = ApiError;
+
+    fn from_param(param: &'a str) -> Result<Self, Self::Error> {
+        Self::parse(param)
+    }
+}
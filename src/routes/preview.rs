@@ -0,0 +1,229 @@
+use std::net::IpAddr;
+
+use rocket::response::content::RawHtml;
+use rocket::serde::json::Json;
+use rocket::serde::Serialize;
+use rocket::{routes, Route, State};
+
+use crate::db::{Database, FileUpload};
+use crate::legal_hold;
+use crate::routes::error::ApiError;
+use crate::routes::{file_thumbnail, html_escape, thumb_url, ServerInfo, Sha256Param};
+use crate::settings::Settings;
+
+pub fn preview_routes() -> Vec<Route> {
+    routes![oembed, preview_page]
+}
+
+/// oEmbed response, see https://oembed.com
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct OEmbed {
+    pub version: &'static str,
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    pub title: String,
+    pub provider_name: String,
+    pub provider_url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub html: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub width: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub height: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub thumbnail_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub thumbnail_width: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub thumbnail_height: Option<u32>,
+}
+
+/// Direct link to the blob, with an extension so previewers can guess the type
+fn blob_url(settings: &Settings, upload: &FileUpload) -> String {
+    format!(
+        "{}/{}{}",
+        settings.public_url,
+        hex::encode(&upload.id),
+        mime2ext::mime2ext(&upload.mime_type)
+            .map(|m| format!(".{m}"))
+            .unwrap_or("".to_string())
+    )
+}
+
+fn title(settings: &Settings, upload: &FileUpload) -> String {
+    if !upload.name.is_empty() && !settings.hide_file_names.unwrap_or(false) {
+        upload.name.clone()
+    } else {
+        hex::encode(&upload.id)
+    }
+}
+
+async fn load_upload(db: &Database, id: &[u8]) -> Option<FileUpload> {
+    db.get_file(&id.to_vec())
+        .await
+        .ok()
+        .flatten()
+        .filter(|f| !f.quarantined)
+}
+
+#[rocket::get("/oembed?<url>&<format>")]
+async fn oembed(
+    url: &str,
+    format: Option<&str>,
+    ip: Option<IpAddr>,
+    db: &State<Database>,
+    settings: &State<Settings>,
+) -> Option<Json<OEmbed>> {
+    if format.is_some_and(|f| f != "json") {
+        return None;
+    }
+    // accept both direct blob links and preview page links
+    let path = url.split(['?', '#']).next()?;
+    let id = Sha256Param::parse(path.trim_end_matches('/').rsplit('/').next()?)
+        .ok()?
+        .id;
+    let upload = load_upload(db, &id).await?;
+    legal_hold::log_read(db, &upload, "oembed", None, ip).await;
+
+    let src = blob_url(settings, &upload);
+    let info = ServerInfo::from_settings(settings);
+    let mut rsp = OEmbed {
+        version: "1.0",
+        kind: "link",
+        title: title(settings, &upload),
+        provider_name: info.name,
+        provider_url: settings.public_url.clone(),
+        url: None,
+        html: None,
+        width: upload.width,
+        height: upload.height,
+        thumbnail_url: None,
+        thumbnail_width: None,
+        thumbnail_height: None,
+    };
+    if let Some(thumb) = file_thumbnail(db, &upload.id).await {
+        rsp.thumbnail_url = Some(thumb_url(settings, &thumb.id));
+        rsp.thumbnail_width = thumb.width;
+        rsp.thumbnail_height = thumb.height;
+    }
+    if upload.mime_type.starts_with("image/") {
+        rsp.kind = "photo";
+        rsp.url = Some(src.clone());
+        if rsp.thumbnail_url.is_none() {
+            rsp.thumbnail_url = Some(src);
+            rsp.thumbnail_width = upload.width;
+            rsp.thumbnail_height = upload.height;
+        }
+    } else if upload.mime_type.starts_with("video/") {
+        rsp.kind = "video";
+        rsp.html = Some(format!(
+            "<video src=\"{}\" controls{}{}></video>",
+            html_escape(&src),
+            upload
+                .width
+                .map(|w| format!(" width=\"{}\"", w))
+                .unwrap_or_default(),
+            upload
+                .height
+                .map(|h| format!(" height=\"{}\"", h))
+                .unwrap_or_default()
+        ));
+    }
+    Some(Json(rsp))
+}
+
+/// HTML wrapper with OpenGraph tags for rich embeds in chat apps
+#[rocket::get("/p/<sha256>")]
+async fn preview_page(
+    sha256: Result<Sha256Param, ApiError>,
+    ip: Option<IpAddr>,
+    db: &State<Database>,
+    settings: &State<Settings>,
+) -> Option<RawHtml<String>> {
+    let id = sha256.ok()?.id;
+    let upload = load_upload(db, &id).await?;
+    legal_hold::log_read(db, &upload, "preview", None, ip).await;
+
+    let src = blob_url(settings, &upload);
+    let page = format!("{}/p/{}", settings.public_url, hex::encode(&upload.id));
+    let title = title(settings, &upload);
+    let info = ServerInfo::from_settings(settings);
+
+    let mut meta = vec![
+        ("og:site_name".to_string(), info.name),
+        ("og:title".to_string(), title.clone()),
+        ("og:url".to_string(), page.clone()),
+    ];
+    let (og_type, card, media, body) = if upload.mime_type.starts_with("image/") {
+        if let Some(alt) = &upload.alt {
+            meta.push(("og:image:alt".to_string(), alt.clone()));
+        }
+        (
+            "website",
+            "summary_large_image",
+            Some("og:image"),
+            format!(
+                "<img src=\"{}\" alt=\"{}\">",
+                html_escape(&src),
+                html_escape(upload.alt.as_deref().unwrap_or(""))
+            ),
+        )
+    } else if upload.mime_type.starts_with("video/") {
+        (
+            "video.other",
+            "player",
+            Some("og:video"),
+            format!("<video src=\"{}\" controls></video>", html_escape(&src)),
+        )
+    } else {
+        (
+            "website",
+            "summary",
+            None,
+            format!(
+                "<a href=\"{}\">{}</a>",
+                html_escape(&src),
+                html_escape(&title)
+            ),
+        )
+    };
+    if let Some(media) = media {
+        meta.push((media.to_string(), src.clone()));
+        meta.push((format!("{}:type", media), upload.mime_type.clone()));
+        if let (Some(w), Some(h)) = (upload.width, upload.height) {
+            meta.push((format!("{}:width", media), w.to_string()));
+            meta.push((format!("{}:height", media), h.to_string()));
+        }
+    }
+    meta.push(("og:type".to_string(), og_type.to_string()));
+
+    let mut head: String = meta
+        .iter()
+        .map(|(k, v)| format!("<meta property=\"{}\" content=\"{}\">", k, html_escape(v)))
+        .collect();
+    head.push_str(&format!(
+        "<meta name=\"twitter:card\" content=\"{}\">",
+        card
+    ));
+    if let Some(bh) = &upload.blur_hash {
+        head.push_str(&format!(
+            "<meta name=\"blurhash\" content=\"{}\">",
+            html_escape(bh)
+        ));
+    }
+    head.push_str(&format!(
+        "<link rel=\"alternate\" type=\"application/json+oembed\" href=\"{}/oembed?url={}\">",
+        settings.public_url,
+        html_escape(&page)
+    ));
+    Some(RawHtml(format!(
+        "<!DOCTYPE html><html lang=\"en\"><head><meta charset=\"UTF-8\"><title>{}</title>{}</head>\
+        <body>{}</body></html>",
+        html_escape(&title),
+        head,
+        body
+    )))
+}
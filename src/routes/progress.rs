@@ -0,0 +1,268 @@
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use rocket::request::{FromRequest, Outcome};
+use rocket::response::stream::{Event, EventStream};
+use rocket::serde::json::Json;
+use rocket::serde::Serialize;
+use rocket::{async_trait, routes, Request, Route, State};
+use tokio::io::{AsyncRead, ReadBuf};
+
+use crate::auth::AuthClient;
+
+/// Finished sessions are kept this long so clients can read the final state
+const SESSION_TTL: Duration = Duration::from_secs(600);
+
+/// Maximum number of tracked sessions
+const MAX_SESSIONS: usize = 10_000;
+
+pub fn progress_routes() -> Vec<Route> {
+    routes![get_progress, progress_events]
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(crate = "rocket::serde", rename_all = "lowercase")]
+pub enum UploadStage {
+    Receiving,
+    Processing,
+    Complete,
+    Failed,
+}
+
+struct ProgressEntry {
+    received: AtomicU64,
+    total: Option<u64>,
+    state: Mutex<(UploadStage, Instant)>,
+    /// Stage the upload was in when it failed
+    failed_in: Mutex<Option<UploadStage>>,
+}
+
+impl ProgressEntry {
+    fn new(total: Option<u64>) -> Self {
+        Self {
+            received: AtomicU64::new(0),
+            total,
+            state: Mutex::new((UploadStage::Receiving, Instant::now())),
+            failed_in: Mutex::new(None),
+        }
+    }
+
+    fn stage(&self) -> UploadStage {
+        self.state.lock().unwrap().0
+    }
+
+    fn set_stage(&self, stage: UploadStage) {
+        *self.state.lock().unwrap() = (stage, Instant::now());
+    }
+
+    fn fail(&self) {
+        let mut state = self.state.lock().unwrap();
+        if state.0 != UploadStage::Complete && state.0 != UploadStage::Failed {
+            *self.failed_in.lock().unwrap() = Some(state.0);
+            *state = (UploadStage::Failed, Instant::now());
+        }
+    }
+
+    fn status(&self) -> UploadProgress {
+        UploadProgress {
+            stage: self.stage(),
+            received: self.received.load(Ordering::Relaxed),
+            total: self.total,
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct UploadProgress {
+    pub stage: UploadStage,
+    pub received: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total: Option<u64>,
+}
+
+/// Upload sessions by client generated id (X-Upload-Id header)
+#[derive(Default)]
+pub struct ProgressTracker {
+    sessions: Mutex<HashMap<String, Arc<ProgressEntry>>>,
+}
+
+impl ProgressTracker {
+    fn start(&self, id: &str, total: Option<u64>) -> Option<Arc<ProgressEntry>> {
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|_, e| {
+            let (stage, updated) = *e.state.lock().unwrap();
+            !matches!(stage, UploadStage::Complete | UploadStage::Failed)
+                || updated.elapsed() < SESSION_TTL
+        });
+        if sessions.len() >= MAX_SESSIONS {
+            return None;
+        }
+        let entry = Arc::new(ProgressEntry::new(total));
+        sessions.insert(id.to_string(), entry.clone());
+        Some(entry)
+    }
+
+    fn get(&self, id: &str) -> Option<Arc<ProgressEntry>> {
+        self.sessions.lock().unwrap().get(id).cloned()
+    }
+}
+
+/// Maximum length of a stored user-agent
+const MAX_USER_AGENT_LEN: usize = 256;
+
+/// The app an upload was made with, see `client_hints` in the settings
+#[derive(Debug, Clone, Default)]
+pub struct ClientHints {
+    pub user_agent: Option<String>,
+    /// Client named in the auth event
+    pub client: Option<String>,
+}
+
+impl ClientHints {
+    pub fn is_empty(&self) -> bool {
+        self.user_agent.is_none() && self.client.is_none()
+    }
+}
+
+/// Progress tracking for the current upload, only visible to clients which sent an upload id
+#[derive(Default)]
+pub struct UploadSession(Option<Arc<ProgressEntry>>, ClientHints);
+
+impl UploadSession {
+    pub fn client(&self) -> &ClientHints {
+        &self.1
+    }
+
+    /// Count bytes read from the stream, switching to processing at the end of the stream
+    pub fn wrap<S: AsyncRead + Unpin>(&self, inner: S) -> ProgressReader<S> {
+        ProgressReader {
+            inner,
+            entry: self.0.clone(),
+        }
+    }
+
+    pub fn set_stage(&self, stage: UploadStage) {
+        if let Some(e) = &self.0 {
+            e.set_stage(stage);
+        }
+    }
+}
+
+impl Drop for UploadSession {
+    /// Requests which end without completing the upload are marked as failed
+    fn drop(&mut self) {
+        if let Some(e) = &self.0 {
+            e.fail();
+        }
+    }
+}
+
+/// Upload state of the current request, cached by the UploadSession guard for the failure log
+#[derive(Default)]
+pub struct UploadTrace(Option<Arc<ProgressEntry>>);
+
+impl UploadTrace {
+    /// Where a failed upload stopped, None when the request is not an upload
+    pub fn stage(&self) -> Option<&'static str> {
+        let e = self.0.as_ref()?;
+        let stage = e.failed_in.lock().unwrap().unwrap_or(e.stage());
+        Some(match stage {
+            UploadStage::Receiving if e.received.load(Ordering::Relaxed) == 0 => "request",
+            UploadStage::Receiving => "receiving",
+            _ => "processing",
+        })
+    }
+
+    /// Declared size of the upload
+    pub fn total(&self) -> Option<u64> {
+        self.0.as_ref().and_then(|e| e.total)
+    }
+}
+
+#[async_trait]
+impl<'r> FromRequest<'r> for UploadSession {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let total = request
+            .headers()
+            .get_one("x-content-length")
+            .or(request.headers().get_one("content-length"))
+            .and_then(|v| v.parse().ok());
+        // untracked uploads still get an entry so failures can report their stage
+        let entry = match request.headers().get_one("x-upload-id") {
+            Some(id) if !id.is_empty() && id.len() <= 64 => request
+                .rocket()
+                .state::<ProgressTracker>()
+                .and_then(|t| t.start(id, total)),
+            _ => None,
+        }
+        .unwrap_or_else(|| Arc::new(ProgressEntry::new(total)));
+        request.local_cache(|| UploadTrace(Some(entry.clone())));
+        // the auth guard runs first and caches the client tag
+        let client = ClientHints {
+            user_agent: request
+                .headers()
+                .get_one("user-agent")
+                .filter(|u| !u.is_empty())
+                .map(|u| u.chars().take(MAX_USER_AGENT_LEN).collect()),
+            client: request.local_cache(|| AuthClient(None)).0.clone(),
+        };
+        Outcome::Success(UploadSession(Some(entry), client))
+    }
+}
+
+pub struct ProgressReader<S> {
+    inner: S,
+    entry: Option<Arc<ProgressEntry>>,
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for ProgressReader<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let before = buf.filled().len();
+        let res = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let (Poll::Ready(Ok(())), Some(e)) = (&res, &self.entry) {
+            // only count the initial upload, not re-reads of stored data
+            if e.stage() == UploadStage::Receiving {
+                let n = (buf.filled().len() - before) as u64;
+                if n == 0 {
+                    e.set_stage(UploadStage::Processing);
+                } else {
+                    e.received.fetch_add(n, Ordering::Relaxed);
+                }
+            }
+        }
+        res
+    }
+}
+
+#[rocket::get("/upload/progress/<id>")]
+fn get_progress(id: &str, tracker: &State<ProgressTracker>) -> Option<Json<UploadProgress>> {
+    tracker.get(id).map(|e| Json(e.status()))
+}
+
+/// Server-sent events with the upload progress, ends when the upload completes or fails
+#[rocket::get("/upload/progress/<id>/events")]
+fn progress_events(id: &str, tracker: &State<ProgressTracker>) -> Option<EventStream![]> {
+    let entry = tracker.get(id)?;
+    Some(EventStream! {
+        loop {
+            let status = entry.status();
+            let done = matches!(status.stage, UploadStage::Complete | UploadStage::Failed);
+            yield Event::json(&status);
+            if done {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(500)).await;
+        }
+    })
+}
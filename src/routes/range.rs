@@ -0,0 +1,82 @@
+use std::io::{Cursor, SeekFrom};
+use std::ops::Range;
+use std::os::unix::fs::FileExt;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use rocket::futures::{stream, StreamExt, TryStreamExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt};
+use tokio_util::io::{ReaderStream, StreamReader};
+
+use crate::filesystem::BlobReader;
+use crate::settings::Settings;
+
+/// Default bytes read from disk at a time
+const DEFAULT_BUFFER_SIZE: usize = 256 * 1024;
+
+/// Default number of reads running ahead of the client
+const DEFAULT_READAHEAD: usize = 4;
+
+/// Default bytes served for an open ended range
+pub const DEFAULT_MAX_UNBOUNDED_RANGE: u64 = 1024 * 1024;
+
+pub type RangeBody = Pin<Box<dyn AsyncRead + Send>>;
+
+/// Body of a range response.
+///
+/// Plain files are read with positioned reads (pread) of `buffer_size` bytes, keeping up to
+/// `readahead` reads in flight, so concurrent reads never share a seek position.
+/// Encrypted files are decrypted in order and are seeked once.
+pub fn range_body(file: BlobReader, range: Range<u64>, settings: Option<&Settings>) -> RangeBody {
+    let cfg = settings.and_then(|s| s.range_reads.as_ref());
+    let buffer_size = cfg
+        .and_then(|c| c.buffer_size)
+        .unwrap_or(DEFAULT_BUFFER_SIZE);
+    let readahead = cfg
+        .and_then(|c| c.readahead)
+        .unwrap_or(DEFAULT_READAHEAD)
+        .max(1);
+
+    let file = match file {
+        BlobReader::Plain(f) => match f.try_into_std() {
+            Ok(f) => return positioned(Arc::new(f), range, buffer_size, readahead),
+            // an operation is still in flight on the handle
+            Err(f) => BlobReader::Plain(f),
+        },
+        #[cfg(feature = "encryption")]
+        f => f,
+    };
+    let len = range.end - range.start;
+    let body = stream::once(async move {
+        let mut file = file;
+        file.seek(SeekFrom::Start(range.start)).await?;
+        Ok::<_, std::io::Error>(ReaderStream::with_capacity(file.take(len), buffer_size))
+    })
+    .try_flatten();
+    Box::pin(StreamReader::new(body))
+}
+
+fn positioned(
+    file: Arc<std::fs::File>,
+    range: Range<u64>,
+    buffer_size: usize,
+    readahead: usize,
+) -> RangeBody {
+    let end = range.end;
+    let chunks = stream::iter(range.step_by(buffer_size))
+        .map(move |offset| {
+            let file = file.clone();
+            let len = (buffer_size as u64).min(end - offset) as usize;
+            async move {
+                tokio::task::spawn_blocking(move || {
+                    let mut buf = vec![0u8; len];
+                    file.read_exact_at(&mut buf, offset)?;
+                    Ok::<_, std::io::Error>(Cursor::new(buf))
+                })
+                .await
+                .map_err(std::io::Error::other)?
+            }
+        })
+        .buffered(readahead);
+    Box::pin(StreamReader::new(chunks))
+}
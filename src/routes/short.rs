@@ -0,0 +1,219 @@
+use rocket::response::Redirect;
+use rocket::serde::json::Json;
+use rocket::serde::Serialize;
+use rocket::{routes, Route, State};
+use sqlx::Error;
+
+use crate::auth::nip98::Nip98Auth;
+use crate::db::{Database, FileUpload};
+use crate::routes::error::{ApiError, ErrorCode};
+use crate::routes::Sha256Param;
+use crate::settings::Settings;
+
+/// Default shortest sha256 prefix resolved
+const DEFAULT_MIN_PREFIX: usize = 8;
+
+/// Default length of generated codes
+const DEFAULT_CODE_LENGTH: usize = 7;
+
+/// Attempts at generating an unused code
+const MAX_CODE_ATTEMPTS: usize = 5;
+
+/// Lowercase only, codes are compared case-insensitively by the database
+const CODE_ALPHABET: &[u8] = b"0123456789abcdefghijklmnopqrstuvwxyz";
+
+pub fn short_routes() -> Vec<Route> {
+    routes![resolve_short_link, create_short_link]
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct ShortLink {
+    pub code: String,
+    pub url: String,
+}
+
+impl Database {
+    pub async fn get_short_link(&self, code: &str) -> Result<Option<Vec<u8>>, Error> {
+        sqlx::query_scalar("select file from short_links where code = ?")
+            .bind(code)
+            .fetch_optional(&self.pool)
+            .await
+    }
+
+    pub async fn get_file_short_link(&self, file: &Vec<u8>) -> Result<Option<String>, Error> {
+        sqlx::query_scalar("select code from short_links where file = ?")
+            .bind(file)
+            .fetch_optional(&self.pool)
+            .await
+    }
+
+    pub async fn add_short_link(&self, code: &str, file: &Vec<u8>) -> Result<(), Error> {
+        sqlx::query("insert into short_links(code,file) values(?,?)")
+            .bind(code)
+            .bind(file)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn delete_short_link(&self, file: &Vec<u8>) -> Result<(), Error> {
+        sqlx::query("delete from short_links where file = ?")
+            .bind(file)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Up to `limit` file ids in the range [start, end]
+    pub async fn list_files_in_range(
+        &self,
+        start: &Vec<u8>,
+        end: &Vec<u8>,
+        limit: u32,
+    ) -> Result<Vec<Vec<u8>>, Error> {
+        sqlx::query_scalar("select id from uploads where id between ? and ? order by id limit ?")
+            .bind(start)
+            .bind(end)
+            .bind(limit)
+            .fetch_all(&self.pool)
+            .await
+    }
+}
+
+/// Random code which can't be mistaken for a sha256 prefix
+fn generate_code(len: usize) -> String {
+    loop {
+        let bytes: Vec<u8> = (0..len.div_ceil(16))
+            .flat_map(|_| uuid::Uuid::new_v4().into_bytes())
+            .collect();
+        let code: String = bytes[..len]
+            .iter()
+            .map(|b| CODE_ALPHABET[*b as usize % CODE_ALPHABET.len()] as char)
+            .collect();
+        if !code.chars().all(|c| c.is_ascii_hexdigit()) {
+            return code;
+        }
+    }
+}
+
+/// Canonical url of a file
+fn file_url(settings: &Settings, upload: &FileUpload) -> String {
+    format!(
+        "{}/{}{}",
+        settings.public_url,
+        hex::encode(&upload.id),
+        mime2ext::mime2ext(&upload.mime_type)
+            .map(|m| format!(".{m}"))
+            .unwrap_or("".to_string())
+    )
+}
+
+/// Find the file for a generated code or an unambiguous sha256 prefix
+async fn resolve(db: &Database, settings: &Settings, code: &str) -> Result<Vec<u8>, ApiError> {
+    if let Some(f) = db
+        .get_short_link(code)
+        .await
+        .map_err(|e| ApiError::internal(e.to_string()))?
+    {
+        return Ok(f);
+    }
+    let min_prefix = settings
+        .short_links
+        .as_ref()
+        .and_then(|s| s.min_prefix)
+        .unwrap_or(DEFAULT_MIN_PREFIX);
+    let prefix = code.to_lowercase();
+    if prefix.len() < min_prefix
+        || prefix.len() > 64
+        || !prefix.chars().all(|c| c.is_ascii_hexdigit())
+    {
+        return Err(ErrorCode::NotFound.into());
+    }
+    let start = hex::decode(format!("{:0<64}", prefix)).map_err(|_| ErrorCode::InvalidFileId)?;
+    let end = hex::decode(format!("{:f<64}", prefix)).map_err(|_| ErrorCode::InvalidFileId)?;
+    let mut ids = db
+        .list_files_in_range(&start, &end, 2)
+        .await
+        .map_err(|e| ApiError::internal(e.to_string()))?;
+    match ids.len() {
+        0 => Err(ErrorCode::NotFound.into()),
+        1 => Ok(ids.remove(0)),
+        _ => Err(ErrorCode::AmbiguousId.into()),
+    }
+}
+
+/// Redirect a short code or sha256 prefix to the canonical url of the file
+#[rocket::get("/s/<code>")]
+async fn resolve_short_link(
+    code: &str,
+    db: &State<Database>,
+    settings: &State<Settings>,
+) -> Result<Redirect, ApiError> {
+    let code = code.split('.').next().unwrap_or(code);
+    let id = resolve(db, settings, code).await?;
+    match db.get_file(&id).await {
+        // prefixes can become ambiguous, don't let clients cache the redirect
+        Ok(Some(f)) => Ok(Redirect::temporary(file_url(settings, &f))),
+        Ok(None) => Err(ErrorCode::NotFound.into()),
+        Err(e) => Err(ApiError::internal(e.to_string())),
+    }
+}
+
+/// Generate a short code for a file owned by the caller, returns the existing code if there is one
+#[rocket::post("/s/<sha256>")]
+async fn create_short_link(
+    auth: Nip98Auth,
+    sha256: Result<Sha256Param, ApiError>,
+    db: &State<Database>,
+    settings: &State<Settings>,
+) -> Result<Json<ShortLink>, ApiError> {
+    let id = sha256?.id;
+    match db.get_file(&id).await {
+        Ok(Some(_)) => {}
+        Ok(None) => return Err(ErrorCode::NotFound.into()),
+        Err(e) => return Err(ApiError::internal(e.to_string())),
+    }
+    let owners = db
+        .get_file_owners(&id)
+        .await
+        .map_err(|e| ApiError::internal(e.to_string()))?;
+    let pubkey = auth.pubkey.to_bytes().to_vec();
+    if !owners.iter().any(|o| o.pubkey == pubkey) {
+        return Err(ErrorCode::NotOwner.into());
+    }
+
+    let len = settings
+        .short_links
+        .as_ref()
+        .and_then(|s| s.code_length)
+        .unwrap_or(DEFAULT_CODE_LENGTH);
+    let mut attempts = 0;
+    let code = loop {
+        if let Some(c) = db
+            .get_file_short_link(&id)
+            .await
+            .map_err(|e| ApiError::internal(e.to_string()))?
+        {
+            break c;
+        }
+        let code = generate_code(len);
+        match db.add_short_link(&code, &id).await {
+            Ok(()) => break code,
+            // code taken, or another request created a code for this file
+            Err(e)
+                if attempts < MAX_CODE_ATTEMPTS
+                    && e.as_database_error()
+                        .and_then(|d| d.code())
+                        .is_some_and(|c| c == "23000") =>
+            {
+                attempts += 1;
+            }
+            Err(e) => return Err(ApiError::internal(e.to_string())),
+        }
+    };
+    Ok(Json(ShortLink {
+        url: format!("{}/s/{}", settings.public_url, code),
+        code,
+    }))
+}
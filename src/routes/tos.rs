@@ -0,0 +1,228 @@
+use chrono::{DateTime, Utc};
+use nostr::{Event, JsonUtil, Kind, Timestamp};
+use rocket::http::{ContentType, Header};
+use rocket::serde::json::Json;
+use rocket::serde::Serialize;
+use rocket::{routes, Responder, Route, State};
+use sqlx::{Error, FromRow};
+
+use crate::db::Database;
+use crate::routes::error::{ApiError, ErrorCode};
+use crate::settings::{Settings, TosConfig};
+
+/// Kind of the event a pubkey signs to accept the terms of service
+pub const TOS_KIND: u16 = 24243;
+
+/// Default maximum age of an acceptance event in seconds
+const DEFAULT_MAX_AGE: u64 = 600;
+
+pub fn tos_routes() -> Vec<Route> {
+    routes![get_tos, accept_tos, get_tos_status]
+}
+
+/// Version of the terms accepted by a pubkey
+#[derive(Clone, FromRow, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct TosAcceptance {
+    pub version: String,
+    /// Signed acceptance event (json)
+    pub event: String,
+    pub created: DateTime<Utc>,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct TosStatus {
+    /// Current version of the terms
+    pub version: String,
+    /// Whether the current version was accepted
+    pub accepted: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub accepted_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Responder)]
+struct TosDocument {
+    inner: (ContentType, Vec<u8>),
+    version: Header<'static>,
+}
+
+impl Database {
+    /// Record the acceptance of a version, accepting it again keeps the first event
+    pub async fn accept_tos(&self, user_id: u64, version: &str, event: &str) -> Result<(), Error> {
+        sqlx::query("insert ignore into tos_acceptances(user_id,version,event) values(?,?,?)")
+            .bind(user_id)
+            .bind(version)
+            .bind(event)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn get_tos_acceptance(
+        &self,
+        pubkey: &Vec<u8>,
+        version: &str,
+    ) -> Result<Option<TosAcceptance>, Error> {
+        sqlx::query_as(
+            "select t.version, t.event, t.created from tos_acceptances t \
+            join users u on u.id = t.user_id \
+            where u.pubkey = ? and t.version = ?",
+        )
+        .bind(pubkey)
+        .bind(version)
+        .fetch_optional(&self.pool)
+        .await
+    }
+}
+
+/// Public url of the terms of service, the configured server_info.tos_url or the served document
+pub fn tos_url(settings: &Settings) -> Option<String> {
+    settings
+        .server_info
+        .as_ref()
+        .and_then(|i| i.tos_url.clone())
+        .or_else(|| {
+            settings
+                .tos
+                .as_ref()
+                .map(|_| format!("{}/tos", settings.public_url))
+        })
+}
+
+/// Reject uploads from pubkeys which have not accepted the current terms of service
+pub(crate) async fn check_tos(
+    db: &Database,
+    settings: &Settings,
+    pubkey: &Vec<u8>,
+) -> Result<(), ApiError> {
+    let cfg = match &settings.tos {
+        Some(c) => c,
+        None => return Ok(()),
+    };
+    match db.get_tos_acceptance(pubkey, &cfg.version).await {
+        Ok(Some(_)) => Ok(()),
+        Ok(None) => Err(ApiError::with_detail(
+            ErrorCode::TosNotAccepted,
+            format!(
+                "accept version {} at {}/tos",
+                cfg.version, settings.public_url
+            ),
+        )),
+        Err(e) => Err(ApiError::internal(e.to_string())),
+    }
+}
+
+fn find_tag<'a>(event: &'a Event, name: &str) -> Option<&'a String> {
+    event.tags.iter().find_map(|t| match t.as_slice() {
+        [n, v, ..] if n == name => Some(v),
+        _ => None,
+    })
+}
+
+/// Check an acceptance event is signed, recent and for the current version
+fn check_event(cfg: &TosConfig, event: &Event) -> Result<(), ApiError> {
+    if event.kind != Kind::Custom(TOS_KIND) {
+        return Err(ApiError::with_detail(
+            ErrorCode::BadRequest,
+            format!("expected a kind {} event", TOS_KIND),
+        ));
+    }
+    let now = Timestamp::now().as_u64();
+    let max_age = cfg.max_age.unwrap_or(DEFAULT_MAX_AGE);
+    if event.created_at.as_u64() > now || event.created_at.as_u64() + max_age < now {
+        return Err(ApiError::with_detail(
+            ErrorCode::BadRequest,
+            "event created_at is not recent",
+        ));
+    }
+    if find_tag(event, "version") != Some(&cfg.version) {
+        return Err(ApiError::with_detail(
+            ErrorCode::BadRequest,
+            format!("version tag must be {}", cfg.version),
+        ));
+    }
+    if event.verify().is_err() {
+        return Err(ApiError::with_detail(
+            ErrorCode::InvalidAuth,
+            "event signature invalid",
+        ));
+    }
+    Ok(())
+}
+
+#[rocket::get("/tos")]
+async fn get_tos(settings: &State<Settings>) -> Result<TosDocument, ApiError> {
+    let cfg = settings.tos.as_ref().ok_or(ErrorCode::NotFound)?;
+    let body = tokio::fs::read(&cfg.document)
+        .await
+        .map_err(|e| ApiError::internal(format!("Could not read terms of service: {}", e)))?;
+    let content_type = cfg
+        .content_type
+        .as_deref()
+        .and_then(ContentType::parse_flexible)
+        .unwrap_or(ContentType::Plain);
+    Ok(TosDocument {
+        inner: (content_type, body),
+        version: Header::new("x-tos-version", cfg.version.clone()),
+    })
+}
+
+/// Accept the current terms with a signed event, the body is the event json
+#[rocket::post("/tos", data = "<body>")]
+async fn accept_tos(
+    body: &str,
+    db: &State<Database>,
+    settings: &State<Settings>,
+) -> Result<Json<TosStatus>, ApiError> {
+    let cfg = settings.tos.as_ref().ok_or(ErrorCode::NotFound)?;
+    let event = Event::from_json(body)
+        .map_err(|e| ApiError::with_detail(ErrorCode::BadRequest, e.to_string()))?;
+    check_event(cfg, &event)?;
+
+    let pubkey = event.pubkey.to_bytes().to_vec();
+    let user_id = db
+        .upsert_user(&pubkey)
+        .await
+        .map_err(|e| ApiError::internal(e.to_string()))?;
+    db.accept_tos(user_id, &cfg.version, &event.as_json())
+        .await
+        .map_err(|e| ApiError::internal(e.to_string()))?;
+    let accepted = db
+        .get_tos_acceptance(&pubkey, &cfg.version)
+        .await
+        .map_err(|e| ApiError::internal(e.to_string()))?;
+    Ok(Json(TosStatus {
+        version: cfg.version.clone(),
+        accepted: true,
+        accepted_at: accepted.map(|a| a.created),
+    }))
+}
+
+/// Whether a pubkey accepted the current terms
+#[rocket::get("/tos/<pubkey>")]
+async fn get_tos_status(
+    pubkey: &str,
+    db: &State<Database>,
+    settings: &State<Settings>,
+) -> Result<Json<TosStatus>, ApiError> {
+    let cfg = settings.tos.as_ref().ok_or(ErrorCode::NotFound)?;
+    let pubkey = match hex::decode(pubkey) {
+        Ok(p) if p.len() == 32 => p,
+        _ => {
+            return Err(ApiError::with_detail(
+                ErrorCode::BadRequest,
+                "invalid pubkey",
+            ))
+        }
+    };
+    let accepted = db
+        .get_tos_acceptance(&pubkey, &cfg.version)
+        .await
+        .map_err(|e| ApiError::internal(e.to_string()))?;
+    Ok(Json(TosStatus {
+        version: cfg.version.clone(),
+        accepted: accepted.is_some(),
+        accepted_at: accepted.map(|a| a.created),
+    }))
+}
@@ -0,0 +1,181 @@
+use std::path::{Path, PathBuf};
+
+use rocket::catcher::BoxFuture;
+use rocket::fs::NamedFile;
+use rocket::http::uri::fmt::Path as UriPath;
+use rocket::http::uri::Segments;
+use rocket::http::{ContentType, Method, Status};
+use rocket::request::{FromRequest, FromSegments, Outcome};
+use rocket::response::Responder;
+use rocket::{async_trait, routes, Catcher, Request, Response, Route, State};
+
+use crate::routes::error::{ApiError, ErrorCode};
+use crate::routes::static_dir;
+use crate::settings::Settings;
+
+pub fn ui_routes() -> Vec<Route> {
+    routes![ui_files]
+}
+
+/// SPA fallback, single segment paths are routed to blobs first
+pub fn ui_catchers() -> Vec<Catcher> {
+    vec![Catcher::new(404, ui_not_found)]
+}
+
+/// Request details used to pick the UI file to serve
+pub struct UiRequest {
+    accept_encoding: String,
+    wants_html: bool,
+}
+
+impl UiRequest {
+    fn new(request: &Request<'_>) -> Self {
+        Self {
+            accept_encoding: request
+                .headers()
+                .get_one("accept-encoding")
+                .unwrap_or("")
+                .to_lowercase(),
+            wants_html: request
+                .accept()
+                .map(|a| a.preferred().media_type().is_html())
+                .unwrap_or(false),
+        }
+    }
+
+    fn accepts_encoding(&self, enc: &str) -> bool {
+        self.accept_encoding
+            .split(',')
+            .any(|e| e.split(';').next().unwrap_or("").trim() == enc)
+    }
+}
+
+#[async_trait]
+impl<'r> FromRequest<'r> for UiRequest {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(UiRequest::new(request))
+    }
+}
+
+/// A file from the UI directory
+pub struct UiFile {
+    file: NamedFile,
+    content_type: Option<ContentType>,
+    encoding: Option<&'static str>,
+    immutable: bool,
+}
+
+impl<'r> Responder<'r, 'static> for UiFile {
+    fn respond_to(self, request: &'r Request<'_>) -> rocket::response::Result<'static> {
+        let mut response = self.file.respond_to(request)?;
+        if let Some(ct) = self.content_type {
+            response.set_header(ct);
+        }
+        if let Some(enc) = self.encoding {
+            response.set_raw_header("content-encoding", enc);
+        }
+        response.set_raw_header("vary", "accept-encoding");
+        response.set_raw_header(
+            "cache-control",
+            if self.immutable {
+                "public, max-age=31536000, immutable"
+            } else {
+                "no-cache"
+            },
+        );
+        Ok(response)
+    }
+}
+
+/// Check if a file name contains a build hash (eg. index-B1a2c3d4.js)
+fn is_hashed_asset(path: &Path) -> bool {
+    if path.starts_with("assets") {
+        return true;
+    }
+    let stem = match path.file_stem().and_then(|s| s.to_str()) {
+        Some(s) => s,
+        None => return false,
+    };
+    match stem.rsplit(|c| c == '-' || c == '.').next() {
+        Some(hash) if hash.len() >= 8 && hash != stem => {
+            hash.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        }
+        _ => false,
+    }
+}
+
+/// Open a file from the UI directory, using pre-compressed variants when accepted
+async fn open_ui_file(dir: &Path, path: &Path, req: &UiRequest) -> Option<UiFile> {
+    let full_path = dir.join(path);
+    if !full_path.is_file() {
+        return None;
+    }
+    let content_type = full_path
+        .extension()
+        .and_then(|e| e.to_str())
+        .and_then(ContentType::from_extension);
+    let immutable = is_hashed_asset(path);
+    for (enc, ext) in [("br", "br"), ("gzip", "gz")] {
+        if !req.accepts_encoding(enc) {
+            continue;
+        }
+        let mut compressed = full_path.clone().into_os_string();
+        compressed.push(format!(".{}", ext));
+        if let Ok(file) = NamedFile::open(compressed).await {
+            return Some(UiFile {
+                file,
+                content_type,
+                encoding: Some(enc),
+                immutable,
+            });
+        }
+    }
+    let file = NamedFile::open(full_path).await.ok()?;
+    Some(UiFile {
+        file,
+        content_type,
+        encoding: None,
+        immutable,
+    })
+}
+
+/// Serve a UI file, falling back to index.html for html navigation
+async fn serve_ui(settings: &Settings, path: &Path, req: &UiRequest) -> Option<UiFile> {
+    let dir = static_dir(settings);
+    if let Some(f) = open_ui_file(&dir, path, req).await {
+        return Some(f);
+    }
+    if req.wants_html {
+        open_ui_file(&dir, Path::new("index.html"), req).await
+    } else {
+        None
+    }
+}
+
+#[rocket::get("/<path..>", rank = 20)]
+async fn ui_files(path: PathBuf, req: UiRequest, settings: &State<Settings>) -> Option<UiFile> {
+    serve_ui(settings, &path, &req).await
+}
+
+fn ui_not_found<'r>(status: Status, request: &'r Request<'_>) -> BoxFuture<'r> {
+    Box::pin(async move {
+        if request.method() == Method::Get {
+            let segments: Segments<'_, UriPath> = request.uri().path().segments();
+            if let (Ok(path), Some(settings)) = (
+                PathBuf::from_segments(segments),
+                request.rocket().state::<Settings>(),
+            ) {
+                let req = UiRequest::new(request);
+                if let Some(f) = serve_ui(settings, &path, &req).await {
+                    return f.respond_to(request);
+                }
+            }
+        }
+        match ErrorCode::from_status(status) {
+            Some(code) => ApiError::new(code).respond_to(request),
+            None => Response::build().status(status).ok(),
+        }
+    })
+}
@@ -0,0 +1,309 @@
+use std::collections::HashSet;
+use std::io::Cursor;
+use std::net::IpAddr;
+
+use chrono::{DateTime, Datelike, Timelike, Utc};
+use log::warn;
+use rocket::futures::{stream, StreamExt};
+use rocket::http::{ContentType, Header};
+use rocket::response::Responder;
+use rocket::{routes, Request, Response, Route, State};
+use tokio::io::AsyncReadExt;
+use tokio::sync::mpsc;
+use tokio_util::io::StreamReader;
+
+use crate::auth::nip98::Nip98Auth;
+use crate::db::{Database, FileUpload};
+use crate::filesystem::FileStore;
+use crate::legal_hold;
+use crate::routes::error::{ApiError, ErrorCode};
+use crate::routes::Sha256Param;
+use crate::settings::Settings;
+use crate::shed::ReadSlot;
+
+/// Default maximum number of files in an archive
+const DEFAULT_MAX_FILES: usize = 100;
+
+/// Default maximum size of the files in an archive
+const DEFAULT_MAX_BYTES: u64 = 1024 * 1024 * 1024;
+
+/// Archives are written without zip64, sizes and offsets must fit in 32 bits
+pub const ZIP32_MAX_BYTES: u64 = u32::MAX as u64;
+
+const LOCAL_HEADER_LEN: u64 = 30;
+const DATA_DESCRIPTOR_LEN: u64 = 16;
+const CENTRAL_HEADER_LEN: u64 = 46;
+const END_RECORD_LEN: u64 = 22;
+
+/// Sizes and CRC follow the data (bit 3), names are UTF-8 (bit 11)
+const ZIP_FLAGS: u16 = 0x0008 | 0x0800;
+
+/// Chunks buffered between the archive writer and the response
+const CHANNEL_CHUNKS: usize = 4;
+
+pub fn zip_routes() -> Vec<Route> {
+    routes![zip_blobs]
+}
+
+/// Mime type and size of each file in a zip response, read by the analytics fairing
+/// to count the egress of every file
+#[derive(Default, Clone)]
+pub struct ZipEntries(pub Vec<(String, u64)>);
+
+struct ZipEntry {
+    upload: FileUpload,
+    name: String,
+}
+
+struct ZipPayload {
+    body:
+        StreamReader<stream::BoxStream<'static, std::io::Result<Cursor<Vec<u8>>>>, Cursor<Vec<u8>>>,
+    len: u64,
+    entries: ZipEntries,
+}
+
+impl<'r> Responder<'r, 'static> for ZipPayload {
+    fn respond_to(self, request: &'r Request<'_>) -> rocket::response::Result<'static> {
+        request.local_cache(|| self.entries.clone());
+        let mut response = Response::new();
+        response.set_header(ContentType::ZIP);
+        response.set_header(Header::new("content-length", self.len.to_string()));
+        response.set_header(Header::new(
+            "content-disposition",
+            "attachment; filename=\"files.zip\"",
+        ));
+        response.set_streamed_body(self.body);
+        Ok(response)
+    }
+}
+
+/// Download several blobs as one (uncompressed) zip archive, built while it is sent
+#[rocket::post("/zip", data = "<body>", format = "json")]
+async fn zip_blobs(
+    auth: Nip98Auth,
+    _slot: ReadSlot,
+    ip: Option<IpAddr>,
+    fs: &State<FileStore>,
+    db: &State<Database>,
+    settings: &State<Settings>,
+    body: Vec<u8>,
+) -> Result<ZipPayload, ApiError> {
+    let req: Vec<String> = auth.parse_json(&body)?;
+    let max_files = settings
+        .zip
+        .as_ref()
+        .and_then(|z| z.max_files)
+        .unwrap_or(DEFAULT_MAX_FILES);
+    let max_bytes = settings
+        .zip
+        .as_ref()
+        .and_then(|z| z.max_bytes)
+        .unwrap_or(DEFAULT_MAX_BYTES);
+    if req.is_empty() || req.len() > max_files {
+        return Err(ApiError::with_detail(
+            ErrorCode::BadRequest,
+            format!("Archive must contain 1-{} files", max_files),
+        ));
+    }
+
+    let mut seen = HashSet::new();
+    let mut entries = Vec::with_capacity(req.len());
+    let mut total = 0u64;
+    for sha256 in req.iter() {
+        let id = match Sha256Param::parse(sha256) {
+            Ok(p) => p.id,
+            Err(_) => {
+                return Err(ApiError::with_detail(
+                    ErrorCode::InvalidFileId,
+                    sha256.to_string(),
+                ))
+            }
+        };
+        if !seen.insert(id.clone()) {
+            continue;
+        }
+        let upload = match db.get_file(&id).await {
+            Ok(Some(u)) if !u.quarantined => u,
+            Ok(_) => {
+                return Err(ApiError::with_detail(
+                    ErrorCode::NotFound,
+                    sha256.to_string(),
+                ))
+            }
+            Err(e) => return Err(ApiError::internal(e.to_string())),
+        };
+        total += upload.size;
+        if total > max_bytes {
+            return Err(ApiError::with_detail(
+                ErrorCode::TooLarge,
+                format!("Files are larger than {} bytes", max_bytes),
+            ));
+        }
+        let name = format!(
+            "{}{}",
+            hex::encode(&upload.id),
+            mime2ext::mime2ext(&upload.mime_type)
+                .map(|m| format!(".{m}"))
+                .unwrap_or_default()
+        );
+        entries.push(ZipEntry { upload, name });
+    }
+
+    let len = archive_len(&entries);
+    if len > ZIP32_MAX_BYTES {
+        return Err(ApiError::with_detail(
+            ErrorCode::TooLarge,
+            "Archive is too large",
+        ));
+    }
+    let pubkey = auth.pubkey.to_bytes();
+    for e in &entries {
+        legal_hold::log_read(db, &e.upload, "zip", Some(&pubkey), ip).await;
+    }
+    let stats = ZipEntries(
+        entries
+            .iter()
+            .map(|e| (e.upload.mime_type.clone(), e.upload.size))
+            .collect(),
+    );
+
+    let (tx, rx) = mpsc::channel(CHANNEL_CHUNKS);
+    let fs = fs.inner().clone();
+    tokio::spawn(async move {
+        if let Err(e) = write_archive(&fs, &entries, &tx).await {
+            warn!("Failed to write zip archive: {}", e);
+            let _ = tx.send(Err(e)).await;
+        }
+    });
+    let body = stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|chunk| (chunk, rx))
+    });
+    Ok(ZipPayload {
+        body: StreamReader::new(body.boxed()),
+        len,
+        entries: stats,
+    })
+}
+
+/// Exact size of the archive, sent as content-length
+fn archive_len(entries: &[ZipEntry]) -> u64 {
+    entries
+        .iter()
+        .map(|e| {
+            let name = e.name.len() as u64;
+            LOCAL_HEADER_LEN
+                + name
+                + e.upload.size
+                + DATA_DESCRIPTOR_LEN
+                + CENTRAL_HEADER_LEN
+                + name
+        })
+        .sum::<u64>()
+        + END_RECORD_LEN
+}
+
+type ChunkSender = mpsc::Sender<std::io::Result<Cursor<Vec<u8>>>>;
+
+async fn send(tx: &ChunkSender, chunk: Vec<u8>) -> std::io::Result<()> {
+    tx.send(Ok(Cursor::new(chunk)))
+        .await
+        .map_err(|_| std::io::Error::new(std::io::ErrorKind::BrokenPipe, "Client went away"))
+}
+
+async fn write_archive(
+    fs: &FileStore,
+    entries: &[ZipEntry],
+    tx: &ChunkSender,
+) -> std::io::Result<()> {
+    let mut central = Vec::new();
+    let mut offset = 0u64;
+    for e in entries {
+        let (time, date) = dos_time(&e.upload.created);
+        let name = e.name.as_bytes();
+
+        let mut header = Vec::with_capacity(LOCAL_HEADER_LEN as usize + name.len());
+        header.extend(0x04034b50u32.to_le_bytes());
+        header.extend(20u16.to_le_bytes());
+        header.extend(ZIP_FLAGS.to_le_bytes());
+        header.extend(0u16.to_le_bytes()); // stored
+        header.extend(time.to_le_bytes());
+        header.extend(date.to_le_bytes());
+        header.extend([0u8; 12]); // crc and sizes are in the data descriptor
+        header.extend((name.len() as u16).to_le_bytes());
+        header.extend(0u16.to_le_bytes());
+        header.extend(name);
+        let header_offset = offset;
+        offset += header.len() as u64;
+        send(tx, header).await?;
+
+        let mut file = fs.open(&e.upload.id).await?;
+        let mut crc = crc32fast::Hasher::new();
+        let mut size = 0u64;
+        loop {
+            let mut buf = vec![0u8; 64 * 1024];
+            let n = file.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            buf.truncate(n);
+            crc.update(&buf);
+            size += n as u64;
+            send(tx, buf).await?;
+        }
+        // content-length was computed from the database size
+        if size != e.upload.size {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "{} is {} bytes, expected {}",
+                    hex::encode(&e.upload.id),
+                    size,
+                    e.upload.size
+                ),
+            ));
+        }
+        let crc = crc.finalize();
+        offset += size;
+
+        let mut descriptor = Vec::with_capacity(DATA_DESCRIPTOR_LEN as usize);
+        descriptor.extend(0x08074b50u32.to_le_bytes());
+        descriptor.extend(crc.to_le_bytes());
+        descriptor.extend((size as u32).to_le_bytes());
+        descriptor.extend((size as u32).to_le_bytes());
+        offset += descriptor.len() as u64;
+        send(tx, descriptor).await?;
+
+        central.extend(0x02014b50u32.to_le_bytes());
+        central.extend(20u16.to_le_bytes()); // made by
+        central.extend(20u16.to_le_bytes()); // needed
+        central.extend(ZIP_FLAGS.to_le_bytes());
+        central.extend(0u16.to_le_bytes());
+        central.extend(time.to_le_bytes());
+        central.extend(date.to_le_bytes());
+        central.extend(crc.to_le_bytes());
+        central.extend((size as u32).to_le_bytes());
+        central.extend((size as u32).to_le_bytes());
+        central.extend((name.len() as u16).to_le_bytes());
+        central.extend([0u8; 12]); // extra, comment, disk, internal and external attributes
+        central.extend((header_offset as u32).to_le_bytes());
+        central.extend(name);
+    }
+
+    let mut end = Vec::with_capacity(END_RECORD_LEN as usize);
+    end.extend(0x06054b50u32.to_le_bytes());
+    end.extend([0u8; 4]); // disk numbers
+    end.extend((entries.len() as u16).to_le_bytes());
+    end.extend((entries.len() as u16).to_le_bytes());
+    end.extend((central.len() as u32).to_le_bytes());
+    end.extend((offset as u32).to_le_bytes());
+    end.extend(0u16.to_le_bytes());
+    send(tx, central).await?;
+    send(tx, end).await
+}
+
+/// MS-DOS time and date of a timestamp, as used in zip headers
+fn dos_time(t: &DateTime<Utc>) -> (u16, u16) {
+    let time = (t.hour() << 11) | (t.minute() << 5) | (t.second() / 2);
+    let date = ((t.year().clamp(1980, 2107) - 1980) as u32) << 9 | (t.month() << 5) | t.day();
+    (time as u16, date as u16)
+}
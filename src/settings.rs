@@ -1,7 +1,10 @@
+use config::{Config, ConfigError, Environment, File};
+use nostr::serde_json;
 use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct Settings {
     /// Listen addr:port
     pub listen: Option<String>,
@@ -15,9 +18,16 @@ pub struct Settings {
     /// Maximum support filesize for uploading
     pub max_upload_bytes: u64,
 
+    /// Largest accepted JSON request body (mirror, zip, admin), defaults to 256 KiB
+    pub max_json_bytes: Option<u64>,
+
     /// Public facing url
     pub public_url: String,
 
+    /// Other public urls (eg. an onion address), used in responses when the
+    /// request Host matches their host
+    pub public_urls: Option<Vec<String>>,
+
     /// Whitelisted pubkeys
     pub whitelist: Option<Vec<String>>,
 
@@ -30,11 +40,282 @@ pub struct Settings {
     /// Analytics tracking
     pub plausible_url: Option<String>,
 
+    /// Where analytics are sent, defaults to plausible when plausible_url is set
+    pub analytics: Option<AnalyticsSink>,
+
     #[cfg(feature = "void-cat-redirects")]
     pub void_cat_database: Option<String>,
 
     /// Path to void.cat uploads (files-v2)
     pub void_cat_files: Option<PathBuf>,
+
+    /// Restrictions for mirror requests
+    pub mirror: Option<MirrorConfig>,
+
+    /// Directory containing the web UI, server info is shown on / when missing
+    pub static_dir: Option<PathBuf>,
+
+    /// Public information about this server
+    pub server_info: Option<ServerInfoConfig>,
+
+    /// Translated error messages, language => error code => message
+    pub error_messages: Option<HashMap<String, HashMap<String, String>>>,
+
+    /// Periodic integrity checking of stored files
+    pub scrub: Option<ScrubConfig>,
+
+    /// Don't store or serve original file names
+    pub hide_file_names: Option<bool>,
+
+    /// Generate torrents for large files
+    #[cfg(feature = "torrent-v2")]
+    pub torrent: Option<TorrentConfig>,
+
+    /// Other servers holding copies of our files
+    pub replication: Option<ReplicationConfig>,
+
+    /// NIP-98 auth validation
+    pub nip98: Option<Nip98Config>,
+
+    /// Number of used auth events remembered for replay protection, new events are refused
+    /// while it is full of unexpired ones
+    pub replay_cache_size: Option<usize>,
+
+    /// Keep the original upload next to the optimized rendition on /media
+    pub media_keep_original: Option<bool>,
+
+    /// Size limits for media which is decoded (compression, renditions), protects
+    /// against decompression bombs
+    pub media_limits: Option<MediaLimitsConfig>,
+
+    /// Reject uploads by file extension or detected type
+    pub blocked_uploads: Option<BlockConfig>,
+
+    /// Accept API keys (Authorization: Bearer) on upload, list and delete routes
+    pub api_keys: Option<bool>,
+
+    /// Upload expiry per user tier
+    pub retention: Option<RetentionConfig>,
+
+    /// Free space watchdog for storage_dir
+    pub disk: Option<DiskConfig>,
+
+    /// Publish a server announcement to nostr relays
+    pub announce: Option<AnnounceConfig>,
+
+    /// Number of upload failures kept for /account/failures and /admin/failures
+    pub failure_log_size: Option<u64>,
+
+    /// Hardware accelerated media processing
+    pub hwaccel: Option<HwAccelConfig>,
+
+    /// Limits on concurrent media processing
+    pub processing: Option<ProcessingConfig>,
+
+    /// Fetch uploader profiles for moderation
+    pub profiles: Option<ProfilesConfig>,
+
+    /// Storage quota per user tier
+    pub quota: Option<QuotaConfig>,
+
+    /// Programs run after files are stored or deleted
+    pub hooks: Option<HooksConfig>,
+
+    /// Process NIP-09 deletions of file metadata events
+    pub deletions: Option<DeletionsConfig>,
+
+    /// Upload files sent as direct messages to the server key
+    pub bot: Option<BotConfig>,
+
+    /// Serve WebP renditions of images to clients which accept them (media-compression),
+    /// the original is always served with ?original=true
+    pub image_negotiation: Option<bool>,
+
+    /// Extra tags added to all NIP-94 events, values can use {sha256}, {size} and {mime}
+    pub nip94_tags: Option<BTreeMap<String, String>>,
+
+    /// Additional storage roots, new files are stored in the first matching root
+    /// or storage_dir when none match
+    pub storage_roots: Option<Vec<StorageRoot>>,
+
+    /// Record the user-agent and auth event client tag of uploads, for analytics and abuse tracing
+    pub client_hints: Option<bool>,
+
+    /// Short links (/s/<code>) to files by sha256 prefix or generated code
+    pub short_links: Option<ShortLinksConfig>,
+
+    /// Check database rows against stored files in the background after startup
+    pub startup_scan: Option<StartupScanConfig>,
+
+    /// Limits of zip downloads (POST /zip)
+    pub zip: Option<ZipConfig>,
+
+    /// Concurrency ceilings per class of routes, requests over them get 503
+    pub load_shedding: Option<LoadSheddingConfig>,
+
+    /// Per-user subdomains (<name>.<domain>) serving only that user's files
+    pub vanity_hosts: Option<VanityHostsConfig>,
+
+    /// Log levels and outputs, RUST_LOG is used when not set
+    pub logging: Option<LoggingConfig>,
+
+    /// Periodically check that the urls files were mirrored from are still alive
+    pub source_check: Option<SourceCheckConfig>,
+
+    /// Read sizes used when serving range requests
+    pub range_reads: Option<RangeReadsConfig>,
+
+    /// Overlay an image on the WebP renditions of images uploaded by some tiers,
+    /// see image_negotiation
+    pub watermark: Option<WatermarkConfig>,
+
+    /// Lower the WebP quality of compressed images until they fit a size budget
+    pub quality_search: Option<QualitySearchConfig>,
+
+    /// Use the blurhash and dimensions sent by NIP-96 clients, defaults to ignore
+    pub client_metadata: Option<ClientMetadataPolicy>,
+
+    /// Scheduled export of the database tables and a manifest of the stored files
+    pub backup: Option<BackupConfig>,
+
+    /// Length limits of the file names and alt text sent by clients
+    pub metadata_limits: Option<MetadataLimitsConfig>,
+
+    /// Require uploaders to accept the terms of service
+    pub tos: Option<TosConfig>,
+
+    /// How uploads which do not match their declared size are handled, defaults to strict
+    pub upload_length: Option<LengthPolicy>,
+
+    /// Abort uploads and mirror downloads which stall or are too slow
+    pub transfer_limits: Option<TransferLimitsConfig>,
+
+    /// Compress json responses and UI assets for clients which accept gzip or brotli
+    pub compression: Option<CompressionConfig>,
+
+    /// Weekly usage report sent to the operators
+    pub reports: Option<ReportsConfig>,
+
+    /// Concurrent upload ceilings per user and in total, uploads over them get 429
+    pub upload_concurrency: Option<UploadConcurrencyConfig>,
+
+    /// How the `x` tags of Blossom auth events are checked, defaults to lenient
+    pub auth_x_tag: Option<XTagPolicy>,
+
+    /// Periodically probe images and videos stored without dimensions or blurhash
+    pub metadata_backfill: Option<MetadataBackfillConfig>,
+
+    /// Types PUT /media converts and how other types are handled
+    pub media_types: Option<MediaTypesConfig>,
+
+    /// Encrypt stored files at rest
+    #[cfg(feature = "encryption")]
+    pub encryption: Option<EncryptionConfig>,
+
+    /// gRPC admin API
+    #[cfg(feature = "grpc")]
+    pub grpc: Option<GrpcConfig>,
+}
+
+/// Prefix of the environment variables overriding settings, `APP_ENV` selects the overlay
+pub const ENV_PREFIX: &str = "APP";
+
+/// Keys whose values are replaced in [Settings::redacted]
+const SECRET_KEYS: [&str; 7] = [
+    "server_key",
+    "master_key",
+    "previous_keys",
+    "webhook_url",
+    "webhook",
+    "nostr_key",
+    "password",
+];
+
+/// Settings merged from the base file, the environment overlay and environment variables
+pub struct LoadedSettings {
+    pub settings: Settings,
+    /// Environment selected by `APP_ENV`
+    pub env: Option<String>,
+    /// Overlay file of the environment and whether it was found
+    pub overlay: Option<(PathBuf, bool)>,
+}
+
+impl Settings {
+    /// Load the settings in layers, later ones override earlier ones:
+    /// the base file, `<name>.<APP_ENV>.<ext>` next to it when `APP_ENV` is set and
+    /// `APP_` environment variables. Nested keys are separated by a double underscore,
+    /// eg. `APP_MIRROR__TIMEOUT=30` sets `mirror.timeout`
+    pub fn load(path: &str) -> Result<LoadedSettings, ConfigError> {
+        let env = std::env::var(format!("{}_ENV", ENV_PREFIX))
+            .ok()
+            .filter(|e| !e.is_empty());
+        let mut builder = Config::builder().add_source(File::with_name(path));
+        let overlay = env.as_ref().map(|e| {
+            let base = Path::new(path);
+            let stem = base
+                .file_stem()
+                .and_then(|s| s.to_str())
+                .unwrap_or("config");
+            let ext = base.extension().and_then(|s| s.to_str()).unwrap_or("yaml");
+            let overlay = base.with_file_name(format!("{}.{}.{}", stem, e, ext));
+            let exists = overlay.exists();
+            (overlay, exists)
+        });
+        if let Some((o, true)) = &overlay {
+            builder = builder.add_source(File::from(o.as_path()));
+        }
+        let settings = builder
+            .add_source(
+                Environment::with_prefix(ENV_PREFIX)
+                    .prefix_separator("_")
+                    .separator("__"),
+            )
+            .build()?
+            .try_deserialize()?;
+        Ok(LoadedSettings {
+            settings,
+            env,
+            overlay,
+        })
+    }
+
+    /// The settings as json with secrets replaced, for logging.
+    /// Passwords are removed from database urls, keys and the webhook url are hidden
+    pub fn redacted(&self) -> serde_json::Value {
+        let mut v = serde_json::to_value(self).unwrap_or_default();
+        redact(&mut v);
+        v
+    }
+}
+
+fn redact(v: &mut serde_json::Value) {
+    match v {
+        serde_json::Value::Object(m) => {
+            for (k, v) in m.iter_mut() {
+                if v.is_null() {
+                    continue;
+                }
+                if k == "database" || k.ends_with("_database") {
+                    if let serde_json::Value::String(s) = v {
+                        *s = match url::Url::parse(s) {
+                            Ok(mut u) if u.password().is_some() => {
+                                let _ = u.set_password(Some("redacted"));
+                                u.to_string()
+                            }
+                            Ok(_) => s.clone(),
+                            Err(_) => "<redacted>".to_string(),
+                        };
+                    }
+                } else if SECRET_KEYS.contains(&k.as_str()) {
+                    *v = serde_json::Value::String("<redacted>".to_string());
+                } else {
+                    redact(v);
+                }
+            }
+        }
+        serde_json::Value::Array(a) => a.iter_mut().for_each(redact),
+        _ => {}
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -42,3 +323,717 @@ pub struct VitModelConfig {
     pub model: PathBuf,
     pub config: PathBuf,
 }
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct ServerInfoConfig {
+    /// Display name of this server
+    pub name: Option<String>,
+
+    /// Short description of this server
+    pub description: Option<String>,
+
+    /// Operator contact pubkey (hex)
+    pub pubkey: Option<String>,
+
+    /// Terms of service url
+    pub tos_url: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MirrorConfig {
+    /// Only allow mirroring from these domains (and their subdomains)
+    pub allow_domains: Option<Vec<String>>,
+
+    /// Never mirror from these domains (and their subdomains)
+    pub deny_domains: Option<Vec<String>>,
+
+    /// Maximum size of a mirrored file, capped by max_upload_bytes
+    pub max_bytes: Option<u64>,
+
+    /// Timeout in seconds for downloading a mirrored file
+    pub timeout: Option<u64>,
+
+    /// Allow mirroring from private/internal addresses
+    #[serde(default)]
+    pub allow_private: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScrubConfig {
+    /// Seconds between scrub runs, defaults to daily
+    pub interval: Option<u64>,
+
+    /// Number of files checked per run
+    pub batch_size: Option<u32>,
+
+    /// Servers to restore corrupt files from (GET <server>/<sha256>), downloaded with the
+    /// mirror rules, timeout and size limit
+    pub restore_peers: Option<Vec<String>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AnalyticsSink {
+    /// Send events to the plausible instance at plausible_url
+    Plausible,
+    /// Store hourly rollups in the local database
+    Database,
+}
+
+#[cfg(feature = "torrent-v2")]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TorrentConfig {
+    /// Only files of at least this many bytes get a torrent
+    pub min_size: u64,
+
+    /// Torrent piece length in bytes, defaults to 1MiB
+    pub piece_length: Option<u64>,
+
+    /// Tracker announce urls
+    pub trackers: Option<Vec<String>>,
+
+    /// Directory watched by an external seeder, .torrent files and
+    /// links to the data are placed here
+    pub seed_dir: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReplicationConfig {
+    /// Peer server urls
+    pub peers: Vec<String>,
+
+    /// Server nostr secret key (hex/nsec), used to sign requests to peers
+    pub server_key: Option<String>,
+
+    /// Delete files from peers when they are removed locally, needs server_key
+    #[serde(default)]
+    pub propagate_deletes: bool,
+
+    /// Pubkeys (hex) whose deletes remove files completely, instead of only their ownership
+    pub trusted_peers: Option<Vec<String>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct Nip98Config {
+    /// Maximum age of auth events in seconds, defaults to 60
+    pub max_age: Option<u64>,
+
+    /// Require the query string in the u tag to match the request
+    #[serde(default)]
+    pub strict_url: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BlockConfig {
+    /// File extensions to reject, eg. "exe"
+    #[serde(default)]
+    pub extensions: Vec<String>,
+
+    /// Mime types to reject, checked against the claimed and sniffed type
+    #[serde(default)]
+    pub mime_types: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RetentionConfig {
+    /// Days uploads are kept for users without a configured tier, unset keeps forever
+    pub default_days: Option<u32>,
+
+    /// Days uploads are kept per tier, null keeps forever
+    #[serde(default)]
+    pub tiers: HashMap<String, Option<u32>>,
+
+    /// Seconds between expired upload cleanups, defaults to 1 hour
+    pub interval: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DiskConfig {
+    /// Reject uploads and switch to read-only mode below this many free bytes
+    pub min_free_bytes: u64,
+
+    /// Seconds between free space checks, defaults to 30
+    pub interval: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum AnnounceKind {
+    /// NIP-89 handler information (kind 31990)
+    #[default]
+    Handler,
+    /// Blossom server listing (kind 36363)
+    Blossom,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PriceConfig {
+    /// Price amount, eg. "0.05"
+    pub amount: String,
+
+    /// Currency code, eg. "USD" or "sats"
+    pub currency: String,
+
+    /// What the price is charged for, eg. "GB/month"
+    pub unit: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AnnounceConfig {
+    /// Relays the announcement is published to
+    pub relays: Vec<String>,
+
+    /// Server nostr secret key (hex/nsec) used to sign the announcement
+    pub server_key: String,
+
+    /// Event type to publish, defaults to handler
+    pub kind: Option<AnnounceKind>,
+
+    /// Pricing published with the announcement
+    pub pricing: Option<Vec<PriceConfig>>,
+
+    /// Seconds between announcements, defaults to 12 hours
+    pub interval: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HwAccelKind {
+    /// Use the first available device
+    Auto,
+    Vaapi,
+    Nvenc,
+    Videotoolbox,
+    /// Software only
+    None,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HwAccelConfig {
+    /// Hardware backend, falls back to software when the device cannot be opened
+    pub kind: HwAccelKind,
+
+    /// Device to open, eg. /dev/dri/renderD128 for vaapi
+    pub device: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProcessingConfig {
+    /// Uploads processed at the same time, defaults to the number of cpus
+    pub parallel: Option<usize>,
+
+    /// NIP-96 uploads are accepted with 202 and processed in the background
+    /// when this many uploads are waiting
+    pub defer_depth: Option<usize>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProfilesConfig {
+    /// Relays to fetch profiles (kind 0) from
+    pub relays: Vec<String>,
+
+    /// Seconds between refresh runs, defaults to 10 minutes
+    pub interval: Option<u64>,
+
+    /// Seconds before a profile is fetched again, defaults to 1 day
+    pub max_age: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QuotaConfig {
+    /// Bytes stored by users without a configured tier, unset is unlimited
+    pub default_bytes: Option<u64>,
+
+    /// Bytes stored per tier, null is unlimited
+    #[serde(default)]
+    pub tiers: HashMap<String, Option<u64>>,
+
+    /// Upload responses include a warning above this percentage of the quota, defaults to 80
+    pub warn_percent: Option<u8>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HooksConfig {
+    /// Program run after a file is stored, gets a json event on stdin
+    pub post_store: Option<PathBuf>,
+
+    /// Program run after a file is removed from storage, gets a json event on stdin
+    pub post_delete: Option<PathBuf>,
+
+    /// Seconds before a hook program is killed, defaults to 30
+    pub timeout: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeletionsConfig {
+    /// Relays watched for deletion events (kind 5)
+    pub relays: Vec<String>,
+
+    /// Seconds between relay checks, defaults to 60
+    pub interval: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BotConfig {
+    /// Relays the bot listens on for direct messages
+    pub relays: Vec<String>,
+
+    /// Secret key of the bot (nsec or hex)
+    pub server_key: String,
+
+    /// Max size of a base64 encoded file sent in a message, defaults to 64KiB
+    pub max_inline_bytes: Option<usize>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StorageRoot {
+    /// Name recorded for files stored in this root, must not change once used
+    pub name: String,
+
+    /// Directory files are stored in
+    pub path: PathBuf,
+
+    /// Mime type prefixes stored here, eg. "video/"
+    pub mime_types: Option<Vec<String>>,
+
+    /// Only files of at least this size are stored here
+    pub min_size: Option<u64>,
+}
+
+impl StorageRoot {
+    /// Check if a new file should be stored in this root
+    pub fn matches(&self, mime_type: &str, size: u64) -> bool {
+        let mime_ok = match &self.mime_types {
+            Some(m) => m.iter().any(|p| mime_type.starts_with(p.as_str())),
+            None => true,
+        };
+        mime_ok && self.min_size.map(|s| size >= s).unwrap_or(true)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MediaLimitsConfig {
+    /// Maximum width or height in pixels, defaults to 16384
+    pub max_dimension: Option<u32>,
+
+    /// Maximum pixels of a decoded frame (width * height), defaults to 100 million
+    pub max_pixels: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ShortLinksConfig {
+    /// Shortest sha256 prefix (hex characters) resolved, defaults to 8
+    pub min_prefix: Option<usize>,
+
+    /// Length of generated codes, defaults to 7
+    pub code_length: Option<usize>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StartupScanConfig {
+    /// Only check this many random rows / files, the full tree is scanned when not set
+    pub sample: Option<u32>,
+
+    /// Maximum rows / files checked per second
+    pub rate: Option<u32>,
+
+    /// Queue repair jobs for the problems found
+    #[serde(default)]
+    pub fix: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ZipConfig {
+    /// Maximum number of files in an archive, defaults to 100
+    pub max_files: Option<usize>,
+
+    /// Maximum total size of the files in an archive, defaults to 1GiB
+    pub max_bytes: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LoadSheddingConfig {
+    /// Maximum concurrent blob downloads and zip archives
+    pub max_reads: Option<usize>,
+
+    /// Maximum concurrent uploads (PUT /upload, POST /n96)
+    pub max_uploads: Option<usize>,
+
+    /// Maximum concurrent mirror requests (PUT /mirror, PUT /mirror/batch)
+    pub max_mirrors: Option<usize>,
+
+    /// Maximum concurrent media uploads (PUT /media)
+    pub max_media: Option<usize>,
+
+    /// Reject uploads, mirrors and media uploads while this many uploads wait for processing
+    pub max_queue_depth: Option<usize>,
+
+    /// Seconds sent in the Retry-After header of rejected requests, defaults to 5
+    pub retry_after: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VanityHostsConfig {
+    /// Domain the user subdomains are under, eg. media.example for alice.media.example
+    pub domain: String,
+
+    /// Subdomain name => pubkey (hex)
+    pub names: Option<HashMap<String, String>>,
+
+    /// Users with a NIP-05 address verified on this domain get its name as subdomain,
+    /// eg. alice@example.com => alice.media.example
+    pub nip05_domain: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LoggingConfig {
+    /// Default level (error, warn, info, debug, trace, off), defaults to info
+    pub level: Option<String>,
+
+    /// Level per module / log target, eg. rocket: warn
+    pub modules: Option<HashMap<String, String>>,
+
+    /// Log to stderr, defaults to true
+    pub stderr: Option<bool>,
+
+    /// Also log to a file
+    pub file: Option<LogFileConfig>,
+
+    /// Also log to this syslog socket, eg. /dev/log (read by journald on systemd hosts)
+    pub syslog: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LogFileConfig {
+    /// Log file, rotated files get the period appended (route96.log.2025-02-11)
+    pub path: PathBuf,
+
+    /// How often a new file is started, defaults to daily
+    pub rotate: Option<LogRotation>,
+
+    /// Number of rotated files kept, defaults to 7
+    pub keep: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogRotation {
+    Hourly,
+    Daily,
+    Never,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SourceCheckConfig {
+    /// Seconds between runs, defaults to 3600
+    pub interval: Option<u64>,
+
+    /// Seconds after which a source is checked again, defaults to 7 days
+    pub max_age: Option<u64>,
+
+    /// Sources checked per run, defaults to 100
+    pub batch: Option<u32>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RangeReadsConfig {
+    /// Bytes read from disk at a time, defaults to 256 KiB
+    pub buffer_size: Option<usize>,
+
+    /// Reads of a range running ahead of the client, defaults to 4
+    pub readahead: Option<usize>,
+
+    /// Bytes served for an open ended range (bytes=N-), defaults to 1 MiB
+    pub max_unbounded: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WatermarkConfig {
+    /// Overlay image (PNG), its transparency is kept
+    pub image: PathBuf,
+
+    /// Where the overlay is placed, defaults to bottom-right
+    pub position: Option<WatermarkPosition>,
+
+    /// Overlay width as a fraction of the image width, defaults to 0.2
+    pub scale: Option<f32>,
+
+    /// Pixels between the overlay and the edges of the image, defaults to 16
+    pub margin: Option<u32>,
+
+    /// Overlay opacity from 0 to 1, defaults to 1
+    pub opacity: Option<f32>,
+
+    /// Watermark images of users without a configured tier, defaults to true
+    pub default: Option<bool>,
+
+    /// Watermark images per tier
+    #[serde(default)]
+    pub tiers: HashMap<String, bool>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum WatermarkPosition {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+    Center,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QualitySearchConfig {
+    /// Size budgets by image size, the first budget whose max_dimension fits the larger side
+    /// of the image is used. Images larger than every class are encoded with max_quality
+    pub budgets: Vec<SizeBudget>,
+
+    /// Lowest quality tried, the result is kept even when it is over budget, defaults to 30
+    pub min_quality: Option<u8>,
+
+    /// Highest quality tried, defaults to 90
+    pub max_quality: Option<u8>,
+
+    /// Most encodes per image, defaults to 6
+    pub max_attempts: Option<u32>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SizeBudget {
+    /// Largest width or height in pixels of this class
+    pub max_dimension: u32,
+
+    /// Largest encoded size in bytes
+    pub max_bytes: u64,
+}
+
+impl QualitySearchConfig {
+    /// Size budget for an image
+    pub fn budget(&self, width: usize, height: usize) -> Option<u64> {
+        let size = width.max(height) as u64;
+        self.budgets
+            .iter()
+            .filter(|b| size <= b.max_dimension as u64)
+            .min_by_key(|b| b.max_dimension)
+            .map(|b| b.max_bytes)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ClientMetadataPolicy {
+    /// Only metadata computed by the server is stored
+    Ignore,
+    /// Client metadata is validated and stored for fields the server could not compute
+    Fallback,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LengthPolicy {
+    /// Mismatches are logged and counted, the upload is stored as received
+    Lenient,
+    /// Uploads must match the declared size, they are aborted as soon as they go over it
+    Strict,
+    /// Like strict, and Blossom uploads without a size tag or Content-Length are rejected
+    Require,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum XTagPolicy {
+    /// Uploaded and deleted blobs must match an `x` tag when the auth event has any
+    Lenient,
+    /// Like lenient, and auth events of uploads and deletes without an `x` tag are rejected
+    Strict,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TransferLimitsConfig {
+    /// Limits for every path without its own
+    pub default: Option<TransferLimits>,
+
+    /// Blossom PUT /upload
+    pub upload: Option<TransferLimits>,
+
+    /// Blossom PUT /media
+    pub media: Option<TransferLimits>,
+
+    /// Downloads of mirrored files
+    pub mirror: Option<TransferLimits>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TransferLimits {
+    /// Seconds without receiving any bytes before the transfer is aborted, defaults to 60
+    pub read_timeout: Option<u64>,
+
+    /// Minimum rate in KB/s, off when not set
+    pub min_rate: Option<u64>,
+
+    /// Seconds the minimum rate is measured over, defaults to 30
+    pub rate_window: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CompressionConfig {
+    /// Smallest response body compressed in bytes, defaults to 1024
+    pub min_size: Option<usize>,
+
+    /// Largest response body compressed in bytes, defaults to 8MiB
+    pub max_size: Option<usize>,
+
+    /// Content types compressed, defaults to json, javascript, css, html, plain text, xml and svg
+    pub content_types: Option<Vec<String>>,
+
+    /// Offer brotli as well as gzip, defaults to true
+    pub brotli: Option<bool>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReportsConfig {
+    /// Url the report is posted to as json
+    pub webhook: Option<String>,
+
+    /// Send the report by email
+    pub smtp: Option<SmtpConfig>,
+
+    /// Operator pubkeys (npub or hex) sent the report as a NIP-17 direct message
+    pub nostr_pubkeys: Option<Vec<String>>,
+
+    /// Secret key the direct messages are sent from (nsec or hex), defaults to the bot key
+    pub nostr_key: Option<String>,
+
+    /// Relays the direct messages are sent to, defaults to the bot relays
+    pub relays: Option<Vec<String>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SmtpConfig {
+    /// SMTP server, connected to with TLS (or STARTTLS on port 587)
+    pub host: String,
+
+    /// Defaults to 465
+    pub port: Option<u16>,
+
+    pub username: Option<String>,
+
+    pub password: Option<String>,
+
+    /// Sender address, eg. "route96 <reports@example.com>"
+    pub from: String,
+
+    /// Recipient addresses
+    pub to: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UploadConcurrencyConfig {
+    /// Maximum uploads one pubkey can have in progress (PUT /upload, PUT /media, POST /n96)
+    pub max_per_user: Option<usize>,
+
+    /// Maximum uploads in progress across every user
+    pub max_total: Option<usize>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MetadataBackfillConfig {
+    /// Seconds between runs, defaults to 3600
+    pub interval: Option<u64>,
+
+    /// Reprocess jobs kept queued at once, defaults to 100
+    pub batch: Option<u32>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MediaTypesConfig {
+    /// Input types converted to WebP, defaults to jpeg, png, gif, bmp and tiff images
+    pub supported: Option<Vec<String>>,
+
+    /// How uploads of other types are handled, defaults to store
+    pub unsupported: Option<UnsupportedMediaPolicy>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum UnsupportedMediaPolicy {
+    /// Stored as uploaded, like PUT /upload
+    Store,
+    /// Rejected with 415 listing the supported types
+    Reject,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BackupConfig {
+    /// Directory each backup is written to, in a sub directory named by its start time
+    pub dir: PathBuf,
+
+    /// Seconds between backups, defaults to daily
+    pub interval: Option<u64>,
+
+    /// Number of backups kept, defaults to 7
+    pub keep: Option<usize>,
+
+    /// Tables exported, defaults to users, uploads and user_uploads
+    pub tables: Option<Vec<String>>,
+
+    /// Program run with the path of each finished backup, eg. to copy it to S3
+    pub command: Option<PathBuf>,
+
+    /// Seconds the command may run, defaults to 3600
+    pub command_timeout: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MetadataLimitsConfig {
+    /// Longest file name in characters, defaults to 255
+    pub max_name_length: Option<usize>,
+
+    /// Longest alt text in characters, defaults to 512
+    pub max_alt_length: Option<usize>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TosConfig {
+    /// File with the terms of service, served at /tos
+    pub document: PathBuf,
+
+    /// Version of the terms, each pubkey must accept the current version before uploading
+    pub version: String,
+
+    /// Content type of the document, defaults to text/plain
+    pub content_type: Option<String>,
+
+    /// Maximum age of an acceptance event in seconds, defaults to 600
+    pub max_age: Option<u64>,
+}
+
+#[cfg(feature = "encryption")]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EncryptionConfig {
+    /// Master key (32 bytes hex) which wraps the key of each file
+    pub master_key: Option<String>,
+
+    /// Command printing the master key (hex) on stdout, eg. a KMS client, instead of master_key
+    pub key_command: Option<PathBuf>,
+
+    /// Previous master keys (hex), files wrapped with these are still readable until
+    /// `r96util rotate-key` re-wraps them with the current key
+    pub previous_keys: Option<Vec<String>>,
+}
+
+#[cfg(feature = "grpc")]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GrpcConfig {
+    /// Listen addr:port
+    pub listen: String,
+
+    /// Server certificate (PEM)
+    pub cert: PathBuf,
+
+    /// Server private key (PEM)
+    pub key: PathBuf,
+
+    /// CA which signs client certificates (PEM), every client it signed has admin access
+    pub client_ca: PathBuf,
+}
@@ -0,0 +1,218 @@
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome};
+use rocket::{async_trait, Request};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::queue::ProcessingQueue;
+use crate::routes::error::{ApiError, ErrorCode};
+use crate::settings::Settings;
+
+/// Default seconds clients are asked to wait after being shed
+pub const DEFAULT_RETRY_AFTER: u64 = 5;
+
+/// Groups of routes with their own concurrency limit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RouteClass {
+    /// Blob downloads and zip archives
+    Read,
+    /// PUT /upload and POST /n96
+    Upload,
+    /// PUT /mirror and mirror batches
+    Mirror,
+    /// PUT /media, which transcodes before responding
+    Media,
+}
+
+impl RouteClass {
+    pub const ALL: [RouteClass; 4] = [
+        RouteClass::Read,
+        RouteClass::Upload,
+        RouteClass::Mirror,
+        RouteClass::Media,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RouteClass::Read => "read",
+            RouteClass::Upload => "upload",
+            RouteClass::Mirror => "mirror",
+            RouteClass::Media => "media",
+        }
+    }
+
+    fn index(&self) -> usize {
+        *self as usize
+    }
+}
+
+#[derive(Default)]
+struct ClassState {
+    max: Option<usize>,
+    limit: Option<Arc<Semaphore>>,
+    in_flight: AtomicU64,
+    rejected: AtomicU64,
+}
+
+/// Rejects requests with 503 once a class of routes has too many requests running,
+/// so slow uploads and transcodes cannot starve downloads of workers.
+///
+/// Rocket has one worker pool, each class gets a separate ceiling within it.
+#[derive(Clone)]
+pub struct LoadShedder {
+    classes: Arc<[ClassState; 4]>,
+    max_queue_depth: Option<usize>,
+}
+
+impl LoadShedder {
+    pub fn new(settings: &Settings) -> Self {
+        let cfg = settings.load_shedding.as_ref();
+        let class = |max: Option<usize>| ClassState {
+            max,
+            limit: max.map(|n| Arc::new(Semaphore::new(n))),
+            ..Default::default()
+        };
+        let classes = [
+            class(cfg.and_then(|c| c.max_reads)),
+            class(cfg.and_then(|c| c.max_uploads)),
+            class(cfg.and_then(|c| c.max_mirrors)),
+            class(cfg.and_then(|c| c.max_media)),
+        ];
+        Self {
+            classes: Arc::new(classes),
+            max_queue_depth: cfg.and_then(|c| c.max_queue_depth),
+        }
+    }
+
+    /// Take a slot for a request, None when the class is saturated
+    pub fn try_acquire(&self, class: RouteClass, queue: Option<&ProcessingQueue>) -> Option<Slot> {
+        let state = &self.classes[class.index()];
+        let queue_full = class != RouteClass::Read
+            && matches!((self.max_queue_depth, queue), (Some(max), Some(q)) if q.depth() >= max);
+        let permit = if queue_full {
+            None
+        } else {
+            match &state.limit {
+                Some(s) => s.clone().try_acquire_owned().ok().map(Some),
+                None => Some(None),
+            }
+        };
+        match permit {
+            Some(permit) => {
+                state.in_flight.fetch_add(1, Ordering::Relaxed);
+                Some(Slot {
+                    shedder: self.clone(),
+                    class,
+                    _permit: permit,
+                })
+            }
+            None => {
+                state.rejected.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    /// Requests of the class currently running
+    pub fn in_flight(&self, class: RouteClass) -> u64 {
+        self.classes[class.index()]
+            .in_flight
+            .load(Ordering::Relaxed)
+    }
+
+    /// Requests of the class rejected since startup
+    pub fn rejected(&self, class: RouteClass) -> u64 {
+        self.classes[class.index()].rejected.load(Ordering::Relaxed)
+    }
+
+    /// Configured ceiling of the class
+    pub fn limit(&self, class: RouteClass) -> Option<usize> {
+        self.classes[class.index()].max
+    }
+}
+
+/// A running request of a class, released when dropped
+pub struct Slot {
+    shedder: LoadShedder,
+    class: RouteClass,
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.shedder.classes[self.class.index()]
+            .in_flight
+            .fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Marker for the route class taken by a [ClassSlot] guard
+pub trait SlotClass: Send + Sync + 'static {
+    const CLASS: RouteClass;
+}
+
+pub struct Read;
+pub struct Upload;
+pub struct Mirror;
+pub struct Media;
+
+impl SlotClass for Read {
+    const CLASS: RouteClass = RouteClass::Read;
+}
+impl SlotClass for Upload {
+    const CLASS: RouteClass = RouteClass::Upload;
+}
+impl SlotClass for Mirror {
+    const CLASS: RouteClass = RouteClass::Mirror;
+}
+impl SlotClass for Media {
+    const CLASS: RouteClass = RouteClass::Media;
+}
+
+/// Request guard holding a slot of its class until the response is returned
+pub struct ClassSlot<C: SlotClass> {
+    _slot: Option<Slot>,
+    _class: PhantomData<C>,
+}
+
+pub type ReadSlot = ClassSlot<Read>;
+pub type UploadSlot = ClassSlot<Upload>;
+pub type MirrorSlot = ClassSlot<Mirror>;
+pub type MediaSlot = ClassSlot<Media>;
+
+#[async_trait]
+impl<'r, C: SlotClass> FromRequest<'r> for ClassSlot<C> {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let shedder = match request.rocket().state::<LoadShedder>() {
+            Some(s) => s,
+            None => {
+                return Outcome::Success(ClassSlot {
+                    _slot: None,
+                    _class: PhantomData,
+                })
+            }
+        };
+        let queue = request.rocket().state::<ProcessingQueue>();
+        match shedder.try_acquire(C::CLASS, queue) {
+            Some(slot) => Outcome::Success(ClassSlot {
+                _slot: Some(slot),
+                _class: PhantomData,
+            }),
+            None => {
+                // rendered by the error catcher, which adds Retry-After
+                request.local_cache(|| {
+                    Some(ApiError::with_detail(
+                        ErrorCode::Overloaded,
+                        format!("Too many {} requests", C::CLASS.as_str()),
+                    ))
+                });
+                Outcome::Error((Status::ServiceUnavailable, ()))
+            }
+        }
+    }
+}
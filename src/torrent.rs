@@ -0,0 +1,231 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use anyhow::Error;
+use sha2::{Digest, Sha256};
+use tokio::fs::File;
+use tokio::io::AsyncReadExt;
+
+/// BitTorrent v2 merkle tree block size (BEP-52)
+const BLOCK_SIZE: usize = 16 * 1024;
+
+/// Default piece length, 1MiB
+pub const DEFAULT_PIECE_LENGTH: u64 = 1024 * 1024;
+
+/// Bencoded value
+pub enum BValue {
+    Int(i64),
+    Bytes(Vec<u8>),
+    List(Vec<BValue>),
+    Dict(BTreeMap<Vec<u8>, BValue>),
+}
+
+impl BValue {
+    fn str(s: &str) -> Self {
+        BValue::Bytes(s.as_bytes().to_vec())
+    }
+
+    fn dict<const N: usize>(entries: [(&str, BValue); N]) -> Self {
+        BValue::Dict(
+            entries
+                .into_iter()
+                .map(|(k, v)| (k.as_bytes().to_vec(), v))
+                .collect(),
+        )
+    }
+
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            BValue::Int(i) => out.extend(format!("i{}e", i).as_bytes()),
+            BValue::Bytes(b) => {
+                out.extend(format!("{}:", b.len()).as_bytes());
+                out.extend(b);
+            }
+            BValue::List(l) => {
+                out.push(b'l');
+                for v in l {
+                    v.encode(out);
+                }
+                out.push(b'e');
+            }
+            BValue::Dict(d) => {
+                // BTreeMap keeps keys sorted as required by bencode
+                out.push(b'd');
+                for (k, v) in d {
+                    BValue::Bytes(k.clone()).encode(out);
+                    v.encode(out);
+                }
+                out.push(b'e');
+            }
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.encode(&mut out);
+        out
+    }
+}
+
+/// A v2-only single file torrent
+pub struct Torrent {
+    /// SHA-256 of the bencoded info dict
+    pub info_hash: Vec<u8>,
+    /// Complete bencoded .torrent file
+    pub data: Vec<u8>,
+}
+
+/// Hash pairs of nodes until a single root remains, padding with `pad`
+fn merkle_root(mut layer: Vec<[u8; 32]>, mut pad: [u8; 32]) -> [u8; 32] {
+    if layer.is_empty() {
+        return pad;
+    }
+    while layer.len() > 1 {
+        if layer.len() % 2 == 1 {
+            layer.push(pad);
+        }
+        layer = layer
+            .chunks(2)
+            .map(|p| {
+                let mut h = Sha256::new();
+                h.update(p[0]);
+                h.update(p[1]);
+                h.finalize().into()
+            })
+            .collect();
+        let mut h = Sha256::new();
+        h.update(pad);
+        h.update(pad);
+        pad = h.finalize().into();
+    }
+    layer[0]
+}
+
+/// Build a BEP-52 torrent for a stored file.
+///
+/// `web_seed` is added as a BEP-19 url-list entry so clients can always fetch
+/// the data over http, even when no peers are seeding.
+pub async fn create_torrent(
+    path: &Path,
+    name: &str,
+    piece_length: u64,
+    trackers: &[String],
+    web_seed: &str,
+) -> Result<Torrent, Error> {
+    let piece_length = piece_length.max(BLOCK_SIZE as u64).next_power_of_two();
+    let blocks_per_piece = (piece_length as usize) / BLOCK_SIZE;
+
+    let mut file = File::open(path).await?;
+    let length = file.metadata().await?.len();
+
+    // hash every 16KiB block, grouping them into pieces
+    let mut piece_hashes: Vec<[u8; 32]> = Vec::new();
+    let mut blocks: Vec<[u8; 32]> = Vec::with_capacity(blocks_per_piece);
+    let mut buf = vec![0u8; BLOCK_SIZE];
+    loop {
+        let mut n = 0;
+        while n < BLOCK_SIZE {
+            let r = file.read(&mut buf[n..]).await?;
+            if r == 0 {
+                break;
+            }
+            n += r;
+        }
+        if n == 0 {
+            break;
+        }
+        blocks.push(Sha256::digest(&buf[..n]).into());
+        if blocks.len() == blocks_per_piece {
+            piece_hashes.push(merkle_root(std::mem::take(&mut blocks), [0u8; 32]));
+        }
+        if n < BLOCK_SIZE {
+            break;
+        }
+    }
+    if !blocks.is_empty() {
+        // in multi-piece files the last piece is padded with zero leaves up to a full piece
+        if !piece_hashes.is_empty() {
+            blocks.resize(blocks_per_piece, [0u8; 32]);
+        }
+        piece_hashes.push(merkle_root(blocks, [0u8; 32]));
+    }
+
+    let pieces_root = if length <= piece_length {
+        piece_hashes.first().copied()
+    } else {
+        // remaining levels are padded with the hash of an all-zero piece
+        Some(merkle_root(
+            piece_hashes.clone(),
+            merkle_root(vec![[0u8; 32]; blocks_per_piece], [0u8; 32]),
+        ))
+    };
+
+    let mut file_entry = BTreeMap::new();
+    file_entry.insert(b"length".to_vec(), BValue::Int(length as i64));
+    if let Some(root) = pieces_root {
+        file_entry.insert(b"pieces root".to_vec(), BValue::Bytes(root.to_vec()));
+    }
+    let info = BValue::dict([
+        (
+            "file tree",
+            BValue::dict([(name, BValue::dict([("", BValue::Dict(file_entry))]))]),
+        ),
+        ("meta version", BValue::Int(2)),
+        ("name", BValue::str(name)),
+        ("piece length", BValue::Int(piece_length as i64)),
+    ]);
+    let info_bytes = info.to_bytes();
+    let info_hash = Sha256::digest(&info_bytes).to_vec();
+
+    let mut torrent = BTreeMap::new();
+    torrent.insert(b"info".to_vec(), info);
+    torrent.insert(
+        b"url-list".to_vec(),
+        BValue::List(vec![BValue::str(web_seed)]),
+    );
+    if let Some(t) = trackers.first() {
+        torrent.insert(b"announce".to_vec(), BValue::str(t));
+        torrent.insert(
+            b"announce-list".to_vec(),
+            BValue::List(
+                trackers
+                    .iter()
+                    .map(|t| BValue::List(vec![BValue::str(t)]))
+                    .collect(),
+            ),
+        );
+    }
+    if let (Some(root), true) = (pieces_root, length > piece_length) {
+        let layer: Vec<u8> = piece_hashes.iter().flatten().copied().collect();
+        let mut layers = BTreeMap::new();
+        layers.insert(root.to_vec(), BValue::Bytes(layer));
+        torrent.insert(b"piece layers".to_vec(), BValue::Dict(layers));
+    }
+
+    Ok(Torrent {
+        info_hash,
+        data: BValue::Dict(torrent).to_bytes(),
+    })
+}
+
+/// Build a magnet link for a v2 info hash
+pub fn magnet_link(info_hash: &[u8], name: &str, web_seed: &str, torrent_url: &str) -> String {
+    format!(
+        "magnet:?xt=urn:btmh:1220{}&dn={}&ws={}&xs={}",
+        hex::encode(info_hash),
+        urlencode(name),
+        urlencode(web_seed),
+        urlencode(torrent_url)
+    )
+}
+
+fn urlencode(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
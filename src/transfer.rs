@@ -0,0 +1,248 @@
+use std::fmt::{Display, Formatter};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use anyhow::Error;
+use log::warn;
+use tokio::io::{AsyncRead, ReadBuf};
+use tokio::time::{Instant, Sleep};
+
+use crate::settings::{Settings, TransferLimits};
+
+/// Default seconds without receiving any bytes before a transfer is aborted
+const DEFAULT_READ_TIMEOUT: u64 = 60;
+
+/// Default seconds the minimum rate is measured over
+const DEFAULT_RATE_WINDOW: u64 = 30;
+
+/// Streaming path a transfer limit applies to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransferKind {
+    /// Blossom PUT /upload
+    Upload,
+    /// Blossom PUT /media
+    Media,
+    /// Downloads of mirrored files
+    Mirror,
+}
+
+impl TransferKind {
+    pub const ALL: [TransferKind; 3] = [
+        TransferKind::Upload,
+        TransferKind::Media,
+        TransferKind::Mirror,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TransferKind::Upload => "upload",
+            TransferKind::Media => "media",
+            TransferKind::Mirror => "mirror",
+        }
+    }
+}
+
+/// Why a slow transfer was aborted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SlowReason {
+    /// No bytes were received for the read timeout
+    Timeout,
+    /// Less than the minimum rate was received over the rate window
+    Rate,
+}
+
+/// Transfer aborted for being too slow
+#[derive(Debug, Clone, Copy)]
+pub struct SlowTransfer {
+    pub kind: TransferKind,
+    pub reason: SlowReason,
+    /// Read timeout or rate window in seconds
+    pub seconds: u64,
+    /// Minimum rate in KB/s
+    pub min_rate: u64,
+}
+
+impl Display for SlowTransfer {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.reason {
+            SlowReason::Timeout => write!(
+                f,
+                "{} received no data for {}s",
+                self.kind.as_str(),
+                self.seconds
+            ),
+            SlowReason::Rate => write!(
+                f,
+                "{} was slower than {} KB/s over {}s",
+                self.kind.as_str(),
+                self.min_rate,
+                self.seconds
+            ),
+        }
+    }
+}
+
+impl std::error::Error for SlowTransfer {}
+
+impl SlowTransfer {
+    /// Find the [SlowTransfer] which made a transfer fail
+    pub fn find(e: &Error) -> Option<SlowTransfer> {
+        e.chain().find_map(|c| {
+            c.downcast_ref::<SlowTransfer>().copied().or_else(|| {
+                c.downcast_ref::<std::io::Error>()
+                    .and_then(|e| e.get_ref())
+                    .and_then(|e| e.downcast_ref::<SlowTransfer>())
+                    .copied()
+            })
+        })
+    }
+}
+
+/// Slow transfers aborted by path
+pub struct SlowTransferStats {
+    /// Aborted after the read timeout
+    pub timeout: AtomicU64,
+    /// Aborted for being under the minimum rate
+    pub rate: AtomicU64,
+}
+
+impl SlowTransferStats {
+    const fn new() -> Self {
+        Self {
+            timeout: AtomicU64::new(0),
+            rate: AtomicU64::new(0),
+        }
+    }
+}
+
+static STATS: [SlowTransferStats; 3] = [
+    SlowTransferStats::new(),
+    SlowTransferStats::new(),
+    SlowTransferStats::new(),
+];
+
+pub fn stats(kind: TransferKind) -> &'static SlowTransferStats {
+    &STATS[kind as usize]
+}
+
+/// Limits of a path with the defaults applied
+#[derive(Debug, Clone, Copy)]
+struct Limits {
+    read_timeout: Duration,
+    /// Bytes per second
+    min_rate: Option<u64>,
+    rate_window: Duration,
+}
+
+fn limits(settings: &Settings, kind: TransferKind) -> Option<Limits> {
+    let cfg = settings.transfer_limits.as_ref()?;
+    let route = match kind {
+        TransferKind::Upload => cfg.upload.as_ref(),
+        TransferKind::Media => cfg.media.as_ref(),
+        TransferKind::Mirror => cfg.mirror.as_ref(),
+    };
+    let get = |f: fn(&TransferLimits) -> Option<u64>| {
+        route
+            .and_then(f)
+            .or_else(|| cfg.default.as_ref().and_then(f))
+    };
+    Some(Limits {
+        read_timeout: Duration::from_secs(get(|l| l.read_timeout).unwrap_or(DEFAULT_READ_TIMEOUT)),
+        min_rate: get(|l| l.min_rate).filter(|r| *r > 0).map(|r| r * 1024),
+        rate_window: Duration::from_secs(get(|l| l.rate_window).unwrap_or(DEFAULT_RATE_WINDOW)),
+    })
+}
+
+/// Aborts a transfer which receives nothing for the read timeout, or less than the minimum
+/// rate over the rate window. Passes the stream through when transfer_limits is not configured
+pub struct TransferGuard<R> {
+    inner: R,
+    kind: TransferKind,
+    limits: Option<Limits>,
+    idle: Option<Pin<Box<Sleep>>>,
+    window_start: Instant,
+    window_bytes: u64,
+}
+
+impl<R> TransferGuard<R> {
+    pub fn new(inner: R, settings: &Settings, kind: TransferKind) -> Self {
+        let limits = limits(settings, kind);
+        Self {
+            inner,
+            kind,
+            limits,
+            idle: limits.map(|l| Box::pin(tokio::time::sleep(l.read_timeout))),
+            window_start: Instant::now(),
+            window_bytes: 0,
+        }
+    }
+
+    fn abort(&self, limits: &Limits, reason: SlowReason) -> std::io::Error {
+        let s = stats(self.kind);
+        let (counter, seconds) = match reason {
+            SlowReason::Timeout => (&s.timeout, limits.read_timeout.as_secs()),
+            SlowReason::Rate => (&s.rate, limits.rate_window.as_secs()),
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        let e = SlowTransfer {
+            kind: self.kind,
+            reason,
+            seconds,
+            min_rate: limits.min_rate.unwrap_or(0) / 1024,
+        };
+        warn!("Aborted slow transfer: {}", e);
+        std::io::Error::new(std::io::ErrorKind::TimedOut, e)
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for TransferGuard<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = &mut *self;
+        let limits = match this.limits {
+            Some(l) => l,
+            None => return Pin::new(&mut this.inner).poll_read(cx, buf),
+        };
+        let before = buf.filled().len();
+        match Pin::new(&mut this.inner).poll_read(cx, buf) {
+            Poll::Pending => {
+                if let Some(idle) = &mut this.idle {
+                    if idle.as_mut().poll(cx).is_ready() {
+                        return Poll::Ready(Err(this.abort(&limits, SlowReason::Timeout)));
+                    }
+                }
+                Poll::Pending
+            }
+            Poll::Ready(Ok(())) => {
+                let n = (buf.filled().len() - before) as u64;
+                // the end of the stream is never too slow
+                if n == 0 {
+                    return Poll::Ready(Ok(()));
+                }
+                let now = Instant::now();
+                if let Some(idle) = &mut this.idle {
+                    idle.as_mut().reset(now + limits.read_timeout);
+                }
+                if let Some(rate) = limits.min_rate {
+                    this.window_bytes += n;
+                    let elapsed = now - this.window_start;
+                    if elapsed >= limits.rate_window {
+                        if this.window_bytes < rate * elapsed.as_secs() {
+                            return Poll::Ready(Err(this.abort(&limits, SlowReason::Rate)));
+                        }
+                        this.window_start = now;
+                        this.window_bytes = 0;
+                    }
+                }
+                Poll::Ready(Ok(()))
+            }
+            r => r,
+        }
+    }
+}
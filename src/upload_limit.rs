@@ -0,0 +1,184 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use log::warn;
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome};
+use rocket::{async_trait, Request};
+
+use crate::auth::AuthPubkey;
+use crate::routes::error::{ApiError, ErrorCode};
+use crate::settings::Settings;
+
+/// Ceiling an upload was rejected by
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UploadScope {
+    /// Uploads of one pubkey
+    User,
+    /// Uploads of every user
+    Global,
+}
+
+impl UploadScope {
+    pub const ALL: [UploadScope; 2] = [UploadScope::User, UploadScope::Global];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            UploadScope::User => "user",
+            UploadScope::Global => "global",
+        }
+    }
+}
+
+#[derive(Default)]
+struct ActiveState {
+    total: usize,
+    by_user: HashMap<Vec<u8>, usize>,
+}
+
+struct LimiterState {
+    max_per_user: Option<usize>,
+    max_total: Option<usize>,
+    active: Mutex<ActiveState>,
+    rejected: [AtomicU64; 2],
+}
+
+/// Counts uploads in progress per pubkey and in total, new uploads over the ceilings
+/// are rejected with 429 so one key cannot fill the disk with parallel uploads.
+///
+/// Uploads are always counted for the metrics, even without configured ceilings.
+#[derive(Clone)]
+pub struct UploadLimiter {
+    inner: Arc<LimiterState>,
+}
+
+impl UploadLimiter {
+    pub fn new(settings: &Settings) -> Self {
+        let cfg = settings.upload_concurrency.as_ref();
+        Self {
+            inner: Arc::new(LimiterState {
+                max_per_user: cfg.and_then(|c| c.max_per_user),
+                max_total: cfg.and_then(|c| c.max_total),
+                active: Mutex::new(ActiveState::default()),
+                rejected: [AtomicU64::new(0), AtomicU64::new(0)],
+            }),
+        }
+    }
+
+    /// Start an upload for a pubkey, returns the ceiling it is over when rejected
+    pub fn try_acquire(&self, pubkey: &[u8]) -> Result<ActiveUpload, UploadScope> {
+        let mut active = self.inner.active.lock().unwrap();
+        let user = active.by_user.get(pubkey).copied().unwrap_or(0);
+        let over = if self.inner.max_total.is_some_and(|m| active.total >= m) {
+            Some(UploadScope::Global)
+        } else if self.inner.max_per_user.is_some_and(|m| user >= m) {
+            Some(UploadScope::User)
+        } else {
+            None
+        };
+        if let Some(scope) = over {
+            self.inner.rejected[scope as usize].fetch_add(1, Ordering::Relaxed);
+            return Err(scope);
+        }
+        active.total += 1;
+        *active.by_user.entry(pubkey.to_vec()).or_insert(0) += 1;
+        Ok(ActiveUpload {
+            limiter: self.clone(),
+            pubkey: pubkey.to_vec(),
+        })
+    }
+
+    fn release(&self, pubkey: &[u8]) {
+        let mut active = self.inner.active.lock().unwrap();
+        active.total = active.total.saturating_sub(1);
+        match active.by_user.get_mut(pubkey) {
+            Some(n) if *n > 1 => *n -= 1,
+            Some(_) => {
+                active.by_user.remove(pubkey);
+            }
+            None => warn!(
+                "Released an upload of {} which was not active",
+                hex::encode(pubkey)
+            ),
+        }
+    }
+
+    /// Uploads currently in progress
+    pub fn active(&self) -> usize {
+        self.inner.active.lock().unwrap().total
+    }
+
+    /// Users with at least one upload in progress
+    pub fn active_users(&self) -> usize {
+        self.inner.active.lock().unwrap().by_user.len()
+    }
+
+    /// Configured ceiling of the scope
+    pub fn limit(&self, scope: UploadScope) -> Option<usize> {
+        match scope {
+            UploadScope::User => self.inner.max_per_user,
+            UploadScope::Global => self.inner.max_total,
+        }
+    }
+
+    /// Uploads rejected by the scope since startup
+    pub fn rejected(&self, scope: UploadScope) -> u64 {
+        self.inner.rejected[scope as usize].load(Ordering::Relaxed)
+    }
+}
+
+/// An upload in progress, released when dropped. The handler future is dropped when the
+/// client disconnects, so aborted uploads do not keep their slot
+pub struct ActiveUpload {
+    limiter: UploadLimiter,
+    pubkey: Vec<u8>,
+}
+
+impl Drop for ActiveUpload {
+    fn drop(&mut self) {
+        self.limiter.release(&self.pubkey);
+    }
+}
+
+/// Request guard holding an upload slot of the authenticated user until the response is returned.
+///
+/// Must come after the auth guard in the route arguments, it reads the pubkey the auth guard
+/// cached. Request guards run before the body is read, so rejected uploads are not received
+pub struct UploadPermit {
+    _upload: Option<ActiveUpload>,
+}
+
+#[async_trait]
+impl<'r> FromRequest<'r> for UploadPermit {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let limiter = match request.rocket().state::<UploadLimiter>() {
+            Some(l) => l,
+            None => return Outcome::Success(UploadPermit { _upload: None }),
+        };
+        let pubkey = match request.local_cache(|| AuthPubkey(None)).0 {
+            Some(p) => p,
+            None => return Outcome::Error((Status::Unauthorized, ())),
+        };
+        match limiter.try_acquire(pubkey.as_bytes()) {
+            Ok(upload) => Outcome::Success(UploadPermit {
+                _upload: Some(upload),
+            }),
+            Err(scope) => {
+                // rendered by the error catcher, which adds Retry-After
+                request.local_cache(|| {
+                    Some(ApiError::with_detail(
+                        ErrorCode::TooManyUploads,
+                        match scope {
+                            UploadScope::User => "Too many uploads from this key",
+                            UploadScope::Global => "Too many uploads on the server",
+                        },
+                    ))
+                });
+                Outcome::Error((Status::TooManyRequests, ()))
+            }
+        }
+    }
+}
@@ -0,0 +1,639 @@
+use std::collections::HashSet;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::net::SocketAddr;
+use std::path::Path;
+
+use nostr::{Keys, PublicKey};
+use url::Url;
+
+use crate::logging::parse_level;
+use crate::routes::zip::ZIP32_MAX_BYTES;
+use crate::routes::NIP94_RESERVED_TAGS;
+use crate::settings::Settings;
+use crate::vanity::is_valid_name;
+
+/// A problem found in the settings
+pub struct ConfigIssue {
+    /// Config key the problem was found in
+    pub key: String,
+    pub message: String,
+    /// The server cannot start with this problem
+    pub fatal: bool,
+}
+
+impl Display for ConfigIssue {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {}: {}",
+            if self.fatal { "error" } else { "warning" },
+            self.key,
+            self.message
+        )
+    }
+}
+
+#[derive(Default)]
+struct Issues(Vec<ConfigIssue>);
+
+impl Issues {
+    fn error(&mut self, key: impl Into<String>, message: impl Into<String>) {
+        self.0.push(ConfigIssue {
+            key: key.into(),
+            message: message.into(),
+            fatal: true,
+        });
+    }
+
+    fn warn(&mut self, key: impl Into<String>, message: impl Into<String>) {
+        self.0.push(ConfigIssue {
+            key: key.into(),
+            message: message.into(),
+            fatal: false,
+        });
+    }
+
+    fn url(&mut self, key: impl Into<String>, value: &str, schemes: &[&str]) {
+        let key = key.into();
+        match Url::parse(value) {
+            Ok(u) if schemes.contains(&u.scheme()) => {}
+            Ok(u) => self.error(
+                key,
+                format!(
+                    "unsupported scheme \"{}\" in \"{}\", expected {}",
+                    u.scheme(),
+                    value,
+                    schemes.join("/")
+                ),
+            ),
+            Err(e) => self.error(key, format!("invalid url \"{}\": {}", value, e)),
+        }
+    }
+
+    fn secret_key(&mut self, key: impl Into<String>, value: &str) {
+        if Keys::parse(value).is_err() {
+            self.error(key, "invalid secret key, expected hex or nsec");
+        }
+    }
+
+    fn hex_pubkey(&mut self, key: impl Into<String>, value: &str) {
+        let key = key.into();
+        if value.len() == 64 && value.chars().all(|c| matches!(c, '0'..='9' | 'a'..='f')) {
+            return;
+        }
+        match PublicKey::parse(value) {
+            Ok(pk) => self.error(
+                key,
+                format!(
+                    "\"{}\" must be written as lowercase hex: {}",
+                    value,
+                    pk.to_hex()
+                ),
+            ),
+            Err(_) => self.error(key, format!("\"{}\" is not a valid pubkey", value)),
+        }
+    }
+
+    fn readable(&mut self, key: impl Into<String>, path: &Path) {
+        if let Err(e) = fs::File::open(path) {
+            self.error(key, format!("cannot read {}: {}", path.display(), e));
+        }
+    }
+
+    fn dir(&mut self, key: impl Into<String>, path: &Path) {
+        if !path.is_dir() {
+            self.error(key, format!("{} is not a directory", path.display()));
+        }
+    }
+
+    fn writable_dir(&mut self, key: impl Into<String>, path: &Path) {
+        let key = key.into();
+        if !path.is_dir() {
+            self.error(key, format!("{} is not a directory", path.display()));
+            return;
+        }
+        let probe = path.join(format!(".route96-{}", uuid::Uuid::new_v4()));
+        match fs::write(&probe, b"") {
+            Ok(_) => {
+                let _ = fs::remove_file(&probe);
+            }
+            Err(e) => self.error(key, format!("cannot write to {}: {}", path.display(), e)),
+        }
+    }
+}
+
+/// Check the settings for problems which would only show up later at runtime
+pub fn validate_settings(settings: &Settings) -> Vec<ConfigIssue> {
+    let mut i = Issues::default();
+
+    if let Some(l) = &settings.listen {
+        if l.parse::<SocketAddr>().is_err() {
+            i.error("listen", format!("\"{}\" is not an ip:port address", l));
+        }
+    }
+    i.writable_dir("storage_dir", Path::new(&settings.storage_dir));
+    let mut root_names = HashSet::new();
+    for (n, r) in settings.storage_roots.iter().flatten().enumerate() {
+        let key = format!("storage_roots[{}]", n);
+        i.writable_dir(format!("{}.path", key), &r.path);
+        if r.name.is_empty() || r.name.len() > 32 || r.name == "default" {
+            i.error(
+                format!("{}.name", key),
+                "must be 1-32 characters and not \"default\"",
+            );
+        }
+        if !root_names.insert(r.name.as_str()) {
+            i.error(
+                format!("{}.name", key),
+                format!("duplicate name \"{}\"", r.name),
+            );
+        }
+    }
+    i.url("database", &settings.database, &["mysql"]);
+    if settings.max_upload_bytes == 0 {
+        i.error("max_upload_bytes", "must be greater than 0");
+    }
+    if settings.max_json_bytes.is_some_and(|b| b < 1024) {
+        i.error("max_json_bytes", "must be at least 1024");
+    }
+    i.url("public_url", &settings.public_url, &["http", "https"]);
+    if settings.public_url.ends_with('/') {
+        i.warn("public_url", "trailing slash produces urls with //");
+    }
+    for (n, u) in settings.public_urls.iter().flatten().enumerate() {
+        i.url(format!("public_urls[{}]", n), u, &["http", "https"]);
+    }
+    for (n, pk) in settings.whitelist.iter().flatten().enumerate() {
+        i.hex_pubkey(format!("whitelist[{}]", n), pk);
+    }
+    if let Some(vit) = &settings.vit_model {
+        i.readable("vit_model.model", &vit.model);
+        i.readable("vit_model.config", &vit.config);
+    }
+    if let Some(u) = &settings.webhook_url {
+        i.url("webhook_url", u, &["http", "https"]);
+    }
+    if let Some(u) = &settings.plausible_url {
+        i.url("plausible_url", u, &["http", "https"]);
+    }
+    if let Some(d) = &settings.void_cat_files {
+        i.dir("void_cat_files", d);
+    }
+    if let Some(d) = &settings.static_dir {
+        i.dir("static_dir", d);
+    }
+    if let Some(info) = &settings.server_info {
+        if let Some(pk) = &info.pubkey {
+            i.hex_pubkey("server_info.pubkey", pk);
+        }
+        if let Some(u) = &info.tos_url {
+            i.url("server_info.tos_url", u, &["http", "https"]);
+        }
+    }
+    if let Some(scrub) = &settings.scrub {
+        for (n, p) in scrub.restore_peers.iter().flatten().enumerate() {
+            i.url(format!("scrub.restore_peers[{}]", n), p, &["http", "https"]);
+        }
+    }
+    if let Some(m) = &settings.media_limits {
+        if m.max_dimension == Some(0) {
+            i.error("media_limits.max_dimension", "must be greater than 0");
+        }
+        if m.max_pixels == Some(0) {
+            i.error("media_limits.max_pixels", "must be greater than 0");
+        }
+    }
+    if let Some(s) = &settings.short_links {
+        if s.min_prefix.is_some_and(|p| !(4..=64).contains(&p)) {
+            i.error("short_links.min_prefix", "must be between 4 and 64");
+        }
+        if s.code_length.is_some_and(|l| !(4..=16).contains(&l)) {
+            i.error("short_links.code_length", "must be between 4 and 16");
+        }
+    }
+    if let Some(s) = &settings.startup_scan {
+        if s.rate == Some(0) {
+            i.error("startup_scan.rate", "must be greater than 0");
+        }
+    }
+    if let Some(z) = &settings.zip {
+        if z.max_files == Some(0) || z.max_files.is_some_and(|n| n > u16::MAX as usize) {
+            i.error("zip.max_files", "must be between 1 and 65535");
+        }
+        if z.max_bytes.is_some_and(|b| b >= ZIP32_MAX_BYTES) {
+            i.error(
+                "zip.max_bytes",
+                "must be less than 4GiB, zip64 is not supported",
+            );
+        }
+    }
+    if let Some(l) = &settings.load_shedding {
+        for (key, max) in [
+            ("load_shedding.max_reads", l.max_reads),
+            ("load_shedding.max_uploads", l.max_uploads),
+            ("load_shedding.max_mirrors", l.max_mirrors),
+            ("load_shedding.max_media", l.max_media),
+            ("load_shedding.max_queue_depth", l.max_queue_depth),
+        ] {
+            if max == Some(0) {
+                i.error(key, "must be at least 1, remove it for no limit");
+            }
+        }
+        if l.retry_after == Some(0) {
+            i.warn(
+                "load_shedding.retry_after",
+                "clients will retry immediately",
+            );
+        }
+    }
+    if let Some(v) = &settings.vanity_hosts {
+        if v.domain.is_empty() || v.domain.contains(['/', ':']) {
+            i.error(
+                "vanity_hosts.domain",
+                "must be a domain name, eg. media.example",
+            );
+        }
+        for (name, pk) in v.names.iter().flatten() {
+            if !is_valid_name(name) {
+                i.error(
+                    format!("vanity_hosts.names.{}", name),
+                    "must be a single DNS label",
+                );
+            }
+            i.hex_pubkey(format!("vanity_hosts.names.{}", name), pk);
+        }
+        if v.names.is_none() && v.nip05_domain.is_none() {
+            i.warn(
+                "vanity_hosts",
+                "neither names nor nip05_domain is set, no user has a subdomain",
+            );
+        }
+    }
+    if let Some(l) = &settings.logging {
+        if let Some(level) = &l.level {
+            if let Err(e) = parse_level(level) {
+                i.error("logging.level", e.to_string());
+            }
+        }
+        for (target, level) in l.modules.iter().flatten() {
+            if let Err(e) = parse_level(level) {
+                i.error(format!("logging.modules.{}", target), e.to_string());
+            }
+        }
+        if let Some(f) = &l.file {
+            if f.keep == Some(0) {
+                i.error("logging.file.keep", "must be at least 1");
+            }
+        }
+        if let Some(s) = &l.syslog {
+            if !s.exists() {
+                i.error("logging.syslog", format!("{} does not exist", s.display()));
+            }
+        }
+        if l.stderr == Some(false) && l.file.is_none() && l.syslog.is_none() {
+            i.warn("logging", "stderr is disabled and no other output is set");
+        }
+    }
+    if let Some(s) = &settings.source_check {
+        if s.interval == Some(0) {
+            i.error("source_check.interval", "must be at least 1");
+        }
+        if s.batch == Some(0) {
+            i.error("source_check.batch", "must be at least 1");
+        }
+    }
+    if let Some(w) = &settings.watermark {
+        if !w.image.exists() {
+            i.error("watermark.image", "does not exist");
+        }
+        if w.scale.is_some_and(|s| s <= 0.0 || s > 1.0) {
+            i.error("watermark.scale", "must be more than 0 and at most 1");
+        }
+        if w.opacity.is_some_and(|o| !(0.0..=1.0).contains(&o)) {
+            i.error("watermark.opacity", "must be between 0 and 1");
+        }
+        if !settings.image_negotiation.unwrap_or(false) {
+            i.warn(
+                "watermark",
+                "only renditions are watermarked, enable image_negotiation",
+            );
+        }
+    }
+    if let Some(r) = &settings.range_reads {
+        if r.buffer_size.is_some_and(|b| b < 4096) {
+            i.error("range_reads.buffer_size", "must be at least 4096");
+        }
+        if r.readahead == Some(0) {
+            i.error("range_reads.readahead", "must be at least 1");
+        }
+        if r.max_unbounded == Some(0) {
+            i.error("range_reads.max_unbounded", "must be at least 1");
+        }
+    }
+    if let Some(q) = &settings.quality_search {
+        if q.budgets.is_empty() {
+            i.warn(
+                "quality_search.budgets",
+                "no budgets, images are not searched",
+            );
+        }
+        for (n, b) in q.budgets.iter().enumerate() {
+            if b.max_bytes == 0 {
+                i.error(
+                    format!("quality_search.budgets[{}].max_bytes", n),
+                    "must be at least 1",
+                );
+            }
+        }
+        let min = q.min_quality.unwrap_or(30);
+        let max = q.max_quality.unwrap_or(90);
+        if max > 100 {
+            i.error("quality_search.max_quality", "must be at most 100");
+        }
+        if min > max {
+            i.error(
+                "quality_search.min_quality",
+                "must not be more than max_quality",
+            );
+        }
+        if q.max_attempts == Some(0) {
+            i.error("quality_search.max_attempts", "must be at least 1");
+        }
+    }
+    if let Some(b) = &settings.backup {
+        if b.keep == Some(0) {
+            i.error("backup.keep", "must be at least 1");
+        }
+        for (n, t) in b.tables.iter().flatten().enumerate() {
+            if t.is_empty() || !t.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+                i.error(
+                    format!("backup.tables[{}]", n),
+                    "must only contain letters, digits and _",
+                );
+            }
+        }
+        if b.command.as_ref().is_some_and(|c| !c.exists()) {
+            i.error("backup.command", "does not exist");
+        }
+    }
+    if let Some(m) = &settings.metadata_limits {
+        // sizes of the uploads.name and uploads.alt columns
+        if m.max_name_length.is_some_and(|l| l == 0 || l > 256) {
+            i.error(
+                "metadata_limits.max_name_length",
+                "must be between 1 and 256",
+            );
+        }
+        if m.max_alt_length.is_some_and(|l| l == 0 || l > 512) {
+            i.error(
+                "metadata_limits.max_alt_length",
+                "must be between 1 and 512",
+            );
+        }
+    }
+    if let Some(t) = &settings.tos {
+        if !t.document.exists() {
+            i.error("tos.document", "does not exist");
+        }
+        // size of the tos_acceptances.version column
+        if t.version.trim().is_empty() || t.version.len() > 64 {
+            i.error("tos.version", "must be between 1 and 64 characters");
+        }
+        if t.max_age == Some(0) {
+            i.error("tos.max_age", "must be more than 0");
+        }
+    }
+    if let Some(t) = &settings.transfer_limits {
+        for (name, l) in [
+            ("default", &t.default),
+            ("upload", &t.upload),
+            ("media", &t.media),
+            ("mirror", &t.mirror),
+        ]
+        .into_iter()
+        .filter_map(|(n, l)| l.as_ref().map(|l| (n, l)))
+        {
+            if l.read_timeout == Some(0) {
+                i.error(
+                    format!("transfer_limits.{}.read_timeout", name),
+                    "must be more than 0",
+                );
+            }
+            if l.rate_window == Some(0) {
+                i.error(
+                    format!("transfer_limits.{}.rate_window", name),
+                    "must be more than 0",
+                );
+            }
+        }
+    }
+    if let Some(r) = &settings.reports {
+        let pubkeys = r.nostr_pubkeys.as_deref().unwrap_or_default();
+        if r.webhook.is_none() && r.smtp.is_none() && pubkeys.is_empty() {
+            i.warn(
+                "reports",
+                "no webhook, smtp or nostr_pubkeys, reports are only kept for the admin API",
+            );
+        }
+        for (n, p) in pubkeys.iter().enumerate() {
+            if nostr::PublicKey::parse(p).is_err() {
+                i.error(format!("reports.nostr_pubkeys[{}]", n), "invalid pubkey");
+            }
+        }
+        if !pubkeys.is_empty() {
+            match r
+                .nostr_key
+                .as_ref()
+                .or(settings.bot.as_ref().map(|b| &b.server_key))
+            {
+                Some(k) if nostr::Keys::parse(k).is_err() => {
+                    i.error("reports.nostr_key", "invalid secret key")
+                }
+                Some(_) => {}
+                None => i.error("reports.nostr_key", "required to send direct messages"),
+            }
+            let relays = r
+                .relays
+                .as_ref()
+                .or(settings.bot.as_ref().map(|b| &b.relays));
+            if !relays.is_some_and(|r| !r.is_empty()) {
+                i.error("reports.relays", "required to send direct messages");
+            }
+        }
+        if let Some(s) = &r.smtp {
+            if s.to.is_empty() {
+                i.error("reports.smtp.to", "must list at least one address");
+            }
+        }
+    }
+    if let Some(m) = &settings.media_types {
+        if let Some(t) = &m.supported {
+            if t.is_empty() {
+                i.warn(
+                    "media_types.supported",
+                    "empty, no /media upload is converted",
+                );
+            }
+            for (n, t) in t.iter().enumerate() {
+                if !t.starts_with("image/") {
+                    i.warn(
+                        format!("media_types.supported[{}]", n),
+                        "only images are converted to WebP",
+                    );
+                }
+            }
+        }
+    }
+    if let Some(m) = &settings.metadata_backfill {
+        if m.interval == Some(0) {
+            i.error("metadata_backfill.interval", "must be at least 1 second");
+        }
+        if m.batch == Some(0) {
+            i.error("metadata_backfill.batch", "must be at least 1");
+        }
+    }
+    if let Some(u) = &settings.upload_concurrency {
+        for (key, max) in [
+            ("upload_concurrency.max_per_user", u.max_per_user),
+            ("upload_concurrency.max_total", u.max_total),
+        ] {
+            if max == Some(0) {
+                i.error(key, "must be at least 1, remove it for no limit");
+            }
+        }
+        if let (Some(user), Some(total)) = (u.max_per_user, u.max_total) {
+            if user > total {
+                i.warn(
+                    "upload_concurrency.max_per_user",
+                    "is more than max_total, which applies first",
+                );
+            }
+        }
+    }
+    #[cfg(feature = "encryption")]
+    if let Some(e) = &settings.encryption {
+        match (&e.master_key, &e.key_command) {
+            (Some(k), None) => {
+                if hex::decode(k).map(|k| k.len()) != Ok(32) {
+                    i.error("encryption.master_key", "must be 32 bytes hex encoded");
+                }
+            }
+            (None, Some(c)) => {
+                if !c.exists() {
+                    i.error("encryption.key_command", "does not exist");
+                }
+            }
+            _ => i.error(
+                "encryption",
+                "exactly one of master_key or key_command must be set",
+            ),
+        }
+        for (n, k) in e.previous_keys.iter().flatten().enumerate() {
+            if hex::decode(k).map(|k| k.len()) != Ok(32) {
+                i.error(
+                    format!("encryption.previous_keys[{}]", n),
+                    "must be 32 bytes hex encoded",
+                );
+            }
+        }
+    }
+    #[cfg(feature = "torrent-v2")]
+    if let Some(t) = &settings.torrent {
+        for (n, u) in t.trackers.iter().flatten().enumerate() {
+            i.url(
+                format!("torrent.trackers[{}]", n),
+                u,
+                &["http", "https", "udp"],
+            );
+        }
+        if let Some(d) = &t.seed_dir {
+            if d.exists() {
+                i.writable_dir("torrent.seed_dir", d);
+            }
+        }
+    }
+    if let Some(r) = &settings.replication {
+        for (n, p) in r.peers.iter().enumerate() {
+            i.url(format!("replication.peers[{}]", n), p, &["http", "https"]);
+        }
+        if let Some(k) = &r.server_key {
+            i.secret_key("replication.server_key", k);
+        } else if r.propagate_deletes {
+            i.error(
+                "replication.propagate_deletes",
+                "server_key is required to sign deletes on peers",
+            );
+        }
+        for (n, pk) in r.trusted_peers.iter().flatten().enumerate() {
+            i.hex_pubkey(format!("replication.trusted_peers[{}]", n), pk);
+        }
+    }
+    if let Some(a) = &settings.announce {
+        for (n, r) in a.relays.iter().enumerate() {
+            i.url(format!("announce.relays[{}]", n), r, &["ws", "wss"]);
+        }
+        i.secret_key("announce.server_key", &a.server_key);
+    }
+    if let Some(p) = &settings.profiles {
+        for (n, r) in p.relays.iter().enumerate() {
+            i.url(format!("profiles.relays[{}]", n), r, &["ws", "wss"]);
+        }
+    }
+    if let Some(d) = &settings.deletions {
+        for (n, r) in d.relays.iter().enumerate() {
+            i.url(format!("deletions.relays[{}]", n), r, &["ws", "wss"]);
+        }
+    }
+    for k in settings.nip94_tags.iter().flatten().map(|(k, _)| k) {
+        if NIP94_RESERVED_TAGS.contains(&k.as_str()) {
+            i.warn(
+                format!("nip94_tags.{}", k),
+                "standard tag set by the server, it will be ignored",
+            );
+        }
+    }
+    if let Some(b) = &settings.bot {
+        for (n, r) in b.relays.iter().enumerate() {
+            i.url(format!("bot.relays[{}]", n), r, &["ws", "wss"]);
+        }
+        i.secret_key("bot.server_key", &b.server_key);
+    }
+    if let Some(d) = &settings.disk {
+        if d.min_free_bytes < settings.max_upload_bytes {
+            i.warn(
+                "disk.min_free_bytes",
+                "lower than max_upload_bytes, a single upload can fill the disk",
+            );
+        }
+    }
+    if let Some(dev) = settings.hwaccel.as_ref().and_then(|h| h.device.as_ref()) {
+        if dev.starts_with('/') && !Path::new(dev).exists() {
+            i.warn(
+                "hwaccel.device",
+                format!("{} does not exist, software processing will be used", dev),
+            );
+        }
+    }
+    if let Some(h) = &settings.hooks {
+        if let Some(p) = &h.post_store {
+            i.readable("hooks.post_store", p);
+        }
+        if let Some(p) = &h.post_delete {
+            i.readable("hooks.post_delete", p);
+        }
+    }
+    #[cfg(feature = "grpc")]
+    if let Some(g) = &settings.grpc {
+        if g.listen.parse::<SocketAddr>().is_err() {
+            i.error(
+                "grpc.listen",
+                format!("\"{}\" is not an ip:port address", g.listen),
+            );
+        }
+        i.readable("grpc.cert", &g.cert);
+        i.readable("grpc.key", &g.key);
+        i.readable("grpc.client_ca", &g.client_ca);
+    }
+    i.0
+}
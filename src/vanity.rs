@@ -0,0 +1,179 @@
+use std::collections::HashMap;
+
+use log::warn;
+use rocket::request::{FromRequest, Outcome};
+use rocket::{async_trait, Request};
+use sqlx::Error;
+use url::Url;
+
+use crate::db::Database;
+use crate::settings::Settings;
+
+impl Database {
+    /// Pubkey of the user with a verified NIP-05 address
+    pub async fn get_pubkey_by_nip05(&self, nip05: &str) -> Result<Option<Vec<u8>>, Error> {
+        sqlx::query_scalar("select pubkey from users where nip05 = ? and nip05_verified = 1")
+            .bind(nip05)
+            .fetch_optional(&self.pool)
+            .await
+    }
+}
+
+/// Serves the files of a user on `<name>.<domain>` and generates their urls with that host.
+///
+/// Names come from the config or from NIP-05 addresses verified on `nip05_domain`.
+pub struct VanityHosts {
+    db: Database,
+    domain: String,
+    scheme: String,
+    /// name => pubkey
+    names: HashMap<String, Vec<u8>>,
+    nip05_domain: Option<String>,
+    /// Hosts of the public urls, never treated as vanity hosts
+    public_hosts: Vec<String>,
+}
+
+impl VanityHosts {
+    pub fn new(settings: &Settings, db: Database) -> Option<Self> {
+        let cfg = settings.vanity_hosts.as_ref()?;
+        let names = cfg
+            .names
+            .iter()
+            .flatten()
+            .filter_map(|(name, pubkey)| match hex::decode(pubkey) {
+                Ok(p) if p.len() == 32 => Some((name.to_lowercase(), p)),
+                _ => {
+                    warn!("Ignoring vanity host {} with invalid pubkey", name);
+                    None
+                }
+            })
+            .collect();
+        let scheme = Url::parse(&settings.public_url)
+            .map(|u| u.scheme().to_string())
+            .unwrap_or("https".to_string());
+        let public_hosts = std::iter::once(&settings.public_url)
+            .chain(settings.public_urls.iter().flatten())
+            .filter_map(|u| Some(Url::parse(u).ok()?.host_str()?.to_lowercase()))
+            .collect();
+        Some(Self {
+            db,
+            domain: cfg.domain.trim_matches('.').to_lowercase(),
+            scheme,
+            names,
+            nip05_domain: cfg.nip05_domain.as_ref().map(|d| d.to_lowercase()),
+            public_hosts,
+        })
+    }
+
+    /// Name of a vanity host, None when the host is not a subdomain of the vanity domain
+    fn name_of<'a>(&self, host: &'a str) -> Option<&'a str> {
+        if self.public_hosts.iter().any(|h| h == host) {
+            return None;
+        }
+        host.strip_suffix(self.domain.as_str())?
+            .strip_suffix('.')
+            .filter(|n| is_valid_name(n))
+    }
+
+    /// Pubkey a name belongs to
+    pub async fn resolve(&self, name: &str) -> Option<Vec<u8>> {
+        let name = name.to_lowercase();
+        if let Some(p) = self.names.get(&name) {
+            return Some(p.clone());
+        }
+        let domain = self.nip05_domain.as_ref()?;
+        match self
+            .db
+            .get_pubkey_by_nip05(&format!("{}@{}", name, domain))
+            .await
+        {
+            Ok(p) => p,
+            Err(e) => {
+                warn!("Failed to resolve vanity host {}: {}", name, e);
+                None
+            }
+        }
+    }
+
+    /// Name of a user's vanity host, if they have one
+    pub async fn name_for(&self, pubkey: &Vec<u8>) -> Option<String> {
+        if let Some((n, _)) = self.names.iter().find(|(_, p)| *p == pubkey) {
+            return Some(n.clone());
+        }
+        let domain = self.nip05_domain.as_ref()?;
+        let user = self.db.get_user(pubkey).await.ok()?;
+        if !user.nip05_verified {
+            return None;
+        }
+        let nip05 = user.nip05?.to_lowercase();
+        let (name, d) = nip05.split_once('@')?;
+        if d != domain || !is_valid_name(name) {
+            return None;
+        }
+        Some(name.to_string())
+    }
+
+    /// Public url (scheme://name.domain) of a user's files, if they have a vanity host
+    pub async fn public_url_for(&self, pubkey: &Vec<u8>) -> Option<String> {
+        let name = self.name_for(pubkey).await?;
+        Some(format!("{}://{}.{}", self.scheme, name, self.domain))
+    }
+}
+
+/// Names must be a single DNS label, NIP-05 names like `_` can't be hosts
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 63
+        && !name.starts_with('-')
+        && !name.ends_with('-')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+}
+
+/// Vanity host a request was made to
+pub enum VanityHost {
+    /// Not a vanity host, all files are served
+    None,
+    /// Host of a user, only their files are served
+    User(Vec<u8>),
+    /// Host of a name nobody has
+    Unknown,
+}
+
+impl VanityHost {
+    /// Whether a file can be served on this host
+    pub async fn allows(&self, db: &Database, file: &Vec<u8>) -> bool {
+        match self {
+            VanityHost::None => true,
+            VanityHost::Unknown => false,
+            VanityHost::User(pubkey) => db
+                .get_file_owners(file)
+                .await
+                .map(|o| o.iter().any(|u| u.pubkey == *pubkey))
+                .unwrap_or(false),
+        }
+    }
+}
+
+#[async_trait]
+impl<'r> FromRequest<'r> for VanityHost {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let hosts = match request.rocket().state::<Option<VanityHosts>>() {
+            Some(Some(h)) => h,
+            _ => return Outcome::Success(VanityHost::None),
+        };
+        let host = match request.host() {
+            Some(h) => h.domain().as_str().to_lowercase(),
+            None => return Outcome::Success(VanityHost::None),
+        };
+        let name = match hosts.name_of(&host) {
+            Some(n) => n,
+            None => return Outcome::Success(VanityHost::None),
+        };
+        Outcome::Success(match hosts.resolve(name).await {
+            Some(p) => VanityHost::User(p),
+            None => VanityHost::Unknown,
+        })
+    }
+}
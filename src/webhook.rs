@@ -5,6 +5,7 @@ use serde::{Deserialize, Serialize};
 
 use crate::filesystem::FileSystemResult;
 
+#[derive(Clone)]
 pub struct Webhook {
     url: String,
     client: Client,
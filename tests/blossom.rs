@@ -0,0 +1,308 @@
+mod common;
+
+use common::{blossom_auth, sha256_hex, TestServer, PUBLIC_URL};
+use nostr::serde_json::Value;
+use nostr::Keys;
+use rocket::http::{ContentType, Status};
+use sqlx::MySqlPool;
+
+/// Upload a blob with the owner's auth, returning its descriptor
+async fn upload(server: &TestServer, keys: &Keys, data: &[u8]) -> Value {
+    let id = sha256_hex(data);
+    let rsp = server
+        .client
+        .put("/upload")
+        .header(blossom_auth(keys, "upload", &[&id]))
+        .header(ContentType::Binary)
+        .body(data)
+        .dispatch()
+        .await;
+    assert_eq!(rsp.status(), Status::Ok);
+    rsp.into_json().await.expect("blob descriptor")
+}
+
+#[sqlx::test]
+async fn upload_stores_blob(pool: MySqlPool) {
+    let server = TestServer::new(pool).await;
+    let keys = Keys::generate();
+    let data = b"hello route96";
+    let id = sha256_hex(data);
+
+    let desc = upload(&server, &keys, data).await;
+    assert_eq!(desc["sha256"], id);
+    assert_eq!(desc["size"], data.len());
+    let url = desc["url"].as_str().expect("url");
+    assert!(url.starts_with(&format!("{}/{}", PUBLIC_URL, id)));
+
+    let rsp = server.client.get(format!("/{}", id)).dispatch().await;
+    assert_eq!(rsp.status(), Status::Ok);
+    assert_eq!(rsp.into_bytes().await.as_deref(), Some(&data[..]));
+}
+
+#[sqlx::test]
+async fn upload_again_returns_same_blob(pool: MySqlPool) {
+    let server = TestServer::new(pool).await;
+    let keys = Keys::generate();
+    let data = b"uploaded twice";
+
+    let first = upload(&server, &keys, data).await;
+    let second = upload(&server, &keys, data).await;
+    assert_eq!(first["sha256"], second["sha256"]);
+}
+
+#[sqlx::test]
+async fn list_returns_uploads_of_pubkey(pool: MySqlPool) {
+    let server = TestServer::new(pool).await;
+    let keys = Keys::generate();
+    let other = Keys::generate();
+    upload(&server, &keys, b"first").await;
+    upload(&server, &keys, b"second").await;
+    upload(&server, &other, b"someone else").await;
+
+    let rsp = server
+        .client
+        .get(format!("/list/{}", keys.public_key().to_hex()))
+        .dispatch()
+        .await;
+    assert_eq!(rsp.status(), Status::Ok);
+    let list: Vec<Value> = rsp.into_json().await.expect("blob list");
+    let mut ids: Vec<_> = list.iter().map(|d| d["sha256"].clone()).collect();
+    ids.sort_by_key(|i| i.to_string());
+    let mut expected = vec![
+        Value::from(sha256_hex(b"first")),
+        Value::from(sha256_hex(b"second")),
+    ];
+    expected.sort_by_key(|i| i.to_string());
+    assert_eq!(ids, expected);
+}
+
+#[sqlx::test]
+async fn list_of_unknown_pubkey_is_empty(pool: MySqlPool) {
+    let server = TestServer::new(pool).await;
+    let rsp = server
+        .client
+        .get(format!("/list/{}", Keys::generate().public_key().to_hex()))
+        .dispatch()
+        .await;
+    assert_eq!(rsp.status(), Status::Ok);
+    let list: Vec<Value> = rsp.into_json().await.expect("blob list");
+    assert!(list.is_empty());
+}
+
+#[sqlx::test]
+async fn delete_by_owner_removes_blob(pool: MySqlPool) {
+    let server = TestServer::new(pool).await;
+    let keys = Keys::generate();
+    let data = b"to be deleted";
+    let id = sha256_hex(data);
+    upload(&server, &keys, data).await;
+
+    let rsp = server
+        .client
+        .delete(format!("/{}", id))
+        .header(blossom_auth(&keys, "delete", &[&id]))
+        .dispatch()
+        .await;
+    assert_eq!(rsp.status(), Status::Ok);
+
+    let rsp = server.client.get(format!("/{}", id)).dispatch().await;
+    assert_eq!(rsp.status(), Status::NotFound);
+    let file = server.db.get_file(&hex::decode(&id).unwrap()).await;
+    assert!(file.expect("query").is_none());
+}
+
+#[sqlx::test]
+async fn delete_removes_short_link_and_upload_state(pool: MySqlPool) {
+    let server = TestServer::new(pool).await;
+    let keys = Keys::generate();
+    let data = b"linked blob";
+    let id = sha256_hex(data);
+    upload(&server, &keys, data).await;
+    let file = hex::decode(&id).unwrap();
+    server.db.add_short_link("linked", &file).await.unwrap();
+    // left behind by an upload which never finished
+    sqlx::query(
+        "insert into upload_states(id,state,active,updated) \
+        values(?,'processing',1,current_timestamp - interval 2 hour)",
+    )
+    .bind(&file)
+    .execute(&server.pool)
+    .await
+    .unwrap();
+
+    let rsp = server
+        .client
+        .delete(format!("/{}", id))
+        .header(blossom_auth(&keys, "delete", &[&id]))
+        .dispatch()
+        .await;
+    assert_eq!(rsp.status(), Status::Ok);
+
+    assert!(server.db.get_short_link("linked").await.unwrap().is_none());
+    let states: i64 = sqlx::query_scalar("select count(*) from upload_states where id = ?")
+        .bind(&file)
+        .fetch_one(&server.pool)
+        .await
+        .unwrap();
+    assert_eq!(states, 0);
+}
+
+#[sqlx::test]
+async fn delete_by_one_owner_keeps_blob_for_others(pool: MySqlPool) {
+    let server = TestServer::new(pool).await;
+    let keys = Keys::generate();
+    let other = Keys::generate();
+    let data = b"shared blob";
+    let id = sha256_hex(data);
+    upload(&server, &keys, data).await;
+    upload(&server, &other, data).await;
+
+    let rsp = server
+        .client
+        .delete(format!("/{}", id))
+        .header(blossom_auth(&keys, "delete", &[&id]))
+        .dispatch()
+        .await;
+    assert_eq!(rsp.status(), Status::Ok);
+
+    let rsp = server.client.get(format!("/{}", id)).dispatch().await;
+    assert_eq!(rsp.status(), Status::Ok);
+    let rsp = server
+        .client
+        .get(format!("/list/{}", keys.public_key().to_hex()))
+        .dispatch()
+        .await;
+    let list: Vec<Value> = rsp.into_json().await.expect("blob list");
+    assert!(list.is_empty());
+}
+
+#[sqlx::test]
+async fn delete_by_admin_removes_blob_of_others(pool: MySqlPool) {
+    let server = TestServer::new(pool).await;
+    let keys = Keys::generate();
+    let admin = Keys::generate();
+    server.add_admin(&admin).await;
+    let data = b"removed by admin";
+    let id = sha256_hex(data);
+    upload(&server, &keys, data).await;
+
+    let rsp = server
+        .client
+        .delete(format!("/{}", id))
+        .header(blossom_auth(&admin, "delete", &[&id]))
+        .dispatch()
+        .await;
+    assert_eq!(rsp.status(), Status::Ok);
+
+    let rsp = server.client.get(format!("/{}", id)).dispatch().await;
+    assert_eq!(rsp.status(), Status::NotFound);
+}
+
+#[sqlx::test]
+async fn failed_upload_can_be_retried_with_same_auth(pool: MySqlPool) {
+    let server = TestServer::new(pool).await;
+    let keys = Keys::generate();
+    let data = b"retried upload";
+    let auth = blossom_auth(&keys, "upload", &[&sha256_hex(data)]);
+
+    let rsp = server
+        .client
+        .put("/upload")
+        .header(auth.clone())
+        .header(ContentType::Binary)
+        .body(b"truncated")
+        .dispatch()
+        .await;
+    assert_eq!(rsp.status(), Status::BadRequest);
+
+    let rsp = server
+        .client
+        .put("/upload")
+        .header(auth.clone())
+        .header(ContentType::Binary)
+        .body(data)
+        .dispatch()
+        .await;
+    assert_eq!(rsp.status(), Status::Ok);
+
+    // used by a successful upload, a replay is rejected
+    let rsp = server
+        .client
+        .put("/upload")
+        .header(auth)
+        .header(ContentType::Binary)
+        .body(data)
+        .dispatch()
+        .await;
+    assert_eq!(rsp.status(), Status::Unauthorized);
+    assert!(rsp
+        .headers()
+        .get_one("X-Reason")
+        .is_some_and(|r| r.contains("Auth event already used")));
+}
+
+#[cfg(feature = "media-compression")]
+#[sqlx::test]
+async fn media_x_tag_matches_original(pool: MySqlPool) {
+    let server = TestServer::with_settings(pool, |s| {
+        s.auth_x_tag = Some(route96::settings::XTagPolicy::Strict)
+    })
+    .await;
+    let keys = Keys::generate();
+    let png = common::tiny_png();
+    let rsp = server
+        .client
+        .put("/media")
+        .header(blossom_auth(&keys, "media", &[&sha256_hex(&png)]))
+        .header(ContentType::PNG)
+        .body(png)
+        .dispatch()
+        .await;
+    assert_eq!(rsp.status(), Status::Ok);
+}
+
+#[cfg(feature = "media-compression")]
+#[sqlx::test]
+async fn thumbnail_urls_are_content_addressed(pool: MySqlPool) {
+    use route96::processing::thumbnail::THUMBNAIL_PARAMS;
+
+    let server = TestServer::new(pool).await;
+    let keys = Keys::generate();
+    upload(&server, &keys, b"photo").await;
+    upload(&server, &keys, b"thumbnail").await;
+    let photo = hex::decode(sha256_hex(b"photo")).unwrap();
+    let thumb = hex::decode(sha256_hex(b"thumbnail")).unwrap();
+    server
+        .db
+        .add_derivation(&photo, THUMBNAIL_PARAMS, &thumb)
+        .await
+        .unwrap();
+    let thumb_url = format!("{}/{}.webp", PUBLIC_URL, hex::encode(&thumb));
+
+    // the legacy url redirects to the thumbnail by its own hash
+    let rsp = server
+        .client
+        .get(format!("/thumb/{}", hex::encode(&photo)))
+        .dispatch()
+        .await;
+    assert_eq!(rsp.status(), Status::TemporaryRedirect);
+    assert_eq!(rsp.headers().get_one("Location"), Some(thumb_url.as_str()));
+    let rsp = server
+        .client
+        .get(format!("/thumb/{}", hex::encode(&thumb)))
+        .dispatch()
+        .await;
+    assert_eq!(rsp.status(), Status::NotFound);
+
+    let rsp = server
+        .client
+        .get(format!("/list/{}", keys.public_key().to_hex()))
+        .dispatch()
+        .await;
+    let list: Vec<Value> = rsp.into_json().await.expect("blob list");
+    let desc = list
+        .iter()
+        .find(|d| d["sha256"] == hex::encode(&photo))
+        .expect("photo");
+    assert_eq!(desc["nip94"]["thumb"], thumb_url);
+}
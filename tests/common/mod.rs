@@ -0,0 +1,141 @@
+//! Shared setup of the route tests.
+//!
+//! Each test gets its own database from `#[sqlx::test]` (needs `DATABASE_URL` pointing at a
+//! MySQL server the tests may create databases on) and its own storage directory.
+#![allow(dead_code)]
+
+use std::borrow::Cow;
+
+use base64::prelude::*;
+use nostr::{
+    Alphabet, EventBuilder, JsonUtil, Keys, Kind, SingleLetterTag, Tag, TagKind, Timestamp,
+};
+use rocket::http::Header;
+use rocket::local::asynchronous::Client;
+use route96::app::build_rocket;
+use route96::background::JobCancels;
+use route96::db::Database;
+use route96::settings::Settings;
+use sha2::{Digest, Sha256};
+use sqlx::MySqlPool;
+use tempfile::TempDir;
+
+pub const PUBLIC_URL: &str = "http://localhost:8000";
+
+/// 1x1 red PNG, small enough to compress in tests
+pub const TINY_PNG: &str = "iVBORw0KGgoAAAANSUhEUgAAAAEAAAABCAYAAAAfFcSJAAAADUlEQVR42mP8z8DwHwAFBQIAX8jx0gAAAABJRU5ErkJggg==";
+
+pub fn tiny_png() -> Vec<u8> {
+    BASE64_STANDARD.decode(TINY_PNG).expect("png")
+}
+
+/// Server running requests in-memory, the storage directory is removed when dropped
+pub struct TestServer {
+    pub client: Client,
+    pub db: Database,
+    pub pool: MySqlPool,
+    pub settings: Settings,
+    _storage: TempDir,
+}
+
+impl TestServer {
+    pub async fn new(pool: MySqlPool) -> Self {
+        Self::with_settings(pool, |_| {}).await
+    }
+
+    /// Start a server with settings changed by the test
+    pub async fn with_settings(pool: MySqlPool, customize: impl FnOnce(&mut Settings)) -> Self {
+        let storage = TempDir::new().expect("storage dir");
+        let mut settings = Settings {
+            storage_dir: storage.path().to_string_lossy().to_string(),
+            database: String::new(),
+            max_upload_bytes: 1024 * 1024,
+            public_url: PUBLIC_URL.to_string(),
+            ..Default::default()
+        };
+        customize(&mut settings);
+        let db = Database::from_pool(pool.clone());
+        let rocket = build_rocket(
+            rocket::Config::debug_default(),
+            settings.clone(),
+            db.clone(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            JobCancels::default(),
+        );
+        let client = Client::tracked(rocket).await.expect("valid rocket");
+        Self {
+            client,
+            db,
+            pool,
+            settings,
+            _storage: storage,
+        }
+    }
+
+    /// Make a pubkey an admin, creating the user
+    pub async fn add_admin(&self, keys: &Keys) {
+        let pubkey = keys.public_key().to_bytes().to_vec();
+        let id = self.db.upsert_user(&pubkey).await.expect("user");
+        sqlx::query("update users set is_admin = 1 where id = ?")
+            .bind(id)
+            .execute(&self.pool)
+            .await
+            .expect("admin");
+    }
+}
+
+pub fn sha256_hex(data: &[u8]) -> String {
+    hex::encode(Sha256::digest(data))
+}
+
+/// Blossom auth header for an action (`t` tag), optionally limited to blobs (`x` tags)
+pub fn blossom_auth(keys: &Keys, action: &str, x: &[&str]) -> Header<'static> {
+    let mut tags = vec![
+        Tag::custom(
+            TagKind::SingleLetter(SingleLetterTag::lowercase(Alphabet::T)),
+            [action],
+        ),
+        Tag::expiration(Timestamp::now() + 300),
+    ];
+    for id in x {
+        tags.push(Tag::custom(
+            TagKind::SingleLetter(SingleLetterTag::lowercase(Alphabet::X)),
+            [*id],
+        ));
+    }
+    let event = EventBuilder::new(Kind::Custom(24242), "")
+        .tags(tags)
+        .sign_with_keys(keys)
+        .expect("signed event");
+    Header::new(
+        "authorization",
+        format!("Nostr {}", BASE64_STANDARD.encode(event.as_json())),
+    )
+}
+
+/// NIP-98 auth header for a request, `url` is the path below [PUBLIC_URL]
+pub fn nip98_auth(keys: &Keys, url: &str, method: &str, payload: Option<&[u8]>) -> Header<'static> {
+    let mut tags = vec![
+        Tag::custom(
+            TagKind::SingleLetter(SingleLetterTag::lowercase(Alphabet::U)),
+            [format!("{}{}", PUBLIC_URL, url)],
+        ),
+        Tag::custom(TagKind::Custom(Cow::Borrowed("method")), [method]),
+    ];
+    if let Some(p) = payload {
+        tags.push(Tag::custom(
+            TagKind::Custom(Cow::Borrowed("payload")),
+            [sha256_hex(p)],
+        ));
+    }
+    let event = EventBuilder::new(Kind::HttpAuth, "")
+        .tags(tags)
+        .sign_with_keys(keys)
+        .expect("signed event");
+    Header::new(
+        "authorization",
+        format!("Nostr {}", BASE64_STANDARD.encode(event.as_json())),
+    )
+}
@@ -0,0 +1,338 @@
+//! Status codes, X-Error-Code and X-Reason of rejected requests
+mod common;
+
+#[cfg(feature = "media-compression")]
+use common::tiny_png;
+use common::{blossom_auth, sha256_hex, TestServer};
+use nostr::serde_json::Value;
+use nostr::Keys;
+use rocket::http::{ContentType, Header, Status};
+use rocket::local::asynchronous::LocalResponse;
+#[cfg(feature = "media-compression")]
+use route96::settings::{MediaTypesConfig, UnsupportedMediaPolicy, XTagPolicy};
+use sqlx::MySqlPool;
+
+/// Check a response is the json error of a code with the reason in X-Reason
+async fn assert_error(rsp: LocalResponse<'_>, status: Status, code: &str, reason: &str) {
+    assert_eq!(rsp.status(), status);
+    assert_eq!(rsp.headers().get_one("X-Error-Code"), Some(code));
+    let x_reason = rsp
+        .headers()
+        .get_one("X-Reason")
+        .expect("X-Reason")
+        .to_string();
+    assert!(
+        x_reason.contains(reason),
+        "X-Reason {:?} does not contain {:?}",
+        x_reason,
+        reason
+    );
+    let body: Value = rsp.into_json().await.expect("json error");
+    assert_eq!(body["status"], "error");
+    assert_eq!(body["code"], code);
+    assert_eq!(body["message"], x_reason);
+}
+
+#[sqlx::test]
+async fn upload_without_auth(pool: MySqlPool) {
+    let server = TestServer::new(pool).await;
+    let rsp = server
+        .client
+        .put("/upload")
+        .header(ContentType::Binary)
+        .body(b"data")
+        .dispatch()
+        .await;
+    assert_error(
+        rsp,
+        Status::Unauthorized,
+        "invalid_auth",
+        "Auth header not found",
+    )
+    .await;
+}
+
+#[sqlx::test]
+async fn upload_with_wrong_action(pool: MySqlPool) {
+    let server = TestServer::new(pool).await;
+    let keys = Keys::generate();
+    let rsp = server
+        .client
+        .put("/upload")
+        .header(blossom_auth(&keys, "delete", &[]))
+        .header(ContentType::Binary)
+        .body(b"data")
+        .dispatch()
+        .await;
+    assert_error(
+        rsp,
+        Status::Unauthorized,
+        "invalid_auth",
+        "Invalid request method tag",
+    )
+    .await;
+}
+
+#[sqlx::test]
+async fn upload_too_large(pool: MySqlPool) {
+    let server = TestServer::with_settings(pool, |s| s.max_upload_bytes = 16).await;
+    let keys = Keys::generate();
+    let data = [0u8; 32];
+    let rsp = server
+        .client
+        .put("/upload")
+        .header(blossom_auth(&keys, "upload", &[&sha256_hex(&data)]))
+        .header(ContentType::Binary)
+        .header(Header::new("content-length", data.len().to_string()))
+        .body(data)
+        .dispatch()
+        .await;
+    assert_error(rsp, Status::PayloadTooLarge, "too_large", "File too large").await;
+}
+
+#[sqlx::test]
+async fn upload_not_whitelisted(pool: MySqlPool) {
+    let server = TestServer::with_settings(pool, |s| {
+        s.whitelist = Some(vec![Keys::generate().public_key().to_hex()])
+    })
+    .await;
+    let keys = Keys::generate();
+    let rsp = server
+        .client
+        .put("/upload")
+        .header(blossom_auth(&keys, "upload", &[&sha256_hex(b"data")]))
+        .header(ContentType::Binary)
+        .body(b"data")
+        .dispatch()
+        .await;
+    assert_error(
+        rsp,
+        Status::Forbidden,
+        "not_whitelisted",
+        "Not on whitelist",
+    )
+    .await;
+}
+
+#[sqlx::test]
+async fn upload_not_matching_x_tag(pool: MySqlPool) {
+    let server = TestServer::new(pool).await;
+    let keys = Keys::generate();
+    let rsp = server
+        .client
+        .put("/upload")
+        .header(blossom_auth(&keys, "upload", &[&sha256_hex(b"other")]))
+        .header(ContentType::Binary)
+        .body(b"data")
+        .dispatch()
+        .await;
+    assert_eq!(rsp.status(), Status::BadRequest);
+    assert_eq!(rsp.headers().get_one("X-Error-Code"), Some("hash_mismatch"));
+    assert!(rsp.headers().get_one("X-Reason").is_some());
+}
+
+#[sqlx::test]
+async fn mirror_with_wrong_action(pool: MySqlPool) {
+    let server = TestServer::new(pool).await;
+    let keys = Keys::generate();
+    let rsp = server
+        .client
+        .put("/mirror")
+        .header(blossom_auth(&keys, "upload", &[]))
+        .header(ContentType::JSON)
+        .body(r#"{"url":"http://127.0.0.1:1/blob"}"#)
+        .dispatch()
+        .await;
+    assert_error(
+        rsp,
+        Status::Unauthorized,
+        "invalid_auth",
+        "Invalid request method tag",
+    )
+    .await;
+}
+
+#[sqlx::test]
+async fn mirror_unreachable_source(pool: MySqlPool) {
+    let server = TestServer::new(pool).await;
+    let keys = Keys::generate();
+    let rsp = server
+        .client
+        .put("/mirror")
+        .header(blossom_auth(&keys, "mirror", &[]))
+        .header(ContentType::JSON)
+        .body(r#"{"url":"http://127.0.0.1:1/blob"}"#)
+        .dispatch()
+        .await;
+    assert_error(
+        rsp,
+        Status::BadGateway,
+        "mirror_failed",
+        "Failed to mirror file",
+    )
+    .await;
+}
+
+#[cfg(feature = "media-compression")]
+#[sqlx::test]
+async fn media_unsupported_type_rejected(pool: MySqlPool) {
+    let server = TestServer::with_settings(pool, |s| {
+        s.media_types = Some(MediaTypesConfig {
+            supported: None,
+            unsupported: Some(UnsupportedMediaPolicy::Reject),
+        })
+    })
+    .await;
+    let keys = Keys::generate();
+    let rsp = server
+        .client
+        .put("/media")
+        .header(blossom_auth(&keys, "media", &[]))
+        .header(ContentType::Text)
+        .body(b"not an image")
+        .dispatch()
+        .await;
+    assert_error(
+        rsp,
+        Status::UnsupportedMediaType,
+        "unsupported_media_type",
+        "text/plain cannot be processed",
+    )
+    .await;
+}
+
+#[cfg(feature = "media-compression")]
+#[sqlx::test]
+async fn media_with_wrong_action(pool: MySqlPool) {
+    let server = TestServer::new(pool).await;
+    let keys = Keys::generate();
+    let rsp = server
+        .client
+        .put("/media")
+        .header(blossom_auth(&keys, "upload", &[]))
+        .header(ContentType::PNG)
+        .body(b"not an image")
+        .dispatch()
+        .await;
+    assert_error(
+        rsp,
+        Status::Unauthorized,
+        "invalid_auth",
+        "Invalid request method tag",
+    )
+    .await;
+}
+
+#[cfg(feature = "media-compression")]
+#[sqlx::test]
+async fn media_not_matching_x_tag(pool: MySqlPool) {
+    let server = TestServer::with_settings(pool, |s| s.auth_x_tag = Some(XTagPolicy::Strict)).await;
+    let keys = Keys::generate();
+    // signed for other content, the stored file is derived so the original bytes are checked
+    let rsp = server
+        .client
+        .put("/media")
+        .header(blossom_auth(&keys, "media", &[&sha256_hex(b"other")]))
+        .header(ContentType::PNG)
+        .body(tiny_png())
+        .dispatch()
+        .await;
+    assert_eq!(rsp.status(), Status::BadRequest);
+    assert_eq!(rsp.headers().get_one("X-Error-Code"), Some("hash_mismatch"));
+}
+
+#[sqlx::test]
+async fn list_invalid_pubkey(pool: MySqlPool) {
+    let server = TestServer::new(pool).await;
+    let rsp = server.client.get("/list/not-hex").dispatch().await;
+    assert_error(rsp, Status::BadRequest, "bad_request", "invalid pubkey").await;
+}
+
+#[sqlx::test]
+async fn delete_unknown_blob(pool: MySqlPool) {
+    let server = TestServer::new(pool).await;
+    let keys = Keys::generate();
+    let id = sha256_hex(b"never uploaded");
+    let rsp = server
+        .client
+        .delete(format!("/{}", id))
+        .header(blossom_auth(&keys, "delete", &[&id]))
+        .dispatch()
+        .await;
+    assert_error(rsp, Status::NotFound, "not_found", "Not found").await;
+}
+
+#[sqlx::test]
+async fn delete_by_non_owner(pool: MySqlPool) {
+    let server = TestServer::new(pool).await;
+    let owner = Keys::generate();
+    let other = Keys::generate();
+    let data = b"owned blob";
+    let id = sha256_hex(data);
+    let rsp = server
+        .client
+        .put("/upload")
+        .header(blossom_auth(&owner, "upload", &[&id]))
+        .header(ContentType::Binary)
+        .body(data)
+        .dispatch()
+        .await;
+    assert_eq!(rsp.status(), Status::Ok);
+    // the other key needs a user to get past the unknown user check
+    server
+        .db
+        .upsert_user(&other.public_key().to_bytes().to_vec())
+        .await
+        .expect("user");
+
+    let rsp = server
+        .client
+        .delete(format!("/{}", id))
+        .header(blossom_auth(&other, "delete", &[&id]))
+        .dispatch()
+        .await;
+    assert_error(
+        rsp,
+        Status::Forbidden,
+        "not_owner",
+        "You dont own this file",
+    )
+    .await;
+}
+
+#[sqlx::test]
+async fn delete_with_other_x_tag(pool: MySqlPool) {
+    let server = TestServer::new(pool).await;
+    let keys = Keys::generate();
+    let id = sha256_hex(b"some blob");
+    let rsp = server
+        .client
+        .delete(format!("/{}", id))
+        .header(blossom_auth(&keys, "delete", &[&sha256_hex(b"other blob")]))
+        .dispatch()
+        .await;
+    assert_error(
+        rsp,
+        Status::Unauthorized,
+        "invalid_auth",
+        "Blob does not match the x tag",
+    )
+    .await;
+}
+
+#[sqlx::test]
+async fn delete_invalid_id(pool: MySqlPool) {
+    let server = TestServer::new(pool).await;
+    let keys = Keys::generate();
+    let rsp = server
+        .client
+        .delete("/not-a-hash")
+        .header(blossom_auth(&keys, "delete", &[]))
+        .dispatch()
+        .await;
+    assert_eq!(rsp.status(), Status::BadRequest);
+    assert_eq!(
+        rsp.headers().get_one("X-Error-Code"),
+        Some("invalid_file_id")
+    );
+}
@@ -0,0 +1,116 @@
+//! Access logging of files under legal hold
+mod common;
+
+use common::{blossom_auth, sha256_hex, TestServer};
+use nostr::Keys;
+use rocket::http::{ContentType, Status};
+use route96::legal_hold::LegalHoldAction;
+use sqlx::MySqlPool;
+
+/// Upload a blob, returning its id
+async fn upload(server: &TestServer, keys: &Keys, data: &[u8]) -> Vec<u8> {
+    let id = sha256_hex(data);
+    let rsp = server
+        .client
+        .put("/upload")
+        .header(blossom_auth(keys, "upload", &[&id]))
+        .header(ContentType::Binary)
+        .body(data)
+        .dispatch()
+        .await;
+    assert_eq!(rsp.status(), Status::Ok);
+    hex::decode(id).unwrap()
+}
+
+/// Upload a blob and place a hold on it
+async fn held_upload(server: &TestServer, data: &[u8]) -> Vec<u8> {
+    let keys = Keys::generate();
+    let id = upload(server, &keys, data).await;
+    let admin = Keys::generate();
+    server.add_admin(&admin).await;
+    server
+        .db
+        .set_legal_hold(&id, &admin.public_key().to_bytes(), None)
+        .await
+        .unwrap();
+    id
+}
+
+/// Routes of the access events of a file, without the client
+async fn accesses(server: &TestServer, id: &Vec<u8>) -> Vec<String> {
+    server
+        .db
+        .list_legal_hold_events(id)
+        .await
+        .unwrap()
+        .into_iter()
+        .filter(|e| e.action == LegalHoldAction::Access)
+        .filter_map(|e| e.detail)
+        .map(|d| d.split(' ').next().unwrap_or_default().to_string())
+        .collect()
+}
+
+#[sqlx::test]
+async fn head_is_logged(pool: MySqlPool) {
+    let server = TestServer::new(pool).await;
+    let id = held_upload(&server, b"held head").await;
+
+    let rsp = server
+        .client
+        .head(format!("/{}", hex::encode(&id)))
+        .dispatch()
+        .await;
+    assert_eq!(rsp.status(), Status::Ok);
+    assert_eq!(accesses(&server, &id).await, ["head"]);
+}
+
+#[sqlx::test]
+async fn preview_is_logged(pool: MySqlPool) {
+    let server = TestServer::new(pool).await;
+    let id = held_upload(&server, b"held preview").await;
+    let hex_id = hex::encode(&id);
+
+    let rsp = server.client.get(format!("/p/{}", hex_id)).dispatch().await;
+    assert_eq!(rsp.status(), Status::Ok);
+    let rsp = server
+        .client
+        .get(format!("/oembed?url=http://localhost:8000/{}", hex_id))
+        .dispatch()
+        .await;
+    assert_eq!(rsp.status(), Status::Ok);
+    assert_eq!(accesses(&server, &id).await, ["preview", "oembed"]);
+}
+
+#[sqlx::test]
+async fn derived_file_read_is_logged_on_source(pool: MySqlPool) {
+    let server = TestServer::new(pool).await;
+    let id = held_upload(&server, b"held source").await;
+    let derived = upload(&server, &Keys::generate(), b"derived").await;
+    server
+        .db
+        .add_derivation(&id, "thumbnail:webp", &derived)
+        .await
+        .unwrap();
+
+    let rsp = server
+        .client
+        .get(format!("/{}", hex::encode(&derived)))
+        .dispatch()
+        .await;
+    assert_eq!(rsp.status(), Status::Ok);
+    assert_eq!(accesses(&server, &id).await, ["download"]);
+}
+
+#[sqlx::test]
+async fn reads_of_unheld_files_are_not_logged(pool: MySqlPool) {
+    let server = TestServer::new(pool).await;
+    let id = upload(&server, &Keys::generate(), b"not held").await;
+
+    let rsp = server
+        .client
+        .head(format!("/{}", hex::encode(&id)))
+        .dispatch()
+        .await;
+    assert_eq!(rsp.status(), Status::Ok);
+    assert!(accesses(&server, &id).await.is_empty());
+}
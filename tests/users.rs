@@ -0,0 +1,115 @@
+//! Merging user rows whose pubkey was stored as hex text
+mod common;
+
+use common::{blossom_auth, sha256_hex, TestServer};
+use nostr::Keys;
+use rocket::http::{ContentType, Status};
+use route96::background::users::{MergeUsersHandler, MergeUsersJob, MERGE_USERS_JOB};
+use route96::background::{enqueue, JobHandler};
+use sqlx::MySqlPool;
+
+/// Upload a blob, returning its id
+async fn upload(server: &TestServer, keys: &Keys, data: &[u8]) -> Vec<u8> {
+    let id = sha256_hex(data);
+    let rsp = server
+        .client
+        .put("/upload")
+        .header(blossom_auth(keys, "upload", &[&id]))
+        .header(ContentType::Binary)
+        .body(data)
+        .dispatch()
+        .await;
+    assert_eq!(rsp.status(), Status::Ok);
+    hex::decode(id).unwrap()
+}
+
+/// Create the user of a key and a duplicate with the pubkey stored as (truncated) hex text
+async fn users_with_duplicate(server: &TestServer, keys: &Keys) -> (u64, u64) {
+    let pubkey = keys.public_key().to_bytes().to_vec();
+    let user = server.db.upsert_user(&pubkey).await.expect("user");
+    let text = hex::encode(&pubkey)[..32].as_bytes().to_vec();
+    let duplicate = server.db.upsert_user(&text).await.expect("duplicate");
+    (user, duplicate)
+}
+
+async fn run_merge(server: &TestServer) {
+    let id = enqueue(&server.db, MERGE_USERS_JOB, &MergeUsersJob::default())
+        .await
+        .expect("job");
+    let job = server.db.get_job(id).await.expect("query").expect("job");
+    MergeUsersHandler::new(server.db.clone())
+        .run(&job)
+        .await
+        .expect("merge");
+}
+
+#[sqlx::test]
+async fn merge_moves_collections(pool: MySqlPool) {
+    let server = TestServer::new(pool).await;
+    let keys = Keys::generate();
+    let (user, duplicate) = users_with_duplicate(&server, &keys).await;
+    let a = upload(&server, &keys, b"file a").await;
+    let b = upload(&server, &keys, b"file b").await;
+    let c = upload(&server, &keys, b"file c").await;
+
+    let own = server
+        .db
+        .create_collection(user, "photos", "t1")
+        .await
+        .unwrap();
+    server.db.add_collection_file(own, &a).await.unwrap();
+    // same name as a collection of the user, merged into it
+    let same = server
+        .db
+        .create_collection(duplicate, "photos", "t2")
+        .await
+        .unwrap();
+    server.db.add_collection_file(same, &a).await.unwrap();
+    server.db.add_collection_file(same, &b).await.unwrap();
+    let other = server
+        .db
+        .create_collection(duplicate, "videos", "t3")
+        .await
+        .unwrap();
+    server.db.add_collection_file(other, &c).await.unwrap();
+
+    run_merge(&server).await;
+
+    let collections = server.db.list_collections(user).await.unwrap();
+    let names: Vec<_> = collections.iter().map(|c| c.name.as_str()).collect();
+    assert_eq!(names, ["photos", "videos"]);
+    assert_eq!(collections[0].id, own);
+    assert_eq!(collections[0].files, 2);
+    assert_eq!(collections[1].id, other);
+    assert_eq!(collections[1].files, 1);
+    assert!(server.db.get_collection(same).await.unwrap().is_none());
+    assert!(server
+        .db
+        .list_collections(duplicate)
+        .await
+        .unwrap()
+        .is_empty());
+}
+
+#[sqlx::test]
+async fn merge_keeps_tos_acceptance(pool: MySqlPool) {
+    let server = TestServer::new(pool).await;
+    let keys = Keys::generate();
+    let (user, duplicate) = users_with_duplicate(&server, &keys).await;
+    server.db.accept_tos(duplicate, "v1", "{}").await.unwrap();
+    server
+        .db
+        .accept_tos(user, "v2", "{\"own\":1}")
+        .await
+        .unwrap();
+    server.db.accept_tos(duplicate, "v2", "{}").await.unwrap();
+
+    run_merge(&server).await;
+
+    let pubkey = keys.public_key().to_bytes().to_vec();
+    let v1 = server.db.get_tos_acceptance(&pubkey, "v1").await.unwrap();
+    assert_eq!(v1.expect("moved acceptance").event, "{}");
+    // the user's own acceptance of a version is kept
+    let v2 = server.db.get_tos_acceptance(&pubkey, "v2").await.unwrap();
+    assert_eq!(v2.expect("own acceptance").event, "{\"own\":1}");
+}
@@ -19,9 +19,12 @@ export class Route96 {
     return data;
   }
 
-  async listFiles(page = 0, count = 10) {
+  async listFiles(page = 0, count = 10, mime?: Array<string>) {
+    const filter = mime?.length
+      ? `&mime=${encodeURIComponent(mime.join(","))}`
+      : "";
     const rsp = await this.#req(
-      `admin/files?page=${page}&count=${count}`,
+      `admin/files?page=${page}&count=${count}${filter}`,
       "GET",
     );
     const data = await this.#handleResponse<AdminResponseFileList>(rsp);